@@ -0,0 +1,190 @@
+//! Failover scenario driver
+//!
+//! Kills the leader with a configured command at a point of the run and
+//! optionally restarts it later, then reports how long writes were unavailable,
+//! the errors clients saw meanwhile and how long throughput took to recover.
+//! Clients are expected to be given every node with `--server`, so they can
+//! move on to the new leader.
+
+use crate::stats::Timeline;
+use std::time::Duration;
+
+/// Fraction of the pre-failover throughput that counts as recovered
+const RECOVERED_RATIO: f64 = 0.9;
+
+/// Failover options
+#[derive(clap::Args, Debug, Clone)]
+#[command(about = None, long_about = None, next_help_heading = "Failover")]
+pub struct ChaosArgs {
+    /// Seconds after the start of the run at which the leader is killed
+    #[arg(long, requires = "kill_cmd", conflicts_with = "saturation")]
+    pub kill_at: Option<u64>,
+
+    /// Shell command that kills the leader, e.g. `kill $(cat node1.pid)`
+    #[arg(long)]
+    pub kill_cmd: Option<String>,
+
+    /// Seconds after the kill at which the restart command runs
+    #[arg(long, requires = "restart_cmd")]
+    pub restart_after: Option<u64>,
+
+    /// Shell command that restarts the killed node
+    #[arg(long)]
+    pub restart_cmd: Option<String>,
+}
+
+impl ChaosArgs {
+    /// Time since the start of the run at which the leader is killed, None if chaos is off
+    pub fn kill_offset(&self) -> Option<Duration> {
+        self.kill_at.map(Duration::from_secs)
+    }
+
+    /// Schedules the kill and restart commands
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - Start of the run
+    pub fn start(&self, start: tokio::time::Instant) {
+        let (kill_at, kill_cmd) = match (self.kill_offset(), &self.kill_cmd) {
+            (Some(kill_at), Some(kill_cmd)) => (kill_at, kill_cmd.clone()),
+            _ => return,
+        };
+        let restart = self
+            .restart_after
+            .map(Duration::from_secs)
+            .zip(self.restart_cmd.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep_until(start + kill_at).await;
+            run_command("kill", kill_cmd).await;
+            if let Some((restart_after, restart_cmd)) = restart {
+                tokio::time::sleep_until(start + kill_at + restart_after).await;
+                run_command("restart", restart_cmd).await;
+            }
+        });
+    }
+}
+
+/// Runs a shell command without blocking the runtime
+async fn run_command(name: &'static str, command: String) {
+    println!("Running {} command: {}", name, command);
+    let result = tokio::task::spawn_blocking(move || {
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .status()
+    })
+    .await;
+    match result {
+        Ok(Ok(status)) if status.success() => {}
+        Ok(Ok(status)) => eprintln!("{} command exited with {}", name, status),
+        Ok(Err(e)) => eprintln!("{} command failed to start: {}", name, e),
+        Err(e) => eprintln!("{} command panicked: {}", name, e),
+    }
+}
+
+/// Tracks successful writes around the failover
+#[derive(Default)]
+pub struct Outage {
+    /// Time since the start of the run at which the leader is killed
+    kill_at: Option<Duration>,
+    /// Completion of the latest successful write
+    last_write: Duration,
+    /// Longest gap between successful writes that ends after the kill
+    window: Option<(Duration, Duration)>,
+}
+
+impl Outage {
+    /// Creates a tracker for a failover at the given time
+    pub fn new(kill_at: Option<Duration>) -> Self {
+        Self {
+            kill_at,
+            ..Default::default()
+        }
+    }
+
+    /// Records a successful write
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Completion time since the start of the run
+    pub fn record_write(&mut self, offset: Duration) {
+        let kill_at = match self.kill_at {
+            Some(kill_at) => kill_at,
+            None => return,
+        };
+        if offset > kill_at && offset > self.last_write {
+            let gap = offset - self.last_write;
+            let longest = self.window.map(|(from, to)| to - from).unwrap_or_default();
+            if gap > longest {
+                self.window = Some((self.last_write, offset));
+            }
+        }
+        self.last_write = self.last_write.max(offset);
+    }
+
+    /// Prints the unavailability window, the errors during it and the recovery time
+    ///
+    /// # Arguments
+    ///
+    /// * `timeline` - Per-second results of the run
+    pub fn report(&self, timeline: &Timeline) {
+        let kill_at = match self.kill_at {
+            Some(kill_at) => kill_at,
+            None => return,
+        };
+        println!("\nFailover:");
+        println!("Leader killed at: {:.1}s", kill_at.as_secs_f64());
+        let (from, to) = match self.window {
+            Some(window) => window,
+            None => {
+                println!("No successful write after the kill");
+                return;
+            }
+        };
+        println!(
+            "Write unavailability: {:.3}s (from {:.3}s to {:.3}s)",
+            (to - from).as_secs_f64(),
+            from.as_secs_f64(),
+            to.as_secs_f64()
+        );
+        println!(
+            "First write after kill: {:.3}s",
+            to.saturating_sub(kill_at).as_secs_f64()
+        );
+
+        let seconds = timeline.seconds();
+        let kill_second = kill_at.as_secs() as usize;
+        let recovery_second = (to.as_secs() as usize).min(seconds.len());
+        let mut errors = std::collections::BTreeMap::<&String, u64>::new();
+        for second in &seconds[kill_second.min(seconds.len())..recovery_second] {
+            for (status, count) in &second.errors {
+                *errors.entry(status).or_insert(0) += count;
+            }
+        }
+        println!("Errors until recovery: {}", errors.values().sum::<u64>());
+        for (status, count) in errors {
+            println!("  {}: {}", status, count);
+        }
+
+        let before = &seconds[..kill_second.min(seconds.len())];
+        if before.is_empty() {
+            return;
+        }
+        let baseline =
+            before.iter().map(|s| s.histogram.len()).sum::<u64>() as f64 / before.len() as f64;
+        let recovered = seconds
+            .iter()
+            .enumerate()
+            .skip(kill_second + 1)
+            .find(|(_, s)| s.histogram.len() as f64 >= baseline * RECOVERED_RATIO);
+        match recovered {
+            Some((second, _)) => println!(
+                "Throughput recovered after: {}s (to {:.0}% of {:.2}/s)",
+                second - kill_second,
+                RECOVERED_RATIO * 100.0,
+                baseline
+            ),
+            None => println!("Throughput did not recover to {:.2}/s", baseline),
+        }
+    }
+}
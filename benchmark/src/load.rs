@@ -0,0 +1,549 @@
+//! Open-loop load generation
+//!
+//! Every client sends requests at fixed intended times, independent of how long
+//! earlier requests take, and latency is measured from the intended send time.
+//! A slow server therefore shows up as growing latency instead of silently
+//! lowering the offered load (coordinated omission).
+
+use crate::chaos::Outage;
+use crate::pb::match_service_client::MatchServiceClient;
+use crate::pb::{
+    CancelOrderRequest, Order, OrderSide, OrderType, PlaceOrderRequest, QueryOrderRequest,
+    TimeInForce,
+};
+use crate::phase::{Phase, Schedule};
+use crate::price::PriceProcess;
+use crate::stats::{Stats, Timeline};
+use crate::subscribe::Subscriptions;
+use crate::symbols::{SymbolPicker, SymbolSpec};
+use crate::verify::{self, Verifier};
+use crate::workload::{Mode, OpKind, WorkloadArgs};
+use once_cell::sync::OnceCell;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time::{sleep, sleep_until, Instant};
+use tonic::transport::{Channel, Endpoint};
+
+/// Highest number of clients, bounded by the client index bits of order IDs
+///
+/// The last client index is reserved for preloaded orders.
+pub const MAX_CLIENTS: usize = (1 << 16) - 1;
+
+/// Maximum number of preload requests in flight
+const PRELOAD_CONCURRENCY: usize = 64;
+
+/// Longest wait for in-flight requests once the run is over
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Metadata key carrying the tenant API key
+const API_KEY_HEADER: &str = "x-api-key";
+/// Metadata key carrying the key authorizing funding requests and admin streams
+const ADMIN_KEY_HEADER: &str = "x-admin-key";
+/// Metadata key carrying the ID a command is applied and deduplicated under
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Settings and shared state of the client tasks
+pub struct RunContext {
+    /// Random ID of the run, part of every order ID
+    pub run_id: u16,
+    /// Server addresses, clients move on to the next one when a server stops responding
+    pub servers: Vec<String>,
+    /// Timeout of each request, also passed to the server as deadline
+    pub request_timeout: Duration,
+    /// API key sent with every request, None if the cluster has no tenants
+    pub api_key: Option<String>,
+    /// Time since the start of the run at which the leader is killed, if any
+    pub kill_at: Option<Duration>,
+    /// Number of clients
+    pub concurrency: usize,
+    /// Maximum number of requests in flight across all clients
+    pub max_inflight: usize,
+    /// Operation mix and order shapes
+    pub workload: WorkloadArgs,
+    /// Symbols the load is spread across
+    pub symbols: Vec<SymbolSpec>,
+    /// Picks the symbol of each placed order
+    pub picker: SymbolPicker,
+    /// Price process of each symbol
+    pub prices: Vec<std::sync::Mutex<PriceProcess>>,
+    /// Orders queried in read mode as (symbol index, order ID)
+    pub preloaded: OnceCell<Vec<(usize, u64)>>,
+    /// Streams the events of the placed orders are awaited on, None without subscribers
+    pub subscriptions: Option<Arc<Subscriptions>>,
+    /// Checks the orders against a reference matcher, None if the run is not verified
+    pub verifier: Option<Arc<Verifier>>,
+}
+
+/// Request of one operation, generated ahead of sending it
+enum OpRequest {
+    /// Place an order
+    Place(PlaceOrderRequest, Placement),
+    /// Cancel an order
+    Cancel(CancelOrderRequest),
+    /// Query an order
+    Query(QueryOrderRequest),
+}
+
+/// How a placed order is followed up once the server accepted it
+struct Placement {
+    /// ID of the order
+    order_id: u64,
+    /// Whether the order may rest on the book
+    resting: bool,
+    /// Whether the client cancels the order later
+    cancel: bool,
+}
+
+/// Operation about to be sent
+struct Op {
+    /// Kind of the operation
+    kind: OpKind,
+    /// Index of the symbol the operation is for
+    symbol: usize,
+    /// Request to send
+    request: OpRequest,
+}
+
+/// Orders of one client
+struct ClientOrders {
+    /// Prefix of the client's order IDs, unique per run and client
+    id_prefix: u64,
+    /// Sequence number of the next order
+    next_seq: u64,
+    /// Accepted limit orders as (symbol index, order ID)
+    placed: Vec<(usize, u64)>,
+    /// Orders to cancel as (due time, symbol index, order ID), in due order
+    cancels: VecDeque<(Instant, usize, u64)>,
+}
+
+impl ClientOrders {
+    /// Creates the order state of a client
+    ///
+    /// Order IDs consist of the run ID in the top 16 bits, the client index in the
+    /// next 16 bits and a per-client sequence number in the low 32 bits, so they are
+    /// unique across clients and, with high probability, across runs.
+    fn new(run_id: u16, client_index: usize) -> Self {
+        Self {
+            id_prefix: (run_id as u64) << 48 | (client_index as u64 & 0xffff) << 32,
+            next_seq: 0,
+            placed: Vec::new(),
+            cancels: VecDeque::new(),
+        }
+    }
+
+    /// Allocates the ID of the next order
+    fn next_order_id(&mut self) -> u64 {
+        let order_id = self.id_prefix | (self.next_seq & 0xffff_ffff);
+        self.next_seq += 1;
+        order_id
+    }
+}
+
+/// Generates the next operation of the workload
+///
+/// Cancels of the client's own orders go first once they are due. In read mode
+/// every other operation queries a preloaded order. Otherwise orders are placed
+/// on a symbol chosen by the symbol picker, and queries target a random order
+/// previously placed by the same client, falling back to placing an order
+/// while the client has none.
+fn next_op(ctx: &RunContext, rng: &mut StdRng, orders: &std::sync::Mutex<ClientOrders>) -> Op {
+    let mut orders = orders.lock().unwrap();
+    if let Some((due, _, _)) = orders.cancels.front() {
+        if *due <= Instant::now() {
+            let (_, symbol, order_id) = orders.cancels.pop_front().unwrap();
+            if let Some(index) = orders
+                .placed
+                .iter()
+                .position(|placed| *placed == (symbol, order_id))
+            {
+                orders.placed.swap_remove(index);
+            }
+            return Op {
+                kind: OpKind::Cancel,
+                symbol,
+                request: OpRequest::Cancel(CancelOrderRequest {
+                    symbol: ctx.symbols[symbol].name.clone(),
+                    order_id,
+                    ..Default::default()
+                }),
+            };
+        }
+    }
+    if ctx.workload.mode == Mode::Read {
+        if let Some(preloaded) = ctx.preloaded.get().filter(|p| !p.is_empty()) {
+            let (symbol, order_id) = preloaded[rng.gen_range(0..preloaded.len())];
+            return query_op(ctx, symbol, order_id);
+        }
+    }
+    match ctx.workload.next_op(rng) {
+        OpKind::Query if !orders.placed.is_empty() => {
+            let (symbol, order_id) = orders.placed[rng.gen_range(0..orders.placed.len())];
+            query_op(ctx, symbol, order_id)
+        }
+        _ => place_op(ctx, rng, &mut orders),
+    }
+}
+
+/// Builds a query for an order
+fn query_op(ctx: &RunContext, symbol: usize, order_id: u64) -> Op {
+    Op {
+        kind: OpKind::Query,
+        symbol,
+        request: OpRequest::Query(QueryOrderRequest {
+            symbol: ctx.symbols[symbol].name.clone(),
+            order_id,
+            ..Default::default()
+        }),
+    }
+}
+
+/// Builds a new order on a symbol chosen by the symbol picker
+///
+/// Market orders are sent IOC, so what they cannot fill is canceled rather than
+/// left on the book.
+fn place_op(ctx: &RunContext, rng: &mut StdRng, orders: &mut ClientOrders) -> Op {
+    let workload = &ctx.workload;
+    let symbol = ctx.picker.pick(rng);
+    let order_id = orders.next_order_id();
+    let is_buy = workload.is_buy(rng);
+    let order_side = if is_buy {
+        OrderSide::Buy
+    } else {
+        OrderSide::Sell
+    };
+    let (order_type, price) = if workload.is_market(rng) {
+        (OrderType::Market, "0".to_string())
+    } else {
+        let mut prices = ctx.prices[symbol].lock().unwrap();
+        let price = prices.next_price(is_buy, rng);
+        (OrderType::Limit, format!("{:.*}", prices.decimals(), price))
+    };
+    let resting = order_type == OrderType::Limit;
+    let time_in_force = if resting {
+        TimeInForce::Gtc
+    } else {
+        TimeInForce::Ioc
+    };
+    let request = PlaceOrderRequest {
+        order: Some(Order {
+            symbol: ctx.symbols[symbol].name.clone(),
+            account_id: workload.account(rng),
+            order_side: order_side as i32,
+            order_type: order_type as i32,
+            time_in_force: time_in_force as i32,
+            quantity: format!("{:.5}", workload.size.sample(rng)),
+            price,
+            order_id,
+            taker_fee: "0.0005".to_string(),
+            maker_fee: "0.0005".to_string(),
+            ..Default::default()
+        }),
+    };
+    Op {
+        kind: OpKind::Place,
+        symbol,
+        request: OpRequest::Place(
+            request,
+            Placement {
+                order_id,
+                resting,
+                cancel: resting && workload.is_cancelled(rng),
+            },
+        ),
+    }
+}
+
+/// Creates a client that connects to a server on first use
+pub fn connect(addr: &str) -> Result<MatchServiceClient<Channel>, String> {
+    let endpoint = Endpoint::from_shared(addr.to_string())
+        .map_err(|e| format!("invalid server address {}: {}", addr, e))?;
+    Ok(MatchServiceClient::new(endpoint.connect_lazy()))
+}
+
+/// Checks whether an error suggests the server is down or no longer the leader
+pub fn is_failover_error(code: tonic::Code) -> bool {
+    matches!(
+        code,
+        tonic::Code::Unavailable | tonic::Code::DeadlineExceeded
+    )
+}
+
+/// Wraps a message into a request carrying the API key, if any
+pub fn authorized<T>(message: T, api_key: Option<&str>) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    if let Some(key) = api_key.and_then(|key| key.parse().ok()) {
+        request.metadata_mut().insert(API_KEY_HEADER, key);
+    }
+    request
+}
+
+/// Adds the admin key, if any, to a request
+pub fn with_admin_key<T>(
+    mut request: tonic::Request<T>,
+    admin_key: Option<&str>,
+) -> tonic::Request<T> {
+    if let Some(key) = admin_key.and_then(|key| key.parse().ok()) {
+        request.metadata_mut().insert(ADMIN_KEY_HEADER, key);
+    }
+    request
+}
+
+/// Wraps a message into a request with the configured timeout and API key
+fn with_timeout<T: Clone>(ctx: &RunContext, message: &T) -> tonic::Request<T> {
+    let mut request = authorized(message.clone(), ctx.api_key.as_deref());
+    request.set_timeout(ctx.request_timeout);
+    request
+}
+
+/// Adds the ID a command is applied under to a request
+fn with_request_id<T>(mut request: tonic::Request<T>, request_id: String) -> tonic::Request<T> {
+    if let Ok(request_id) = request_id.parse() {
+        request.metadata_mut().insert(REQUEST_ID_HEADER, request_id);
+    }
+    request
+}
+
+/// Sends an operation and remembers successfully placed limit orders
+///
+/// Places and cancels are sent under request IDs derived from the order ID.
+/// Placed orders are registered with the subscribers and the verifier right
+/// before they are sent.
+async fn send(
+    client: &mut MatchServiceClient<Channel>,
+    ctx: &RunContext,
+    op: &Op,
+    orders: &std::sync::Mutex<ClientOrders>,
+) -> Result<(), tonic::Status> {
+    match &op.request {
+        OpRequest::Place(request, placement) => {
+            if let Some(subscriptions) = &ctx.subscriptions {
+                subscriptions.sent(&ctx.symbols[op.symbol].name, placement.order_id);
+            }
+            if let (Some(verifier), Some(order)) = (&ctx.verifier, &request.order) {
+                verifier.sent(order);
+            }
+            let request_id = verify::place_request_id(placement.order_id);
+            client
+                .place_order(with_request_id(with_timeout(ctx, request), request_id))
+                .await?;
+            if let Some(verifier) = &ctx.verifier {
+                verifier.acknowledged(placement.order_id);
+            }
+            let mut orders = orders.lock().unwrap();
+            if placement.resting {
+                orders.placed.push((op.symbol, placement.order_id));
+            }
+            if placement.cancel {
+                let due = Instant::now() + ctx.workload.cancel_delay();
+                orders
+                    .cancels
+                    .push_back((due, op.symbol, placement.order_id));
+            }
+            Ok(())
+        }
+        OpRequest::Cancel(request) => {
+            let request_id = verify::cancel_request_id(request.order_id);
+            client
+                .cancel_order(with_request_id(with_timeout(ctx, request), request_id))
+                .await?;
+            if let Some(verifier) = &ctx.verifier {
+                verifier.cancel_acknowledged(request.order_id);
+            }
+            Ok(())
+        }
+        OpRequest::Query(request) => client
+            .query_order(with_timeout(ctx, request))
+            .await
+            .map(|_| ()),
+    }
+}
+
+/// Places the orders read mode queries
+///
+/// Orders are placed like those of the write workload, under the reserved
+/// client index `MAX_CLIENTS`, and the accepted limit orders are stored in the
+/// run context.
+///
+/// # Arguments
+///
+/// * `ctx` - Settings and shared state of the clients
+/// * `count` - Number of orders to place
+pub async fn preload(ctx: Arc<RunContext>, count: usize) -> Result<(), String> {
+    let client = connect(&ctx.servers[0])?;
+    let orders = Arc::new(std::sync::Mutex::new(ClientOrders::new(
+        ctx.run_id,
+        MAX_CLIENTS,
+    )));
+    let permits = Arc::new(Semaphore::new(PRELOAD_CONCURRENCY));
+    let mut rng = StdRng::from_entropy();
+    let mut handles = Vec::with_capacity(count);
+    for _ in 0..count {
+        let permit = permits.clone().acquire_owned().await.unwrap();
+        let op = place_op(&ctx, &mut rng, &mut orders.lock().unwrap());
+        let mut client = client.clone();
+        let ctx = ctx.clone();
+        let orders = orders.clone();
+        handles.push(tokio::spawn(async move {
+            if let Err(e) = send(&mut client, &ctx, &op, &orders).await {
+                eprintln!("preload request failed: {}", e);
+            }
+            drop(permit);
+        }));
+    }
+    for handle in handles {
+        let _ = handle.await;
+    }
+    let placed = std::mem::take(&mut orders.lock().unwrap().placed);
+    println!("Preloaded {} orders", placed.len());
+    let _ = ctx.preloaded.set(placed);
+    Ok(())
+}
+
+/// Results of a run
+#[derive(Default)]
+pub struct RunResult {
+    /// Statistics of each reported phase
+    pub phases: BTreeMap<Phase, Stats>,
+    /// Throughput, latency and errors per second over the whole run
+    pub timeline: Timeline,
+    /// Successful writes around the failover
+    pub outage: Outage,
+}
+
+/// Runs the load at a fixed rate through all phases of a schedule
+///
+/// # Arguments
+///
+/// * `ctx` - Settings and shared state of the clients
+/// * `schedule` - Phases of the run, starting now
+/// * `rate` - Target requests per second at full concurrency
+///
+/// # Returns
+///
+/// Returns the statistics of each reported phase and the timeline of the run
+pub async fn run(ctx: Arc<RunContext>, schedule: Arc<Schedule>, rate: f64) -> RunResult {
+    let stats = Arc::new(Mutex::new(RunResult {
+        outage: Outage::new(ctx.kill_at),
+        ..Default::default()
+    }));
+    let inflight = Arc::new(AtomicUsize::new(0));
+    // Each client sends at an equal share of the rate, offset so sends are spread evenly
+    let interval = Duration::from_secs_f64(ctx.concurrency as f64 / rate);
+    let start = Instant::from_std(schedule.start());
+
+    let mut handles = vec![];
+    for client_index in 0..ctx.concurrency {
+        let ctx = ctx.clone();
+        let schedule = schedule.clone();
+        let stats = stats.clone();
+        let inflight = inflight.clone();
+
+        let handle = tokio::spawn(async move {
+            let mut server = 0;
+            let mut client = match connect(&ctx.servers[server]) {
+                Ok(client) => client,
+                Err(e) => {
+                    eprintln!("Failed to connect to server: {}", e);
+                    return;
+                }
+            };
+            let failed = Arc::new(AtomicBool::new(false));
+            let mut rng = StdRng::from_entropy();
+            let orders = Arc::new(std::sync::Mutex::new(ClientOrders::new(
+                ctx.run_id,
+                client_index,
+            )));
+            let mut next = start + interval.mul_f64(client_index as f64 / ctx.concurrency as f64);
+
+            loop {
+                sleep_until(next).await;
+                let intended = next;
+                next += interval;
+                let phase = match schedule.phase_at(intended.into_std()) {
+                    Some((phase, _)) => phase,
+                    None => break,
+                };
+                if !schedule.is_active(client_index, intended.into_std()) {
+                    continue;
+                }
+                if failed.swap(false, Ordering::Relaxed) && ctx.servers.len() > 1 {
+                    server = (server + 1) % ctx.servers.len();
+                    match connect(&ctx.servers[server]) {
+                        Ok(next_client) => client = next_client,
+                        Err(e) => eprintln!("Failed to connect to server: {}", e),
+                    }
+                }
+                if inflight.fetch_add(1, Ordering::Relaxed) >= ctx.max_inflight {
+                    inflight.fetch_sub(1, Ordering::Relaxed);
+                    if phase != Phase::Warmup {
+                        let mut stats = stats.lock().await;
+                        stats
+                            .phases
+                            .entry(phase)
+                            .or_insert_with(Stats::new)
+                            .record_missed();
+                    }
+                    continue;
+                }
+
+                let op = next_op(&ctx, &mut rng, &orders);
+                let mut client = client.clone();
+                let ctx = ctx.clone();
+                let stats = stats.clone();
+                let inflight = inflight.clone();
+                let orders = orders.clone();
+                let failed = failed.clone();
+                tokio::spawn(async move {
+                    let result = send(&mut client, &ctx, &op, &orders).await;
+                    let latency = intended.elapsed();
+                    if let Err(e) = &result {
+                        eprintln!("{} request failed: {}", op.kind, e);
+                        if is_failover_error(e.code()) {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                    }
+                    let mut stats = stats.lock().await;
+                    match &result {
+                        Ok(()) => {
+                            stats.timeline.record(start.elapsed(), latency);
+                            if op.kind != OpKind::Query {
+                                stats.outage.record_write(start.elapsed());
+                            }
+                        }
+                        Err(e) => stats.timeline.record_error(start.elapsed(), e.code()),
+                    }
+                    if phase != Phase::Warmup {
+                        let stats = stats.phases.entry(phase).or_insert_with(Stats::new);
+                        match result {
+                            Ok(()) => stats.record(op.kind, &ctx.symbols[op.symbol].name, latency),
+                            Err(e) => stats.record_error(op.kind, e.code()),
+                        }
+                    }
+                    inflight.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+        });
+
+        handles.push(handle);
+    }
+
+    // Run through all phases
+    sleep(schedule.total()).await;
+    for handle in handles {
+        handle.abort();
+    }
+
+    // Wait for the last responses so slow requests at the end are not lost
+    let drain_start = Instant::now();
+    while inflight.load(Ordering::Relaxed) > 0 && drain_start.elapsed() < DRAIN_TIMEOUT {
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    let mut stats = stats.lock().await;
+    std::mem::take(&mut *stats)
+}
@@ -1,34 +1,111 @@
+mod chaos;
+mod load;
+mod phase;
+mod price;
+mod reference;
+mod replay;
+mod report;
+mod saturation;
+mod stats;
+mod subscribe;
+mod symbols;
+mod verify;
+mod workload;
+
 use clap::Parser;
-use hdrhistogram::Histogram;
+use once_cell::sync::OnceCell;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::Mutex;
-use tokio::time::sleep;
+use tonic::transport::Channel;
 
+use chaos::ChaosArgs;
+use load::RunContext;
 use pb::match_service_client::MatchServiceClient;
-use pb::{
-    CreateSymbolRequest, Order, OrderSide, OrderType, PlaceOrderRequest, Symbol, SymbolStatus,
-    TimeInForce,
-};
+use pb::{CreateSymbolRequest, Symbol, SymbolStatus};
+use phase::{Phase, PhaseArgs, Schedule};
+use price::{PriceArgs, PriceProcess};
+use replay::ReplayArgs;
+use report::{CompareArgs, OutputArgs, RunReport};
+use saturation::SaturationArgs;
+use stats::Stats;
+use subscribe::{SubscribeArgs, Subscriptions};
+use symbols::{SymbolArgs, SymbolSpec};
+use verify::{Verifier, VerifyArgs};
+use workload::{Mode, WorkloadArgs};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Number of concurrent clients
     #[arg(short, long, default_value = "1")]
     concurrency: usize,
 
-    /// INTERVAL ms
-    #[arg(short, long, default_value = "100")]
-    interval: u64,
+    /// Target requests per second across all clients, sent regardless of response latency
+    #[arg(short, long, default_value = "10")]
+    rate: f64,
+
+    /// Maximum number of requests in flight, further sends are counted as missed
+    #[arg(long, default_value = "10000")]
+    max_inflight: usize,
 
-    /// Duration of the benchmark in seconds
+    /// Duration of the steady-state phase in seconds
     #[arg(short, long, default_value = "30")]
     duration: u64,
 
-    /// Server address
-    #[arg(short, long, default_value = "grpc://127.0.0.1:4001")]
-    server: String,
+    /// Server addresses, list every node of the cluster for failover runs
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        default_value = "grpc://127.0.0.1:4001"
+    )]
+    server: Vec<String>,
+
+    /// Timeout of each request in milliseconds
+    #[arg(long, default_value = "5000")]
+    request_timeout_ms: u64,
+
+    /// API key sent with every request, for clusters that configure tenants
+    #[arg(long)]
+    api_key: Option<String>,
+
+    #[command(flatten)]
+    workload: WorkloadArgs,
+
+    #[command(flatten)]
+    price: PriceArgs,
+
+    #[command(flatten)]
+    symbols: SymbolArgs,
+
+    #[command(flatten)]
+    phases: PhaseArgs,
+
+    #[command(flatten)]
+    saturation: SaturationArgs,
+
+    #[command(flatten)]
+    chaos: ChaosArgs,
+
+    #[command(flatten)]
+    subscribe: SubscribeArgs,
+
+    #[command(flatten)]
+    verify: VerifyArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Compare two JSON result files, exits with an error if a metric regressed
+    Compare(CompareArgs),
+    /// Replay client requests recorded by a match node, see `record_path`
+    Replay(ReplayArgs),
 }
 
 #[allow(clippy::module_inception)]
@@ -36,7 +113,7 @@ pub mod pb {
     tonic::include_proto!("r#match");
 }
 
-async fn create_symbol(server_addr: &str) {
+async fn create_symbols(server_addr: &str, api_key: Option<&str>, specs: &[SymbolSpec]) {
     let mut client = match MatchServiceClient::connect(server_addr.to_string()).await {
         Ok(client) => client,
         Err(e) => {
@@ -44,118 +121,192 @@ async fn create_symbol(server_addr: &str) {
             return;
         }
     };
-    let request = tonic::Request::new(CreateSymbolRequest {
-        symbol: Some(Symbol {
-            symbol: "BTCUSDT".to_string(),
-            base: "BTC".to_string(),
-            quote: "USDT".to_string(),
-            min_quantity: "0.000001".to_string(),
-            max_quantity: "1000000".to_string(),
-            min_amount: "0.000001".to_string(),
-            max_amount: "1000000".to_string(),
-            price_precision: 5,
-            quantity_precision: 5,
-            status: SymbolStatus::Alive as i32,
-        }),
-    });
+    for spec in specs {
+        create_symbol(&mut client, api_key, spec).await;
+    }
+}
+
+async fn create_symbol(
+    client: &mut MatchServiceClient<Channel>,
+    api_key: Option<&str>,
+    spec: &SymbolSpec,
+) {
+    let request = load::authorized(
+        CreateSymbolRequest {
+            symbol: Some(Symbol {
+                symbol: spec.name.clone(),
+                base: spec.base.clone(),
+                quote: spec.quote.clone(),
+                min_quantity: "0.000001".to_string(),
+                max_quantity: "1000000".to_string(),
+                min_amount: "0.000001".to_string(),
+                max_amount: "1000000".to_string(),
+                price_precision: 5,
+                quantity_precision: 5,
+                status: SymbolStatus::Alive as i32,
+                settle_balances: false,
+                ..Default::default()
+            }),
+        },
+        api_key,
+    );
     match client.create_symbol(request).await {
-        Ok(_) => println!("Symbol created"),
-        Err(e) => eprintln!("Failed to create symbol: {}", e),
+        Ok(_) => println!("Symbol {} created", spec.name),
+        Err(e) => eprintln!("Failed to create symbol {}: {}", spec.name, e),
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
+    match &args.command {
+        Some(Command::Compare(compare)) => {
+            let regressions = report::compare(compare)?;
+            if regressions > 0 {
+                return Err(format!("{} metrics regressed", regressions).into());
+            }
+            return Ok(());
+        }
+        Some(Command::Replay(replay)) => {
+            replay::run(replay).await?;
+            return Ok(());
+        }
+        None => {}
+    }
+    if args.concurrency > load::MAX_CLIENTS {
+        return Err(format!("at most {} clients are supported", load::MAX_CLIENTS).into());
+    }
+    if args.concurrency == 0
+        || args.rate <= 0.0
+        || args.saturation.rate_step.is_some_and(|step| step <= 0.0)
+    {
+        return Err("concurrency, rate and rate step must be positive".into());
+    }
+    args.workload.validate()?;
+    args.price.validate()?;
+    args.symbols.validate()?;
 
-    // Connect to the server
-    let server_addr = args.server.clone();
-    let histogram = Arc::new(Mutex::new(Histogram::<u64>::new(3).unwrap()));
-    let total_requests = Arc::new(Mutex::new(0u64));
+    let symbols = args.symbols.specs();
+    let verifier = Verifier::new(
+        &args.verify,
+        args.api_key.as_deref(),
+        args.subscribe.admin_key.as_deref(),
+    );
+    let ctx = Arc::new(RunContext {
+        run_id: rand::random(),
+        servers: args.server.clone(),
+        request_timeout: Duration::from_millis(args.request_timeout_ms),
+        api_key: args.api_key.clone(),
+        kill_at: args.chaos.kill_offset(),
+        concurrency: args.concurrency,
+        max_inflight: args.max_inflight,
+        workload: args.workload.clone(),
+        prices: symbols
+            .iter()
+            .map(|_| std::sync::Mutex::new(PriceProcess::new(args.price.clone())))
+            .collect(),
+        symbols,
+        picker: args.symbols.picker(),
+        preloaded: OnceCell::new(),
+        subscriptions: Subscriptions::new(&args.subscribe),
+        verifier: verifier.clone(),
+    });
 
     println!(
-        "Starting benchmark with {} concurrent clients, target INTERVAL: {}",
-        args.concurrency, args.interval
+        "Starting benchmark with {} concurrent clients, target rate: {}/s",
+        args.concurrency, args.rate
     );
+    println!("Workload: {:?}", args.workload);
 
-    create_symbol(&server_addr).await;
-
-    // Spawn client tasks
-    let mut handles = vec![];
-    for _ in 0..args.concurrency {
-        let server_addr = server_addr.clone();
-        let histogram = histogram.clone();
-        let total_requests = total_requests.clone();
-
-        let handle = tokio::spawn(async move {
-            let mut client = match MatchServiceClient::connect(server_addr).await {
-                Ok(client) => client,
-                Err(e) => {
-                    eprintln!("Failed to connect to server: {}", e);
-                    return;
-                }
-            };
-
-            loop {
-                let start = Instant::now();
-
-                // Send request
-                let request = tonic::Request::new(PlaceOrderRequest {
-                    order: Some(Order {
-                        symbol: "BTCUSDT".to_string(),
-                        account_id: rand::random::<u64>(),
-                        order_side: OrderSide::Buy as i32,
-                        order_type: OrderType::Limit as i32,
-                        time_in_force: TimeInForce::Gtc as i32,
-                        quantity: "0.001".to_string(),
-                        price: "50000.0".to_string(),
-                        order_id: rand::random::<u64>() % 1000,
-                        taker_fee: "0.0005".to_string(),
-                        maker_fee: "0.0005".to_string(),
-                    }),
-                });
-
-                // println!("Request sent {:?}", request);
-                match client.place_order(request).await {
-                    Ok(_) => {
-                        let duration = start.elapsed();
-                        let mut hist = histogram.lock().await;
-                        hist.record(duration.as_micros() as u64).unwrap();
-                        let mut total = total_requests.lock().await;
-                        *total += 1;
-                        // println!("Request success cost: {} us", duration.as_micros());
-                    }
-                    Err(e) => eprintln!("Request failed: {}", e),
-                }
-
-                tokio::time::sleep(Duration::from_millis(args.interval)).await;
+    create_symbols(&args.server[0], args.api_key.as_deref(), &ctx.symbols).await;
+    let verification = match verifier {
+        Some(verifier) => {
+            let feed = verifier.start(args.server.clone());
+            if !verifier.ready().await {
+                return Err("the change feed could not be subscribed for verification".into());
             }
-        });
+            Some((verifier, feed))
+        }
+        None => None,
+    };
+    if args.workload.mode == Mode::Read {
+        load::preload(ctx.clone(), args.workload.preload).await?;
+    }
 
-        handles.push(handle);
+    if args.saturation.saturation {
+        saturation::search(ctx, &args.saturation, &args.phases, args.rate).await;
+        return Ok(());
     }
 
-    // Run for specified duration
-    sleep(Duration::from_secs(args.duration)).await;
+    let subscribers = ctx.subscriptions.as_ref().map(|subscriptions| {
+        let symbols = ctx.symbols.iter().map(|spec| spec.name.clone()).collect();
+        let handles = subscriptions.start(&args.server, args.api_key.as_deref(), symbols);
+        (subscriptions.clone(), handles, Instant::now())
+    });
 
-    // Cancel all tasks
-    for handle in handles {
-        handle.abort();
-    }
+    // Connection setup falls into the warmup phase
+    let schedule = Arc::new(Schedule::new(
+        &args.phases,
+        Duration::from_secs(args.duration),
+        args.concurrency,
+    ));
+    args.chaos
+        .start(tokio::time::Instant::from_std(schedule.start()));
+    let stats = load::run(ctx, schedule.clone(), args.rate).await;
 
     // Print statistics
-    let total = *total_requests.lock().await;
-    let hist = histogram.lock().await;
-
-    println!("\nBenchmark Results:");
-    println!("Total Requests: {}", total);
-    println!("Average TPS: {:.2}", total as f64 / args.duration as f64);
-    println!("\nLatency Distribution (microseconds):");
-    println!("p50: {}", hist.value_at_percentile(50.0));
-    println!("p90: {}", hist.value_at_percentile(90.0));
-    println!("p95: {}", hist.value_at_percentile(95.0));
-    println!("p99: {}", hist.value_at_percentile(99.0));
-    println!("p99.9: {}", hist.value_at_percentile(99.9));
+    for phase in Phase::REPORTED {
+        let length = schedule.length(phase);
+        if length.is_zero() {
+            continue;
+        }
+        let empty = Stats::new();
+        let phase_stats = stats.phases.get(&phase).unwrap_or(&empty);
+        phase_stats.report(&phase.to_string(), length);
+    }
+    let warmup = schedule.length(Phase::Warmup).as_secs() as usize;
+    stats
+        .timeline
+        .report(warmup..schedule.total().as_secs() as usize);
+    stats.outage.report(&stats.timeline);
+    let subscriptions = match subscribers {
+        Some((subscriptions, handles, started)) => {
+            tokio::time::sleep(subscribe::EVENT_GRACE).await;
+            for handle in handles {
+                handle.abort();
+            }
+            Some(subscriptions.report(started.elapsed()))
+        }
+        None => None,
+    };
+    let verification = match verification {
+        Some((verifier, feed)) => {
+            let settled = verifier.settle().await;
+            feed.abort();
+            let timeout = Duration::from_millis(args.request_timeout_ms);
+            let queried = verifier.reconcile(&args.server, timeout).await;
+            Some(verifier.report(settled, queried))
+        }
+        None => None,
+    };
+    let issues = verification
+        .as_ref()
+        .map_or(0, |verification| verification.issue_count());
+
+    let mut report = RunReport::new(&stats, &schedule, args.rate, args.concurrency);
+    report.subscriptions = subscriptions;
+    report.verification = verification;
+    if let Some(path) = &args.output.output {
+        report.write(path, args.output.format)?;
+        println!("\nResults written to {}", path);
+    }
+    if let Some(path) = &args.output.timeseries {
+        report.write_timeseries(path)?;
+        println!("Timeseries written to {}", path);
+    }
+    if issues > 0 {
+        return Err(format!("verification found {} mismatches", issues).into());
+    }
 
     Ok(())
 }
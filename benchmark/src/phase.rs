@@ -0,0 +1,140 @@
+//! Run phases
+//!
+//! A run goes through warmup, ramp-up, steady-state and ramp-down. Warmup
+//! samples are discarded so connection setup and cold caches don't pollute the
+//! results; the other phases are reported separately. During ramp-up and
+//! ramp-down the number of active clients changes linearly.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Phase options
+#[derive(clap::Args, Debug, Clone)]
+#[command(about = None, long_about = None, next_help_heading = "Phases")]
+pub struct PhaseArgs {
+    /// Warmup in seconds at full concurrency, its samples are discarded
+    #[arg(long, default_value = "0")]
+    pub warmup: u64,
+
+    /// Seconds over which the active clients grow from one to full concurrency
+    #[arg(long, default_value = "0")]
+    pub ramp_up: u64,
+
+    /// Seconds over which the active clients shrink from full concurrency to one
+    #[arg(long, default_value = "0")]
+    pub ramp_down: u64,
+}
+
+/// Phase of a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// Samples are discarded
+    Warmup,
+    /// Active clients are growing
+    RampUp,
+    /// All clients are active
+    Steady,
+    /// Active clients are shrinking
+    RampDown,
+}
+
+impl Phase {
+    /// Reported phases, in run order
+    pub const REPORTED: [Phase; 3] = [Phase::RampUp, Phase::Steady, Phase::RampDown];
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Phase::Warmup => "warmup",
+            Phase::RampUp => "ramp-up",
+            Phase::Steady => "steady-state",
+            Phase::RampDown => "ramp-down",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Timeline of a run
+pub struct Schedule {
+    /// Start of the run
+    start: Instant,
+    /// Number of clients
+    concurrency: usize,
+    /// Length of each phase, in run order
+    phases: [(Phase, Duration); 4],
+}
+
+impl Schedule {
+    /// Creates the timeline of a run starting now
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - Phase options
+    /// * `steady` - Length of the steady-state phase
+    /// * `concurrency` - Number of clients
+    pub fn new(args: &PhaseArgs, steady: Duration, concurrency: usize) -> Self {
+        Self {
+            start: Instant::now(),
+            concurrency,
+            phases: [
+                (Phase::Warmup, Duration::from_secs(args.warmup)),
+                (Phase::RampUp, Duration::from_secs(args.ramp_up)),
+                (Phase::Steady, steady),
+                (Phase::RampDown, Duration::from_secs(args.ramp_down)),
+            ],
+        }
+    }
+
+    /// Start of the run
+    pub fn start(&self) -> Instant {
+        self.start
+    }
+
+    /// Total length of the run
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|(_, length)| *length).sum()
+    }
+
+    /// Length of a phase
+    pub fn length(&self, phase: Phase) -> Duration {
+        self.phases
+            .iter()
+            .find(|(p, _)| *p == phase)
+            .map(|(_, length)| *length)
+            .unwrap_or_default()
+    }
+
+    /// Returns the phase at a point in time and the time spent in it, None once the run is over
+    pub fn phase_at(&self, at: Instant) -> Option<(Phase, Duration)> {
+        let mut offset = at.saturating_duration_since(self.start);
+        for (phase, length) in self.phases {
+            if offset < length {
+                return Some((phase, offset));
+            }
+            offset -= length;
+        }
+        None
+    }
+
+    /// Checks whether a client takes part in the load at a point in time
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - Index of the client, starting at 0
+    /// * `at` - Point in time
+    pub fn is_active(&self, client: usize, at: Instant) -> bool {
+        let (phase, offset) = match self.phase_at(at) {
+            Some(current) => current,
+            None => return false,
+        };
+        let fraction = offset.as_secs_f64() / self.length(phase).as_secs_f64();
+        let extra = (self.concurrency - 1) as f64;
+        let active = match phase {
+            Phase::Warmup | Phase::Steady => self.concurrency,
+            Phase::RampUp => 1 + (extra * fraction) as usize,
+            Phase::RampDown => 1 + (extra * (1.0 - fraction)) as usize,
+        };
+        client < active
+    }
+}
@@ -0,0 +1,117 @@
+//! Price process
+//!
+//! Order prices are generated around a mid price that follows a random walk.
+//! Passive orders rest behind the spread at an exponentially distributed
+//! distance, aggressive orders cross it, so the run exercises matching,
+//! partial fills and book depth.
+
+use rand::Rng;
+use rand_distr::{Distribution, Exp, Normal};
+
+/// One basis point
+const BPS: f64 = 0.0001;
+
+/// Price options
+#[derive(clap::Args, Debug, Clone)]
+#[command(about = None, long_about = None, next_help_heading = "Prices")]
+pub struct PriceArgs {
+    /// Initial mid price
+    #[arg(long, default_value = "50000.0")]
+    pub mid_price: f64,
+
+    /// Quoted spread around the mid in basis points
+    #[arg(long, default_value = "10.0")]
+    pub spread_bps: f64,
+
+    /// Standard deviation of a mid price step in basis points, one step per order
+    #[arg(long, default_value = "1.0")]
+    pub volatility_bps: f64,
+
+    /// Mean distance of passive orders behind the spread in basis points
+    #[arg(long, default_value = "20.0")]
+    pub depth_bps: f64,
+
+    /// Fraction of limit orders priced through the spread
+    #[arg(long, default_value = "0.3")]
+    pub cross_ratio: f64,
+
+    /// Price tick size, prices are rounded to a multiple of it
+    #[arg(long, default_value = "0.01")]
+    pub tick_size: f64,
+}
+
+impl PriceArgs {
+    /// Checks that prices can be generated
+    pub fn validate(&self) -> Result<(), String> {
+        if self.mid_price <= 0.0 || self.tick_size <= 0.0 {
+            return Err("mid price and tick size must be positive".to_string());
+        }
+        if self.spread_bps < 0.0 || self.volatility_bps < 0.0 || self.depth_bps <= 0.0 {
+            return Err(
+                "spread and volatility must not be negative, depth must be positive".into(),
+            );
+        }
+        if !(0.0..=1.0).contains(&self.cross_ratio) {
+            return Err("cross ratio must be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Random walk mid price and the distributions orders are priced from
+pub struct PriceProcess {
+    /// Price options
+    args: PriceArgs,
+    /// Current mid price
+    mid: f64,
+    /// Relative mid price step
+    step: Normal<f64>,
+    /// Relative distance of an order from the touch
+    depth: Exp<f64>,
+}
+
+impl PriceProcess {
+    /// Creates a price process starting at the configured mid price
+    pub fn new(args: PriceArgs) -> Self {
+        Self {
+            mid: args.mid_price,
+            step: Normal::new(0.0, args.volatility_bps * BPS).unwrap(),
+            depth: Exp::new(1.0 / (args.depth_bps * BPS)).unwrap(),
+            args,
+        }
+    }
+
+    /// Advances the mid price and prices the next limit order
+    ///
+    /// # Arguments
+    ///
+    /// * `is_buy` - Whether the order is a buy
+    /// * `rng` - Random number generator
+    ///
+    /// # Returns
+    ///
+    /// Returns the order price, rounded to the tick size
+    pub fn next_price<R: Rng>(&mut self, is_buy: bool, rng: &mut R) -> f64 {
+        self.mid = (self.mid * (1.0 + self.step.sample(rng))).max(self.args.tick_size);
+        let half_spread = self.args.spread_bps * BPS / 2.0;
+        let distance = self.depth.sample(rng);
+        // Positive offsets move the price away from the opposite side of the book
+        let offset = if rng.gen_bool(self.args.cross_ratio) {
+            -half_spread - distance
+        } else {
+            half_spread + distance
+        };
+        let price = if is_buy {
+            self.mid * (1.0 - offset)
+        } else {
+            self.mid * (1.0 + offset)
+        };
+        let ticks = (price / self.args.tick_size).round().max(1.0);
+        ticks * self.args.tick_size
+    }
+
+    /// Number of decimals needed to print prices on the tick grid
+    pub fn decimals(&self) -> usize {
+        (-self.args.tick_size.log10()).ceil().max(0.0) as usize
+    }
+}
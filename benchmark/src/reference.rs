@@ -0,0 +1,158 @@
+//! Reference matcher
+//!
+//! A deliberately small order book that verification replays the run's orders
+//! through, in the order the cluster applied them. It knows only what the
+//! workload sends: orders match in price-time priority at the price of the
+//! resting order, market orders take any price, and the rest of an order stays
+//! on the book only if it is a good-till-canceled limit order.
+
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Side of an order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+/// Order as the reference matcher sees it
+#[derive(Debug, Clone)]
+pub struct RefOrder {
+    /// ID of the order
+    pub id: u64,
+    /// Side of the order
+    pub side: Side,
+    /// Limit price, None for market orders
+    pub price: Option<Decimal>,
+    /// Quantity of the order
+    pub quantity: Decimal,
+    /// Whether the rest of the order stays on the book after matching
+    pub resting: bool,
+}
+
+/// Fill of a placed order against a resting one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fill {
+    /// ID of the resting order
+    pub maker: u64,
+    /// Price of the resting order
+    pub price: Decimal,
+    /// Quantity traded
+    pub quantity: Decimal,
+}
+
+/// Order resting on the book
+struct Resting {
+    /// ID of the order
+    id: u64,
+    /// Quantity not filled yet
+    remaining: Decimal,
+}
+
+/// Order book of one symbol
+#[derive(Default)]
+pub struct Book {
+    /// Buy orders by price, in arrival order per price
+    bids: BTreeMap<Decimal, VecDeque<Resting>>,
+    /// Sell orders by price, in arrival order per price
+    asks: BTreeMap<Decimal, VecDeque<Resting>>,
+    /// Side and price of each resting order
+    resting: HashMap<u64, (Side, Decimal)>,
+}
+
+impl Book {
+    /// Matches an order against the book and rests what is left of it
+    ///
+    /// # Arguments
+    ///
+    /// * `order` - The order to place
+    ///
+    /// # Returns
+    ///
+    /// The fills of the order, in the order they happened
+    pub fn place(&mut self, order: &RefOrder) -> Vec<Fill> {
+        let mut fills = Vec::new();
+        let mut remaining = order.quantity;
+        while remaining > Decimal::ZERO {
+            let best = match order.side {
+                Side::Buy => self.asks.keys().next().copied(),
+                Side::Sell => self.bids.keys().next_back().copied(),
+            };
+            let Some(price) = best else {
+                break;
+            };
+            let crosses = match (order.side, order.price) {
+                (_, None) => true,
+                (Side::Buy, Some(limit)) => price <= limit,
+                (Side::Sell, Some(limit)) => price >= limit,
+            };
+            if !crosses {
+                break;
+            }
+            let levels = match order.side {
+                Side::Buy => &mut self.asks,
+                Side::Sell => &mut self.bids,
+            };
+            let level = levels.get_mut(&price).unwrap();
+            let maker = level.front_mut().unwrap();
+            let quantity = remaining.min(maker.remaining);
+            maker.remaining -= quantity;
+            remaining -= quantity;
+            fills.push(Fill {
+                maker: maker.id,
+                price,
+                quantity,
+            });
+            if maker.remaining.is_zero() {
+                let id = maker.id;
+                level.pop_front();
+                if level.is_empty() {
+                    levels.remove(&price);
+                }
+                self.resting.remove(&id);
+            }
+        }
+        if let Some(price) = order
+            .price
+            .filter(|_| order.resting && remaining > Decimal::ZERO)
+        {
+            let levels = match order.side {
+                Side::Buy => &mut self.bids,
+                Side::Sell => &mut self.asks,
+            };
+            levels.entry(price).or_default().push_back(Resting {
+                id: order.id,
+                remaining,
+            });
+            self.resting.insert(order.id, (order.side, price));
+        }
+        fills
+    }
+
+    /// Removes a resting order from the book
+    ///
+    /// # Arguments
+    ///
+    /// * `order_id` - ID of the order
+    ///
+    /// # Returns
+    ///
+    /// Whether the order was resting, i.e. whether a cancel succeeds
+    pub fn cancel(&mut self, order_id: u64) -> bool {
+        let Some((side, price)) = self.resting.remove(&order_id) else {
+            return false;
+        };
+        let levels = match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        if let Some(level) = levels.get_mut(&price) {
+            level.retain(|order| order.id != order_id);
+            if level.is_empty() {
+                levels.remove(&price);
+            }
+        }
+        true
+    }
+}
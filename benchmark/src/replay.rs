@@ -0,0 +1,335 @@
+//! Replay of recorded client order flow
+//!
+//! Reads a recording written by a match node with `record_path` set and sends
+//! the requests to a cluster at the recorded pace, optionally sped up. Sends are
+//! open loop like those of the load generator: each request goes out at its
+//! recorded offset divided by the speed-up, and latency is measured from that
+//! intended time. Symbol and balance requests are awaited before the replay moves
+//! on, so orders never overtake the creation of their symbol or their funding.
+
+use crate::load;
+use crate::pb::match_service_client::MatchServiceClient;
+use crate::pb::recorded_request::Request;
+use crate::pb::RecordedRequest;
+use crate::stats::Stats;
+use crate::workload::OpKind;
+use prost::Message;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, sleep_until, Instant};
+use tonic::transport::Channel;
+
+/// Longest wait for in-flight requests once the recording is exhausted
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Replay options
+#[derive(clap::Args, Debug, Clone)]
+pub struct ReplayArgs {
+    /// Recording file written by a node with `record_path` set
+    pub file: String,
+
+    /// Server addresses, list every node of the cluster to follow a failover
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        default_value = "grpc://127.0.0.1:4001"
+    )]
+    pub server: Vec<String>,
+
+    /// Speed-up over the recorded pace, 2 sends the flow twice as fast
+    #[arg(long, default_value = "1.0")]
+    pub speed: f64,
+
+    /// Maximum number of requests in flight, further sends are counted as missed
+    #[arg(long, default_value = "10000")]
+    pub max_inflight: usize,
+
+    /// Timeout of each request in milliseconds
+    #[arg(long, default_value = "5000")]
+    pub request_timeout_ms: u64,
+
+    /// API key sent with the requests of a tenant, as `tenant=key`
+    #[arg(long, value_delimiter = ',')]
+    pub api_key: Vec<String>,
+
+    /// Admin key sent with deposits, withdrawals, adjustments and risk limits
+    #[arg(long)]
+    pub admin_key: Option<String>,
+}
+
+impl ReplayArgs {
+    /// Parses the API keys by tenant
+    fn api_keys(&self) -> Result<HashMap<String, String>, String> {
+        self.api_key
+            .iter()
+            .map(|entry| {
+                entry
+                    .split_once('=')
+                    .map(|(tenant, key)| (tenant.to_string(), key.to_string()))
+                    .ok_or_else(|| format!("invalid api key {:?}, expected tenant=key", entry))
+            })
+            .collect()
+    }
+}
+
+/// Reads all records of a recording file
+///
+/// # Arguments
+///
+/// * `path` - Path of the recording
+///
+/// # Returns
+///
+/// Returns the records in recorded order, or an error if the file is unreadable
+/// or truncated mid-record
+pub fn load(path: &str) -> Result<Vec<RecordedRequest>, String> {
+    let data = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let mut buf = data.as_slice();
+    let mut records = Vec::new();
+    while !buf.is_empty() {
+        let record = RecordedRequest::decode_length_delimited(&mut buf)
+            .map_err(|e| format!("{} is corrupt after {} records: {}", path, records.len(), e))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Connections to the cluster, moving on to the next server after failures
+struct Servers {
+    /// Client of each server
+    clients: Vec<MatchServiceClient<Channel>>,
+    /// Index of the server requests are sent to
+    current: AtomicUsize,
+}
+
+impl Servers {
+    /// Client of the current server
+    fn client(&self) -> MatchServiceClient<Channel> {
+        self.clients[self.current.load(Ordering::Relaxed) % self.clients.len()].clone()
+    }
+
+    /// Moves on to the next server if the failed one is still the current server
+    fn fail(&self, failed: usize) {
+        let _ = self.current.compare_exchange(
+            failed,
+            (failed + 1) % self.clients.len(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Sends a recorded request
+///
+/// # Arguments
+///
+/// * `client` - Client of the server to send to
+/// * `request` - The recorded request
+/// * `api_key` - API key of the request's tenant, if any
+/// * `admin_key` - Admin key sent with funding, risk and contract requests, if any
+/// * `timeout` - Timeout of the request
+async fn send(
+    mut client: MatchServiceClient<Channel>,
+    request: Request,
+    api_key: Option<&str>,
+    admin_key: Option<&str>,
+    timeout: Duration,
+) -> Result<(), tonic::Status> {
+    fn wrap<T>(message: T, api_key: Option<&str>, timeout: Duration) -> tonic::Request<T> {
+        let mut request = load::authorized(message, api_key);
+        request.set_timeout(timeout);
+        request
+    }
+    match request {
+        Request::PlaceOrder(r) => client
+            .place_order(wrap(r, api_key, timeout))
+            .await
+            .map(|_| ()),
+        Request::PlaceOrders(r) => client
+            .place_orders(wrap(r, api_key, timeout))
+            .await
+            .map(|_| ()),
+        Request::CancelOrder(r) => client
+            .cancel_order(wrap(r, api_key, timeout))
+            .await
+            .map(|_| ()),
+        Request::AmendOrder(r) => client
+            .amend_order(wrap(r, api_key, timeout))
+            .await
+            .map(|_| ()),
+        Request::ReplaceOrder(r) => client
+            .replace_order(wrap(r, api_key, timeout))
+            .await
+            .map(|_| ()),
+        Request::CancelAllOrders(r) => client
+            .cancel_all_orders(wrap(r, api_key, timeout))
+            .await
+            .map(|_| ()),
+        Request::CreateSymbol(r) => client
+            .create_symbol(wrap(r, api_key, timeout))
+            .await
+            .map(|_| ()),
+        Request::UpdateSymbol(r) => client
+            .update_symbol(wrap(r, api_key, timeout))
+            .await
+            .map(|_| ()),
+        Request::RemoveSymbol(r) => client
+            .remove_symbol(wrap(r, api_key, timeout))
+            .await
+            .map(|_| ()),
+        Request::Deposit(r) => client
+            .deposit(load::with_admin_key(wrap(r, api_key, timeout), admin_key))
+            .await
+            .map(|_| ()),
+        Request::Withdraw(r) => client
+            .withdraw(load::with_admin_key(wrap(r, api_key, timeout), admin_key))
+            .await
+            .map(|_| ()),
+        Request::AdjustBalance(r) => client
+            .adjust_balance(load::with_admin_key(wrap(r, api_key, timeout), admin_key))
+            .await
+            .map(|_| ()),
+        Request::SetRiskLimits(r) => client
+            .set_risk_limits(load::with_admin_key(wrap(r, api_key, timeout), admin_key))
+            .await
+            .map(|_| ()),
+        Request::SetMarkPrice(r) => client
+            .set_mark_price(load::with_admin_key(wrap(r, api_key, timeout), admin_key))
+            .await
+            .map(|_| ()),
+        Request::Liquidate(r) => client
+            .liquidate(load::with_admin_key(wrap(r, api_key, timeout), admin_key))
+            .await
+            .map(|_| ()),
+    }
+}
+
+/// Replays a recording and prints the results
+///
+/// # Arguments
+///
+/// * `args` - Replay options
+///
+/// # Returns
+///
+/// Returns an error if the options or the recording are invalid
+pub async fn run(args: &ReplayArgs) -> Result<(), String> {
+    if args.speed <= 0.0 {
+        return Err("speed must be positive".to_string());
+    }
+    let api_keys = Arc::new(args.api_keys()?);
+    let records = load(&args.file)?;
+    let first = match records.first() {
+        Some(record) => record.received_at_us,
+        None => return Err(format!("{} holds no requests", args.file)),
+    };
+    let recorded =
+        Duration::from_micros(records.last().unwrap().received_at_us.saturating_sub(first));
+    println!(
+        "Replaying {} requests recorded over {:.1}s at {}x speed",
+        records.len(),
+        recorded.as_secs_f64(),
+        args.speed
+    );
+
+    let servers = Arc::new(Servers {
+        clients: args
+            .server
+            .iter()
+            .map(|addr| load::connect(addr))
+            .collect::<Result<_, _>>()?,
+        current: AtomicUsize::new(0),
+    });
+    let timeout = Duration::from_millis(args.request_timeout_ms);
+    let stats = Arc::new(Mutex::new(Stats::new()));
+    let inflight = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    for record in records {
+        let request = match record.request {
+            Some(request) => request,
+            None => continue,
+        };
+        let offset = Duration::from_micros(record.received_at_us.saturating_sub(first));
+        let intended = start + offset.div_f64(args.speed);
+        sleep_until(intended).await;
+        let server = servers.current.load(Ordering::Relaxed);
+        let (kind, symbol) = match &request {
+            Request::PlaceOrder(r) => (
+                OpKind::Place,
+                r.order
+                    .as_ref()
+                    .map(|o| o.symbol.clone())
+                    .unwrap_or_default(),
+            ),
+            Request::PlaceOrders(r) => (
+                OpKind::Place,
+                r.orders
+                    .first()
+                    .map(|o| o.symbol.clone())
+                    .unwrap_or_default(),
+            ),
+            Request::CancelOrder(r) => (OpKind::Cancel, r.symbol.clone()),
+            Request::AmendOrder(r) => (OpKind::Amend, r.symbol.clone()),
+            Request::ReplaceOrder(r) => (OpKind::Amend, r.symbol.clone()),
+            Request::CancelAllOrders(r) => (OpKind::Cancel, r.symbol.clone()),
+            Request::CreateSymbol(_)
+            | Request::UpdateSymbol(_)
+            | Request::RemoveSymbol(_)
+            | Request::Deposit(_)
+            | Request::Withdraw(_)
+            | Request::AdjustBalance(_)
+            | Request::SetRiskLimits(_)
+            | Request::SetMarkPrice(_)
+            | Request::Liquidate(_) => {
+                let api_key = api_keys.get(&record.tenant).map(String::as_str);
+                let admin_key = args.admin_key.as_deref();
+                if let Err(e) = send(servers.client(), request, api_key, admin_key, timeout).await {
+                    eprintln!("setup request failed: {}", e);
+                    if load::is_failover_error(e.code()) {
+                        servers.fail(server);
+                    }
+                }
+                continue;
+            }
+        };
+        if inflight.fetch_add(1, Ordering::Relaxed) >= args.max_inflight {
+            inflight.fetch_sub(1, Ordering::Relaxed);
+            stats.lock().await.record_missed();
+            continue;
+        }
+        let client = servers.client();
+        let servers = servers.clone();
+        let api_keys = api_keys.clone();
+        let stats = stats.clone();
+        let inflight = inflight.clone();
+        tokio::spawn(async move {
+            let api_key = api_keys.get(&record.tenant).map(String::as_str);
+            let result = send(client, request, api_key, None, timeout).await;
+            let latency = intended.elapsed();
+            let mut stats = stats.lock().await;
+            match result {
+                Ok(()) => stats.record(kind, &symbol, latency),
+                Err(e) => {
+                    eprintln!("{} request failed: {}", kind, e);
+                    if load::is_failover_error(e.code()) {
+                        servers.fail(server);
+                    }
+                    stats.record_error(kind, e.code());
+                }
+            }
+            inflight.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    // Wait for the last responses so slow requests at the end are not lost
+    let drain_start = Instant::now();
+    while inflight.load(Ordering::Relaxed) > 0 && drain_start.elapsed() < DRAIN_TIMEOUT {
+        sleep(Duration::from_millis(10)).await;
+    }
+    stats.lock().await.report("replay", start.elapsed());
+    Ok(())
+}
@@ -0,0 +1,367 @@
+//! Machine-readable results and run comparison
+//!
+//! Results are written as JSON, with the full latency histograms and the
+//! per-second timeseries, or as CSV with one row per phase and operation kind.
+//! Two JSON result files can be compared to track performance regressions.
+
+use crate::load::RunResult;
+use crate::phase::{Phase, Schedule};
+use crate::subscribe::SubscriptionReport;
+use crate::verify::VerificationReport;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Percentiles included in every operation report
+pub const PERCENTILES: [(&str, f64); 5] = [
+    ("p50", 50.0),
+    ("p90", 90.0),
+    ("p95", 95.0),
+    ("p99", 99.0),
+    ("p99.9", 99.9),
+];
+
+/// Output format of the results
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum Format {
+    /// Full results as JSON
+    Json,
+    /// Summary rows as CSV
+    Csv,
+}
+
+/// Result output options
+#[derive(clap::Args, Debug, Clone)]
+#[command(about = None, long_about = None, next_help_heading = "Output")]
+pub struct OutputArgs {
+    /// File the results are written to
+    #[arg(long)]
+    pub output: Option<String>,
+
+    /// Format of the results file
+    #[arg(long, value_enum, default_value = "json")]
+    pub format: Format,
+
+    /// File the per-second timeseries is written to as CSV
+    #[arg(long)]
+    pub timeseries: Option<String>,
+}
+
+/// Results of a run
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunReport {
+    /// Start of the run in seconds since the Unix epoch
+    pub started_at: u64,
+    /// Target requests per second
+    pub rate: f64,
+    /// Number of clients
+    pub concurrency: usize,
+    /// Results of each reported phase
+    pub phases: Vec<PhaseReport>,
+    /// Results of each second of the run, including the warmup
+    pub timeseries: Vec<SecondReport>,
+    /// Results of the subscribers, if the run had any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subscriptions: Option<SubscriptionReport>,
+    /// Results of the verification, if the run was verified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification: Option<VerificationReport>,
+}
+
+/// Results of one second of a run
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SecondReport {
+    /// Seconds since the start of the run
+    pub second: u64,
+    /// Successful requests completed in this second
+    pub requests: u64,
+    /// Median latency in microseconds
+    pub p50: u64,
+    /// 99th percentile latency in microseconds
+    pub p99: u64,
+    /// Failed requests by gRPC status
+    pub errors: BTreeMap<String, u64>,
+}
+
+/// Results of one phase
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PhaseReport {
+    /// Name of the phase
+    pub phase: String,
+    /// Length of the phase in seconds
+    pub seconds: f64,
+    /// Successful requests
+    pub requests: u64,
+    /// Average successful requests per second
+    pub tps: f64,
+    /// Requests not sent because the in-flight limit was reached
+    pub missed: u64,
+    /// Results of each operation kind
+    pub ops: Vec<OpReport>,
+}
+
+/// Results of one operation kind
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpReport {
+    /// Name of the operation kind
+    pub op: String,
+    /// Successful requests
+    pub requests: u64,
+    /// Failed requests
+    pub errors: u64,
+    /// Failed requests by gRPC status
+    #[serde(default)]
+    pub errors_by_status: BTreeMap<String, u64>,
+    /// Average successful requests per second
+    pub tps: f64,
+    /// Latency percentiles in microseconds by name
+    pub percentiles: BTreeMap<String, u64>,
+    /// Latency histogram as (highest value in bucket in microseconds, count) pairs
+    pub histogram: Vec<(u64, u64)>,
+}
+
+impl RunReport {
+    /// Builds the report of a run
+    ///
+    /// # Arguments
+    ///
+    /// * `result` - Results of the run
+    /// * `schedule` - Phases of the run
+    /// * `rate` - Target requests per second
+    /// * `concurrency` - Number of clients
+    pub fn new(result: &RunResult, schedule: &Schedule, rate: f64, concurrency: usize) -> Self {
+        let elapsed = schedule.start().elapsed();
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .saturating_sub(elapsed)
+            .as_secs();
+        let phases = Phase::REPORTED
+            .iter()
+            .filter(|phase| !schedule.length(**phase).is_zero())
+            .map(|phase| {
+                let seconds = schedule.length(*phase).as_secs_f64();
+                let stats = result.phases.get(phase);
+                let ops = stats
+                    .map(|stats| {
+                        stats
+                            .ops()
+                            .map(|(op, op_stats)| {
+                                let hist = &op_stats.histogram;
+                                OpReport {
+                                    op: op.to_string(),
+                                    requests: hist.len(),
+                                    errors: op_stats.errors,
+                                    errors_by_status: op_stats.errors_by_status.clone(),
+                                    tps: hist.len() as f64 / seconds,
+                                    percentiles: PERCENTILES
+                                        .iter()
+                                        .map(|(name, p)| {
+                                            (name.to_string(), hist.value_at_percentile(*p))
+                                        })
+                                        .collect(),
+                                    histogram: hist
+                                        .iter_recorded()
+                                        .map(|v| (v.value_iterated_to(), v.count_at_value()))
+                                        .collect(),
+                                }
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let requests = stats.map(|s| s.requests()).unwrap_or(0);
+                PhaseReport {
+                    phase: phase.to_string(),
+                    seconds,
+                    requests,
+                    tps: requests as f64 / seconds,
+                    missed: stats.map(|s| s.missed()).unwrap_or(0),
+                    ops,
+                }
+            })
+            .collect();
+        Self {
+            started_at,
+            rate,
+            concurrency,
+            phases,
+            timeseries: result
+                .timeline
+                .seconds()
+                .iter()
+                .enumerate()
+                .map(|(second, s)| SecondReport {
+                    second: second as u64,
+                    requests: s.histogram.len(),
+                    p50: s.histogram.value_at_percentile(50.0),
+                    p99: s.histogram.value_at_percentile(99.0),
+                    errors: s.errors.clone(),
+                })
+                .collect(),
+            subscriptions: None,
+            verification: None,
+        }
+    }
+
+    /// Writes the report to a file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Destination file
+    /// * `format` - Output format
+    pub fn write(&self, path: &str, format: Format) -> Result<(), String> {
+        let content = match format {
+            Format::Json => serde_json::to_string_pretty(self).map_err(|e| e.to_string())?,
+            Format::Csv => self.to_csv(),
+        };
+        std::fs::write(path, content).map_err(|e| format!("failed to write {}: {}", path, e))
+    }
+
+    /// Writes the timeseries as CSV, with one column per gRPC status seen
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Destination file
+    pub fn write_timeseries(&self, path: &str) -> Result<(), String> {
+        let statuses: BTreeSet<&String> = self
+            .timeseries
+            .iter()
+            .flat_map(|s| s.errors.keys())
+            .collect();
+        let mut csv = String::from("second,requests,p50_us,p99_us");
+        for status in &statuses {
+            let _ = write!(csv, ",errors_{}", status);
+        }
+        csv.push('\n');
+        for second in &self.timeseries {
+            let _ = write!(
+                csv,
+                "{},{},{},{}",
+                second.second, second.requests, second.p50, second.p99
+            );
+            for status in &statuses {
+                let _ = write!(csv, ",{}", second.errors.get(*status).unwrap_or(&0));
+            }
+            csv.push('\n');
+        }
+        std::fs::write(path, csv).map_err(|e| format!("failed to write {}: {}", path, e))
+    }
+
+    /// Loads a JSON report
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        serde_json::from_str(&content).map_err(|e| format!("invalid result file {}: {}", path, e))
+    }
+
+    /// Formats one row per phase and operation kind
+    fn to_csv(&self) -> String {
+        let mut csv = String::from("phase,op,requests,errors,tps");
+        for (name, _) in PERCENTILES {
+            let _ = write!(csv, ",{}_us", name);
+        }
+        csv.push('\n');
+        for phase in &self.phases {
+            for op in &phase.ops {
+                let _ = write!(
+                    csv,
+                    "{},{},{},{},{:.2}",
+                    phase.phase, op.op, op.requests, op.errors, op.tps
+                );
+                for (name, _) in PERCENTILES {
+                    let _ = write!(csv, ",{}", op.percentiles.get(name).unwrap_or(&0));
+                }
+                csv.push('\n');
+            }
+        }
+        csv
+    }
+}
+
+/// Run comparison options
+#[derive(clap::Args, Debug, Clone)]
+pub struct CompareArgs {
+    /// Result file of the baseline run
+    pub baseline: String,
+
+    /// Result file of the candidate run
+    pub candidate: String,
+
+    /// Change in percent beyond which a metric counts as a regression
+    #[arg(long, default_value = "5.0")]
+    pub threshold: f64,
+}
+
+/// Compares two result files and prints the change of each metric
+///
+/// Lower throughput and higher latency or error counts beyond the threshold are
+/// marked as regressions.
+///
+/// # Returns
+///
+/// Returns the number of regressions found
+pub fn compare(args: &CompareArgs) -> Result<usize, String> {
+    let baseline = RunReport::load(&args.baseline)?;
+    let candidate = RunReport::load(&args.candidate)?;
+    let mut regressions = 0;
+    let mut line = |name: String, old: f64, new: f64, higher_is_better: bool| {
+        let change = if old == 0.0 {
+            if new == 0.0 {
+                0.0
+            } else {
+                100.0
+            }
+        } else {
+            (new - old) * 100.0 / old
+        };
+        let worse = if higher_is_better { -change } else { change };
+        let marker = if worse > args.threshold {
+            regressions += 1;
+            "  << REGRESSION"
+        } else {
+            ""
+        };
+        println!(
+            "{:<32} {:>14.2} {:>14.2} {:>+9.2}%{}",
+            name, old, new, change, marker
+        );
+    };
+
+    println!(
+        "{:<32} {:>14} {:>14} {:>10}",
+        "metric", "baseline", "candidate", "change"
+    );
+    for new_phase in &candidate.phases {
+        let old_phase = match baseline.phases.iter().find(|p| p.phase == new_phase.phase) {
+            Some(phase) => phase,
+            None => continue,
+        };
+        line(
+            format!("{} tps", new_phase.phase),
+            old_phase.tps,
+            new_phase.tps,
+            true,
+        );
+        for new_op in &new_phase.ops {
+            let old_op = match old_phase.ops.iter().find(|o| o.op == new_op.op) {
+                Some(op) => op,
+                None => continue,
+            };
+            let prefix = format!("{} {}", new_phase.phase, new_op.op);
+            line(format!("{} tps", prefix), old_op.tps, new_op.tps, true);
+            line(
+                format!("{} errors", prefix),
+                old_op.errors as f64,
+                new_op.errors as f64,
+                false,
+            );
+            for (name, _) in PERCENTILES {
+                let old = *old_op.percentiles.get(name).unwrap_or(&0) as f64;
+                let new = *new_op.percentiles.get(name).unwrap_or(&0) as f64;
+                line(format!("{} {} us", prefix, name), old, new, false);
+            }
+        }
+    }
+    Ok(regressions)
+}
@@ -0,0 +1,86 @@
+//! Saturation search
+//!
+//! Raises the offered rate step by step until the server no longer keeps up,
+//! either because achieved throughput falls behind the target or because p99
+//! latency exceeds the limit, and reports the highest sustained rate.
+
+use crate::load::{self, RunContext};
+use crate::phase::{Phase, PhaseArgs, Schedule};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Fraction of the target rate that must be achieved for a step to count as sustained
+const SUSTAINED_RATIO: f64 = 0.95;
+
+/// Saturation search options
+#[derive(clap::Args, Debug, Clone)]
+#[command(about = None, long_about = None, next_help_heading = "Saturation search")]
+pub struct SaturationArgs {
+    /// Search for the saturation point instead of running at a single rate
+    #[arg(long, default_value_t = false)]
+    pub saturation: bool,
+
+    /// Rate increase between search steps, defaults to the starting rate
+    #[arg(long)]
+    pub rate_step: Option<f64>,
+
+    /// Length of each search step in seconds
+    #[arg(long, default_value = "10")]
+    pub step_duration: u64,
+
+    /// Highest acceptable p99 latency in milliseconds
+    #[arg(long, default_value = "100")]
+    pub max_p99_ms: u64,
+}
+
+/// Runs search steps of increasing rate until the server saturates
+///
+/// The warmup of `phases` runs before the first step only; ramps are not used.
+///
+/// # Arguments
+///
+/// * `ctx` - Settings and shared state of the clients
+/// * `args` - Saturation search options
+/// * `phases` - Phase options
+/// * `rate` - Rate of the first step
+pub async fn search(ctx: Arc<RunContext>, args: &SaturationArgs, phases: &PhaseArgs, rate: f64) {
+    let step = args.rate_step.unwrap_or(rate);
+    let mut rate = rate;
+    let mut sustained = None;
+    let mut warmup = phases.warmup;
+    println!("\nSaturation Search:");
+    loop {
+        let step_phases = PhaseArgs {
+            warmup,
+            ramp_up: 0,
+            ramp_down: 0,
+        };
+        warmup = 0;
+        let schedule = Arc::new(Schedule::new(
+            &step_phases,
+            Duration::from_secs(args.step_duration),
+            ctx.concurrency,
+        ));
+        let stats = load::run(ctx.clone(), schedule, rate).await;
+        let (achieved, p99) = match stats.phases.get(&Phase::Steady) {
+            Some(steady) => (
+                steady.requests() as f64 / args.step_duration as f64,
+                steady.percentile(99.0),
+            ),
+            None => (0.0, 0),
+        };
+        println!(
+            "target {:.0}/s achieved {:.2}/s p99 {} us",
+            rate, achieved, p99
+        );
+        if achieved < rate * SUSTAINED_RATIO || p99 > args.max_p99_ms * 1000 {
+            break;
+        }
+        sustained = Some(rate);
+        rate += step;
+    }
+    match sustained {
+        Some(rate) => println!("Saturation point: {:.0} requests/s", rate),
+        None => println!("Saturation point: below {:.0} requests/s", rate),
+    }
+}
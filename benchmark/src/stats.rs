@@ -0,0 +1,264 @@
+//! Benchmark statistics
+//!
+//! Collects latency histograms and error counts per operation kind, the
+//! latency of each symbol and a per-second timeline of the run.
+
+use crate::workload::OpKind;
+use hdrhistogram::Histogram;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::Duration;
+
+/// Number of symbols listed in the per-symbol report
+const SYMBOLS_REPORTED: usize = 10;
+
+/// Results of one second of a run
+pub struct Second {
+    /// Latency of the successful requests completed in this second in microseconds
+    pub histogram: Histogram<u64>,
+    /// Failed requests completed in this second by gRPC status
+    pub errors: BTreeMap<String, u64>,
+}
+
+/// Throughput, latency and errors per second of a run
+///
+/// Requests are attributed to the second they completed in, so stalls show up
+/// as seconds without throughput followed by a latency spike.
+#[derive(Default)]
+pub struct Timeline {
+    /// Results of each second since the start of the run
+    seconds: Vec<Second>,
+}
+
+impl Timeline {
+    /// Returns the results of the second a request completed in
+    fn second(&mut self, offset: Duration) -> &mut Second {
+        let second = offset.as_secs() as usize;
+        while self.seconds.len() <= second {
+            self.seconds.push(Second {
+                histogram: Histogram::<u64>::new(3).unwrap(),
+                errors: BTreeMap::new(),
+            });
+        }
+        &mut self.seconds[second]
+    }
+
+    /// Records a successful request
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Completion time since the start of the run
+    /// * `latency` - Latency of the request
+    pub fn record(&mut self, offset: Duration, latency: Duration) {
+        self.second(offset)
+            .histogram
+            .saturating_record(latency.as_micros() as u64);
+    }
+
+    /// Records a failed request
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Completion time since the start of the run
+    /// * `code` - gRPC status of the failure
+    pub fn record_error(&mut self, offset: Duration, code: tonic::Code) {
+        *self
+            .second(offset)
+            .errors
+            .entry(format!("{:?}", code))
+            .or_insert(0) += 1;
+    }
+
+    /// Results of each second of the run
+    pub fn seconds(&self) -> &[Second] {
+        &self.seconds
+    }
+
+    /// Prints the seconds with the lowest throughput and the highest p99 latency
+    ///
+    /// # Arguments
+    ///
+    /// * `measured` - Seconds to consider, leaving out the warmup and the drain after the run
+    pub fn report(&self, measured: Range<usize>) {
+        let seconds: Vec<(usize, &Second)> = self
+            .seconds
+            .iter()
+            .enumerate()
+            .skip(measured.start)
+            .take(measured.len())
+            .collect();
+        let slowest = seconds
+            .iter()
+            .max_by_key(|(_, s)| s.histogram.value_at_percentile(99.0));
+        let emptiest = seconds.iter().min_by_key(|(_, s)| s.histogram.len());
+        let errors: u64 = seconds.iter().flat_map(|(_, s)| s.errors.values()).sum();
+        println!("\nTimeline:");
+        if let Some((second, s)) = slowest {
+            println!(
+                "Highest p99: {} us at second {}",
+                s.histogram.value_at_percentile(99.0),
+                second
+            );
+        }
+        if let Some((second, s)) = emptiest {
+            println!("Lowest TPS: {} at second {}", s.histogram.len(), second);
+        }
+        println!(
+            "Seconds with errors: {} ({} errors)",
+            seconds.iter().filter(|(_, s)| !s.errors.is_empty()).count(),
+            errors
+        );
+    }
+}
+
+/// Results of one operation kind
+pub struct OpStats {
+    /// Latency of successful requests in microseconds
+    pub histogram: Histogram<u64>,
+    /// Number of failed requests
+    pub errors: u64,
+    /// Number of failed requests by gRPC status
+    pub errors_by_status: BTreeMap<String, u64>,
+}
+
+impl OpStats {
+    /// Creates empty statistics
+    fn new() -> Self {
+        Self {
+            histogram: Histogram::<u64>::new(3).unwrap(),
+            errors: 0,
+            errors_by_status: BTreeMap::new(),
+        }
+    }
+}
+
+/// Results of the whole run, by operation kind and by symbol
+pub struct Stats {
+    /// Statistics of each operation kind that was issued
+    ops: BTreeMap<OpKind, OpStats>,
+    /// Latency of successful requests in microseconds by symbol
+    symbols: BTreeMap<String, Histogram<u64>>,
+    /// Requests not sent because the in-flight limit was reached
+    missed: u64,
+}
+
+impl Stats {
+    /// Creates empty statistics
+    pub fn new() -> Self {
+        Self {
+            ops: BTreeMap::new(),
+            symbols: BTreeMap::new(),
+            missed: 0,
+        }
+    }
+
+    /// Records a successful request
+    pub fn record(&mut self, op: OpKind, symbol: &str, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        let stats = self.ops.entry(op).or_insert_with(OpStats::new);
+        stats.histogram.saturating_record(micros);
+        if !self.symbols.contains_key(symbol) {
+            self.symbols
+                .insert(symbol.to_string(), Histogram::<u64>::new(3).unwrap());
+        }
+        self.symbols
+            .get_mut(symbol)
+            .unwrap()
+            .saturating_record(micros);
+    }
+
+    /// Statistics of each operation kind that was issued
+    pub fn ops(&self) -> impl Iterator<Item = (&OpKind, &OpStats)> {
+        self.ops.iter()
+    }
+
+    /// Number of requests not sent because the in-flight limit was reached
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+
+    /// Records a failed request
+    pub fn record_error(&mut self, op: OpKind, code: tonic::Code) {
+        let stats = self.ops.entry(op).or_insert_with(OpStats::new);
+        stats.errors += 1;
+        *stats
+            .errors_by_status
+            .entry(format!("{:?}", code))
+            .or_insert(0) += 1;
+    }
+
+    /// Records a request that was not sent because the in-flight limit was reached
+    pub fn record_missed(&mut self) {
+        self.missed += 1;
+    }
+
+    /// Number of successful requests
+    pub fn requests(&self) -> u64 {
+        self.ops.values().map(|s| s.histogram.len()).sum()
+    }
+
+    /// Latency percentile in microseconds across all operation kinds
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let mut all = Histogram::<u64>::new(3).unwrap();
+        for stats in self.ops.values() {
+            all.add(&stats.histogram).unwrap();
+        }
+        all.value_at_percentile(percentile)
+    }
+
+    /// Prints the results
+    ///
+    /// # Arguments
+    ///
+    /// * `phase` - Name of the measured phase
+    /// * `elapsed` - Length of the measured phase
+    pub fn report(&self, phase: &str, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let total = self.requests();
+        println!("\nBenchmark Results ({}):", phase);
+        println!("Total Requests: {}", total);
+        println!("Average TPS: {:.2}", total as f64 / seconds);
+        println!("Missed (in-flight limit): {}", self.missed);
+        for op in OpKind::ALL {
+            let stats = match self.ops.get(&op) {
+                Some(stats) => stats,
+                None => continue,
+            };
+            let hist = &stats.histogram;
+            println!("\n[{}]", op);
+            println!("Requests: {}", hist.len());
+            println!("Errors: {}", stats.errors);
+            for (status, count) in &stats.errors_by_status {
+                println!("  {}: {}", status, count);
+            }
+            println!("TPS: {:.2}", hist.len() as f64 / seconds);
+            println!("Latency Distribution (microseconds):");
+            println!("p50: {}", hist.value_at_percentile(50.0));
+            println!("p90: {}", hist.value_at_percentile(90.0));
+            println!("p95: {}", hist.value_at_percentile(95.0));
+            println!("p99: {}", hist.value_at_percentile(99.0));
+            println!("p99.9: {}", hist.value_at_percentile(99.9));
+        }
+        if self.symbols.len() > 1 {
+            self.report_symbols(seconds);
+        }
+    }
+
+    /// Prints the busiest symbols with their share of the load and latency
+    fn report_symbols(&self, seconds: f64) {
+        let total: u64 = self.symbols.values().map(|h| h.len()).sum();
+        let mut symbols: Vec<_> = self.symbols.iter().collect();
+        symbols.sort_by_key(|(_, hist)| std::cmp::Reverse(hist.len()));
+        println!("\nBusiest Symbols (of {}):", symbols.len());
+        for (symbol, hist) in symbols.iter().take(SYMBOLS_REPORTED) {
+            println!(
+                "{}: {:.1}% TPS {:.2} p50 {} p99 {}",
+                symbol,
+                hist.len() as f64 * 100.0 / total as f64,
+                hist.len() as f64 / seconds,
+                hist.value_at_percentile(50.0),
+                hist.value_at_percentile(99.0)
+            );
+        }
+    }
+}
@@ -0,0 +1,396 @@
+//! Subscription load
+//!
+//! Opens streams next to the order load and measures how fast the events of
+//! the placed orders reach the subscribers. Each order is registered with the
+//! time it is sent, and the first event a subscriber receives that names the
+//! order gives its propose-to-event latency. Subscribers are spread across the
+//! listed servers, every node serves the streams, and every event received
+//! counts towards the fan-out throughput.
+//!
+//! Orders are recognized by their order ID in drop copy reports and in the
+//! change feed. Depth streams are subscribed in L3, whose order IDs are
+//! anonymized per tenant and symbol (see the node's `depth_order_id_key`), so
+//! the anonymized ID is derived the same way when the order is sent. Only orders
+//! that rest within the subscribed levels show up in depth.
+
+use crate::load;
+use crate::pb::change_event::Change;
+use crate::pb::{
+    ChangeEvent, Depth, DepthMode, ExecutionReport, SubscribeChangesRequest, SubscribeDepthRequest,
+    SubscribeDropCopyRequest,
+};
+use hdrhistogram::Histogram;
+use hmac::{Hmac, Mac};
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tonic::Streaming;
+
+/// Time the events of the last orders are given to arrive once the run is over
+pub const EVENT_GRACE: Duration = Duration::from_secs(1);
+
+/// Stream the subscribers open
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    /// L3 depth of every benchmark symbol, `SubscribeDepth`
+    Depth,
+    /// Execution reports of the tenant's drop copy accounts, `SubscribeDropCopy`
+    DropCopy,
+    /// Commands applied on the node, `SubscribeChanges`
+    Changes,
+}
+
+impl fmt::Display for StreamKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            StreamKind::Depth => "depth",
+            StreamKind::DropCopy => "drop-copy",
+            StreamKind::Changes => "changes",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Subscription options
+#[derive(clap::Args, Debug, Clone)]
+#[command(about = None, long_about = None, next_help_heading = "Subscriptions")]
+pub struct SubscribeArgs {
+    /// Number of streams opened for the run, 0 runs without subscribers
+    #[arg(long, default_value = "0", conflicts_with = "saturation")]
+    pub subscribers: usize,
+
+    /// Stream each subscriber opens; drop-copy needs the orders placed for drop
+    /// copy accounts, see `--accounts`
+    #[arg(long, value_enum, default_value = "depth")]
+    pub stream: StreamKind,
+
+    /// Price levels per side of depth streams
+    #[arg(long, default_value = "20")]
+    pub depth_levels: u32,
+
+    /// Key the nodes anonymize L3 order IDs with, their `depth_order_id_key`
+    #[arg(long, default_value = "")]
+    pub depth_order_id_key: String,
+
+    /// Tenant the API key belongs to, part of anonymized L3 order IDs
+    #[arg(long, default_value = "default")]
+    pub tenant: String,
+
+    /// Admin key sent with drop copy and change subscriptions, also those of `--verify`
+    #[arg(long)]
+    pub admin_key: Option<String>,
+}
+
+/// Orders sent during the run, by the key their events are recognized by
+pub struct Subscriptions {
+    /// Options of the subscribers
+    args: SubscribeArgs,
+    /// Send time of each order by key
+    sent: Mutex<HashMap<String, Instant>>,
+    /// Results of each subscriber
+    subscribers: Vec<Arc<Mutex<SubscriberStats>>>,
+}
+
+/// Results of one subscriber
+struct SubscriberStats {
+    /// Messages received
+    events: u64,
+    /// Propose-to-event latency of the orders seen in microseconds
+    histogram: Histogram<u64>,
+    /// Keys of the orders seen so far
+    seen: HashSet<String>,
+    /// Why the stream failed or ended before the run did, if it did
+    error: Option<String>,
+}
+
+/// Results of the subscribers of a run
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubscriptionReport {
+    /// Stream the subscribers opened
+    pub stream: String,
+    /// Number of subscribers
+    pub subscribers: usize,
+    /// Messages received by all subscribers
+    pub events: u64,
+    /// Messages received per second by all subscribers
+    pub events_per_sec: f64,
+    /// Messages received per second by the slowest subscriber
+    pub min_events_per_sec: f64,
+    /// Orders sent while subscribed
+    pub orders: u64,
+    /// Orders seen, summed over the subscribers
+    pub deliveries: u64,
+    /// Subscribers whose stream failed or ended before the run did
+    pub failed: usize,
+    /// Propose-to-event latency percentiles in microseconds by name
+    pub percentiles: BTreeMap<String, u64>,
+}
+
+impl Subscriptions {
+    /// Creates the registry of a run
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - Subscription options
+    ///
+    /// # Returns
+    ///
+    /// None if the run has no subscribers
+    pub fn new(args: &SubscribeArgs) -> Option<Arc<Self>> {
+        (args.subscribers > 0).then(|| {
+            Arc::new(Self {
+                args: args.clone(),
+                sent: Mutex::new(HashMap::new()),
+                subscribers: (0..args.subscribers)
+                    .map(|_| {
+                        Arc::new(Mutex::new(SubscriberStats {
+                            events: 0,
+                            histogram: Histogram::<u64>::new(3).unwrap(),
+                            seen: HashSet::new(),
+                            error: None,
+                        }))
+                    })
+                    .collect(),
+            })
+        })
+    }
+
+    /// Registers an order right before it is sent
+    ///
+    /// # Arguments
+    ///
+    /// * `symbol` - Symbol of the order
+    /// * `order_id` - ID of the order
+    pub fn sent(&self, symbol: &str, order_id: u64) {
+        let key = match self.args.stream {
+            StreamKind::Depth => anonymize(
+                self.args.depth_order_id_key.as_bytes(),
+                &self.args.tenant,
+                symbol,
+                &order_id.to_string(),
+            ),
+            StreamKind::DropCopy | StreamKind::Changes => order_id.to_string(),
+        };
+        self.sent.lock().unwrap().insert(key, Instant::now());
+    }
+
+    /// Opens the streams, spread across the servers
+    ///
+    /// # Arguments
+    ///
+    /// * `servers` - Server addresses
+    /// * `api_key` - API key sent with the subscriptions
+    /// * `symbols` - Symbols of the run, subscribed to by depth streams
+    ///
+    /// # Returns
+    ///
+    /// The subscriber tasks, they run until aborted
+    pub fn start(
+        self: &Arc<Self>,
+        servers: &[String],
+        api_key: Option<&str>,
+        symbols: Vec<String>,
+    ) -> Vec<JoinHandle<()>> {
+        (0..self.subscribers.len())
+            .map(|index| {
+                let subscriptions = self.clone();
+                let server = servers[index % servers.len()].clone();
+                let api_key = api_key.map(str::to_string);
+                let symbols = symbols.clone();
+                tokio::spawn(async move {
+                    let stats = subscriptions.subscribers[index].clone();
+                    let result = subscriptions
+                        .receive(&server, api_key.as_deref(), symbols, &stats)
+                        .await;
+                    let error = match result {
+                        Ok(()) => "stream ended".to_string(),
+                        Err(e) => e,
+                    };
+                    eprintln!("subscriber {} on {}: {}", index, server, error);
+                    stats.lock().unwrap().error = Some(error);
+                })
+            })
+            .collect()
+    }
+
+    /// Opens one stream and records its messages until it ends
+    async fn receive(
+        &self,
+        server: &str,
+        api_key: Option<&str>,
+        symbols: Vec<String>,
+        stats: &Mutex<SubscriberStats>,
+    ) -> Result<(), String> {
+        let mut client = load::connect(server)?;
+        let status = |e: tonic::Status| e.to_string();
+        match self.args.stream {
+            StreamKind::Depth => {
+                let request = SubscribeDepthRequest {
+                    symbols,
+                    levels: self.args.depth_levels,
+                    mode: DepthMode::L3 as i32,
+                };
+                let stream = client
+                    .subscribe_depth(self.request(request, api_key))
+                    .await
+                    .map_err(status)?;
+                self.record(stream.into_inner(), stats, depth_keys).await
+            }
+            StreamKind::DropCopy => {
+                let stream = client
+                    .subscribe_drop_copy(self.request(SubscribeDropCopyRequest::default(), api_key))
+                    .await
+                    .map_err(status)?;
+                self.record(stream.into_inner(), stats, report_keys).await
+            }
+            StreamKind::Changes => {
+                let stream = client
+                    .subscribe_changes(self.request(SubscribeChangesRequest::default(), api_key))
+                    .await
+                    .map_err(status)?;
+                self.record(stream.into_inner(), stats, change_keys).await
+            }
+        }
+    }
+
+    /// Wraps a subscription into a request carrying the keys
+    fn request<T>(&self, message: T, api_key: Option<&str>) -> tonic::Request<T> {
+        let request = load::authorized(message, api_key);
+        load::with_admin_key(request, self.args.admin_key.as_deref())
+    }
+
+    /// Records the messages of a stream until it ends
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The opened stream
+    /// * `stats` - Results of the subscriber
+    /// * `keys` - Keys of the orders a message names
+    async fn record<T>(
+        &self,
+        mut stream: Streaming<T>,
+        stats: &Mutex<SubscriberStats>,
+        keys: fn(&T) -> Vec<String>,
+    ) -> Result<(), String> {
+        while let Some(message) = stream.message().await.map_err(|e| e.to_string())? {
+            let received = Instant::now();
+            let keys = keys(&message);
+            let sent = self.sent.lock().unwrap();
+            let mut stats = stats.lock().unwrap();
+            stats.events += 1;
+            for key in keys {
+                let Some(at) = sent.get(&key) else {
+                    continue;
+                };
+                if stats.seen.insert(key) {
+                    let latency = received.saturating_duration_since(*at);
+                    stats
+                        .histogram
+                        .saturating_record(latency.as_micros() as u64);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints the results and returns them for the result file
+    ///
+    /// # Arguments
+    ///
+    /// * `elapsed` - How long the subscribers ran
+    pub fn report(&self, elapsed: Duration) -> SubscriptionReport {
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        let orders = self.sent.lock().unwrap().len() as u64;
+        let mut histogram = Histogram::<u64>::new(3).unwrap();
+        let mut events = 0;
+        let mut min_events = u64::MAX;
+        let mut failed = 0;
+        for stats in &self.subscribers {
+            let stats = stats.lock().unwrap();
+            histogram.add(&stats.histogram).unwrap();
+            events += stats.events;
+            min_events = min_events.min(stats.events);
+            failed += stats.error.is_some() as usize;
+        }
+        let report = SubscriptionReport {
+            stream: self.args.stream.to_string(),
+            subscribers: self.subscribers.len(),
+            events,
+            events_per_sec: events as f64 / seconds,
+            min_events_per_sec: min_events as f64 / seconds,
+            orders,
+            deliveries: histogram.len(),
+            failed,
+            percentiles: crate::report::PERCENTILES
+                .iter()
+                .map(|(name, p)| (name.to_string(), histogram.value_at_percentile(*p)))
+                .collect(),
+        };
+        println!(
+            "\nSubscriptions ({} x {}):",
+            report.subscribers, report.stream
+        );
+        println!(
+            "Events: {} ({:.2}/s, slowest subscriber {:.2}/s)",
+            report.events, report.events_per_sec, report.min_events_per_sec
+        );
+        println!(
+            "Orders seen: {} of {} sent, summed over the subscribers",
+            report.deliveries, report.orders
+        );
+        println!("Failed streams: {}", report.failed);
+        println!("Propose-to-event Latency (microseconds):");
+        for (name, _) in crate::report::PERCENTILES {
+            println!("{}: {}", name, report.percentiles[name]);
+        }
+        report
+    }
+}
+
+/// Anonymizes an order ID like the nodes do in L3 depth
+fn anonymize(key: &[u8], tenant: &str, symbol: &str, order_id: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    for part in [tenant, symbol, order_id] {
+        mac.update(part.as_bytes());
+        mac.update(&[0]);
+    }
+    hex::encode(&mac.finalize().into_bytes()[..8])
+}
+
+/// Anonymized IDs of the orders a depth update shows
+fn depth_keys(depth: &Depth) -> Vec<String> {
+    depth
+        .bids
+        .iter()
+        .chain(&depth.asks)
+        .flat_map(|level| level.orders.iter().map(|order| order.order_id.clone()))
+        .collect()
+}
+
+/// ID of the order an execution report is about
+fn report_keys(report: &ExecutionReport) -> Vec<String> {
+    report
+        .order
+        .as_ref()
+        .and_then(|state| state.order.as_ref())
+        .map(|order| vec![order.order_id.to_string()])
+        .unwrap_or_default()
+}
+
+/// IDs of the orders an applied command placed or canceled, none for snapshot chunks
+fn change_keys(event: &ChangeEvent) -> Vec<String> {
+    match &event.change {
+        Some(Change::Command(record)) => record
+            .orders
+            .iter()
+            .filter_map(|state| state.order.as_ref())
+            .map(|order| order.order_id.to_string())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
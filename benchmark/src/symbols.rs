@@ -0,0 +1,84 @@
+//! Symbol selection
+//!
+//! Spreads load across several symbols. With a positive skew the symbol
+//! popularity follows a Zipf distribution, so a few hot symbols receive most of
+//! the requests.
+
+use rand::Rng;
+use rand_distr::{Distribution, Zipf};
+
+/// Symbol options
+#[derive(clap::Args, Debug, Clone)]
+#[command(about = None, long_about = None, next_help_heading = "Symbols")]
+pub struct SymbolArgs {
+    /// Number of symbols the load is spread across
+    #[arg(long, default_value = "1")]
+    pub symbols: u64,
+
+    /// Zipf exponent of the symbol popularity, 0 spreads load evenly
+    #[arg(long, default_value = "0.0")]
+    pub symbol_skew: f64,
+}
+
+/// Base and quote currency of a benchmark symbol
+pub struct SymbolSpec {
+    /// Name of the symbol
+    pub name: String,
+    /// Base currency
+    pub base: String,
+    /// Quote currency
+    pub quote: String,
+}
+
+/// Picks the symbol of each request
+pub struct SymbolPicker {
+    /// Popularity rank distribution, ranks start at 1
+    zipf: Zipf<f64>,
+}
+
+impl SymbolArgs {
+    /// Checks that symbols can be picked
+    pub fn validate(&self) -> Result<(), String> {
+        if self.symbols == 0 {
+            return Err("at least one symbol is required".to_string());
+        }
+        if self.symbol_skew.is_nan() || self.symbol_skew < 0.0 {
+            return Err("symbol skew must not be negative".to_string());
+        }
+        Ok(())
+    }
+
+    /// Describes the symbols used by the run
+    ///
+    /// A single symbol keeps the historical `BTCUSDT` name.
+    pub fn specs(&self) -> Vec<SymbolSpec> {
+        if self.symbols == 1 {
+            return vec![SymbolSpec {
+                name: "BTCUSDT".to_string(),
+                base: "BTC".to_string(),
+                quote: "USDT".to_string(),
+            }];
+        }
+        (0..self.symbols)
+            .map(|i| SymbolSpec {
+                name: format!("SYM{}USDT", i),
+                base: format!("SYM{}", i),
+                quote: "USDT".to_string(),
+            })
+            .collect()
+    }
+
+    /// Creates the picker for the configured distribution
+    pub fn picker(&self) -> SymbolPicker {
+        SymbolPicker {
+            zipf: Zipf::new(self.symbols, self.symbol_skew).unwrap(),
+        }
+    }
+}
+
+impl SymbolPicker {
+    /// Picks the index of the symbol of the next request
+    pub fn pick<R: Rng>(&self, rng: &mut R) -> usize {
+        self.zipf.sample(rng) as usize - 1
+    }
+}
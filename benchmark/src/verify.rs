@@ -0,0 +1,670 @@
+//! Correctness verification
+//!
+//! With `--verify` every order and cancel of the run is sent under a request ID
+//! derived from its order ID, and the change feed of the cluster is followed
+//! while the load runs. Each applied command of the run is replayed through the
+//! reference matcher in raft order and what the cluster reported for it, the
+//! state of the placed order, its trades or the canceled order, is compared with
+//! what the reference matcher did. Once the run is over, the state of every
+//! order is read back with `QueryOrder` and compared with the reference state.
+//!
+//! The reference matcher starts from empty books, so the benchmark symbols must
+//! carry no orders from other runs or clients. Mismatches, orders the servers
+//! acknowledged that never applied, orders applied twice and orders filled
+//! beyond their quantity are reported, and the run fails if there are any.
+
+use crate::load;
+use crate::pb::change_event::Change;
+use crate::pb::{
+    AuditRecord, ChangeEvent, Order, OrderSide, OrderState, OrderStatus, OrderType,
+    QueryOrderRequest, ReadConsistency, SubscribeChangesRequest, TimeInForce,
+};
+use crate::reference::{Book, Fill, RefOrder, Side};
+use rust_decimal::Decimal;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Instant};
+
+/// Longest wait for the change feed to be subscribed, and to show the acknowledged
+/// commands once the run is over
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause before the change feed is subscribed again on the next server
+const RESUBSCRIBE_DELAY: Duration = Duration::from_millis(100);
+
+/// Maximum number of order queries in flight
+const QUERY_CONCURRENCY: usize = 64;
+
+/// Mismatches described in full, later ones are only counted
+const MAX_EXAMPLES: usize = 20;
+
+/// Verification options
+#[derive(clap::Args, Debug, Clone)]
+#[command(about = None, long_about = None, next_help_heading = "Verification")]
+pub struct VerifyArgs {
+    /// Reconciles what the cluster reports against a local reference matcher;
+    /// follows `SubscribeChanges` with `--admin-key` and needs `query_store_path`
+    /// on the nodes
+    #[arg(long, conflicts_with = "saturation")]
+    pub verify: bool,
+}
+
+/// Request ID a placed order is sent under
+pub fn place_request_id(order_id: u64) -> String {
+    format!("p{}", order_id)
+}
+
+/// Request ID the cancel of an order is sent under
+pub fn cancel_request_id(order_id: u64) -> String {
+    format!("c{}", order_id)
+}
+
+/// Command of the run a request ID names
+enum Command {
+    /// Placement of the order with the ID
+    Place(u64),
+    /// Cancel of the order with the ID
+    Cancel(u64),
+}
+
+impl Command {
+    /// Parses a request ID of `place_request_id` or `cancel_request_id`
+    fn parse(request_id: &str) -> Option<Command> {
+        if let Some(order_id) = request_id.strip_prefix('p') {
+            order_id.parse().ok().map(Command::Place)
+        } else if let Some(order_id) = request_id.strip_prefix('c') {
+            order_id.parse().ok().map(Command::Cancel)
+        } else {
+            None
+        }
+    }
+}
+
+/// Order of the run and what became of it
+struct Tracked {
+    /// Symbol of the order
+    symbol: String,
+    /// The order as sent
+    order: RefOrder,
+    /// Whether the server acknowledged the order
+    acknowledged: bool,
+    /// Raft index the order was placed at, None until the change feed shows it
+    applied_at: Option<u64>,
+    /// Whether the cluster rejected the order
+    rejected: bool,
+    /// Quantity the reference matcher filled
+    filled: Decimal,
+    /// Whether the reference matcher canceled the rest of the order
+    canceled: bool,
+    /// Quantity the cluster reported filled, as taker and maker
+    reported_filled: Decimal,
+    /// Whether the server acknowledged a cancel of the order
+    cancel_acknowledged: bool,
+    /// Whether the change feed showed a cancel of the order
+    cancel_applied: bool,
+}
+
+impl Tracked {
+    /// Status of the order the reference matcher expects
+    fn status(&self) -> OrderStatus {
+        if self.rejected {
+            OrderStatus::Rejected
+        } else if self.filled >= self.order.quantity {
+            OrderStatus::Filled
+        } else if self.canceled {
+            OrderStatus::Canceled
+        } else if self.filled > Decimal::ZERO {
+            OrderStatus::PartiallyFilled
+        } else {
+            OrderStatus::New
+        }
+    }
+
+    /// Describes how a reported order state differs from the reference state
+    ///
+    /// # Returns
+    ///
+    /// None if the state matches
+    fn compare(&self, state: &OrderState) -> Option<String> {
+        let filled = decimal(&state.filled_quantity);
+        let status = state.status();
+        (filled != self.filled || status != self.status()).then(|| {
+            format!(
+                "order {} is {:?} with {} filled, the reference has it {:?} with {} filled",
+                self.order.id,
+                status,
+                filled,
+                self.status(),
+                self.filled
+            )
+        })
+    }
+}
+
+/// State of the verification
+#[derive(Default)]
+struct State {
+    /// Orders sent by the run by ID
+    orders: HashMap<u64, Tracked>,
+    /// Reference book of each symbol
+    books: HashMap<String, Book>,
+    /// Whether the change feed sent anything yet
+    subscribed: bool,
+    /// Raft index of the last command received from the change feed
+    last_index: u64,
+    /// Number of mismatches by kind
+    issues: BTreeMap<String, u64>,
+    /// First mismatches in full
+    examples: Vec<String>,
+}
+
+impl State {
+    /// Counts a mismatch and keeps the first ones in full
+    fn issue(&mut self, kind: &str, detail: String) {
+        *self.issues.entry(kind.to_string()).or_default() += 1;
+        if self.examples.len() < MAX_EXAMPLES {
+            self.examples.push(format!("{}: {}", kind, detail));
+        }
+    }
+
+    /// Replays a command of the change feed
+    fn apply(&mut self, record: &AuditRecord) {
+        if record.index <= self.last_index {
+            return;
+        }
+        self.last_index = record.index;
+        if record.duplicate {
+            return;
+        }
+        match Command::parse(&record.request_id) {
+            Some(Command::Place(order_id)) => self.apply_place(record, order_id),
+            Some(Command::Cancel(order_id)) => self.apply_cancel(record, order_id),
+            None => {}
+        }
+    }
+
+    /// Places an order of the run on the reference book and compares the outcome
+    fn apply_place(&mut self, record: &AuditRecord, order_id: u64) {
+        let Some(tracked) = self.orders.get_mut(&order_id) else {
+            return;
+        };
+        if let Some(index) = tracked.applied_at {
+            let detail = format!(
+                "order {} placed at index {} and {}",
+                order_id, index, record.index
+            );
+            self.issue("applied twice", detail);
+            return;
+        }
+        tracked.applied_at = Some(record.index);
+        let Some(state) = record.orders.iter().find(|state| names(state, order_id)) else {
+            let detail = format!("order {} at index {}", order_id, record.index);
+            self.issue("not reported", detail);
+            return;
+        };
+        if state.status() == OrderStatus::Rejected {
+            tracked.rejected = true;
+            let detail = format!("order {} at index {}", order_id, record.index);
+            self.issue("rejected", detail);
+            return;
+        }
+
+        let order = tracked.order.clone();
+        let fills = self
+            .books
+            .entry(tracked.symbol.clone())
+            .or_default()
+            .place(&order);
+        for fill in &fills {
+            if let Some(maker) = self.orders.get_mut(&fill.maker) {
+                maker.filled += fill.quantity;
+            }
+        }
+        let tracked = self.orders.get_mut(&order_id).unwrap();
+        tracked.filled = fills.iter().map(|fill| fill.quantity).sum();
+        tracked.canceled = !order.resting && tracked.filled < order.quantity;
+        let mismatch = tracked.compare(state);
+
+        let reported: Vec<Fill> = record
+            .trades
+            .iter()
+            .map(|trade| {
+                let maker = match trade.taker_side() {
+                    OrderSide::Buy => &trade.seller_order_id,
+                    OrderSide::Sell => &trade.buyer_order_id,
+                };
+                Fill {
+                    maker: maker.parse().unwrap_or_default(),
+                    price: decimal(&trade.price),
+                    quantity: decimal(&trade.quantity),
+                }
+            })
+            .collect();
+        if reported != fills {
+            let detail = format!(
+                "order {} at index {} filled {:?}, the reference filled {:?}",
+                order_id, record.index, reported, fills
+            );
+            self.issue("fill mismatch", detail);
+        }
+        if let Some(detail) = mismatch {
+            self.issue("state mismatch", detail);
+        }
+        for fill in &reported {
+            self.report_fill(order_id, fill.quantity);
+            self.report_fill(fill.maker, fill.quantity);
+        }
+    }
+
+    /// Adds a fill the cluster reported to an order of the run
+    fn report_fill(&mut self, order_id: u64, quantity: Decimal) {
+        let Some(tracked) = self.orders.get_mut(&order_id) else {
+            return;
+        };
+        let before = tracked.reported_filled;
+        tracked.reported_filled += quantity;
+        if before <= tracked.order.quantity && tracked.reported_filled > tracked.order.quantity {
+            let detail = format!(
+                "order {} of quantity {} filled {}",
+                order_id, tracked.order.quantity, tracked.reported_filled
+            );
+            self.issue("overfilled", detail);
+        }
+    }
+
+    /// Cancels an order of the run on the reference book and compares the outcome
+    fn apply_cancel(&mut self, record: &AuditRecord, order_id: u64) {
+        let Some(tracked) = self.orders.get_mut(&order_id) else {
+            return;
+        };
+        tracked.cancel_applied = true;
+        let expected = self
+            .books
+            .get_mut(&tracked.symbol)
+            .is_some_and(|book| book.cancel(order_id));
+        tracked.canceled |= expected;
+        let reported = record
+            .orders
+            .iter()
+            .any(|state| names(state, order_id) && state.status() == OrderStatus::Canceled);
+        if reported != expected {
+            let outcome = |canceled: bool| if canceled { "canceled" } else { "not canceled" };
+            let detail = format!(
+                "order {} at index {} was {}, the reference has it {}",
+                order_id,
+                record.index,
+                outcome(reported),
+                outcome(expected)
+            );
+            self.issue("cancel mismatch", detail);
+        }
+    }
+
+    /// Number of acknowledged orders and cancels the change feed has not shown yet
+    fn pending(&self) -> usize {
+        self.orders
+            .values()
+            .map(|tracked| {
+                (tracked.acknowledged && tracked.applied_at.is_none()) as usize
+                    + (tracked.cancel_acknowledged && !tracked.cancel_applied) as usize
+            })
+            .sum()
+    }
+}
+
+/// Checks whether an order state is about an order
+fn names(state: &OrderState, order_id: u64) -> bool {
+    state
+        .order
+        .as_ref()
+        .is_some_and(|order| order.order_id == order_id)
+}
+
+/// Parses a decimal of a response, zero if empty or invalid
+fn decimal(value: &str) -> Decimal {
+    Decimal::from_str(value).unwrap_or_default()
+}
+
+/// Results of the verification of a run
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VerificationReport {
+    /// Orders sent
+    pub orders: u64,
+    /// Orders the change feed showed placed
+    pub applied: u64,
+    /// Orders whose state was read back
+    pub queried: u64,
+    /// Number of mismatches by kind
+    pub issues: BTreeMap<String, u64>,
+    /// First mismatches in full
+    pub examples: Vec<String>,
+}
+
+impl VerificationReport {
+    /// Total number of mismatches
+    pub fn issue_count(&self) -> u64 {
+        self.issues.values().sum()
+    }
+}
+
+/// Orders of the run checked against the reference matcher
+pub struct Verifier {
+    /// API key the state of the orders is read with
+    api_key: Option<String>,
+    /// Admin key the change feed is subscribed with
+    admin_key: Option<String>,
+    /// State of the verification
+    state: Mutex<State>,
+}
+
+impl Verifier {
+    /// Creates the verifier of a run
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - Verification options
+    /// * `api_key` - API key the state of the orders is read with
+    /// * `admin_key` - Admin key the change feed is subscribed with
+    ///
+    /// # Returns
+    ///
+    /// None if the run is not verified
+    pub fn new(
+        args: &VerifyArgs,
+        api_key: Option<&str>,
+        admin_key: Option<&str>,
+    ) -> Option<Arc<Self>> {
+        args.verify.then(|| {
+            Arc::new(Self {
+                api_key: api_key.map(str::to_string),
+                admin_key: admin_key.map(str::to_string),
+                state: Mutex::new(State::default()),
+            })
+        })
+    }
+
+    /// Registers an order right before it is sent
+    pub fn sent(&self, order: &Order) {
+        let side = match order.order_side() {
+            OrderSide::Buy => Side::Buy,
+            OrderSide::Sell => Side::Sell,
+        };
+        let limit = order.order_type() != OrderType::Market;
+        let tracked = Tracked {
+            symbol: order.symbol.clone(),
+            order: RefOrder {
+                id: order.order_id,
+                side,
+                price: limit.then(|| decimal(&order.price)),
+                quantity: decimal(&order.quantity),
+                resting: limit && order.time_in_force() == TimeInForce::Gtc,
+            },
+            acknowledged: false,
+            applied_at: None,
+            rejected: false,
+            filled: Decimal::ZERO,
+            canceled: false,
+            reported_filled: Decimal::ZERO,
+            cancel_acknowledged: false,
+            cancel_applied: false,
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .orders
+            .insert(order.order_id, tracked);
+    }
+
+    /// Records that the server acknowledged an order
+    pub fn acknowledged(&self, order_id: u64) {
+        if let Some(tracked) = self.state.lock().unwrap().orders.get_mut(&order_id) {
+            tracked.acknowledged = true;
+        }
+    }
+
+    /// Records that the server acknowledged the cancel of an order
+    pub fn cancel_acknowledged(&self, order_id: u64) {
+        if let Some(tracked) = self.state.lock().unwrap().orders.get_mut(&order_id) {
+            tracked.cancel_acknowledged = true;
+        }
+    }
+
+    /// Follows the change feed, moving on to the next server when a stream fails
+    ///
+    /// # Arguments
+    ///
+    /// * `servers` - Server addresses
+    ///
+    /// # Returns
+    ///
+    /// The task following the feed, it runs until aborted
+    pub fn start(self: &Arc<Self>, servers: Vec<String>) -> JoinHandle<()> {
+        let verifier = self.clone();
+        tokio::spawn(async move {
+            for server in servers.iter().cycle() {
+                if let Err(e) = verifier.follow(server).await {
+                    eprintln!("change feed on {}: {}", server, e);
+                }
+                sleep(RESUBSCRIBE_DELAY).await;
+            }
+        })
+    }
+
+    /// Replays the change feed of one server until the stream ends
+    ///
+    /// The feed is resumed after the last command received; a snapshot in
+    /// place of the commands after it means commands were missed.
+    async fn follow(&self, server: &str) -> Result<(), String> {
+        let from_index = match self.state.lock().unwrap().last_index {
+            0 => 0,
+            last_index => last_index + 1,
+        };
+        let request = load::authorized(
+            SubscribeChangesRequest { from_index },
+            self.api_key.as_deref(),
+        );
+        let request = load::with_admin_key(request, self.admin_key.as_deref());
+        let mut stream = load::connect(server)?
+            .subscribe_changes(request)
+            .await
+            .map_err(|e| e.to_string())?
+            .into_inner();
+        while let Some(ChangeEvent { change }) =
+            stream.message().await.map_err(|e| e.to_string())?
+        {
+            let mut state = self.state.lock().unwrap();
+            state.subscribed = true;
+            match change {
+                Some(Change::Command(record)) => state.apply(&record),
+                Some(Change::Snapshot(chunk)) => {
+                    if from_index > 0 && chunk.offset == 0 {
+                        let detail = format!(
+                            "commands {} to {} were missed on {}",
+                            from_index, chunk.index, server
+                        );
+                        state.issue("change feed gap", detail);
+                    }
+                    state.last_index = state.last_index.max(chunk.index);
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until the change feed is subscribed, so it shows every order sent after
+    ///
+    /// # Returns
+    ///
+    /// False if nothing arrived on the feed before the timeout
+    pub async fn ready(&self) -> bool {
+        let deadline = Instant::now() + SETTLE_TIMEOUT;
+        loop {
+            let subscribed = self.state.lock().unwrap().subscribed;
+            if subscribed {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Waits until the change feed showed every acknowledged order and cancel
+    ///
+    /// # Returns
+    ///
+    /// False if some were still missing after the timeout
+    pub async fn settle(&self) -> bool {
+        let deadline = Instant::now() + SETTLE_TIMEOUT;
+        loop {
+            let pending = self.state.lock().unwrap().pending();
+            if pending == 0 {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Reads back the state of the applied orders and compares it with the reference
+    ///
+    /// # Arguments
+    ///
+    /// * `servers` - Server addresses, queries move on to the next one when a server
+    ///   is unavailable
+    /// * `timeout` - Timeout of each query
+    ///
+    /// # Returns
+    ///
+    /// The number of orders queried
+    pub async fn reconcile(self: &Arc<Self>, servers: &[String], timeout: Duration) -> u64 {
+        let queries: Vec<(String, u64)> = {
+            let state = self.state.lock().unwrap();
+            state
+                .orders
+                .values()
+                .filter(|tracked| tracked.applied_at.is_some() && !tracked.rejected)
+                .map(|tracked| (tracked.symbol.clone(), tracked.order.id))
+                .collect()
+        };
+        let permits = Arc::new(Semaphore::new(QUERY_CONCURRENCY));
+        let mut handles = Vec::with_capacity(queries.len());
+        for (symbol, order_id) in queries {
+            let permit = permits.clone().acquire_owned().await.unwrap();
+            let verifier = self.clone();
+            let servers = servers.to_vec();
+            handles.push(tokio::spawn(async move {
+                let result = verifier.query(&servers, &symbol, order_id, timeout).await;
+                let mut state = verifier.state.lock().unwrap();
+                let mismatch = match result {
+                    Ok(Some(order)) => state.orders[&order_id].compare(&order),
+                    Ok(None) => Some(format!("order {} is unknown", order_id)),
+                    Err(e) => Some(format!("order {}: {}", order_id, e)),
+                };
+                if let Some(detail) = mismatch {
+                    state.issue("query mismatch", detail);
+                }
+                drop(permit);
+            }));
+        }
+        let queried = handles.len() as u64;
+        for handle in handles {
+            let _ = handle.await;
+        }
+        queried
+    }
+
+    /// Reads the state of an order from the leader
+    async fn query(
+        &self,
+        servers: &[String],
+        symbol: &str,
+        order_id: u64,
+        timeout: Duration,
+    ) -> Result<Option<OrderState>, String> {
+        let message = QueryOrderRequest {
+            symbol: symbol.to_string(),
+            order_id,
+            consistency: ReadConsistency::Linearizable as i32,
+            ..Default::default()
+        };
+        let mut error = String::new();
+        for server in servers {
+            let mut request = load::authorized(message.clone(), self.api_key.as_deref());
+            request.set_timeout(timeout);
+            match load::connect(server)?.query_order(request).await {
+                Ok(response) => return Ok(response.into_inner().order),
+                Err(e) if load::is_failover_error(e.code()) => error = e.to_string(),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Err(error)
+    }
+
+    /// Prints the results and returns them for the result file
+    ///
+    /// # Arguments
+    ///
+    /// * `settled` - Whether the change feed showed every acknowledged command
+    /// * `queried` - Number of orders whose state was read back
+    pub fn report(&self, settled: bool, queried: u64) -> VerificationReport {
+        let mut state = self.state.lock().unwrap();
+        if !settled {
+            let missing: Vec<(u64, bool)> = state
+                .orders
+                .values()
+                .filter(|tracked| {
+                    (tracked.acknowledged && tracked.applied_at.is_none())
+                        || (tracked.cancel_acknowledged && !tracked.cancel_applied)
+                })
+                .map(|tracked| (tracked.order.id, tracked.applied_at.is_none()))
+                .collect();
+            for (order_id, order_lost) in missing {
+                let detail = if order_lost {
+                    format!("order {} was acknowledged but never applied", order_id)
+                } else {
+                    format!(
+                        "cancel of order {} was acknowledged but never applied",
+                        order_id
+                    )
+                };
+                state.issue("lost", detail);
+            }
+        }
+        let report = VerificationReport {
+            orders: state.orders.len() as u64,
+            applied: state
+                .orders
+                .values()
+                .filter(|tracked| tracked.applied_at.is_some())
+                .count() as u64,
+            queried,
+            issues: state.issues.clone(),
+            examples: state.examples.clone(),
+        };
+        println!("\nVerification:");
+        println!(
+            "Orders: {} sent, {} applied, {} read back",
+            report.orders, report.applied, report.queried
+        );
+        if report.issues.is_empty() {
+            println!("No mismatches");
+        }
+        for (kind, count) in &report.issues {
+            println!("{}: {}", kind, count);
+        }
+        for example in &report.examples {
+            println!("  {}", example);
+        }
+        report
+    }
+}
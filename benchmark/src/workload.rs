@@ -0,0 +1,208 @@
+//! Workload specification
+//!
+//! Describes the mix of operations each client issues, how the orders it
+//! places are shaped (side, type and size) and which of them it cancels again.
+
+use rand::Rng;
+use rand_distr::{Distribution, LogNormal};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Operation issued by a benchmark client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OpKind {
+    /// Place a new order
+    Place,
+    /// Cancel an order the client placed earlier
+    Cancel,
+    /// Query a previously placed order
+    Query,
+    /// Reprice or resize a resting order, only sent when replaying recordings
+    Amend,
+}
+
+impl OpKind {
+    /// All operation kinds, in report order
+    pub const ALL: [OpKind; 4] = [OpKind::Place, OpKind::Cancel, OpKind::Query, OpKind::Amend];
+}
+
+impl fmt::Display for OpKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OpKind::Place => "place",
+            OpKind::Cancel => "cancel",
+            OpKind::Query => "query",
+            OpKind::Amend => "amend",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Path the workload exercises
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Places orders and mixes in queries of the client's own orders
+    Write,
+    /// Only queries orders placed before the run, see `--preload`
+    Read,
+}
+
+/// Distribution order quantities are drawn from
+#[derive(Debug, Clone, Copy)]
+pub enum SizeDistribution {
+    /// Every order has the same quantity
+    Fixed(f64),
+    /// Quantities uniformly distributed in `[min, max)`
+    Uniform(f64, f64),
+    /// Log-normally distributed quantities with the given median and shape
+    LogNormal(f64, f64),
+}
+
+impl SizeDistribution {
+    /// Draws an order quantity
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> f64 {
+        match *self {
+            SizeDistribution::Fixed(quantity) => quantity,
+            SizeDistribution::Uniform(min, max) => rng.gen_range(min..max),
+            SizeDistribution::LogNormal(median, sigma) => LogNormal::new(median.ln(), sigma)
+                .map(|d| d.sample(rng))
+                .unwrap_or(median),
+        }
+    }
+}
+
+impl FromStr for SizeDistribution {
+    type Err = String;
+
+    /// Parses `fixed:Q`, `uniform:MIN:MAX` or `lognormal:MEDIAN:SIGMA`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let number = |i: usize| -> Result<f64, String> {
+            parts
+                .get(i)
+                .ok_or_else(|| format!("missing parameter in size distribution {}", s))?
+                .parse::<f64>()
+                .map_err(|e| format!("invalid size distribution {}: {}", s, e))
+        };
+        let distribution = match parts[0] {
+            "fixed" => SizeDistribution::Fixed(number(1)?),
+            "uniform" => SizeDistribution::Uniform(number(1)?, number(2)?),
+            "lognormal" => SizeDistribution::LogNormal(number(1)?, number(2)?),
+            kind => return Err(format!("unknown size distribution {}", kind)),
+        };
+        match distribution {
+            SizeDistribution::Fixed(q) if q <= 0.0 => Err("quantity must be positive".into()),
+            SizeDistribution::Uniform(min, max) if min <= 0.0 || max <= min => {
+                Err("uniform sizes need 0 < min < max".into())
+            }
+            SizeDistribution::LogNormal(median, sigma) if median <= 0.0 || sigma < 0.0 => {
+                Err("lognormal sizes need median > 0 and sigma >= 0".into())
+            }
+            _ => Ok(distribution),
+        }
+    }
+}
+
+/// Workload options
+#[derive(clap::Args, Debug, Clone)]
+#[command(about = None, long_about = None, next_help_heading = "Workload")]
+pub struct WorkloadArgs {
+    /// Path to benchmark, reads are measured separately from writes in read mode
+    #[arg(long, value_enum, default_value = "write")]
+    pub mode: Mode,
+
+    /// Orders placed before the run for read mode to query
+    #[arg(long, default_value = "1000")]
+    pub preload: usize,
+
+    /// Relative weight of place order requests
+    #[arg(long, default_value = "100")]
+    pub place_weight: u32,
+
+    /// Relative weight of query requests for the client's own orders
+    #[arg(long, default_value = "0")]
+    pub query_weight: u32,
+
+    /// Fraction of placed orders that are buys
+    #[arg(long, default_value = "0.5")]
+    pub buy_ratio: f64,
+
+    /// Fraction of placed orders that are market orders
+    #[arg(long, default_value = "0.0")]
+    pub market_ratio: f64,
+
+    /// Order size distribution: fixed:Q, uniform:MIN:MAX or lognormal:MEDIAN:SIGMA
+    #[arg(long, default_value = "fixed:0.001")]
+    pub size: SizeDistribution,
+
+    /// Fraction of accepted limit orders the client cancels again
+    #[arg(long, default_value = "0.0")]
+    pub cancel_ratio: f64,
+
+    /// Milliseconds an order rests before the client cancels it
+    #[arg(long, default_value = "0")]
+    pub cancel_delay_ms: u64,
+
+    /// Number of accounts orders are placed for, numbered from 1; 0 places every
+    /// order for a random account
+    #[arg(long, default_value = "0")]
+    pub accounts: u64,
+}
+
+impl WorkloadArgs {
+    /// Checks that the workload can generate requests
+    pub fn validate(&self) -> Result<(), String> {
+        if self.mode == Mode::Read && self.preload == 0 {
+            return Err("read mode needs preloaded orders".to_string());
+        }
+        if self.place_weight + self.query_weight == 0 {
+            return Err("at least one operation weight must be positive".to_string());
+        }
+        let ratios = [self.buy_ratio, self.market_ratio, self.cancel_ratio];
+        if ratios.iter().any(|ratio| !(0.0..=1.0).contains(ratio)) {
+            return Err("ratios must be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// Picks the next operation according to the configured weights
+    ///
+    /// Cancels are not part of the mix, they follow from `cancel_ratio`.
+    pub fn next_op<R: Rng>(&self, rng: &mut R) -> OpKind {
+        let total = self.place_weight + self.query_weight;
+        if rng.gen_range(0..total) < self.place_weight {
+            OpKind::Place
+        } else {
+            OpKind::Query
+        }
+    }
+
+    /// Decides whether the next order is a buy
+    pub fn is_buy<R: Rng>(&self, rng: &mut R) -> bool {
+        rng.gen_bool(self.buy_ratio)
+    }
+
+    /// Decides whether an accepted limit order is cancelled later
+    pub fn is_cancelled<R: Rng>(&self, rng: &mut R) -> bool {
+        rng.gen_bool(self.cancel_ratio)
+    }
+
+    /// Time an order rests before it is cancelled
+    pub fn cancel_delay(&self) -> Duration {
+        Duration::from_millis(self.cancel_delay_ms)
+    }
+
+    /// Decides whether the next order is a market order
+    pub fn is_market<R: Rng>(&self, rng: &mut R) -> bool {
+        rng.gen_bool(self.market_ratio)
+    }
+
+    /// Picks the account of the next order
+    pub fn account<R: Rng>(&self, rng: &mut R) -> u64 {
+        match self.accounts {
+            0 => rng.gen::<u64>(),
+            accounts => rng.gen_range(1..=accounts),
+        }
+    }
+}
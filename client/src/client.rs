@@ -0,0 +1,658 @@
+//! Cluster client with leader discovery and retries
+
+use crate::error::Error;
+use crate::pb::match_service_client::MatchServiceClient;
+use crate::pb::{
+    AdjustBalanceRequest, AmendOrderRequest, CancelAllOrdersRequest, CancelOrderRequest,
+    ClusterEvent, ClusterEventKind, CreateSymbolRequest, DepositRequest, GetBalancesRequest,
+    MarketType, PlaceOrderRequest, PlaceOrdersRequest, ReadConsistency, RemoveSymbolRequest,
+    ReplaceOrderRequest, SubscribeClusterEventsRequest, UpdateSymbolRequest, WithdrawRequest,
+};
+use crate::types::{price_band, transfer_amount, Balance, NewOrder, SymbolSpec};
+use rust_decimal::Decimal;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Channel, Endpoint, Uri};
+
+/// Metadata key carrying the tenant API key
+const API_KEY_HEADER: &str = "x-api-key";
+/// Metadata key carrying the key authorizing funding calls
+const ADMIN_KEY_HEADER: &str = "x-admin-key";
+/// Metadata key carrying the request ID, reused on retries
+const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Metadata key carrying the client ID
+const CLIENT_ID_HEADER: &str = "x-client-id";
+/// Metadata key carrying the leader address on writes refused by a follower
+const LEADER_ADDR_HEADER: &str = "x-leader-addr";
+
+/// Builder of a `Client`
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    /// Addresses of the cluster nodes
+    endpoints: Vec<String>,
+    /// Tenant API key sent with every request
+    api_key: Option<String>,
+    /// Admin key sent with every request
+    admin_key: Option<String>,
+    /// Client ID sent with every request
+    client_id: Option<String>,
+    /// Timeout of a single attempt
+    timeout: Duration,
+    /// Attempts per call before giving up
+    max_attempts: usize,
+    /// Pause before retrying on another node
+    backoff: Duration,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            api_key: None,
+            admin_key: None,
+            client_id: None,
+            timeout: Duration::from_secs(5),
+            max_attempts: 10,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl ClientBuilder {
+    /// Sets the addresses of the cluster nodes, e.g. `http://10.0.0.1:4001`
+    ///
+    /// Listing every node lets the client find the leader when the node it
+    /// talks to fails; nodes it is redirected to are added as they come up.
+    pub fn endpoints<I, S>(mut self, endpoints: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.endpoints = endpoints.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the tenant API key sent with every request
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Sets the admin key sent with every request, needed for deposits,
+    /// withdrawals and adjustments if the cluster has `admin_keys` configured
+    pub fn admin_key(mut self, admin_key: impl Into<String>) -> Self {
+        self.admin_key = Some(admin_key.into());
+        self
+    }
+
+    /// Sets the client ID sent with every request, shown in the server logs
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+
+    /// Sets the timeout of a single attempt, default 5s
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the number of attempts per call before giving up, default 10
+    pub fn max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Sets the pause before retrying on another node, default 100ms
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Creates the client
+    ///
+    /// Connections are opened on first use, so this must be called within a
+    /// tokio runtime but does not wait for the nodes.
+    ///
+    /// # Returns
+    ///
+    /// Returns the client, or an error if no endpoint is set or one is invalid
+    pub fn build(self) -> Result<Client, Error> {
+        if self.endpoints.is_empty() {
+            return Err(Error::Config("no endpoints".to_string()));
+        }
+        let nodes = self
+            .endpoints
+            .iter()
+            .map(|addr| Node::connect(addr))
+            .collect::<Result<_, _>>()?;
+        Ok(Client {
+            nodes: RwLock::new(nodes),
+            current: AtomicUsize::new(0),
+            api_key: metadata_value("api key", self.api_key)?,
+            admin_key: metadata_value("admin key", self.admin_key)?,
+            client_id: metadata_value("client id", self.client_id)?,
+            timeout: self.timeout,
+            max_attempts: self.max_attempts,
+            backoff: self.backoff,
+        })
+    }
+}
+
+/// Parses an optional setting into a metadata value
+fn metadata_value(
+    what: &str,
+    value: Option<String>,
+) -> Result<Option<MetadataValue<Ascii>>, Error> {
+    value
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| Error::Config(format!("{} is not valid ASCII metadata", what)))
+        })
+        .transpose()
+}
+
+/// Connection to one node of the cluster
+struct Node {
+    /// Address of the node
+    uri: Uri,
+    /// Client of the node, connected lazily
+    client: MatchServiceClient<Channel>,
+}
+
+impl Node {
+    /// Creates a lazily connected client of a node
+    fn connect(addr: &str) -> Result<Self, Error> {
+        let endpoint = Endpoint::from_shared(addr.to_string())
+            .map_err(|e| Error::Config(format!("invalid endpoint {}: {}", addr, e)))?;
+        Ok(Self {
+            uri: endpoint.uri().clone(),
+            client: MatchServiceClient::new(endpoint.connect_lazy()),
+        })
+    }
+}
+
+/// Client of a raft-match cluster
+///
+/// Calls go to the node believed to be the leader. A follower refuses writes
+/// and names the leader, which the client then switches to; unreachable nodes
+/// are skipped in turn. All attempts of a call share one request ID, so the
+/// cluster applies a retried write at most once.
+pub struct Client {
+    /// Known nodes, the configured endpoints first
+    nodes: RwLock<Vec<Node>>,
+    /// Index of the node calls go to
+    current: AtomicUsize,
+    /// Tenant API key sent with every request
+    api_key: Option<MetadataValue<Ascii>>,
+    /// Admin key sent with every request
+    admin_key: Option<MetadataValue<Ascii>>,
+    /// Client ID sent with every request
+    client_id: Option<MetadataValue<Ascii>>,
+    /// Timeout of a single attempt
+    timeout: Duration,
+    /// Attempts per call before giving up
+    max_attempts: usize,
+    /// Pause before retrying on another node
+    backoff: Duration,
+}
+
+impl Client {
+    /// Starts building a client
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// Places an order and waits until it is committed
+    pub async fn place_order(&self, order: NewOrder) -> Result<(), Error> {
+        let request = PlaceOrderRequest {
+            order: Some(order.to_pb()?),
+        };
+        self.call(request, |mut client, request| async move {
+            client.place_order(request).await.map(|_| ())
+        })
+        .await
+    }
+
+    /// Places several orders of one account with a single proposal and waits
+    /// until the batch is committed
+    ///
+    /// The orders are applied in the given order with no other command in
+    /// between; each needs its own order ID.
+    pub async fn place_orders(&self, orders: Vec<NewOrder>) -> Result<(), Error> {
+        let request = PlaceOrdersRequest {
+            orders: orders
+                .iter()
+                .map(NewOrder::to_pb)
+                .collect::<Result<_, _>>()?,
+        };
+        self.call(request, |mut client, request| async move {
+            client.place_orders(request).await.map(|_| ())
+        })
+        .await
+    }
+
+    /// Cancels an order and places a new one of the same account in its stead
+    /// with a single proposal, and waits until both are committed
+    ///
+    /// Returns whether the order was replaced, i.e. canceled and the new order
+    /// placed; an order that is no longer open is not, and the new order is
+    /// rejected. The old order can be canceled even if the new one is rejected.
+    pub async fn replace_order(
+        &self,
+        symbol: &str,
+        order_id: u64,
+        order: NewOrder,
+    ) -> Result<bool, Error> {
+        let order = order.to_pb()?;
+        let request = ReplaceOrderRequest {
+            symbol: symbol.to_string(),
+            order_id,
+            market: order.market,
+            account_id: order.account_id,
+            order: Some(order),
+            ..Default::default()
+        };
+        self.call(request, |mut client, request| async move {
+            client
+                .replace_order(request)
+                .await
+                .map(|response| response.into_inner().replaced)
+        })
+        .await
+    }
+
+    /// Cancels an order and waits until the cancel is committed
+    pub async fn cancel_order(&self, symbol: &str, order_id: u64) -> Result<(), Error> {
+        let request = CancelOrderRequest {
+            symbol: symbol.to_string(),
+            order_id,
+            market: MarketType::Spot as i32,
+            ..Default::default()
+        };
+        self.call(request, |mut client, request| async move {
+            client.cancel_order(request).await.map(|_| ())
+        })
+        .await
+    }
+
+    /// Cancels the open order of an account with a client order ID and waits
+    /// until the cancel is committed
+    pub async fn cancel_client_order(
+        &self,
+        symbol: &str,
+        account_id: u64,
+        client_order_id: &str,
+    ) -> Result<(), Error> {
+        let request = CancelOrderRequest {
+            symbol: symbol.to_string(),
+            market: MarketType::Spot as i32,
+            client_order_id: client_order_id.to_string(),
+            account_id,
+            ..Default::default()
+        };
+        self.call(request, |mut client, request| async move {
+            client.cancel_order(request).await.map(|_| ())
+        })
+        .await
+    }
+
+    /// Cancels every open spot order of an account, on one symbol or on all of
+    /// them, and waits until the cancel is committed
+    ///
+    /// Returns the IDs of the canceled orders in the order they were canceled.
+    pub async fn cancel_all_orders(
+        &self,
+        account_id: u64,
+        symbol: Option<&str>,
+    ) -> Result<Vec<u64>, Error> {
+        let request = CancelAllOrdersRequest {
+            account_id,
+            symbol: symbol.unwrap_or_default().to_string(),
+            market: MarketType::Spot as i32,
+        };
+        self.call(request, |mut client, request| async move {
+            client
+                .cancel_all_orders(request)
+                .await
+                .map(|response| response.into_inner().order_ids)
+        })
+        .await
+    }
+
+    /// Changes the price or quantity of a resting order and waits until the
+    /// amendment is committed
+    ///
+    /// A zero price or quantity keeps the current one; the quantity is the new
+    /// total including what already filled.
+    pub async fn amend_order(
+        &self,
+        symbol: &str,
+        order_id: u64,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Result<(), Error> {
+        let field = |value: Decimal| {
+            if value.is_zero() {
+                String::new()
+            } else {
+                value.normalize().to_string()
+            }
+        };
+        let request = AmendOrderRequest {
+            symbol: symbol.to_string(),
+            order_id,
+            market: MarketType::Spot as i32,
+            price: field(price),
+            quantity: field(quantity),
+            ..Default::default()
+        };
+        self.call(request, |mut client, request| async move {
+            client.amend_order(request).await.map(|_| ())
+        })
+        .await
+    }
+
+    /// Creates a symbol and waits until it is committed
+    pub async fn create_symbol(&self, symbol: &SymbolSpec) -> Result<(), Error> {
+        let request = CreateSymbolRequest {
+            symbol: Some(symbol.to_pb()?),
+        };
+        self.call(request, |mut client, request| async move {
+            client.create_symbol(request).await.map(|_| ())
+        })
+        .await
+    }
+
+    /// Changes the price band of a symbol and waits until the update is committed
+    ///
+    /// A zero band removes it.
+    pub async fn set_price_band(&self, symbol: &str, band: Decimal) -> Result<(), Error> {
+        let request = UpdateSymbolRequest {
+            symbol: symbol.to_string(),
+            market: MarketType::Spot as i32,
+            price_band: price_band(band)?,
+        };
+        self.call(request, |mut client, request| async move {
+            client.update_symbol(request).await.map(|_| ())
+        })
+        .await
+    }
+
+    /// Removes a symbol and waits until the removal is committed
+    pub async fn remove_symbol(&self, symbol: &str) -> Result<(), Error> {
+        let request = RemoveSymbolRequest {
+            symbol: symbol.to_string(),
+            market: MarketType::Spot as i32,
+        };
+        self.call(request, |mut client, request| async move {
+            client.remove_symbol(request).await.map(|_| ())
+        })
+        .await
+    }
+
+    /// Credits funds to an account and waits until the deposit is committed
+    ///
+    /// The request ID serves as idempotency key, so retries of the call are
+    /// applied once.
+    pub async fn deposit(
+        &self,
+        account_id: u64,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), Error> {
+        let request = DepositRequest {
+            account_id,
+            currency: currency.to_string(),
+            amount: transfer_amount(currency, amount)?,
+            ..Default::default()
+        };
+        self.call(request, |mut client, request| async move {
+            client.deposit(request).await.map(|_| ())
+        })
+        .await
+    }
+
+    /// Debits available funds from an account and waits until the withdrawal
+    /// is committed
+    ///
+    /// A withdrawal exceeding the available funds is dropped by the cluster.
+    pub async fn withdraw(
+        &self,
+        account_id: u64,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), Error> {
+        let request = WithdrawRequest {
+            account_id,
+            currency: currency.to_string(),
+            amount: transfer_amount(currency, amount)?,
+            ..Default::default()
+        };
+        self.call(request, |mut client, request| async move {
+            client.withdraw(request).await.map(|_| ())
+        })
+        .await
+    }
+
+    /// Corrects the available funds of an account by a signed amount and waits
+    /// until the adjustment is committed
+    ///
+    /// A debit exceeding the available funds is dropped by the cluster. The
+    /// reason is kept in the cluster's funding audit trail.
+    pub async fn adjust_balance(
+        &self,
+        account_id: u64,
+        currency: &str,
+        amount: Decimal,
+        reason: &str,
+    ) -> Result<(), Error> {
+        if reason.trim().is_empty() {
+            return Err(Error::InvalidRequest("reason is empty".to_string()));
+        }
+        // Checks the currency and that the amount is nonzero
+        transfer_amount(currency, amount.abs())?;
+        let request = AdjustBalanceRequest {
+            account_id,
+            currency: currency.to_string(),
+            amount: amount.normalize().to_string(),
+            reason: reason.to_string(),
+            ..Default::default()
+        };
+        self.call(request, |mut client, request| async move {
+            client.adjust_balance(request).await.map(|_| ())
+        })
+        .await
+    }
+
+    /// Returns the funds of an account per currency
+    ///
+    /// Balances are read from the leader, so they include every write this
+    /// client had acknowledged.
+    pub async fn balances(&self, account_id: u64) -> Result<Vec<Balance>, Error> {
+        let request = GetBalancesRequest {
+            account_id,
+            consistency: ReadConsistency::Leader as i32,
+            ..Default::default()
+        };
+        let balances = self
+            .call(request, |mut client, request| async move {
+                client
+                    .get_balances(request)
+                    .await
+                    .map(|response| response.into_inner().balances)
+            })
+            .await?;
+        balances.into_iter().map(Balance::from_pb).collect()
+    }
+
+    /// Subscribes to the cluster events seen by the node calls currently go to
+    ///
+    /// The stream starts with the leader that node knows of and goes on with
+    /// leadership changes, read fencing of the node and halts and resumes of the
+    /// tenant's symbols. Pass events to `follow_leader` to send further calls straight to
+    /// a new leader. The stream ends if the node fails or the subscriber falls
+    /// behind; subscribe again to get the current state.
+    pub async fn cluster_events(&self) -> Result<tonic::Streaming<ClusterEvent>, Error> {
+        let (index, mut client) = self.node();
+        let mut request = tonic::Request::new(SubscribeClusterEventsRequest {});
+        self.authenticate(&mut request);
+        match client.subscribe_cluster_events(request).await {
+            Ok(response) => Ok(response.into_inner()),
+            Err(status) => {
+                if is_retryable(&status) {
+                    self.skip(index);
+                }
+                Err(Error::Status(status))
+            }
+        }
+    }
+
+    /// Sends further calls to the leader named by a leadership change
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the event named a leader to switch to
+    pub fn follow_leader(&self, event: &ClusterEvent) -> bool {
+        if event.kind() != ClusterEventKind::LeaderChanged {
+            return false;
+        }
+        match event.leader_addr.parse() {
+            Ok(leader) => {
+                self.switch_to(leader);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Sends a request to the leader, following redirects and skipping failed nodes
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - Request message, sent again on every attempt
+    /// * `send` - Sends one attempt to a node
+    async fn call<T, R, F, Fut>(&self, message: T, send: F) -> Result<R, Error>
+    where
+        T: Clone,
+        F: Fn(MatchServiceClient<Channel>, tonic::Request<T>) -> Fut,
+        Fut: Future<Output = Result<R, tonic::Status>>,
+    {
+        let request_id: MetadataValue<Ascii> = uuid::Uuid::new_v4()
+            .to_string()
+            .parse()
+            .expect("uuid is valid metadata");
+        let mut last = None;
+        for _ in 0..self.max_attempts {
+            let (index, client) = self.node();
+            let mut request = tonic::Request::new(message.clone());
+            request.set_timeout(self.timeout);
+            request
+                .metadata_mut()
+                .insert(REQUEST_ID_HEADER, request_id.clone());
+            self.authenticate(&mut request);
+            let status = match send(client, request).await {
+                Ok(response) => return Ok(response),
+                Err(status) if is_retryable(&status) => status,
+                Err(status) => return Err(Error::Status(status)),
+            };
+            // A redirect to another node is followed at once, anything else
+            // moves on to the next node after a pause
+            let redirected = leader_hint(&status)
+                .map(|leader| self.switch_to(leader))
+                .is_some_and(|leader| leader != index);
+            if !redirected {
+                self.skip(index);
+                tokio::time::sleep(self.backoff).await;
+            }
+            last = Some(status);
+        }
+        Err(Error::NoLeader {
+            attempts: self.max_attempts,
+            last: last.expect("at least one attempt"),
+        })
+    }
+
+    /// Adds the keys and the client ID to a request
+    fn authenticate<T>(&self, request: &mut tonic::Request<T>) {
+        let metadata = request.metadata_mut();
+        if let Some(api_key) = &self.api_key {
+            metadata.insert(API_KEY_HEADER, api_key.clone());
+        }
+        if let Some(admin_key) = &self.admin_key {
+            metadata.insert(ADMIN_KEY_HEADER, admin_key.clone());
+        }
+        if let Some(client_id) = &self.client_id {
+            metadata.insert(CLIENT_ID_HEADER, client_id.clone());
+        }
+    }
+
+    /// Node calls currently go to, with its index
+    fn node(&self) -> (usize, MatchServiceClient<Channel>) {
+        let nodes = self.nodes.read().unwrap();
+        let index = self.current.load(Ordering::Relaxed) % nodes.len();
+        (index, nodes[index].client.clone())
+    }
+
+    /// Moves on to the next node unless another call already did
+    fn skip(&self, failed: usize) {
+        let len = self.nodes.read().unwrap().len();
+        let _ = self.current.compare_exchange(
+            failed,
+            (failed + 1) % len,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Sends further calls to the leader a follower named, adding it if unknown
+    ///
+    /// # Returns
+    ///
+    /// Returns the index of the leader
+    fn switch_to(&self, leader: Uri) -> usize {
+        let mut nodes = self.nodes.write().unwrap();
+        let index = match nodes
+            .iter()
+            .position(|node| node.uri.authority() == leader.authority())
+        {
+            Some(index) => index,
+            None => match Node::connect(&leader.to_string()) {
+                Ok(node) => {
+                    nodes.push(node);
+                    nodes.len() - 1
+                }
+                Err(_) => return self.current.load(Ordering::Relaxed),
+            },
+        };
+        self.current.store(index, Ordering::Relaxed);
+        index
+    }
+}
+
+/// Checks whether a failed attempt may succeed on another node or later
+///
+/// Writes are deduplicated by request ID, so retrying after a timeout is safe.
+fn is_retryable(status: &tonic::Status) -> bool {
+    match status.code() {
+        tonic::Code::Unavailable | tonic::Code::DeadlineExceeded => true,
+        tonic::Code::Unknown => std::error::Error::source(status)
+            .is_some_and(|source| source.is::<tonic::transport::Error>()),
+        _ => false,
+    }
+}
+
+/// Address of the leader named by a follower that refused a write
+fn leader_hint(status: &tonic::Status) -> Option<Uri> {
+    status
+        .metadata()
+        .get(LEADER_ADDR_HEADER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
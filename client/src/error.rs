@@ -0,0 +1,26 @@
+//! Client errors
+
+/// Error returned by client calls
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The client was configured without any endpoint or with an invalid one
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    /// The request is invalid and was not sent
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    /// The service answered with a response the client cannot read
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+    /// The service refused or failed the request
+    #[error("request failed: {0}")]
+    Status(#[from] tonic::Status),
+    /// No node accepted the request within the configured attempts
+    #[error("no leader reachable after {attempts} attempts, last error: {last}")]
+    NoLeader {
+        /// Number of attempts made
+        attempts: usize,
+        /// Error of the last attempt
+        last: tonic::Status,
+    },
+}
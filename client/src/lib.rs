@@ -0,0 +1,41 @@
+//! Rust client for the raft-match order matching service
+//!
+//! Wraps the generated gRPC stubs with typed requests (`Decimal` prices and
+//! quantities, plain enums) and a `Client` that finds the leader of a cluster on
+//! its own: writes sent to a follower are redirected to the leader the follower
+//! names, and failed nodes are skipped. Every write carries a request ID that is
+//! kept across retries, so a retried write is applied at most once.
+//!
+//! ```no_run
+//! use raft_match_client::{Client, NewOrder, Side};
+//! use rust_decimal::Decimal;
+//!
+//! # async fn example() -> Result<(), raft_match_client::Error> {
+//! let client = Client::builder()
+//!     .endpoints(["http://10.0.0.1:4001", "http://10.0.0.2:4001"])
+//!     .build()?;
+//! let order = NewOrder::limit(1, 42, "BTCUSDT", Side::Buy, Decimal::new(30_000, 0), Decimal::ONE);
+//! client.place_order(order).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `Client::cluster_events` streams leadership changes, read fencing and symbol
+//! halts and resumes, so a client can move to a new leader or stop quoting a
+//! halted symbol before its calls fail. Trade and order updates cannot be subscribed to yet.
+
+mod client;
+mod error;
+mod types;
+
+pub use client::{Client, ClientBuilder};
+pub use error::Error;
+pub use rust_decimal::Decimal;
+pub use types::{
+    Balance, CircuitBreaker, NewOrder, OrderType, Side, SymbolSpec, SymbolStatus, TimeInForce,
+};
+
+/// Generated protocol buffer types and gRPC stubs of the match service
+pub mod pb {
+    tonic::include_proto!("r#match");
+}
@@ -0,0 +1,511 @@
+//! Typed requests and their conversion to the wire format
+
+use crate::error::Error;
+use crate::pb;
+use rust_decimal::Decimal;
+use std::time::Duration;
+
+/// Side of an order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// Buy the base currency
+    Buy,
+    /// Sell the base currency
+    Sell,
+}
+
+/// Type of an order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderType {
+    /// Trades at the given price or better, the rest rests on the book
+    Limit,
+    /// Trades at any price until filled or the book is exhausted
+    Market,
+    /// Limit order that only rests on the book and never takes liquidity
+    LimitMaker,
+    /// Market order placed once the last trade price reaches the stop price
+    Stop,
+    /// Limit order placed once the last trade price reaches the stop price
+    StopLimit,
+}
+
+/// How long an order stays on the book
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeInForce {
+    /// Good till cancelled
+    Gtc,
+    /// Immediate or cancel, the unfilled rest is cancelled
+    Ioc,
+    /// Fill or kill, the order is cancelled unless it fills completely
+    Fok,
+    /// Good till date, the order is cancelled at its expiry time
+    Gtd,
+}
+
+/// Trading status of a symbol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolStatus {
+    /// Open for trading
+    Alive,
+    /// Temporarily halted
+    Paused,
+    /// Closed for trading
+    Stopped,
+}
+
+/// Order to place
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewOrder {
+    /// Client assigned order ID, unique per symbol
+    pub order_id: u64,
+    /// Account the order is placed for
+    pub account_id: u64,
+    /// Symbol to trade
+    pub symbol: String,
+    /// Side of the order
+    pub side: Side,
+    /// Type of the order
+    pub order_type: OrderType,
+    /// How long the order stays on the book
+    pub time_in_force: TimeInForce,
+    /// Limit price, ignored for market orders
+    pub price: Decimal,
+    /// Quantity in the base currency
+    pub quantity: Decimal,
+    /// Fee rate charged when the order takes liquidity
+    pub taker_fee: Decimal,
+    /// Fee rate charged when the order provides liquidity
+    pub maker_fee: Decimal,
+    /// ID unique among the open orders of the account, empty if none
+    pub client_order_id: String,
+    /// Last trade price that triggers a stop or stop-limit order
+    pub stop_price: Decimal,
+    /// Quantity an iceberg order shows in the book at a time, zero to show all
+    pub display_quantity: Decimal,
+    /// Distance a trailing stop keeps its stop price from the last trade price
+    pub trailing_offset: Decimal,
+    /// Milliseconds since the epoch a good-till-date order is cancelled at
+    pub expire_at: u64,
+}
+
+impl NewOrder {
+    /// Creates a good-till-cancelled limit order without fees
+    pub fn limit(
+        order_id: u64,
+        account_id: u64,
+        symbol: impl Into<String>,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Self {
+        Self {
+            order_id,
+            account_id,
+            symbol: symbol.into(),
+            side,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::Gtc,
+            price,
+            quantity,
+            taker_fee: Decimal::ZERO,
+            maker_fee: Decimal::ZERO,
+            client_order_id: String::new(),
+            stop_price: Decimal::ZERO,
+            display_quantity: Decimal::ZERO,
+            trailing_offset: Decimal::ZERO,
+            expire_at: 0,
+        }
+    }
+
+    /// Creates a market order without fees
+    pub fn market(
+        order_id: u64,
+        account_id: u64,
+        symbol: impl Into<String>,
+        side: Side,
+        quantity: Decimal,
+    ) -> Self {
+        Self {
+            order_type: OrderType::Market,
+            ..Self::limit(order_id, account_id, symbol, side, Decimal::ZERO, quantity)
+        }
+    }
+
+    /// Sets the time in force
+    pub fn time_in_force(mut self, time_in_force: TimeInForce) -> Self {
+        self.time_in_force = time_in_force;
+        self
+    }
+
+    /// Makes the order good till date, cancelled at a time in milliseconds
+    /// since the epoch
+    pub fn good_till(mut self, expire_at: u64) -> Self {
+        self.time_in_force = TimeInForce::Gtd;
+        self.expire_at = expire_at;
+        self
+    }
+
+    /// Sets the taker and maker fee rates
+    pub fn fees(mut self, taker_fee: Decimal, maker_fee: Decimal) -> Self {
+        self.taker_fee = taker_fee;
+        self.maker_fee = maker_fee;
+        self
+    }
+
+    /// Sets the client order ID; with an order ID of 0 the exchange assigns one
+    pub fn client_order_id(mut self, client_order_id: impl Into<String>) -> Self {
+        self.client_order_id = client_order_id.into();
+        self
+    }
+
+    /// Turns the order into a stop order triggered at a last trade price, a
+    /// market order becomes a stop order and a limit order a stop-limit order
+    pub fn stop_price(mut self, stop_price: Decimal) -> Self {
+        self.order_type = match self.order_type {
+            OrderType::Market | OrderType::Stop => OrderType::Stop,
+            OrderType::Limit | OrderType::LimitMaker | OrderType::StopLimit => OrderType::StopLimit,
+        };
+        self.stop_price = stop_price;
+        self
+    }
+
+    /// Turns the order into a trailing stop order whose stop price follows the
+    /// last trade price at a distance; without a stop price the first one is
+    /// taken from the last trade price
+    pub fn trailing_offset(mut self, trailing_offset: Decimal) -> Self {
+        self.order_type = match self.order_type {
+            OrderType::Market | OrderType::Stop => OrderType::Stop,
+            OrderType::Limit | OrderType::LimitMaker | OrderType::StopLimit => OrderType::StopLimit,
+        };
+        self.trailing_offset = trailing_offset;
+        self
+    }
+
+    /// Turns the order into an iceberg order showing only part of its quantity
+    /// in the book at a time; market orders cannot be icebergs
+    pub fn display_quantity(mut self, display_quantity: Decimal) -> Self {
+        self.display_quantity = display_quantity;
+        self
+    }
+
+    /// Checks the order and converts it to the wire format
+    pub(crate) fn to_pb(&self) -> Result<pb::Order, Error> {
+        if self.symbol.is_empty() {
+            return Err(Error::InvalidRequest("symbol is empty".to_string()));
+        }
+        if self.quantity <= Decimal::ZERO {
+            return Err(Error::InvalidRequest(format!(
+                "quantity {} is not positive",
+                self.quantity
+            )));
+        }
+        let stop = matches!(self.order_type, OrderType::Stop | OrderType::StopLimit);
+        let good_till_date = self.time_in_force == TimeInForce::Gtd;
+        if good_till_date && self.expire_at == 0 {
+            return Err(Error::InvalidRequest(
+                "good-till-date order has no expiry time".to_string(),
+            ));
+        }
+        if self.trailing_offset < Decimal::ZERO {
+            return Err(Error::InvalidRequest(format!(
+                "trailing offset {} is negative",
+                self.trailing_offset
+            )));
+        }
+        let trailing = stop && self.trailing_offset > Decimal::ZERO;
+        if stop && !trailing && self.stop_price <= Decimal::ZERO {
+            return Err(Error::InvalidRequest(format!(
+                "stop price {} is not positive",
+                self.stop_price
+            )));
+        }
+        let market = matches!(self.order_type, OrderType::Market | OrderType::Stop);
+        if !market && self.price <= Decimal::ZERO {
+            return Err(Error::InvalidRequest(format!(
+                "price {} is not positive",
+                self.price
+            )));
+        }
+        if self.display_quantity < Decimal::ZERO || (market && !self.display_quantity.is_zero()) {
+            return Err(Error::InvalidRequest(format!(
+                "display quantity {} is not allowed on a {:?} order",
+                self.display_quantity, self.order_type
+            )));
+        }
+        Ok(pb::Order {
+            order_id: self.order_id,
+            account_id: self.account_id,
+            order_side: match self.side {
+                Side::Buy => pb::OrderSide::Buy,
+                Side::Sell => pb::OrderSide::Sell,
+            } as i32,
+            order_type: match self.order_type {
+                OrderType::Limit => pb::OrderType::Limit,
+                OrderType::Market => pb::OrderType::Market,
+                OrderType::LimitMaker => pb::OrderType::LimitMaker,
+                OrderType::Stop => pb::OrderType::Stop,
+                OrderType::StopLimit => pb::OrderType::StopLimit,
+            } as i32,
+            time_in_force: match self.time_in_force {
+                TimeInForce::Gtc => pb::TimeInForce::Gtc,
+                TimeInForce::Ioc => pb::TimeInForce::Ioc,
+                TimeInForce::Fok => pb::TimeInForce::Fok,
+                TimeInForce::Gtd => pb::TimeInForce::Gtd,
+            } as i32,
+            symbol: self.symbol.clone(),
+            quantity: self.quantity.normalize().to_string(),
+            price: self.price.normalize().to_string(),
+            taker_fee: self.taker_fee.normalize().to_string(),
+            maker_fee: self.maker_fee.normalize().to_string(),
+            market: pb::MarketType::Spot as i32,
+            leverage: String::new(),
+            client_order_id: self.client_order_id.clone(),
+            stop_price: if stop && !self.stop_price.is_zero() {
+                self.stop_price.normalize().to_string()
+            } else {
+                String::new()
+            },
+            display_quantity: if self.display_quantity.is_zero() {
+                String::new()
+            } else {
+                self.display_quantity.normalize().to_string()
+            },
+            expire_at: if good_till_date { self.expire_at } else { 0 },
+            trailing_offset: if trailing {
+                self.trailing_offset.normalize().to_string()
+            } else {
+                String::new()
+            },
+        })
+    }
+}
+
+/// Symbol to create
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolSpec {
+    /// Name of the symbol, e.g. `BTCUSDT`
+    pub name: String,
+    /// Base currency
+    pub base: String,
+    /// Quote currency
+    pub quote: String,
+    /// Decimal places of prices
+    pub price_precision: u32,
+    /// Decimal places of quantities
+    pub quantity_precision: u32,
+    /// Smallest order quantity
+    pub min_quantity: Decimal,
+    /// Largest order quantity
+    pub max_quantity: Decimal,
+    /// Smallest order amount in the quote currency
+    pub min_amount: Decimal,
+    /// Largest order amount in the quote currency
+    pub max_amount: Decimal,
+    /// Trading status
+    pub status: SymbolStatus,
+    /// Whether orders hold and settle account balances, funded with `Client::deposit`
+    pub settle_balances: bool,
+    /// Base or quote currency fees are paid in, empty to take them from what orders receive
+    pub fee_currency: String,
+    /// Largest distance of a limit price from the last trade or mid price as a
+    /// fraction of it, zero for no band
+    pub price_band: Decimal,
+    /// Halts trading when the price moves too far, None for no circuit breaker
+    pub circuit_breaker: Option<CircuitBreaker>,
+}
+
+/// Circuit breaker of a symbol
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitBreaker {
+    /// Largest move of the trade price within `window` as a fraction of the
+    /// lowest or highest price traded in it, e.g. `0.1` for 10%
+    pub max_move: Decimal,
+    /// How far back trade prices are compared
+    pub window: Duration,
+    /// How long trading halts once the price moved too far
+    pub halt: Duration,
+    /// Whether a halt cancels the orders resting on the symbol
+    pub cancel_orders: bool,
+}
+
+impl SymbolSpec {
+    /// Checks the symbol and converts it to the wire format
+    pub(crate) fn to_pb(&self) -> Result<pb::Symbol, Error> {
+        if self.name.is_empty() {
+            return Err(Error::InvalidRequest("symbol name is empty".to_string()));
+        }
+        if self.min_quantity > self.max_quantity || self.min_amount > self.max_amount {
+            return Err(Error::InvalidRequest(format!(
+                "limits of {} are inverted",
+                self.name
+            )));
+        }
+        let breaker = self.circuit_breaker.as_ref();
+        if breaker.is_some_and(|breaker| {
+            breaker.max_move <= Decimal::ZERO || breaker.window.is_zero() || breaker.halt.is_zero()
+        }) {
+            return Err(Error::InvalidRequest(format!(
+                "circuit breaker of {} needs a positive move, window and halt",
+                self.name
+            )));
+        }
+        Ok(pb::Symbol {
+            symbol: self.name.clone(),
+            base: self.base.clone(),
+            quote: self.quote.clone(),
+            min_quantity: self.min_quantity.normalize().to_string(),
+            max_quantity: self.max_quantity.normalize().to_string(),
+            min_amount: self.min_amount.normalize().to_string(),
+            max_amount: self.max_amount.normalize().to_string(),
+            price_precision: self.price_precision as i32,
+            quantity_precision: self.quantity_precision as i32,
+            status: match self.status {
+                SymbolStatus::Alive => pb::SymbolStatus::Alive,
+                SymbolStatus::Paused => pb::SymbolStatus::Pause,
+                SymbolStatus::Stopped => pb::SymbolStatus::Stoped,
+            } as i32,
+            settle_balances: self.settle_balances,
+            market: pb::MarketType::Spot as i32,
+            max_leverage: String::new(),
+            maintenance_margin: String::new(),
+            fee_currency: self.fee_currency.clone(),
+            price_band: price_band(self.price_band)?,
+            circuit_breaker: breaker
+                .map(|breaker| breaker.max_move.normalize().to_string())
+                .unwrap_or_default(),
+            circuit_window_ms: breaker.map_or(0, |breaker| breaker.window.as_millis() as u64),
+            halt_ms: breaker.map_or(0, |breaker| breaker.halt.as_millis() as u64),
+            cancel_on_halt: breaker.is_some_and(|breaker| breaker.cancel_orders),
+        })
+    }
+}
+
+/// Funds of an account in one currency
+#[derive(Debug, Clone, PartialEq)]
+pub struct Balance {
+    /// Currency of the funds
+    pub currency: String,
+    /// Funds free to trade or withdraw
+    pub available: Decimal,
+    /// Funds held for open orders
+    pub held: Decimal,
+}
+
+impl Balance {
+    /// Converts a balance from the wire format
+    pub(crate) fn from_pb(balance: pb::Balance) -> Result<Self, Error> {
+        let parse = |value: &str| {
+            value.parse::<Decimal>().map_err(|e| {
+                Error::InvalidResponse(format!("balance {} of {}: {}", value, balance.currency, e))
+            })
+        };
+        Ok(Self {
+            available: parse(&balance.available)?,
+            held: parse(&balance.held)?,
+            currency: balance.currency,
+        })
+    }
+}
+
+/// Checks a transfer amount and converts it to the wire format
+pub(crate) fn transfer_amount(currency: &str, amount: Decimal) -> Result<String, Error> {
+    if currency.is_empty() {
+        return Err(Error::InvalidRequest("currency is empty".to_string()));
+    }
+    if amount <= Decimal::ZERO {
+        return Err(Error::InvalidRequest(format!(
+            "amount {} is not positive",
+            amount
+        )));
+    }
+    Ok(amount.normalize().to_string())
+}
+
+/// Checks a price band and converts it to the wire format, empty for no band
+pub(crate) fn price_band(band: Decimal) -> Result<String, Error> {
+    if band < Decimal::ZERO {
+        return Err(Error::InvalidRequest(format!(
+            "price band {} is negative",
+            band
+        )));
+    }
+    if band.is_zero() {
+        return Ok(String::new());
+    }
+    Ok(band.normalize().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_are_sent_with_canonical_decimals() {
+        let order = NewOrder::limit(
+            7,
+            42,
+            "BTCUSDT",
+            Side::Sell,
+            Decimal::new(3_000_050, 2),
+            Decimal::new(1_500, 3),
+        )
+        .time_in_force(TimeInForce::Ioc);
+        let pb = order.to_pb().unwrap();
+        assert_eq!(pb.price, "30000.5");
+        assert_eq!(pb.quantity, "1.5");
+        assert_eq!(pb.order_side(), pb::OrderSide::Sell);
+        assert_eq!(pb.time_in_force(), pb::TimeInForce::Ioc);
+        let expiring = order.clone().good_till(1_700_000_000_000).to_pb().unwrap();
+        assert_eq!(expiring.time_in_force(), pb::TimeInForce::Gtd);
+        assert_eq!(expiring.expire_at, 1_700_000_000_000);
+        let endless = order.clone().time_in_force(TimeInForce::Gtd);
+        assert!(matches!(endless.to_pb(), Err(Error::InvalidRequest(_))));
+
+        let market = NewOrder::market(8, 42, "BTCUSDT", Side::Buy, Decimal::ONE);
+        assert_eq!(market.to_pb().unwrap().price, "0");
+        let empty = NewOrder::market(9, 42, "BTCUSDT", Side::Buy, Decimal::ZERO);
+        assert!(matches!(empty.to_pb(), Err(Error::InvalidRequest(_))));
+
+        let stop = market.clone().stop_price(Decimal::new(29_000_00, 2));
+        let pb = stop.to_pb().unwrap();
+        assert_eq!(pb.order_type(), pb::OrderType::Stop);
+        assert_eq!(pb.stop_price, "29000");
+        let untriggered = market.clone().stop_price(Decimal::ZERO);
+        assert!(matches!(untriggered.to_pb(), Err(Error::InvalidRequest(_))));
+        let trailing = market
+            .clone()
+            .trailing_offset(Decimal::new(250, 2))
+            .to_pb()
+            .unwrap();
+        assert_eq!(trailing.order_type(), pb::OrderType::Stop);
+        assert_eq!(trailing.trailing_offset, "2.5");
+        assert_eq!(trailing.stop_price, "");
+
+        let iceberg = order.display_quantity(Decimal::new(500, 3));
+        assert_eq!(iceberg.to_pb().unwrap().display_quantity, "0.5");
+        let hidden_market = market.display_quantity(Decimal::ONE);
+        assert!(matches!(
+            hidden_market.to_pb(),
+            Err(Error::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn balances_are_read_from_decimal_strings() {
+        let balance = Balance::from_pb(pb::Balance {
+            currency: "USDT".to_string(),
+            available: "1200.5".to_string(),
+            held: "0".to_string(),
+        })
+        .unwrap();
+        assert_eq!(balance.available, Decimal::new(12_005, 1));
+        assert_eq!(balance.held, Decimal::ZERO);
+
+        let broken = Balance::from_pb(pb::Balance {
+            currency: "USDT".to_string(),
+            available: "a lot".to_string(),
+            held: "0".to_string(),
+        });
+        assert!(matches!(broken, Err(Error::InvalidResponse(_))));
+    }
+}
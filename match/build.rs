@@ -5,5 +5,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .protoc_arg("--experimental_allow_proto3_optional")
         .compile(&["../proto/raft.proto"], &["../proto"])?;
+    tonic_build::configure()
+        .protoc_arg("--experimental_allow_proto3_optional")
+        .compile(&["../proto/command.proto"], &["../proto"])?;
+    tonic_build::configure()
+        .protoc_arg("--experimental_allow_proto3_optional")
+        .compile(&["../proto/admin.proto"], &["../proto"])?;
     Ok(())
 }
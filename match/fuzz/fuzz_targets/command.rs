@@ -0,0 +1,13 @@
+//! Decodes arbitrary bytes as a replicated command and applies it to an engine
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use raft_match::engine::codec;
+use raft_match::engine::matchengine::MatchEngine;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = codec::decode(data);
+    let mut engine = MatchEngine::new();
+    let _ = engine.on_message(1, data);
+});
@@ -0,0 +1,16 @@
+//! Scans arbitrary bytes as a raft log segment file and reads back every entry it locates
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use raft_match::raft::segment;
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    let mut file = Cursor::new(data);
+    if let Ok(index) = segment::scan(&mut file) {
+        for pos in index.entry_positions.values() {
+            let _ = segment::read_entry_at(&mut file, *pos);
+        }
+    }
+});
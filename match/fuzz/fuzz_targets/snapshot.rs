@@ -0,0 +1,12 @@
+//! Restores an engine from arbitrary snapshot bytes and snapshots it again
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use raft_match::engine::matchengine::MatchEngine;
+
+fuzz_target!(|data: &[u8]| {
+    let mut engine = MatchEngine::new();
+    let _ = engine.on_snapshot(data);
+    engine.snapshot().unwrap();
+});
@@ -0,0 +1,451 @@
+//! Admin service implementation
+//!
+//! This module implements the gRPC service operators manage a cluster with, see
+//! `proto/admin.proto` and `raftctl`. Every call requires an admin key if admin
+//! keys are configured. Membership changes, leader transfers and symbol commands
+//! are made on the leader; snapshots are saved by the node called.
+
+use std::time::Duration;
+
+use pb::admin_service_server::AdminService;
+use pb::{
+    AddNodeRequest, AddNodeResponse, CompactLogRequest, CompactLogResponse,
+    GetClusterStatusRequest, GetClusterStatusResponse, HaltSymbolRequest, HaltSymbolResponse,
+    MassCancelRequest, MassCancelResponse, PeerStatus, RemoveNodeRequest, RemoveNodeResponse,
+    ResumeSymbolRequest, ResumeSymbolResponse, TransferLeaderRequest, TransferLeaderResponse,
+    TriggerSnapshotRequest, TriggerSnapshotResponse,
+};
+use raft::prelude::{ConfChange, ConfChangeType};
+use tokio::sync::oneshot::Receiver;
+use tokio::time::Instant;
+
+use crate::engine::entry::Symbol;
+use crate::engine::matchengine::{MarketType, MatchCmd, MatchCmdType, DEFAULT_TENANT};
+use crate::match_service::{check_admin, check_leader, envelope, propose, request_deadline};
+use crate::raft::proposal::{Proposal, SnapshotRequest};
+use crate::slow_log::RequestTrace;
+use crate::{cluster_status, config, read_view, server};
+
+/// Protocol buffer definitions for admin service
+#[allow(clippy::module_inception)]
+pub mod pb {
+    tonic::include_proto!("admin");
+}
+
+/// Time a call waits for the raft loop if the client set no deadline
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Admin service implementation
+#[derive(Debug, Default)]
+pub struct AdminServiceSVC {}
+
+/// Returns the deadline of a call, `DEFAULT_TIMEOUT` from now if the client set none
+fn call_deadline<T>(request: &tonic::Request<T>) -> Instant {
+    request_deadline(request).unwrap_or_else(|| Instant::now() + DEFAULT_TIMEOUT)
+}
+
+/// Returns the raft state of this node, UNAVAILABLE before the raft loop ticked
+fn raft_status() -> Result<cluster_status::RaftStatus, tonic::Status> {
+    cluster_status::current().ok_or_else(|| tonic::Status::unavailable("raft is not running yet"))
+}
+
+/// Checks that a tenant exists
+///
+/// # Arguments
+///
+/// * `tenant` - ID of the tenant, empty for the default tenant
+///
+/// # Returns
+///
+/// Returns the tenant ID or an invalid argument status if no such tenant is configured
+fn check_tenant(tenant: &str) -> Result<String, tonic::Status> {
+    let config = config::instance().lock().unwrap();
+    if config.tenants.is_empty() && (tenant.is_empty() || tenant == DEFAULT_TENANT) {
+        return Ok(DEFAULT_TENANT.to_string());
+    }
+    if config.tenants.iter().any(|t| t.id == tenant) {
+        return Ok(tenant.to_string());
+    }
+    Err(tonic::Status::invalid_argument(format!(
+        "unknown tenant {:?}",
+        tenant
+    )))
+}
+
+/// Converts the market of a request
+fn market_type(market: pb::MarketType) -> MarketType {
+    match market {
+        pb::MarketType::Spot => MarketType::Spot,
+        pb::MarketType::Perp => MarketType::Perp,
+    }
+}
+
+/// Sends a proposal to the raft loop and waits for its result
+///
+/// # Arguments
+///
+/// * `proposal` - Conf change, leader transfer or snapshot request
+/// * `rx` - Receiver of the result of the proposal
+/// * `deadline` - Point in time the caller stops waiting at
+/// * `failure` - Message of the ABORTED status answered if the proposal failed
+///
+/// # Returns
+///
+/// Returns Ok once the proposal succeeded, or an error status; UNAVAILABLE if
+/// the node lost leadership before taking the proposal up
+async fn submit(
+    proposal: Proposal,
+    rx: Receiver<bool>,
+    deadline: Instant,
+    failure: &str,
+) -> Result<(), tonic::Status> {
+    let proposal = proposal.with_deadline(Some(deadline));
+    let sender = server::instance().lock().await.proposal_sender(true);
+    let _ = sender.send(proposal).await;
+    let result = tokio::time::timeout_at(deadline, rx)
+        .await
+        .map_err(|_| tonic::Status::deadline_exceeded("deadline exceeded"))?;
+    match result {
+        Ok(true) => Ok(()),
+        _ if deadline <= Instant::now() => {
+            Err(tonic::Status::deadline_exceeded("deadline exceeded"))
+        }
+        Ok(false) => Err(tonic::Status::aborted(failure)),
+        Err(_) => Err(tonic::Status::unavailable(
+            "not leader, the proposal was dropped",
+        )),
+    }
+}
+
+/// Proposes a symbol command on behalf of an operator and waits until it is applied
+///
+/// # Arguments
+///
+/// * `request` - Incoming request, carrying the request ID and deadline
+/// * `cmd` - Type of the command
+/// * `tenant` - Tenant of the symbol
+/// * `market` - Market the symbol is listed on
+/// * `symbol` - ID of the symbol
+/// * `method` - Name of the RPC method, used in the latency trace
+///
+/// # Returns
+///
+/// Returns the tenant and request ID of the command once it is applied, or an
+/// error status
+async fn propose_symbol_cmd<T>(
+    request: &tonic::Request<T>,
+    cmd: MatchCmdType,
+    (tenant, market, symbol): (&str, pb::MarketType, &str),
+    method: &'static str,
+) -> Result<(String, String), tonic::Status> {
+    check_admin(request)?;
+    if symbol.is_empty() {
+        return Err(tonic::Status::invalid_argument("a symbol is required"));
+    }
+    let tenant = check_tenant(tenant)?;
+    let mut trace = RequestTrace::new(method);
+    let cmd = MatchCmd {
+        cmd,
+        tenant: tenant.clone(),
+        symbol: Some(Symbol {
+            name: symbol.to_string(),
+            ..Default::default()
+        }),
+        market: market_type(market),
+        ..Default::default()
+    };
+    let envelope = envelope(request, 0, cmd);
+    let request_id = envelope.request_id.clone();
+    propose(envelope, request_deadline(request), true, &mut trace).await?;
+    Ok((tenant, request_id))
+}
+
+#[tonic::async_trait]
+impl AdminService for AdminServiceSVC {
+    /// Returns the raft state of this node
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Cluster status request
+    ///
+    /// # Returns
+    ///
+    /// Returns the role, term and log indexes of the node, and the members of the
+    /// configuration with their replication progress if the node leads
+    async fn get_cluster_status(
+        &self,
+        request: tonic::Request<GetClusterStatusRequest>,
+    ) -> Result<tonic::Response<GetClusterStatusResponse>, tonic::Status> {
+        check_admin(&request)?;
+        let status = raft_status()?;
+        Ok(tonic::Response::new(GetClusterStatusResponse {
+            id: status.id,
+            term: status.term,
+            leader_id: status.leader_id,
+            role: status.role.to_string(),
+            commit_index: status.commit_index,
+            applied_index: status.applied_index,
+            first_index: status.first_index,
+            last_index: status.last_index,
+            lead_transferee: status.lead_transferee,
+            peers: status
+                .peers
+                .into_iter()
+                .map(|peer| PeerStatus {
+                    id: peer.id,
+                    voter: peer.voter,
+                    matched: peer.matched,
+                    state: peer.state.to_string(),
+                    recent_active: peer.recent_active,
+                })
+                .collect(),
+        }))
+    }
+
+    /// Adds a node to the cluster
+    ///
+    /// The node must be in `node_list`, members look up its address there.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Add node request
+    ///
+    /// # Returns
+    ///
+    /// Returns once the configuration change is applied on the leader
+    async fn add_node(
+        &self,
+        request: tonic::Request<AddNodeRequest>,
+    ) -> Result<tonic::Response<AddNodeResponse>, tonic::Status> {
+        log::info!("add node {:?}", request.get_ref());
+        check_admin(&request)?;
+        check_leader()?;
+        let node_id = request.get_ref().node_id;
+        let listed = config::instance()
+            .lock()
+            .unwrap()
+            .node_list
+            .iter()
+            .any(|node| node.id == node_id);
+        if !listed {
+            return Err(tonic::Status::failed_precondition(format!(
+                "node {} is not in node_list, add it to the configuration of every member first",
+                node_id
+            )));
+        }
+        let mut conf_change = ConfChange::default();
+        conf_change.node_id = node_id;
+        conf_change.set_change_type(if request.get_ref().learner {
+            ConfChangeType::AddLearnerNode
+        } else {
+            ConfChangeType::AddNode
+        });
+        let (proposal, rx) = Proposal::conf_change(&conf_change);
+        submit(
+            proposal,
+            rx,
+            call_deadline(&request),
+            "configuration change refused: another one is pending or it would demote a voter",
+        )
+        .await?;
+        Ok(tonic::Response::new(AddNodeResponse {}))
+    }
+
+    /// Removes a node from the cluster
+    ///
+    /// The leader and the last voter are not removed, leadership has to be
+    /// transferred first.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Remove node request
+    ///
+    /// # Returns
+    ///
+    /// Returns once the configuration change is applied on the leader
+    async fn remove_node(
+        &self,
+        request: tonic::Request<RemoveNodeRequest>,
+    ) -> Result<tonic::Response<RemoveNodeResponse>, tonic::Status> {
+        log::info!("remove node {:?}", request.get_ref());
+        check_admin(&request)?;
+        check_leader()?;
+        let node_id = request.get_ref().node_id;
+        let status = raft_status()?;
+        if node_id == status.id {
+            return Err(tonic::Status::failed_precondition(
+                "the leader cannot remove itself, transfer leadership first",
+            ));
+        }
+        if !status.peers.iter().any(|peer| peer.id == node_id) {
+            return Err(tonic::Status::not_found(format!(
+                "node {} is not a member",
+                node_id
+            )));
+        }
+        let mut conf_change = ConfChange::default();
+        conf_change.node_id = node_id;
+        conf_change.set_change_type(ConfChangeType::RemoveNode);
+        let (proposal, rx) = Proposal::conf_change(&conf_change);
+        submit(
+            proposal,
+            rx,
+            call_deadline(&request),
+            "configuration change refused: another one is pending",
+        )
+        .await?;
+        Ok(tonic::Response::new(RemoveNodeResponse {}))
+    }
+
+    /// Transfers leadership to another voter
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Transfer leader request
+    ///
+    /// # Returns
+    ///
+    /// Returns once the transferee leads, ABORTED if the transfer timed out
+    /// or another node was elected
+    async fn transfer_leader(
+        &self,
+        request: tonic::Request<TransferLeaderRequest>,
+    ) -> Result<tonic::Response<TransferLeaderResponse>, tonic::Status> {
+        log::info!("transfer leader {:?}", request.get_ref());
+        check_admin(&request)?;
+        check_leader()?;
+        let (proposal, rx) = Proposal::transfer_leader(request.get_ref().node_id);
+        submit(
+            proposal,
+            rx,
+            call_deadline(&request),
+            "transfer failed: the node is not a voter, did not catch up in time or lost the election",
+        )
+        .await?;
+        Ok(tonic::Response::new(TransferLeaderResponse {}))
+    }
+
+    /// Saves a snapshot of this node and compacts its log like the periodic save
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Trigger snapshot request
+    ///
+    /// # Returns
+    ///
+    /// Returns once the snapshot is saved
+    async fn trigger_snapshot(
+        &self,
+        request: tonic::Request<TriggerSnapshotRequest>,
+    ) -> Result<tonic::Response<TriggerSnapshotResponse>, tonic::Status> {
+        log::info!("trigger snapshot");
+        check_admin(&request)?;
+        let (proposal, rx) = Proposal::snapshot(SnapshotRequest::Save);
+        submit(
+            proposal,
+            rx,
+            call_deadline(&request),
+            "snapshot failed, see the log of the node",
+        )
+        .await?;
+        Ok(tonic::Response::new(TriggerSnapshotResponse {}))
+    }
+
+    /// Saves a snapshot of this node and compacts its log up to it
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Compact log request
+    ///
+    /// # Returns
+    ///
+    /// Returns once the snapshot is saved and the log compacted
+    async fn compact_log(
+        &self,
+        request: tonic::Request<CompactLogRequest>,
+    ) -> Result<tonic::Response<CompactLogResponse>, tonic::Status> {
+        log::info!("compact log");
+        check_admin(&request)?;
+        let (proposal, rx) = Proposal::snapshot(SnapshotRequest::Compact);
+        submit(
+            proposal,
+            rx,
+            call_deadline(&request),
+            "snapshot failed, see the log of the node",
+        )
+        .await?;
+        Ok(tonic::Response::new(CompactLogResponse {}))
+    }
+
+    /// Halts trading in a symbol until an operator resumes it
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Halt symbol request
+    ///
+    /// # Returns
+    ///
+    /// Returns once the command is applied; a symbol that is not active is left as is
+    async fn halt_symbol(
+        &self,
+        request: tonic::Request<HaltSymbolRequest>,
+    ) -> Result<tonic::Response<HaltSymbolResponse>, tonic::Status> {
+        log::info!("halt symbol {:?}", request.get_ref());
+        let req = request.get_ref();
+        let target = (req.tenant.as_str(), req.market(), req.symbol.as_str());
+        propose_symbol_cmd(&request, MatchCmdType::HaltSymbol, target, "halt_symbol").await?;
+        Ok(tonic::Response::new(HaltSymbolResponse {}))
+    }
+
+    /// Resumes trading in a halted symbol
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Resume symbol request
+    ///
+    /// # Returns
+    ///
+    /// Returns once the command is applied; a symbol that is not halted is left as is
+    async fn resume_symbol(
+        &self,
+        request: tonic::Request<ResumeSymbolRequest>,
+    ) -> Result<tonic::Response<ResumeSymbolResponse>, tonic::Status> {
+        log::info!("resume symbol {:?}", request.get_ref());
+        let req = request.get_ref();
+        let target = (req.tenant.as_str(), req.market(), req.symbol.as_str());
+        propose_symbol_cmd(
+            &request,
+            MatchCmdType::ResumeSymbol,
+            target,
+            "resume_symbol",
+        )
+        .await?;
+        Ok(tonic::Response::new(ResumeSymbolResponse {}))
+    }
+
+    /// Cancels every order on a symbol, of every account
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Mass cancel request
+    ///
+    /// # Returns
+    ///
+    /// Returns the IDs of the canceled orders once the command is applied
+    async fn mass_cancel(
+        &self,
+        request: tonic::Request<MassCancelRequest>,
+    ) -> Result<tonic::Response<MassCancelResponse>, tonic::Status> {
+        log::info!("mass cancel {:?}", request.get_ref());
+        let req = request.get_ref();
+        let target = (req.tenant.as_str(), req.market(), req.symbol.as_str());
+        let (tenant, request_id) =
+            propose_symbol_cmd(&request, MatchCmdType::MassCancel, target, "mass_cancel").await?;
+        // Published before the proposal was acknowledged; a retry of an applied
+        // request reads the outcome of its first apply
+        let order_ids = read_view::cancellation(&tenant, &request_id)
+            .map(|cancellation| cancellation.order_ids)
+            .unwrap_or_default();
+        Ok(tonic::Response::new(MassCancelResponse { order_ids }))
+    }
+}
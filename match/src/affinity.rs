@@ -0,0 +1,93 @@
+//! Thread placement
+//!
+//! The raft loop, the apply workers and the gRPC runtime can be kept on separate
+//! cores, see `RuntimeLayout`, so the scheduler does not move the hot apply path
+//! around or let request handling preempt it. Pinning is only supported on Linux;
+//! elsewhere threads are left where the scheduler puts them and a warning is
+//! logged once per thread.
+
+/// Pins the calling thread to a set of cores
+///
+/// # Arguments
+///
+/// * `cores` - IDs of the cores the thread may run on, empty leaves it unpinned
+///
+/// # Returns
+///
+/// Returns why the thread could not be pinned
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cores: &[usize]) -> Result<(), String> {
+    if cores.is_empty() {
+        return Ok(());
+    }
+    // SAFETY: cpu_set_t is a plain bit set, all zeroes is the empty set
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &core in cores {
+        if core >= libc::CPU_SETSIZE as usize {
+            return Err(format!("core {} is beyond the supported cores", core));
+        }
+        // SAFETY: the core is within the set, checked above
+        unsafe { libc::CPU_SET(core, &mut set) };
+    }
+    // SAFETY: the set outlives the call and its size is passed along
+    let result =
+        unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if result != 0 {
+        return Err(format!(
+            "failed to pin to cores {:?}: {}",
+            cores,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Pins the calling thread to a set of cores, unsupported on this platform
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(cores: &[usize]) -> Result<(), String> {
+    if cores.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "pinning to cores {:?} is only supported on Linux",
+        cores
+    ))
+}
+
+/// Pins the calling thread, logging instead of failing if it cannot be pinned
+///
+/// # Arguments
+///
+/// * `name` - Name of the thread for the log
+/// * `cores` - IDs of the cores the thread may run on, empty leaves it unpinned
+pub fn pin_or_warn(name: &str, cores: &[usize]) {
+    if let Err(e) = pin_current_thread(cores) {
+        log::warn!("{} thread not pinned: {}", name, e);
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_to_an_allowed_core() {
+        std::thread::spawn(|| {
+            // SAFETY: the set is only written by sched_getaffinity
+            let mut allowed: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            let size = std::mem::size_of::<libc::cpu_set_t>();
+            assert_eq!(unsafe { libc::sched_getaffinity(0, size, &mut allowed) }, 0);
+            let core = (0..libc::CPU_SETSIZE as usize)
+                .find(|core| unsafe { libc::CPU_ISSET(*core, &allowed) })
+                .unwrap();
+
+            pin_current_thread(&[core]).unwrap();
+            let mut pinned: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            assert_eq!(unsafe { libc::sched_getaffinity(0, size, &mut pinned) }, 0);
+            assert_eq!(unsafe { libc::CPU_COUNT(&pinned) }, 1);
+            assert!(pin_current_thread(&[libc::CPU_SETSIZE as usize]).is_err());
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+//! Allocation accounting
+//!
+//! This module provides a global allocator that wraps the allocator selected at
+//! build time and counts allocations, so memory growth of long-running nodes can
+//! be followed from the metrics endpoint. The system allocator is used unless the
+//! `jemalloc` or `mimalloc` feature is enabled.
+//!
+//! With jemalloc its own statistics are served on `/debug/heap` as well. With the
+//! `heap-profiling` feature and profiling switched on at startup
+//! (`_RJEM_MALLOC_CONF=prof:true`) jemalloc samples allocations, and
+//! `/debug/heap/profile` dumps a profile for `jeprof`.
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the jemalloc and mimalloc features select competing allocators, enable one");
+
+use serde_json::json;
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+#[cfg(feature = "mimalloc")]
+use mimalloc::MiMalloc as Inner;
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+use std::alloc::System as Inner;
+#[cfg(feature = "jemalloc")]
+use tikv_jemallocator::Jemalloc as Inner;
+
+/// Name of the allocator selected at build time
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub const NAME: &str = "system";
+/// Name of the allocator selected at build time
+#[cfg(feature = "mimalloc")]
+pub const NAME: &str = "mimalloc";
+/// Name of the allocator selected at build time
+#[cfg(feature = "jemalloc")]
+pub const NAME: &str = "jemalloc";
+
+/// Bytes currently allocated
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+/// Number of allocations since start
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+/// Number of deallocations since start
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Snapshot of the allocation counters
+#[derive(Debug, Clone, Copy)]
+pub struct AllocStats {
+    /// Bytes currently allocated
+    pub allocated: usize,
+    /// Number of allocations since start
+    pub allocations: u64,
+    /// Number of deallocations since start
+    pub deallocations: u64,
+}
+
+/// Returns the current allocation counters
+pub fn stats() -> AllocStats {
+    AllocStats {
+        allocated: ALLOCATED.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+    }
+}
+
+/// Allocator wrapper keeping allocation counters
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = Inner.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = Inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        Inner.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = Inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+/// Describes the heap for the `/debug/heap` endpoint
+///
+/// # Returns
+///
+/// Returns the allocation counters and, with jemalloc, its statistics
+pub fn heap_stats() -> serde_json::Value {
+    let stats = stats();
+    json!({
+        "allocator": NAME,
+        "allocated_bytes": stats.allocated,
+        "allocations": stats.allocations,
+        "deallocations": stats.deallocations,
+        "jemalloc": jemalloc_stats(),
+    })
+}
+
+/// Reads jemalloc's statistics, refreshed first
+#[cfg(feature = "jemalloc")]
+fn jemalloc_stats() -> serde_json::Value {
+    use tikv_jemalloc_ctl::{epoch, stats};
+    let read = || -> tikv_jemalloc_ctl::Result<serde_json::Value> {
+        epoch::advance()?;
+        Ok(json!({
+            "allocated_bytes": stats::allocated::read()?,
+            "active_bytes": stats::active::read()?,
+            "metadata_bytes": stats::metadata::read()?,
+            "resident_bytes": stats::resident::read()?,
+            "mapped_bytes": stats::mapped::read()?,
+            "retained_bytes": stats::retained::read()?,
+        }))
+    };
+    read().unwrap_or_else(|e| json!({ "error": e.to_string() }))
+}
+
+/// Reads jemalloc's statistics, null in builds without jemalloc
+#[cfg(not(feature = "jemalloc"))]
+fn jemalloc_stats() -> serde_json::Value {
+    serde_json::Value::Null
+}
+
+/// Dumps a jemalloc heap profile
+///
+/// # Returns
+///
+/// Returns the profile in the format read by `jeprof`, or why none was taken
+#[cfg(feature = "heap-profiling")]
+pub fn heap_profile() -> Result<Vec<u8>, String> {
+    use std::ffi::CString;
+    use tikv_jemalloc_ctl::raw;
+    // SAFETY: opt.prof is a bool option and the name is NUL terminated
+    let enabled: bool = unsafe { raw::read(b"opt.prof\0") }.map_err(|e| e.to_string())?;
+    if !enabled {
+        return Err(
+            "heap profiling is off, start the node with _RJEM_MALLOC_CONF=prof:true".into(),
+        );
+    }
+    let file = tempfile::NamedTempFile::new().map_err(|e| e.to_string())?;
+    let path = CString::new(file.path().to_string_lossy().as_bytes()).map_err(|e| e.to_string())?;
+    // SAFETY: prof.dump takes a NUL terminated path, which outlives the call
+    unsafe { raw::write(b"prof.dump\0", path.as_ptr()) }.map_err(|e| e.to_string())?;
+    std::fs::read(file.path()).map_err(|e| e.to_string())
+}
@@ -0,0 +1,91 @@
+//! Order book self-consistency audit
+//!
+//! With `book_audit_interval_ms` set, a background task checks the invariants of
+//! every order book changed since its last audit, see `OrderBook::audit`. Books
+//! are read through `read_view::book`, so the audit sees each book as of a known
+//! applied index and never holds up matching; a book written while it is being
+//! audited is copied once. Violations are logged with the index they were found
+//! at and counted in `book_audit_violations{tenant,symbol,invariant}`, and
+//! `book_audit_failing{tenant,symbol}` stays 1 until a later audit of the book
+//! passes, so engine bugs show up before a snapshot carries them to other nodes.
+
+use crate::engine::data::Violation;
+use crate::{config, metrics, read_view};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Starts the audit task if an interval is configured
+pub fn start() {
+    let Some(interval_ms) = config::instance().lock().unwrap().book_audit_interval_ms else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut audited = HashMap::new();
+        let mut interval = tokio::time::interval(Duration::from_millis(interval_ms.max(1)));
+        loop {
+            interval.tick().await;
+            run(&mut audited).await;
+        }
+    });
+}
+
+/// Audits the books published since their last audit
+///
+/// # Arguments
+///
+/// * `audited` - Index each book was last audited at, by tenant and symbol
+async fn run(audited: &mut HashMap<(String, String), u64>) {
+    let published = read_view::published_books();
+    audited.retain(|(tenant, symbol), _| {
+        let listed = published.iter().any(|(t, s, _)| t == tenant && s == symbol);
+        if !listed {
+            let _ = metrics::BOOK_AUDIT_FAILING_GAUGE_VEC.remove_label_values(&[tenant, symbol]);
+        }
+        listed
+    });
+    for (tenant, symbol, index) in published {
+        let key = (tenant, symbol);
+        if audited.get(&key) == Some(&index) {
+            continue;
+        }
+        let Some(view) = read_view::book(&key.0, &key.1).await else {
+            continue;
+        };
+        let index = view.index;
+        match tokio::task::spawn_blocking(move || view.orderbook().audit()).await {
+            Ok(violations) => report(&key.0, &key.1, index, &violations),
+            Err(e) => {
+                log::error!("order book audit of {} failed: {}", key.1, e);
+                continue;
+            }
+        }
+        audited.insert(key, index);
+    }
+}
+
+/// Logs and counts the violations found in a book
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the symbol belongs to
+/// * `symbol` - Symbol of the book
+/// * `index` - Applied index the book was audited at
+/// * `violations` - Violations found, empty if the book is consistent
+fn report(tenant: &str, symbol: &str, index: u64, violations: &[Violation]) {
+    for violation in violations {
+        log::error!(
+            "order book {} of tenant {} violates {} at index {}: {}",
+            symbol,
+            tenant,
+            violation.invariant,
+            index,
+            violation.detail
+        );
+        metrics::BOOK_AUDIT_VIOLATION_COUNTER_VEC
+            .with_label_values(&[tenant, symbol, violation.invariant])
+            .inc();
+    }
+    metrics::BOOK_AUDIT_FAILING_GAUGE_VEC
+        .with_label_values(&[tenant, symbol])
+        .set(!violations.is_empty() as i64);
+}
@@ -0,0 +1,535 @@
+//! Regulatory audit trail
+//!
+//! With `audit_trail_path` set, every command applied by this node is appended to
+//! segment files in that directory as a length-delimited `AuditRecord`: the
+//! command as replicated with its request ID, client, account and timestamps, and
+//! what it did, i.e. the orders it placed or canceled, its trades, settlements and
+//! funding outcome. Duplicates of an applied request ID are recorded as such.
+//!
+//! Records are written and flushed on the raft loop after each apply batch, like
+//! the settlement journal, and never dropped. The trail is append-only and
+//! ordered by raft index: records of entries replayed after a restart are skipped,
+//! and a record cut short by a crash is truncated when the trail is opened again.
+//! A segment is closed once it reaches `audit_trail_segment_bytes` and named after
+//! the index of its first record, so an export of an index range only reads the
+//! segments covering it. Like the query store, the trail holds what this node
+//! applied; a node that catches up through a snapshot lacks the commands before it.
+//!
+//! `ExportAuditTrail` streams the records of a tenant within an index and engine
+//! time range for investigations.
+
+use crate::config;
+use crate::drop_copy::order_ref;
+use crate::engine::entry::OrderSide;
+use crate::engine::history::{CommandRecord, OrderChange, OrderEvent};
+use crate::engine::matchengine::{FundingRecord, Settlement};
+use crate::match_service::pb::{self, AuditRecord};
+use crate::match_service::{order_state, trade_record};
+use crate::query_store::{OrderRecord, TradeRecord};
+use crate::{funding_log, settlement_log};
+use once_cell::sync::OnceCell;
+use prost::Message;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::sync::mpsc::{self, Receiver};
+
+/// Records buffered per export stream
+const EXPORT_BUFFER: usize = 1024;
+
+/// Trail of this node, None if the trail is disabled
+static TRAIL: OnceCell<Option<Mutex<Trail>>> = OnceCell::new();
+
+/// Stream of records sent to an export
+pub type RecordStream = Receiver<Result<AuditRecord, tonic::Status>>;
+
+/// Bounds of an export, records must lie within all of them
+#[derive(Debug, Clone, Default)]
+pub struct Range {
+    /// Tenant the records belong to
+    pub tenant: String,
+    /// First raft index
+    pub from_index: u64,
+    /// Last raft index, None for no bound
+    pub to_index: Option<u64>,
+    /// First engine time in milliseconds
+    pub from_time: u64,
+    /// Last engine time in milliseconds, None for no bound
+    pub to_time: Option<u64>,
+}
+
+impl Range {
+    /// Returns whether a record lies within the bounds
+    fn contains(&self, record: &AuditRecord) -> bool {
+        record.tenant == self.tenant
+            && record.index >= self.from_index
+            && record.engine_time >= self.from_time
+            && self.to_time.map_or(true, |to| record.engine_time <= to)
+    }
+
+    /// Returns whether records from the index on lie past the bounds
+    fn ends_before(&self, index: u64) -> bool {
+        self.to_index.is_some_and(|to| index > to)
+    }
+}
+
+/// Append-only segment files of the audit trail
+pub struct Trail {
+    /// Directory the segments are kept in
+    dir: PathBuf,
+    /// Size after which a new segment is started
+    segment_bytes: u64,
+    /// Segment records are appended to, None until the first record
+    file: Option<BufWriter<File>>,
+    /// Bytes written to the current segment
+    size: u64,
+    /// Index of the last record written
+    last_index: u64,
+}
+
+impl Trail {
+    /// Opens the trail in a directory, creating it if needed
+    ///
+    /// A record cut short at the end of the last segment is truncated.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Directory of the segments
+    /// * `segment_bytes` - Size after which a new segment is started
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the directory or last segment cannot be read
+    pub fn open(dir: &str, segment_bytes: u64) -> Result<Trail, String> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("cannot create audit trail {}: {}", dir, e))?;
+        let dir = PathBuf::from(dir);
+        let mut trail = Trail {
+            dir: dir.clone(),
+            segment_bytes,
+            file: None,
+            size: 0,
+            last_index: 0,
+        };
+        let Some((_, path)) = segments(&dir)?.pop() else {
+            return Ok(trail);
+        };
+        let data = std::fs::read(&path).map_err(|e| format!("cannot read {:?}: {}", path, e))?;
+        let mut buf = data.as_slice();
+        while !buf.is_empty() {
+            match AuditRecord::decode_length_delimited(&mut buf) {
+                Ok(record) => trail.last_index = record.index,
+                Err(_) => break,
+            }
+        }
+        let valid = (data.len() - buf.len()) as u64;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("cannot open {:?}: {}", path, e))?;
+        if !buf.is_empty() {
+            log::warn!(
+                "truncating {} bytes of a record cut short in {:?}",
+                buf.len(),
+                path
+            );
+            file.set_len(valid)
+                .map_err(|e| format!("cannot truncate {:?}: {}", path, e))?;
+        }
+        trail.file = Some(BufWriter::new(file));
+        trail.size = valid;
+        Ok(trail)
+    }
+
+    /// Appends records not written yet and flushes the segment
+    ///
+    /// # Arguments
+    ///
+    /// * `records` - Records in raft log order
+    pub fn append(&mut self, records: &[AuditRecord]) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        for record in records {
+            if record.index <= self.last_index {
+                continue;
+            }
+            if self.file.is_none() || self.size >= self.segment_bytes {
+                if let Some(file) = self.file.as_mut() {
+                    file.flush()?;
+                }
+                let path = self.dir.join(segment_name(record.index));
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                self.file = Some(BufWriter::new(file));
+                self.size = 0;
+            }
+            buf.clear();
+            record
+                .encode_length_delimited(&mut buf)
+                .expect("encode audit record");
+            self.file.as_mut().unwrap().write_all(&buf)?;
+            self.size += buf.len() as u64;
+            self.last_index = record.index;
+        }
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Name of the segment starting at a raft index
+fn segment_name(index: u64) -> String {
+    format!("audit-{:020}.log", index)
+}
+
+/// Lists the segments of a trail by the index of their first record
+fn segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>, String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("cannot list {:?}: {}", dir, e))?;
+    let mut segments: Vec<(u64, PathBuf)> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path.file_name()?.to_str()?;
+            let index = name.strip_prefix("audit-")?.strip_suffix(".log")?;
+            Some((index.parse().ok()?, path))
+        })
+        .collect();
+    segments.sort();
+    Ok(segments)
+}
+
+/// Reads the records of a trail within a range in log order
+///
+/// Segments entirely before the range are skipped. Reading stops at a record
+/// that does not decode, e.g. one being appended.
+///
+/// # Arguments
+///
+/// * `dir` - Directory of the segments
+/// * `range` - Bounds of the records
+/// * `each` - Called with every record in range, returns false to stop
+///
+/// # Returns
+///
+/// Returns an error if a segment cannot be read
+pub fn read(
+    dir: &Path,
+    range: &Range,
+    mut each: impl FnMut(AuditRecord) -> bool,
+) -> Result<(), String> {
+    let segments = segments(dir)?;
+    for (i, (first, path)) in segments.iter().enumerate() {
+        if range.ends_before(*first) {
+            break;
+        }
+        let next = segments.get(i + 1).map(|(next, _)| *next);
+        if next.is_some_and(|next| next <= range.from_index) {
+            continue;
+        }
+        let data = std::fs::read(path).map_err(|e| format!("cannot read {:?}: {}", path, e))?;
+        let mut buf = data.as_slice();
+        while !buf.is_empty() {
+            let Ok(record) = AuditRecord::decode_length_delimited(&mut buf) else {
+                break;
+            };
+            if range.ends_before(record.index) {
+                return Ok(());
+            }
+            if range.contains(&record) && !each(record) {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Joins the commands of an apply batch with what they did
+///
+/// # Arguments
+///
+/// * `commands` - Commands of the batch, in log order
+/// * `order_events` - Order events of the batch
+/// * `settlements` - Settlements of the batch
+/// * `funding` - Funding records of the batch
+pub(crate) fn records(
+    commands: &[CommandRecord],
+    order_events: &[OrderEvent],
+    settlements: &[Settlement],
+    funding: &[FundingRecord],
+) -> Vec<AuditRecord> {
+    let mut records: Vec<AuditRecord> = commands
+        .iter()
+        .map(|command| AuditRecord {
+            index: command.index,
+            tenant: command.tenant.clone(),
+            request_id: command.request_id.clone(),
+            client_id: command.client_id.clone(),
+            account_id: command.account_id,
+            proposed_at: command.proposed_at,
+            engine_time: command.time_ms,
+            command: format!("{:?}", command.cmd),
+            duplicate: command.duplicate,
+            data: command.data.clone(),
+            ..Default::default()
+        })
+        .collect();
+    let positions: BTreeMap<u64, usize> = records
+        .iter()
+        .enumerate()
+        .map(|(i, record)| (record.index, i))
+        .collect();
+    let record_of = |index: u64| positions.get(&index).copied();
+    for event in order_events {
+        let Some(i) = record_of(event.index) else {
+            continue;
+        };
+        let record = &mut records[i];
+        match &event.change {
+            OrderChange::Placed { order, trades } => {
+                record.orders.push(order_state(OrderRecord {
+                    index: event.index,
+                    account_id: event.account_id,
+                    order: order.clone(),
+                    last_change: (event.index, trades.len() as u32),
+                }));
+                for (seq, trade) in trades.iter().enumerate() {
+                    let taker_side = if trade.buyer_order_id == order.id {
+                        OrderSide::Buy
+                    } else {
+                        OrderSide::Sell
+                    };
+                    let (buyer_account_id, seller_account_id) = match taker_side {
+                        OrderSide::Buy => (event.account_id, 0),
+                        OrderSide::Sell => (0, event.account_id),
+                    };
+                    record.trades.push(trade_record(TradeRecord {
+                        index: event.index,
+                        trade_seq: seq as u32,
+                        symbol: order.symbol.clone(),
+                        taker_side,
+                        price: trade.price,
+                        quantity: trade.quantity,
+                        buyer_order_id: trade.buyer_order_id.clone(),
+                        buyer_account_id,
+                        seller_order_id: trade.seller_order_id.clone(),
+                        seller_account_id,
+                        time: event.time,
+                    }));
+                }
+            }
+            OrderChange::Canceled { symbol, order_id } => {
+                let status = pb::OrderStatus::Canceled;
+                let order = order_ref(event.account_id, symbol, order_id, status);
+                record.orders.push(order);
+            }
+            OrderChange::SymbolRemoved { symbol } => record.removed_symbol = symbol.clone(),
+            OrderChange::SymbolListed { .. }
+            | OrderChange::SymbolHalted { .. }
+            | OrderChange::SymbolResumed { .. } => {}
+        }
+    }
+    for settlement in settlements {
+        if let Some(i) = record_of(settlement.index) {
+            records[i]
+                .settlements
+                .push(settlement_log::event(settlement));
+        }
+    }
+    for funding in funding {
+        if let Some(i) = record_of(funding.index) {
+            records[i].funding = Some(funding_log::event(funding));
+        }
+    }
+    records
+}
+
+/// Opens the audit trail if `audit_trail_path` is configured
+///
+/// Must be called before the raft loop starts applying entries.
+///
+/// # Returns
+///
+/// Returns an error if the trail cannot be opened
+pub fn open() -> Result<(), String> {
+    let (path, segment_bytes) = {
+        let config = config::instance().lock().unwrap();
+        (
+            config.audit_trail_path.clone(),
+            config.audit_trail_segment_bytes,
+        )
+    };
+    let trail = match path {
+        Some(path) => {
+            let trail = Trail::open(&path, segment_bytes.max(1))?;
+            log::info!(
+                "writing the audit trail to {} from index {}",
+                path,
+                trail.last_index + 1
+            );
+            Some(Mutex::new(trail))
+        }
+        None => None,
+    };
+    let _ = TRAIL.set(trail);
+    Ok(())
+}
+
+/// Returns whether the audit trail is enabled
+pub fn is_open() -> bool {
+    matches!(TRAIL.get(), Some(Some(_)))
+}
+
+/// Appends the commands of an apply batch with what they did to the trail
+///
+/// # Arguments
+///
+/// * `commands` - Commands of the batch, in log order
+/// * `order_events` - Order events of the batch
+/// * `settlements` - Settlements of the batch
+/// * `funding` - Funding records of the batch
+pub fn write(
+    commands: &[CommandRecord],
+    order_events: &[OrderEvent],
+    settlements: &[Settlement],
+    funding: &[FundingRecord],
+) {
+    let trail = match TRAIL.get() {
+        Some(Some(trail)) if !commands.is_empty() => trail,
+        _ => return,
+    };
+    let records = records(commands, order_events, settlements, funding);
+    if let Err(e) = trail.lock().unwrap().append(&records) {
+        log::error!(
+            "cannot write {} records to the audit trail: {}",
+            records.len(),
+            e
+        );
+    }
+}
+
+/// Streams the records of a range from the trail of this node
+///
+/// # Arguments
+///
+/// * `range` - Bounds of the records
+///
+/// # Returns
+///
+/// Returns the records in log order, or why the trail cannot be read
+pub fn export(range: Range) -> Result<RecordStream, String> {
+    let dir = match TRAIL.get() {
+        Some(Some(trail)) => trail.lock().unwrap().dir.clone(),
+        _ => return Err("audit trail exports need audit_trail_path to be configured".to_string()),
+    };
+    let (sender, receiver) = mpsc::channel(EXPORT_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        let result = read(&dir, &range, |record| {
+            sender.blocking_send(Ok(record)).is_ok()
+        });
+        if let Err(e) = result {
+            log::error!("audit trail export failed: {}", e);
+            let _ = sender.blocking_send(Err(tonic::Status::internal(e)));
+        }
+    });
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(index: u64, tenant: &str, engine_time: u64) -> AuditRecord {
+        AuditRecord {
+            index,
+            tenant: tenant.to_string(),
+            engine_time,
+            command: "PlaceOrder".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn records_are_appended_once_across_segments_and_read_by_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut trail = Trail::open(path, 1).unwrap();
+        let batch: Vec<_> = (1..=4).map(|i| record(i, "a", i * 1000)).collect();
+        trail.append(&batch).unwrap();
+        drop(trail);
+
+        // A restart replays the batch and appends the next one
+        let mut trail = Trail::open(path, 1).unwrap();
+        assert_eq!(trail.last_index, 4);
+        let mut replay = batch.clone();
+        replay.push(record(5, "b", 5000));
+        replay.push(record(6, "a", 6000));
+        trail.append(&replay).unwrap();
+        assert_eq!(segments(dir.path()).unwrap().len(), 6);
+
+        let indexes = |range: Range| {
+            let mut indexes = Vec::new();
+            read(dir.path(), &range, |record| {
+                indexes.push(record.index);
+                true
+            })
+            .unwrap();
+            indexes
+        };
+        let tenant = "a".to_string();
+        assert_eq!(
+            indexes(Range {
+                tenant: tenant.clone(),
+                ..Default::default()
+            }),
+            [1, 2, 3, 4, 6]
+        );
+        assert_eq!(
+            indexes(Range {
+                tenant: tenant.clone(),
+                from_index: 2,
+                to_index: Some(5),
+                ..Default::default()
+            }),
+            [2, 3, 4]
+        );
+        assert_eq!(
+            indexes(Range {
+                tenant,
+                from_time: 3000,
+                to_time: Some(6000),
+                ..Default::default()
+            }),
+            [3, 4, 6]
+        );
+    }
+
+    #[test]
+    fn a_record_cut_short_is_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut trail = Trail::open(path, 1 << 20).unwrap();
+        trail
+            .append(&[record(1, "a", 0), record(2, "a", 0)])
+            .unwrap();
+        drop(trail);
+        let (_, segment) = segments(dir.path()).unwrap().pop().unwrap();
+        let len = std::fs::metadata(&segment).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&segment).unwrap();
+        file.set_len(len - 1).unwrap();
+
+        let mut trail = Trail::open(path, 1 << 20).unwrap();
+        assert_eq!(trail.last_index, 1);
+        trail.append(&[record(2, "a", 0)]).unwrap();
+        let mut indexes = Vec::new();
+        let range = Range {
+            tenant: "a".to_string(),
+            ..Default::default()
+        };
+        read(dir.path(), &range, |record| {
+            indexes.push(record.index);
+            true
+        })
+        .unwrap();
+        assert_eq!(indexes, [1, 2]);
+    }
+}
@@ -0,0 +1,266 @@
+//! Raft log inspection tool
+//!
+//! Reads the segment files of a node offline and prints every entry with its
+//! index and term, decoding conf changes and match commands. Entries are shown
+//! in file order, so entries that raft later overwrote appear next to their
+//! replacements.
+
+use clap::{Parser, ValueEnum};
+use protobuf::Message;
+use raft::eraftpb::{ConfChange, ConfChangeV2, Entry, EntryType};
+use raft_match::engine::codec;
+use raft_match::engine::matchengine::CommandEnvelope;
+use raft_match::raft::segment;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Data directories (`base_path`) or individual segment files
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+
+    /// First raft index to print
+    #[arg(long)]
+    from: Option<u64>,
+
+    /// Last raft index to print
+    #[arg(long)]
+    to: Option<u64>,
+
+    /// Only print commands of this symbol
+    #[arg(long)]
+    symbol: Option<String>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value = "text")]
+    format: Format,
+}
+
+/// Output format of the dump
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Format {
+    /// One line per entry
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Decoded payload of a log entry
+enum Payload {
+    /// Entry without data, appended by a new leader
+    Empty,
+    /// Match command
+    Command(Box<CommandEnvelope>),
+    /// Membership change
+    ConfChange(ConfChange),
+    /// Joint consensus membership change
+    ConfChangeV2(ConfChangeV2),
+    /// Data that could not be decoded
+    Invalid(String),
+}
+
+/// Lists the segment files of the given paths, ordered by start index within each directory
+///
+/// # Arguments
+///
+/// * `paths` - Data directories or segment files
+///
+/// # Returns
+///
+/// Returns the segment files, or an error if a directory cannot be read
+fn segment_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        let mut segments: Vec<_> = std::fs::read_dir(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                Some((segment::file_start_index(&path)?, path))
+            })
+            .collect();
+        segments.sort();
+        files.extend(segments.into_iter().map(|(_, path)| path));
+    }
+    Ok(files)
+}
+
+/// Decodes the payload of a log entry
+fn decode(entry: &Entry) -> Payload {
+    if entry.data.is_empty() {
+        return Payload::Empty;
+    }
+    match entry.get_entry_type() {
+        EntryType::EntryNormal => match codec::decode(&entry.data) {
+            Ok(envelope) => Payload::Command(Box::new(envelope)),
+            Err(e) => Payload::Invalid(e),
+        },
+        EntryType::EntryConfChange => {
+            let mut cc = ConfChange::default();
+            match cc.merge_from_bytes(&entry.data) {
+                Ok(()) => Payload::ConfChange(cc),
+                Err(e) => Payload::Invalid(e.to_string()),
+            }
+        }
+        EntryType::EntryConfChangeV2 => {
+            let mut cc = ConfChangeV2::default();
+            match cc.merge_from_bytes(&entry.data) {
+                Ok(()) => Payload::ConfChangeV2(cc),
+                Err(e) => Payload::Invalid(e.to_string()),
+            }
+        }
+    }
+}
+
+/// Returns the symbol a command operates on
+fn command_symbol(envelope: &CommandEnvelope) -> Option<&str> {
+    let cmd = &envelope.cmd;
+    cmd.order
+        .as_ref()
+        .map(|order| order.symbol.as_str())
+        .or_else(|| cmd.symbol.as_ref().map(|symbol| symbol.name.as_str()))
+}
+
+/// Prints an entry in the requested format
+fn print_entry(format: Format, file: &Path, entry: &Entry, payload: &Payload) {
+    match format {
+        Format::Text => {
+            let summary = match payload {
+                Payload::Empty => "EMPTY".to_string(),
+                Payload::Command(envelope) => {
+                    let cmd = &envelope.cmd;
+                    let detail = match (&cmd.order, &cmd.symbol) {
+                        (Some(order), _) => format!(
+                            "{} order {} {:?} {:?} {}@{}",
+                            order.symbol,
+                            order.id,
+                            order.side,
+                            order.order_type,
+                            order.quantity,
+                            order.price
+                        ),
+                        (None, Some(symbol)) => format!("{} {:?}", symbol.name, symbol.status),
+                        (None, None) => String::new(),
+                    };
+                    format!(
+                        "{:?} tenant={:?} request={:?} proposed_at={} hlc={} {}",
+                        cmd.cmd,
+                        cmd.tenant,
+                        envelope.request_id,
+                        envelope.proposed_at,
+                        envelope.hlc,
+                        detail
+                    )
+                }
+                Payload::ConfChange(cc) => {
+                    format!("CONF {:?} node {}", cc.get_change_type(), cc.node_id)
+                }
+                Payload::ConfChangeV2(cc) => format!(
+                    "CONF_V2 {:?}",
+                    cc.get_changes()
+                        .iter()
+                        .map(|c| (c.get_change_type(), c.node_id))
+                        .collect::<Vec<_>>()
+                ),
+                Payload::Invalid(e) => format!("INVALID {}", e),
+            };
+            println!("{:>10} {:>6} {}", entry.index, entry.term, summary);
+        }
+        Format::Json => {
+            let payload = match payload {
+                Payload::Empty => json!({ "type": "empty" }),
+                Payload::Command(envelope) => json!({ "type": "command", "command": envelope }),
+                Payload::ConfChange(cc) => json!({
+                    "type": "conf_change",
+                    "change_type": format!("{:?}", cc.get_change_type()),
+                    "node_id": cc.node_id,
+                }),
+                Payload::ConfChangeV2(cc) => json!({
+                    "type": "conf_change_v2",
+                    "changes": cc
+                        .get_changes()
+                        .iter()
+                        .map(|c| json!({
+                            "change_type": format!("{:?}", c.get_change_type()),
+                            "node_id": c.node_id,
+                        }))
+                        .collect::<Vec<Value>>(),
+                }),
+                Payload::Invalid(e) => json!({ "type": "invalid", "error": e }),
+            };
+            let mut line = json!({
+                "file": file.display().to_string(),
+                "index": entry.index,
+                "term": entry.term,
+            });
+            line.as_object_mut()
+                .unwrap()
+                .extend(payload.as_object().unwrap().clone());
+            println!("{}", line);
+        }
+    }
+}
+
+/// Prints the entries of a segment file that pass the filters
+///
+/// # Returns
+///
+/// Returns an error if the file is not a readable segment
+fn dump_segment(args: &Args, path: &Path) -> Result<(), String> {
+    let mut file =
+        File::open(path).map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+    let index = segment::scan(&mut file)
+        .map_err(|e| format!("corrupt segment {}: {}", path.display(), e))?;
+    for pos in index.entry_positions.values() {
+        let data = segment::read_entry_at(&mut file, *pos)
+            .map_err(|e| format!("corrupt segment {}: {}", path.display(), e))?;
+        let mut entry = Entry::default();
+        if let Err(e) = entry.merge_from_bytes(&data) {
+            eprintln!(
+                "{}: undecodable entry at offset {}: {}",
+                path.display(),
+                pos,
+                e
+            );
+            continue;
+        }
+        if args.from.is_some_and(|from| entry.index < from)
+            || args.to.is_some_and(|to| entry.index > to)
+        {
+            continue;
+        }
+        let payload = decode(&entry);
+        if let Some(symbol) = &args.symbol {
+            let matches = match &payload {
+                Payload::Command(envelope) => command_symbol(envelope) == Some(symbol.as_str()),
+                _ => false,
+            };
+            if !matches {
+                continue;
+            }
+        }
+        print_entry(args.format, path, &entry, &payload);
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let mut failed = false;
+    for path in segment_files(&args.paths)? {
+        if let Err(e) = dump_segment(&args, &path) {
+            eprintln!("{}", e);
+            failed = true;
+        }
+    }
+    if failed {
+        return Err("some segments could not be read".into());
+    }
+    Ok(())
+}
@@ -0,0 +1,663 @@
+//! Binary order entry
+//!
+//! With `binary_gateway_addr` set the node accepts order entry sessions over raw
+//! TCP next to gRPC, for clients whose latency budget is taken up by protobuf
+//! and HTTP/2. Messages have a fixed layout, SBE style: every frame is an 8 byte
+//! header, the body length (u16), the template ID (u16) and the sequence number
+//! (u32), followed by a body whose length is fixed per template. Integers are
+//! little-endian, text fields are UTF-8 padded with zeros, decimals are an i64
+//! mantissa followed by a u8 scale.
+//!
+//! | ID  | Direction | Message    | Body                                                  |
+//! |-----|-----------|------------|-------------------------------------------------------|
+//! | 1   | in        | Login      | api key (64), client ID (32), heartbeat ms (u32)      |
+//! | 2   | both      | Heartbeat  | empty                                                 |
+//! | 3   | in        | NewOrder   | order ID (u64), account (u64), symbol (16), side (u8, |
+//! |     |           |            | 0 buy), type (u8, 0 limit, 1 market), market (u8, 0   |
+//! |     |           |            | spot, 1 perp), price, quantity                        |
+//! | 4   | in        | Cancel     | order ID (u64), symbol (16), market (u8)              |
+//! | 5   | both      | Logout     | reason (64)                                           |
+//! | 101 | out       | LoginAck   | heartbeat ms (u32)                                    |
+//! | 103 | out       | OrderAck   | sequence acked (u32), order ID (u64), accepted (u8),  |
+//! |     |           |            | gRPC status code (u8), reason (64)                    |
+//!
+//! A session starts with a Login authenticated by a tenant API key. Each side
+//! numbers its messages from 1 and the other side checks there is no gap;
+//! heartbeats carry the last number sent without using up one. A message out of
+//! sequence or malformed ends the session with a Logout naming the problem. A
+//! side sends a heartbeat when it sent nothing for the agreed interval (100 ms
+//! to 60 s, 1 s if the login asks for 0) and the node ends sessions that sent
+//! nothing for three intervals.
+//!
+//! Orders and cancels take the same path as `PlaceOrder` and `CancelOrder` and
+//! are acknowledged once applied, or refused with the status the RPC would
+//! return. Messages of a session are proposed as they arrive without waiting
+//! for earlier ones, so acks may come out of order; a cancel should wait for the
+//! ack of its order. Request IDs are derived from the client ID and order ID, so
+//! a message resent after a reconnect is applied once.
+
+use crate::config;
+use crate::engine::entry::{Order, OrderSide, OrderType};
+use crate::engine::matchengine::{MarketType, MatchCmd, MatchCmdType};
+use crate::match_service::{check_degraded, propose, stamped_envelope};
+use crate::memory;
+use crate::metrics;
+use crate::slow_log::RequestTrace;
+use rust_decimal::Decimal;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+/// Size of the frame header
+const HEADER_LEN: usize = 8;
+
+/// Heartbeat interval used when the login asks for none
+const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// Shortest heartbeat interval a login may ask for
+const MIN_HEARTBEAT: Duration = Duration::from_millis(100);
+
+/// Longest heartbeat interval a login may ask for
+const MAX_HEARTBEAT: Duration = Duration::from_secs(60);
+
+/// Heartbeat intervals without a message after which a session is ended
+const IDLE_INTERVALS: u32 = 3;
+
+/// Size of text fields carrying a reason
+const REASON_LEN: usize = 64;
+
+/// Size of the symbol field
+const SYMBOL_LEN: usize = 16;
+
+/// Template IDs
+mod template {
+    // Inbound
+    pub const LOGIN: u16 = 1;
+    pub const HEARTBEAT: u16 = 2;
+    pub const NEW_ORDER: u16 = 3;
+    pub const CANCEL: u16 = 4;
+    pub const LOGOUT: u16 = 5;
+    // Outbound
+    pub const LOGIN_ACK: u16 = 101;
+    pub const ORDER_ACK: u16 = 103;
+}
+
+/// Message received from a client
+#[derive(Debug, Clone, PartialEq)]
+enum Inbound {
+    /// Opens the session
+    Login {
+        api_key: String,
+        client_id: String,
+        heartbeat_ms: u32,
+    },
+    /// Keeps the session alive
+    Heartbeat,
+    /// Places an order
+    NewOrder {
+        order_id: u64,
+        account_id: u64,
+        symbol: String,
+        side: OrderSide,
+        order_type: OrderType,
+        market: MarketType,
+        price: Decimal,
+        quantity: Decimal,
+    },
+    /// Cancels a resting order
+    Cancel {
+        order_id: u64,
+        symbol: String,
+        market: MarketType,
+    },
+    /// Ends the session
+    Logout,
+}
+
+/// Message sent to a client, numbered by the session writer
+#[derive(Debug, Clone, PartialEq)]
+enum Outbound {
+    /// Accepts the login
+    LoginAck { heartbeat_ms: u32 },
+    /// Keeps the session alive
+    Heartbeat,
+    /// Outcome of an order or cancel
+    OrderAck {
+        seq: u32,
+        order_id: u64,
+        status: tonic::Code,
+        reason: String,
+    },
+    /// Ends the session
+    Logout { reason: String },
+}
+
+/// Reads the fields of a message body in order
+struct Fields<'a> {
+    /// Remaining bytes of the body
+    body: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    /// Takes the next bytes of the body, the caller checked its length
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        let (field, rest) = self.body.split_at(len);
+        self.body = rest;
+        field
+    }
+
+    /// Reads a byte
+    fn u8(&mut self) -> u8 {
+        self.take(1)[0]
+    }
+
+    /// Reads a little-endian u32
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take(4).try_into().unwrap())
+    }
+
+    /// Reads a little-endian u64
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take(8).try_into().unwrap())
+    }
+
+    /// Reads a text field of a fixed size, dropping the zero padding
+    fn text(&mut self, len: usize) -> Result<String, String> {
+        let field = self.take(len);
+        let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+        String::from_utf8(field[..end].to_vec()).map_err(|_| "text is not UTF-8".to_string())
+    }
+
+    /// Reads a decimal as mantissa and scale
+    fn decimal(&mut self) -> Result<Decimal, String> {
+        let mantissa = self.u64() as i64;
+        let scale = self.u8() as u32;
+        Decimal::try_from_i128_with_scale(mantissa as i128, scale)
+            .map_err(|_| format!("invalid decimal scale {}", scale))
+    }
+
+    /// Reads a market, 0 spot and 1 perp
+    fn market(&mut self) -> Result<MarketType, String> {
+        match self.u8() {
+            0 => Ok(MarketType::Spot),
+            1 => Ok(MarketType::Perp),
+            market => Err(format!("invalid market {}", market)),
+        }
+    }
+}
+
+/// Returns the fixed body length of an inbound template
+fn body_len(template: u16) -> Option<usize> {
+    match template {
+        template::LOGIN => Some(64 + 32 + 4),
+        template::HEARTBEAT => Some(0),
+        template::NEW_ORDER => Some(8 + 8 + SYMBOL_LEN + 3 + 9 + 9),
+        template::CANCEL => Some(8 + SYMBOL_LEN + 1),
+        template::LOGOUT => Some(REASON_LEN),
+        _ => None,
+    }
+}
+
+/// Decodes the body of an inbound message
+///
+/// # Arguments
+///
+/// * `template` - Template ID from the header
+/// * `body` - Body, of the length the template has
+///
+/// # Returns
+///
+/// Returns the message, or why it is malformed
+fn decode(template: u16, body: &[u8]) -> Result<Inbound, String> {
+    match body_len(template) {
+        Some(len) if len == body.len() => {}
+        Some(len) => {
+            return Err(format!(
+                "template {} has {} bytes, not {}",
+                template,
+                len,
+                body.len()
+            ))
+        }
+        None => return Err(format!("unknown template {}", template)),
+    }
+    let mut fields = Fields { body };
+    let message = match template {
+        template::LOGIN => Inbound::Login {
+            api_key: fields.text(64)?,
+            client_id: fields.text(32)?,
+            heartbeat_ms: fields.u32(),
+        },
+        template::HEARTBEAT => Inbound::Heartbeat,
+        template::NEW_ORDER => Inbound::NewOrder {
+            order_id: fields.u64(),
+            account_id: fields.u64(),
+            symbol: fields.text(SYMBOL_LEN)?,
+            side: match fields.u8() {
+                0 => OrderSide::Buy,
+                1 => OrderSide::Sell,
+                side => return Err(format!("invalid side {}", side)),
+            },
+            order_type: match fields.u8() {
+                0 => OrderType::Limit,
+                1 => OrderType::Market,
+                order_type => return Err(format!("invalid order type {}", order_type)),
+            },
+            market: fields.market()?,
+            price: fields.decimal()?,
+            quantity: fields.decimal()?,
+        },
+        template::CANCEL => Inbound::Cancel {
+            order_id: fields.u64(),
+            symbol: fields.text(SYMBOL_LEN)?,
+            market: fields.market()?,
+        },
+        _ => Inbound::Logout,
+    };
+    Ok(message)
+}
+
+/// Appends a text field padded with zeros, cut to its size
+fn put_text(body: &mut Vec<u8>, text: &str, len: usize) {
+    let mut end = text.len().min(len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    body.extend_from_slice(&text.as_bytes()[..end]);
+    body.resize(body.len() + len - end, 0);
+}
+
+/// Encodes a frame
+///
+/// # Arguments
+///
+/// * `template` - Template ID
+/// * `seq` - Sequence number, the last one sent for heartbeats
+/// * `body` - Body of the message
+fn frame(template: u16, seq: u32, body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+    frame.extend_from_slice(&(body.len() as u16).to_le_bytes());
+    frame.extend_from_slice(&template.to_le_bytes());
+    frame.extend_from_slice(&seq.to_le_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// Encodes an outbound message
+///
+/// # Arguments
+///
+/// * `message` - Message to send
+/// * `last_seq` - Last sequence number sent, updated unless the message is a heartbeat
+fn encode(message: &Outbound, last_seq: &mut u32) -> Vec<u8> {
+    let mut body = Vec::new();
+    let template = match message {
+        Outbound::LoginAck { heartbeat_ms } => {
+            body.extend_from_slice(&heartbeat_ms.to_le_bytes());
+            template::LOGIN_ACK
+        }
+        Outbound::Heartbeat => return frame(template::HEARTBEAT, *last_seq, &body),
+        Outbound::OrderAck {
+            seq,
+            order_id,
+            status,
+            reason,
+        } => {
+            body.extend_from_slice(&seq.to_le_bytes());
+            body.extend_from_slice(&order_id.to_le_bytes());
+            body.push(u8::from(*status == tonic::Code::Ok));
+            body.push(*status as u8);
+            put_text(&mut body, reason, REASON_LEN);
+            template::ORDER_ACK
+        }
+        Outbound::Logout { reason } => {
+            put_text(&mut body, reason, REASON_LEN);
+            template::LOGOUT
+        }
+    };
+    *last_seq += 1;
+    frame(template, *last_seq, &body)
+}
+
+/// Starts accepting binary order entry sessions if configured
+///
+/// # Returns
+///
+/// Returns an error if the listener cannot be bound
+pub fn start() -> Result<(), String> {
+    let Some(addr) = config::instance()
+        .lock()
+        .unwrap()
+        .binary_gateway_addr
+        .clone()
+    else {
+        return Ok(());
+    };
+    let listener = std::net::TcpListener::bind(&addr)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)
+        })
+        .map_err(|e| format!("cannot bind binary gateway to {}: {}", addr, e))?;
+    log::info!("binary order entry listening on {}", addr);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = session(stream).await {
+                            log::info!("binary session from {} ended: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => log::warn!("cannot accept binary session: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reads the next frame
+///
+/// # Returns
+///
+/// Returns the template ID, sequence number and message, or why reading failed
+async fn read_frame(
+    reader: &mut BufReader<OwnedReadHalf>,
+) -> Result<(u16, u32, Result<Inbound, String>), String> {
+    let mut header = [0; HEADER_LEN];
+    reader
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| e.to_string())?;
+    let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+    let template = u16::from_le_bytes([header[2], header[3]]);
+    let seq = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let mut body = vec![0; len];
+    reader
+        .read_exact(&mut body)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok((template, seq, decode(template, &body)))
+}
+
+/// Runs a session until either side ends it
+async fn session(stream: TcpStream) -> Result<(), String> {
+    let _ = stream.set_nodelay(true);
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    let (tenant, client_id, heartbeat) = match read_frame(&mut reader).await? {
+        (
+            _,
+            1,
+            Ok(Inbound::Login {
+                api_key,
+                client_id,
+                heartbeat_ms,
+            }),
+        ) => {
+            let tenant = config::instance()
+                .lock()
+                .unwrap()
+                .tenant_for_api_key(Some(&api_key));
+            let heartbeat = match heartbeat_ms {
+                0 => DEFAULT_HEARTBEAT,
+                ms => Duration::from_millis(ms as u64).clamp(MIN_HEARTBEAT, MAX_HEARTBEAT),
+            };
+            (tenant, client_id, heartbeat)
+        }
+        _ => (None, String::new(), DEFAULT_HEARTBEAT),
+    };
+    let writer = tokio::spawn(write_frames(writer, receiver, heartbeat));
+    let Some(tenant) = tenant else {
+        let reason = "expected a login with a valid api key as message 1".to_string();
+        let _ = sender.send(Outbound::Logout {
+            reason: reason.clone(),
+        });
+        drop(sender);
+        let _ = writer.await;
+        return Err(reason);
+    };
+    let _ = sender.send(Outbound::LoginAck {
+        heartbeat_ms: heartbeat.as_millis() as u32,
+    });
+    log::info!(
+        "binary session of client {:?} of tenant {}",
+        client_id,
+        tenant
+    );
+
+    let mut expected = 2;
+    let reason = loop {
+        let read = tokio::time::timeout(heartbeat * IDLE_INTERVALS, read_frame(&mut reader));
+        let (template, seq, message) = match read.await {
+            Ok(Ok(frame)) => frame,
+            Ok(Err(e)) => break e,
+            Err(_) => break "no message within three heartbeat intervals".to_string(),
+        };
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => break e,
+        };
+        if template == template::HEARTBEAT {
+            if seq + 1 != expected {
+                break format!("heartbeat after {}, expected {}", seq, expected - 1);
+            }
+            continue;
+        }
+        if seq != expected {
+            break format!("got message {}, expected {}", seq, expected);
+        }
+        expected += 1;
+        match message {
+            Inbound::Logout => break "logout".to_string(),
+            Inbound::Login { .. } => break "already logged in".to_string(),
+            Inbound::Heartbeat => {}
+            Inbound::NewOrder { .. } | Inbound::Cancel { .. } => {
+                let (tenant, client_id, sender) =
+                    (tenant.clone(), client_id.clone(), sender.clone());
+                tokio::spawn(async move {
+                    let ack = submit(&tenant, &client_id, seq, message).await;
+                    let _ = sender.send(ack);
+                });
+            }
+        }
+    };
+    let _ = sender.send(Outbound::Logout {
+        reason: reason.clone(),
+    });
+    drop(sender);
+    let _ = writer.await;
+    Err(reason)
+}
+
+/// Writes the messages of a session, sending heartbeats while it is idle
+///
+/// Ends after a Logout, or once every sender is dropped.
+async fn write_frames(
+    mut writer: OwnedWriteHalf,
+    mut receiver: UnboundedReceiver<Outbound>,
+    heartbeat: Duration,
+) {
+    let mut last_seq = 0;
+    loop {
+        let message = match tokio::time::timeout(heartbeat, receiver.recv()).await {
+            Ok(Some(message)) => message,
+            Ok(None) => return,
+            Err(_) => Outbound::Heartbeat,
+        };
+        let logout = matches!(message, Outbound::Logout { .. });
+        if writer
+            .write_all(&encode(&message, &mut last_seq))
+            .await
+            .is_err()
+            || logout
+        {
+            let _ = writer.shutdown().await;
+            return;
+        }
+    }
+}
+
+/// Proposes an order or cancel and describes the outcome
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant of the session
+/// * `client_id` - Client ID of the session
+/// * `seq` - Sequence number of the message
+/// * `message` - NewOrder or Cancel
+async fn submit(tenant: &str, client_id: &str, seq: u32, message: Inbound) -> Outbound {
+    let (order_id, result) = match message {
+        Inbound::NewOrder {
+            order_id,
+            account_id,
+            symbol,
+            side,
+            order_type,
+            market,
+            price,
+            quantity,
+        } => {
+            let mut trace = RequestTrace::new("binary_new_order");
+            metrics::TENANT_REQ_COUNTER_VEC
+                .with_label_values(&[tenant, "binary_new_order"])
+                .inc();
+            let order = Order::new(
+                order_id.to_string(),
+                symbol,
+                order_type,
+                side,
+                price.to_string(),
+                quantity.to_string(),
+            );
+            let result = async {
+                memory::check_order(tenant, &order.symbol)
+                    .map_err(tonic::Status::resource_exhausted)?;
+                check_degraded().await?;
+                let cmd = MatchCmd {
+                    cmd: MatchCmdType::PlaceOrder,
+                    tenant: tenant.to_string(),
+                    order: Some(order),
+                    symbol: None,
+                    transfer: None,
+                    risk: None,
+                    market,
+                    mark_price: None,
+                    orders: Vec::new(),
+                };
+                let request_id = format!("{}-{}", client_id, order_id);
+                let envelope = stamped_envelope(request_id, client_id.to_string(), account_id, cmd);
+                propose(envelope, None, false, &mut trace).await
+            };
+            (order_id, result.await)
+        }
+        Inbound::Cancel {
+            order_id,
+            symbol,
+            market,
+        } => {
+            let mut trace = RequestTrace::new("binary_cancel");
+            metrics::TENANT_REQ_COUNTER_VEC
+                .with_label_values(&[tenant, "binary_cancel"])
+                .inc();
+            let cmd = MatchCmd {
+                cmd: MatchCmdType::CancelOrder,
+                tenant: tenant.to_string(),
+                order: Some(Order {
+                    id: order_id.to_string(),
+                    symbol,
+                    ..Default::default()
+                }),
+                symbol: None,
+                transfer: None,
+                risk: None,
+                market,
+                mark_price: None,
+                orders: Vec::new(),
+            };
+            let request_id = format!("{}-cancel-{}", client_id, order_id);
+            let envelope = stamped_envelope(request_id, client_id.to_string(), 0, cmd);
+            (order_id, propose(envelope, None, true, &mut trace).await)
+        }
+        _ => unreachable!("only orders and cancels are submitted"),
+    };
+    let (status, reason) = match result {
+        Ok(()) => (tonic::Code::Ok, String::new()),
+        Err(status) => (status.code(), status.message().to_string()),
+    };
+    Outbound::OrderAck {
+        seq,
+        order_id,
+        status,
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn new_order_body() -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&42u64.to_le_bytes());
+        body.extend_from_slice(&7u64.to_le_bytes());
+        put_text(&mut body, "BTCUSDT", SYMBOL_LEN);
+        body.extend_from_slice(&[1, 0, 0]);
+        body.extend_from_slice(&10050i64.to_le_bytes());
+        body.push(2);
+        body.extend_from_slice(&5i64.to_le_bytes());
+        body.push(1);
+        body
+    }
+
+    #[test]
+    fn orders_are_decoded_from_their_fixed_layout() {
+        assert_eq!(
+            decode(template::NEW_ORDER, &new_order_body()),
+            Ok(Inbound::NewOrder {
+                order_id: 42,
+                account_id: 7,
+                symbol: "BTCUSDT".to_string(),
+                side: OrderSide::Sell,
+                order_type: OrderType::Limit,
+                market: MarketType::Spot,
+                price: dec!(100.50),
+                quantity: dec!(0.5),
+            })
+        );
+        let mut short = new_order_body();
+        short.pop();
+        assert!(decode(template::NEW_ORDER, &short).is_err());
+        let mut bad_side = new_order_body();
+        bad_side[32] = 9;
+        assert!(decode(template::NEW_ORDER, &bad_side).is_err());
+        assert!(decode(99, &[]).is_err());
+    }
+
+    #[test]
+    fn heartbeats_repeat_the_last_sequence_number() {
+        let mut last_seq = 0;
+        let ack = encode(&Outbound::LoginAck { heartbeat_ms: 500 }, &mut last_seq);
+        assert_eq!(u16::from_le_bytes([ack[0], ack[1]]), 4);
+        assert_eq!(u16::from_le_bytes([ack[2], ack[3]]), template::LOGIN_ACK);
+        assert_eq!(u32::from_le_bytes(ack[4..8].try_into().unwrap()), 1);
+        let heartbeat = encode(&Outbound::Heartbeat, &mut last_seq);
+        assert_eq!(heartbeat, frame(template::HEARTBEAT, 1, &[]));
+        let rejected = Outbound::OrderAck {
+            seq: 3,
+            order_id: 42,
+            status: tonic::Code::Unavailable,
+            reason: "no leader".to_string(),
+        };
+        let rejected = encode(&rejected, &mut last_seq);
+        assert_eq!(u32::from_le_bytes(rejected[4..8].try_into().unwrap()), 2);
+        assert_eq!(rejected.len(), HEADER_LEN + 4 + 8 + 2 + REASON_LEN);
+        assert_eq!(rejected[HEADER_LEN + 12..HEADER_LEN + 14], [0, 14]);
+    }
+}
@@ -0,0 +1,324 @@
+//! Change data capture
+//!
+//! With `change_feed_buffer` set, `SubscribeChanges` lets external systems tail
+//! the commands this node applies, of every tenant and in log order, to build
+//! their own replica of the engine. Each command is sent as an `AuditRecord`: the
+//! command as replicated, which applies with `MatchEngine::on_message`, and what it
+//! did, see `audit_trail`.
+//!
+//! The node keeps the records of the last `change_feed_buffer` commands in memory.
+//! A subscription from an index still kept replays the records from there; one
+//! from index 0 or from an index no longer kept starts with a snapshot of the
+//! engine, taken at the end of the next apply batch and sent in chunks, which
+//! restores with `MatchEngine::on_snapshot`, followed by the commands after it.
+//! Subscribers are fed at their own pace: one that falls behind the records kept
+//! is disconnected and resubscribes from the index after its last record, getting
+//! a new snapshot if that is gone too.
+//!
+//! The records start over when the node restores a snapshot, on start or from the
+//! leader, so after a restart the node keeps the commands replayed after its
+//! snapshot.
+
+use crate::audit_trail;
+use crate::engine::history::{CommandRecord, OrderEvent};
+use crate::engine::matchengine::{FundingRecord, Settlement};
+use crate::match_service::pb::{change_event, AuditRecord, ChangeEvent, SnapshotChunk};
+use crate::raft::FrozenSnapshot;
+use crate::{config, metrics};
+use once_cell::sync::OnceCell;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::{oneshot, watch};
+
+/// Events buffered per subscriber stream
+const STREAM_BUFFER: usize = 1024;
+
+/// Records copied out of the feed at a time
+const READ_BATCH: usize = 256;
+
+/// Size of the snapshot chunks in bytes
+const SNAPSHOT_CHUNK_BYTES: usize = 1 << 20;
+
+/// Feed of this node, None if the feed is disabled
+static FEED: OnceCell<Option<Mutex<Feed>>> = OnceCell::new();
+
+/// Stream of changes sent to a subscriber
+pub type ChangeStream = Receiver<Result<ChangeEvent, tonic::Status>>;
+
+/// Serialized snapshot of the engine with the raft index it is as of
+type Snapshot = Result<(u64, Arc<Vec<u8>>), String>;
+
+/// Records of the commands applied lately
+struct Feed {
+    /// Number of records kept
+    capacity: usize,
+    /// Records in log order
+    records: VecDeque<AuditRecord>,
+    /// Index after which every applied command is kept
+    kept_after: u64,
+    /// Index of the last command applied, watched by the subscribers
+    applied: watch::Sender<u64>,
+    /// Subscribers waiting for a snapshot
+    waiting: Vec<oneshot::Sender<Snapshot>>,
+}
+
+impl Feed {
+    /// Creates a feed without records
+    fn new(capacity: usize) -> Self {
+        Feed {
+            capacity,
+            records: VecDeque::new(),
+            kept_after: 0,
+            applied: watch::channel(0).0,
+            waiting: Vec::new(),
+        }
+    }
+
+    /// Appends the records of an apply batch, dropping the oldest beyond capacity
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of the last command of the batch
+    /// * `records` - Records of the batch, in log order
+    fn append(&mut self, index: u64, records: Vec<AuditRecord>) {
+        self.records.extend(records);
+        while self.records.len() > self.capacity {
+            if let Some(record) = self.records.pop_front() {
+                self.kept_after = record.index;
+            }
+        }
+        self.applied.send_replace(index);
+    }
+
+    /// Drops every record, the engine was restored from a snapshot
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index the snapshot is as of
+    fn restart(&mut self, index: u64) {
+        self.records.clear();
+        self.kept_after = index;
+        self.applied.send_replace(index);
+    }
+
+    /// Returns the records from an index on
+    ///
+    /// # Arguments
+    ///
+    /// * `from_index` - First index wanted
+    /// * `limit` - Most records returned
+    ///
+    /// # Returns
+    ///
+    /// The records in log order, None if the feed no longer keeps the index
+    fn read(&self, from_index: u64, limit: usize) -> Option<Vec<AuditRecord>> {
+        if from_index <= self.kept_after {
+            return None;
+        }
+        let start = self
+            .records
+            .partition_point(|record| record.index < from_index);
+        Some(self.records.range(start..).take(limit).cloned().collect())
+    }
+}
+
+/// Enables the feed if `change_feed_buffer` is set
+///
+/// Must be called before the state machine is created, which records the
+/// applied commands only if the audit trail or the feed need them.
+pub fn start() {
+    let capacity = config::instance().lock().unwrap().change_feed_buffer;
+    let feed = (capacity > 0).then(|| Mutex::new(Feed::new(capacity)));
+    let _ = FEED.set(feed);
+}
+
+/// Returns whether the feed is enabled
+pub fn is_enabled() -> bool {
+    matches!(FEED.get(), Some(Some(_)))
+}
+
+/// Appends the commands of an apply batch with what they did to the feed
+///
+/// # Arguments
+///
+/// * `index` - Index of the last command applied
+/// * `commands` - Commands of the batch, in log order
+/// * `order_events` - Order events of the batch
+/// * `settlements` - Settlements of the batch
+/// * `funding` - Funding records of the batch
+/// * `freeze` - Freezes the engine as of `index`, called if a subscriber waits
+///   for a snapshot
+pub fn write(
+    index: u64,
+    commands: &[CommandRecord],
+    order_events: &[OrderEvent],
+    settlements: &[Settlement],
+    funding: &[FundingRecord],
+    freeze: impl FnOnce() -> FrozenSnapshot,
+) {
+    let Some(Some(feed)) = FEED.get() else {
+        return;
+    };
+    let records = audit_trail::records(commands, order_events, settlements, funding);
+    let mut feed = feed.lock().unwrap();
+    feed.append(index, records);
+    if feed.waiting.is_empty() {
+        return;
+    }
+    let waiting = std::mem::take(&mut feed.waiting);
+    let frozen = freeze();
+    tokio::task::spawn_blocking(move || {
+        let snapshot = frozen().map(|data| (index, Arc::new(data)));
+        for subscriber in waiting {
+            let _ = subscriber.send(snapshot.clone());
+        }
+    });
+}
+
+/// Drops the records kept, the engine was restored from a snapshot
+///
+/// # Arguments
+///
+/// * `index` - Index the snapshot is as of
+pub fn restored(index: u64) {
+    if let Some(Some(feed)) = FEED.get() {
+        feed.lock().unwrap().restart(index);
+    }
+}
+
+/// Subscribes to the commands applied from an index on
+///
+/// # Arguments
+///
+/// * `from_index` - First index wanted, 0 for a snapshot of the engine first
+///
+/// # Returns
+///
+/// Returns the changes in log order, or an error if the feed is disabled
+pub fn subscribe(from_index: u64) -> Result<ChangeStream, String> {
+    if !is_enabled() {
+        return Err("the change feed needs change_feed_buffer to be configured".to_string());
+    }
+    let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+    tokio::spawn(async move {
+        metrics::CHANGE_FEED_SUBSCRIBERS_GAUGE.inc();
+        feed_subscriber(from_index, sender).await;
+        metrics::CHANGE_FEED_SUBSCRIBERS_GAUGE.dec();
+    });
+    Ok(receiver)
+}
+
+/// Sends a subscriber a snapshot if it needs one, then the records as they are applied
+///
+/// Returns when the subscriber is gone or fell behind the records kept.
+///
+/// # Arguments
+///
+/// * `from_index` - First index the subscriber wants
+/// * `sender` - Queue of the subscriber's stream
+async fn feed_subscriber(from_index: u64, sender: Sender<Result<ChangeEvent, tonic::Status>>) {
+    let Some(Some(feed)) = FEED.get() else {
+        return;
+    };
+    let (mut applied, snapshot) = {
+        let mut feed = feed.lock().unwrap();
+        let snapshot = (from_index <= feed.kept_after).then(|| {
+            let (waiter, snapshot) = oneshot::channel();
+            feed.waiting.push(waiter);
+            snapshot
+        });
+        (feed.applied.subscribe(), snapshot)
+    };
+    let mut next = from_index;
+    if let Some(snapshot) = snapshot {
+        let (index, data) = match snapshot.await {
+            Ok(Ok(snapshot)) => snapshot,
+            Ok(Err(e)) => {
+                log::error!("change feed snapshot failed: {}", e);
+                let _ = sender.send(Err(tonic::Status::internal(e))).await;
+                return;
+            }
+            Err(_) => return,
+        };
+        for (i, chunk) in data.chunks(SNAPSHOT_CHUNK_BYTES).enumerate() {
+            let chunk = SnapshotChunk {
+                index,
+                offset: (i * SNAPSHOT_CHUNK_BYTES) as u64,
+                size: data.len() as u64,
+                data: chunk.to_vec(),
+            };
+            let event = ChangeEvent {
+                change: Some(change_event::Change::Snapshot(chunk)),
+            };
+            if sender.send(Ok(event)).await.is_err() {
+                return;
+            }
+        }
+        next = index + 1;
+    }
+    loop {
+        applied.borrow_and_update();
+        let records = feed.lock().unwrap().read(next, READ_BATCH);
+        let Some(records) = records else {
+            log::warn!("change feed subscriber fell behind at index {}", next);
+            let status = tonic::Status::out_of_range(format!(
+                "index {} is no longer kept, subscribe again from it",
+                next
+            ));
+            let _ = sender.send(Err(status)).await;
+            return;
+        };
+        if records.is_empty() {
+            if applied.changed().await.is_err() {
+                return;
+            }
+            continue;
+        }
+        for record in records {
+            next = record.index + 1;
+            let event = ChangeEvent {
+                change: Some(change_event::Change::Command(record)),
+            };
+            if sender.send(Ok(event)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(index: u64) -> AuditRecord {
+        AuditRecord {
+            index,
+            command: "PlaceOrder".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn indexes(records: Option<Vec<AuditRecord>>) -> Option<Vec<u64>> {
+        records.map(|records| records.iter().map(|record| record.index).collect())
+    }
+
+    #[test]
+    fn records_are_read_from_an_index_while_kept() {
+        let mut feed = Feed::new(3);
+        feed.append(2, vec![record(1), record(2)]);
+        // Entries that are no commands leave gaps in the indexes
+        feed.append(5, vec![record(3), record(5)]);
+        assert_eq!(*feed.applied.borrow(), 5);
+        assert_eq!(indexes(feed.read(1, 10)), None);
+        assert_eq!(indexes(feed.read(2, 10)), Some(vec![2, 3, 5]));
+        assert_eq!(indexes(feed.read(4, 10)), Some(vec![5]));
+        assert_eq!(indexes(feed.read(3, 1)), Some(vec![3]));
+        assert_eq!(indexes(feed.read(6, 10)), Some(vec![]));
+
+        feed.restart(9);
+        assert_eq!(indexes(feed.read(9, 10)), None);
+        feed.append(10, vec![record(10)]);
+        assert_eq!(indexes(feed.read(10, 10)), Some(vec![10]));
+    }
+}
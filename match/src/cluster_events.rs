@@ -0,0 +1,296 @@
+//! Cluster event notifications
+//!
+//! Clients subscribe with `SubscribeClusterEvents` to learn of leadership
+//! changes, of the node fencing its reads and of symbols halting and resuming
+//! as soon as the node they are connected to does, instead of by their calls
+//! timing out. A subscription starts with the leader currently known, and with
+//! a fence or failure event if reads are fenced or the state machine failed, so
+//! clients need no separate query for the state.
+//!
+//! Leadership and fencing are seen by every node on its own; halts and resumes
+//! are sent once the command causing them is applied, on every node alike.
+//! Subscribers that fall `channels.cluster_events` events behind are
+//! disconnected rather than missing events, and resubscribe to get the current
+//! state again.
+
+use crate::engine::history::{OrderChange, OrderEvent};
+use crate::match_service::pb::{ClusterEvent, ClusterEventKind};
+use crate::{config, metrics};
+use std::sync::Mutex;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+/// Cluster state last seen and the subscribers to its changes
+static STATE: Mutex<State> = Mutex::new(State::new());
+
+/// Stream of events sent to one subscriber
+pub type EventStream = Receiver<Result<ClusterEvent, tonic::Status>>;
+
+/// Client subscribed to cluster events
+#[derive(Debug)]
+struct Subscriber {
+    /// Tenant the client is scoped to, only its symbols are reported
+    tenant: String,
+    /// Queue of the events not yet streamed to the client
+    sender: Sender<Result<ClusterEvent, tonic::Status>>,
+}
+
+/// Cluster state as seen by this node
+#[derive(Debug)]
+struct State {
+    /// Leader known to the node, 0 if there is none
+    leader_id: u64,
+    /// Term of the known leader
+    term: u64,
+    /// Whether the node refuses reads after a divergence
+    fenced: bool,
+    /// Index of the entry the state machine failed at, 0 if it has not failed
+    failed_at: u64,
+    /// Connected clients
+    subscribers: Vec<Subscriber>,
+}
+
+impl State {
+    /// Creates the state of a node that knows of no leader
+    const fn new() -> Self {
+        State {
+            leader_id: 0,
+            term: 0,
+            fenced: false,
+            failed_at: 0,
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Describes the known leader
+    fn leader_event(&self) -> ClusterEvent {
+        let config = config::instance().lock().unwrap();
+        let leader_addr = config
+            .node_list
+            .iter()
+            .find(|node| node.id == self.leader_id)
+            .map(|node| node.addr.clone())
+            .unwrap_or_default();
+        ClusterEvent {
+            kind: ClusterEventKind::LeaderChanged as i32,
+            node_id: config.id,
+            leader_id: self.leader_id,
+            leader_addr,
+            term: self.term,
+            ..Default::default()
+        }
+    }
+
+    /// Describes whether reads are fenced
+    fn fence_event(&self) -> ClusterEvent {
+        let kind = if self.fenced {
+            ClusterEventKind::ReadsFenced
+        } else {
+            ClusterEventKind::ReadsRestored
+        };
+        ClusterEvent {
+            kind: kind as i32,
+            node_id: config::instance().lock().unwrap().id,
+            leader_id: self.leader_id,
+            term: self.term,
+            ..Default::default()
+        }
+    }
+
+    /// Describes the failure of the state machine
+    fn failed_event(&self) -> ClusterEvent {
+        ClusterEvent {
+            kind: ClusterEventKind::StateMachineFailed as i32,
+            node_id: config::instance().lock().unwrap().id,
+            leader_id: self.leader_id,
+            term: self.term,
+            index: self.failed_at,
+            ..Default::default()
+        }
+    }
+
+    /// Queues an event for the subscribers, disconnecting those that are gone
+    /// or too far behind
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - Event to send
+    /// * `tenant` - Tenant the event concerns, None if it concerns every client
+    fn publish(&mut self, event: &ClusterEvent, tenant: Option<&str>) {
+        self.subscribers.retain(|subscriber| {
+            if tenant.is_some_and(|tenant| tenant != subscriber.tenant) {
+                return !subscriber.sender.is_closed();
+            }
+            match subscriber.sender.try_send(Ok(event.clone())) {
+                Ok(()) => true,
+                Err(TrySendError::Closed(_)) => false,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!(
+                        "disconnect cluster event subscriber of tenant {} falling behind",
+                        subscriber.tenant
+                    );
+                    metrics::CHANNEL_DROPPED_COUNTER_VEC
+                        .with_label_values(&["cluster_events"])
+                        .inc();
+                    false
+                }
+            }
+        });
+        metrics::CLUSTER_EVENT_SUBSCRIBERS_GAUGE.set(self.subscribers.len() as i64);
+    }
+}
+
+/// Subscribes a client to cluster events
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the client is scoped to
+///
+/// # Returns
+///
+/// Returns the events, starting with the current state
+pub fn subscribe(tenant: &str) -> EventStream {
+    let capacity = config::instance()
+        .lock()
+        .unwrap()
+        .channels
+        .cluster_events
+        .max(3);
+    let (sender, receiver) = mpsc::channel(capacity);
+    let mut state = STATE.lock().unwrap();
+    let _ = sender.try_send(Ok(state.leader_event()));
+    if state.fenced {
+        let _ = sender.try_send(Ok(state.fence_event()));
+    }
+    if state.failed_at != 0 {
+        let _ = sender.try_send(Ok(state.failed_event()));
+    }
+    state.subscribers.push(Subscriber {
+        tenant: tenant.to_string(),
+        sender,
+    });
+    metrics::CLUSTER_EVENT_SUBSCRIBERS_GAUGE.set(state.subscribers.len() as i64);
+    receiver
+}
+
+/// Records the leader known to the node, notifying subscribers if it changed
+///
+/// # Arguments
+///
+/// * `leader_id` - Leader known to the node, 0 if there is none
+/// * `term` - Current raft term
+pub fn observe_leader(leader_id: u64, term: u64) {
+    let mut state = STATE.lock().unwrap();
+    if state.leader_id == leader_id && (leader_id == 0 || state.term == term) {
+        return;
+    }
+    state.leader_id = leader_id;
+    state.term = term;
+    let event = state.leader_event();
+    log::info!("leader changed to {} in term {}", leader_id, term);
+    state.publish(&event, None);
+}
+
+/// Records whether the node refuses reads, notifying subscribers if it changed
+///
+/// # Arguments
+///
+/// * `fenced` - Whether reads are fenced, see `divergence::is_fenced`
+pub fn observe_fenced(fenced: bool) {
+    let mut state = STATE.lock().unwrap();
+    if state.fenced == fenced {
+        return;
+    }
+    state.fenced = fenced;
+    let event = state.fence_event();
+    state.publish(&event, None);
+}
+
+/// Notifies subscribers that the state machine failed, see `state_failure`
+///
+/// # Arguments
+///
+/// * `index` - Index of the entry applying panicked at
+pub fn observe_failed(index: u64) {
+    let mut state = STATE.lock().unwrap();
+    state.failed_at = index;
+    let event = state.failed_event();
+    state.publish(&event, None);
+}
+
+/// Notifies subscribers of the symbols halted, by their removal or circuit
+/// breaker, and resumed by a batch of applied commands
+///
+/// # Arguments
+///
+/// * `events` - Order events of the batch, in log order
+pub fn write(events: &[OrderEvent]) {
+    let changes: Vec<_> = events
+        .iter()
+        .filter_map(|event| match &event.change {
+            OrderChange::SymbolRemoved { symbol } => {
+                Some((event, ClusterEventKind::SymbolHalted, symbol, "removed"))
+            }
+            OrderChange::SymbolHalted { symbol, reason } => Some((
+                event,
+                ClusterEventKind::SymbolHalted,
+                symbol,
+                reason.as_str(),
+            )),
+            OrderChange::SymbolResumed { symbol } => {
+                Some((event, ClusterEventKind::SymbolResumed, symbol, ""))
+            }
+            _ => None,
+        })
+        .collect();
+    if changes.is_empty() {
+        return;
+    }
+    let mut state = STATE.lock().unwrap();
+    for (event, kind, symbol, reason) in changes {
+        let changed = ClusterEvent {
+            kind: kind as i32,
+            node_id: config::instance().lock().unwrap().id,
+            leader_id: state.leader_id,
+            term: state.term,
+            symbol: symbol.clone(),
+            reason: reason.to_string(),
+            index: event.index,
+            ..Default::default()
+        };
+        state.publish(&changed, Some(&event.tenant));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_get_their_tenants_events_until_they_fall_behind() {
+        let mut state = State::new();
+        let (sender, mut receiver) = mpsc::channel(2);
+        state.subscribers.push(Subscriber {
+            tenant: "a".to_string(),
+            sender,
+        });
+        let event = |symbol: &str| ClusterEvent {
+            symbol: symbol.to_string(),
+            ..Default::default()
+        };
+
+        state.publish(&event("other"), Some("b"));
+        state.publish(&event("mine"), Some("a"));
+        state.publish(&event("all"), None);
+        assert_eq!(receiver.try_recv().unwrap().unwrap().symbol, "mine");
+        assert_eq!(receiver.try_recv().unwrap().unwrap().symbol, "all");
+
+        // A full queue disconnects the subscriber instead of skipping an event
+        state.publish(&event("1"), None);
+        state.publish(&event("2"), None);
+        state.publish(&event("3"), None);
+        assert!(state.subscribers.is_empty());
+        assert_eq!(receiver.try_recv().unwrap().unwrap().symbol, "1");
+        assert_eq!(receiver.try_recv().unwrap().unwrap().symbol, "2");
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,66 @@
+//! Cluster status
+//!
+//! Keeps the raft state of this node as of its last tick for the admin service,
+//! see `admin_service`: its role, term and log indexes, the members of the
+//! configuration it knows and, while it leads, how far each of them replicated.
+//! Followers do not track replication, their peers report no progress.
+
+use std::sync::RwLock;
+
+/// Raft state of this node, None before its first tick
+static STATUS: RwLock<Option<RaftStatus>> = RwLock::new(None);
+
+/// Member of the configuration known to this node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerStatus {
+    /// ID of the member
+    pub id: u64,
+    /// Whether the member votes, learners only replicate
+    pub voter: bool,
+    /// Highest index known to be replicated to the member, 0 unless leader
+    pub matched: u64,
+    /// Replication state of the member, `probe`, `replicate` or `snapshot`,
+    /// empty unless leader
+    pub state: &'static str,
+    /// Whether the member was heard from within the last election timeout,
+    /// false unless leader
+    pub recent_active: bool,
+}
+
+/// Raft state of a node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaftStatus {
+    /// ID of the node
+    pub id: u64,
+    /// Current term
+    pub term: u64,
+    /// Leader of the current term, 0 if unknown
+    pub leader_id: u64,
+    /// Role of the node, `leader`, `follower`, `candidate` or `precandidate`
+    pub role: &'static str,
+    /// Highest index known to be committed
+    pub commit_index: u64,
+    /// Highest index applied to the state machine
+    pub applied_index: u64,
+    /// First index still in the log, entries before it were compacted
+    pub first_index: u64,
+    /// Last index in the log
+    pub last_index: u64,
+    /// Node leadership is being transferred to, 0 if none
+    pub lead_transferee: u64,
+    /// Members of the configuration by ID
+    pub peers: Vec<PeerStatus>,
+}
+
+/// Records the raft state of this node
+///
+/// # Arguments
+/// * `status` - State as of the last tick
+pub fn publish(status: RaftStatus) {
+    *STATUS.write().unwrap() = Some(status);
+}
+
+/// Returns the raft state of this node, None before the raft loop ticked
+pub fn current() -> Option<RaftStatus> {
+    STATUS.read().unwrap().clone()
+}
@@ -0,0 +1,380 @@
+//! Dead-letter journal
+//!
+//! Publishers that push events to outside systems, currently the webhooks,
+//! give an event up when its endpoint keeps failing past the retries or when
+//! the endpoint is so far behind that its queue is full. With `dead_letter_path`
+//! set those events are appended to that file as length-delimited `DeadLetter`
+//! records instead of being dropped with a log line, so an outage of a
+//! downstream system loses nothing and never holds up the raft loop.
+//!
+//! `ListDeadLetters` shows the letters of a tenant and `ReplayDeadLetters` hands
+//! them back to their publisher, which delivers them again like new events. A
+//! replayed letter is marked by appending a record naming its `seq`, so the
+//! journal is never rewritten; a letter that fails again is spooled again under
+//! a new `seq`. The journal is local to the node whose publisher gave the events
+//! up, so it has to be listed and replayed on that node. A record cut short by a
+//! crash is truncated when the journal is opened again.
+
+use crate::config;
+use crate::match_service::pb::DeadLetter;
+use crate::metrics;
+use crate::webhook;
+use once_cell::sync::OnceCell;
+use prost::Message;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Letters returned by a listing that does not ask for a number
+const DEFAULT_LIST_LIMIT: usize = 100;
+
+/// Journal, None if dead letters are not kept
+static JOURNAL: OnceCell<Option<Mutex<Journal>>> = OnceCell::new();
+
+/// Open dead-letter journal
+struct Journal {
+    /// File records are appended to
+    file: BufWriter<File>,
+    /// Seq of the last letter spooled
+    last_seq: u64,
+    /// Letters not replayed yet, by seq
+    pending: BTreeMap<u64, DeadLetter>,
+}
+
+impl Journal {
+    /// Opens the journal, creating the file if needed
+    ///
+    /// A record cut short at the end of the file is truncated.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Journal file
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the file cannot be read or opened
+    fn open(path: &str) -> Result<Journal, String> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("cannot read dead-letter journal {}: {}", path, e)),
+        };
+        let mut last_seq = 0;
+        let mut pending = BTreeMap::new();
+        let mut buf = data.as_slice();
+        while !buf.is_empty() {
+            match DeadLetter::decode_length_delimited(&mut buf) {
+                Ok(letter) if letter.replayed > 0 => {
+                    pending.remove(&letter.replayed);
+                }
+                Ok(letter) => {
+                    last_seq = letter.seq;
+                    pending.insert(letter.seq, letter);
+                }
+                Err(_) => break,
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("cannot open dead-letter journal {}: {}", path, e))?;
+        if !buf.is_empty() {
+            log::warn!(
+                "truncating {} bytes of a dead letter cut short in {}",
+                buf.len(),
+                path
+            );
+            file.set_len((data.len() - buf.len()) as u64)
+                .map_err(|e| format!("cannot truncate {}: {}", path, e))?;
+        }
+        Ok(Journal {
+            file: BufWriter::new(file),
+            last_seq,
+            pending,
+        })
+    }
+
+    /// Appends records and flushes the file
+    fn append(&mut self, records: &[DeadLetter]) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        for record in records {
+            buf.clear();
+            record
+                .encode_length_delimited(&mut buf)
+                .expect("encode dead letter");
+            self.file.write_all(&buf)?;
+        }
+        self.file.flush()
+    }
+
+    /// Appends given up events as new letters
+    ///
+    /// # Arguments
+    ///
+    /// * `letters` - Events given up, their seq and time are assigned here
+    fn spool(&mut self, letters: Vec<DeadLetter>) -> std::io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let mut spooled = Vec::with_capacity(letters.len());
+        for mut letter in letters {
+            self.last_seq += 1;
+            letter.seq = self.last_seq;
+            letter.time = time;
+            letter.replayed = 0;
+            spooled.push(letter);
+        }
+        self.append(&spooled)?;
+        for letter in spooled {
+            self.pending.insert(letter.seq, letter);
+        }
+        Ok(())
+    }
+
+    /// Returns the pending letters of a tenant from a seq on
+    fn list(&self, tenant: &str, sink: Option<&str>, from_seq: u64) -> Vec<&DeadLetter> {
+        self.pending
+            .range(from_seq..)
+            .map(|(_, letter)| letter)
+            .filter(|letter| letter.tenant == tenant)
+            .filter(|letter| sink.is_none_or(|sink| letter.sink == sink))
+            .collect()
+    }
+
+    /// Marks a letter as replayed
+    fn replayed(&mut self, seq: u64) -> std::io::Result<()> {
+        self.append(&[DeadLetter {
+            replayed: seq,
+            ..Default::default()
+        }])?;
+        self.pending.remove(&seq);
+        Ok(())
+    }
+}
+
+/// Opens the dead-letter journal if `dead_letter_path` is configured
+///
+/// Must be called before the publishers start.
+///
+/// # Returns
+///
+/// Returns an error if the journal cannot be opened
+pub fn open() -> Result<(), String> {
+    let path = config::instance().lock().unwrap().dead_letter_path.clone();
+    let journal = match path {
+        Some(path) => {
+            let journal = Journal::open(&path)?;
+            log::info!(
+                "keeping dead letters in {}, {} pending",
+                path,
+                journal.pending.len()
+            );
+            metrics::DEAD_LETTERS_GAUGE.set(journal.pending.len() as i64);
+            Some(Mutex::new(journal))
+        }
+        None => None,
+    };
+    let _ = JOURNAL.set(journal);
+    Ok(())
+}
+
+/// Returns whether dead letters are kept
+pub fn is_enabled() -> bool {
+    matches!(JOURNAL.get(), Some(Some(_)))
+}
+
+/// Keeps events a publisher gave up, or logs them if the journal is disabled
+///
+/// # Arguments
+///
+/// * `letters` - Events given up, with their sink, ID, tenant, body and error
+pub fn spool(letters: Vec<DeadLetter>) {
+    if letters.is_empty() {
+        return;
+    }
+    let journal = match JOURNAL.get() {
+        Some(Some(journal)) => journal,
+        _ => {
+            for letter in &letters {
+                log::error!(
+                    "{} dropped event {}: {}",
+                    letter.sink,
+                    letter.id,
+                    letter.error
+                );
+            }
+            return;
+        }
+    };
+    let mut journal = journal.lock().unwrap();
+    let count = letters.len();
+    if let Err(e) = journal.spool(letters) {
+        log::error!("cannot write {} dead letters to the journal: {}", count, e);
+    }
+    metrics::DEAD_LETTERS_GAUGE.set(journal.pending.len() as i64);
+}
+
+/// Lists the pending dead letters of a tenant
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant of the letters
+/// * `sink` - Publisher of the letters, any if None
+/// * `from_seq` - First seq returned
+/// * `limit` - Maximum number of letters, 0 for the default
+///
+/// # Returns
+///
+/// Returns the letters by seq, or an error if the journal is disabled
+pub fn list(
+    tenant: &str,
+    sink: Option<&str>,
+    from_seq: u64,
+    limit: usize,
+) -> Result<Vec<DeadLetter>, String> {
+    let Some(Some(journal)) = JOURNAL.get() else {
+        return Err("dead-letter journal is disabled".to_string());
+    };
+    let limit = if limit == 0 {
+        DEFAULT_LIST_LIMIT
+    } else {
+        limit
+    };
+    let journal = journal.lock().unwrap();
+    Ok(journal
+        .list(tenant, sink, from_seq)
+        .into_iter()
+        .take(limit)
+        .cloned()
+        .collect())
+}
+
+/// Hands pending dead letters of a tenant back to their publishers
+///
+/// Stops at the first letter its publisher does not take, e.g. because its
+/// queue is full or it is no longer configured.
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant of the letters
+/// * `seqs` - Letters replayed, every pending letter of the tenant if empty
+/// * `sink` - Publisher of the letters, any if None
+///
+/// # Returns
+///
+/// Returns the number of letters handed back, or an error naming how many
+/// were handed back before a publisher refused one
+pub fn replay(tenant: &str, seqs: &[u64], sink: Option<&str>) -> Result<u64, String> {
+    let Some(Some(journal)) = JOURNAL.get() else {
+        return Err("dead-letter journal is disabled".to_string());
+    };
+    let mut journal = journal.lock().unwrap();
+    let letters: Vec<DeadLetter> = journal
+        .list(tenant, sink, 0)
+        .into_iter()
+        .filter(|letter| seqs.is_empty() || seqs.contains(&letter.seq))
+        .cloned()
+        .collect();
+    let mut replayed = 0;
+    let mut result = Ok(());
+    for letter in letters {
+        result = dispatch(&letter).and_then(|()| {
+            journal
+                .replayed(letter.seq)
+                .map_err(|e| format!("cannot mark dead letter {}: {}", letter.seq, e))
+        });
+        if result.is_err() {
+            break;
+        }
+        replayed += 1;
+    }
+    metrics::DEAD_LETTERS_GAUGE.set(journal.pending.len() as i64);
+    result
+        .map(|()| replayed)
+        .map_err(|e| format!("replayed {} dead letters, then: {}", replayed, e))
+}
+
+/// Hands a letter to the publisher named by its sink
+fn dispatch(letter: &DeadLetter) -> Result<(), String> {
+    if letter.sink.starts_with("webhook_") {
+        return webhook::replay(letter);
+    }
+    Err(format!("unknown sink {}", letter.sink))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn letter(sink: &str, tenant: &str, id: &str) -> DeadLetter {
+        DeadLetter {
+            sink: sink.to_string(),
+            id: id.to_string(),
+            tenant: tenant.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn replayed_letters_stay_replayed_after_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead_letters");
+        let path = path.to_str().unwrap();
+        let mut journal = Journal::open(path).unwrap();
+        journal
+            .spool(vec![
+                letter("webhook_0", "a", "1-0"),
+                letter("webhook_1", "a", "1-1"),
+                letter("webhook_0", "b", "2-0"),
+            ])
+            .unwrap();
+        journal.replayed(1).unwrap();
+        drop(journal);
+
+        let mut journal = Journal::open(path).unwrap();
+        assert_eq!(journal.last_seq, 3);
+        let ids: Vec<_> = journal.list("a", None, 0).iter().map(|l| l.seq).collect();
+        assert_eq!(ids, [2]);
+        assert!(journal.list("a", Some("webhook_0"), 0).is_empty());
+        assert_eq!(journal.list("b", None, 3).len(), 1);
+        assert!(journal.list("b", None, 4).is_empty());
+        journal
+            .spool(vec![letter("webhook_0", "a", "1-0")])
+            .unwrap();
+        assert_eq!(
+            journal.pending.keys().copied().collect::<Vec<_>>(),
+            [2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn a_letter_cut_short_is_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead_letters");
+        let path = path.to_str().unwrap();
+        let mut journal = Journal::open(path).unwrap();
+        journal
+            .spool(vec![
+                letter("webhook_0", "a", "1-0"),
+                letter("webhook_0", "a", "1-1"),
+            ])
+            .unwrap();
+        drop(journal);
+        let len = std::fs::metadata(path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(len - 1).unwrap();
+
+        let mut journal = Journal::open(path).unwrap();
+        assert_eq!(journal.last_seq, 1);
+        journal
+            .spool(vec![letter("webhook_0", "a", "1-1")])
+            .unwrap();
+        drop(journal);
+        let journal = Journal::open(path).unwrap();
+        assert_eq!(journal.pending.len(), 2);
+        assert_eq!(journal.pending[&2].id, "1-1");
+    }
+}
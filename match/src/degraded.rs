@@ -0,0 +1,104 @@
+//! Degraded mode of the leader
+//!
+//! While the raft loop falls behind, every order placed adds to the backlog
+//! that cancels have to wait behind. With `degraded_apply_lag` the leader
+//! refuses new orders while more committed entries than that wait to be
+//! applied, and with `degraded_queued_proposals` while more regular writes
+//! than that wait to be proposed. Orders are refused with `UNAVAILABLE`, so
+//! clients retry them later; cancels, which take the priority lane, and every
+//! other command are still accepted, so risk can be reduced during overload or
+//! while a restarted leader catches up.
+
+use crate::{config, metrics};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether orders were refused by the last check
+static DEGRADED: AtomicBool = AtomicBool::new(false);
+
+/// Finds the threshold a backlog is above
+///
+/// # Arguments
+/// * `apply_lag` - Committed entries not yet applied
+/// * `queued` - Regular proposals waiting for the raft loop
+/// * `max_apply_lag` - Largest apply lag orders are accepted at
+/// * `max_queued` - Largest proposal queue orders are accepted at
+///
+/// # Returns
+/// The threshold exceeded and why, if one is
+fn exceeded(
+    apply_lag: u64,
+    queued: usize,
+    max_apply_lag: Option<u64>,
+    max_queued: Option<usize>,
+) -> Option<(&'static str, String)> {
+    if let Some(limit) = max_apply_lag.filter(|limit| apply_lag > *limit) {
+        return Some((
+            "apply_lag",
+            format!("{} entries wait to be applied, above {}", apply_lag, limit),
+        ));
+    }
+    if let Some(limit) = max_queued.filter(|limit| queued > *limit) {
+        return Some((
+            "queued_proposals",
+            format!("{} writes wait to be proposed, above {}", queued, limit),
+        ));
+    }
+    None
+}
+
+/// Checks whether new orders may be proposed
+///
+/// # Arguments
+///
+/// * `queued` - Regular proposals waiting for the raft loop
+///
+/// # Returns
+///
+/// Returns why orders are refused while the leader is degraded
+pub fn check_order(queued: usize) -> Result<(), String> {
+    let (max_apply_lag, max_queued) = {
+        let config = config::instance().lock().unwrap();
+        (config.degraded_apply_lag, config.degraded_queued_proposals)
+    };
+    if max_apply_lag.is_none() && max_queued.is_none() {
+        return Ok(());
+    }
+    let apply_lag = metrics::RAFT_APPLY_LAG_GAUGE.get().max(0) as u64;
+    let exceeded = exceeded(apply_lag, queued, max_apply_lag, max_queued);
+    let degraded = exceeded.is_some();
+    if DEGRADED.swap(degraded, Ordering::Relaxed) != degraded {
+        match &exceeded {
+            Some((_, reason)) => log::warn!("degraded, refusing new orders: {}", reason),
+            None => log::info!("no longer degraded, accepting new orders"),
+        }
+        metrics::DEGRADED_GAUGE.set(degraded as i64);
+    }
+    match exceeded {
+        Some((threshold, reason)) => {
+            metrics::DEGRADED_REJECTED_COUNTER_VEC
+                .with_label_values(&[threshold])
+                .inc();
+            Err(format!("degraded: {}", reason))
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_are_refused_above_either_threshold() {
+        assert!(exceeded(1000, 1000, None, None).is_none());
+        assert!(exceeded(100, 10, Some(100), Some(10)).is_none());
+        assert_eq!(
+            exceeded(101, 0, Some(100), Some(10)).unwrap().0,
+            "apply_lag"
+        );
+        assert_eq!(
+            exceeded(0, 11, Some(100), Some(10)).unwrap().0,
+            "queued_proposals"
+        );
+    }
+}
@@ -0,0 +1,317 @@
+//! Cross-replica state divergence detection
+//!
+//! Every `state_check_interval` applied entries each node takes a checksum of its
+//! engine state. Followers report their checksums to the leader, which compares
+//! them with its own checksum of the same index. Mismatches are logged and counted
+//! on both sides; with `fence_divergent_reads` the divergent follower also refuses
+//! reads and fails its health check until a later checksum matches again.
+
+use crate::raft::StateMachine;
+use crate::raft_service::pb::raft_service_client::RaftServiceClient;
+use crate::raft_service::pb::{ChecksumResult, ReportChecksumRequest};
+use crate::{cluster_events, config, metrics};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tonic::transport::Channel;
+
+/// Number of own checksums kept for comparison with follower reports
+const RETAINED_CHECKPOINTS: usize = 64;
+
+/// Own checksums by applied index, oldest first
+static CHECKPOINTS: Mutex<VecDeque<(u64, String)>> = Mutex::new(VecDeque::new());
+/// Whether the last checksum reported to the leader mismatched
+static DIVERGENT: AtomicBool = AtomicBool::new(false);
+
+/// Checksum of the engine state taken at an applied index
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    /// Applied index the checksum was taken at
+    pub index: u64,
+    /// Hex encoded checksum of the state machine snapshot
+    pub checksum: String,
+    /// Leader known to the node when the checksum was taken, 0 if there is none
+    pub leader_id: u64,
+}
+
+/// Checksum schedule handed to the raft node
+pub struct StateCheck {
+    /// Number of applied entries between checksums
+    interval: u64,
+    /// Channel the checkpoints are published to
+    checkpoints: Sender<Checkpoint>,
+}
+
+impl StateCheck {
+    /// Creates a checksum schedule
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - Number of applied entries between checksums
+    ///
+    /// # Returns
+    ///
+    /// Returns the schedule and the receiver of its checkpoints
+    pub fn new(interval: u64) -> (Self, Receiver<Checkpoint>) {
+        let (checkpoints, receiver) = mpsc::channel(16);
+        metrics::watch_channel("state_checkpoints", &checkpoints);
+        (
+            StateCheck {
+                interval: interval.max(1),
+                checkpoints,
+            },
+            receiver,
+        )
+    }
+
+    /// Returns whether a checkpoint is due at an index
+    ///
+    /// The node applies entries up to such an index as a batch of its own, so
+    /// the checkpoint sees the state of exactly that index.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of an entry
+    pub fn is_due(&self, index: u64) -> bool {
+        index.is_multiple_of(self.interval)
+    }
+
+    /// Takes a checkpoint if one is due at the index just applied
+    ///
+    /// Runs on the raft loop right after the entry is applied and freezes the
+    /// state machine there, see `StateMachine::freeze`, so every node hashes the
+    /// state of exactly the same index. The engine shares its order books with
+    /// the frozen copy copy-on-write, so freezing is cheap and hashing it on a
+    /// background thread does not hold up matching. Checkpoints are dropped if
+    /// the reporting task falls behind.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of the entry just applied
+    /// * `leader_id` - Leader known to the node, 0 if there is none
+    /// * `state_machine` - State machine the entry was applied to
+    pub fn observe<S: StateMachine>(&self, index: u64, leader_id: u64, state_machine: &mut S) {
+        if !self.is_due(index) {
+            return;
+        }
+        state_machine.barrier();
+        let frozen = match state_machine.freeze() {
+            Ok(frozen) => frozen,
+            Err(e) => {
+                log::error!("failed to freeze state for checksum at {}: {}", index, e);
+                return;
+            }
+        };
+        let checkpoints = self.checkpoints.clone();
+        let spawned = std::thread::Builder::new()
+            .name("checksum".to_string())
+            .spawn(move || {
+                let data = match frozen() {
+                    Ok(data) => data,
+                    Err(e) => {
+                        log::error!("failed to serialize state for checksum at {}: {}", index, e);
+                        return;
+                    }
+                };
+                let checkpoint = Checkpoint {
+                    index,
+                    checksum: crate::engine::snapshot::checksum(&data),
+                    leader_id,
+                };
+                if checkpoints.try_send(checkpoint).is_err() {
+                    metrics::CHANNEL_DROPPED_COUNTER_VEC
+                        .with_label_values(&["state_checkpoints"])
+                        .inc();
+                }
+            });
+        if let Err(e) = spawned {
+            log::error!("failed to start checksum thread: {}", e);
+        }
+    }
+}
+
+/// Returns whether the last checksum reported to the leader mismatched
+pub fn is_divergent() -> bool {
+    DIVERGENT.load(Ordering::Relaxed)
+}
+
+/// Returns whether reads are refused because the node diverged from the leader
+pub fn is_fenced() -> bool {
+    is_divergent() && config::instance().lock().unwrap().fence_divergent_reads
+}
+
+/// Remembers an own checksum for comparison with follower reports
+fn record(checkpoint: &Checkpoint) {
+    let mut checkpoints = CHECKPOINTS.lock().unwrap();
+    checkpoints.push_back((checkpoint.index, checkpoint.checksum.clone()));
+    while checkpoints.len() > RETAINED_CHECKPOINTS {
+        checkpoints.pop_front();
+    }
+    metrics::STATE_CHECKSUM_INDEX_GAUGE.set(checkpoint.index as i64);
+}
+
+/// Compares a follower's checksum with the own checksum of the same index
+///
+/// # Arguments
+///
+/// * `request` - Checksum reported by the follower
+///
+/// # Returns
+///
+/// Returns whether the checksums match, or unknown if no own checksum of the
+/// index is retained
+pub fn compare(request: &ReportChecksumRequest) -> ChecksumResult {
+    let own = CHECKPOINTS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(index, _)| *index == request.index)
+        .map(|(_, checksum)| checksum.clone());
+    let node = request.node_id.to_string();
+    match own {
+        None => ChecksumResult::Unknown,
+        Some(checksum) if checksum == request.checksum => {
+            metrics::STATE_DIVERGENT_GAUGE_VEC
+                .with_label_values(&[&node])
+                .set(0);
+            ChecksumResult::Match
+        }
+        Some(checksum) => {
+            log::error!(
+                "state of node {} diverged at index {}: checksum {}, leader has {}",
+                request.node_id,
+                request.index,
+                request.checksum,
+                checksum
+            );
+            metrics::STATE_DIVERGENCE_COUNTER_VEC
+                .with_label_values(&[&node])
+                .inc();
+            metrics::STATE_DIVERGENT_GAUGE_VEC
+                .with_label_values(&[&node])
+                .set(1);
+            ChecksumResult::Mismatch
+        }
+    }
+}
+
+/// Reports a checksum to the leader
+///
+/// # Arguments
+///
+/// * `client` - Cached connection, replaced if the leader changed
+/// * `checkpoint` - Checksum to report
+/// * `node_id` - ID of this node
+///
+/// # Returns
+///
+/// Returns the leader's verdict, or an error if the leader cannot be reached
+async fn report(
+    client: &mut Option<(u64, RaftServiceClient<Channel>)>,
+    checkpoint: &Checkpoint,
+    node_id: u64,
+) -> Result<ChecksumResult, String> {
+    if client.as_ref().map(|(leader, _)| *leader) != Some(checkpoint.leader_id) {
+        let addr = config::instance()
+            .lock()
+            .unwrap()
+            .node_list
+            .iter()
+            .find(|node| node.id == checkpoint.leader_id)
+            .map(|node| node.addr.clone())
+            .ok_or_else(|| format!("leader {} is not configured", checkpoint.leader_id))?;
+        let connected = RaftServiceClient::connect(addr)
+            .await
+            .map_err(|e| e.to_string())?;
+        *client = Some((checkpoint.leader_id, connected));
+    }
+    let (_, connected) = client.as_mut().unwrap();
+    let response = connected
+        .report_checksum(ReportChecksumRequest {
+            node_id,
+            index: checkpoint.index,
+            checksum: checkpoint.checksum.clone(),
+        })
+        .await;
+    match response {
+        Ok(response) => Ok(response.into_inner().result()),
+        Err(e) => {
+            *client = None;
+            Err(e.to_string())
+        }
+    }
+}
+
+/// Starts the task that records checkpoints and reports them to the leader
+///
+/// # Arguments
+///
+/// * `checkpoints` - Receiver of the checkpoints taken by the raft node
+pub fn start(mut checkpoints: Receiver<Checkpoint>) {
+    let node_id = config::instance().lock().unwrap().id;
+    tokio::spawn(async move {
+        let mut client = None;
+        while let Some(checkpoint) = checkpoints.recv().await {
+            record(&checkpoint);
+            if checkpoint.leader_id == 0 || checkpoint.leader_id == node_id {
+                continue;
+            }
+            match report(&mut client, &checkpoint, node_id).await {
+                Ok(ChecksumResult::Match) => DIVERGENT.store(false, Ordering::Relaxed),
+                Ok(ChecksumResult::Mismatch) => {
+                    log::error!(
+                        "state diverged from leader {} at index {}",
+                        checkpoint.leader_id,
+                        checkpoint.index
+                    );
+                    metrics::STATE_DIVERGENCE_COUNTER_VEC
+                        .with_label_values(&[&node_id.to_string()])
+                        .inc();
+                    DIVERGENT.store(true, Ordering::Relaxed);
+                }
+                Ok(ChecksumResult::Unknown) => {}
+                Err(e) => log::warn!(
+                    "failed to report state checksum at index {}: {}",
+                    checkpoint.index,
+                    e
+                ),
+            }
+            metrics::STATE_FENCED_GAUGE.set(is_fenced() as i64);
+            cluster_events::observe_fenced(is_fenced());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follower_checksums_are_compared_by_index() {
+        for index in 1..=RETAINED_CHECKPOINTS as u64 + 1 {
+            record(&Checkpoint {
+                index: index * 10,
+                checksum: format!("c{}", index),
+                leader_id: 1,
+            });
+        }
+        let report = |index: u64, checksum: &str| ReportChecksumRequest {
+            node_id: 2,
+            index,
+            checksum: checksum.to_string(),
+        };
+
+        assert_eq!(compare(&report(20, "c2")), ChecksumResult::Match);
+        assert_eq!(compare(&report(20, "c3")), ChecksumResult::Mismatch);
+        assert_eq!(
+            metrics::STATE_DIVERGENT_GAUGE_VEC
+                .with_label_values(&["2"])
+                .get(),
+            1
+        );
+        // Not taken yet, and evicted
+        assert_eq!(compare(&report(15, "c2")), ChecksumResult::Unknown);
+        assert_eq!(compare(&report(10, "c1")), ChecksumResult::Unknown);
+    }
+}
@@ -0,0 +1,512 @@
+//! Drop copy feed
+//!
+//! A supervisory consumer, e.g. a compliance or risk desk, subscribes with
+//! `SubscribeDropCopy` to a copy of every execution report of the accounts
+//! listed in its tenant's `drop_copy_accounts`: orders accepted or rejected,
+//! fills as taker and as maker, and cancels, including the orders dropped with
+//! a removed symbol. The feed is built from the order events of each apply
+//! batch, independent of the requests and streams of the owning clients.
+//!
+//! Order sessions, see `order_session`, get the reports of the accounts they
+//! place orders for the same way, without the accounts being listed.
+//!
+//! Every node applies the same log and can serve the feed; reports carry the
+//! raft index of their command, so a consumer that reconnects to another node
+//! can tell which reports it already has. The node remembers which resting
+//! orders belong to the listed accounts to attribute maker fills; orders placed
+//! before a restart are looked up in the query store, if enabled. Subscribers
+//! that fall `channels.drop_copy` reports behind are disconnected rather than
+//! missing reports.
+
+use crate::engine::entry::order::OrderStatus;
+use crate::engine::entry::OrderSide;
+use crate::engine::history::{OrderChange, OrderEvent};
+use crate::match_service::pb::{self, ExecutionKind, ExecutionReport};
+use crate::match_service::{order_state, trade_record};
+use crate::query_store::{self, OrderRecord, TradeRecord};
+use crate::{config, metrics};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+/// Resting orders of the listed accounts and the subscribers to their reports
+static STATE: Mutex<State> = Mutex::new(State::new());
+
+/// Stream of reports sent to one subscriber
+pub type ReportStream = Receiver<Result<ExecutionReport, tonic::Status>>;
+
+/// Consumer subscribed to the drop copy of a tenant
+#[derive(Debug)]
+struct Subscriber {
+    /// ID of an order session, 0 for drop copy consumers
+    session: u64,
+    /// Tenant the consumer is scoped to
+    tenant: String,
+    /// Accounts the consumer gets reports of
+    accounts: BTreeSet<u64>,
+    /// Queue of the reports not yet streamed to the consumer
+    sender: Sender<Result<ExecutionReport, tonic::Status>>,
+}
+
+/// Resting order of a listed account
+#[derive(Debug)]
+struct Resting {
+    /// Account the order was placed for
+    account_id: u64,
+    /// Quantity not filled yet
+    remaining: Decimal,
+}
+
+/// Drop copy state of the node
+#[derive(Debug)]
+struct State {
+    /// Resting orders of the listed accounts keyed by tenant, symbol and order ID
+    resting: BTreeMap<(String, String, String), Resting>,
+    /// Connected consumers
+    subscribers: Vec<Subscriber>,
+    /// ID of the last order session subscribed
+    last_session: u64,
+}
+
+impl State {
+    /// Creates the state of a node that applied nothing yet
+    const fn new() -> Self {
+        State {
+            resting: BTreeMap::new(),
+            subscribers: Vec::new(),
+            last_session: 0,
+        }
+    }
+
+    /// Derives the reports of a batch of order events
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - Order events of the batch, in log order
+    /// * `listed` - Drop copy accounts by tenant
+    /// * `maker_order` - Looks up the account and remaining quantity of a maker
+    ///   order not known to be resting
+    fn reports(
+        &mut self,
+        events: &[OrderEvent],
+        listed: &BTreeMap<String, BTreeSet<u64>>,
+        maker_order: impl Fn(&str, &str, &str) -> Option<(u64, Decimal)>,
+    ) -> Vec<(String, ExecutionReport)> {
+        let resting = &mut self.resting;
+        let mut reports = Vec::new();
+        for event in events {
+            let Some(accounts) = listed.get(&event.tenant) else {
+                continue;
+            };
+            let mut report = |account_id: u64, kind: ExecutionKind, order, trade, maker| {
+                reports.push((
+                    event.tenant.clone(),
+                    ExecutionReport {
+                        kind: kind as i32,
+                        index: event.index,
+                        account_id,
+                        order: Some(order),
+                        trade,
+                        maker,
+                    },
+                ))
+            };
+            match &event.change {
+                OrderChange::Placed { order, trades } => {
+                    let own = accounts.contains(&event.account_id);
+                    let state = order_state(OrderRecord {
+                        index: event.index,
+                        account_id: event.account_id,
+                        order: order.clone(),
+                        last_change: (event.index, trades.len() as u32),
+                    });
+                    if own {
+                        let kind = match order.status {
+                            OrderStatus::Rejected => ExecutionKind::Rejected,
+                            _ => ExecutionKind::Accepted,
+                        };
+                        report(event.account_id, kind, state.clone(), None, false);
+                    }
+                    for (seq, trade) in trades.iter().enumerate() {
+                        let taker_side = if trade.buyer_order_id == order.id {
+                            OrderSide::Buy
+                        } else {
+                            OrderSide::Sell
+                        };
+                        let maker_id = match taker_side {
+                            OrderSide::Buy => &trade.seller_order_id,
+                            OrderSide::Sell => &trade.buyer_order_id,
+                        };
+                        let key = (event.tenant.clone(), order.symbol.clone(), maker_id.clone());
+                        let maker = match resting.get_mut(&key) {
+                            Some(maker) => {
+                                maker.remaining -= trade.quantity;
+                                let maker = (maker.account_id, maker.remaining);
+                                if maker.1 <= Decimal::ZERO {
+                                    resting.remove(&key);
+                                }
+                                Some(maker)
+                            }
+                            None => maker_order(&event.tenant, &order.symbol, maker_id),
+                        };
+                        let maker_account = maker.map(|(account_id, _)| account_id);
+                        let (buyer_account_id, seller_account_id) = match taker_side {
+                            OrderSide::Buy => (event.account_id, maker_account.unwrap_or_default()),
+                            OrderSide::Sell => {
+                                (maker_account.unwrap_or_default(), event.account_id)
+                            }
+                        };
+                        let trade = trade_record(TradeRecord {
+                            index: event.index,
+                            trade_seq: seq as u32,
+                            symbol: order.symbol.clone(),
+                            taker_side,
+                            price: trade.price,
+                            quantity: trade.quantity,
+                            buyer_order_id: trade.buyer_order_id.clone(),
+                            buyer_account_id,
+                            seller_order_id: trade.seller_order_id.clone(),
+                            seller_account_id,
+                            time: event.time,
+                        });
+                        if own {
+                            let fill = Some(trade.clone());
+                            report(
+                                event.account_id,
+                                ExecutionKind::Fill,
+                                state.clone(),
+                                fill,
+                                false,
+                            );
+                        }
+                        if let Some((maker, remaining)) =
+                            maker.filter(|(maker, _)| accounts.contains(maker))
+                        {
+                            let status = if remaining <= Decimal::ZERO {
+                                pb::OrderStatus::Filled
+                            } else {
+                                pb::OrderStatus::PartiallyFilled
+                            };
+                            let order = order_ref(maker, &order.symbol, maker_id, status);
+                            report(maker, ExecutionKind::Fill, order, Some(trade), true);
+                        }
+                    }
+                    if own && order.is_cancelable() {
+                        resting.insert(
+                            (event.tenant.clone(), order.symbol.clone(), order.id.clone()),
+                            Resting {
+                                account_id: event.account_id,
+                                remaining: order.remaining_quantity(),
+                            },
+                        );
+                    }
+                }
+                OrderChange::Canceled { symbol, order_id } => {
+                    let key = (event.tenant.clone(), symbol.clone(), order_id.clone());
+                    let account_id = resting
+                        .remove(&key)
+                        .map_or(event.account_id, |order| order.account_id);
+                    if accounts.contains(&account_id) {
+                        let status = pb::OrderStatus::Canceled;
+                        let order = order_ref(account_id, symbol, order_id, status);
+                        report(account_id, ExecutionKind::Canceled, order, None, false);
+                    }
+                }
+                OrderChange::SymbolRemoved { symbol } => {
+                    let dropped: Vec<_> = resting
+                        .keys()
+                        .filter(|(tenant, dropped, _)| *tenant == event.tenant && dropped == symbol)
+                        .cloned()
+                        .collect();
+                    for key in dropped {
+                        let account_id = resting.remove(&key).unwrap().account_id;
+                        let status = pb::OrderStatus::Canceled;
+                        let order = order_ref(account_id, symbol, &key.2, status);
+                        report(account_id, ExecutionKind::Canceled, order, None, false);
+                    }
+                }
+                OrderChange::SymbolListed { .. }
+                | OrderChange::SymbolHalted { .. }
+                | OrderChange::SymbolResumed { .. } => {}
+            }
+        }
+        reports
+    }
+
+    /// Queues reports for the subscribers, disconnecting those that are gone
+    /// or too far behind
+    ///
+    /// # Arguments
+    ///
+    /// * `reports` - Reports with the tenant of their account
+    fn publish(&mut self, reports: &[(String, ExecutionReport)]) {
+        self.subscribers.retain(|subscriber| {
+            for (tenant, report) in reports {
+                if *tenant != subscriber.tenant || !subscriber.accounts.contains(&report.account_id)
+                {
+                    continue;
+                }
+                match subscriber.sender.try_send(Ok(report.clone())) {
+                    Ok(()) => {}
+                    Err(TrySendError::Closed(_)) => return false,
+                    Err(TrySendError::Full(_)) => {
+                        log::warn!(
+                            "disconnect drop copy subscriber of tenant {} falling behind",
+                            subscriber.tenant
+                        );
+                        metrics::CHANNEL_DROPPED_COUNTER_VEC
+                            .with_label_values(&["drop_copy"])
+                            .inc();
+                        return false;
+                    }
+                }
+            }
+            !subscriber.sender.is_closed()
+        });
+        let consumers = self.subscribers.iter().filter(|s| s.session == 0).count();
+        metrics::DROP_COPY_SUBSCRIBERS_GAUGE.set(consumers as i64);
+    }
+}
+
+/// Describes an order by its ID and status alone
+pub(crate) fn order_ref(
+    account_id: u64,
+    symbol: &str,
+    order_id: &str,
+    status: pb::OrderStatus,
+) -> pb::OrderState {
+    pb::OrderState {
+        order: Some(pb::Order {
+            order_id: order_id.parse().unwrap_or_default(),
+            account_id,
+            symbol: symbol.to_string(),
+            ..Default::default()
+        }),
+        status: status as i32,
+        ..Default::default()
+    }
+}
+
+/// Returns the drop copy accounts of the tenants that list any
+fn listed_accounts() -> BTreeMap<String, BTreeSet<u64>> {
+    config::instance()
+        .lock()
+        .unwrap()
+        .tenants
+        .iter()
+        .filter(|tenant| !tenant.drop_copy_accounts.is_empty())
+        .map(|tenant| {
+            let accounts = tenant.drop_copy_accounts.iter().copied().collect();
+            (tenant.id.clone(), accounts)
+        })
+        .collect()
+}
+
+/// Subscribes a consumer to the drop copy of a tenant
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the consumer is scoped to
+/// * `accounts` - Accounts to get reports of, empty for all listed accounts
+///
+/// # Returns
+///
+/// Returns the reports, or why an account is not listed for the drop copy
+pub fn subscribe(tenant: &str, accounts: &[u64]) -> Result<ReportStream, String> {
+    let listed = listed_accounts().remove(tenant).unwrap_or_default();
+    if let Some(account) = accounts.iter().find(|account| !listed.contains(account)) {
+        return Err(format!(
+            "account {} is not listed for the drop copy",
+            account
+        ));
+    }
+    if listed.is_empty() {
+        return Err(format!("tenant {} lists no drop copy accounts", tenant));
+    }
+    let accounts = if accounts.is_empty() {
+        listed
+    } else {
+        accounts.iter().copied().collect()
+    };
+    let capacity = config::instance().lock().unwrap().channels.drop_copy;
+    let (sender, receiver) = mpsc::channel(capacity.max(1));
+    let mut state = STATE.lock().unwrap();
+    state.subscribers.push(Subscriber {
+        session: 0,
+        tenant: tenant.to_string(),
+        accounts,
+        sender,
+    });
+    let consumers = state.subscribers.iter().filter(|s| s.session == 0).count();
+    metrics::DROP_COPY_SUBSCRIBERS_GAUGE.set(consumers as i64);
+    Ok(receiver)
+}
+
+/// Subscribes an order session to the reports of the accounts it watches
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the session is scoped to
+///
+/// # Returns
+///
+/// Returns the ID of the session and its reports, none until it watches an account
+pub fn subscribe_session(tenant: &str) -> (u64, ReportStream) {
+    let capacity = config::instance().lock().unwrap().channels.drop_copy;
+    let (sender, receiver) = mpsc::channel(capacity.max(1));
+    let mut state = STATE.lock().unwrap();
+    state.last_session += 1;
+    let session = state.last_session;
+    state.subscribers.push(Subscriber {
+        session,
+        tenant: tenant.to_string(),
+        accounts: BTreeSet::new(),
+        sender,
+    });
+    (session, receiver)
+}
+
+/// Sends an order session the reports of an account from the next batch on
+///
+/// # Arguments
+///
+/// * `session` - ID of the session, see `subscribe_session`
+/// * `account_id` - Account the session places an order for
+pub fn watch(session: u64, account_id: u64) {
+    let mut state = STATE.lock().unwrap();
+    if let Some(subscriber) = state
+        .subscribers
+        .iter_mut()
+        .find(|subscriber| subscriber.session == session)
+    {
+        subscriber.accounts.insert(account_id);
+    }
+}
+
+/// Sends the reports of a batch of applied commands to the subscribers
+///
+/// # Arguments
+///
+/// * `events` - Order events of the batch, in log order
+pub fn write(events: &[OrderEvent]) {
+    if events.is_empty() {
+        return;
+    }
+    let mut listed = listed_accounts();
+    let mut state = STATE.lock().unwrap();
+    for subscriber in state.subscribers.iter().filter(|s| s.session != 0) {
+        listed
+            .entry(subscriber.tenant.clone())
+            .or_default()
+            .extend(&subscriber.accounts);
+    }
+    if listed.is_empty() {
+        return;
+    }
+    let maker_order = |tenant: &str, symbol: &str, order_id: &str| {
+        let store = query_store::instance()?;
+        match store.order(tenant, symbol, order_id) {
+            Ok(record) => {
+                record.map(|record| (record.account_id, record.order.remaining_quantity()))
+            }
+            Err(e) => {
+                log::warn!("drop copy cannot look up maker order {}: {}", order_id, e);
+                None
+            }
+        }
+    };
+    let reports = state.reports(events, &listed, maker_order);
+    if !reports.is_empty() {
+        state.publish(&reports);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::entry::{Order, Trade};
+    use rust_decimal_macros::dec;
+
+    fn event(index: u64, account_id: u64, change: OrderChange) -> OrderEvent {
+        OrderEvent {
+            index,
+            tenant: "a".to_string(),
+            account_id,
+            time: 0,
+            change,
+        }
+    }
+
+    fn placed(id: &str, side: OrderSide, status: OrderStatus, trades: Vec<Trade>) -> OrderChange {
+        let order = Order {
+            id: id.to_string(),
+            symbol: "BTCUSDT".to_string(),
+            side,
+            quantity: dec!(2),
+            filled_quantity: trades.iter().map(|trade| trade.quantity).sum(),
+            status,
+            ..Default::default()
+        };
+        OrderChange::Placed { order, trades }
+    }
+
+    #[test]
+    fn listed_accounts_get_their_executions_as_taker_and_maker() {
+        let mut state = State::new();
+        let listed = BTreeMap::from([("a".to_string(), BTreeSet::from([1]))]);
+        let trade = |quantity| {
+            Trade::new(
+                "t".to_string(),
+                "BTCUSDT".to_string(),
+                dec!(100),
+                quantity,
+                "2".to_string(),
+                "1".to_string(),
+                0,
+            )
+        };
+        let events = [
+            event(1, 1, placed("1", OrderSide::Sell, OrderStatus::New, vec![])),
+            event(
+                2,
+                9,
+                placed("9", OrderSide::Buy, OrderStatus::Rejected, vec![]),
+            ),
+            event(
+                3,
+                2,
+                placed(
+                    "2",
+                    OrderSide::Buy,
+                    OrderStatus::Filled,
+                    vec![trade(dec!(1))],
+                ),
+            ),
+            event(
+                4,
+                1,
+                OrderChange::Canceled {
+                    symbol: "BTCUSDT".to_string(),
+                    order_id: "1".to_string(),
+                },
+            ),
+        ];
+        let reports = state.reports(&events, &listed, |_, _, _| None);
+        let kinds: Vec<_> = reports
+            .iter()
+            .map(|(_, report)| (report.index, report.account_id, report.kind, report.maker))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (1, 1, ExecutionKind::Accepted as i32, false),
+                (3, 1, ExecutionKind::Fill as i32, true),
+                (4, 1, ExecutionKind::Canceled as i32, false),
+            ]
+        );
+        let fill = reports[1].1.trade.as_ref().unwrap();
+        assert_eq!((fill.buyer_account_id, fill.seller_account_id), (2, 1));
+        assert!(state.resting.is_empty());
+    }
+}
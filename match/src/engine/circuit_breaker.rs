@@ -0,0 +1,438 @@
+//! Circuit Breaker Module
+//!
+//! This module halts trading in a symbol whose trade price moves too far within a
+//! window of engine time, see `Symbol::circuit_breaker`. The candidates for the
+//! lowest and highest price traded in the window of each symbol are part of the
+//! replicated tenant state and only advanced by the engine time of the commands,
+//! so every replica halts the same symbol after the same order. The halt itself
+//! is the `Halted` status of the symbol and ends with a timer, see `engine::timers`.
+//!
+//! An order or liquidation that moves the price too far still completes its
+//! matching; the orders after it are rejected until trading resumes.
+
+use crate::engine::entry::{Order, Symbol};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+/// Prices traded within the window of one symbol
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+struct Window {
+    /// Engine time and price of the trades that may still be the lowest in the
+    /// window, prices rising from the lowest
+    lows: VecDeque<(u64, Decimal)>,
+    /// Engine time and price of the trades that may still be the highest in the
+    /// window, prices falling from the highest
+    highs: VecDeque<(u64, Decimal)>,
+}
+
+/// Halt of a symbol tripped by its circuit breaker
+#[derive(Debug, Clone)]
+pub struct Halt {
+    /// Engine time in milliseconds trading resumes at
+    pub until_ms: u64,
+    /// Why trading halted
+    pub reason: String,
+    /// Orders canceled by the halt, in the order they were canceled
+    pub canceled: Vec<Order>,
+}
+
+/// Price windows of the symbols of a tenant with a circuit breaker
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct CircuitBreakers {
+    /// Windows of the symbols that traded, keyed by symbol
+    windows: BTreeMap<String, Window>,
+}
+
+impl CircuitBreakers {
+    /// Records a trade price of a symbol and checks how far it moved
+    ///
+    /// Trades that left the window of `circuit_window_ms` before `now_ms` are
+    /// forgotten first.
+    ///
+    /// # Arguments
+    /// * `symbol` - The symbol that traded
+    /// * `price` - Price of its last trade
+    /// * `now_ms` - Engine time of the trade in milliseconds
+    ///
+    /// # Returns
+    /// The price traded in the window that the new price moved more than
+    /// `circuit_breaker` away from, None if the symbol may keep trading
+    pub fn observe(&mut self, symbol: &Symbol, price: Decimal, now_ms: u64) -> Option<Decimal> {
+        if symbol.circuit_breaker.is_zero() {
+            return None;
+        }
+        let window = self.windows.entry(symbol.name.clone()).or_default();
+        let start = now_ms.saturating_sub(symbol.circuit_window_ms);
+        for prices in [&mut window.lows, &mut window.highs] {
+            while prices.front().is_some_and(|&(at_ms, _)| at_ms < start) {
+                prices.pop_front();
+            }
+        }
+        let low = window.lows.front().map(|&(_, low)| low);
+        let high = window.highs.front().map(|&(_, high)| high);
+        let moved = low
+            .filter(|&low| price - low > low * symbol.circuit_breaker)
+            .or_else(|| high.filter(|&high| high - price > high * symbol.circuit_breaker));
+        while window.lows.back().is_some_and(|&(_, low)| low >= price) {
+            window.lows.pop_back();
+        }
+        window.lows.push_back((now_ms, price));
+        while window.highs.back().is_some_and(|&(_, high)| high <= price) {
+            window.highs.pop_back();
+        }
+        window.highs.push_back((now_ms, price));
+        moved
+    }
+
+    /// Forgets the prices traded on a symbol, e.g. once it halted or was removed
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol
+    pub fn remove(&mut self, symbol: &str) {
+        self.windows.remove(symbol);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::clock::Hlc;
+    use crate::engine::codec;
+    use crate::engine::entry::order::OrderStatus;
+    use crate::engine::entry::{OrderSide, OrderType, SymbolStatus};
+    use crate::engine::matchengine::{
+        CommandEnvelope, MarketType, MatchCmd, MatchCmdType, MatchEngine, OrderChange,
+        DEFAULT_TENANT,
+    };
+    use rust_decimal_macros::dec;
+
+    fn apply(engine: &mut MatchEngine, index: u64, at_ms: u64, account_id: u64, cmd: MatchCmd) {
+        let envelope = CommandEnvelope {
+            account_id,
+            hlc: Hlc::from_millis(at_ms).0,
+            cmd,
+            ..Default::default()
+        };
+        engine.on_message(index, &codec::encode(&envelope)).unwrap();
+    }
+
+    fn place(id: &str, side: OrderSide, price: Decimal) -> MatchCmd {
+        MatchCmd {
+            order: Some(Order {
+                id: id.to_string(),
+                symbol: "BTC".to_string(),
+                order_type: OrderType::Limit,
+                side,
+                price,
+                quantity: dec!(1),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn status(engine: &MatchEngine) -> SymbolStatus {
+        let tenant = engine.get_tenant(DEFAULT_TENANT).unwrap();
+        tenant.get_symbol(MarketType::Spot, "BTC").unwrap().status
+    }
+
+    fn admin(cmd: MatchCmdType) -> MatchCmd {
+        MatchCmd {
+            cmd,
+            symbol: Some(Symbol {
+                name: "BTC".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn windows_keep_the_lowest_and_highest_price_traded_in_them() {
+        let symbol = Symbol {
+            name: "BTC".to_string(),
+            circuit_breaker: dec!(0.1),
+            circuit_window_ms: 1_000,
+            ..Default::default()
+        };
+        let mut breakers = CircuitBreakers::default();
+        assert_eq!(breakers.observe(&symbol, dec!(100), 1_000), None);
+        assert_eq!(breakers.observe(&symbol, dec!(105), 1_200), None);
+        assert_eq!(breakers.observe(&symbol, dec!(95), 1_400), None);
+        // 111 is more than 10% above 100, 95 is the lowest price in the window
+        assert_eq!(breakers.observe(&symbol, dec!(111), 1_600), Some(dec!(95)));
+        // Once 95 left the window, 111 is the highest and 100 less than 10% below it
+        assert_eq!(breakers.observe(&symbol, dec!(100), 2_500), None);
+        assert_eq!(breakers.observe(&symbol, dec!(101), 2_550), None);
+        // Once 111 left it as well, 101 is the highest
+        assert_eq!(breakers.observe(&symbol, dec!(89), 2_700), Some(dec!(101)));
+    }
+
+    #[test]
+    fn halts_cancel_resting_orders_and_end_with_their_timer() {
+        let mut engine = MatchEngine::new();
+        let symbol = Symbol {
+            name: "BTC".to_string(),
+            max_price: dec!(1000),
+            max_quantity: dec!(1000),
+            circuit_breaker: dec!(0.1),
+            circuit_window_ms: 1_000,
+            halt_ms: 5_000,
+            cancel_on_halt: true,
+            ..Default::default()
+        };
+        let create = MatchCmd {
+            cmd: MatchCmdType::CreateSymbol,
+            symbol: Some(symbol),
+            ..Default::default()
+        };
+        apply(&mut engine, 1, 1_000, 1, create);
+        apply(
+            &mut engine,
+            2,
+            1_000,
+            1,
+            place("1", OrderSide::Sell, dec!(100)),
+        );
+        apply(
+            &mut engine,
+            3,
+            1_000,
+            2,
+            place("2", OrderSide::Buy, dec!(100)),
+        );
+        apply(
+            &mut engine,
+            4,
+            1_000,
+            1,
+            place("3", OrderSide::Sell, dec!(120)),
+        );
+        apply(
+            &mut engine,
+            5,
+            1_000,
+            1,
+            place("4", OrderSide::Sell, dec!(200)),
+        );
+        engine.take_order_events();
+
+        // Buying at 120 moves the price 20% within the window
+        apply(
+            &mut engine,
+            6,
+            1_500,
+            2,
+            place("5", OrderSide::Buy, dec!(120)),
+        );
+        assert_eq!(status(&engine), SymbolStatus::Halted);
+        assert_eq!(engine.timers().next_due(), Some(6_500));
+        let events = engine.take_order_events();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(
+            &events[1].change,
+            OrderChange::Canceled { order_id, .. } if order_id == "4"
+        ));
+        assert_eq!(events[1].account_id, 1);
+        assert!(matches!(events[2].change, OrderChange::SymbolHalted { .. }));
+
+        apply(
+            &mut engine,
+            7,
+            2_000,
+            2,
+            place("6", OrderSide::Buy, dec!(120)),
+        );
+        let events = engine.take_order_events();
+        assert!(matches!(
+            &events[0].change,
+            OrderChange::Placed { order, .. } if order.status == OrderStatus::Rejected
+        ));
+
+        let tick = MatchCmd {
+            cmd: MatchCmdType::Tick,
+            ..Default::default()
+        };
+        apply(&mut engine, 8, 6_500, 0, tick);
+        assert_eq!(status(&engine), SymbolStatus::Active);
+        let events = engine.take_order_events();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0].change,
+            OrderChange::SymbolResumed { .. }
+        ));
+        // The window starts over, 200 is far from 120 but nothing traded since
+        apply(
+            &mut engine,
+            9,
+            6_600,
+            1,
+            place("7", OrderSide::Sell, dec!(200)),
+        );
+        apply(
+            &mut engine,
+            10,
+            6_600,
+            2,
+            place("8", OrderSide::Buy, dec!(200)),
+        );
+        assert_eq!(status(&engine), SymbolStatus::Active);
+    }
+
+    #[test]
+    fn liquidations_trip_the_circuit_breaker() {
+        let mut engine = MatchEngine::new();
+        let contract = Symbol {
+            name: "BTC".to_string(),
+            max_price: dec!(1000),
+            max_quantity: dec!(1000),
+            max_leverage: dec!(20),
+            maintenance_margin: dec!(0.01),
+            circuit_breaker: dec!(0.1),
+            circuit_window_ms: 1_000,
+            halt_ms: 5_000,
+            ..Default::default()
+        };
+        let perp = |cmd: MatchCmd| MatchCmd {
+            market: MarketType::Perp,
+            ..cmd
+        };
+        let create = MatchCmd {
+            cmd: MatchCmdType::CreateSymbol,
+            symbol: Some(contract),
+            ..Default::default()
+        };
+        apply(&mut engine, 1, 1_000, 1, perp(create));
+        let sell = perp(place("1", OrderSide::Sell, dec!(100)));
+        apply(&mut engine, 2, 1_000, 1, sell);
+        let buy = perp(place("2", OrderSide::Buy, dec!(100)));
+        apply(&mut engine, 3, 1_000, 2, buy);
+        let bid = perp(place("3", OrderSide::Buy, dec!(85)));
+        apply(&mut engine, 4, 1_000, 2, bid);
+        engine.take_order_events();
+
+        // Liquidating into the bid at 85 moves the price 15% within the window
+        let liquidation = MatchCmd {
+            cmd: MatchCmdType::Liquidate,
+            ..perp(place("4", OrderSide::Sell, Decimal::ZERO))
+        };
+        apply(&mut engine, 5, 1_500, 1, liquidation);
+        let tenant = engine.get_tenant(DEFAULT_TENANT).unwrap();
+        let contract = tenant.get_symbol(MarketType::Perp, "BTC").unwrap();
+        assert_eq!(contract.status, SymbolStatus::Halted);
+        assert_eq!(engine.timers().next_due(), Some(6_500));
+        let events = engine.take_order_events();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            &events[1].change,
+            OrderChange::SymbolHalted { reason, .. } if reason.contains("85")
+        ));
+
+        let tick = MatchCmd {
+            cmd: MatchCmdType::Tick,
+            ..Default::default()
+        };
+        apply(&mut engine, 6, 6_500, 0, tick);
+        let tenant = engine.get_tenant(DEFAULT_TENANT).unwrap();
+        let contract = tenant.get_symbol(MarketType::Perp, "BTC").unwrap();
+        assert_eq!(contract.status, SymbolStatus::Active);
+    }
+
+    #[test]
+    fn operator_halts_last_until_resumed_and_mass_cancels_clear_the_book() {
+        let mut engine = MatchEngine::new();
+        let symbol = Symbol {
+            name: "BTC".to_string(),
+            max_price: dec!(1000),
+            max_quantity: dec!(1000),
+            circuit_breaker: dec!(0.1),
+            circuit_window_ms: 1_000,
+            halt_ms: 5_000,
+            ..Default::default()
+        };
+        let create = MatchCmd {
+            cmd: MatchCmdType::CreateSymbol,
+            symbol: Some(symbol),
+            ..Default::default()
+        };
+        apply(&mut engine, 1, 1_000, 1, create);
+        apply(
+            &mut engine,
+            2,
+            1_000,
+            1,
+            place("1", OrderSide::Sell, dec!(100)),
+        );
+        apply(
+            &mut engine,
+            3,
+            1_000,
+            2,
+            place("2", OrderSide::Buy, dec!(100)),
+        );
+        apply(
+            &mut engine,
+            4,
+            1_000,
+            1,
+            place("3", OrderSide::Sell, dec!(120)),
+        );
+        apply(
+            &mut engine,
+            5,
+            1_000,
+            1,
+            place("4", OrderSide::Sell, dec!(200)),
+        );
+        apply(
+            &mut engine,
+            6,
+            1_500,
+            2,
+            place("5", OrderSide::Buy, dec!(120)),
+        );
+        assert_eq!(status(&engine), SymbolStatus::Halted);
+        engine.take_order_events();
+
+        // The operator takes over the circuit breaker halt, it no longer ends by itself
+        apply(&mut engine, 7, 2_000, 0, admin(MatchCmdType::HaltSymbol));
+        assert!(engine.timers().is_empty());
+        let tick = MatchCmd {
+            cmd: MatchCmdType::Tick,
+            ..Default::default()
+        };
+        apply(&mut engine, 8, 6_500, 0, tick);
+        assert_eq!(status(&engine), SymbolStatus::Halted);
+
+        // Halted books take cancels, of every account
+        apply(&mut engine, 9, 7_000, 0, admin(MatchCmdType::MassCancel));
+        let tenant = engine.get_tenant(DEFAULT_TENANT).unwrap();
+        assert!(!tenant.is_open("BTC", "4"));
+        let events = engine.take_order_events();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            &events[0].change,
+            OrderChange::Canceled { order_id, .. } if order_id == "4"
+        ));
+        assert_eq!(events[0].account_id, 1);
+
+        apply(&mut engine, 10, 7_500, 0, admin(MatchCmdType::ResumeSymbol));
+        assert_eq!(status(&engine), SymbolStatus::Active);
+        apply(&mut engine, 11, 7_500, 0, admin(MatchCmdType::ResumeSymbol));
+        let events = engine.take_order_events();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0].change,
+            OrderChange::SymbolResumed { .. }
+        ));
+
+        apply(&mut engine, 12, 8_000, 0, admin(MatchCmdType::HaltSymbol));
+        assert_eq!(status(&engine), SymbolStatus::Halted);
+        let events = engine.take_order_events();
+        assert!(matches!(
+            &events[0].change,
+            OrderChange::SymbolHalted { reason, .. } if reason == "halted by operator"
+        ));
+    }
+}
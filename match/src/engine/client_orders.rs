@@ -0,0 +1,133 @@
+//! Client Order ID Module
+//!
+//! This module maps the IDs clients give their orders to the orders of an account.
+//! An order placed with a client order ID is entered under its account, and a second
+//! order of the account with the same ID is rejected while the first one is open.
+//! Cancels naming only a client order ID are resolved to the order it maps to.
+//!
+//! The index changes only while commands are applied in log order: entries are added
+//! when an order is placed, and replaced or pruned after the engine ran its barrier,
+//! so whether an order is still open is read from books every replica agrees on.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Orders entered between two prunes of the closed orders
+pub const PRUNE_EVERY: u64 = 10_000;
+
+/// Order a client order ID maps to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientOrder {
+    /// Symbol the order was placed on
+    pub symbol: String,
+    /// ID of the order in the engine
+    pub order_id: String,
+}
+
+/// Client order IDs of the accounts of a tenant
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClientOrderIndex {
+    /// Orders keyed by account and client order ID
+    accounts: BTreeMap<u64, BTreeMap<String, ClientOrder>>,
+    /// Orders entered since the last prune
+    entered: u64,
+}
+
+impl ClientOrderIndex {
+    /// Looks up the order of an account with a client order ID
+    ///
+    /// # Arguments
+    /// * `account_id` - Account the order was placed for
+    /// * `client_order_id` - ID the client gave the order
+    ///
+    /// # Returns
+    /// The order the ID was last given to, open or not, None if unknown
+    pub fn get(&self, account_id: u64, client_order_id: &str) -> Option<&ClientOrder> {
+        self.accounts.get(&account_id)?.get(client_order_id)
+    }
+
+    /// Enters an order under its client order ID, replacing an earlier one
+    ///
+    /// # Arguments
+    /// * `account_id` - Account the order is placed for
+    /// * `client_order_id` - ID the client gave the order
+    /// * `order` - The order
+    pub fn insert(&mut self, account_id: u64, client_order_id: &str, order: ClientOrder) {
+        self.accounts
+            .entry(account_id)
+            .or_default()
+            .insert(client_order_id.to_string(), order);
+        self.entered += 1;
+    }
+
+    /// Returns whether `PRUNE_EVERY` orders were entered since the last prune
+    pub fn prune_due(&self) -> bool {
+        self.entered >= PRUNE_EVERY
+    }
+
+    /// Forgets the orders that are no longer open
+    ///
+    /// # Arguments
+    /// * `open` - Whether an order is still open, by symbol and order ID
+    pub fn prune(&mut self, mut open: impl FnMut(&str, &str) -> bool) {
+        for orders in self.accounts.values_mut() {
+            orders.retain(|_, order| open(&order.symbol, &order.order_id));
+        }
+        self.accounts.retain(|_, orders| !orders.is_empty());
+        self.entered = 0;
+    }
+
+    /// Returns the number of client order IDs entered
+    pub fn len(&self) -> usize {
+        self.accounts.values().map(BTreeMap::len).sum()
+    }
+
+    /// Returns whether no client order ID is entered
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(symbol: &str, order_id: &str) -> ClientOrder {
+        ClientOrder {
+            symbol: symbol.to_string(),
+            order_id: order_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn ids_are_scoped_to_accounts_and_pruned_once_closed() {
+        let mut index = ClientOrderIndex::default();
+        index.insert(1, "a", order("BTC", "10"));
+        index.insert(2, "a", order("BTC", "11"));
+        index.insert(1, "b", order("ETH", "12"));
+        assert_eq!(index.get(1, "a"), Some(&order("BTC", "10")));
+        assert_eq!(index.get(2, "a"), Some(&order("BTC", "11")));
+        assert_eq!(index.get(3, "a"), None);
+
+        index.insert(1, "a", order("BTC", "13"));
+        assert_eq!(index.get(1, "a"), Some(&order("BTC", "13")));
+        index.prune(|_, order_id| order_id != "11" && order_id != "12");
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.get(2, "a"), None);
+        assert_eq!(index.get(1, "b"), None);
+        assert!(index.accounts.get(&2).is_none());
+    }
+
+    #[test]
+    fn prunes_are_due_by_entered_orders() {
+        let mut index = ClientOrderIndex::default();
+        for n in 0..PRUNE_EVERY {
+            assert!(!index.prune_due());
+            index.insert(1, &n.to_string(), order("BTC", &n.to_string()));
+        }
+        assert!(index.prune_due());
+        index.prune(|_, _| true);
+        assert!(!index.prune_due());
+        assert_eq!(index.len(), PRUNE_EVERY as usize);
+    }
+}
@@ -0,0 +1,115 @@
+//! Hybrid Logical Clock Module
+//!
+//! This module provides the hybrid logical clock (HLC) engine time is taken from.
+//! A timestamp packs the wall clock in milliseconds into its upper 48 bits and a
+//! logical counter into the lower 16 bits, so timestamps compare as plain integers
+//! and a counter overflow carries into the next millisecond.
+//!
+//! The node proposing a command stamps it from its local clock, which never runs
+//! behind the timestamps it has seen applied. The engine moves its own, replicated
+//! clock to the stamp of every command, or one tick past its last time if the
+//! stamp is older, e.g. when a new leader's wall clock lags the previous one. Engine
+//! time is therefore monotonic and identical on every replica.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bits of a timestamp used by the logical counter
+const LOGICAL_BITS: u32 = 16;
+
+/// Latest timestamp issued or observed by this node
+static LOCAL: AtomicU64 = AtomicU64::new(0);
+
+/// Hybrid logical clock timestamp
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Default)]
+pub struct Hlc(pub u64);
+
+impl Hlc {
+    /// Creates the first timestamp of a wall clock millisecond
+    ///
+    /// # Arguments
+    /// * `millis` - Wall clock in milliseconds since the epoch
+    pub fn from_millis(millis: u64) -> Self {
+        Hlc(millis << LOGICAL_BITS)
+    }
+
+    /// Returns the wall clock part in milliseconds since the epoch
+    pub fn millis(self) -> u64 {
+        self.0 >> LOGICAL_BITS
+    }
+
+    /// Returns the logical counter within the millisecond
+    pub fn logical(self) -> u64 {
+        self.0 & ((1 << LOGICAL_BITS) - 1)
+    }
+
+    /// Moves the clock to a received timestamp
+    ///
+    /// # Arguments
+    /// * `stamp` - Timestamp carried by the command
+    ///
+    /// # Returns
+    /// The stamp if it is ahead of the clock, otherwise the next tick of the clock
+    pub fn advance(self, stamp: Hlc) -> Hlc {
+        if stamp > self {
+            stamp
+        } else {
+            Hlc(self.0 + 1)
+        }
+    }
+}
+
+/// Returns the current wall clock as timestamp
+fn wall_clock() -> Hlc {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    Hlc::from_millis(millis)
+}
+
+/// Issues a timestamp for a command proposed by this node
+///
+/// # Returns
+/// A timestamp after every timestamp issued or observed before
+pub fn now() -> Hlc {
+    let wall = wall_clock();
+    let previous = LOCAL
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| {
+            Some(Hlc(last).advance(wall).0)
+        })
+        .unwrap_or_default();
+    Hlc(previous).advance(wall)
+}
+
+/// Records a timestamp applied by the engine, later timestamps issued by this
+/// node will follow it even if the local wall clock is behind
+///
+/// # Arguments
+/// * `stamp` - Engine time after the last applied command
+pub fn observe(stamp: Hlc) {
+    LOCAL.fetch_max(stamp.0, Ordering::AcqRel);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn engine_time_never_goes_back() {
+        let clock = Hlc::from_millis(5_000);
+        // A stamp of a leader whose wall clock lags only ticks the logical counter
+        let next = clock.advance(Hlc::from_millis(4_000));
+        assert_eq!((next.millis(), next.logical()), (5_000, 1));
+        let ahead = next.advance(Hlc::from_millis(6_000));
+        assert_eq!((ahead.millis(), ahead.logical()), (6_000, 0));
+        // The counter carries into the next millisecond
+        let full = Hlc(Hlc::from_millis(7_000).0 | 0xffff);
+        assert_eq!(full.advance(Hlc::default()).millis(), 7_001);
+
+        observe(Hlc::from_millis(u64::MAX >> LOGICAL_BITS >> 1));
+        let issued = now();
+        assert!(issued > Hlc::from_millis(u64::MAX >> LOGICAL_BITS >> 1));
+        assert!(now() > issued);
+    }
+}
@@ -0,0 +1,1152 @@
+//! Command Codec Module
+//!
+//! This module encodes and decodes `CommandEnvelope` for the raft log. Commands are
+//! written as protobuf (see `proto/command.proto`) behind a short header, so the log
+//! format stays decodable as the Rust types evolve. Entries written before the protobuf
+//! format existed are bincode dumps and are still accepted through a frozen legacy decoder.
+//!
+//! Format versions:
+//! - 1: bare `MatchCmd`
+//! - 2: `CommandEnvelope` wrapping the command
+//!
+//! Within a format version, commands that rely on behavior older builds lack list
+//! the required features in their envelope. Builds refuse to decode commands with
+//! features they do not know, and leaders only propose such commands once every
+//! member supports them (see `crate::version`), so mixed-version clusters never
+//! apply a command differently on different nodes.
+
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+
+use crate::engine::entry::order::OrderStatus;
+use crate::engine::entry::{Order, OrderSide, OrderType, Symbol, SymbolStatus, TimeInForce};
+use crate::engine::matchengine::{
+    CommandEnvelope, MarkPrice, MarketType, MatchCmd, MatchCmdType, RiskLimits, Transfer,
+};
+use prost::Message;
+
+/// Protocol buffer definitions for replicated commands
+#[allow(clippy::module_inception)]
+pub mod pb {
+    tonic::include_proto!("command");
+}
+
+/// First byte of every protobuf encoded command
+///
+/// Legacy bincode commands start with the little-endian command type index,
+/// which is always below this value.
+const CMD_MAGIC: u8 = 0xC0;
+/// Protobuf format carrying a bare command
+const CMD_FORMAT_BARE: u8 = 1;
+/// Current protobuf command format version
+const CMD_FORMAT_VERSION: u8 = 2;
+
+/// Command features this build can apply, newer builds append to the list
+pub const SUPPORTED_FEATURES: &[&str] = &[
+    FEATURE_BALANCES,
+    FEATURE_FEES,
+    FEATURE_FUNDING,
+    FEATURE_RISK_LIMITS,
+    FEATURE_PERP,
+    FEATURE_HLC,
+    FEATURE_CLIENT_ORDER_IDS,
+    FEATURE_FEE_CURRENCY,
+    FEATURE_TIMERS,
+    FEATURE_TIME_IN_FORCE,
+    FEATURE_FILL_OR_KILL,
+    FEATURE_POST_ONLY,
+    FEATURE_STOP_ORDERS,
+    FEATURE_ICEBERG,
+    FEATURE_TRAILING_STOPS,
+    FEATURE_GOOD_TILL_DATE,
+    FEATURE_AMEND_ORDERS,
+    FEATURE_BATCHES,
+    FEATURE_CANCEL_ALL,
+    FEATURE_REPLACE_ORDERS,
+    FEATURE_PRICE_BANDS,
+    FEATURE_CIRCUIT_BREAKERS,
+    FEATURE_SYMBOL_ADMIN,
+];
+
+/// Balance commands and symbols that settle balances, see `engine::ledger`
+const FEATURE_BALANCES: &str = "balances";
+/// Orders charging fees when their trades are settled
+const FEATURE_FEES: &str = "fees";
+/// Balance adjustments and transfers carrying an idempotency key, see `engine::funding`
+const FEATURE_FUNDING: &str = "funding";
+/// Per-account credit lines and trading switches, see `Ledger::set_risk_limits`
+const FEATURE_RISK_LIMITS: &str = "risk_limits";
+/// Commands addressing perpetual contracts, see `engine::perp`
+const FEATURE_PERP: &str = "perp";
+/// Commands timed by a hybrid logical clock timestamp, see `engine::clock`
+pub const FEATURE_HLC: &str = "hlc";
+/// Orders placed, canceled or looked up by client order ID, see `engine::client_orders`
+const FEATURE_CLIENT_ORDER_IDS: &str = "client_order_ids";
+/// Symbols paying fees in a designated currency, see `Ledger::settle`
+const FEATURE_FEE_CURRENCY: &str = "fee_currency";
+/// Tick commands firing replicated timers, see `engine::timers`
+pub const FEATURE_TIMERS: &str = "timers";
+/// Orders whose unfilled remainder does not rest in the book, see `Matcher::place_order`
+const FEATURE_TIME_IN_FORCE: &str = "time_in_force";
+/// Orders filled completely or rejected, see `Matcher::can_fill`
+const FEATURE_FILL_OR_KILL: &str = "fill_or_kill";
+/// Limit orders rejected if they would take liquidity, see `Matcher::would_take`
+const FEATURE_POST_ONLY: &str = "post_only";
+/// Orders held off the book until the last trade price reaches their stop
+/// price, see `engine::spot::trigger_manager`
+const FEATURE_STOP_ORDERS: &str = "stop_orders";
+/// Orders showing only a slice of their quantity in the book, see `Order::visible_quantity`
+const FEATURE_ICEBERG: &str = "iceberg";
+/// Stop orders whose stop price follows the last trade price, see `StopOrder::trail`
+const FEATURE_TRAILING_STOPS: &str = "trailing_stops";
+/// Orders canceled by a timer at their expiry time, see `MatchEngine::apply`
+const FEATURE_GOOD_TILL_DATE: &str = "good_till_date";
+/// Commands repricing or resizing a resting order, see `Matcher::amend_order`
+const FEATURE_AMEND_ORDERS: &str = "amend_orders";
+/// Commands placing several orders at one raft index, see `MatchCmd::orders`
+const FEATURE_BATCHES: &str = "batches";
+/// Commands canceling every open order of an account, see `Matcher::cancel_account_orders`
+const FEATURE_CANCEL_ALL: &str = "cancel_all";
+/// Commands canceling an order and placing its replacement at one raft index, see
+/// `MatchCmdType::ReplaceOrder`
+const FEATURE_REPLACE_ORDERS: &str = "replace_orders";
+/// Symbols rejecting limit prices far from the reference price, and the symbol
+/// updates changing them, see `Symbol::price_in_band`
+const FEATURE_PRICE_BANDS: &str = "price_bands";
+/// Symbols halting when their trade price moves too far, see `engine::circuit_breaker`
+const FEATURE_CIRCUIT_BREAKERS: &str = "circuit_breakers";
+/// Commands halting or resuming a symbol or canceling its whole book, see
+/// `MatchCmdType::HaltSymbol`
+const FEATURE_SYMBOL_ADMIN: &str = "symbol_admin";
+
+/// Lists the features beyond the base format a command relies on
+///
+/// # Arguments
+/// * `envelope` - The command to inspect
+///
+/// # Returns
+/// Names of the required features, all of them in `SUPPORTED_FEATURES`
+pub fn required_features(envelope: &CommandEnvelope) -> Vec<&'static str> {
+    let cmd = &envelope.cmd;
+    let mut features = Vec::new();
+    let settles = cmd.symbol.as_ref().is_some_and(|s| s.settle_balances);
+    let funding = matches!(
+        cmd.cmd,
+        MatchCmdType::Deposit | MatchCmdType::Withdraw | MatchCmdType::Adjust
+    );
+    if funding || settles {
+        features.push(FEATURE_BALANCES);
+    }
+    let keyed = cmd
+        .transfer
+        .as_ref()
+        .is_some_and(|t| !t.idempotency_key.is_empty());
+    if matches!(cmd.cmd, MatchCmdType::Adjust) || keyed {
+        features.push(FEATURE_FUNDING);
+    }
+    if matches!(cmd.cmd, MatchCmdType::SetRiskLimits) {
+        features.push(FEATURE_RISK_LIMITS);
+    }
+    let orders: Vec<&Order> = cmd.order.iter().chain(&cmd.orders).collect();
+    if orders
+        .iter()
+        .any(|o| !o.taker_fee.is_zero() || !o.maker_fee.is_zero())
+    {
+        features.push(FEATURE_FEES);
+    }
+    let contract = matches!(
+        cmd.cmd,
+        MatchCmdType::SetMarkPrice | MatchCmdType::Liquidate
+    );
+    if cmd.market == MarketType::Perp || contract {
+        features.push(FEATURE_PERP);
+    }
+    if envelope.hlc > 0 {
+        features.push(FEATURE_HLC);
+    }
+    if orders.iter().any(|o| !o.client_order_id.is_empty()) {
+        features.push(FEATURE_CLIENT_ORDER_IDS);
+    }
+    if cmd
+        .symbol
+        .as_ref()
+        .is_some_and(|s| !s.fee_currency.is_empty())
+    {
+        features.push(FEATURE_FEE_CURRENCY);
+    }
+    if matches!(cmd.cmd, MatchCmdType::Tick) {
+        features.push(FEATURE_TIMERS);
+    }
+    for (time_in_force, feature) in [
+        (TimeInForce::Ioc, FEATURE_TIME_IN_FORCE),
+        (TimeInForce::Fok, FEATURE_FILL_OR_KILL),
+        (TimeInForce::Gtd, FEATURE_GOOD_TILL_DATE),
+    ] {
+        if orders.iter().any(|o| o.time_in_force == time_in_force) {
+            features.push(feature);
+        }
+    }
+    if orders.iter().any(|o| o.order_type == OrderType::LimitMaker) {
+        features.push(FEATURE_POST_ONLY);
+    }
+    if orders.iter().any(|o| o.is_stop()) {
+        features.push(FEATURE_STOP_ORDERS);
+    }
+    if orders.iter().any(|o| o.is_iceberg()) {
+        features.push(FEATURE_ICEBERG);
+    }
+    if orders.iter().any(|o| o.is_trailing()) {
+        features.push(FEATURE_TRAILING_STOPS);
+    }
+    if matches!(cmd.cmd, MatchCmdType::AmendOrder) {
+        features.push(FEATURE_AMEND_ORDERS);
+    }
+    if matches!(cmd.cmd, MatchCmdType::Batch) {
+        features.push(FEATURE_BATCHES);
+    }
+    if matches!(cmd.cmd, MatchCmdType::CancelAllOrders) {
+        features.push(FEATURE_CANCEL_ALL);
+    }
+    if matches!(cmd.cmd, MatchCmdType::ReplaceOrder) {
+        features.push(FEATURE_REPLACE_ORDERS);
+    }
+    if matches!(cmd.cmd, MatchCmdType::UpdateSymbol)
+        || cmd.symbol.as_ref().is_some_and(|s| !s.price_band.is_zero())
+    {
+        features.push(FEATURE_PRICE_BANDS);
+    }
+    if cmd
+        .symbol
+        .as_ref()
+        .is_some_and(|s| !s.circuit_breaker.is_zero())
+    {
+        features.push(FEATURE_CIRCUIT_BREAKERS);
+    }
+    if matches!(
+        cmd.cmd,
+        MatchCmdType::HaltSymbol | MatchCmdType::ResumeSymbol | MatchCmdType::MassCancel
+    ) {
+        features.push(FEATURE_SYMBOL_ADMIN);
+    }
+    features
+}
+
+/// Encodes a command envelope for the raft log
+///
+/// # Arguments
+/// * `envelope` - Command envelope to encode
+///
+/// # Returns
+/// Header followed by the protobuf encoded envelope
+pub fn encode(envelope: &CommandEnvelope) -> Vec<u8> {
+    let msg = pb::CommandEnvelope::from(envelope);
+    let mut data = Vec::with_capacity(2 + msg.encoded_len());
+    data.push(CMD_MAGIC);
+    data.push(CMD_FORMAT_VERSION);
+    msg.encode(&mut data)
+        .expect("encoding into a Vec cannot fail");
+    data
+}
+
+/// Decodes a command envelope read from the raft log
+///
+/// Commands written without an envelope are wrapped into an empty one.
+///
+/// # Arguments
+/// * `data` - Raw entry data, either protobuf with header or legacy bincode
+///
+/// # Returns
+/// * `Ok(CommandEnvelope)` - The decoded command envelope
+/// * `Err(String)` - Error message if the data is not a valid command
+pub fn decode(data: &[u8]) -> Result<CommandEnvelope, String> {
+    let envelope = match data {
+        [CMD_MAGIC, CMD_FORMAT_VERSION, payload @ ..] => {
+            let msg = pb::CommandEnvelope::decode(payload)
+                .map_err(|e| format!("invalid protobuf command: {}", e))?;
+            let unsupported = unsupported_features(&msg.features);
+            if !unsupported.is_empty() {
+                return Err(format!(
+                    "command requires unsupported features {:?}, upgrade this node",
+                    unsupported
+                ));
+            }
+            CommandEnvelope::try_from(msg)
+        }
+        [CMD_MAGIC, CMD_FORMAT_BARE, payload @ ..] => {
+            let msg = pb::MatchCmd::decode(payload)
+                .map_err(|e| format!("invalid protobuf command: {}", e))?;
+            Ok(CommandEnvelope {
+                cmd: MatchCmd::try_from(msg)?,
+                ..Default::default()
+            })
+        }
+        [CMD_MAGIC, version, ..] => Err(format!("unsupported command format {}", version)),
+        _ => Ok(CommandEnvelope {
+            cmd: legacy::decode(data)?,
+            ..Default::default()
+        }),
+    }?;
+    check_payload(&envelope.cmd)?;
+    Ok(envelope)
+}
+
+/// Tells whether a command `decode` refused needs a newer build
+///
+/// A newer build applies such a command, so a node must not skip it the way it
+/// skips commands that are malformed for every build.
+///
+/// # Arguments
+/// * `data` - Raw entry data
+///
+/// # Returns
+/// True if the command has a format version or requires features this build
+/// does not support
+pub fn needs_upgrade(data: &[u8]) -> bool {
+    match data {
+        [CMD_MAGIC, CMD_FORMAT_VERSION, payload @ ..] => pb::CommandEnvelope::decode(payload)
+            .is_ok_and(|msg| !unsupported_features(&msg.features).is_empty()),
+        [CMD_MAGIC, CMD_FORMAT_BARE, ..] => false,
+        [CMD_MAGIC, ..] => true,
+        _ => false,
+    }
+}
+
+/// Returns the features of a command this build does not support
+fn unsupported_features(features: &[String]) -> Vec<&String> {
+    features
+        .iter()
+        .filter(|feature| !SUPPORTED_FEATURES.contains(&feature.as_str()))
+        .collect()
+}
+
+/// Checks that a command carries the order, symbol or transfer its type operates on
+fn check_payload(cmd: &MatchCmd) -> Result<(), String> {
+    let complete = match cmd.cmd {
+        MatchCmdType::PlaceOrder
+        | MatchCmdType::CancelOrder
+        | MatchCmdType::AmendOrder
+        | MatchCmdType::Liquidate => cmd.order.is_some(),
+        MatchCmdType::CreateSymbol
+        | MatchCmdType::UpdateSymbol
+        | MatchCmdType::RemoveSymbol
+        | MatchCmdType::HaltSymbol
+        | MatchCmdType::ResumeSymbol
+        | MatchCmdType::MassCancel => cmd.symbol.is_some(),
+        MatchCmdType::Deposit | MatchCmdType::Withdraw | MatchCmdType::Adjust => {
+            cmd.transfer.is_some()
+        }
+        MatchCmdType::SetRiskLimits => cmd.risk.is_some(),
+        MatchCmdType::SetMarkPrice => cmd.mark_price.is_some(),
+        MatchCmdType::Batch => !cmd.orders.is_empty(),
+        MatchCmdType::ReplaceOrder => cmd.order.is_some() && cmd.orders.len() == 1,
+        MatchCmdType::CancelAllOrders | MatchCmdType::Tick => true,
+    };
+    if complete {
+        Ok(())
+    } else {
+        Err(format!("{:?} command without payload", cmd.cmd))
+    }
+}
+
+/// Parses a decimal field of a protobuf command
+fn parse_decimal(field: &str, value: &str) -> Result<Decimal, String> {
+    if value.is_empty() {
+        return Ok(Decimal::ZERO);
+    }
+    Decimal::from_str(value).map_err(|e| format!("invalid {} {:?}: {}", field, value, e))
+}
+
+impl From<&CommandEnvelope> for pb::CommandEnvelope {
+    fn from(envelope: &CommandEnvelope) -> Self {
+        pb::CommandEnvelope {
+            request_id: envelope.request_id.clone(),
+            client_id: envelope.client_id.clone(),
+            account_id: envelope.account_id,
+            proposed_at: envelope.proposed_at,
+            hlc: envelope.hlc,
+            cmd: Some(pb::MatchCmd::from(&envelope.cmd)),
+            features: required_features(envelope)
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<pb::CommandEnvelope> for CommandEnvelope {
+    type Error = String;
+
+    fn try_from(msg: pb::CommandEnvelope) -> Result<Self, Self::Error> {
+        let cmd = msg.cmd.ok_or("command envelope without command")?;
+        Ok(CommandEnvelope {
+            request_id: msg.request_id,
+            client_id: msg.client_id,
+            account_id: msg.account_id,
+            proposed_at: msg.proposed_at,
+            hlc: msg.hlc,
+            cmd: MatchCmd::try_from(cmd)?,
+        })
+    }
+}
+
+impl From<&MatchCmd> for pb::MatchCmd {
+    fn from(cmd: &MatchCmd) -> Self {
+        let cmd_type = match cmd.cmd {
+            MatchCmdType::PlaceOrder => pb::MatchCmdType::PlaceOrder,
+            MatchCmdType::CancelOrder => pb::MatchCmdType::CancelOrder,
+            MatchCmdType::CreateSymbol => pb::MatchCmdType::CreateSymbol,
+            MatchCmdType::UpdateSymbol => pb::MatchCmdType::UpdateSymbol,
+            MatchCmdType::RemoveSymbol => pb::MatchCmdType::RemoveSymbol,
+            MatchCmdType::Deposit => pb::MatchCmdType::Deposit,
+            MatchCmdType::Withdraw => pb::MatchCmdType::Withdraw,
+            MatchCmdType::Adjust => pb::MatchCmdType::Adjust,
+            MatchCmdType::SetRiskLimits => pb::MatchCmdType::SetRiskLimits,
+            MatchCmdType::SetMarkPrice => pb::MatchCmdType::SetMarkPrice,
+            MatchCmdType::Liquidate => pb::MatchCmdType::Liquidate,
+            MatchCmdType::Tick => pb::MatchCmdType::Tick,
+            MatchCmdType::AmendOrder => pb::MatchCmdType::AmendOrder,
+            MatchCmdType::Batch => pb::MatchCmdType::Batch,
+            MatchCmdType::CancelAllOrders => pb::MatchCmdType::CancelAllOrders,
+            MatchCmdType::ReplaceOrder => pb::MatchCmdType::ReplaceOrder,
+            MatchCmdType::HaltSymbol => pb::MatchCmdType::HaltSymbol,
+            MatchCmdType::ResumeSymbol => pb::MatchCmdType::ResumeSymbol,
+            MatchCmdType::MassCancel => pb::MatchCmdType::MassCancel,
+        };
+        let market = match cmd.market {
+            MarketType::Spot => pb::MarketType::Spot,
+            MarketType::Perp => pb::MarketType::Perp,
+        };
+        pb::MatchCmd {
+            cmd: cmd_type as i32,
+            tenant: cmd.tenant.clone(),
+            order: cmd.order.as_ref().map(pb::Order::from),
+            symbol: cmd.symbol.as_ref().map(pb::Symbol::from),
+            transfer: cmd.transfer.as_ref().map(pb::Transfer::from),
+            risk: cmd.risk.as_ref().map(pb::RiskLimits::from),
+            market: market as i32,
+            mark_price: cmd.mark_price.as_ref().map(|mark| pb::MarkPrice {
+                symbol: mark.symbol.clone(),
+                price: mark.price.to_string(),
+            }),
+            orders: cmd.orders.iter().map(pb::Order::from).collect(),
+        }
+    }
+}
+
+impl TryFrom<pb::MatchCmd> for MatchCmd {
+    type Error = String;
+
+    fn try_from(msg: pb::MatchCmd) -> Result<Self, Self::Error> {
+        let cmd = match pb::MatchCmdType::from_i32(msg.cmd) {
+            Some(pb::MatchCmdType::PlaceOrder) => MatchCmdType::PlaceOrder,
+            Some(pb::MatchCmdType::CancelOrder) => MatchCmdType::CancelOrder,
+            Some(pb::MatchCmdType::CreateSymbol) => MatchCmdType::CreateSymbol,
+            Some(pb::MatchCmdType::UpdateSymbol) => MatchCmdType::UpdateSymbol,
+            Some(pb::MatchCmdType::RemoveSymbol) => MatchCmdType::RemoveSymbol,
+            Some(pb::MatchCmdType::Deposit) => MatchCmdType::Deposit,
+            Some(pb::MatchCmdType::Withdraw) => MatchCmdType::Withdraw,
+            Some(pb::MatchCmdType::Adjust) => MatchCmdType::Adjust,
+            Some(pb::MatchCmdType::SetRiskLimits) => MatchCmdType::SetRiskLimits,
+            Some(pb::MatchCmdType::SetMarkPrice) => MatchCmdType::SetMarkPrice,
+            Some(pb::MatchCmdType::Liquidate) => MatchCmdType::Liquidate,
+            Some(pb::MatchCmdType::Tick) => MatchCmdType::Tick,
+            Some(pb::MatchCmdType::AmendOrder) => MatchCmdType::AmendOrder,
+            Some(pb::MatchCmdType::Batch) => MatchCmdType::Batch,
+            Some(pb::MatchCmdType::CancelAllOrders) => MatchCmdType::CancelAllOrders,
+            Some(pb::MatchCmdType::ReplaceOrder) => MatchCmdType::ReplaceOrder,
+            Some(pb::MatchCmdType::HaltSymbol) => MatchCmdType::HaltSymbol,
+            Some(pb::MatchCmdType::ResumeSymbol) => MatchCmdType::ResumeSymbol,
+            Some(pb::MatchCmdType::MassCancel) => MatchCmdType::MassCancel,
+            None => return Err(format!("unknown command type {}", msg.cmd)),
+        };
+        let market = match pb::MarketType::from_i32(msg.market) {
+            Some(pb::MarketType::Spot) => MarketType::Spot,
+            Some(pb::MarketType::Perp) => MarketType::Perp,
+            None => return Err(format!("unknown market type {}", msg.market)),
+        };
+        let mark_price = match msg.mark_price {
+            Some(mark) => Some(MarkPrice {
+                price: parse_decimal("mark price", &mark.price)?,
+                symbol: mark.symbol,
+            }),
+            None => None,
+        };
+        Ok(MatchCmd {
+            cmd,
+            tenant: msg.tenant,
+            order: msg.order.map(Order::try_from).transpose()?,
+            symbol: msg.symbol.map(Symbol::try_from).transpose()?,
+            transfer: msg.transfer.map(Transfer::try_from).transpose()?,
+            risk: msg.risk.map(RiskLimits::try_from).transpose()?,
+            market,
+            mark_price,
+            orders: msg
+                .orders
+                .into_iter()
+                .map(Order::try_from)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl From<&Order> for pb::Order {
+    fn from(order: &Order) -> Self {
+        let order_type = match order.order_type {
+            OrderType::Market => pb::OrderType::Market,
+            OrderType::Limit => pb::OrderType::Limit,
+            OrderType::LimitMaker => pb::OrderType::LimitMaker,
+            OrderType::Stop => pb::OrderType::Stop,
+            OrderType::StopLimit => pb::OrderType::StopLimit,
+        };
+        let side = match order.side {
+            OrderSide::Buy => pb::OrderSide::Buy,
+            OrderSide::Sell => pb::OrderSide::Sell,
+        };
+        let status = match order.status {
+            OrderStatus::New => pb::OrderStatus::New,
+            OrderStatus::PartiallyFilled => pb::OrderStatus::PartiallyFilled,
+            OrderStatus::Filled => pb::OrderStatus::Filled,
+            OrderStatus::Canceled => pb::OrderStatus::Canceled,
+            OrderStatus::Rejected => pb::OrderStatus::Rejected,
+        };
+        let time_in_force = match order.time_in_force {
+            TimeInForce::Gtc => pb::TimeInForce::Gtc,
+            TimeInForce::Ioc => pb::TimeInForce::Ioc,
+            TimeInForce::Fok => pb::TimeInForce::Fok,
+            TimeInForce::Gtd => pb::TimeInForce::Gtd,
+        };
+        pb::Order {
+            id: order.id.clone(),
+            symbol: order.symbol.clone(),
+            order_type: order_type as i32,
+            side: side as i32,
+            price: order.price.to_string(),
+            quantity: order.quantity.to_string(),
+            filled_quantity: order.filled_quantity.to_string(),
+            status: status as i32,
+            created_at: order.created_at,
+            updated_at: order.updated_at,
+            taker_fee: order.taker_fee.to_string(),
+            maker_fee: order.maker_fee.to_string(),
+            leverage: order.leverage.to_string(),
+            client_order_id: order.client_order_id.clone(),
+            time_in_force: time_in_force as i32,
+            stop_price: order.stop_price.to_string(),
+            display_quantity: order.display_quantity.to_string(),
+            trailing_offset: order.trailing_offset.to_string(),
+            expire_at: order.expire_at,
+        }
+    }
+}
+
+impl TryFrom<pb::Order> for Order {
+    type Error = String;
+
+    fn try_from(msg: pb::Order) -> Result<Self, Self::Error> {
+        let order_type = match pb::OrderType::from_i32(msg.order_type) {
+            Some(pb::OrderType::Market) => OrderType::Market,
+            Some(pb::OrderType::Limit) => OrderType::Limit,
+            Some(pb::OrderType::LimitMaker) => OrderType::LimitMaker,
+            Some(pb::OrderType::Stop) => OrderType::Stop,
+            Some(pb::OrderType::StopLimit) => OrderType::StopLimit,
+            None => return Err(format!("unknown order type {}", msg.order_type)),
+        };
+        let side = match pb::OrderSide::from_i32(msg.side) {
+            Some(pb::OrderSide::Buy) => OrderSide::Buy,
+            Some(pb::OrderSide::Sell) => OrderSide::Sell,
+            None => return Err(format!("unknown order side {}", msg.side)),
+        };
+        let status = match pb::OrderStatus::from_i32(msg.status) {
+            Some(pb::OrderStatus::New) => OrderStatus::New,
+            Some(pb::OrderStatus::PartiallyFilled) => OrderStatus::PartiallyFilled,
+            Some(pb::OrderStatus::Filled) => OrderStatus::Filled,
+            Some(pb::OrderStatus::Canceled) => OrderStatus::Canceled,
+            Some(pb::OrderStatus::Rejected) => OrderStatus::Rejected,
+            None => return Err(format!("unknown order status {}", msg.status)),
+        };
+        let time_in_force = match pb::TimeInForce::from_i32(msg.time_in_force) {
+            Some(pb::TimeInForce::Gtc) => TimeInForce::Gtc,
+            Some(pb::TimeInForce::Ioc) => TimeInForce::Ioc,
+            Some(pb::TimeInForce::Fok) => TimeInForce::Fok,
+            Some(pb::TimeInForce::Gtd) => TimeInForce::Gtd,
+            None => return Err(format!("unknown time in force {}", msg.time_in_force)),
+        };
+        Ok(Order {
+            id: msg.id,
+            symbol: msg.symbol,
+            order_type,
+            side,
+            price: parse_decimal("price", &msg.price)?,
+            quantity: parse_decimal("quantity", &msg.quantity)?,
+            filled_quantity: parse_decimal("filled quantity", &msg.filled_quantity)?,
+            status,
+            created_at: msg.created_at,
+            updated_at: msg.updated_at,
+            taker_fee: parse_decimal("taker fee", &msg.taker_fee)?,
+            maker_fee: parse_decimal("maker fee", &msg.maker_fee)?,
+            leverage: parse_decimal("leverage", &msg.leverage)?,
+            client_order_id: msg.client_order_id,
+            time_in_force,
+            stop_price: parse_decimal("stop price", &msg.stop_price)?,
+            display_quantity: parse_decimal("display quantity", &msg.display_quantity)?,
+            trailing_offset: parse_decimal("trailing offset", &msg.trailing_offset)?,
+            expire_at: msg.expire_at,
+            // Taken from the envelope when the order is applied
+            account_id: 0,
+        })
+    }
+}
+
+impl From<&Symbol> for pb::Symbol {
+    fn from(symbol: &Symbol) -> Self {
+        let status = match symbol.status {
+            SymbolStatus::Active => pb::SymbolStatus::Active,
+            SymbolStatus::Inactive => pb::SymbolStatus::Inactive,
+            SymbolStatus::Delisted => pb::SymbolStatus::Delisted,
+            SymbolStatus::Halted => pb::SymbolStatus::Halted,
+        };
+        pb::Symbol {
+            name: symbol.name.clone(),
+            tenant: symbol.tenant.clone(),
+            base_currency: symbol.base_currency.clone(),
+            quote_currency: symbol.quote_currency.clone(),
+            price_precision: symbol.price_precision,
+            quantity_precision: symbol.quantity_precision,
+            min_price: symbol.min_price.to_string(),
+            max_price: symbol.max_price.to_string(),
+            min_quantity: symbol.min_quantity.to_string(),
+            max_quantity: symbol.max_quantity.to_string(),
+            status: status as i32,
+            created_at: symbol.created_at,
+            updated_at: symbol.updated_at,
+            settle_balances: symbol.settle_balances,
+            max_leverage: symbol.max_leverage.to_string(),
+            maintenance_margin: symbol.maintenance_margin.to_string(),
+            fee_currency: symbol.fee_currency.clone(),
+            price_band: symbol.price_band.to_string(),
+            circuit_breaker: symbol.circuit_breaker.to_string(),
+            circuit_window_ms: symbol.circuit_window_ms,
+            halt_ms: symbol.halt_ms,
+            cancel_on_halt: symbol.cancel_on_halt,
+        }
+    }
+}
+
+impl TryFrom<pb::Symbol> for Symbol {
+    type Error = String;
+
+    fn try_from(msg: pb::Symbol) -> Result<Self, Self::Error> {
+        let status = match pb::SymbolStatus::from_i32(msg.status) {
+            Some(pb::SymbolStatus::Active) => SymbolStatus::Active,
+            Some(pb::SymbolStatus::Inactive) => SymbolStatus::Inactive,
+            Some(pb::SymbolStatus::Delisted) => SymbolStatus::Delisted,
+            Some(pb::SymbolStatus::Halted) => SymbolStatus::Halted,
+            None => return Err(format!("unknown symbol status {}", msg.status)),
+        };
+        Ok(Symbol {
+            name: msg.name,
+            tenant: msg.tenant,
+            base_currency: msg.base_currency,
+            quote_currency: msg.quote_currency,
+            price_precision: msg.price_precision,
+            quantity_precision: msg.quantity_precision,
+            min_price: parse_decimal("min price", &msg.min_price)?,
+            max_price: parse_decimal("max price", &msg.max_price)?,
+            min_quantity: parse_decimal("min quantity", &msg.min_quantity)?,
+            max_quantity: parse_decimal("max quantity", &msg.max_quantity)?,
+            status,
+            created_at: msg.created_at,
+            updated_at: msg.updated_at,
+            settle_balances: msg.settle_balances,
+            max_leverage: parse_decimal("max leverage", &msg.max_leverage)?,
+            maintenance_margin: parse_decimal("maintenance margin", &msg.maintenance_margin)?,
+            fee_currency: msg.fee_currency,
+            price_band: parse_decimal("price band", &msg.price_band)?,
+            circuit_breaker: parse_decimal("circuit breaker", &msg.circuit_breaker)?,
+            circuit_window_ms: msg.circuit_window_ms,
+            halt_ms: msg.halt_ms,
+            cancel_on_halt: msg.cancel_on_halt,
+        })
+    }
+}
+
+impl From<&Transfer> for pb::Transfer {
+    fn from(transfer: &Transfer) -> Self {
+        pb::Transfer {
+            currency: transfer.currency.clone(),
+            amount: transfer.amount.to_string(),
+            idempotency_key: transfer.idempotency_key.clone(),
+            reason: transfer.reason.clone(),
+        }
+    }
+}
+
+impl TryFrom<pb::Transfer> for Transfer {
+    type Error = String;
+
+    fn try_from(msg: pb::Transfer) -> Result<Self, Self::Error> {
+        Ok(Transfer {
+            currency: msg.currency,
+            amount: parse_decimal("amount", &msg.amount)?,
+            idempotency_key: msg.idempotency_key,
+            reason: msg.reason,
+        })
+    }
+}
+
+impl From<&RiskLimits> for pb::RiskLimits {
+    fn from(limits: &RiskLimits) -> Self {
+        pb::RiskLimits {
+            credit: limits
+                .credit
+                .iter()
+                .map(|(currency, amount)| pb::CreditLine {
+                    currency: currency.clone(),
+                    amount: amount.to_string(),
+                })
+                .collect(),
+            trading_disabled: limits.trading_disabled,
+            orders_per_second: limits.orders_per_second.unwrap_or_default(),
+            order_burst: limits.order_burst,
+        }
+    }
+}
+
+impl TryFrom<pb::RiskLimits> for RiskLimits {
+    type Error = String;
+
+    fn try_from(msg: pb::RiskLimits) -> Result<Self, Self::Error> {
+        Ok(RiskLimits {
+            credit: msg
+                .credit
+                .into_iter()
+                .map(|line| Ok((line.currency, parse_decimal("credit", &line.amount)?)))
+                .collect::<Result<_, String>>()?,
+            trading_disabled: msg.trading_disabled,
+            orders_per_second: (msg.orders_per_second > 0).then_some(msg.orders_per_second),
+            order_burst: msg.order_burst,
+        })
+    }
+}
+
+/// Decoder for commands written with bincode before the protobuf format
+///
+/// The types here are frozen copies of the original layout and must never change,
+/// otherwise old log entries become undecodable.
+mod legacy {
+    use rust_decimal::Decimal;
+    use serde::Deserialize;
+
+    use crate::engine::entry::order::OrderStatus;
+    use crate::engine::entry::{OrderSide, OrderType, SymbolStatus};
+
+    #[derive(Deserialize)]
+    enum MatchCmdType {
+        PlaceOrder,
+        CancelOrder,
+        CreateSymbol,
+        UpdateSymbol,
+        RemoveSymbol,
+    }
+
+    #[derive(Deserialize)]
+    struct MatchCmd {
+        cmd: MatchCmdType,
+        order: Option<Order>,
+        symbol: Option<Symbol>,
+    }
+
+    #[derive(Deserialize)]
+    struct Order {
+        id: String,
+        symbol: String,
+        order_type: OrderType,
+        side: OrderSide,
+        price: Decimal,
+        quantity: Decimal,
+        filled_quantity: Decimal,
+        status: OrderStatus,
+        created_at: u64,
+        updated_at: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct Symbol {
+        name: String,
+        base_currency: String,
+        quote_currency: String,
+        price_precision: i32,
+        quantity_precision: i32,
+        min_price: Decimal,
+        max_price: Decimal,
+        min_quantity: Decimal,
+        max_quantity: Decimal,
+        status: SymbolStatus,
+        created_at: u64,
+        updated_at: u64,
+    }
+
+    /// Decodes a legacy bincode command into the current command type
+    pub fn decode(data: &[u8]) -> Result<super::MatchCmd, String> {
+        let cmd: MatchCmd =
+            bincode::deserialize(data).map_err(|e| format!("invalid legacy command: {}", e))?;
+        let cmd_type = match cmd.cmd {
+            MatchCmdType::PlaceOrder => super::MatchCmdType::PlaceOrder,
+            MatchCmdType::CancelOrder => super::MatchCmdType::CancelOrder,
+            MatchCmdType::CreateSymbol => super::MatchCmdType::CreateSymbol,
+            MatchCmdType::UpdateSymbol => super::MatchCmdType::UpdateSymbol,
+            MatchCmdType::RemoveSymbol => super::MatchCmdType::RemoveSymbol,
+        };
+        Ok(super::MatchCmd {
+            cmd: cmd_type,
+            tenant: String::new(),
+            order: cmd.order.map(|o| super::Order {
+                id: o.id,
+                symbol: o.symbol,
+                order_type: o.order_type,
+                side: o.side,
+                price: o.price,
+                quantity: o.quantity,
+                filled_quantity: o.filled_quantity,
+                status: o.status,
+                created_at: o.created_at,
+                updated_at: o.updated_at,
+                taker_fee: Decimal::ZERO,
+                maker_fee: Decimal::ZERO,
+                leverage: Decimal::ZERO,
+                client_order_id: String::new(),
+                time_in_force: super::TimeInForce::Gtc,
+                stop_price: Decimal::ZERO,
+                display_quantity: Decimal::ZERO,
+                trailing_offset: Decimal::ZERO,
+                expire_at: 0,
+                account_id: 0,
+            }),
+            symbol: cmd.symbol.map(|s| super::Symbol {
+                name: s.name,
+                tenant: String::new(),
+                base_currency: s.base_currency,
+                quote_currency: s.quote_currency,
+                price_precision: s.price_precision,
+                quantity_precision: s.quantity_precision,
+                min_price: s.min_price,
+                max_price: s.max_price,
+                min_quantity: s.min_quantity,
+                max_quantity: s.max_quantity,
+                status: s.status,
+                created_at: s.created_at,
+                updated_at: s.updated_at,
+                settle_balances: false,
+                max_leverage: Decimal::ZERO,
+                maintenance_margin: Decimal::ZERO,
+                fee_currency: String::new(),
+                price_band: Decimal::ZERO,
+                circuit_breaker: Decimal::ZERO,
+                circuit_window_ms: 0,
+                halt_ms: 0,
+                cancel_on_halt: false,
+            }),
+            transfer: None,
+            risk: None,
+            market: super::MarketType::Spot,
+            mark_price: None,
+            orders: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Reads a command as written to the raft log by an older build
+    fn read_fixture(name: &str) -> Vec<u8> {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/commands")
+            .join(name);
+        fs::read(&path).unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e))
+    }
+
+    fn order(id: &str) -> Order {
+        Order {
+            id: id.to_string(),
+            symbol: "BTCUSDT".to_string(),
+            order_type: OrderType::StopLimit,
+            side: OrderSide::Sell,
+            price: dec!(30000.50),
+            quantity: dec!(0.25),
+            filled_quantity: dec!(0.1),
+            status: OrderStatus::PartiallyFilled,
+            created_at: 1_700_000_000,
+            updated_at: 1_700_000_001,
+            taker_fee: dec!(0.001),
+            maker_fee: dec!(-0.0002),
+            leverage: dec!(5),
+            client_order_id: format!("c{}", id),
+            time_in_force: TimeInForce::Gtd,
+            stop_price: dec!(29000),
+            display_quantity: dec!(0.05),
+            trailing_offset: dec!(10),
+            expire_at: 1_700_000_060_000,
+            // Never encoded, the account is taken from the envelope
+            account_id: 0,
+        }
+    }
+
+    /// A command of the given type with every field its type may carry set
+    fn command(cmd: MatchCmdType) -> MatchCmd {
+        let symbol = Symbol {
+            name: "BTCUSDT".to_string(),
+            tenant: "t1".to_string(),
+            base_currency: "BTC".to_string(),
+            quote_currency: "USDT".to_string(),
+            price_precision: 2,
+            quantity_precision: 4,
+            min_price: dec!(0.01),
+            max_price: dec!(1000000),
+            min_quantity: dec!(0.0001),
+            max_quantity: dec!(1000),
+            status: SymbolStatus::Halted,
+            created_at: 1_700_000_000,
+            updated_at: 1_700_000_001,
+            settle_balances: true,
+            max_leverage: dec!(20),
+            maintenance_margin: dec!(0.005),
+            fee_currency: "BNB".to_string(),
+            price_band: dec!(0.05),
+            circuit_breaker: dec!(0.1),
+            circuit_window_ms: 60_000,
+            halt_ms: 300_000,
+            cancel_on_halt: true,
+        };
+        let mut msg = MatchCmd {
+            tenant: "t1".to_string(),
+            market: MarketType::Perp,
+            ..Default::default()
+        };
+        match cmd {
+            MatchCmdType::PlaceOrder
+            | MatchCmdType::CancelOrder
+            | MatchCmdType::AmendOrder
+            | MatchCmdType::Liquidate
+            | MatchCmdType::CancelAllOrders => msg.order = Some(order("1")),
+            MatchCmdType::CreateSymbol
+            | MatchCmdType::UpdateSymbol
+            | MatchCmdType::RemoveSymbol
+            | MatchCmdType::HaltSymbol
+            | MatchCmdType::ResumeSymbol
+            | MatchCmdType::MassCancel => msg.symbol = Some(symbol),
+            MatchCmdType::Deposit | MatchCmdType::Withdraw | MatchCmdType::Adjust => {
+                msg.transfer = Some(Transfer {
+                    currency: "USDT".to_string(),
+                    amount: dec!(-12.5),
+                    idempotency_key: "k1".to_string(),
+                    reason: "fee rebate".to_string(),
+                })
+            }
+            MatchCmdType::SetRiskLimits => {
+                msg.risk = Some(RiskLimits {
+                    credit: BTreeMap::from([("USDT".to_string(), dec!(1000.00))]),
+                    trading_disabled: true,
+                    orders_per_second: Some(50),
+                    order_burst: 100,
+                })
+            }
+            MatchCmdType::SetMarkPrice => {
+                msg.mark_price = Some(MarkPrice {
+                    symbol: "BTCUSDT".to_string(),
+                    price: dec!(30123.45),
+                })
+            }
+            MatchCmdType::Tick => {}
+            MatchCmdType::Batch => msg.orders = vec![order("1"), order("2")],
+            MatchCmdType::ReplaceOrder => {
+                msg.order = Some(order("1"));
+                msg.orders = vec![order("2")];
+            }
+        }
+        msg.cmd = cmd;
+        msg
+    }
+
+    #[test]
+    fn every_command_type_round_trips() {
+        let types = [
+            MatchCmdType::PlaceOrder,
+            MatchCmdType::CancelOrder,
+            MatchCmdType::CreateSymbol,
+            MatchCmdType::UpdateSymbol,
+            MatchCmdType::RemoveSymbol,
+            MatchCmdType::Deposit,
+            MatchCmdType::Withdraw,
+            MatchCmdType::Adjust,
+            MatchCmdType::SetRiskLimits,
+            MatchCmdType::SetMarkPrice,
+            MatchCmdType::Liquidate,
+            MatchCmdType::Tick,
+            MatchCmdType::AmendOrder,
+            MatchCmdType::Batch,
+            MatchCmdType::CancelAllOrders,
+            MatchCmdType::ReplaceOrder,
+            MatchCmdType::HaltSymbol,
+            MatchCmdType::ResumeSymbol,
+            MatchCmdType::MassCancel,
+        ];
+        for cmd in types {
+            let envelope = CommandEnvelope {
+                request_id: "r1".to_string(),
+                client_id: "c1".to_string(),
+                account_id: 7,
+                proposed_at: 1_700_000_000_000,
+                hlc: 1_700_000_000_000 << 16,
+                cmd: command(cmd.clone()),
+            };
+            let decoded =
+                decode(&encode(&envelope)).unwrap_or_else(|e| panic!("{:?} command: {}", cmd, e));
+            assert_eq!(
+                serde_json::to_value(&decoded).unwrap(),
+                serde_json::to_value(&envelope).unwrap(),
+                "{:?} command",
+                cmd
+            );
+        }
+    }
+
+    #[test]
+    fn bincode_commands_of_the_first_release_decode() {
+        let envelope = decode(&read_fixture("legacy_place_order.bincode")).unwrap();
+        assert!(matches!(envelope.cmd.cmd, MatchCmdType::PlaceOrder));
+        assert_eq!(envelope.request_id, "");
+        assert_eq!(envelope.cmd.tenant, "");
+        assert_eq!(envelope.cmd.market, MarketType::Spot);
+        assert!(required_features(&envelope).is_empty());
+        let order = envelope.cmd.order.unwrap();
+        assert_eq!(order.id, "42");
+        assert_eq!(order.symbol, "BTCUSDT");
+        assert_eq!(order.order_type, OrderType::Limit);
+        assert_eq!(order.side, OrderSide::Sell);
+        assert_eq!(order.price, dec!(30000.5));
+        assert_eq!(order.quantity, dec!(0.25));
+        assert_eq!(order.filled_quantity, dec!(0));
+        assert_eq!(order.status, OrderStatus::New);
+        assert_eq!(order.created_at, 1_700_000_000);
+        assert_eq!(order.time_in_force, TimeInForce::Gtc);
+
+        let envelope = decode(&read_fixture("legacy_create_symbol.bincode")).unwrap();
+        assert!(matches!(envelope.cmd.cmd, MatchCmdType::CreateSymbol));
+        assert!(envelope.cmd.order.is_none());
+        let symbol = envelope.cmd.symbol.unwrap();
+        assert_eq!(symbol.name, "BTCUSDT");
+        assert_eq!(symbol.base_currency, "BTC");
+        assert_eq!(symbol.quote_currency, "USDT");
+        assert_eq!(symbol.price_precision, 2);
+        assert_eq!(symbol.quantity_precision, 4);
+        assert_eq!(symbol.min_price, dec!(0.01));
+        assert_eq!(symbol.max_quantity, dec!(1000));
+        assert_eq!(symbol.status, SymbolStatus::Active);
+        assert!(!symbol.settle_balances);
+        assert!(symbol.circuit_breaker.is_zero());
+
+        // Truncated entries are refused rather than padded
+        let data = read_fixture("legacy_place_order.bincode");
+        assert!(decode(&data[..data.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn commands_with_unknown_features_are_refused() {
+        let envelope = CommandEnvelope {
+            request_id: "r1".to_string(),
+            cmd: MatchCmd {
+                order: Some(Order {
+                    id: "1".to_string(),
+                    symbol: "BTCUSDT".to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let data = encode(&envelope);
+        assert_eq!(decode(&data).unwrap().request_id, "r1");
+
+        // The same command as written by a newer build relying on a new feature
+        let mut msg = pb::CommandEnvelope::from(&envelope);
+        msg.features.push("from_the_future".to_string());
+        let mut data = vec![CMD_MAGIC, CMD_FORMAT_VERSION];
+        msg.encode(&mut data).unwrap();
+        let err = decode(&data).unwrap_err();
+        assert!(err.contains("from_the_future"), "{}", err);
+        assert!(needs_upgrade(&data));
+
+        // So is a newer format version, while malformed commands are refused by every build
+        assert!(needs_upgrade(&[CMD_MAGIC, CMD_FORMAT_VERSION + 1]));
+        assert!(!needs_upgrade(&[CMD_MAGIC, CMD_FORMAT_VERSION, 0xff]));
+        assert!(!needs_upgrade(&encode(&envelope)));
+    }
+
+    #[test]
+    fn batches_need_the_features_of_their_orders() {
+        let order = |id: &str, time_in_force| Order {
+            id: id.to_string(),
+            symbol: "BTCUSDT".to_string(),
+            time_in_force,
+            ..Default::default()
+        };
+        let envelope = CommandEnvelope {
+            cmd: MatchCmd {
+                cmd: MatchCmdType::Batch,
+                orders: vec![order("1", TimeInForce::Gtc), order("2", TimeInForce::Ioc)],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let features = required_features(&envelope);
+        assert!(features.contains(&FEATURE_BATCHES));
+        assert!(features.contains(&FEATURE_TIME_IN_FORCE));
+
+        let decoded = decode(&encode(&envelope)).unwrap();
+        let ids: Vec<&str> = decoded.cmd.orders.iter().map(|o| o.id.as_str()).collect();
+        assert_eq!(ids, ["1", "2"]);
+
+        // A batch without orders is refused
+        let empty = CommandEnvelope {
+            cmd: MatchCmd {
+                cmd: MatchCmdType::Batch,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(decode(&encode(&empty)).is_err());
+    }
+
+    #[test]
+    fn replaces_carry_one_replacement() {
+        let order = |id: &str| Order {
+            id: id.to_string(),
+            symbol: "BTCUSDT".to_string(),
+            ..Default::default()
+        };
+        let replace = |orders| CommandEnvelope {
+            cmd: MatchCmd {
+                cmd: MatchCmdType::ReplaceOrder,
+                order: Some(order("1")),
+                orders,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let envelope = replace(vec![order("2")]);
+        assert!(required_features(&envelope).contains(&FEATURE_REPLACE_ORDERS));
+        let decoded = decode(&encode(&envelope)).unwrap();
+        assert_eq!(decoded.cmd.order.unwrap().id, "1");
+        assert_eq!(decoded.cmd.orders[0].id, "2");
+
+        assert!(decode(&encode(&replace(Vec::new()))).is_err());
+        assert!(decode(&encode(&replace(vec![order("2"), order("3")]))).is_err());
+    }
+}
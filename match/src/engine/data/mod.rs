@@ -5,4 +5,4 @@
 
 pub mod orderbook;
 
-pub use orderbook::OrderBook;
+pub use orderbook::{OrderBook, Violation};
@@ -3,10 +3,21 @@
 //! This module provides the core order book data structure and operations for managing
 //! buy and sell orders in a trading system.
 
+use crate::engine::entry::order::OrderStatus;
 use crate::engine::entry::{Order, OrderSide};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+
+/// Broken invariant of an order book found by `OrderBook::audit`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// Name of the invariant, used as metric label
+    pub invariant: &'static str,
+    /// What was found
+    pub detail: String,
+}
 
 /// Represents an order book for a specific trading symbol
 /// Maintains separate collections for buy (bids) and sell (asks) orders
@@ -87,6 +98,75 @@ impl OrderBook {
         self.orders_by_id.get(order_id)
     }
 
+    /// Retrieves a resting order with its fills
+    ///
+    /// Unlike `get_order`, which reads the ID index, this reads the copy on the
+    /// order's price level, the one fills are applied to.
+    ///
+    /// # Arguments
+    /// * `order_id` - The ID of the order to retrieve
+    ///
+    /// # Returns
+    /// A reference to the order if it rests in the book, None otherwise
+    pub fn resting_order(&self, order_id: &str) -> Option<&Order> {
+        let indexed = self.orders_by_id.get(order_id)?;
+        let levels = match indexed.side {
+            OrderSide::Buy => &self.bids,
+            OrderSide::Sell => &self.asks,
+        };
+        levels
+            .get(&indexed.price)?
+            .iter()
+            .find(|order| order.id == order_id)
+    }
+
+    /// Changes the price and quantity of a resting order
+    ///
+    /// The order keeps its place in the queue if only its quantity goes down;
+    /// at a new price or with a larger quantity it goes to the back of its level.
+    ///
+    /// # Arguments
+    /// * `order_id` - The ID of the order to amend
+    /// * `price` - The new price
+    /// * `quantity` - The new quantity, including what already filled
+    ///
+    /// # Returns
+    /// The amended order if it rests in the book, None otherwise
+    pub fn amend_order(
+        &mut self,
+        order_id: &str,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Option<Order> {
+        let resting = self.resting_order(order_id)?;
+        let keeps_priority = price == resting.price && quantity <= resting.quantity;
+        let (side, old_price) = (resting.side, resting.price);
+        let levels = match side {
+            OrderSide::Buy => &mut self.bids,
+            OrderSide::Sell => &mut self.asks,
+        };
+        let orders = levels.get_mut(&old_price)?;
+        let position = orders.iter().position(|order| order.id == order_id)?;
+        let amended = if keeps_priority {
+            orders[position].quantity = quantity;
+            orders[position].clone()
+        } else {
+            let mut order = orders.remove(position);
+            if orders.is_empty() {
+                levels.remove(&old_price);
+            }
+            order.price = price;
+            order.quantity = quantity;
+            levels.entry(price).or_default().push(order.clone());
+            order
+        };
+        if let Some(indexed) = self.orders_by_id.get_mut(order_id) {
+            indexed.price = price;
+            indexed.quantity = quantity;
+        }
+        Some(amended)
+    }
+
     /// Gets the highest bid price in the order book
     ///
     /// # Returns
@@ -103,6 +183,177 @@ impl OrderBook {
         self.asks.keys().next().copied()
     }
 
+    /// Aggregates the best price levels of each side
+    ///
+    /// # Arguments
+    /// * `levels` - Maximum number of levels per side
+    ///
+    /// # Returns
+    /// Price and visible quantity of the best bid levels, highest first, and of
+    /// the best ask levels, lowest first; iceberg orders count only their shown slice
+    pub fn depth(&self, levels: usize) -> (Vec<(Decimal, Decimal)>, Vec<(Decimal, Decimal)>) {
+        let level = |(price, orders): (&Decimal, &Vec<Order>)| {
+            (*price, orders.iter().map(Order::visible_quantity).sum())
+        };
+        (
+            self.bids.iter().rev().take(levels).map(level).collect(),
+            self.asks.iter().take(levels).map(level).collect(),
+        )
+    }
+
+    /// Lists the resting orders of the best price levels of each side
+    ///
+    /// # Arguments
+    /// * `levels` - Maximum number of levels per side
+    ///
+    /// # Returns
+    /// Price and orders in time priority of the best bid levels, highest first,
+    /// and of the best ask levels, lowest first
+    pub fn level_orders(
+        &self,
+        levels: usize,
+    ) -> (Vec<(Decimal, &[Order])>, Vec<(Decimal, &[Order])>) {
+        let level = |(price, orders): (&Decimal, &Vec<Order>)| (*price, orders.as_slice());
+        (
+            self.bids.iter().rev().take(levels).map(level).collect(),
+            self.asks.iter().take(levels).map(level).collect(),
+        )
+    }
+
+    /// Counts the orders resting in the book on both sides
+    ///
+    /// # Returns
+    /// The number of resting orders
+    pub fn order_count(&self) -> usize {
+        self.bids
+            .values()
+            .chain(self.asks.values())
+            .map(|orders| orders.len())
+            .sum()
+    }
+
+    /// Counts the price levels on both sides of the book
+    ///
+    /// # Returns
+    /// The number of price levels
+    pub fn level_count(&self) -> usize {
+        self.bids.len() + self.asks.len()
+    }
+
+    /// Estimates the memory footprint of the order book
+    ///
+    /// The estimate covers the price levels, the resting orders and the order ID
+    /// index; allocator and tree node overhead are not included.
+    ///
+    /// # Returns
+    /// Estimated size in bytes
+    pub fn estimated_memory(&self) -> usize {
+        let levels: usize = self
+            .bids
+            .values()
+            .chain(self.asks.values())
+            .map(|orders| {
+                size_of::<Decimal>()
+                    + size_of::<Vec<Order>>()
+                    + orders.capacity() * size_of::<Order>()
+                    + orders.iter().map(Order::heap_size).sum::<usize>()
+            })
+            .sum();
+        let index: usize = self
+            .orders_by_id
+            .iter()
+            .map(|(id, order)| {
+                size_of::<String>() + id.capacity() + size_of::<Order>() + order.heap_size()
+            })
+            .sum();
+        size_of::<Self>() + self.symbol.capacity() + levels + index
+    }
+
+    /// Checks the internal invariants of the book
+    ///
+    /// * `index` - the ID index holds exactly the resting orders, each on the
+    ///   side and price of its level; fills are not mirrored into the index, so
+    ///   only the level copy of an order is checked further
+    /// * `level` - no level is empty and every order rests on its own level's
+    ///   side and price
+    /// * `fill` - resting orders have a positive quantity, are not fully filled
+    ///   and carry the status of their fill
+    /// * `crossed` - the best bid is below the best ask
+    ///
+    /// The book caches no aggregate quantities, level sums are computed when read.
+    ///
+    /// # Returns
+    /// Every violation found, empty if the book is consistent
+    pub fn audit(&self) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let mut violation = |invariant, detail| violations.push(Violation { invariant, detail });
+        let mut resting = 0;
+        for (side, levels) in [(OrderSide::Buy, &self.bids), (OrderSide::Sell, &self.asks)] {
+            for (price, orders) in levels {
+                if orders.is_empty() {
+                    violation("level", format!("empty {:?} level at {}", side, price));
+                }
+                for order in orders {
+                    resting += 1;
+                    if order.side != side || order.price != *price {
+                        violation(
+                            "level",
+                            format!(
+                                "order {} ({:?} at {}) rests on the {:?} level at {}",
+                                order.id, order.side, order.price, side, price
+                            ),
+                        );
+                    }
+                    match self.orders_by_id.get(&order.id) {
+                        Some(indexed) if indexed.side == side && indexed.price == *price => {}
+                        Some(_) => violation(
+                            "index",
+                            format!("order {} is indexed on another level", order.id),
+                        ),
+                        None => violation("index", format!("order {} is not indexed", order.id)),
+                    }
+                    let status_ok = if order.filled_quantity.is_zero() {
+                        order.status == OrderStatus::New
+                    } else {
+                        order.status == OrderStatus::PartiallyFilled
+                    };
+                    if order.quantity <= Decimal::ZERO
+                        || order.filled_quantity < Decimal::ZERO
+                        || order.is_filled()
+                        || !status_ok
+                    {
+                        violation(
+                            "fill",
+                            format!(
+                                "order {} rests with {} of {} filled and status {:?}",
+                                order.id, order.filled_quantity, order.quantity, order.status
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+        if resting != self.orders_by_id.len() {
+            violation(
+                "index",
+                format!(
+                    "{} orders indexed, {} resting",
+                    self.orders_by_id.len(),
+                    resting
+                ),
+            );
+        }
+        if let (Some(bid), Some(ask)) = (self.get_best_bid(), self.get_best_ask()) {
+            if bid >= ask {
+                violation(
+                    "crossed",
+                    format!("best bid {} at or above best ask {}", bid, ask),
+                );
+            }
+        }
+        violations
+    }
+
     /// Calculates the current spread between best ask and best bid
     ///
     /// # Returns
@@ -114,3 +365,58 @@ impl OrderBook {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::entry::{Order, OrderSide, OrderType};
+    use crate::engine::matchlogic::Matcher;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    fn order(id: &str, side: OrderSide, price: Decimal, quantity: Decimal) -> Order {
+        Order {
+            id: id.to_string(),
+            symbol: "BTCUSDT".to_string(),
+            order_type: OrderType::Limit,
+            side,
+            price,
+            quantity,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn depth_sums_the_remaining_quantity_of_the_best_levels() {
+        let mut matcher = Matcher::new("BTCUSDT".to_string());
+        matcher.place_order(order("a", OrderSide::Sell, dec!(101), dec!(2)));
+        matcher.place_order(order("b", OrderSide::Sell, dec!(101), dec!(1)));
+        matcher.place_order(order("c", OrderSide::Sell, dec!(102), dec!(1)));
+        matcher.place_order(order("d", OrderSide::Buy, dec!(99), dec!(1)));
+        matcher.place_order(order("e", OrderSide::Buy, dec!(98), dec!(1)));
+        // Partially fills the first ask
+        matcher.place_order(order("f", OrderSide::Buy, dec!(101), dec!(0.5)));
+        let (bids, asks) = matcher.orderbook().depth(1);
+        assert_eq!(bids, [(dec!(99), dec!(1))]);
+        assert_eq!(asks, [(dec!(101), dec!(2.5))]);
+        assert_eq!(matcher.orderbook().depth(5).1.len(), 2);
+    }
+
+    #[test]
+    fn audit_finds_broken_invariants() {
+        let mut matcher = Matcher::new("BTCUSDT".to_string());
+        matcher.place_order(order("a", OrderSide::Sell, dec!(101), dec!(2)));
+        matcher.place_order(order("b", OrderSide::Buy, dec!(99), dec!(1)));
+        // Partially fills the resting ask
+        matcher.place_order(order("c", OrderSide::Buy, dec!(101), dec!(1)));
+        assert_eq!(matcher.orderbook().audit(), Vec::new());
+
+        let mut book = matcher.orderbook().clone();
+        book.orders_by_id.remove("b");
+        book.asks.get_mut(&dec!(101)).unwrap()[0].filled_quantity = dec!(2);
+        book.add_order(order("d", OrderSide::Sell, dec!(98), dec!(1)));
+        book.bids.insert(dec!(50), Vec::new());
+        let mut found: Vec<&str> = book.audit().iter().map(|v| v.invariant).collect();
+        found.sort();
+        assert_eq!(found, ["crossed", "fill", "index", "index", "level"]);
+    }
+}
@@ -0,0 +1,98 @@
+//! Request Deduplication Module
+//!
+//! This module suppresses duplicate commands caused by client retries. Every command
+//! carries a client supplied request ID; IDs seen within a time window are remembered
+//! and a second command with the same ID is dropped instead of being applied twice.
+//!
+//! The window is measured with the proposer timestamps stored in the raft log, never
+//! with the local clock, so every replica drops exactly the same commands.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+
+/// How long a request ID is remembered, in milliseconds
+pub const DEDUPE_WINDOW_MS: u64 = 5 * 60 * 1000;
+/// Upper bound of remembered request IDs, protecting memory under very high rates
+pub const DEDUPE_MAX_ENTRIES: usize = 1_000_000;
+
+/// Time-bounded set of recently applied request IDs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<(u64, String)>", into = "Vec<(u64, String)>")]
+pub struct RequestDedupe {
+    /// Request IDs in apply order with their propose timestamps
+    entries: VecDeque<(u64, String)>,
+    /// Index over `entries` for constant time lookups
+    ids: HashSet<String>,
+    /// Length of the remembered request IDs, for the memory estimate
+    id_bytes: usize,
+}
+
+impl RequestDedupe {
+    /// Records a request ID and reports whether it was already seen
+    ///
+    /// # Arguments
+    /// * `request_id` - Client supplied request ID
+    /// * `now_ms` - Propose timestamp of the command in milliseconds
+    ///
+    /// # Returns
+    /// True if the request ID was applied before and the command must be skipped
+    pub fn check_and_insert(&mut self, request_id: &str, now_ms: u64) -> bool {
+        self.expire(now_ms);
+        if self.ids.contains(request_id) {
+            return true;
+        }
+        if self.entries.len() >= DEDUPE_MAX_ENTRIES {
+            if let Some((_, id)) = self.entries.pop_front() {
+                self.ids.remove(&id);
+                self.id_bytes -= id.len();
+            }
+        }
+        self.id_bytes += request_id.len();
+        self.ids.insert(request_id.to_string());
+        self.entries.push_back((now_ms, request_id.to_string()));
+        false
+    }
+
+    /// Forgets request IDs that fell out of the dedupe window
+    fn expire(&mut self, now_ms: u64) {
+        let horizon = now_ms.saturating_sub(DEDUPE_WINDOW_MS);
+        while let Some((ts, _)) = self.entries.front() {
+            if *ts >= horizon {
+                break;
+            }
+            if let Some((_, id)) = self.entries.pop_front() {
+                self.ids.remove(&id);
+                self.id_bytes -= id.len();
+            }
+        }
+    }
+
+    /// Estimates the memory held by the remembered request IDs
+    ///
+    /// Each ID is stored twice, in the window and in the index; allocator and
+    /// hash table overhead are not included.
+    ///
+    /// # Returns
+    /// Estimated size in bytes
+    pub fn estimated_memory(&self) -> usize {
+        self.entries.len() * (size_of::<(u64, String)>() + size_of::<String>()) + 2 * self.id_bytes
+    }
+}
+
+impl From<Vec<(u64, String)>> for RequestDedupe {
+    fn from(entries: Vec<(u64, String)>) -> Self {
+        let ids = entries.iter().map(|(_, id)| id.clone()).collect();
+        let id_bytes = entries.iter().map(|(_, id)| id.len()).sum();
+        Self {
+            entries: entries.into(),
+            ids,
+            id_bytes,
+        }
+    }
+}
+
+impl From<RequestDedupe> for Vec<(u64, String)> {
+    fn from(dedupe: RequestDedupe) -> Self {
+        dedupe.entries.into()
+    }
+}
@@ -11,6 +11,6 @@ pub mod order;
 pub mod symbol;
 pub mod trade;
 
-pub use order::{Order, OrderSide, OrderType};
+pub use order::{Order, OrderSide, OrderType, TimeInForce};
 pub use symbol::{Symbol, SymbolStatus};
 pub use trade::Trade;
@@ -17,6 +17,14 @@ pub enum OrderType {
     Market,
     /// Limit order - executed at a specific price or better
     Limit,
+    /// Post-only limit order - rests in the book, rejected if it would trade on arrival
+    LimitMaker,
+    /// Stop order - held off the book, placed as a market order once the last
+    /// trade price reaches its stop price
+    Stop,
+    /// Stop-limit order - held off the book, placed as a limit order once the
+    /// last trade price reaches its stop price
+    StopLimit,
 }
 
 /// Represents the side of an order (buy or sell)
@@ -29,6 +37,31 @@ pub enum OrderSide {
     Sell,
 }
 
+/// Represents how long an order stays in the book
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum TimeInForce {
+    /// Good till canceled - the unfilled remainder rests in the book
+    #[default]
+    Gtc,
+    /// Immediate or cancel - the unfilled remainder is canceled
+    Ioc,
+    /// Fill or kill - the order fills completely on arrival or not at all
+    Fok,
+    /// Good till date - the unfilled remainder rests in the book until the
+    /// order's expiry time, then it is canceled
+    Gtd,
+}
+
+impl TimeInForce {
+    /// Checks if the unfilled remainder of an order rests in the book
+    ///
+    /// # Returns
+    /// True for good-till-canceled and good-till-date orders
+    pub fn rests(self) -> bool {
+        matches!(self, TimeInForce::Gtc | TimeInForce::Gtd)
+    }
+}
+
 /// Represents the current status of an order
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
 pub enum OrderStatus {
@@ -68,6 +101,40 @@ pub struct Order {
     pub created_at: u64,
     /// Timestamp when the order was last updated
     pub updated_at: u64,
+    /// Fee rate charged on fills where the order takes liquidity
+    #[serde(default)]
+    pub taker_fee: Decimal,
+    /// Fee rate charged on fills where the order provides liquidity
+    #[serde(default)]
+    pub maker_fee: Decimal,
+    /// Leverage of an order on a perpetual contract, zero on spot orders
+    #[serde(default)]
+    pub leverage: Decimal,
+    /// ID the client gave the order, unique among the open orders of its
+    /// account, empty if none, see `engine::client_orders`
+    #[serde(default)]
+    pub client_order_id: String,
+    /// How long the unfilled remainder of the order stays in the book
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    /// Last trade price at which a stop order is placed, zero on other orders
+    #[serde(default)]
+    pub stop_price: Decimal,
+    /// Quantity an iceberg order shows in the book at a time, zero to show all
+    #[serde(default)]
+    pub display_quantity: Decimal,
+    /// Distance a trailing stop order keeps its stop price from the last trade
+    /// price, zero on other orders
+    #[serde(default)]
+    pub trailing_offset: Decimal,
+    /// Engine time in milliseconds a good-till-date order is canceled at, zero
+    /// on other orders
+    #[serde(default)]
+    pub expire_at: u64,
+    /// Account the order was placed for, taken from its command when it is
+    /// applied; zero on orders that rested before orders carried it
+    #[serde(default)]
+    pub account_id: u64,
 }
 
 #[allow(unused)]
@@ -104,26 +171,16 @@ impl Order {
             price: Decimal::from_str(&price).unwrap(),
             quantity: Decimal::from_str(&quantity).unwrap(),
             filled_quantity: dec!(0),
-        }
-    }
-
-    /// Creates a new default order with empty values
-    pub fn default() -> Self {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        Self {
-            id: String::new(),
-            symbol: String::new(),
-            order_type: OrderType::default(),
-            side: OrderSide::default(),
-            price: dec!(0),
-            quantity: dec!(0),
-            filled_quantity: dec!(0),
-            status: OrderStatus::default(),
-            created_at: now,
-            updated_at: now,
+            taker_fee: dec!(0),
+            maker_fee: dec!(0),
+            leverage: dec!(0),
+            client_order_id: String::new(),
+            time_in_force: TimeInForce::default(),
+            stop_price: dec!(0),
+            display_quantity: dec!(0),
+            trailing_offset: dec!(0),
+            expire_at: 0,
+            account_id: 0,
         }
     }
 
@@ -135,6 +192,30 @@ impl Order {
         self.quantity - self.filled_quantity
     }
 
+    /// Checks if the order shows only part of its quantity in the book
+    ///
+    /// # Returns
+    /// True if the order has a display quantity
+    pub fn is_iceberg(&self) -> bool {
+        self.display_quantity > dec!(0)
+    }
+
+    /// Calculates the quantity the order shows in the book
+    ///
+    /// An iceberg order shows its display quantity until that slice is filled,
+    /// then the next slice of its remainder.
+    ///
+    /// # Returns
+    /// The unfilled part of the current slice, the remaining quantity for
+    /// orders that are not icebergs
+    pub fn visible_quantity(&self) -> Decimal {
+        if !self.is_iceberg() {
+            return self.remaining_quantity();
+        }
+        let slice_filled = self.filled_quantity % self.display_quantity;
+        (self.display_quantity - slice_filled).min(self.remaining_quantity())
+    }
+
     /// Checks if the order has been completely filled
     ///
     /// # Returns
@@ -143,6 +224,22 @@ impl Order {
         self.filled_quantity >= self.quantity
     }
 
+    /// Checks if the order waits for its stop price before it is placed
+    ///
+    /// # Returns
+    /// True for stop and stop-limit orders
+    pub fn is_stop(&self) -> bool {
+        matches!(self.order_type, OrderType::Stop | OrderType::StopLimit)
+    }
+
+    /// Checks if the stop price of the order follows the last trade price
+    ///
+    /// # Returns
+    /// True for stop and stop-limit orders with a trailing offset
+    pub fn is_trailing(&self) -> bool {
+        self.is_stop() && self.trailing_offset > dec!(0)
+    }
+
     /// Checks if the order can be canceled
     ///
     /// # Returns
@@ -153,15 +250,55 @@ impl Order {
 
     /// Updates the order status based on its current state
     /// Also updates the updated_at timestamp
-    pub fn update_status(&mut self) {
+    ///
+    /// # Arguments
+    /// * `now` - Engine time in seconds, taken from the replicated command so all replicas agree
+    pub fn update_status(&mut self, now: u64) {
         if self.is_filled() {
             self.status = OrderStatus::Filled;
         } else if self.filled_quantity > dec!(0) {
             self.status = OrderStatus::PartiallyFilled;
         }
-        self.updated_at = SystemTime::now()
+        self.updated_at = now;
+    }
+
+    /// Estimates the heap memory owned by the order
+    ///
+    /// # Returns
+    /// Number of bytes allocated for the order's strings
+    pub fn heap_size(&self) -> usize {
+        self.id.capacity() + self.symbol.capacity() + self.client_order_id.capacity()
+    }
+}
+
+impl Default for Order {
+    /// Creates a new default order with empty values
+    fn default() -> Self {
+        let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
+        Self {
+            id: String::new(),
+            symbol: String::new(),
+            order_type: OrderType::default(),
+            side: OrderSide::default(),
+            price: dec!(0),
+            quantity: dec!(0),
+            filled_quantity: dec!(0),
+            status: OrderStatus::default(),
+            created_at: now,
+            updated_at: now,
+            taker_fee: dec!(0),
+            maker_fee: dec!(0),
+            leverage: dec!(0),
+            client_order_id: String::new(),
+            time_in_force: TimeInForce::default(),
+            stop_price: dec!(0),
+            display_quantity: dec!(0),
+            trailing_offset: dec!(0),
+            expire_at: 0,
+            account_id: 0,
+        }
     }
 }
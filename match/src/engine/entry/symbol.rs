@@ -3,6 +3,7 @@
 //! This module defines the trading symbol structure and related functionality.
 //! It includes validation and precision handling for prices and quantities.
 
+use super::OrderSide;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -13,6 +14,8 @@ use std::time::{SystemTime, UNIX_EPOCH};
 pub struct Symbol {
     /// Name of the trading symbol (e.g., "BTC/USDT")
     pub name: String,
+    /// Tenant that owns the symbol
+    pub tenant: String,
     /// Base currency of the trading pair (e.g., "BTC")
     pub base_currency: String,
     /// Quote currency of the trading pair (e.g., "USDT")
@@ -35,6 +38,37 @@ pub struct Symbol {
     pub created_at: u64,
     /// Timestamp when the symbol was last updated
     pub updated_at: u64,
+    /// Whether orders hold and settle account balances, see `engine::ledger`
+    #[serde(default)]
+    pub settle_balances: bool,
+    /// Highest leverage orders on a perpetual contract may use, zero on spot symbols
+    #[serde(default)]
+    pub max_leverage: Decimal,
+    /// Margin ratio below which positions in a perpetual contract are liquidated
+    #[serde(default)]
+    pub maintenance_margin: Decimal,
+    /// Currency fees are paid in, the base or quote currency, empty to take each
+    /// fee from what the order receives, see `Ledger::settle`
+    #[serde(default)]
+    pub fee_currency: String,
+    /// Largest distance of a limit price from the reference price as a fraction
+    /// of it, e.g. 0.1 for 10%, zero for no band, see `Symbol::price_in_band`
+    #[serde(default)]
+    pub price_band: Decimal,
+    /// Largest move of the trade price within `circuit_window_ms` as a fraction
+    /// of the lowest or highest price traded in the window before trading halts,
+    /// zero for no circuit breaker, see `engine::circuit_breaker`
+    #[serde(default)]
+    pub circuit_breaker: Decimal,
+    /// Engine time in milliseconds the circuit breaker looks back
+    #[serde(default)]
+    pub circuit_window_ms: u64,
+    /// Engine time in milliseconds a halt lasts before trading resumes
+    #[serde(default)]
+    pub halt_ms: u64,
+    /// Whether a halt cancels the orders resting on the symbol
+    #[serde(default)]
+    pub cancel_on_halt: bool,
 }
 
 /// Represents the current status of a trading symbol
@@ -47,6 +81,16 @@ pub enum SymbolStatus {
     Inactive,
     /// Symbol has been permanently removed
     Delisted,
+    /// Trading is halted by the circuit breaker until its halt ends, orders
+    /// may still be canceled
+    Halted,
+}
+
+impl SymbolStatus {
+    /// Returns whether resting orders may be canceled, trading may be halted
+    pub fn accepts_cancels(self) -> bool {
+        matches!(self, SymbolStatus::Active | SymbolStatus::Halted)
+    }
 }
 
 #[allow(unused)]
@@ -81,6 +125,7 @@ impl Symbol {
             .as_secs();
         Self {
             name,
+            tenant: String::new(),
             base_currency,
             quote_currency,
             price_precision,
@@ -92,6 +137,15 @@ impl Symbol {
             status: SymbolStatus::Active,
             created_at: now,
             updated_at: now,
+            settle_balances: false,
+            max_leverage: Decimal::ZERO,
+            maintenance_margin: Decimal::ZERO,
+            fee_currency: String::new(),
+            price_band: Decimal::ZERO,
+            circuit_breaker: Decimal::ZERO,
+            circuit_window_ms: 0,
+            halt_ms: 0,
+            cancel_on_halt: false,
         }
     }
 
@@ -106,6 +160,43 @@ impl Symbol {
         price >= self.min_price && price <= self.max_price
     }
 
+    /// Validates if a limit price is within the price band
+    ///
+    /// # Arguments
+    /// * `price` - Price to validate
+    /// * `reference` - Price the band is centered on, see `Matcher::reference_price`
+    ///
+    /// # Returns
+    /// True if the price is at most `price_band` times the reference away from
+    /// it, or if the symbol has no band or no reference price yet
+    pub fn price_in_band(&self, price: Decimal, reference: Option<Decimal>) -> bool {
+        match reference {
+            Some(reference) if !self.price_band.is_zero() => {
+                (price - reference).abs() <= reference * self.price_band
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns the worst price a market order may trade at, the edge of the
+    /// price band on its side
+    ///
+    /// # Arguments
+    /// * `side` - Side of the market order
+    /// * `reference` - Price the band is centered on, see `Matcher::reference_price`
+    ///
+    /// # Returns
+    /// `price_band` times the reference above it for a buy and below it for a
+    /// sell, None if the symbol has no band or no reference price yet
+    pub fn band_edge(&self, side: OrderSide, reference: Option<Decimal>) -> Option<Decimal> {
+        let reference = reference.filter(|_| !self.price_band.is_zero())?;
+        let distance = reference * self.price_band;
+        Some(match side {
+            OrderSide::Buy => reference + distance,
+            OrderSide::Sell => reference - distance,
+        })
+    }
+
     /// Validates if a quantity is within the allowed range
     ///
     /// # Arguments
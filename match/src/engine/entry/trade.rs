@@ -5,13 +5,13 @@
 
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
 
 /// Represents a completed trade in the system
 /// Contains information about the matched orders and trade details
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Trade {
-    /// Unique identifier for the trade
+    /// Unique identifier for the trade, the raft index of its command and its
+    /// position among the trades of that command, see `history::number_trades`
     pub id: String,
     /// Trading symbol for the trade
     pub symbol: String,
@@ -23,8 +23,8 @@ pub struct Trade {
     pub buyer_order_id: String,
     /// ID of the seller's order
     pub seller_order_id: String,
-    /// Timestamp when the trade was created
-    pub created_at: SystemTime,
+    /// Engine time of the command that made the trade, in seconds
+    pub created_at: u64,
 }
 
 #[allow(unused)]
@@ -38,6 +38,7 @@ impl Trade {
     /// * `quantity` - Trade quantity
     /// * `buyer_order_id` - ID of the buyer's order
     /// * `seller_order_id` - ID of the seller's order
+    /// * `created_at` - Engine time of the command that made the trade, in seconds
     pub fn new(
         id: String,
         symbol: String,
@@ -45,6 +46,7 @@ impl Trade {
         quantity: Decimal,
         buyer_order_id: String,
         seller_order_id: String,
+        created_at: u64,
     ) -> Self {
         Self {
             id,
@@ -53,15 +55,15 @@ impl Trade {
             quantity,
             buyer_order_id,
             seller_order_id,
-            created_at: SystemTime::now(),
+            created_at,
         }
     }
 
     /// Calculates the total amount of the trade
     ///
     /// # Returns
-    /// The product of price and quantity
-    pub fn total_amount(&self) -> Decimal {
-        self.price * self.quantity
+    /// The product of price and quantity, None if it exceeds the decimal range
+    pub fn total_amount(&self) -> Option<Decimal> {
+        self.price.checked_mul(self.quantity)
     }
 }
@@ -0,0 +1,195 @@
+//! Funding Module
+//!
+//! This module applies the money movements external funding systems report to the
+//! engine: deposits, withdrawals and manual adjustments. Every funding command
+//! carries an idempotency key. Unlike request IDs, which are forgotten after the
+//! dedupe window, keys are remembered for good together with the record of what
+//! the command did, so a funding system may resubmit a movement at any time
+//! without crediting it twice. The records form the audit trail of the ledger:
+//! they are part of the snapshot and every applied command is also published as
+//! a funding event, see `funding_log`.
+
+use crate::engine::ledger::{Ledger, Transfer};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Kind of money movement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum FundingKind {
+    /// Funds credited from outside the engine
+    #[default]
+    Deposit,
+    /// Funds debited to outside the engine
+    Withdrawal,
+    /// Manual correction, credits positive and debits negative amounts
+    Adjustment,
+}
+
+/// Outcome of a funding command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum FundingOutcome {
+    /// The balance was changed
+    #[default]
+    Applied,
+    /// The balance was left unchanged for the given reason
+    Rejected(String),
+    /// The idempotency key was used before at the given raft index, nothing was done
+    Duplicate(u64),
+}
+
+/// Audit record of a funding command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct FundingRecord {
+    /// Raft index of the command
+    pub index: u64,
+    /// Tenant the account belongs to
+    pub tenant: String,
+    /// Kind of movement
+    pub kind: FundingKind,
+    /// Account credited or debited
+    pub account_id: u64,
+    /// Currency moved
+    pub currency: String,
+    /// Amount moved, negative only for adjustments that debit
+    pub amount: Decimal,
+    /// Key the funding system identifies the movement with
+    pub idempotency_key: String,
+    /// Free text reason given by the operator
+    pub reason: String,
+    /// Request ID of the command
+    pub request_id: String,
+    /// Client that sent the command
+    pub client_id: String,
+    /// Propose timestamp of the command in milliseconds since the epoch
+    pub proposed_at: u64,
+    /// What the command did
+    pub outcome: FundingOutcome,
+}
+
+/// Applied funding commands of a tenant keyed by idempotency key
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct FundingLog {
+    /// Records of applied and rejected commands
+    records: BTreeMap<String, FundingRecord>,
+}
+
+impl FundingLog {
+    /// Returns the record of the command that used an idempotency key
+    pub fn record(&self, idempotency_key: &str) -> Option<&FundingRecord> {
+        if idempotency_key.is_empty() {
+            return None;
+        }
+        self.records.get(idempotency_key)
+    }
+
+    /// Applies a funding command to the ledger unless its key was used before
+    ///
+    /// Rejected commands keep their key as well, a corrected movement must be
+    /// submitted with a new one. Commands without a key, written before keys
+    /// existed, are applied without being remembered.
+    ///
+    /// # Arguments
+    /// * `ledger` - Ledger of the tenant
+    /// * `record` - The command, its outcome is filled in here
+    ///
+    /// # Returns
+    /// The record with its outcome, to be published as audit event
+    pub fn apply(&mut self, ledger: &mut Ledger, mut record: FundingRecord) -> FundingRecord {
+        if let Some(previous) = self.record(&record.idempotency_key) {
+            record.outcome = FundingOutcome::Duplicate(previous.index);
+            return record;
+        }
+        let transfer = Transfer {
+            currency: record.currency.clone(),
+            amount: record.amount,
+            ..Default::default()
+        };
+        let result = match record.kind {
+            FundingKind::Deposit => ledger.deposit(record.account_id, &transfer),
+            FundingKind::Withdrawal => ledger.withdraw(record.account_id, &transfer),
+            FundingKind::Adjustment => ledger.adjust(record.account_id, &transfer),
+        };
+        record.outcome = match result {
+            Ok(()) => FundingOutcome::Applied,
+            Err(e) => FundingOutcome::Rejected(e),
+        };
+        if !record.idempotency_key.is_empty() {
+            self.records
+                .insert(record.idempotency_key.clone(), record.clone());
+        }
+        record
+    }
+
+    /// Estimates the memory held by the remembered records
+    ///
+    /// Walks every record, so it is only taken after funding commands were
+    /// applied; tree node overhead is not included.
+    ///
+    /// # Returns
+    /// Estimated size in bytes
+    pub fn estimated_memory(&self) -> usize {
+        self.records
+            .iter()
+            .map(|(key, record)| {
+                size_of::<String>()
+                    + key.capacity()
+                    + size_of::<FundingRecord>()
+                    + record.tenant.capacity()
+                    + record.currency.capacity()
+                    + record.idempotency_key.capacity()
+                    + record.reason.capacity()
+                    + record.request_id.capacity()
+                    + record.client_id.capacity()
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn record(index: u64, kind: FundingKind, key: &str, amount: Decimal) -> FundingRecord {
+        FundingRecord {
+            index,
+            kind,
+            account_id: 1,
+            currency: "USDT".to_string(),
+            amount,
+            idempotency_key: key.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn keys_are_applied_once_and_remembered() {
+        let mut ledger = Ledger::default();
+        let mut log = FundingLog::default();
+        let deposit = log.apply(
+            &mut ledger,
+            record(3, FundingKind::Deposit, "dep-1", dec!(100)),
+        );
+        assert_eq!(deposit.outcome, FundingOutcome::Applied);
+        let again = log.apply(
+            &mut ledger,
+            record(9, FundingKind::Deposit, "dep-1", dec!(100)),
+        );
+        assert_eq!(again.outcome, FundingOutcome::Duplicate(3));
+        assert_eq!(ledger.balance(1, "USDT").available, dec!(100));
+
+        let overdraft = log.apply(
+            &mut ledger,
+            record(10, FundingKind::Adjustment, "adj-1", dec!(-150)),
+        );
+        assert!(matches!(overdraft.outcome, FundingOutcome::Rejected(_)));
+        assert_eq!(log.record("adj-1"), Some(&overdraft));
+        let fix = log.apply(
+            &mut ledger,
+            record(11, FundingKind::Adjustment, "adj-2", dec!(-40)),
+        );
+        assert_eq!(fix.outcome, FundingOutcome::Applied);
+        assert_eq!(ledger.balance(1, "USDT").available, dec!(60));
+    }
+}
@@ -0,0 +1,213 @@
+//! Order History Module
+//!
+//! This module describes what applied commands did to orders, for read models kept
+//! outside the replicated state such as the query store (see `query_store`).
+//! Events are derived from the command and its result only, never from wall
+//! clocks, and trades are numbered by their command (see `number_trades`), so every
+//! replica produces the same events for the same log.
+
+use crate::engine::entry::order::OrderStatus;
+use crate::engine::entry::{Order, Trade};
+use crate::engine::matchengine::MatchCmdType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Change of the orders of a tenant made by one applied command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderEvent {
+    /// Raft index of the command
+    pub index: u64,
+    /// Tenant the orders belong to
+    pub tenant: String,
+    /// Account the command was made for, 0 if not account scoped
+    pub account_id: u64,
+    /// Engine time of the command in seconds
+    pub time: u64,
+    /// What happened to the orders
+    pub change: OrderChange,
+}
+
+/// What an applied command did to orders
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OrderChange {
+    /// An order was placed or injected as liquidation, with its state after
+    /// matching and the trades it took part in as taker, or a resting order was
+    /// amended, with its new price and quantity and no trades
+    Placed { order: Order, trades: Vec<Trade> },
+    /// A resting order was canceled
+    Canceled { symbol: String, order_id: String },
+    /// A symbol was removed, its resting orders were dropped with it
+    SymbolRemoved { symbol: String },
+    /// A symbol was listed, its book is empty
+    SymbolListed { symbol: String },
+    /// Trading in a symbol was halted by its circuit breaker, the orders the
+    /// halt canceled are reported as canceled before
+    SymbolHalted { symbol: String, reason: String },
+    /// Trading in a halted symbol resumed
+    SymbolResumed { symbol: String },
+}
+
+/// Applied command as kept in the audit trail, see `audit_trail`
+#[derive(Debug, Clone, Default)]
+pub struct CommandRecord {
+    /// Raft index of the command
+    pub index: u64,
+    /// Tenant the command is scoped to
+    pub tenant: String,
+    /// Unique ID of the client request
+    pub request_id: String,
+    /// ID of the client that sent the request
+    pub client_id: String,
+    /// Account the command was made for, 0 if not account scoped
+    pub account_id: u64,
+    /// Wall clock of the proposing leader in milliseconds since the epoch
+    pub proposed_at: u64,
+    /// Engine time of the command in milliseconds
+    pub time_ms: u64,
+    /// Type of the command
+    pub cmd: MatchCmdType,
+    /// Whether the request ID was applied before, so the command was skipped
+    pub duplicate: bool,
+    /// The command as replicated, see `engine::codec`
+    pub data: Vec<u8>,
+}
+
+/// Orders canceled by one applied cancel-all or replace command, for the
+/// request that proposed it, see `read_view::cancellation`
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation {
+    /// Tenant the command is scoped to
+    pub tenant: String,
+    /// Unique ID of the client request
+    pub request_id: String,
+    /// IDs of the canceled orders, in the order they were canceled
+    pub order_ids: Vec<String>,
+    /// Status after matching of the order a replace command placed, None for a
+    /// cancel-all command
+    pub replacement: Option<OrderStatus>,
+    /// Why the order a replace command placed was rejected, empty otherwise
+    pub reject_reason: String,
+}
+
+impl OrderChange {
+    /// Describes a placed order by its state after matching
+    ///
+    /// # Arguments
+    /// * `order` - The order as it was placed
+    /// * `result` - Result of placing the order
+    /// * `resting` - Whether the rest of the order stayed on the book
+    pub fn placed(mut order: Order, result: Result<Vec<Trade>, String>, resting: bool) -> Self {
+        let trades = match result {
+            Ok(trades) => trades,
+            Err(_) => {
+                order.status = OrderStatus::Rejected;
+                return OrderChange::Placed {
+                    order,
+                    trades: Vec::new(),
+                };
+            }
+        };
+        order.filled_quantity = trades.iter().map(|trade| trade.quantity).sum();
+        order.update_status(order.updated_at);
+        if !resting && !order.is_filled() {
+            order.status = OrderStatus::Canceled;
+        }
+        OrderChange::Placed { order, trades }
+    }
+}
+
+/// Gives every trade in the events an ID replicas agree on
+///
+/// The ID is the raft index of the trade's command and the trade's position
+/// among the trades of that command, e.g. `42-0`.
+///
+/// # Arguments
+/// * `events` - Events of whole commands, in log order
+pub fn number_trades(events: &mut [OrderEvent]) {
+    let mut next: BTreeMap<u64, u32> = BTreeMap::new();
+    for event in events {
+        if let OrderChange::Placed { trades, .. } = &mut event.change {
+            let seq = next.entry(event.index).or_default();
+            for trade in trades {
+                trade.id = format!("{}-{}", event.index, seq);
+                *seq += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn trade(quantity: rust_decimal::Decimal) -> Trade {
+        Trade::new(
+            "t".to_string(),
+            "BTCUSDT".to_string(),
+            dec!(100),
+            quantity,
+            "1".to_string(),
+            "2".to_string(),
+            7,
+        )
+    }
+
+    #[test]
+    fn placed_orders_carry_their_state_after_matching() {
+        let order = Order {
+            id: "1".to_string(),
+            quantity: dec!(2),
+            ..Default::default()
+        };
+        let status = |result, resting| match OrderChange::placed(order.clone(), result, resting) {
+            OrderChange::Placed { order, .. } => (order.status, order.filled_quantity),
+            change => panic!("unexpected {:?}", change),
+        };
+        assert_eq!(
+            status(Ok(vec![trade(dec!(0.5))]), true),
+            (OrderStatus::PartiallyFilled, dec!(0.5))
+        );
+        assert_eq!(
+            status(Ok(vec![trade(dec!(0.5))]), false),
+            (OrderStatus::Canceled, dec!(0.5))
+        );
+        assert_eq!(
+            status(Ok(vec![trade(dec!(2))]), false),
+            (OrderStatus::Filled, dec!(2))
+        );
+        assert_eq!(
+            status(Err("no funds".to_string()), false),
+            (OrderStatus::Rejected, dec!(0))
+        );
+    }
+
+    #[test]
+    fn trades_are_numbered_per_command() {
+        let event = |index, trades| OrderEvent {
+            index,
+            tenant: "default".to_string(),
+            account_id: 1,
+            time: 7,
+            change: OrderChange::Placed {
+                order: Order::default(),
+                trades,
+            },
+        };
+        let mut events = vec![
+            event(3, vec![trade(dec!(1)), trade(dec!(1))]),
+            event(4, vec![trade(dec!(1))]),
+            event(3, Vec::new()),
+            event(3, vec![trade(dec!(1))]),
+        ];
+        number_trades(&mut events);
+        let ids: Vec<&str> = events
+            .iter()
+            .flat_map(|event| match &event.change {
+                OrderChange::Placed { trades, .. } => trades.iter().map(|t| t.id.as_str()),
+                change => panic!("unexpected {:?}", change),
+            })
+            .collect();
+        assert_eq!(ids, ["3-0", "3-1", "4-0", "3-2"]);
+    }
+}
@@ -0,0 +1,929 @@
+//! Ledger Module
+//!
+//! This module keeps the account balances of a tenant. An account has an available
+//! and a held amount per currency. Accepting an order on a symbol that settles
+//! balances moves the funds the order may spend from available to held, the quote
+//! currency for buys and the base currency for sells. Trades are settled in the
+//! same apply that matched them: the held funds are paid to the counterparty, less
+//! fees, so the ledger never lags the book. Cancels release what is left. The
+//! ledger only changes while commands are applied, so it is identical on every
+//! replica and part of the snapshot.
+//!
+//! Fees are taken from what an order receives, never from what it holds, so the
+//! hold of a buy is its price times quantity and that of a sell its quantity. A
+//! symbol may name a fee currency instead: a fee in the currency the order pays
+//! comes out of the available funds, and falls back to what the order receives if
+//! those cannot cover it. A negative maker fee is a rebate, paid to the maker by
+//! `FEE_ACCOUNT`, whose balance goes negative if rebates exceed the fees taken.
+//! Per-account risk limits may extend an account a credit line per currency,
+//! letting its available funds go negative by up to that amount, or stop the
+//! account from placing orders at all.
+
+use crate::engine::data::OrderBook;
+use crate::engine::entry::{Order, OrderSide, Trade};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Balance of one currency in an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct Balance {
+    /// Funds free to be withdrawn or held for new orders
+    pub available: Decimal,
+    /// Funds held for open orders
+    pub held: Decimal,
+}
+
+/// Funds held for an order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hold {
+    /// Account the funds belong to
+    pub account_id: u64,
+    /// Currency of the held funds
+    pub currency: String,
+    /// Amount still held
+    pub amount: Decimal,
+    /// Fee rate the order pays on fills where it provides liquidity
+    #[serde(default)]
+    pub maker_fee: Decimal,
+    /// Currency the order pays its fees in, see `Symbol::fee_currency`
+    #[serde(default)]
+    pub fee_currency: String,
+}
+
+/// Amount of a currency moved into or out of an account
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct Transfer {
+    /// Currency to move
+    pub currency: String,
+    /// Amount to move, positive except for adjustments that debit
+    pub amount: Decimal,
+    /// Key the funding system identifies the movement with, see `engine::funding`
+    #[serde(default)]
+    pub idempotency_key: String,
+    /// Free text reason given by the operator
+    #[serde(default)]
+    pub reason: String,
+}
+
+/// Balance movements of a settled trade, published for downstream accounting
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Settlement {
+    /// Raft index of the command that produced the trade
+    pub index: u64,
+    /// Position of the trade among the trades of that command
+    pub trade_seq: u32,
+    /// Tenant the trade belongs to
+    pub tenant: String,
+    /// Symbol the trade happened on
+    pub symbol: String,
+    /// ID of the buy order
+    pub buyer_order_id: String,
+    /// Account of the buy order
+    pub buyer_account_id: u64,
+    /// ID of the sell order
+    pub seller_order_id: String,
+    /// Account of the sell order
+    pub seller_account_id: u64,
+    /// Side of the order that took liquidity
+    pub taker_side: OrderSide,
+    /// Trade price
+    pub price: Decimal,
+    /// Traded base quantity, paid by the seller
+    pub quantity: Decimal,
+    /// Traded quote amount, paid by the buyer
+    pub amount: Decimal,
+    /// Base currency of the symbol
+    pub base: String,
+    /// Quote currency of the symbol
+    pub quote: String,
+    /// Fee the buyer paid, negative for a rebate
+    pub buyer_fee: Decimal,
+    /// Currency of the buyer's fee
+    pub buyer_fee_currency: String,
+    /// Fee the seller paid, negative for a rebate
+    pub seller_fee: Decimal,
+    /// Currency of the seller's fee
+    pub seller_fee_currency: String,
+}
+/// Pre-trade risk settings of an account
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct RiskLimits {
+    /// Amount per currency the available funds may go below zero by
+    #[serde(default)]
+    pub credit: BTreeMap<String, Decimal>,
+    /// Whether new orders of the account are rejected
+    #[serde(default)]
+    pub trading_disabled: bool,
+    /// Orders the account may place per second, unset places no limit, see `engine::throttle`
+    #[serde(default)]
+    pub orders_per_second: Option<u32>,
+    /// Orders the account may place at once after being idle, 0 allows one second's worth
+    #[serde(default)]
+    pub order_burst: u32,
+}
+
+/// Account the fees of a tenant are credited to
+pub const FEE_ACCOUNT: u64 = 0;
+
+/// Account balances and order holds of a tenant
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Ledger {
+    /// Balances keyed by account and currency
+    accounts: BTreeMap<u64, BTreeMap<String, Balance>>,
+    /// Holds of open orders keyed by symbol and order ID
+    holds: BTreeMap<String, BTreeMap<String, Hold>>,
+    /// Settlements not yet taken by the engine
+    #[serde(skip)]
+    settled: Vec<Settlement>,
+    /// Accounts whose balances changed since they were last taken
+    #[serde(skip)]
+    touched: BTreeSet<u64>,
+    /// Risk limits of accounts that have any, keyed by account
+    #[serde(default)]
+    risk: BTreeMap<u64, RiskLimits>,
+}
+
+impl Ledger {
+    /// Returns the balance of a currency in an account
+    ///
+    /// # Arguments
+    /// * `account_id` - ID of the account
+    /// * `currency` - Currency code
+    ///
+    /// # Returns
+    /// The balance, zero if the account never held the currency
+    pub fn balance(&self, account_id: u64, currency: &str) -> Balance {
+        self.accounts
+            .get(&account_id)
+            .and_then(|balances| balances.get(currency))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns all balances of an account
+    ///
+    /// # Arguments
+    /// * `account_id` - ID of the account
+    ///
+    /// # Returns
+    /// Balances keyed by currency, None if the account never held funds
+    pub fn balances(&self, account_id: u64) -> Option<&BTreeMap<String, Balance>> {
+        self.accounts.get(&account_id)
+    }
+
+    /// Returns the IDs of all accounts that ever held funds
+    pub fn accounts(&self) -> impl Iterator<Item = u64> + '_ {
+        self.accounts.keys().copied()
+    }
+
+    /// Returns the accounts whose balances changed since the last call
+    pub fn take_touched(&mut self) -> BTreeSet<u64> {
+        std::mem::take(&mut self.touched)
+    }
+
+    /// Returns the risk limits of an account, the defaults if none were set
+    pub fn risk_limits(&self, account_id: u64) -> RiskLimits {
+        self.risk.get(&account_id).cloned().unwrap_or_default()
+    }
+
+    /// Replaces the risk limits of an account
+    ///
+    /// Lowering a credit line never touches funds already held, it only keeps
+    /// the account from placing orders until its available funds recover.
+    ///
+    /// # Arguments
+    /// * `account_id` - ID of the account
+    /// * `limits` - New limits, the defaults drop the account's entry
+    ///
+    /// # Returns
+    /// * `Ok(())` - If the limits were set
+    /// * `Err(String)` - If a credit line is negative
+    pub fn set_risk_limits(&mut self, account_id: u64, limits: RiskLimits) -> Result<(), String> {
+        if let Some((currency, _)) = limits.credit.iter().find(|(_, c)| c.is_sign_negative()) {
+            return Err(format!(
+                "Credit line of {} for account {} is negative",
+                currency, account_id
+            ));
+        }
+        if limits == RiskLimits::default() {
+            self.risk.remove(&account_id);
+        } else {
+            self.risk.insert(account_id, limits);
+        }
+        Ok(())
+    }
+
+    /// Returns the credit line of an account in a currency
+    fn credit(&self, account_id: u64, currency: &str) -> Decimal {
+        self.risk
+            .get(&account_id)
+            .and_then(|limits| limits.credit.get(currency))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the funds held for an order
+    ///
+    /// # Arguments
+    /// * `symbol` - Symbol the order was placed on
+    /// * `order_id` - ID of the order
+    #[allow(unused)]
+    pub fn hold(&self, symbol: &str, order_id: &str) -> Option<&Hold> {
+        self.holds.get(symbol)?.get(order_id)
+    }
+
+    /// Returns the balance of a currency in an account for update
+    fn balance_mut(&mut self, account_id: u64, currency: &str) -> &mut Balance {
+        self.touched.insert(account_id);
+        self.accounts
+            .entry(account_id)
+            .or_default()
+            .entry(currency.to_string())
+            .or_default()
+    }
+
+    /// Credits funds to an account
+    ///
+    /// # Arguments
+    /// * `account_id` - ID of the account
+    /// * `transfer` - Currency and amount to credit
+    ///
+    /// # Returns
+    /// * `Ok(())` - If the funds were credited
+    /// * `Err(String)` - If the amount is not positive
+    pub fn deposit(&mut self, account_id: u64, transfer: &Transfer) -> Result<(), String> {
+        if transfer.amount <= Decimal::ZERO {
+            return Err(format!(
+                "Deposit amount {} is not positive",
+                transfer.amount
+            ));
+        }
+        let balance = self.balance_mut(account_id, &transfer.currency);
+        balance.available = balance
+            .available
+            .checked_add(transfer.amount)
+            .ok_or_else(|| format!("Balance of account {} overflows", account_id))?;
+        Ok(())
+    }
+
+    /// Debits available funds from an account
+    ///
+    /// # Arguments
+    /// * `account_id` - ID of the account
+    /// * `transfer` - Currency and amount to debit
+    ///
+    /// # Returns
+    /// * `Ok(())` - If the funds were debited
+    /// * `Err(String)` - If the amount is not positive or exceeds the available funds
+    pub fn withdraw(&mut self, account_id: u64, transfer: &Transfer) -> Result<(), String> {
+        if transfer.amount <= Decimal::ZERO {
+            return Err(format!(
+                "Withdrawal amount {} is not positive",
+                transfer.amount
+            ));
+        }
+        let available = self.balance(account_id, &transfer.currency).available;
+        if available < transfer.amount {
+            return Err(format!(
+                "Insufficient {} in account {}: {} available, {} requested",
+                transfer.currency, account_id, available, transfer.amount
+            ));
+        }
+        self.balance_mut(account_id, &transfer.currency).available -= transfer.amount;
+        Ok(())
+    }
+
+    /// Credits or debits available funds of an account by a signed amount
+    ///
+    /// # Arguments
+    /// * `account_id` - ID of the account
+    /// * `transfer` - Currency and amount, negative to debit
+    ///
+    /// # Returns
+    /// * `Ok(())` - If the balance was changed
+    /// * `Err(String)` - If the amount is zero or a debit exceeds the available funds
+    pub fn adjust(&mut self, account_id: u64, transfer: &Transfer) -> Result<(), String> {
+        if transfer.amount.is_zero() {
+            return Err("Adjustment amount is zero".to_string());
+        }
+        if transfer.amount > Decimal::ZERO {
+            return self.deposit(account_id, transfer);
+        }
+        let debit = Transfer {
+            amount: -transfer.amount,
+            ..transfer.clone()
+        };
+        self.withdraw(account_id, &debit)
+    }
+
+    /// Holds funds of an account for an order
+    ///
+    /// # Arguments
+    /// * `symbol` - Symbol the order is placed on
+    /// * `order_id` - ID of the order
+    /// * `hold` - Account, currency and amount to hold
+    ///
+    /// # Returns
+    /// * `Ok(())` - If the funds are held
+    /// * `Err(String)` - If the order already holds funds or the account lacks
+    ///   them, counting its credit line
+    pub fn place_hold(&mut self, symbol: &str, order_id: &str, hold: Hold) -> Result<(), String> {
+        if self.hold(symbol, order_id).is_some() {
+            return Err(format!("Order {} already holds funds", order_id));
+        }
+        let available = self.balance(hold.account_id, &hold.currency).available;
+        let credit = self.credit(hold.account_id, &hold.currency);
+        if available + credit < hold.amount {
+            return Err(format!(
+                "Insufficient {} in account {}: {} available, {} credit, {} required",
+                hold.currency, hold.account_id, available, credit, hold.amount
+            ));
+        }
+        let balance = self.balance_mut(hold.account_id, &hold.currency);
+        balance.available -= hold.amount;
+        balance.held += hold.amount;
+        self.holds
+            .entry(symbol.to_string())
+            .or_default()
+            .insert(order_id.to_string(), hold);
+        Ok(())
+    }
+
+    /// Returns what is left of an order's hold to the available funds
+    ///
+    /// Does nothing if the order holds no funds.
+    ///
+    /// # Arguments
+    /// * `symbol` - Symbol the order was placed on
+    /// * `order_id` - ID of the order
+    pub fn release(&mut self, symbol: &str, order_id: &str) {
+        let hold = match self.holds.get_mut(symbol) {
+            Some(holds) => {
+                let hold = holds.remove(order_id);
+                if holds.is_empty() {
+                    self.holds.remove(symbol);
+                }
+                hold
+            }
+            None => None,
+        };
+        if let Some(hold) = hold {
+            let balance = self.balance_mut(hold.account_id, &hold.currency);
+            balance.held -= hold.amount;
+            balance.available += hold.amount;
+        }
+    }
+
+    /// Releases the part of an order's hold above the amount it still needs
+    ///
+    /// # Arguments
+    /// * `symbol` - Symbol the order was placed on
+    /// * `order_id` - ID of the order
+    /// * `needed` - Amount the order can still spend
+    pub fn trim(&mut self, symbol: &str, order_id: &str, needed: Decimal) {
+        let hold = match self.holds.get_mut(symbol).and_then(|h| h.get_mut(order_id)) {
+            Some(hold) if hold.amount > needed => hold,
+            _ => return,
+        };
+        let excess = hold.amount - needed;
+        hold.amount = needed;
+        let (account_id, currency) = (hold.account_id, hold.currency.clone());
+        let balance = self.balance_mut(account_id, &currency);
+        balance.held -= excess;
+        balance.available += excess;
+    }
+
+    /// Sets an order's hold to the amount it needs after it was amended
+    ///
+    /// A smaller amount is released like `trim` does, a larger one is taken from
+    /// the available funds, counting the credit line. Does nothing if the order
+    /// holds no funds.
+    ///
+    /// # Arguments
+    /// * `symbol` - Symbol the order was placed on
+    /// * `order_id` - ID of the order
+    /// * `needed` - Amount the order can spend after the amendment
+    ///
+    /// # Returns
+    /// * `Ok(())` - If the hold covers the amount
+    /// * `Err(String)` - If the account lacks the funds, the hold is unchanged
+    pub fn resize_hold(
+        &mut self,
+        symbol: &str,
+        order_id: &str,
+        needed: Decimal,
+    ) -> Result<(), String> {
+        let Some(hold) = self.hold(symbol, order_id) else {
+            return Ok(());
+        };
+        if needed <= hold.amount {
+            self.trim(symbol, order_id, needed);
+            return Ok(());
+        }
+        let (account_id, currency) = (hold.account_id, hold.currency.clone());
+        let extra = needed - hold.amount;
+        let available = self.balance(account_id, &currency).available;
+        let credit = self.credit(account_id, &currency);
+        if available + credit < extra {
+            return Err(format!(
+                "Insufficient {} in account {}: {} available, {} credit, {} more required",
+                currency, account_id, available, credit, extra
+            ));
+        }
+        let balance = self.balance_mut(account_id, &currency);
+        balance.available -= extra;
+        balance.held += extra;
+        if let Some(hold) = self.holds.get_mut(symbol).and_then(|h| h.get_mut(order_id)) {
+            hold.amount = needed;
+        }
+        Ok(())
+    }
+
+    /// Releases the holds of every order on a symbol, used when it is delisted
+    ///
+    /// # Arguments
+    /// * `symbol` - Symbol whose orders were dropped
+    pub fn release_symbol(&mut self, symbol: &str) {
+        for (_, hold) in self.holds.remove(symbol).unwrap_or_default() {
+            let balance = self.balance_mut(hold.account_id, &hold.currency);
+            balance.held -= hold.amount;
+            balance.available += hold.amount;
+        }
+    }
+
+    /// Settles a trade between the holds of its buy and sell orders
+    ///
+    /// The buyer pays the quote amount out of its hold and receives the base
+    /// quantity, the seller pays the base quantity and receives the quote amount.
+    /// Each side's fee is credited to `FEE_ACCOUNT`, see `fee` for the currency
+    /// it is paid in; the taker pays its taker rate, the maker the maker rate
+    /// recorded with its hold. The settlement is queued for `take_settlements`.
+    ///
+    /// # Arguments
+    /// * `trade` - Trade to settle
+    /// * `taker` - The incoming order that produced the trade
+    /// * `base` - Base currency of the symbol
+    /// * `quote` - Quote currency of the symbol
+    ///
+    /// # Returns
+    /// * `Ok(())` - If the trade was settled
+    /// * `Err(String)` - If an order holds too little, nothing is changed then
+    pub fn settle(
+        &mut self,
+        trade: &Trade,
+        taker: &Order,
+        base: &str,
+        quote: &str,
+    ) -> Result<(), String> {
+        let overflow = || {
+            format!(
+                "Amount of the trade of orders {} and {} overflows",
+                trade.buyer_order_id, trade.seller_order_id
+            )
+        };
+        let amount = trade.total_amount().ok_or_else(overflow)?;
+        let buyer_hold = self.check_hold(&trade.symbol, &trade.buyer_order_id, amount)?;
+        let seller_hold = self.check_hold(&trade.symbol, &trade.seller_order_id, trade.quantity)?;
+        let (buyer, seller) = (buyer_hold.account_id, seller_hold.account_id);
+        let (buyer_rate, seller_rate) = match taker.side {
+            OrderSide::Buy => (taker.taker_fee, seller_hold.maker_fee),
+            OrderSide::Sell => (buyer_hold.maker_fee, taker.taker_fee),
+        };
+        let (buyer_fee_currency, buyer_fee) = self
+            .fee(
+                buyer,
+                buyer_rate,
+                &buyer_hold.fee_currency,
+                (base, trade.quantity),
+                (quote, amount),
+            )
+            .ok_or_else(overflow)?;
+        let (seller_fee_currency, seller_fee) = self
+            .fee(
+                seller,
+                seller_rate,
+                &seller_hold.fee_currency,
+                (quote, amount),
+                (base, trade.quantity),
+            )
+            .ok_or_else(overflow)?;
+
+        self.spend(&trade.symbol, &trade.buyer_order_id, amount);
+        self.spend(&trade.symbol, &trade.seller_order_id, trade.quantity);
+        self.balance_mut(buyer, base).available += trade.quantity;
+        self.balance_mut(seller, quote).available += amount;
+        for (account_id, currency, fee) in [
+            (buyer, &buyer_fee_currency, buyer_fee),
+            (seller, &seller_fee_currency, seller_fee),
+        ] {
+            if !fee.is_zero() {
+                self.balance_mut(account_id, currency).available -= fee;
+                self.balance_mut(FEE_ACCOUNT, currency).available += fee;
+            }
+        }
+        self.settled.push(Settlement {
+            symbol: trade.symbol.clone(),
+            buyer_order_id: trade.buyer_order_id.clone(),
+            buyer_account_id: buyer,
+            seller_order_id: trade.seller_order_id.clone(),
+            seller_account_id: seller,
+            taker_side: taker.side,
+            price: trade.price,
+            quantity: trade.quantity,
+            amount,
+            base: base.to_string(),
+            quote: quote.to_string(),
+            buyer_fee,
+            buyer_fee_currency,
+            seller_fee,
+            seller_fee_currency,
+            ..Default::default()
+        });
+        Ok(())
+    }
+
+    /// Computes the fee of one side of a trade
+    ///
+    /// The fee is taken from what the side receives, unless its fee currency is
+    /// the one it pays and its available funds, counting its credit line, cover
+    /// the fee in that currency. Rebates are paid in the fee currency too.
+    ///
+    /// # Arguments
+    /// * `account_id` - Account of the side
+    /// * `rate` - Fee rate of the side, negative for a rebate
+    /// * `fee_currency` - Currency the side pays fees in, empty for what it receives
+    /// * `received` - Currency and amount the side receives
+    /// * `paid` - Currency and amount the side pays
+    ///
+    /// # Returns
+    /// The currency and amount of the fee, None if it overflows
+    fn fee(
+        &self,
+        account_id: u64,
+        rate: Decimal,
+        fee_currency: &str,
+        received: (&str, Decimal),
+        paid: (&str, Decimal),
+    ) -> Option<(String, Decimal)> {
+        if !fee_currency.is_empty() && fee_currency == paid.0 {
+            let fee = paid.1.checked_mul(rate)?;
+            let available = self.balance(account_id, paid.0).available;
+            if fee <= available + self.credit(account_id, paid.0) {
+                return Some((paid.0.to_string(), fee));
+            }
+        }
+        Some((received.0.to_string(), received.1.checked_mul(rate)?))
+    }
+
+    /// Returns the settlements of the trades settled since the last call
+    pub fn take_settlements(&mut self) -> Vec<Settlement> {
+        std::mem::take(&mut self.settled)
+    }
+
+    /// Checks that an order holds at least an amount
+    ///
+    /// # Returns
+    /// The hold of the order, with its account and fee settings
+    fn check_hold(&self, symbol: &str, order_id: &str, amount: Decimal) -> Result<&Hold, String> {
+        match self.hold(symbol, order_id) {
+            Some(hold) if hold.amount >= amount => Ok(hold),
+            Some(hold) => Err(format!(
+                "Order {} holds {} {}, {} needed",
+                order_id, hold.amount, hold.currency, amount
+            )),
+            None => Err(format!("Order {} holds no funds", order_id)),
+        }
+    }
+
+    /// Takes an amount out of an order's hold, see `check_hold`
+    fn spend(&mut self, symbol: &str, order_id: &str, amount: Decimal) {
+        let hold = self
+            .holds
+            .get_mut(symbol)
+            .and_then(|holds| holds.get_mut(order_id))
+            .expect("hold checked before spending");
+        hold.amount -= amount;
+        let (account_id, currency) = (hold.account_id, hold.currency.clone());
+        self.balance_mut(account_id, &currency).held -= amount;
+    }
+}
+
+/// Computes the quote amount a market buy pays for a quantity at the current book
+///
+/// Walks the asks in matching order, so the result is exactly what the order
+/// spends when it is matched right after.
+///
+/// # Arguments
+/// * `book` - Order book of the symbol
+/// * `quantity` - Quantity to buy
+///
+/// # Returns
+/// The quote amount, None if it overflows
+pub fn market_buy_cost(book: &OrderBook, quantity: Decimal) -> Option<Decimal> {
+    let mut left = quantity;
+    let mut cost = Decimal::ZERO;
+    for (price, orders) in &book.asks {
+        for order in orders {
+            if left <= Decimal::ZERO {
+                return Some(cost);
+            }
+            let take = left.min(order.remaining_quantity());
+            cost = cost.checked_add(price.checked_mul(take)?)?;
+            left -= take;
+        }
+    }
+    Some(cost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::entry::{OrderType, Symbol};
+    use crate::engine::tenant::Tenant;
+    use rust_decimal_macros::dec;
+
+    fn transfer(currency: &str, amount: Decimal) -> Transfer {
+        Transfer {
+            currency: currency.to_string(),
+            amount,
+            ..Default::default()
+        }
+    }
+
+    fn balance(available: Decimal, held: Decimal) -> Balance {
+        Balance { available, held }
+    }
+
+    fn order(id: &str, order_type: OrderType, side: OrderSide, price: &str, qty: &str) -> Order {
+        Order::new(
+            id.to_string(),
+            "BTCUSDT".to_string(),
+            order_type,
+            side,
+            price.to_string(),
+            qty.to_string(),
+        )
+    }
+
+    #[test]
+    fn withdrawals_never_touch_held_funds() {
+        let mut ledger = Ledger::default();
+        ledger.deposit(1, &transfer("USDT", dec!(100))).unwrap();
+        assert!(ledger.deposit(1, &transfer("USDT", dec!(-1))).is_err());
+        ledger
+            .place_hold(
+                "BTCUSDT",
+                "1",
+                Hold {
+                    account_id: 1,
+                    currency: "USDT".to_string(),
+                    amount: dec!(60),
+                    maker_fee: Decimal::ZERO,
+                    fee_currency: String::new(),
+                },
+            )
+            .unwrap();
+        assert!(ledger.withdraw(1, &transfer("USDT", dec!(50))).is_err());
+        ledger.withdraw(1, &transfer("USDT", dec!(40))).unwrap();
+        assert_eq!(ledger.balance(1, "USDT"), balance(dec!(0), dec!(60)));
+
+        ledger.release("BTCUSDT", "1");
+        assert_eq!(ledger.balance(1, "USDT"), balance(dec!(60), dec!(0)));
+        assert!(ledger.hold("BTCUSDT", "1").is_none());
+    }
+
+    #[test]
+    fn changed_accounts_are_taken_once() {
+        let mut ledger = Ledger::default();
+        ledger.deposit(2, &transfer("USDT", dec!(10))).unwrap();
+        ledger.deposit(1, &transfer("BTC", dec!(1))).unwrap();
+        ledger.deposit(2, &transfer("BTC", dec!(1))).unwrap();
+        assert_eq!(
+            ledger.take_touched().into_iter().collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert!(ledger.take_touched().is_empty());
+
+        let balances = ledger.balances(2).unwrap();
+        assert_eq!(balances.keys().collect::<Vec<_>>(), ["BTC", "USDT"]);
+        assert!(ledger.balances(3).is_none());
+        assert_eq!(ledger.accounts().collect::<Vec<_>>(), [1, 2]);
+    }
+
+    fn settling_tenant() -> Tenant {
+        settling_tenant_paying_fees_in("")
+    }
+
+    fn settling_tenant_paying_fees_in(fee_currency: &str) -> Tenant {
+        let mut tenant = Tenant::new("default".to_string());
+        let mut symbol = Symbol::new(
+            "BTCUSDT".to_string(),
+            "BTC".to_string(),
+            "USDT".to_string(),
+            2,
+            4,
+            dec!(0.01),
+            dec!(1000000),
+            dec!(0.0001),
+            dec!(1000),
+        );
+        symbol.settle_balances = true;
+        symbol.fee_currency = fee_currency.to_string();
+        tenant.spot_processor.add_symbol(symbol).unwrap();
+        tenant
+    }
+
+    #[test]
+    fn orders_hold_and_settle_balances() {
+        let mut tenant = settling_tenant();
+        tenant
+            .ledger
+            .deposit(1, &transfer("USDT", dec!(1000)))
+            .unwrap();
+        tenant.ledger.deposit(2, &transfer("BTC", dec!(3))).unwrap();
+
+        // Unfunded orders are rejected without reaching the book
+        let oversized = order("9", OrderType::Limit, OrderSide::Sell, "100", "4");
+        assert!(tenant.place_order(2, &oversized).is_err());
+        assert!(tenant.ledger.hold("BTCUSDT", "9").is_none());
+
+        let ask = order("1", OrderType::Limit, OrderSide::Sell, "90", "2");
+        tenant.place_order(2, &ask).unwrap();
+        assert_eq!(tenant.ledger.balance(2, "BTC"), balance(dec!(1), dec!(2)));
+
+        // The bid holds its full limit amount and trades below it at the ask price
+        let bid = order("2", OrderType::Limit, OrderSide::Buy, "100", "3");
+        let trades = tenant.place_order(1, &bid).unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(tenant.ledger.balance(1, "BTC"), balance(dec!(2), dec!(0)));
+        assert_eq!(
+            tenant.ledger.balance(1, "USDT"),
+            balance(dec!(720), dec!(100))
+        );
+        assert_eq!(tenant.ledger.balance(2, "BTC"), balance(dec!(1), dec!(0)));
+        assert_eq!(
+            tenant.ledger.balance(2, "USDT"),
+            balance(dec!(180), dec!(0))
+        );
+        assert!(tenant.ledger.hold("BTCUSDT", "1").is_none());
+
+        // Cancelling the rest of the bid releases what it still holds
+        tenant.cancel_order("BTCUSDT", "2").unwrap();
+        assert_eq!(
+            tenant.ledger.balance(1, "USDT"),
+            balance(dec!(820), dec!(0))
+        );
+        assert!(tenant.ledger.hold("BTCUSDT", "2").is_none());
+    }
+
+    #[test]
+    fn risk_limits_extend_credit_and_stop_trading() {
+        let mut tenant = settling_tenant();
+        tenant
+            .ledger
+            .deposit(1, &transfer("USDT", dec!(50)))
+            .unwrap();
+        let bid = order("1", OrderType::Limit, OrderSide::Buy, "100", "1");
+        assert!(tenant.place_order(1, &bid).is_err());
+
+        let limits = RiskLimits {
+            credit: BTreeMap::from([("USDT".to_string(), dec!(60))]),
+            ..Default::default()
+        };
+        tenant.ledger.set_risk_limits(1, limits).unwrap();
+        tenant.place_order(1, &bid).unwrap();
+        assert_eq!(
+            tenant.ledger.balance(1, "USDT"),
+            balance(dec!(-50), dec!(100))
+        );
+        // Funds owed cannot be withdrawn
+        assert!(tenant
+            .ledger
+            .withdraw(1, &transfer("USDT", dec!(1)))
+            .is_err());
+
+        let halted = RiskLimits {
+            trading_disabled: true,
+            ..Default::default()
+        };
+        tenant.ledger.set_risk_limits(2, halted).unwrap();
+        tenant.ledger.deposit(2, &transfer("BTC", dec!(1))).unwrap();
+        let ask = order("2", OrderType::Limit, OrderSide::Sell, "100", "1");
+        assert!(tenant.place_order(2, &ask).is_err());
+        tenant
+            .ledger
+            .set_risk_limits(2, RiskLimits::default())
+            .unwrap();
+        assert_eq!(tenant.place_order(2, &ask).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn fees_go_to_the_fee_account_and_nothing_is_lost() {
+        let mut tenant = settling_tenant();
+        tenant
+            .ledger
+            .deposit(1, &transfer("USDT", dec!(1000)))
+            .unwrap();
+        tenant.ledger.deposit(2, &transfer("BTC", dec!(2))).unwrap();
+        let mut bid = order("1", OrderType::Limit, OrderSide::Buy, "100", "2");
+        bid.maker_fee = dec!(0.001);
+        tenant.place_order(1, &bid).unwrap();
+        let mut ask = order("2", OrderType::Limit, OrderSide::Sell, "100", "2");
+        ask.taker_fee = dec!(0.002);
+        tenant.place_order(2, &ask).unwrap();
+
+        let ledger = &mut tenant.ledger;
+        assert_eq!(ledger.balance(1, "BTC"), balance(dec!(1.998), dec!(0)));
+        assert_eq!(ledger.balance(1, "USDT"), balance(dec!(800), dec!(0)));
+        assert_eq!(ledger.balance(2, "USDT"), balance(dec!(199.6), dec!(0)));
+        assert_eq!(ledger.balance(2, "BTC"), balance(dec!(0), dec!(0)));
+        assert_eq!(
+            ledger.balance(FEE_ACCOUNT, "BTC"),
+            balance(dec!(0.002), dec!(0))
+        );
+        assert_eq!(
+            ledger.balance(FEE_ACCOUNT, "USDT"),
+            balance(dec!(0.4), dec!(0))
+        );
+        for (currency, deposited) in [("BTC", dec!(2)), ("USDT", dec!(1000))] {
+            let total: Decimal = [FEE_ACCOUNT, 1, 2]
+                .iter()
+                .map(|account| {
+                    let balance = ledger.balance(*account, currency);
+                    balance.available + balance.held
+                })
+                .sum();
+            assert_eq!(total, deposited, "{} total", currency);
+        }
+
+        let settlements = ledger.take_settlements();
+        assert_eq!(settlements.len(), 1);
+        assert_eq!(settlements[0].taker_side, OrderSide::Sell);
+        assert_eq!(settlements[0].buyer_account_id, 1);
+        assert_eq!(settlements[0].seller_account_id, 2);
+        assert_eq!(settlements[0].amount, dec!(200));
+        assert_eq!(settlements[0].buyer_fee, dec!(0.002));
+        assert_eq!(settlements[0].seller_fee, dec!(0.4));
+        assert!(ledger.take_settlements().is_empty());
+    }
+
+    #[test]
+    fn fees_in_the_fee_currency_and_maker_rebates() {
+        let mut tenant = settling_tenant_paying_fees_in("USDT");
+        tenant
+            .ledger
+            .deposit(1, &transfer("USDT", dec!(1000)))
+            .unwrap();
+        tenant.ledger.deposit(2, &transfer("BTC", dec!(3))).unwrap();
+        tenant
+            .ledger
+            .deposit(3, &transfer("USDT", dec!(100)))
+            .unwrap();
+        let mut ask = order("1", OrderType::Limit, OrderSide::Sell, "100", "3");
+        ask.maker_fee = dec!(-0.0001);
+        tenant.place_order(2, &ask).unwrap();
+        let mut bid = order("2", OrderType::Limit, OrderSide::Buy, "100", "2");
+        bid.taker_fee = dec!(0.002);
+        tenant.place_order(1, &bid).unwrap();
+        // Account 3 cannot pay the fee in USDT besides the trade, so it pays in BTC
+        let mut bid = order("3", OrderType::Limit, OrderSide::Buy, "100", "1");
+        bid.taker_fee = dec!(0.002);
+        tenant.place_order(3, &bid).unwrap();
+
+        let ledger = &mut tenant.ledger;
+        assert_eq!(ledger.balance(1, "BTC"), balance(dec!(2), dec!(0)));
+        assert_eq!(ledger.balance(1, "USDT"), balance(dec!(799.6), dec!(0)));
+        assert_eq!(ledger.balance(2, "USDT"), balance(dec!(300.03), dec!(0)));
+        assert_eq!(ledger.balance(3, "BTC"), balance(dec!(0.998), dec!(0)));
+        assert_eq!(ledger.balance(3, "USDT"), balance(dec!(0), dec!(0)));
+        assert_eq!(
+            ledger.balance(FEE_ACCOUNT, "USDT"),
+            balance(dec!(0.37), dec!(0))
+        );
+        assert_eq!(
+            ledger.balance(FEE_ACCOUNT, "BTC"),
+            balance(dec!(0.002), dec!(0))
+        );
+
+        let settlements = ledger.take_settlements();
+        let fees: Vec<_> = settlements
+            .iter()
+            .map(|s| {
+                (
+                    s.buyer_fee,
+                    s.buyer_fee_currency.as_str(),
+                    s.seller_fee,
+                    s.seller_fee_currency.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            fees,
+            [
+                (dec!(0.4), "USDT", dec!(-0.02), "USDT"),
+                (dec!(0.002), "BTC", dec!(-0.01), "USDT"),
+            ]
+        );
+    }
+}
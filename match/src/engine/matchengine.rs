@@ -2,11 +2,30 @@
 //!
 //! This module implements the core matching engine functionality for processing orders and symbols.
 //! It handles order placement, cancellation, and symbol management through a state machine interface.
+//! State is partitioned by tenant, and every command is routed to the tenant it names.
 
 pub use super::entry::{Order, Symbol};
-pub use super::spot::OrderProcessor;
+pub use super::funding::{FundingKind, FundingRecord};
+pub use super::history::{Cancellation, CommandRecord, OrderChange, OrderEvent};
+pub use super::ledger::{RiskLimits, Settlement, Transfer};
+pub use super::perp::MarkPrice;
+pub use super::tenant::{Tenant, DEFAULT_TENANT};
 
+use super::circuit_breaker::Halt;
+use super::client_orders::ClientOrder;
+use super::clock::Hlc;
+use super::entry::TimeInForce;
+use super::timers::{TimerAction, Timers};
+use super::workers::{Action, Job, Outcome, Queued, Workers};
+use super::{codec, history, snapshot};
+use crate::{memory, metrics, read_view, symbol_stats};
+
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Represents the different types of commands that can be processed by the match engine
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -22,18 +41,131 @@ pub enum MatchCmdType {
     UpdateSymbol,
     /// Remove a symbol from trading
     RemoveSymbol,
+    /// Credit funds to the envelope's account
+    Deposit,
+    /// Debit available funds from the envelope's account
+    Withdraw,
+    /// Credit or debit the envelope's account by a signed amount
+    Adjust,
+    /// Replace the risk limits of the envelope's account
+    SetRiskLimits,
+    /// Record the mark price of a perpetual contract
+    SetMarkPrice,
+    /// Inject a liquidation order into a perpetual contract
+    Liquidate,
+    /// Move engine time forward so due timers fire, see `engine::timers`
+    Tick,
+    /// Change the price or quantity of a resting order
+    AmendOrder,
+    /// Place the orders of a batch one after the other, all of them or none, see
+    /// `MatchCmd::orders`
+    Batch,
+    /// Cancel every open order of the envelope's account, on the symbol of the
+    /// order if one is given, on all symbols of the market otherwise
+    CancelAllOrders,
+    /// Cancel the order of the command and place its replacement, the one order
+    /// of `MatchCmd::orders`, at the same index
+    ReplaceOrder,
+    /// Halt trading in the symbol of the command until an operator resumes it
+    HaltSymbol,
+    /// Resume trading in the halted symbol of the command
+    ResumeSymbol,
+    /// Cancel every order on the symbol of the command, of every account
+    MassCancel,
+}
+
+/// Market a command addresses
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MarketType {
+    /// Spot symbols, see `engine::spot`
+    #[default]
+    Spot,
+    /// Perpetual contracts, see `engine::perp`
+    Perp,
+}
+
+impl MarketType {
+    /// Returns the market symbols must not clash with when listed on this one
+    pub fn other(self) -> MarketType {
+        match self {
+            MarketType::Spot => MarketType::Perp,
+            MarketType::Perp => MarketType::Spot,
+        }
+    }
 }
 
 /// Command structure for interacting with the match engine
-/// Contains the command type and associated data (order or symbol)
+/// Contains the command type and associated data (order, symbol or transfer)
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct MatchCmd {
     /// The type of command to execute
     pub cmd: MatchCmdType,
+    /// Tenant the command is scoped to
+    pub tenant: String,
     /// Optional order data for order-related commands
     pub order: Option<Order>,
     /// Optional symbol data for symbol-related commands
     pub symbol: Option<Symbol>,
+    /// Optional transfer data for balance commands
+    pub transfer: Option<Transfer>,
+    /// Optional risk limits for risk commands
+    #[serde(default)]
+    pub risk: Option<RiskLimits>,
+    /// Market the order and symbol commands address
+    #[serde(default)]
+    pub market: MarketType,
+    /// Optional mark price for mark price commands
+    #[serde(default)]
+    pub mark_price: Option<MarkPrice>,
+    /// Orders of a batch command, or the replacement of a replace command, all of
+    /// the envelope's account and market
+    #[serde(default)]
+    pub orders: Vec<Order>,
+}
+
+/// Envelope wrapping every command proposed through raft
+///
+/// Carries the metadata needed for duplicate suppression, tracing and
+/// deterministic time alongside the command itself.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CommandEnvelope {
+    /// Unique ID of the client request, retries reuse the same ID
+    pub request_id: String,
+    /// ID of the client that sent the request
+    pub client_id: String,
+    /// Account the request was made for, 0 if not account scoped
+    pub account_id: u64,
+    /// Wall clock of the proposing leader in milliseconds since the epoch
+    pub proposed_at: u64,
+    /// Hybrid logical clock timestamp of the proposing leader, 0 if the command is
+    /// timed by `proposed_at` alone, see `engine::clock`
+    pub hlc: u64,
+    /// The command to apply
+    pub cmd: MatchCmd,
+}
+
+impl CommandEnvelope {
+    /// Wraps a command into an envelope stamped with the local wall clock
+    ///
+    /// # Arguments
+    /// * `request_id` - Unique ID of the client request
+    /// * `client_id` - ID of the client that sent the request
+    /// * `account_id` - Account the request was made for
+    /// * `cmd` - The command to wrap
+    pub fn new(request_id: String, client_id: String, account_id: u64, cmd: MatchCmd) -> Self {
+        let proposed_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        Self {
+            request_id,
+            client_id,
+            account_id,
+            proposed_at,
+            hlc: 0,
+            cmd,
+        }
+    }
 }
 
 /// The main match engine implementation
@@ -42,72 +174,1464 @@ pub struct MatchCmd {
 pub struct MatchEngine {
     /// Current index/version of the engine state
     index: u64,
-    /// Processor for handling spot market orders
-    spot_processor: OrderProcessor,
+    /// Tenant namespaces keyed by tenant ID
+    tenants: BTreeMap<String, Tenant>,
+    /// Hybrid logical clock of the last command stamped with one
+    #[serde(default)]
+    clock: Hlc,
+    /// Timers due at a later engine time
+    #[serde(default)]
+    timers: Timers,
+    /// Features the applied commands relied on, kept in the snapshot envelope
+    /// rather than the state, see `engine::snapshot`
+    #[serde(skip)]
+    features: BTreeSet<&'static str>,
+    /// Order books changed since the last metrics flush, keyed by tenant and symbol
+    #[serde(skip)]
+    touched_books: BTreeSet<(String, String)>,
+    /// Settlements of the trades applied since they were last taken
+    #[serde(skip)]
+    settlements: Vec<Settlement>,
+    /// Audit records of the funding commands applied since they were last taken
+    #[serde(skip)]
+    funding_events: Vec<FundingRecord>,
+    /// Changes of orders made by the commands applied since they were last taken
+    #[serde(skip)]
+    order_events: Vec<OrderEvent>,
+    /// Orders canceled by the cancel-all and replace commands applied since they
+    /// were last published
+    #[serde(skip)]
+    cancellations: Vec<Cancellation>,
+    /// Whether applied commands are recorded for the audit trail
+    #[serde(skip)]
+    record_commands: bool,
+    /// Commands applied since they were last taken, if they are recorded
+    #[serde(skip)]
+    commands: Vec<CommandRecord>,
+    /// Workers commands of single symbols are applied on, None applies every
+    /// command in `on_message`
+    #[serde(skip)]
+    workers: Option<Arc<Workers>>,
+    /// Commands queued for the workers since the last barrier, in log order
+    #[serde(skip)]
+    pending: Vec<Queued>,
+    /// Order, cancel and trade counts since the last metrics flush
+    #[serde(skip)]
+    order_tally: metrics::OrderTally,
 }
 
 impl MatchEngine {
     /// Creates a new instance of the match engine
-    /// Initializes with default state and a new order processor
+    /// Initializes with default state and no tenants
     pub fn new() -> MatchEngine {
         MatchEngine {
             index: 0,
-            spot_processor: OrderProcessor::new(),
+            tenants: BTreeMap::new(),
+            clock: Hlc::default(),
+            timers: Timers::default(),
+            features: BTreeSet::new(),
+            touched_books: BTreeSet::new(),
+            settlements: Vec::new(),
+            funding_events: Vec::new(),
+            order_events: Vec::new(),
+            cancellations: Vec::new(),
+            record_commands: false,
+            commands: Vec::new(),
+            workers: None,
+            pending: Vec::new(),
+            order_tally: metrics::OrderTally::default(),
+        }
+    }
+
+    /// Creates a match engine that applies the orders and cancels of symbols
+    /// without balances on worker threads, see `engine::workers`
+    ///
+    /// # Arguments
+    /// * `count` - Number of worker threads, 0 applies every command in `on_message`
+    /// * `cores` - Cores the worker threads are pinned to, empty leaves them unpinned
+    pub fn with_workers(count: usize, cores: &[usize]) -> MatchEngine {
+        MatchEngine {
+            workers: (count > 0).then(|| Arc::new(Workers::start(count, cores))),
+            ..MatchEngine::new()
         }
     }
 
+    /// Records every applied command from now on, see `take_commands`
+    ///
+    /// # Arguments
+    /// * `record` - Whether commands are recorded
+    pub fn record_commands(&mut self, record: bool) {
+        self.record_commands = record;
+    }
+
+    /// Returns the tenant with the given ID, creating it on first use
+    ///
+    /// # Arguments
+    /// * `tenants` - Tenant namespaces of the engine
+    /// * `id` - Tenant ID, an empty ID maps to the default tenant
+    fn tenant_mut<'a>(tenants: &'a mut BTreeMap<String, Tenant>, id: &str) -> &'a mut Tenant {
+        let id = if id.is_empty() { DEFAULT_TENANT } else { id };
+        tenants
+            .entry(id.to_string())
+            .or_insert_with(|| Tenant::new(id.to_string()))
+    }
+
+    /// Retrieves a tenant by its ID
+    ///
+    /// # Arguments
+    /// * `id` - Tenant ID
+    ///
+    /// # Returns
+    /// Reference to the tenant if it exists, None otherwise
+    #[allow(unused)]
+    pub fn get_tenant(&self, id: &str) -> Option<&Tenant> {
+        self.tenants.get(id)
+    }
+
     /// Processes an incoming message/command
     ///
+    /// Commands whose request ID was already applied are skipped, and all engine
+    /// timestamps are taken from the envelope so replicas stay identical. Commands
+    /// stamped with a hybrid logical clock timestamp move the engine clock, whose
+    /// time never goes back, and fire the timers due by then before they are
+    /// applied; older commands are timed by `proposed_at` alone.
+    ///
+    /// With workers, orders and cancels of symbols without balances are queued
+    /// until the next barrier, every other command runs the barrier first.
+    /// Orders above the order rate of their account are rejected in order, see
+    /// `engine::throttle`. The orders of a batch are applied in order one after
+    /// the other at the batch's index, so no other command comes between them;
+    /// each is throttled, resolved and reported like a single order. Commands
+    /// that decode are recorded for the audit trail if `record_commands` is set,
+    /// duplicates included.
+    ///
     /// # Arguments
     /// * `index` - The new index/version number for this state update
-    /// * `data` - Serialized command data to process
-    pub fn on_message(&mut self, index: u64, data: &[u8]) {
+    /// * `data` - Encoded command envelope to process, see `engine::codec`
+    ///
+    /// # Returns
+    /// Returns why the data could not be decoded, the engine is left unchanged
+    /// but for its index
+    pub fn on_message(&mut self, index: u64, data: &[u8]) -> Result<(), String> {
         log::debug!("on_message: len {}", data.len());
+        self.process(index, data)
+    }
+
+    /// Decodes and applies, or queues, a command
+    ///
+    /// Same as `on_message` without its log line, for batches that are logged
+    /// once as a whole; counters and events are published per batch either
+    /// way, see `flush_book_metrics`.
+    ///
+    /// # Arguments
+    /// * `index` - Raft index of the command
+    /// * `data` - Encoded command envelope
+    ///
+    /// # Returns
+    /// Returns why the data could not be decoded
+    pub fn process(&mut self, index: u64, data: &[u8]) -> Result<(), String> {
         self.index = index;
-        let cmd: Result<MatchCmd, bincode::Error> = bincode::deserialize(data);
-        match cmd {
-            Ok(cmd) => match cmd.cmd {
-                MatchCmdType::PlaceOrder => {
-                    let _ = self.spot_processor.place_order(&cmd.order.unwrap());
+        let mut envelope =
+            codec::decode(data).map_err(|e| format!("failed to deserialize match cmd: {}", e))?;
+        self.features.extend(codec::required_features(&envelope));
+        let now_ms = if envelope.hlc > 0 {
+            self.clock = self.clock.advance(Hlc(envelope.hlc));
+            self.fire_timers(index, self.clock.millis());
+            self.clock.millis()
+        } else {
+            envelope.proposed_at
+        };
+        let tenant = Self::tenant_mut(&mut self.tenants, &envelope.cmd.tenant);
+        let duplicate = !envelope.request_id.is_empty()
+            && tenant.dedupe.check_and_insert(&envelope.request_id, now_ms);
+        if self.record_commands {
+            self.commands.push(CommandRecord {
+                index,
+                tenant: tenant.id.clone(),
+                request_id: envelope.request_id.clone(),
+                client_id: envelope.client_id.clone(),
+                account_id: envelope.account_id,
+                proposed_at: envelope.proposed_at,
+                time_ms: now_ms,
+                cmd: envelope.cmd.cmd.clone(),
+                duplicate,
+                data: data.to_vec(),
+            });
+        }
+        if duplicate {
+            log::warn!(
+                "skip duplicate request {} from client {} at index {}",
+                envelope.request_id,
+                envelope.client_id,
+                index
+            );
+            return Ok(());
+        }
+        let sequence = tenant.next_sequence();
+        log::debug!(
+            "apply request {} tenant {} sequence {} index {}",
+            envelope.request_id,
+            tenant.id,
+            sequence,
+            index
+        );
+        if let MatchCmdType::Batch = envelope.cmd.cmd {
+            self.barrier();
+            self.place_batch(index, envelope, now_ms);
+            return Ok(());
+        }
+        if let MatchCmdType::ReplaceOrder = envelope.cmd.cmd {
+            self.barrier();
+            self.replace(index, envelope, now_ms);
+            return Ok(());
+        }
+        self.dispatch(index, envelope, now_ms, true);
+        Ok(())
+    }
+
+    /// Cancels an order and places its replacement, both at the same index
+    ///
+    /// The replacement is placed only if the order it replaces is open, so an
+    /// order that filled before the replace applied is not followed by a second
+    /// one; otherwise the replacement is rejected. Neither goes to the workers.
+    /// The status of the replacement, or why it was rejected, is published with
+    /// the cancellation for the reply, see `read_view::cancellation`.
+    ///
+    /// # Arguments
+    /// * `index` - Raft index of the command
+    /// * `envelope` - The replace command
+    /// * `now_ms` - Engine time of the command in milliseconds
+    fn replace(&mut self, index: u64, envelope: CommandEnvelope, now_ms: u64) {
+        let mut place = envelope;
+        let replaced = place.cmd.order.take();
+        let replacement = place.cmd.orders.pop();
+        let mut cancel = place.clone();
+        cancel.cmd.cmd = MatchCmdType::CancelOrder;
+        cancel.cmd.order = replaced;
+        place.cmd.cmd = MatchCmdType::PlaceOrder;
+        place.cmd.order = replacement;
+
+        // Resolving the client order ID of a cancel never fails
+        let _ = self.resolve_client_order(index, &mut cancel);
+        let tenant = Self::tenant_mut(&mut self.tenants, &cancel.cmd.tenant);
+        let order = cancel.cmd.order.as_ref().unwrap();
+        let open = !order.id.is_empty() && tenant.is_open(&order.symbol, &order.id);
+        let mut cancellation = Cancellation {
+            tenant: tenant.id.clone(),
+            request_id: cancel.request_id.clone(),
+            ..Default::default()
+        };
+        let first = self.order_events.len();
+        let rejection = if open {
+            cancellation.order_ids.push(order.id.clone());
+            self.dispatch(index, cancel, now_ms, false);
+            self.dispatch(index, place, now_ms, false)
+        } else {
+            let name = if order.id.is_empty() {
+                &order.client_order_id
+            } else {
+                &order.id
+            };
+            let reason = format!("Order {} to replace is not open", name);
+            self.reject_order(index, place, now_ms, reason.clone());
+            Some(reason)
+        };
+        // The replacement is the first order placed after the cancel
+        cancellation.replacement = self.order_events[first..]
+            .iter()
+            .find_map(|event| match &event.change {
+                OrderChange::Placed { order, .. } => Some(order.status),
+                _ => None,
+            });
+        cancellation.reject_reason = rejection.unwrap_or_default();
+        self.cancellations.push(cancellation);
+    }
+
+    /// Places the orders of a batch one after the other, all of them or none
+    ///
+    /// The tenant, timers and order counters are saved before the first order.
+    /// Once an order is rejected they are restored, the settlements, events and
+    /// cancellations of the orders placed before it are dropped, and every order
+    /// of the batch is rejected instead. Saving the tenant shares its order books,
+    /// so a book the batch changes is copied once. None goes to the workers.
+    ///
+    /// # Arguments
+    /// * `index` - Raft index of the command
+    /// * `envelope` - The batch command
+    /// * `now_ms` - Engine time of the command in milliseconds
+    fn place_batch(&mut self, index: u64, envelope: CommandEnvelope, now_ms: u64) {
+        let tenant_id = envelope.cmd.tenant.clone();
+        let members = Self::batch_members(envelope);
+        let tenant = Self::tenant_mut(&mut self.tenants, &tenant_id).clone();
+        let (timers, order_tally) = (self.timers.clone(), self.order_tally.clone());
+        let settlements = self.settlements.len();
+        let order_events = self.order_events.len();
+        let cancellations = self.cancellations.len();
+        let rejected = members.iter().find_map(|member| {
+            let order_id = &member.cmd.order.as_ref().unwrap().id;
+            self.dispatch(index, member.clone(), now_ms, false)
+                .map(|reason| format!("Order {} of the batch was rejected: {}", order_id, reason))
+        });
+        let Some(reason) = rejected else {
+            return;
+        };
+        log::debug!("reject batch at index {}: {}", index, reason);
+        *Self::tenant_mut(&mut self.tenants, &tenant_id) = tenant;
+        self.timers = timers;
+        self.order_tally = order_tally;
+        self.settlements.truncate(settlements);
+        self.order_events.truncate(order_events);
+        self.cancellations.truncate(cancellations);
+        for member in members {
+            self.reject_order(index, member, now_ms, reason.clone());
+        }
+    }
+
+    /// Splits a batch into one order command per order, in batch order
+    ///
+    /// Every order keeps the batch's request ID, account, market and times.
+    ///
+    /// # Arguments
+    /// * `envelope` - The batch command
+    ///
+    /// # Returns
+    /// The order commands of the batch
+    fn batch_members(envelope: CommandEnvelope) -> Vec<CommandEnvelope> {
+        let CommandEnvelope {
+            request_id,
+            client_id,
+            account_id,
+            proposed_at,
+            hlc,
+            cmd,
+        } = envelope;
+        cmd.orders
+            .into_iter()
+            .map(|order| CommandEnvelope {
+                request_id: request_id.clone(),
+                client_id: client_id.clone(),
+                account_id,
+                proposed_at,
+                hlc,
+                cmd: MatchCmd {
+                    cmd: MatchCmdType::PlaceOrder,
+                    tenant: cmd.tenant.clone(),
+                    order: Some(Order {
+                        account_id,
+                        ..order
+                    }),
+                    market: cmd.market,
+                    ..Default::default()
+                },
+            })
+            .collect()
+    }
+
+    /// Applies, or queues, a command that is not a duplicate
+    ///
+    /// # Arguments
+    /// * `index` - Raft index of the command
+    /// * `envelope` - The command and its metadata
+    /// * `now_ms` - Engine time of the command in milliseconds
+    /// * `queue` - Whether the command may be queued for the workers
+    ///
+    /// # Returns
+    /// Why the order of a place command was rejected, None if it was placed or
+    /// queued or the command places no order
+    fn dispatch(
+        &mut self,
+        index: u64,
+        mut envelope: CommandEnvelope,
+        now_ms: u64,
+        queue: bool,
+    ) -> Option<String> {
+        if let (MatchCmdType::PlaceOrder, Some(order)) =
+            (&envelope.cmd.cmd, envelope.cmd.order.as_mut())
+        {
+            order.account_id = envelope.account_id;
+        }
+        let tenant = Self::tenant_mut(&mut self.tenants, &envelope.cmd.tenant);
+        if let (MatchCmdType::PlaceOrder, true) = (&envelope.cmd.cmd, now_ms > 0) {
+            let limits = tenant.ledger.risk_limits(envelope.account_id);
+            if let Err(reason) = tenant.throttle.admit(envelope.account_id, &limits, now_ms) {
+                self.barrier();
+                self.reject_order(index, envelope, now_ms, reason.clone());
+                return Some(reason);
+            }
+        }
+        if let Err(reason) = self.resolve_client_order(index, &mut envelope) {
+            self.reject_order(index, envelope, now_ms, reason.clone());
+            return Some(reason);
+        }
+        let tenant = Self::tenant_mut(&mut self.tenants, &envelope.cmd.tenant);
+        if queue && self.workers.is_some() {
+            if let Some(queued) = Self::queue(tenant, index, &envelope, now_ms) {
+                self.pending.push(queued);
+                return None;
+            }
+        }
+        let cmd = &envelope.cmd;
+        let traded = match (&cmd.cmd, cmd.market, &cmd.order) {
+            (MatchCmdType::PlaceOrder, MarketType::Spot, Some(order)) => {
+                Some((tenant.id.clone(), order.symbol.clone()))
+            }
+            _ => None,
+        };
+        self.barrier();
+        let rejection = self.apply(index, envelope, now_ms);
+        if let Some((tenant, symbol)) = traded {
+            self.trigger_stops(index, &tenant, &symbol, now_ms);
+        }
+        rejection
+    }
+
+    /// Places the stop orders the last trade price of a spot symbol triggers,
+    /// see `engine::spot::trigger_manager`
+    ///
+    /// Triggered orders are applied at this index as orders of their accounts, in
+    /// arrival order, until no more orders trigger, so stops triggered by the
+    /// trades of activated ones are placed as well.
+    ///
+    /// # Arguments
+    /// * `index` - Raft index of the order that may have moved the price
+    /// * `tenant` - Tenant the symbol belongs to
+    /// * `symbol` - ID of the symbol
+    /// * `now_ms` - Engine time in milliseconds
+    fn trigger_stops(&mut self, index: u64, tenant: &str, symbol: &str, now_ms: u64) {
+        loop {
+            let triggered =
+                Self::tenant_mut(&mut self.tenants, tenant).take_triggered_stops(symbol);
+            if triggered.is_empty() {
+                return;
+            }
+            for stop in triggered {
+                log::debug!("trigger stop order {} at index {}", stop.order.id, index);
+                let account_id = stop.account_id;
+                let cmd = MatchCmd {
+                    cmd: MatchCmdType::PlaceOrder,
+                    tenant: tenant.to_string(),
+                    order: Some(stop.activate()),
+                    market: MarketType::Spot,
+                    ..Default::default()
+                };
+                let envelope = CommandEnvelope {
+                    account_id,
+                    cmd,
+                    ..Default::default()
+                };
+                self.apply(index, envelope, now_ms);
+            }
+        }
+    }
+
+    /// Fires the timers due at or before an engine time, see `engine::timers`
+    ///
+    /// Runs the barrier first if a timer is due, so timers act on the books
+    /// every replica has at this index.
+    ///
+    /// # Arguments
+    /// * `index` - Raft index of the command that moved the clock
+    /// * `now_ms` - Engine time in milliseconds
+    fn fire_timers(&mut self, index: u64, now_ms: u64) {
+        if self.timers.next_due().is_none_or(|due| due > now_ms) {
+            return;
+        }
+        self.barrier();
+        for timer in self.timers.take_due(now_ms) {
+            log::debug!("fire timer {} at index {}", timer.id, index);
+            let cmd = match timer.action {
+                TimerAction::ResumeSymbol { market, symbol } => {
+                    let tenant = Self::tenant_mut(&mut self.tenants, &timer.tenant);
+                    if tenant.resume_symbol(market, &symbol) {
+                        log::info!("resume symbol {} at index {}", symbol, index);
+                        self.order_events.push(OrderEvent {
+                            index,
+                            tenant: tenant.id.clone(),
+                            account_id: timer.account_id,
+                            time: now_ms / 1000,
+                            change: OrderChange::SymbolResumed { symbol },
+                        });
+                    }
+                    continue;
+                }
+                TimerAction::CancelOrder {
+                    market,
+                    symbol,
+                    order_id,
+                } => MatchCmd {
+                    cmd: MatchCmdType::CancelOrder,
+                    tenant: timer.tenant,
+                    order: Some(Order {
+                        id: order_id,
+                        symbol,
+                        ..Default::default()
+                    }),
+                    market,
+                    ..Default::default()
+                },
+            };
+            let envelope = CommandEnvelope {
+                account_id: timer.account_id,
+                cmd,
+                ..Default::default()
+            };
+            self.apply(index, envelope, now_ms);
+        }
+    }
+
+    /// Resolves the client order ID of an order or cancel, see `engine::client_orders`
+    ///
+    /// An order placed with a client order ID but no order ID takes its raft index
+    /// as order ID. The order is entered under its client order ID, unless the ID
+    /// belongs to an order of the account that is still open. A cancel without an
+    /// order ID is pointed at the order its client order ID belongs to, and left
+    /// to find nothing if the ID is unknown. Whether an order is open is read after
+    /// the barrier, so from the books every replica has at this index; closed
+    /// orders are pruned the same way every `PRUNE_EVERY` entries.
+    ///
+    /// # Arguments
+    /// * `index` - Raft index of the command
+    /// * `envelope` - The command, its order is updated in place
+    ///
+    /// # Returns
+    /// Why the order is rejected
+    fn resolve_client_order(
+        &mut self,
+        index: u64,
+        envelope: &mut CommandEnvelope,
+    ) -> Result<(), String> {
+        let account_id = envelope.account_id;
+        let cmd = &mut envelope.cmd;
+        let Some(order) = cmd
+            .order
+            .as_mut()
+            .filter(|order| !order.client_order_id.is_empty())
+        else {
+            return Ok(());
+        };
+        match cmd.cmd {
+            MatchCmdType::PlaceOrder => {
+                if order.id.is_empty() {
+                    order.id = index.to_string();
+                }
+                let tenant = Self::tenant_mut(&mut self.tenants, &cmd.tenant);
+                let previous = tenant
+                    .client_orders
+                    .get(account_id, &order.client_order_id)
+                    .cloned();
+                if previous.is_some() || tenant.client_orders.prune_due() {
+                    self.barrier();
                 }
-                MatchCmdType::CancelOrder => {
-                    let symbol = cmd.order.as_ref().unwrap().symbol.clone();
-                    let order_id = cmd.order.as_ref().unwrap().id.clone();
-                    let _ = self.spot_processor.cancel_order(&symbol, &order_id);
+                let tenant = Self::tenant_mut(&mut self.tenants, &cmd.tenant);
+                if let Some(previous) = previous {
+                    if tenant.is_open(&previous.symbol, &previous.order_id) {
+                        return Err(format!(
+                            "Client order ID {} is in use by open order {}",
+                            order.client_order_id, previous.order_id
+                        ));
+                    }
                 }
-                MatchCmdType::CreateSymbol => {
-                    let _ = self.spot_processor.add_symbol(cmd.symbol.unwrap());
+                if tenant.client_orders.prune_due() {
+                    tenant.prune_client_orders();
                 }
-                MatchCmdType::RemoveSymbol => {
-                    let symbol = cmd.symbol.as_ref().unwrap().name.clone();
-                    let _ = self.spot_processor.del_symbol(&symbol);
+                let entry = ClientOrder {
+                    symbol: order.symbol.clone(),
+                    order_id: order.id.clone(),
+                };
+                tenant
+                    .client_orders
+                    .insert(account_id, &order.client_order_id, entry);
+            }
+            MatchCmdType::CancelOrder | MatchCmdType::AmendOrder if order.id.is_empty() => {
+                let tenant = Self::tenant_mut(&mut self.tenants, &cmd.tenant);
+                if let Some(entry) = tenant.client_orders.get(account_id, &order.client_order_id) {
+                    order.symbol = entry.symbol.clone();
+                    order.id = entry.order_id.clone();
                 }
-                _ => {}
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Returns the queued form of a command the workers can apply
+    ///
+    /// Only orders and cancels on listed symbols that do not settle balances and
+    /// have no circuit breaker qualify; orders of accounts with trading disabled
+    /// are rejected in order. Stop orders, and commands on symbols with stop
+    /// orders waiting, are applied in order too, so the triggers see every trade,
+    /// as are good-till-date orders, which register their expiry timer when they
+    /// rest. Symbols with a circuit breaker are applied in order so it can halt
+    /// them between two orders.
+    ///
+    /// # Arguments
+    /// * `tenant` - Tenant the command is scoped to
+    /// * `index` - Raft index of the command
+    /// * `envelope` - The command and its metadata
+    /// * `now_ms` - Engine time of the command in milliseconds
+    ///
+    /// # Returns
+    /// The queued command, None if the command must be applied in order
+    fn queue(
+        tenant: &Tenant,
+        index: u64,
+        envelope: &CommandEnvelope,
+        now_ms: u64,
+    ) -> Option<Queued> {
+        let cmd = &envelope.cmd;
+        let order = cmd.order.as_ref()?;
+        if order.is_stop()
+            || order.time_in_force == TimeInForce::Gtd
+            || tenant.stop_orders.has_pending(&order.symbol)
+        {
+            return None;
+        }
+        let independent = match cmd.market {
+            MarketType::Spot => tenant
+                .spot_processor
+                .get_symbol(&order.symbol)
+                .is_some_and(|symbol| !symbol.settle_balances && symbol.circuit_breaker.is_zero()),
+            MarketType::Perp => tenant
+                .perp_processor
+                .get_symbol(&order.symbol)
+                .is_some_and(|contract| contract.circuit_breaker.is_zero()),
+        };
+        if !independent {
+            return None;
+        }
+        let now = now_ms / 1000;
+        let action = match cmd.cmd {
+            MatchCmdType::PlaceOrder => {
+                if tenant
+                    .ledger
+                    .risk_limits(envelope.account_id)
+                    .trading_disabled
+                {
+                    return None;
+                }
+                let mut order = order.clone();
+                if now_ms > 0 {
+                    order.created_at = now;
+                    order.updated_at = now;
+                }
+                Action::Place(order)
+            }
+            MatchCmdType::CancelOrder => Action::Cancel(order.id.clone()),
+            _ => return None,
+        };
+        Some(Queued {
+            index,
+            tenant: tenant.id.clone(),
+            market: cmd.market,
+            symbol: order.symbol.clone(),
+            account_id: envelope.account_id,
+            time: now,
+            action,
+        })
+    }
+
+    /// Applies the commands queued for the workers
+    ///
+    /// The books of the queued symbols are detached from their tenants, matched on
+    /// the workers and attached again; metrics and order events are recorded in log
+    /// order afterwards. Must run before the state is read.
+    pub fn barrier(&mut self) {
+        let Some(workers) = self.workers.clone() else {
+            return;
+        };
+        if self.pending.is_empty() {
+            return;
+        }
+        let mut jobs: BTreeMap<(String, String), Job> = BTreeMap::new();
+        for queued in self.pending.drain(..) {
+            let key = (queued.tenant.clone(), queued.symbol.clone());
+            let job = match jobs.entry(key) {
+                Entry::Occupied(job) => job.into_mut(),
+                Entry::Vacant(entry) => {
+                    let tenant = Self::tenant_mut(&mut self.tenants, &queued.tenant);
+                    // Symbols are only listed and delisted by commands that run
+                    // the barrier first
+                    let job = Job::start(tenant, &queued).expect("queued symbol is listed");
+                    entry.insert(job)
+                }
+            };
+            job.commands.push((queued, None));
+        }
+        let mut applied = Vec::new();
+        for job in workers.run(jobs.into_values().collect()) {
+            let tenant = Self::tenant_mut(&mut self.tenants, &job.tenant);
+            let (market, commands) = job.finish(tenant);
+            applied.extend(
+                commands
+                    .into_iter()
+                    .map(|(queued, outcome)| (market, queued, outcome)),
+            );
+        }
+        applied.sort_by_key(|(_, queued, _)| queued.index);
+        for (market, queued, outcome) in applied {
+            let tenant = Self::tenant_mut(&mut self.tenants, &queued.tenant);
+            let change = match outcome.expect("queued command was applied") {
+                Outcome::Placed {
+                    order,
+                    result,
+                    resting,
+                } => {
+                    self.order_tally.order(
+                        &tenant.id,
+                        tenant.get_symbol(market, &order.symbol),
+                        &result,
+                    );
+                    OrderChange::placed(order, result, resting)
+                }
+                Outcome::Canceled {
+                    order_id,
+                    canceled: true,
+                } => {
+                    self.order_tally.cancel(&tenant.id, &queued.symbol);
+                    OrderChange::Canceled {
+                        symbol: queued.symbol.clone(),
+                        order_id,
+                    }
+                }
+                Outcome::Canceled { .. } => continue,
+            };
+            self.touched_books
+                .insert((tenant.id.clone(), queued.symbol));
+            self.order_events.push(OrderEvent {
+                index: queued.index,
+                tenant: tenant.id.clone(),
+                account_id: queued.account_id,
+                time: queued.time,
+                change,
+            });
+        }
+    }
+
+    /// Rejects an order in order without placing it, e.g. one above its account's order rate
+    ///
+    /// # Arguments
+    /// * `index` - Raft index of the command
+    /// * `envelope` - The order command and its metadata
+    /// * `now_ms` - Engine time of the command in milliseconds
+    /// * `reason` - Why the order is rejected
+    fn reject_order(&mut self, index: u64, envelope: CommandEnvelope, now_ms: u64, reason: String) {
+        let now = now_ms / 1000;
+        let cmd = envelope.cmd;
+        let tenant = Self::tenant_mut(&mut self.tenants, &cmd.tenant);
+        let mut order = cmd.order.unwrap();
+        order.created_at = now;
+        order.updated_at = now;
+        log::debug!("reject order {} at index {}: {}", order.id, index, reason);
+        let result = Err(reason);
+        self.order_tally.order(
+            &tenant.id,
+            tenant.get_symbol(cmd.market, &order.symbol),
+            &result,
+        );
+        self.order_events.push(OrderEvent {
+            index,
+            tenant: tenant.id.clone(),
+            account_id: envelope.account_id,
+            time: now,
+            change: OrderChange::placed(order, result, false),
+        });
+    }
+
+    /// Registers the timer ending a circuit breaker halt and records the events
+    /// of the halt: the cancels of the orders it canceled, then the halt itself
+    ///
+    /// # Arguments
+    /// * `timers` - Timers of the engine
+    /// * `order_tally` - Order and cancel counters of the engine
+    /// * `order_events` - Order events of the engine
+    /// * `index` - Raft index of the command that tripped the breaker
+    /// * `now` - Engine time of the command in seconds
+    /// * `account_id` - Account of the command
+    /// * `tenant` - Tenant the symbol belongs to
+    /// * `market` - Market the symbol is listed on
+    /// * `symbol` - ID of the halted symbol
+    /// * `halt` - The halt, see `Tenant::check_circuit_breaker`
+    #[allow(clippy::too_many_arguments)]
+    fn record_halt(
+        timers: &mut Timers,
+        order_tally: &mut metrics::OrderTally,
+        order_events: &mut Vec<OrderEvent>,
+        index: u64,
+        now: u64,
+        account_id: u64,
+        tenant: &str,
+        market: MarketType,
+        symbol: String,
+        halt: Halt,
+    ) {
+        log::warn!("halt symbol {} at index {}: {}", symbol, index, halt.reason);
+        timers.schedule(
+            halt.until_ms,
+            tenant,
+            0,
+            TimerAction::ResumeSymbol {
+                market,
+                symbol: symbol.clone(),
             },
-            Err(e) => {
-                log::error!("failed to deserialize match cmd: {}", e);
+        );
+        for order in halt.canceled {
+            order_tally.cancel(tenant, &symbol);
+            order_events.push(OrderEvent {
+                index,
+                tenant: tenant.to_string(),
+                account_id: order.account_id,
+                time: now,
+                change: OrderChange::Canceled {
+                    symbol: order.symbol,
+                    order_id: order.id,
+                },
+            });
+        }
+        order_events.push(OrderEvent {
+            index,
+            tenant: tenant.to_string(),
+            account_id,
+            time: now,
+            change: OrderChange::SymbolHalted {
+                symbol,
+                reason: halt.reason,
+            },
+        });
+    }
+
+    /// Applies a command in order
+    ///
+    /// A good-till-date order that rests registers a timer canceling it at its
+    /// expiry time, see `engine::timers`; one that expires before it is placed is
+    /// rejected. An order or liquidation that trades its symbol past its circuit
+    /// breaker halts the symbol and registers a timer resuming it, see
+    /// `engine::circuit_breaker`.
+    ///
+    /// # Arguments
+    /// * `index` - Raft index of the command
+    /// * `envelope` - The command and its metadata
+    /// * `now_ms` - Engine time of the command in milliseconds
+    ///
+    /// # Returns
+    /// Why the order of a place command was rejected, None otherwise
+    fn apply(&mut self, index: u64, envelope: CommandEnvelope, now_ms: u64) -> Option<String> {
+        let now = now_ms / 1000;
+        let cmd = envelope.cmd;
+        let touched = &mut self.touched_books;
+        let settlements = &mut self.settlements;
+        let funding_events = &mut self.funding_events;
+        let order_events = &mut self.order_events;
+        let cancellations = &mut self.cancellations;
+        let order_tally = &mut self.order_tally;
+        let timers = &mut self.timers;
+        let tenant = Self::tenant_mut(&mut self.tenants, &cmd.tenant);
+        let mut rejection = None;
+        match cmd.cmd {
+            MatchCmdType::PlaceOrder => {
+                let mut order = cmd.order.unwrap();
+                if now_ms > 0 {
+                    order.created_at = now;
+                    order.updated_at = now;
+                }
+                let good_till_date = order.time_in_force == TimeInForce::Gtd;
+                let result = if good_till_date && order.expire_at <= now_ms {
+                    Err(format!(
+                        "Order {} expired at {} before it was placed",
+                        order.id, order.expire_at
+                    ))
+                } else {
+                    match cmd.market {
+                        MarketType::Spot => tenant.place_order(envelope.account_id, &order),
+                        MarketType::Perp => {
+                            tenant.place_contract_order(envelope.account_id, &order)
+                        }
+                    }
+                };
+                let listed = tenant
+                    .get_orderbook(&order.symbol)
+                    .and_then(|_| tenant.get_symbol(cmd.market, &order.symbol));
+                order_tally.order(&tenant.id, listed, &result);
+                if listed.is_some() {
+                    touched.insert((tenant.id.clone(), order.symbol.clone()));
+                }
+                rejection = result.as_ref().err().cloned();
+                for (seq, mut settlement) in
+                    tenant.ledger.take_settlements().into_iter().enumerate()
+                {
+                    settlement.index = index;
+                    settlement.trade_seq = seq as u32;
+                    settlement.tenant = tenant.id.clone();
+                    tenant.positions.apply(&settlement);
+                    settlements.push(settlement);
+                }
+                let resting = tenant.is_open(&order.symbol, &order.id);
+                let last_trade = result
+                    .as_ref()
+                    .ok()
+                    .and_then(|trades| trades.last())
+                    .map(|trade| trade.price);
+                if good_till_date && resting && result.is_ok() {
+                    timers.schedule(
+                        order.expire_at,
+                        &tenant.id,
+                        envelope.account_id,
+                        TimerAction::CancelOrder {
+                            market: cmd.market,
+                            symbol: order.symbol.clone(),
+                            order_id: order.id.clone(),
+                        },
+                    );
+                }
+                let symbol = order.symbol.clone();
+                order_events.push(OrderEvent {
+                    index,
+                    tenant: tenant.id.clone(),
+                    account_id: envelope.account_id,
+                    time: now,
+                    change: OrderChange::placed(order, result, resting),
+                });
+                let halt = last_trade.filter(|_| now_ms > 0).and_then(|price| {
+                    tenant.check_circuit_breaker(cmd.market, &symbol, price, now_ms)
+                });
+                if let Some(halt) = halt {
+                    Self::record_halt(
+                        timers,
+                        order_tally,
+                        order_events,
+                        index,
+                        now,
+                        envelope.account_id,
+                        &tenant.id,
+                        cmd.market,
+                        symbol,
+                        halt,
+                    );
+                }
+            }
+            MatchCmdType::CancelOrder => {
+                let symbol = cmd.order.as_ref().unwrap().symbol.clone();
+                let order_id = cmd.order.as_ref().unwrap().id.clone();
+                let canceled = match cmd.market {
+                    MarketType::Spot => tenant.cancel_order(&symbol, &order_id),
+                    MarketType::Perp => tenant.perp_processor.cancel_order(&symbol, &order_id),
+                };
+                if let Ok(Some(_)) = canceled {
+                    order_tally.cancel(&tenant.id, &symbol);
+                    touched.insert((tenant.id.clone(), symbol.clone()));
+                    order_events.push(OrderEvent {
+                        index,
+                        tenant: tenant.id.clone(),
+                        account_id: envelope.account_id,
+                        time: now,
+                        change: OrderChange::Canceled { symbol, order_id },
+                    });
+                }
+            }
+            MatchCmdType::CancelAllOrders => {
+                let symbol = cmd
+                    .order
+                    .as_ref()
+                    .map(|order| order.symbol.as_str())
+                    .filter(|symbol| !symbol.is_empty());
+                let canceled = match cmd.market {
+                    MarketType::Spot => tenant.cancel_all_orders(envelope.account_id, symbol),
+                    MarketType::Perp => tenant
+                        .perp_processor
+                        .cancel_all_orders(envelope.account_id, symbol),
+                };
+                let canceled = canceled.unwrap_or_else(|e| {
+                    log::warn!("reject cancel-all {}: {}", envelope.request_id, e);
+                    Vec::new()
+                });
+                let mut order_ids = Vec::with_capacity(canceled.len());
+                for order in canceled {
+                    order_tally.cancel(&tenant.id, &order.symbol);
+                    touched.insert((tenant.id.clone(), order.symbol.clone()));
+                    order_ids.push(order.id.clone());
+                    order_events.push(OrderEvent {
+                        index,
+                        tenant: tenant.id.clone(),
+                        account_id: envelope.account_id,
+                        time: now,
+                        change: OrderChange::Canceled {
+                            symbol: order.symbol,
+                            order_id: order.id,
+                        },
+                    });
+                }
+                cancellations.push(Cancellation {
+                    tenant: tenant.id.clone(),
+                    request_id: envelope.request_id,
+                    order_ids,
+                    ..Default::default()
+                });
+            }
+            MatchCmdType::AmendOrder => {
+                let order = cmd.order.unwrap();
+                let amended = match cmd.market {
+                    MarketType::Spot => {
+                        tenant.amend_order(&order.symbol, &order.id, order.price, order.quantity)
+                    }
+                    MarketType::Perp => tenant.amend_contract_order(
+                        &order.symbol,
+                        &order.id,
+                        order.price,
+                        order.quantity,
+                    ),
+                };
+                match amended {
+                    Ok(mut amended) => {
+                        if now_ms > 0 {
+                            amended.updated_at = now;
+                        }
+                        touched.insert((tenant.id.clone(), order.symbol.clone()));
+                        order_events.push(OrderEvent {
+                            index,
+                            tenant: tenant.id.clone(),
+                            account_id: envelope.account_id,
+                            time: now,
+                            change: OrderChange::Placed {
+                                order: amended,
+                                trades: Vec::new(),
+                            },
+                        });
+                    }
+                    Err(e) => log::warn!("reject amendment of order {}: {}", order.id, e),
+                }
+            }
+            MatchCmdType::CreateSymbol => {
+                let mut symbol = cmd.symbol.unwrap();
+                symbol.tenant = tenant.id.clone();
+                if now_ms > 0 {
+                    symbol.created_at = now;
+                    symbol.updated_at = now;
+                }
+                let name = symbol.name.clone();
+                match tenant.add_symbol(cmd.market, symbol) {
+                    Ok(()) => order_events.push(OrderEvent {
+                        index,
+                        tenant: tenant.id.clone(),
+                        account_id: envelope.account_id,
+                        time: now,
+                        change: OrderChange::SymbolListed { symbol: name },
+                    }),
+                    Err(e) => log::warn!("reject symbol {}: {}", name, e),
+                }
+            }
+            MatchCmdType::UpdateSymbol => {
+                let mut update = cmd.symbol.unwrap();
+                if now_ms > 0 {
+                    update.updated_at = now;
+                }
+                if let Err(e) = tenant.update_symbol(cmd.market, &update) {
+                    log::warn!("reject symbol update {}: {}", update.name, e);
+                }
+            }
+            MatchCmdType::RemoveSymbol => {
+                let symbol = cmd.symbol.as_ref().unwrap().name.clone();
+                let removed = match cmd.market {
+                    MarketType::Spot => tenant.remove_symbol(&symbol),
+                    MarketType::Perp => tenant.perp_processor.del_symbol(&symbol),
+                };
+                if removed.is_ok() {
+                    tenant.circuit_breakers.remove(&symbol);
+                    touched.insert((tenant.id.clone(), symbol.clone()));
+                    order_events.push(OrderEvent {
+                        index,
+                        tenant: tenant.id.clone(),
+                        account_id: envelope.account_id,
+                        time: now,
+                        change: OrderChange::SymbolRemoved { symbol },
+                    });
+                }
+            }
+            MatchCmdType::HaltSymbol | MatchCmdType::ResumeSymbol => {
+                let symbol = cmd.symbol.as_ref().unwrap().name.clone();
+                // An operator halt lasts until an operator resumes the symbol, a
+                // circuit breaker halt it takes over never resumes by itself
+                let resume = TimerAction::ResumeSymbol {
+                    market: cmd.market,
+                    symbol: symbol.clone(),
+                };
+                timers.cancel_where(|timer| timer.tenant == tenant.id && timer.action == resume);
+                let change = if let MatchCmdType::ResumeSymbol = cmd.cmd {
+                    if !tenant.resume_symbol(cmd.market, &symbol) {
+                        log::warn!("reject resume of symbol {}: not halted", symbol);
+                        return None;
+                    }
+                    log::info!("resume symbol {} at index {}", symbol, index);
+                    OrderChange::SymbolResumed { symbol }
+                } else {
+                    let canceled = match tenant.halt_symbol(cmd.market, &symbol) {
+                        Ok(canceled) => canceled,
+                        Err(e) => {
+                            log::warn!("reject halt of symbol {}: {}", symbol, e);
+                            return None;
+                        }
+                    };
+                    log::warn!("halt symbol {} at index {} by operator", symbol, index);
+                    touched.insert((tenant.id.clone(), symbol.clone()));
+                    for order in canceled {
+                        order_tally.cancel(&tenant.id, &symbol);
+                        order_events.push(OrderEvent {
+                            index,
+                            tenant: tenant.id.clone(),
+                            account_id: order.account_id,
+                            time: now,
+                            change: OrderChange::Canceled {
+                                symbol: order.symbol,
+                                order_id: order.id,
+                            },
+                        });
+                    }
+                    OrderChange::SymbolHalted {
+                        symbol,
+                        reason: "halted by operator".to_string(),
+                    }
+                };
+                order_events.push(OrderEvent {
+                    index,
+                    tenant: tenant.id.clone(),
+                    account_id: envelope.account_id,
+                    time: now,
+                    change,
+                });
             }
+            MatchCmdType::MassCancel => {
+                let symbol = cmd.symbol.as_ref().unwrap().name.clone();
+                let canceled = tenant.cancel_book(cmd.market, &symbol).unwrap_or_else(|e| {
+                    log::warn!("reject mass cancel {}: {}", envelope.request_id, e);
+                    Vec::new()
+                });
+                let mut order_ids = Vec::with_capacity(canceled.len());
+                for order in canceled {
+                    order_tally.cancel(&tenant.id, &symbol);
+                    touched.insert((tenant.id.clone(), symbol.clone()));
+                    order_ids.push(order.id.clone());
+                    order_events.push(OrderEvent {
+                        index,
+                        tenant: tenant.id.clone(),
+                        account_id: order.account_id,
+                        time: now,
+                        change: OrderChange::Canceled {
+                            symbol: order.symbol,
+                            order_id: order.id,
+                        },
+                    });
+                }
+                cancellations.push(Cancellation {
+                    tenant: tenant.id.clone(),
+                    request_id: envelope.request_id,
+                    order_ids,
+                    ..Default::default()
+                });
+            }
+            MatchCmdType::Deposit | MatchCmdType::Withdraw | MatchCmdType::Adjust => {
+                let transfer = cmd.transfer.unwrap();
+                let record = FundingRecord {
+                    index,
+                    tenant: tenant.id.clone(),
+                    kind: match cmd.cmd {
+                        MatchCmdType::Deposit => FundingKind::Deposit,
+                        MatchCmdType::Withdraw => FundingKind::Withdrawal,
+                        _ => FundingKind::Adjustment,
+                    },
+                    account_id: envelope.account_id,
+                    currency: transfer.currency,
+                    amount: transfer.amount,
+                    idempotency_key: transfer.idempotency_key,
+                    reason: transfer.reason,
+                    request_id: envelope.request_id,
+                    client_id: envelope.client_id,
+                    proposed_at: envelope.proposed_at,
+                    outcome: Default::default(),
+                };
+                let record = tenant.funding.apply(&mut tenant.ledger, record);
+                log::info!(
+                    "{:?} of {} {} to account {} with key {:?}: {:?}",
+                    record.kind,
+                    record.amount,
+                    record.currency,
+                    record.account_id,
+                    record.idempotency_key,
+                    record.outcome
+                );
+                funding_events.push(record);
+            }
+            MatchCmdType::SetRiskLimits => {
+                let limits = cmd.risk.unwrap();
+                match tenant.ledger.set_risk_limits(envelope.account_id, limits) {
+                    Ok(()) => log::info!(
+                        "set risk limits of account {} by request {}",
+                        envelope.account_id,
+                        envelope.request_id
+                    ),
+                    Err(e) => log::warn!("reject risk limits {}: {}", envelope.request_id, e),
+                }
+            }
+            MatchCmdType::SetMarkPrice => {
+                let mark = cmd.mark_price.unwrap();
+                if let Err(e) = tenant.perp_processor.set_mark_price(&mark) {
+                    log::warn!("reject mark price {}: {}", envelope.request_id, e);
+                }
+            }
+            MatchCmdType::Liquidate => {
+                let mut order = cmd.order.unwrap();
+                if now_ms > 0 {
+                    order.created_at = now;
+                    order.updated_at = now;
+                }
+                let result = tenant.perp_processor.liquidate(&order);
+                let last_trade = result
+                    .as_ref()
+                    .ok()
+                    .and_then(|trades| trades.last())
+                    .map(|trade| trade.price);
+                match &result {
+                    Ok(trades) => {
+                        let filled: Decimal = trades.iter().map(|trade| trade.quantity).sum();
+                        log::warn!(
+                            "liquidation {} on {} filled {} of {}",
+                            order.id,
+                            order.symbol,
+                            filled,
+                            order.quantity
+                        );
+                        touched.insert((tenant.id.clone(), order.symbol.clone()));
+                    }
+                    Err(e) => log::warn!("reject liquidation {}: {}", order.id, e),
+                }
+                let symbol = order.symbol.clone();
+                order_events.push(OrderEvent {
+                    index,
+                    tenant: tenant.id.clone(),
+                    account_id: envelope.account_id,
+                    time: now,
+                    change: OrderChange::placed(order, result, false),
+                });
+                // Liquidations move the price like any order
+                let halt = last_trade.filter(|_| now_ms > 0).and_then(|price| {
+                    tenant.check_circuit_breaker(MarketType::Perp, &symbol, price, now_ms)
+                });
+                if let Some(halt) = halt {
+                    Self::record_halt(
+                        timers,
+                        order_tally,
+                        order_events,
+                        index,
+                        now,
+                        envelope.account_id,
+                        &tenant.id,
+                        MarketType::Perp,
+                        symbol,
+                        halt,
+                    );
+                }
+            }
+            _ => {}
+        }
+        rejection
+    }
+
+    /// Publishes the order, cancel and trade counters, and order book gauges,
+    /// memory estimates, read views and spreads for the books changed since the
+    /// last call, see `symbol_stats`
+    ///
+    /// Called once per apply batch rather than per command, walking a book is
+    /// too expensive to do on every order and the counters are looked up once
+    /// per symbol.
+    pub fn flush_book_metrics(&mut self) {
+        self.order_tally.flush();
+        for (tenant, symbol) in std::mem::take(&mut self.touched_books) {
+            let matcher = self
+                .tenants
+                .get(&tenant)
+                .and_then(|t| t.get_shared_matcher(&symbol));
+            let orderbook = matcher.map(|matcher| matcher.orderbook());
+            metrics::record_orderbook(&tenant, &symbol, orderbook);
+            memory::record_book(
+                &tenant,
+                &symbol,
+                orderbook.map(|book| book.estimated_memory()),
+            );
+            symbol_stats::quote(
+                &tenant,
+                &symbol,
+                orderbook.and_then(|book| book.get_spread()),
+            );
+            read_view::publish_book(&tenant, &symbol, self.index, matcher);
+        }
+        read_view::books_published();
+    }
+
+    /// Publishes the memory estimates of the tenants' request and funding histories
+    ///
+    /// Called once per apply batch before the funding events are taken; the
+    /// funding history is only walked for tenants that applied funding commands.
+    pub fn flush_memory(&mut self) {
+        for tenant in self.tenants.values() {
+            memory::record_history(&tenant.id, "dedupe", tenant.dedupe.estimated_memory());
+        }
+        let funded: BTreeSet<&str> = self
+            .funding_events
+            .iter()
+            .map(|record| record.tenant.as_str())
+            .collect();
+        for tenant in funded {
+            if let Some(tenant) = self.tenants.get(tenant) {
+                memory::record_history(&tenant.id, "funding", tenant.funding.estimated_memory());
+            }
+        }
+    }
+
+    /// Publishes the balances and positions changed and the cancellations
+    /// since the last call to the read view
+    ///
+    /// Called once per apply batch, see `read_view`.
+    pub fn flush_read_view(&mut self) {
+        for tenant in self.tenants.values_mut() {
+            let balances = tenant.ledger.take_touched();
+            let positions = tenant.positions.take_touched();
+            Self::publish(tenant, balances, positions);
+        }
+        for cancellation in self.cancellations.drain(..) {
+            read_view::publish_cancellation(cancellation);
+        }
+    }
+
+    /// Publishes balances and positions of a tenant's accounts to the read view
+    fn publish(
+        tenant: &Tenant,
+        balances: impl IntoIterator<Item = u64>,
+        positions: impl IntoIterator<Item = u64>,
+    ) {
+        for account_id in balances {
+            let balances = tenant.ledger.balances(account_id).cloned();
+            read_view::publish_balances(&tenant.id, account_id, balances.unwrap_or_default());
+        }
+        for account_id in positions {
+            let positions = tenant.positions.positions(account_id).cloned();
+            read_view::publish_positions(&tenant.id, account_id, positions.unwrap_or_default());
         }
     }
 
+    /// Returns the settlements of the trades applied since the last call
+    ///
+    /// Every replica produces the same settlements for the same log, identified
+    /// by raft index and trade sequence.
+    pub fn take_settlements(&mut self) -> Vec<Settlement> {
+        std::mem::take(&mut self.settlements)
+    }
+
+    /// Returns the audit records of the funding commands applied since the last call
+    pub fn take_funding_events(&mut self) -> Vec<FundingRecord> {
+        std::mem::take(&mut self.funding_events)
+    }
+
+    /// Returns the raft index of the last command applied
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Returns the hybrid logical clock of the last command stamped with one
+    pub fn clock(&self) -> Hlc {
+        self.clock
+    }
+
+    /// Returns the timers of the engine, see `engine::timers`
+    pub fn timers(&self) -> &Timers {
+        &self.timers
+    }
+
+    /// Returns the timers of the engine to register or unregister timers
+    pub fn timers_mut(&mut self) -> &mut Timers {
+        &mut self.timers
+    }
+
+    /// Returns the features the commands applied so far relied on
+    pub fn features(&self) -> &BTreeSet<&'static str> {
+        &self.features
+    }
+
+    /// Returns the features the applied commands relied on to restore them
+    /// along with a snapshot
+    pub fn features_mut(&mut self) -> &mut BTreeSet<&'static str> {
+        &mut self.features
+    }
+
+    /// Returns the changes of orders made by the commands applied since the last call
+    ///
+    /// Trades are numbered by their command here, see `history::number_trades`, so
+    /// events are taken after the `barrier` applied the commands queued for the workers.
+    pub fn take_order_events(&mut self) -> Vec<OrderEvent> {
+        let mut events = std::mem::take(&mut self.order_events);
+        history::number_trades(&mut events);
+        events
+    }
+
+    /// Returns the commands applied since the last call, empty unless they are
+    /// recorded, see `record_commands`
+    pub fn take_commands(&mut self) -> Vec<CommandRecord> {
+        std::mem::take(&mut self.commands)
+    }
+
     /// Restores engine state from a snapshot
     ///
+    /// Snapshots written by older engine versions are migrated on the fly,
+    /// see `engine::snapshot`. The read view, including the order books, and the
+    /// memory estimates are republished from the restored state.
+    ///
     /// # Arguments
     /// * `data` - Serialized engine state data
-    pub fn on_snapshot(&mut self, data: &[u8]) {
-        match bincode::deserialize(data) {
-            Ok(match_engine) => *self = match_engine,
-            Err(e) => {
-                log::error!("failed to deserialize match engine: {}", e);
+    ///
+    /// # Returns
+    /// Returns why the snapshot could not be decoded, the engine is left unchanged
+    pub fn on_snapshot(&mut self, data: &[u8]) -> Result<(), String> {
+        match snapshot::decode(data) {
+            Ok(match_engine) => {
+                let workers = self.workers.take();
+                let record_commands = self.record_commands;
+                *self = match_engine;
+                self.workers = workers;
+                self.record_commands = record_commands;
+                read_view::clear();
+                memory::clear();
+                for tenant in self.tenants.values() {
+                    Self::publish(
+                        tenant,
+                        tenant.ledger.accounts(),
+                        tenant.positions.accounts(),
+                    );
+                    for symbol in tenant.list_symbols() {
+                        let matcher = tenant.get_shared_matcher(&symbol.name);
+                        read_view::publish_book(&tenant.id, &symbol.name, self.index, matcher);
+                        memory::record_book(
+                            &tenant.id,
+                            &symbol.name,
+                            matcher.map(|matcher| matcher.orderbook().estimated_memory()),
+                        );
+                    }
+                    memory::record_history(&tenant.id, "dedupe", tenant.dedupe.estimated_memory());
+                    memory::record_history(
+                        &tenant.id,
+                        "funding",
+                        tenant.funding.estimated_memory(),
+                    );
+                }
+                read_view::books_published();
+                Ok(())
             }
+            Err(e) => Err(format!("failed to deserialize match engine: {}", e)),
         }
     }
 
     /// Creates a snapshot of the current engine state
     ///
     /// # Returns
-    /// Serialized engine state as a byte vector
-    pub fn snapshot(&self) -> Vec<u8> {
-        bincode::serialize(&self).unwrap()
+    /// Versioned snapshot of the engine state as a byte vector, or why the
+    /// state could not be serialized
+    pub fn snapshot(&self) -> Result<Vec<u8>, String> {
+        debug_assert!(self.pending.is_empty(), "snapshot taken before the barrier");
+        snapshot::encode(self)
     }
 }
@@ -4,9 +4,9 @@
 //! It handles matching of market and limit orders according to price-time priority.
 
 use crate::engine::data::OrderBook;
-use crate::engine::entry::{Order, OrderSide, OrderType, Trade};
+use crate::engine::entry::{Order, OrderSide, OrderType, TimeInForce, Trade};
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 
 /// Core order matching engine for a single trading symbol
 /// Maintains an order book and implements matching logic
@@ -14,6 +14,9 @@ use uuid::Uuid;
 pub struct Matcher {
     /// Order book containing all active orders
     orderbook: OrderBook,
+    /// Price of the last trade, None until the symbol traded
+    #[serde(default)]
+    last_price: Option<Decimal>,
 }
 
 impl Matcher {
@@ -24,40 +27,173 @@ impl Matcher {
     pub fn new(symbol: String) -> Self {
         Self {
             orderbook: OrderBook::new(symbol),
+            last_price: None,
         }
     }
 
     /// Places a new order and attempts to match it with existing orders
     ///
+    /// The unfilled remainder rests in the book, unless the order is
+    /// immediate-or-cancel, then it is dropped. A fill-or-kill order the book
+    /// cannot fill completely is dropped without trading, see `can_fill`, as is
+    /// a post-only order that would take liquidity, see `would_take`. Stop
+    /// orders are held off the book until triggered, see
+    /// `engine::spot::trigger_manager`, and are dropped here.
+    ///
+    /// Resting iceberg orders trade at most the slice they show; once it is
+    /// filled the next slice of the hidden remainder is shown and loses time
+    /// priority at its price, see `Order::visible_quantity`.
+    ///
     /// # Arguments
     /// * `order` - The order to place and match
     ///
     /// # Returns
     /// Vector of trades generated from matching this order
-    pub fn place_order(&mut self, mut order: Order) -> Vec<Trade> {
+    pub fn place_order(&mut self, order: Order) -> Vec<Trade> {
+        self.place_order_within(order, None)
+    }
+
+    /// Places an order like `place_order`, trading a market order only at
+    /// prices up to a collar
+    ///
+    /// A market order stopped by the collar does not rest, whatever its time in
+    /// force; what it could not fill within the collar is dropped.
+    ///
+    /// # Arguments
+    /// * `order` - The order to place and match
+    /// * `collar` - Worst price a market order trades at, None for any price
+    ///
+    /// # Returns
+    /// Vector of trades generated from matching this order
+    pub fn place_order_within(&mut self, mut order: Order, collar: Option<Decimal>) -> Vec<Trade> {
         let mut trades = Vec::new();
 
         if self.orderbook.orders_by_id.contains_key(&order.id) {
             log::warn!("Order {} already exists", order.id);
             return trades;
         }
+        if order.time_in_force == TimeInForce::Fok && !self.can_fill_within(&order, collar) {
+            return trades;
+        }
+        if order.order_type == OrderType::LimitMaker && self.would_take(&order) {
+            return trades;
+        }
 
         match order.order_type {
             OrderType::Market => {
-                trades.extend(self.match_market_order(&mut order));
+                trades.extend(self.match_market_order(&mut order, collar));
             }
             OrderType::Limit => {
                 trades.extend(self.match_limit_order(&mut order));
             }
+            OrderType::LimitMaker => {}
+            OrderType::Stop | OrderType::StopLimit => {
+                log::warn!("Stop order {} placed before its trigger", order.id);
+                return trades;
+            }
+        }
+        if let Some(trade) = trades.last() {
+            self.last_price = Some(trade.price);
         }
 
-        if !order.is_filled() {
+        let collared = order.order_type == OrderType::Market && collar.is_some();
+        if !order.is_filled() && order.time_in_force.rests() && !collared {
             self.orderbook.add_order(order);
         }
 
         trades
     }
 
+    /// Checks whether the book holds enough liquidity to fill an order completely
+    ///
+    /// Counts the resting quantity of the opposite side at prices the order
+    /// accepts, every price for market orders.
+    ///
+    /// # Arguments
+    /// * `order` - The order about to be placed
+    ///
+    /// # Returns
+    /// True if matching the order now would fill it completely
+    pub fn can_fill(&self, order: &Order) -> bool {
+        self.can_fill_within(order, None)
+    }
+
+    /// Checks like `can_fill` whether the book fills an order completely, counting
+    /// a market order only at prices up to a collar
+    ///
+    /// # Arguments
+    /// * `order` - The order about to be placed
+    /// * `collar` - Worst price a market order trades at, None for any price
+    ///
+    /// # Returns
+    /// True if matching the order now would fill it completely
+    pub fn can_fill_within(&self, order: &Order, collar: Option<Decimal>) -> bool {
+        let levels: Box<dyn Iterator<Item = (&Decimal, &Vec<Order>)>> = match order.side {
+            OrderSide::Buy => Box::new(self.orderbook.asks.iter()),
+            OrderSide::Sell => Box::new(self.orderbook.bids.iter().rev()),
+        };
+        let needed = order.remaining_quantity();
+        let mut available = Decimal::ZERO;
+        for (price, orders) in levels {
+            if beyond_limit(order, *price, collar) {
+                break;
+            }
+            available += orders
+                .iter()
+                .map(Order::remaining_quantity)
+                .sum::<Decimal>();
+            if available >= needed {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Checks whether an order would trade against the book on arrival
+    ///
+    /// # Arguments
+    /// * `order` - The order about to be placed
+    ///
+    /// # Returns
+    /// True if the opposite side has an order at a price the order accepts
+    pub fn would_take(&self, order: &Order) -> bool {
+        let best = match order.side {
+            OrderSide::Buy => self.orderbook.get_best_ask(),
+            OrderSide::Sell => self.orderbook.get_best_bid(),
+        };
+        match (best, order.order_type) {
+            (None, _) => false,
+            (Some(_), OrderType::Market) => true,
+            (Some(price), _) => match order.side {
+                OrderSide::Buy => price <= order.price,
+                OrderSide::Sell => price >= order.price,
+            },
+        }
+    }
+
+    /// Returns the order book of this matcher
+    pub fn orderbook(&self) -> &OrderBook {
+        &self.orderbook
+    }
+
+    /// Returns the price of the last trade, None until the symbol traded
+    pub fn last_price(&self) -> Option<Decimal> {
+        self.last_price
+    }
+
+    /// Returns the price the price band of the symbol is centered on
+    ///
+    /// # Returns
+    /// The last trade price, the mid price of the book before the symbol traded,
+    /// None while either side of an untraded book is empty
+    pub fn reference_price(&self) -> Option<Decimal> {
+        self.last_price.or_else(|| {
+            let bid = self.orderbook.get_best_bid()?;
+            let ask = self.orderbook.get_best_ask()?;
+            Some((bid + ask) / Decimal::TWO)
+        })
+    }
+
     /// Cancels an existing order
     ///
     /// # Arguments
@@ -69,15 +205,102 @@ impl Matcher {
         self.orderbook.remove_order(order_id)
     }
 
-    /// Matches a market order against the order book
-    /// Market orders are executed at the best available price
+    /// Cancels every order of an account resting in the book
+    ///
+    /// The book is walked once, bids from the best price down and then asks
+    /// from the best price up, each level in time priority, so every replica
+    /// cancels the same orders in the same sequence.
+    ///
+    /// # Arguments
+    /// * `account_id` - Account whose orders are canceled
+    ///
+    /// # Returns
+    /// The canceled orders in the order they were canceled
+    pub fn cancel_account_orders(&mut self, account_id: u64) -> Vec<Order> {
+        self.cancel_orders_where(|order| order.account_id == account_id)
+    }
+
+    /// Cancels every order resting in the book, in the sequence
+    /// `cancel_account_orders` uses
+    ///
+    /// # Returns
+    /// The canceled orders in the order they were canceled
+    pub fn cancel_all_orders(&mut self) -> Vec<Order> {
+        self.cancel_orders_where(|_| true)
+    }
+
+    /// Cancels the resting orders a filter selects, bids from the best price
+    /// down and then asks from the best price up, each level in time priority
+    fn cancel_orders_where(&mut self, filter: impl Fn(&Order) -> bool) -> Vec<Order> {
+        let book = &self.orderbook;
+        let order_ids: Vec<String> = book
+            .bids
+            .values()
+            .rev()
+            .chain(book.asks.values())
+            .flatten()
+            .filter(|order| filter(order))
+            .map(|order| order.id.clone())
+            .collect();
+        order_ids
+            .iter()
+            .filter_map(|order_id| self.orderbook.remove_order(order_id))
+            .collect()
+    }
+
+    /// Changes the price and quantity of a resting order without trading
+    ///
+    /// Downsizing keeps the order's time priority, a new price or a larger
+    /// quantity loses it, see `OrderBook::amend_order`.
+    ///
+    /// # Arguments
+    /// * `order_id` - ID of the order to amend
+    /// * `price` - The new price
+    /// * `quantity` - The new quantity, including what already filled
+    ///
+    /// # Returns
+    /// * `Ok(Order)` - The amended order
+    /// * `Err(String)` - Why the order cannot be amended, the book is unchanged
+    pub fn amend_order(
+        &mut self,
+        order_id: &str,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Result<Order, String> {
+        let resting = self
+            .orderbook
+            .resting_order(order_id)
+            .ok_or_else(|| format!("Order {} is not resting in the book", order_id))?;
+        if quantity <= resting.filled_quantity {
+            return Err(format!(
+                "Order {} already filled {}, more than the new quantity {}",
+                order_id, resting.filled_quantity, quantity
+            ));
+        }
+        let amended = Order {
+            price,
+            quantity,
+            ..resting.clone()
+        };
+        if price != resting.price && self.would_take(&amended) {
+            return Err(format!("Amended order {} would take liquidity", order_id));
+        }
+        self.orderbook
+            .amend_order(order_id, price, quantity)
+            .ok_or_else(|| format!("Order {} is not resting in the book", order_id))
+    }
+
+    /// Matches an order against the order book
+    /// Market orders are executed at the best available price, limit orders
+    /// until the best price is beyond their limit
     ///
     /// # Arguments
     /// * `order` - The market order to match
+    /// * `collar` - Worst price a market order trades at, None for any price
     ///
     /// # Returns
     /// Vector of trades generated from matching this order
-    fn match_market_order(&mut self, order: &mut Order) -> Vec<Trade> {
+    fn match_market_order(&mut self, order: &mut Order, collar: Option<Decimal>) -> Vec<Trade> {
         let mut trades = Vec::new();
 
         while !order.is_filled() {
@@ -91,6 +314,11 @@ impl Matcher {
             }
 
             let price = best_price.unwrap();
+            // Limit orders stop at their limit price, the rest of them rests in the
+            // book; market orders at their collar
+            if beyond_limit(order, price, collar) {
+                break;
+            }
             let orders = match order.side {
                 OrderSide::Buy => self.orderbook.asks.get_mut(&price),
                 OrderSide::Sell => self.orderbook.bids.get_mut(&price),
@@ -100,9 +328,11 @@ impl Matcher {
                 if let Some(matching_order) = orders.first_mut() {
                     let trade_quantity = order
                         .remaining_quantity()
-                        .min(matching_order.remaining_quantity());
+                        .min(matching_order.visible_quantity());
+                    let now = order.updated_at;
+                    // The ID is given when the events of the command are taken
                     let trade = Trade::new(
-                        Uuid::new_v4().to_string(),
+                        String::new(),
                         order.symbol.clone(),
                         price,
                         trade_quantity,
@@ -116,22 +346,32 @@ impl Matcher {
                         } else {
                             order.id.clone()
                         },
+                        now,
                     );
 
                     order.filled_quantity += trade_quantity;
                     matching_order.filled_quantity += trade_quantity;
-                    order.update_status();
-                    matching_order.update_status();
+                    order.update_status(now);
+                    matching_order.update_status(now);
                     trades.push(trade);
 
                     if matching_order.is_filled() {
-                        orders.remove(0);
+                        let filled = orders.remove(0);
+                        self.orderbook.orders_by_id.remove(&filled.id);
                         if orders.is_empty() {
                             match order.side {
                                 OrderSide::Buy => self.orderbook.asks.remove(&price),
                                 OrderSide::Sell => self.orderbook.bids.remove(&price),
                             };
                         }
+                    } else if matching_order.is_iceberg()
+                        && (matching_order.filled_quantity % matching_order.display_quantity)
+                            .is_zero()
+                    {
+                        // The shown slice of an iceberg order is used up, the next one
+                        // queues behind the orders already resting at its price
+                        let replenished = orders.remove(0);
+                        orders.push(replenished);
                     }
                 } else {
                     break;
@@ -177,9 +417,438 @@ impl Matcher {
                 break;
             }
 
-            trades.extend(self.match_market_order(order));
+            trades.extend(self.match_market_order(order, None));
         }
 
         trades
     }
 }
+
+/// Checks whether a price is worse than an order accepts
+///
+/// # Arguments
+/// * `order` - The order being matched
+/// * `price` - Price of the opposite level
+/// * `collar` - Worst price a market order trades at, None for any price
+///
+/// # Returns
+/// True if the order must not trade at the price
+fn beyond_limit(order: &Order, price: Decimal, collar: Option<Decimal>) -> bool {
+    let limit = match order.order_type {
+        OrderType::Market => collar,
+        _ => Some(order.price),
+    };
+    limit.is_some_and(|limit| match order.side {
+        OrderSide::Buy => price > limit,
+        OrderSide::Sell => price < limit,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use rust_decimal_macros::dec;
+    use std::collections::HashMap;
+
+    /// A step of a generated order stream
+    #[derive(Debug, Clone)]
+    enum Op {
+        Place {
+            side: OrderSide,
+            order_type: OrderType,
+            time_in_force: TimeInForce,
+            price: u32,
+            quantity: u32,
+        },
+        /// Cancels the n-th placed order, modulo the number of placed orders
+        Cancel(usize),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        let side = prop_oneof![Just(OrderSide::Buy), Just(OrderSide::Sell)];
+        let order_type = prop_oneof![
+            4 => Just(OrderType::Limit),
+            1 => Just(OrderType::Market),
+            1 => Just(OrderType::LimitMaker),
+        ];
+        let time_in_force = prop_oneof![
+            4 => Just(TimeInForce::Gtc),
+            1 => Just(TimeInForce::Ioc),
+            1 => Just(TimeInForce::Fok),
+        ];
+        prop_oneof![
+            4 => (side, order_type, time_in_force, 95u32..=105, 1u32..=5).prop_map(
+                |(side, order_type, time_in_force, price, quantity)| Op::Place {
+                    side,
+                    order_type,
+                    time_in_force,
+                    price,
+                    quantity,
+                }
+            ),
+            1 => any::<usize>().prop_map(Op::Cancel),
+        ]
+    }
+
+    /// Resting orders of one side in matching order: best price first, oldest first
+    fn queue(book: &OrderBook, side: OrderSide) -> Vec<Order> {
+        match side {
+            OrderSide::Buy => book.bids.values().rev().flatten().cloned().collect(),
+            OrderSide::Sell => book.asks.values().flatten().cloned().collect(),
+        }
+    }
+
+    /// Checks the invariants that hold for the book between commands
+    fn check_book(book: &OrderBook, arrival: &HashMap<String, usize>) {
+        for orders in book.bids.values().chain(book.asks.values()) {
+            assert!(!orders.is_empty(), "empty price level left in the book");
+            for order in orders {
+                assert!(
+                    order.remaining_quantity() > Decimal::ZERO,
+                    "resting order {} has no remaining quantity",
+                    order.id
+                );
+            }
+            assert!(
+                orders
+                    .windows(2)
+                    .all(|pair| arrival[&pair[0].id] < arrival[&pair[1].id]),
+                "price level is not in arrival order"
+            );
+        }
+        if let (Some(bid), Some(ask)) = (book.get_best_bid(), book.get_best_ask()) {
+            assert!(bid < ask, "book is crossed: bid {} ask {}", bid, ask);
+        }
+        assert_eq!(book.orders_by_id.len(), book.order_count());
+    }
+
+    /// A BTCUSDT order placed with the fields tests vary
+    fn order(
+        id: &str,
+        order_type: OrderType,
+        side: OrderSide,
+        price: &str,
+        quantity: &str,
+    ) -> Order {
+        Order::new(
+            id.to_string(),
+            "BTCUSDT".to_string(),
+            order_type,
+            side,
+            price.to_string(),
+            quantity.to_string(),
+        )
+    }
+
+    /// Runs an order stream, checking every step against the book before it
+    fn run(ops: Vec<Op>) {
+        let mut matcher = Matcher::new("BTCUSDT".to_string());
+        let mut arrival = HashMap::new();
+        let mut placed: Vec<Order> = Vec::new();
+        let mut filled: HashMap<String, Decimal> = HashMap::new();
+        let mut canceled: HashMap<String, Decimal> = HashMap::new();
+
+        for op in ops {
+            let before = matcher.orderbook().clone();
+            match op {
+                Op::Place {
+                    side,
+                    order_type,
+                    time_in_force,
+                    price,
+                    quantity,
+                } => {
+                    let mut order = Order::new(
+                        format!("order-{}", placed.len()),
+                        "BTCUSDT".to_string(),
+                        order_type,
+                        side,
+                        price.to_string(),
+                        quantity.to_string(),
+                    );
+                    order.time_in_force = time_in_force;
+                    arrival.insert(order.id.clone(), placed.len());
+                    placed.push(order.clone());
+                    let trades = matcher.place_order(order.clone());
+
+                    // Makers are consumed strictly in price-time order
+                    let makers = queue(
+                        &before,
+                        match side {
+                            OrderSide::Buy => OrderSide::Sell,
+                            OrderSide::Sell => OrderSide::Buy,
+                        },
+                    );
+                    assert!(trades.len() <= makers.len());
+                    let mut taken = Decimal::ZERO;
+                    for (trade, maker) in trades.iter().zip(&makers) {
+                        let (taker_id, maker_id) = match side {
+                            OrderSide::Buy => (&trade.buyer_order_id, &trade.seller_order_id),
+                            OrderSide::Sell => (&trade.seller_order_id, &trade.buyer_order_id),
+                        };
+                        assert_eq!(taker_id, &order.id);
+                        assert_eq!(maker_id, &maker.id, "maker skipped the queue");
+                        assert_eq!(trade.price, maker.price);
+                        assert!(trade.quantity > Decimal::ZERO);
+                        assert!(trade.quantity <= maker.remaining_quantity());
+                        if order_type == OrderType::Limit {
+                            match side {
+                                OrderSide::Buy => assert!(trade.price <= order.price),
+                                OrderSide::Sell => assert!(trade.price >= order.price),
+                            }
+                        }
+                        taken += trade.quantity;
+                        *filled.entry(maker.id.clone()).or_default() += trade.quantity;
+                    }
+                    assert!(taken <= order.quantity, "taker overfilled");
+                    filled.insert(order.id.clone(), taken);
+                    // The last trade sets the price stop orders are triggered by
+                    if let Some(trade) = trades.last() {
+                        assert_eq!(matcher.last_price(), Some(trade.price));
+                    }
+                    // Immediate-or-cancel orders never rest, their remainder is canceled
+                    if time_in_force == TimeInForce::Ioc {
+                        assert!(matcher.orderbook().get_order(&order.id).is_none());
+                        canceled.insert(order.id.clone(), order.quantity - taken);
+                    }
+                    // Post-only orders never trade on arrival, they rest or are rejected
+                    if order_type == OrderType::LimitMaker {
+                        assert!(trades.is_empty(), "post-only order took liquidity");
+                        if matcher.orderbook().get_order(&order.id).is_none() {
+                            canceled.insert(order.id.clone(), order.quantity);
+                        }
+                    }
+                    // Fill-or-kill orders fill completely or leave the book untouched
+                    if time_in_force == TimeInForce::Fok {
+                        assert!(matcher.orderbook().get_order(&order.id).is_none());
+                        if taken.is_zero() {
+                            assert_eq!(matcher.orderbook().order_count(), before.order_count());
+                            canceled.insert(order.id.clone(), order.quantity);
+                        } else {
+                            assert_eq!(
+                                taken, order.quantity,
+                                "fill-or-kill order partially filled"
+                            );
+                        }
+                    }
+                }
+                Op::Cancel(n) => {
+                    if placed.is_empty() {
+                        continue;
+                    }
+                    let id = placed[n % placed.len()].id.clone();
+                    let resting = queue(&before, OrderSide::Buy)
+                        .into_iter()
+                        .chain(queue(&before, OrderSide::Sell))
+                        .find(|order| order.id == id);
+                    let removed = matcher.cancel_order(&id);
+                    let after = matcher.orderbook();
+                    assert_eq!(removed.is_some(), resting.is_some());
+                    assert!(after.get_order(&id).is_none());
+                    match resting {
+                        Some(order) => {
+                            assert_eq!(after.order_count(), before.order_count() - 1);
+                            canceled.insert(id.clone(), order.remaining_quantity());
+                        }
+                        None => assert_eq!(after.order_count(), before.order_count()),
+                    }
+                    // Every other order keeps its place and quantity
+                    for side in [OrderSide::Buy, OrderSide::Sell] {
+                        let expected: Vec<_> = queue(&before, side)
+                            .into_iter()
+                            .filter(|order| order.id != id)
+                            .map(|order| (order.id, order.filled_quantity))
+                            .collect();
+                        let actual: Vec<_> = queue(after, side)
+                            .into_iter()
+                            .map(|order| (order.id, order.filled_quantity))
+                            .collect();
+                        assert_eq!(expected, actual);
+                    }
+                }
+            }
+            check_book(matcher.orderbook(), &arrival);
+        }
+
+        // Every unit entered is either filled, resting or canceled
+        let resting: HashMap<_, _> = queue(matcher.orderbook(), OrderSide::Buy)
+            .into_iter()
+            .chain(queue(matcher.orderbook(), OrderSide::Sell))
+            .map(|order| (order.id.clone(), order))
+            .collect();
+        for order in &placed {
+            let filled = filled.get(&order.id).copied().unwrap_or_default();
+            let left = match (resting.get(&order.id), canceled.get(&order.id)) {
+                (Some(resting), None) => {
+                    assert_eq!(resting.filled_quantity, filled);
+                    resting.remaining_quantity()
+                }
+                (None, Some(canceled)) => *canceled,
+                (None, None) => Decimal::ZERO,
+                (Some(_), Some(_)) => panic!("order {} canceled but resting", order.id),
+            };
+            assert_eq!(filled + left, order.quantity, "quantity of {}", order.id);
+        }
+    }
+
+    #[test]
+    fn immediate_or_cancel_remainder_does_not_rest() {
+        let mut matcher = Matcher::new("BTCUSDT".to_string());
+        matcher.place_order(order("1", OrderType::Limit, OrderSide::Sell, "100", "1"));
+        let mut taker = order("2", OrderType::Limit, OrderSide::Buy, "100", "3");
+        taker.time_in_force = TimeInForce::Ioc;
+        let trades = matcher.place_order(taker);
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].quantity, Decimal::ONE);
+        assert_eq!(matcher.orderbook().order_count(), 0);
+
+        // Nothing to match, nothing rests
+        let mut taker = order("3", OrderType::Limit, OrderSide::Sell, "100", "1");
+        taker.time_in_force = TimeInForce::Ioc;
+        assert!(matcher.place_order(taker).is_empty());
+        assert!(matcher.orderbook().get_order("3").is_none());
+    }
+
+    #[test]
+    fn fill_or_kill_fills_completely_or_not_at_all() {
+        let mut matcher = Matcher::new("BTCUSDT".to_string());
+        matcher.place_order(order("1", OrderType::Limit, OrderSide::Sell, "100", "1"));
+        matcher.place_order(order("2", OrderType::Limit, OrderSide::Sell, "101", "1"));
+
+        // Only 1 is offered at or below 100
+        let mut taker = order("3", OrderType::Limit, OrderSide::Buy, "100", "2");
+        taker.time_in_force = TimeInForce::Fok;
+        assert!(!matcher.can_fill(&taker));
+        assert!(matcher.place_order(taker).is_empty());
+        assert_eq!(matcher.orderbook().order_count(), 2);
+
+        // A market order takes every price, but not more than is offered
+        assert!(!matcher.can_fill(&order("4", OrderType::Market, OrderSide::Buy, "0", "3")));
+        let mut taker = order("5", OrderType::Limit, OrderSide::Buy, "101", "1.5");
+        taker.time_in_force = TimeInForce::Fok;
+        assert!(matcher.can_fill(&taker));
+        let trades = matcher.place_order(taker);
+        assert_eq!(trades.len(), 2);
+        assert_eq!(
+            trades.iter().map(|t| t.quantity).sum::<Decimal>(),
+            dec!(1.5)
+        );
+        assert!(matcher.orderbook().get_order("5").is_none());
+    }
+
+    #[test]
+    fn post_only_orders_never_take_liquidity() {
+        let mut matcher = Matcher::new("BTCUSDT".to_string());
+        matcher.place_order(order("1", OrderType::Limit, OrderSide::Sell, "100", "1"));
+
+        // Crossing the best ask is refused without trading
+        let crossing = order("2", OrderType::LimitMaker, OrderSide::Buy, "100", "1");
+        assert!(matcher.would_take(&crossing));
+        assert!(matcher.place_order(crossing).is_empty());
+        assert!(matcher.orderbook().get_order("2").is_none());
+
+        // Below the best ask it rests like a limit order
+        let passive = order("3", OrderType::LimitMaker, OrderSide::Buy, "99.5", "1");
+        assert!(!matcher.would_take(&passive));
+        assert!(matcher.place_order(passive).is_empty());
+        assert_eq!(matcher.orderbook().get_best_bid(), Some(dec!(99.5)));
+
+        // and is filled as a maker
+        let trades = matcher.place_order(order("4", OrderType::Market, OrderSide::Sell, "0", "1"));
+        assert_eq!(trades[0].buyer_order_id, "3");
+        assert_eq!(trades[0].price, dec!(99.5));
+    }
+
+    #[test]
+    fn iceberg_orders_show_only_their_display_quantity() {
+        let mut matcher = Matcher::new("BTCUSDT".to_string());
+        let mut iceberg = order("1", OrderType::Limit, OrderSide::Sell, "100", "5");
+        iceberg.display_quantity = dec!(2);
+        matcher.place_order(iceberg);
+        matcher.place_order(order("2", OrderType::Limit, OrderSide::Sell, "100", "1"));
+        let (_, asks) = matcher.orderbook().depth(1);
+        assert_eq!(asks, [(dec!(100), dec!(3))]);
+
+        // A taker larger than the slice takes the slice, then the order behind it
+        let trades = matcher.place_order(order("3", OrderType::Market, OrderSide::Buy, "100", "3"));
+        let fills: Vec<_> = trades
+            .iter()
+            .map(|t| (t.seller_order_id.as_str(), t.quantity))
+            .collect();
+        assert_eq!(fills, [("1", dec!(2)), ("2", dec!(1))]);
+
+        // With nothing else at the price, the next slices follow each other
+        let trades = matcher.place_order(order("4", OrderType::Market, OrderSide::Buy, "100", "3"));
+        let fills: Vec<_> = trades.iter().map(|t| t.quantity).collect();
+        assert_eq!(fills, [dec!(2), dec!(1)]);
+        assert_eq!(matcher.orderbook().order_count(), 0);
+    }
+
+    #[test]
+    fn amending_keeps_priority_only_when_downsizing() {
+        let mut matcher = Matcher::new("BTCUSDT".to_string());
+        matcher.place_order(order("1", OrderType::Limit, OrderSide::Sell, "100", "3"));
+        matcher.place_order(order("2", OrderType::Limit, OrderSide::Sell, "100", "1"));
+        matcher.place_order(order("3", OrderType::Limit, OrderSide::Buy, "99", "1"));
+        matcher.place_order(order("4", OrderType::Market, OrderSide::Buy, "0", "1"));
+
+        // Downsizing keeps order 1 first
+        let amended = matcher.amend_order("1", dec!(100), dec!(2)).unwrap();
+        assert_eq!(amended.remaining_quantity(), dec!(1));
+        assert_eq!(matcher.orderbook().asks[&dec!(100)][0].id, "1");
+        // Growing it sends it behind order 2
+        matcher.amend_order("1", dec!(100), dec!(4)).unwrap();
+        assert_eq!(matcher.orderbook().asks[&dec!(100)][0].id, "2");
+        // Repricing moves it to its new level
+        matcher.amend_order("1", dec!(101), dec!(4)).unwrap();
+        assert_eq!(matcher.orderbook().get_best_ask(), Some(dec!(100)));
+        assert_eq!(
+            matcher.orderbook().asks[&dec!(101)][0].filled_quantity,
+            dec!(1)
+        );
+        assert_eq!(matcher.orderbook().get_order("1").unwrap().price, dec!(101));
+
+        // Amends never trade and never cut below the filled quantity
+        assert!(matcher.amend_order("1", dec!(99), dec!(4)).is_err());
+        assert!(matcher.amend_order("1", dec!(101), dec!(1)).is_err());
+        assert!(matcher.amend_order("5", dec!(101), dec!(1)).is_err());
+        assert_eq!(matcher.orderbook().audit(), Vec::new());
+    }
+
+    #[test]
+    fn account_orders_are_canceled_bids_then_asks_in_priority() {
+        let mut matcher = Matcher::new("BTCUSDT".to_string());
+        for (id, account_id, side, price) in [
+            ("1", 7, OrderSide::Sell, "102"),
+            ("2", 7, OrderSide::Buy, "98"),
+            ("3", 8, OrderSide::Buy, "99"),
+            ("4", 7, OrderSide::Buy, "99"),
+            ("5", 7, OrderSide::Sell, "101"),
+        ] {
+            matcher.place_order(Order {
+                account_id,
+                ..order(id, OrderType::Limit, side, price, "1")
+            });
+        }
+
+        let canceled: Vec<String> = matcher
+            .cancel_account_orders(7)
+            .into_iter()
+            .map(|order| order.id)
+            .collect();
+        assert_eq!(canceled, ["4", "2", "5", "1"]);
+        // Other accounts keep their orders
+        assert_eq!(matcher.orderbook().order_count(), 1);
+        assert!(matcher.orderbook().get_order("3").is_some());
+        assert!(matcher.cancel_account_orders(7).is_empty());
+        assert_eq!(matcher.orderbook().audit(), Vec::new());
+    }
+
+    proptest! {
+        #[test]
+        fn random_order_streams_keep_book_invariants(ops in prop::collection::vec(op(), 1..200)) {
+            run(ops);
+        }
+    }
+}
@@ -1,14 +1,44 @@
 //! Match Engine Module
 //!
 //! This module contains the core components of the matching engine system:
+//! - `circuit_breaker`: Replicated halts of symbols whose price moves too far
+//! - `client_orders`: Orders of the accounts by the IDs their clients gave them
+//! - `clock`: Hybrid logical clock engine time is taken from
+//! - `codec`: Raft log encoding of match commands
 //! - `data`: Data structures and types used throughout the engine
+//! - `dedupe`: Suppression of retried commands by request ID
 //! - `entry`: Order and symbol entry point definitions
+//! - `funding`: Idempotent deposits, withdrawals and adjustments with their audit trail
+//! - `history`: Changes of orders made by applied commands, for read models
+//! - `ledger`: Account balances and the funds held for open orders
 //! - `matchengine`: Main matching engine implementation
 //! - `matchlogic`: Core matching logic and algorithms
+//! - `perp`: Perpetual contract order processing, mark prices and liquidations
+//! - `position`: Net positions and entry prices of accounts per symbol
+//! - `snapshot`: Versioned snapshot format and migrations
 //! - `spot`: Spot market order processing
+//! - `tenant`: Isolated tenant namespaces sharing one cluster
+//! - `throttle`: Replicated per-account order rate limits
+//! - `timers`: Replicated timers firing at engine time
+//! - `workers`: Parallel apply of orders and cancels per symbol
 
+pub mod circuit_breaker;
+pub mod client_orders;
+pub mod clock;
+pub mod codec;
 pub mod data;
+pub mod dedupe;
 pub mod entry;
+pub mod funding;
+pub mod history;
+pub mod ledger;
 pub mod matchengine;
 pub mod matchlogic;
+pub mod perp;
+pub mod position;
+pub mod snapshot;
 pub mod spot;
+pub mod tenant;
+pub mod throttle;
+pub mod timers;
+pub mod workers;
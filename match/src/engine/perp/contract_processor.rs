@@ -0,0 +1,603 @@
+//! Contract Processing Module
+//!
+//! This module provides functionality for processing orders on perpetual contracts.
+//! On top of what the spot order processor does it checks the leverage of every
+//! order against its contract, keeps the mark price of each contract and injects
+//! liquidation orders. Margin is not held in the ledger yet, so contracts never
+//! settle balances; mark prices are published by an external price feed and
+//! liquidations are decided by an external risk engine.
+
+use crate::engine::data::OrderBook;
+use crate::engine::entry::{Order, OrderType, Symbol, SymbolStatus, TimeInForce, Trade};
+use crate::engine::matchlogic::Matcher;
+use crate::engine::spot::SymbolManager;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Mark price of a contract as published by the price feed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct MarkPrice {
+    /// Name of the contract
+    pub symbol: String,
+    /// Fair price positions are valued at
+    pub price: Decimal,
+}
+
+/// Processor for handling perpetual contract orders
+/// Manages contracts, their order matching logic and mark prices
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ContractProcessor {
+    /// Manager for handling contracts, shared with the spot market
+    symbol_manager: SymbolManager,
+    /// Last mark price of each contract
+    mark_prices: BTreeMap<String, Decimal>,
+}
+
+impl ContractProcessor {
+    /// Creates a new contract processor without contracts
+    pub fn new() -> Self {
+        Self {
+            symbol_manager: SymbolManager::new(),
+            mark_prices: BTreeMap::new(),
+        }
+    }
+
+    /// Places a new order on a contract
+    ///
+    /// An order without leverage trades at 1x.
+    ///
+    /// # Arguments
+    /// * `order` - The order to place
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Trade>)` - List of trades generated from matching this order
+    /// * `Err(String)` - Error message if order placement fails
+    pub fn place_order(&mut self, order: &Order) -> Result<Vec<Trade>, String> {
+        let (contract, matcher) = self
+            .symbol_manager
+            .get_symbol_and_matcher(&order.symbol)
+            .ok_or_else(|| format!("Contract with id {} does not exist", &order.symbol))?;
+
+        if contract.status != SymbolStatus::Active {
+            return Err(format!("Contract with id {} is not active", &order.symbol));
+        }
+        if !contract.validate_price(order.price) {
+            return Err(format!("Invalid price for contract {}", contract.name));
+        }
+        if !contract.validate_quantity(order.quantity) {
+            return Err(format!("Invalid quantity for contract {}", contract.name));
+        }
+        // Only orders that may rest in the book can hide part of their quantity
+        if order.is_iceberg()
+            && (order.order_type == OrderType::Market
+                || !contract.validate_quantity(order.display_quantity))
+        {
+            return Err(format!(
+                "Invalid display quantity for contract {}",
+                contract.name
+            ));
+        }
+        let leverage = order.leverage.max(Decimal::ONE);
+        if leverage > contract.max_leverage {
+            return Err(format!(
+                "Leverage {} exceeds the maximum of {} for contract {}",
+                leverage, contract.max_leverage, contract.name
+            ));
+        }
+        if order.is_stop() {
+            return Err(format!(
+                "Stop orders are not supported on contract {}",
+                contract.name
+            ));
+        }
+        if order.order_type != OrderType::Market
+            && !contract.price_in_band(order.price, matcher.reference_price())
+        {
+            return Err(format!(
+                "Price {} is outside the price band of contract {}",
+                order.price, contract.name
+            ));
+        }
+        // Market orders trade up to the edge of the band, the rest is dropped
+        let collar = match order.order_type {
+            OrderType::Market => contract.band_edge(order.side, matcher.reference_price()),
+            _ => None,
+        };
+        if order.time_in_force == TimeInForce::Fok && !matcher.can_fill_within(order, collar) {
+            return Err(format!("Order {} cannot be filled completely", order.id));
+        }
+        if order.order_type == OrderType::LimitMaker && matcher.would_take(order) {
+            return Err(format!("Post-only order {} would take liquidity", order.id));
+        }
+        Ok(matcher.place_order_within(order.clone(), collar))
+    }
+
+    /// Cancels every resting order of an account, see `Matcher::cancel_account_orders`
+    ///
+    /// Without a contract the active and halted contracts are walked in name order.
+    ///
+    /// # Arguments
+    /// * `account_id` - Account whose orders are canceled
+    /// * `symbol_id` - ID of the only contract to cancel on, None for all of them
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Order>)` - The canceled orders, in the order they were canceled
+    /// * `Err(String)` - Error message if the contract does not exist or is not active
+    pub fn cancel_all_orders(
+        &mut self,
+        account_id: u64,
+        symbol_id: Option<&str>,
+    ) -> Result<Vec<Order>, String> {
+        let mut names = match symbol_id {
+            Some(symbol_id) => {
+                let contract = self
+                    .symbol_manager
+                    .get_symbol(symbol_id)
+                    .ok_or_else(|| format!("Contract with id {} does not exist", symbol_id))?;
+                if !contract.status.accepts_cancels() {
+                    return Err(format!("Contract with id {} is not active", symbol_id));
+                }
+                vec![symbol_id.to_string()]
+            }
+            None => self
+                .symbol_manager
+                .list_symbols()
+                .into_iter()
+                .filter(|contract| contract.status.accepts_cancels())
+                .map(|contract| contract.name.clone())
+                .collect(),
+        };
+        names.sort();
+        let mut canceled = Vec::new();
+        for name in names {
+            if let Some(matcher) = self.symbol_manager.get_matcher(&name) {
+                canceled.extend(matcher.cancel_account_orders(account_id));
+            }
+        }
+        Ok(canceled)
+    }
+
+    /// Cancels every order resting in the book of a contract, of every account, see
+    /// `Matcher::cancel_all_orders`
+    ///
+    /// # Arguments
+    /// * `symbol_id` - ID of the contract
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Order>)` - The canceled orders, in the order they were canceled
+    /// * `Err(String)` - Error message if the contract does not exist or takes no cancels
+    pub fn cancel_book(&mut self, symbol_id: &str) -> Result<Vec<Order>, String> {
+        let contract = self
+            .symbol_manager
+            .get_symbol(symbol_id)
+            .ok_or_else(|| format!("Contract with id {} does not exist", symbol_id))?;
+        if !contract.status.accepts_cancels() {
+            return Err(format!("Contract with id {} is not active", symbol_id));
+        }
+        Ok(self
+            .symbol_manager
+            .get_matcher(symbol_id)
+            .map(|matcher| matcher.cancel_all_orders())
+            .unwrap_or_default())
+    }
+
+    /// Changes the price and quantity of a resting order, see `Matcher::amend_order`
+    ///
+    /// # Arguments
+    /// * `symbol_id` - ID of the contract the order belongs to
+    /// * `order_id` - ID of the order to amend
+    /// * `price` - The new price
+    /// * `quantity` - The new quantity, including what already filled
+    ///
+    /// # Returns
+    /// * `Ok(Order)` - The amended order
+    /// * `Err(String)` - Error message if the amendment is rejected
+    pub fn amend_order(
+        &mut self,
+        symbol_id: &str,
+        order_id: &str,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Result<Order, String> {
+        let (contract, matcher) = self
+            .symbol_manager
+            .get_symbol_and_matcher(symbol_id)
+            .ok_or_else(|| format!("Contract with id {} does not exist", symbol_id))?;
+
+        if contract.status != SymbolStatus::Active {
+            return Err(format!("Contract with id {} is not active", symbol_id));
+        }
+        if !contract.validate_price(price) {
+            return Err(format!("Invalid price for contract {}", contract.name));
+        }
+        if !contract.validate_quantity(quantity) {
+            return Err(format!("Invalid quantity for contract {}", contract.name));
+        }
+        if !contract.price_in_band(price, matcher.reference_price()) {
+            return Err(format!(
+                "Price {} is outside the price band of contract {}",
+                price, contract.name
+            ));
+        }
+        matcher.amend_order(order_id, price, quantity)
+    }
+
+    /// Injects a liquidation order
+    ///
+    /// The order is matched as a market order against the book, whatever it
+    /// cannot fill is dropped instead of resting. Liquidations bypass the price,
+    /// quantity and leverage limits and also run on inactive contracts, so risk
+    /// can be reduced while trading is halted.
+    ///
+    /// # Arguments
+    /// * `order` - The liquidation order, its type and price are ignored
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Trade>)` - List of trades generated by the liquidation
+    /// * `Err(String)` - Error message if the contract is not listed
+    pub fn liquidate(&mut self, order: &Order) -> Result<Vec<Trade>, String> {
+        let (_, matcher) = self
+            .symbol_manager
+            .get_symbol_and_matcher(&order.symbol)
+            .ok_or_else(|| format!("Contract with id {} does not exist", &order.symbol))?;
+        if order.quantity <= Decimal::ZERO {
+            return Err(format!("Invalid liquidation quantity {}", order.quantity));
+        }
+        let liquidation = Order {
+            order_type: OrderType::Market,
+            price: Decimal::ZERO,
+            ..order.clone()
+        };
+        let trades = matcher.place_order(liquidation);
+        matcher.cancel_order(&order.id);
+        Ok(trades)
+    }
+
+    /// Cancels an existing order
+    ///
+    /// # Arguments
+    /// * `symbol_id` - ID of the contract the order belongs to
+    /// * `order_id` - ID of the order to cancel
+    ///
+    /// # Returns
+    /// * `Ok(Some(Order))` - The canceled order if found
+    /// * `Ok(None)` - If order was not found
+    /// * `Err(String)` - Error message if cancellation fails
+    pub fn cancel_order(
+        &mut self,
+        symbol_id: &str,
+        order_id: &str,
+    ) -> Result<Option<Order>, String> {
+        let (contract, matcher) = self
+            .symbol_manager
+            .get_symbol_and_matcher(symbol_id)
+            .ok_or_else(|| format!("Contract with id {} does not exist", symbol_id))?;
+
+        if !contract.status.accepts_cancels() {
+            return Err(format!("Contract with id {} is not active", symbol_id));
+        }
+
+        Ok(matcher.cancel_order(order_id))
+    }
+
+    /// Lists a new contract
+    ///
+    /// The maintenance margin must stay below the initial margin at maximum
+    /// leverage, otherwise positions opened at that leverage could be liquidated
+    /// right away.
+    ///
+    /// # Arguments
+    /// * `symbol` - The contract to add
+    ///
+    /// # Returns
+    /// Result indicating success or failure
+    pub fn add_symbol(&mut self, symbol: Symbol) -> Result<(), String> {
+        if symbol.settle_balances {
+            return Err(format!(
+                "Contract {} cannot settle balances, margin is not held in the ledger",
+                symbol.name
+            ));
+        }
+        if symbol.max_leverage < Decimal::ONE {
+            return Err(format!(
+                "Maximum leverage of contract {} must be at least 1",
+                symbol.name
+            ));
+        }
+        if symbol.maintenance_margin <= Decimal::ZERO
+            || symbol.maintenance_margin * symbol.max_leverage >= Decimal::ONE
+        {
+            return Err(format!(
+                "Maintenance margin of contract {} must be positive and below 1/{}",
+                symbol.name, symbol.max_leverage
+            ));
+        }
+        self.symbol_manager.add_symbol(symbol)
+    }
+
+    /// Updates an existing contract's properties
+    ///
+    /// # Arguments
+    /// * `symbol` - The updated contract information
+    ///
+    /// # Returns
+    /// Result indicating success or failure
+    pub fn update_symbol(&mut self, symbol: Symbol) -> Result<(), String> {
+        self.symbol_manager.update_symbol(symbol)
+    }
+
+    /// Halts trading in an active contract, see `SymbolManager::halt_symbol`
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the contract to halt
+    /// * `cancel_orders` - Whether the orders resting in its book are canceled
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Order>)` - The canceled orders
+    /// * `Err(String)` - Error message if the contract does not exist or is not active
+    pub fn halt_symbol(&mut self, symbol: &str, cancel_orders: bool) -> Result<Vec<Order>, String> {
+        self.symbol_manager.halt_symbol(symbol, cancel_orders)
+    }
+
+    /// Resumes trading in a halted contract
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the contract to resume
+    ///
+    /// # Returns
+    /// Whether the contract was halted
+    pub fn resume_symbol(&mut self, symbol: &str) -> bool {
+        self.symbol_manager.resume_symbol(symbol)
+    }
+
+    /// Delists a contract, dropping its order book and mark price
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the contract to remove
+    ///
+    /// # Returns
+    /// Result indicating success or failure
+    pub fn del_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        self.symbol_manager.delist_symbol(symbol)?;
+        self.mark_prices.remove(symbol);
+        Ok(())
+    }
+
+    /// Retrieves a contract's configuration
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the contract
+    ///
+    /// # Returns
+    /// Reference to the contract if found, None otherwise
+    pub fn get_symbol(&self, symbol: &str) -> Option<&Symbol> {
+        self.symbol_manager.get_symbol(symbol)
+    }
+
+    /// Retrieves the order book of a contract
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the contract
+    ///
+    /// # Returns
+    /// Reference to the order book if the contract is listed, None otherwise
+    pub fn get_orderbook(&self, symbol: &str) -> Option<&OrderBook> {
+        self.symbol_manager.get_orderbook(symbol)
+    }
+
+    /// Retrieves the matcher of a contract as shared copy-on-write
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the contract
+    ///
+    /// # Returns
+    /// The shared matcher if the contract is listed, None otherwise
+    pub fn get_shared_matcher(&self, symbol: &str) -> Option<&Arc<Matcher>> {
+        self.symbol_manager.get_shared_matcher(symbol)
+    }
+
+    /// Lists all contracts
+    ///
+    /// # Returns
+    /// Vector of references to all contracts
+    pub fn list_symbols(&self) -> Vec<&Symbol> {
+        self.symbol_manager.list_symbols()
+    }
+
+    /// Records the mark price of a listed contract
+    ///
+    /// # Arguments
+    /// * `mark` - The contract and its new mark price
+    ///
+    /// # Returns
+    /// Result indicating success or failure
+    pub fn set_mark_price(&mut self, mark: &MarkPrice) -> Result<(), String> {
+        if self.symbol_manager.get_orderbook(&mark.symbol).is_none() {
+            return Err(format!("Contract with id {} does not exist", mark.symbol));
+        }
+        if mark.price <= Decimal::ZERO {
+            return Err(format!("Invalid mark price {}", mark.price));
+        }
+        self.mark_prices.insert(mark.symbol.clone(), mark.price);
+        Ok(())
+    }
+
+    /// Returns the last mark price of a contract
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the contract
+    ///
+    /// # Returns
+    /// The mark price, None if no price was published for the contract
+    pub fn mark_price(&self, symbol: &str) -> Option<Decimal> {
+        self.mark_prices.get(symbol).copied()
+    }
+
+    /// Moves a contract, its order book and mark price out into a processor of
+    /// their own
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the contract to move
+    ///
+    /// # Returns
+    /// Processor holding only the contract if found, None otherwise
+    pub fn split_off(&mut self, symbol: &str) -> Option<Self> {
+        let symbol_manager = self.symbol_manager.split_off(symbol)?;
+        let mark_prices = self.mark_prices.remove_entry(symbol).into_iter().collect();
+        Some(Self {
+            symbol_manager,
+            mark_prices,
+        })
+    }
+
+    /// Moves the contracts of a processor split off before back into this one
+    pub fn merge(&mut self, other: Self) {
+        self.symbol_manager.merge(other.symbol_manager);
+        self.mark_prices.extend(other.mark_prices);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::entry::OrderSide;
+    use rust_decimal_macros::dec;
+
+    fn order(id: &str, side: OrderSide, price: Decimal, leverage: Decimal) -> Order {
+        Order {
+            id: id.to_string(),
+            symbol: "BTC-PERP".to_string(),
+            order_type: OrderType::Limit,
+            side,
+            price,
+            quantity: dec!(1),
+            leverage,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn leverage_is_capped_and_liquidations_never_rest() {
+        let mut processor = ContractProcessor::new();
+        let mut contract = Symbol {
+            name: "BTC-PERP".to_string(),
+            min_price: dec!(1),
+            max_price: dec!(1000000),
+            min_quantity: dec!(0.001),
+            max_quantity: dec!(100),
+            max_leverage: dec!(20),
+            maintenance_margin: dec!(0.05),
+            ..Default::default()
+        };
+        assert!(processor.add_symbol(contract.clone()).is_err());
+        contract.maintenance_margin = dec!(0.01);
+        processor.add_symbol(contract).unwrap();
+
+        let reckless = order("1", OrderSide::Buy, dec!(100), dec!(25));
+        assert!(processor.place_order(&reckless).is_err());
+        processor
+            .place_order(&order("2", OrderSide::Buy, dec!(100), dec!(20)))
+            .unwrap();
+
+        // Only one of the two contracts finds a bid, the rest is dropped
+        let mut liquidation = order("3", OrderSide::Sell, dec!(0), dec!(0));
+        liquidation.quantity = dec!(2);
+        let trades = processor.liquidate(&liquidation).unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].price, dec!(100));
+        let book = processor.get_orderbook("BTC-PERP").unwrap();
+        assert!(book.get_order("3").is_none());
+        assert_eq!(book.order_count(), 0);
+
+        let mark = MarkPrice {
+            symbol: "BTC-PERP".to_string(),
+            price: dec!(99.5),
+        };
+        processor.set_mark_price(&mark).unwrap();
+        assert_eq!(processor.mark_price("BTC-PERP"), Some(dec!(99.5)));
+        processor.del_symbol("BTC-PERP").unwrap();
+        assert_eq!(processor.mark_price("BTC-PERP"), None);
+    }
+
+    #[test]
+    fn price_bands_follow_the_mid_then_the_last_trade_price() {
+        let mut processor = ContractProcessor::new();
+        let contract = Symbol {
+            name: "BTC-PERP".to_string(),
+            min_price: dec!(1),
+            max_price: dec!(1000000),
+            min_quantity: dec!(0.001),
+            max_quantity: dec!(100),
+            max_leverage: dec!(20),
+            maintenance_margin: dec!(0.01),
+            ..Default::default()
+        };
+        processor.add_symbol(contract.clone()).unwrap();
+        let mut place = |id, side, price| processor.place_order(&order(id, side, price, dec!(1)));
+        place("1", OrderSide::Buy, dec!(100)).unwrap();
+        place("2", OrderSide::Sell, dec!(110)).unwrap();
+
+        processor
+            .update_symbol(Symbol {
+                price_band: dec!(0.1),
+                ..contract
+            })
+            .unwrap();
+        let mut place = |id, side, price| processor.place_order(&order(id, side, price, dec!(1)));
+        // Before the first trade the band is centered on the mid price of 105
+        assert!(place("3", OrderSide::Sell, dec!(116)).is_err());
+        place("4", OrderSide::Sell, dec!(115)).unwrap();
+        // Then on the last trade price
+        place("5", OrderSide::Sell, dec!(100)).unwrap();
+        assert!(place("6", OrderSide::Buy, dec!(89)).is_err());
+        place("7", OrderSide::Buy, dec!(90)).unwrap();
+        assert!(processor
+            .amend_order("BTC-PERP", "7", dec!(89), dec!(1))
+            .is_err());
+        let book = processor.get_orderbook("BTC-PERP").unwrap();
+        assert!(book.get_order("3").is_none());
+        assert_eq!(book.get_order("7").unwrap().price, dec!(90));
+    }
+
+    #[test]
+    fn market_orders_stop_at_the_edge_of_the_price_band() {
+        let mut processor = ContractProcessor::new();
+        let contract = Symbol {
+            name: "BTC-PERP".to_string(),
+            min_price: dec!(1),
+            max_price: dec!(1000000),
+            min_quantity: dec!(0.001),
+            max_quantity: dec!(100),
+            max_leverage: dec!(20),
+            maintenance_margin: dec!(0.01),
+            price_band: dec!(0.1),
+            ..Default::default()
+        };
+        processor.add_symbol(contract).unwrap();
+        let mut place = |order: Order| processor.place_order(&order);
+        // Without a reference price any ask is accepted
+        place(order("1", OrderSide::Sell, dec!(111), dec!(1))).unwrap();
+        place(order("2", OrderSide::Sell, dec!(100), dec!(1))).unwrap();
+        place(order("3", OrderSide::Buy, dec!(100), dec!(1))).unwrap();
+        place(order("4", OrderSide::Sell, dec!(105), dec!(1))).unwrap();
+        place(order("5", OrderSide::Sell, dec!(110), dec!(1))).unwrap();
+
+        let market = |id: &str, time_in_force| Order {
+            order_type: OrderType::Market,
+            quantity: dec!(3),
+            time_in_force,
+            ..order(id, OrderSide::Buy, dec!(100), dec!(1))
+        };
+        // Only two of the three asks are within 10% of the last trade at 100
+        assert!(place(market("6", TimeInForce::Fok)).is_err());
+        let trades = place(market("7", TimeInForce::Gtc)).unwrap();
+        let prices: Vec<_> = trades.iter().map(|trade| trade.price).collect();
+        assert_eq!(prices, [dec!(105), dec!(110)]);
+        // The remainder is dropped rather than resting beyond the band
+        let book = processor.get_orderbook("BTC-PERP").unwrap();
+        assert!(book.get_order("7").is_none());
+        assert_eq!(book.get_best_ask(), Some(dec!(111)));
+    }
+}
@@ -0,0 +1,13 @@
+//! Perpetual Contract Module
+//!
+//! This module provides functionality for perpetual contract trading alongside the
+//! spot market:
+//! - `contract_processor`: Processes contract orders, mark prices and liquidations
+//!
+//! Contracts are listed as symbols carrying a maximum leverage and a maintenance
+//! margin and are matched by the same `Matcher` and `OrderBook` as spot symbols.
+//! Commands pick the market they address with `MarketType`.
+
+pub mod contract_processor;
+
+pub use contract_processor::{ContractProcessor, MarkPrice};
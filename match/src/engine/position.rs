@@ -0,0 +1,154 @@
+//! Position Module
+//!
+//! This module tracks the net base currency position of every account per symbol
+//! with its average entry price and the profit realized when it is reduced. Only
+//! settled trades move positions, so they exist on symbols that settle balances.
+//! Buys add to the position and sells take from it; a trade that crosses zero
+//! closes the old position and opens the rest at the trade price. Fees are booked
+//! in the ledger and leave positions untouched.
+
+use crate::engine::ledger::Settlement;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Position of an account in one symbol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct Position {
+    /// Net base quantity, negative if more was sold than bought
+    pub quantity: Decimal,
+    /// Average price the open quantity was entered at, zero if flat
+    pub entry_price: Decimal,
+    /// Profit in the quote currency realized by reducing the position
+    pub realized_pnl: Decimal,
+}
+
+impl Position {
+    /// Applies a fill to the position
+    ///
+    /// # Arguments
+    /// * `quantity` - Filled base quantity, positive for buys and negative for sells
+    /// * `price` - Fill price
+    fn fill(&mut self, quantity: Decimal, price: Decimal) {
+        let same_direction = self.quantity.is_zero()
+            || self.quantity.is_sign_positive() == quantity.is_sign_positive();
+        if same_direction {
+            let total = self.quantity + quantity;
+            self.entry_price =
+                (self.entry_price * self.quantity.abs() + price * quantity.abs()) / total.abs();
+            self.quantity = total;
+            return;
+        }
+        let closed = quantity.abs().min(self.quantity.abs());
+        let direction = if self.quantity.is_sign_positive() {
+            Decimal::ONE
+        } else {
+            -Decimal::ONE
+        };
+        self.realized_pnl += (price - self.entry_price) * closed * direction;
+        self.quantity += quantity;
+        if self.quantity.is_zero() {
+            self.entry_price = Decimal::ZERO;
+        } else if self.quantity.is_sign_positive() != direction.is_sign_positive() {
+            // The fill crossed zero, the rest opens a position at the fill price
+            self.entry_price = price;
+        }
+    }
+}
+
+/// Positions of all accounts of a tenant
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Positions {
+    /// Positions keyed by account and symbol
+    accounts: BTreeMap<u64, BTreeMap<String, Position>>,
+    /// Accounts whose positions changed since they were last taken
+    #[serde(skip)]
+    touched: BTreeSet<u64>,
+}
+
+impl Positions {
+    /// Returns all positions of an account keyed by symbol
+    pub fn positions(&self, account_id: u64) -> Option<&BTreeMap<String, Position>> {
+        self.accounts.get(&account_id)
+    }
+
+    /// Returns the IDs of all accounts that ever traded
+    pub fn accounts(&self) -> impl Iterator<Item = u64> + '_ {
+        self.accounts.keys().copied()
+    }
+
+    /// Returns the accounts whose positions changed since the last call
+    pub fn take_touched(&mut self) -> BTreeSet<u64> {
+        std::mem::take(&mut self.touched)
+    }
+
+    /// Moves the positions of both sides of a settled trade
+    ///
+    /// # Arguments
+    /// * `settlement` - The settled trade
+    pub fn apply(&mut self, settlement: &Settlement) {
+        let sides = [
+            (settlement.buyer_account_id, settlement.quantity),
+            (settlement.seller_account_id, -settlement.quantity),
+        ];
+        for (account_id, quantity) in sides {
+            self.touched.insert(account_id);
+            self.accounts
+                .entry(account_id)
+                .or_default()
+                .entry(settlement.symbol.clone())
+                .or_default()
+                .fill(quantity, settlement.price);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn trade(buyer: u64, seller: u64, price: Decimal, quantity: Decimal) -> Settlement {
+        Settlement {
+            symbol: "BTCUSDT".to_string(),
+            buyer_account_id: buyer,
+            seller_account_id: seller,
+            price,
+            quantity,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn entry_price_averages_and_reductions_realize_pnl() {
+        let mut positions = Positions::default();
+        positions.apply(&trade(1, 2, dec!(100), dec!(1)));
+        positions.apply(&trade(1, 2, dec!(130), dec!(2)));
+        let long = positions.positions(1).unwrap()["BTCUSDT"];
+        assert_eq!(long.quantity, dec!(3));
+        assert_eq!(long.entry_price, dec!(120));
+        let short = positions.positions(2).unwrap()["BTCUSDT"];
+        assert_eq!(short.quantity, dec!(-3));
+        assert_eq!(short.entry_price, dec!(120));
+
+        // Selling 4 closes the long at a profit and opens a short of 1
+        positions.apply(&trade(2, 1, dec!(150), dec!(4)));
+        let flipped = positions.positions(1).unwrap()["BTCUSDT"];
+        assert_eq!(flipped.quantity, dec!(-1));
+        assert_eq!(flipped.entry_price, dec!(150));
+        assert_eq!(flipped.realized_pnl, dec!(90));
+        let covered = positions.positions(2).unwrap()["BTCUSDT"];
+        assert_eq!(covered.quantity, dec!(1));
+        assert_eq!(covered.realized_pnl, dec!(-90));
+
+        positions.apply(&trade(1, 2, dec!(140), dec!(1)));
+        let flat = positions.positions(1).unwrap()["BTCUSDT"];
+        assert_eq!(flat.quantity, dec!(0));
+        assert_eq!(flat.entry_price, dec!(0));
+        assert_eq!(flat.realized_pnl, dec!(100));
+        assert_eq!(
+            positions.take_touched().into_iter().collect::<Vec<_>>(),
+            [1, 2]
+        );
+    }
+}
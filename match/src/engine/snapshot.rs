@@ -0,0 +1,655 @@
+//! Snapshot Schema Module
+//!
+//! This module defines the on-disk format of engine snapshots. A snapshot is a small
+//! versioned envelope around the engine state:
+//!
+//! ```text
+//! | magic "RMSS" (4 bytes) | version (u32 LE) | features length (u32 LE) | features | payload |
+//! ```
+//!
+//! The payload is the JSON encoded engine state, so fields added with `#[serde(default)]`
+//! stay readable by older and newer engines alike. Object keys are written in sorted
+//! order, so equal engine states encode to identical bytes and can be compared by
+//! `checksum`. Structural changes bump
+//! `SNAPSHOT_VERSION` and register a migration that rewrites the previous version's
+//! JSON tree, so `on_snapshot` can restore any supported version.
+//!
+//! Fields an older engine does not know are dropped silently when it reads the payload,
+//! so the envelope lists the command features the state was built with, as a JSON
+//! array of names. Like `codec::decode` refuses commands, `decode` refuses snapshots
+//! listing features this build does not support instead of restoring part of them.
+//!
+//! Version history:
+//! - 1: headerless bincode dump of the single-tenant engine
+//! - 2: envelope with JSON payload, state partitioned by tenant
+//! - 3: envelope listing the required command features
+
+use std::collections::BTreeSet;
+
+use serde_json::Value;
+use sha3::{Digest, Sha3_256};
+
+use crate::engine::codec::SUPPORTED_FEATURES;
+use crate::engine::matchengine::MatchEngine;
+
+/// Magic bytes at the start of every versioned snapshot
+const SNAPSHOT_MAGIC: &[u8; 4] = b"RMSS";
+/// Size of the magic and version in bytes
+const HEADER_SIZE: usize = 8;
+/// Size of the features length in bytes
+const FEATURES_LEN_SIZE: usize = 4;
+/// First snapshot version listing the required features
+const FEATURES_VERSION: u32 = 3;
+/// Snapshot version written by this engine
+pub const SNAPSHOT_VERSION: u32 = 3;
+
+/// Migration step upgrading the JSON tree of version N to version N + 1
+type Migration = fn(Value) -> Result<Value, String>;
+
+/// Migrations indexed by source version, `MIGRATIONS[0]` upgrades version 1 to 2
+const MIGRATIONS: [Migration; (SNAPSHOT_VERSION - 1) as usize] =
+    [migrate_v1_to_v2, migrate_v2_to_v3];
+
+/// Snapshot split into its parts, see `read_versioned`
+struct Versioned<'a> {
+    /// Snapshot version
+    version: u32,
+    /// Required command features, empty before `FEATURES_VERSION`
+    features: Vec<String>,
+    /// Encoded engine state
+    payload: &'a [u8],
+}
+
+/// Encodes the engine state into a versioned snapshot
+///
+/// # Arguments
+/// * `engine` - Engine state to encode
+///
+/// # Returns
+/// * `Ok(Vec<u8>)` - The snapshot bytes
+/// * `Err(String)` - Error message if the state cannot be encoded
+pub fn encode(engine: &MatchEngine) -> Result<Vec<u8>, String> {
+    let features = serde_json::to_vec(engine.features())
+        .map_err(|e| format!("failed to encode snapshot features: {}", e))?;
+    let mut data = Vec::with_capacity(HEADER_SIZE + FEATURES_LEN_SIZE + features.len());
+    data.extend_from_slice(SNAPSHOT_MAGIC);
+    data.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    data.extend_from_slice(&(features.len() as u32).to_le_bytes());
+    data.extend_from_slice(&features);
+    // Going through `Value` sorts the keys of the hash maps in the engine state
+    let state =
+        serde_json::to_value(engine).map_err(|e| format!("failed to encode snapshot: {}", e))?;
+    serde_json::to_writer(&mut data, &state)
+        .map_err(|e| format!("failed to encode snapshot: {}", e))?;
+    Ok(data)
+}
+
+/// Computes the checksum of the engine state in a snapshot
+///
+/// The feature list is left out: a node that restored a snapshot written before
+/// features were listed lacks the features of the entries before it, while its state
+/// equals that of a node which applied them.
+///
+/// # Arguments
+/// * `data` - Snapshot bytes
+///
+/// # Returns
+/// Hex encoded SHA3-256 digest of the snapshot payload, or of the whole data if it
+/// has no envelope
+pub fn checksum(data: &[u8]) -> String {
+    let payload = read_versioned(data).map_or(data, |versioned| versioned.payload);
+    hex::encode(Sha3_256::digest(payload))
+}
+
+/// Decodes a snapshot of any supported version into the current engine state
+///
+/// # Arguments
+/// * `data` - Snapshot bytes
+///
+/// # Returns
+/// * `Ok(MatchEngine)` - The restored engine, migrated to the current version
+/// * `Err(String)` - Error message if the snapshot is corrupt, too new or requires
+///   features this build does not support
+pub fn decode(data: &[u8]) -> Result<MatchEngine, String> {
+    let versioned = read_versioned(data)?;
+    let version = versioned.version;
+    if version == 0 || version > SNAPSHOT_VERSION {
+        return Err(format!(
+            "unsupported snapshot version {}, this engine supports up to {}",
+            version, SNAPSHOT_VERSION
+        ));
+    }
+    let features = supported_features(&versioned.features)?;
+    let mut state = if version == 1 {
+        v1::decode(versioned.payload)?
+    } else {
+        serde_json::from_slice(versioned.payload)
+            .map_err(|e| format!("corrupt snapshot payload: {}", e))?
+    };
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        state = migration(state)?;
+    }
+    let mut engine: MatchEngine =
+        serde_json::from_value(state).map_err(|e| format!("failed to decode snapshot: {}", e))?;
+    *engine.features_mut() = features;
+    Ok(engine)
+}
+
+/// Splits a snapshot into its version, required features and payload
+fn read_versioned(data: &[u8]) -> Result<Versioned<'_>, String> {
+    if data.len() < HEADER_SIZE || &data[..4] != SNAPSHOT_MAGIC {
+        return Ok(Versioned {
+            version: 1,
+            features: Vec::new(),
+            payload: data,
+        });
+    }
+    let version = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+    if version < FEATURES_VERSION {
+        return Ok(Versioned {
+            version,
+            features: Vec::new(),
+            payload: &data[HEADER_SIZE..],
+        });
+    }
+    let rest = &data[HEADER_SIZE..];
+    if rest.len() < FEATURES_LEN_SIZE {
+        return Err("corrupt snapshot: truncated features".to_string());
+    }
+    let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+    let rest = &rest[FEATURES_LEN_SIZE..];
+    if rest.len() < len {
+        return Err("corrupt snapshot: truncated features".to_string());
+    }
+    let features = serde_json::from_slice(&rest[..len])
+        .map_err(|e| format!("corrupt snapshot features: {}", e))?;
+    Ok(Versioned {
+        version,
+        features,
+        payload: &rest[len..],
+    })
+}
+
+/// Checks that this build supports every feature a snapshot requires
+///
+/// # Returns
+/// * `Ok(BTreeSet)` - The features, as listed in `SUPPORTED_FEATURES`
+/// * `Err(String)` - Error message naming the unsupported features
+fn supported_features(features: &[String]) -> Result<BTreeSet<&'static str>, String> {
+    let unsupported: Vec<&String> = features
+        .iter()
+        .filter(|feature| !SUPPORTED_FEATURES.contains(&feature.as_str()))
+        .collect();
+    if !unsupported.is_empty() {
+        return Err(format!(
+            "snapshot requires unsupported features {:?}, upgrade this node",
+            unsupported
+        ));
+    }
+    Ok(SUPPORTED_FEATURES
+        .iter()
+        .copied()
+        .filter(|supported| features.iter().any(|feature| feature == supported))
+        .collect())
+}
+
+/// Upgrades a single-tenant state into the tenant partitioned layout
+///
+/// The single spot processor becomes the default tenant and its symbols are tagged
+/// with the tenant ID.
+fn migrate_v1_to_v2(mut state: Value) -> Result<Value, String> {
+    let tenant = crate::engine::tenant::DEFAULT_TENANT;
+    let index = state.get("index").cloned().unwrap_or(Value::from(0u64));
+    let mut spot_processor = state
+        .get_mut("spot_processor")
+        .map(Value::take)
+        .ok_or("v1 snapshot has no spot processor")?;
+    if let Some(symbols) = spot_processor
+        .pointer_mut("/symbol_manager/symbols")
+        .and_then(Value::as_object_mut)
+    {
+        for symbol in symbols.values_mut() {
+            if let Some(symbol) = symbol.as_object_mut() {
+                symbol.insert("tenant".to_string(), Value::from(tenant));
+            }
+        }
+    }
+    Ok(serde_json::json!({
+        "index": index,
+        "tenants": {
+            tenant: {
+                "id": tenant,
+                "sequence": 0,
+                "spot_processor": spot_processor,
+            }
+        }
+    }))
+}
+
+/// Version 3 only adds the feature list to the envelope, the state is unchanged
+fn migrate_v2_to_v3(state: Value) -> Result<Value, String> {
+    Ok(state)
+}
+
+/// Decoder for version 1 snapshots
+///
+/// The types here are frozen copies of the version 1 engine layout and must never
+/// change, otherwise version 1 snapshots become unreadable.
+mod v1 {
+    use std::collections::{BTreeMap, HashMap};
+
+    use rust_decimal::Decimal;
+    use serde::{Deserialize, Serialize};
+    use serde_json::Value;
+
+    use crate::engine::entry::order::OrderStatus;
+    use crate::engine::entry::{OrderSide, OrderType, SymbolStatus};
+
+    #[derive(Serialize, Deserialize)]
+    pub struct MatchEngine {
+        pub index: u64,
+        pub spot_processor: OrderProcessor,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct OrderProcessor {
+        pub symbol_manager: SymbolManager,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct SymbolManager {
+        pub symbols: HashMap<String, Symbol>,
+        pub matchers: HashMap<String, Matcher>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Symbol {
+        pub name: String,
+        pub base_currency: String,
+        pub quote_currency: String,
+        pub price_precision: i32,
+        pub quantity_precision: i32,
+        pub min_price: Decimal,
+        pub max_price: Decimal,
+        pub min_quantity: Decimal,
+        pub max_quantity: Decimal,
+        pub status: SymbolStatus,
+        pub created_at: u64,
+        pub updated_at: u64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Matcher {
+        pub orderbook: OrderBook,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct OrderBook {
+        pub symbol: String,
+        pub bids: BTreeMap<Decimal, Vec<Order>>,
+        pub asks: BTreeMap<Decimal, Vec<Order>>,
+        pub orders_by_id: HashMap<String, Order>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct Order {
+        pub id: String,
+        pub symbol: String,
+        pub order_type: OrderType,
+        pub side: OrderSide,
+        pub price: Decimal,
+        pub quantity: Decimal,
+        pub filled_quantity: Decimal,
+        pub status: OrderStatus,
+        pub created_at: u64,
+        pub updated_at: u64,
+    }
+
+    /// Decodes a version 1 bincode snapshot into its JSON tree
+    pub fn decode(data: &[u8]) -> Result<Value, String> {
+        let engine: MatchEngine =
+            bincode::deserialize(data).map_err(|e| format!("corrupt v1 snapshot: {}", e))?;
+        serde_json::to_value(&engine).map_err(|e| format!("failed to convert v1 snapshot: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::codec;
+    use crate::engine::entry::{Order, OrderSide, OrderType, Symbol};
+    use crate::engine::matchengine::{CommandEnvelope, MatchCmd, MatchCmdType};
+    use rust_decimal_macros::dec;
+    use std::collections::{BTreeMap, HashMap};
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Set to rewrite the golden files of the current snapshot version
+    const UPDATE_GOLDEN: &str = "UPDATE_GOLDEN_SNAPSHOTS";
+
+    fn symbol(name: &str) -> Symbol {
+        Symbol::new(
+            name.to_string(),
+            "BTC".to_string(),
+            "USDT".to_string(),
+            2,
+            4,
+            dec!(0.01),
+            dec!(1000000),
+            dec!(0.0001),
+            dec!(1000),
+        )
+    }
+
+    fn order(id: &str, side: OrderSide, price: &str, quantity: &str) -> Order {
+        Order::new(
+            id.to_string(),
+            "BTCUSDT".to_string(),
+            OrderType::Limit,
+            side,
+            price.to_string(),
+            quantity.to_string(),
+        )
+    }
+
+    /// Applies a command at a fixed engine time, so the resulting state is reproducible
+    fn apply(engine: &mut MatchEngine, index: u64, cmd: MatchCmd) {
+        let envelope = CommandEnvelope {
+            cmd,
+            proposed_at: index * 1000,
+            ..Default::default()
+        };
+        engine.on_message(index, &codec::encode(&envelope)).unwrap();
+    }
+
+    /// Engine with two tenants, resting orders on both sides and a partial fill
+    fn populated_engine() -> MatchEngine {
+        let mut engine = MatchEngine::new();
+        let mut index = 0;
+        for tenant in ["default", "other"] {
+            index += 1;
+            apply(
+                &mut engine,
+                index,
+                MatchCmd {
+                    cmd: MatchCmdType::CreateSymbol,
+                    tenant: tenant.to_string(),
+                    symbol: Some(symbol("BTCUSDT")),
+                    ..Default::default()
+                },
+            );
+        }
+        let orders = [
+            order("1", OrderSide::Buy, "100", "1"),
+            order("2", OrderSide::Buy, "99", "2"),
+            order("3", OrderSide::Sell, "101", "1.5"),
+            order("4", OrderSide::Sell, "100", "0.4"),
+        ];
+        for order in orders {
+            index += 1;
+            apply(
+                &mut engine,
+                index,
+                MatchCmd {
+                    cmd: MatchCmdType::PlaceOrder,
+                    tenant: "default".to_string(),
+                    order: Some(order),
+                    ..Default::default()
+                },
+            );
+        }
+        engine
+    }
+
+    #[test]
+    fn current_version_round_trip() {
+        let engine = populated_engine();
+        let data = encode(&engine).unwrap();
+        assert_eq!(&data[..4], SNAPSHOT_MAGIC);
+        assert_eq!(data[4..8], SNAPSHOT_VERSION.to_le_bytes());
+
+        let restored = decode(&data).unwrap();
+        assert_eq!(
+            serde_json::to_value(&engine).unwrap(),
+            serde_json::to_value(&restored).unwrap()
+        );
+    }
+
+    /// Single-tenant engine in the frozen version 1 layout, with a partially filled bid
+    fn legacy_engine() -> v1::MatchEngine {
+        let resting = v1::Order {
+            id: "7".to_string(),
+            symbol: "BTCUSDT".to_string(),
+            order_type: OrderType::Limit,
+            side: OrderSide::Buy,
+            price: dec!(100),
+            quantity: dec!(2),
+            filled_quantity: dec!(0.5),
+            status: crate::engine::entry::order::OrderStatus::PartiallyFilled,
+            created_at: 1,
+            updated_at: 2,
+        };
+        v1::MatchEngine {
+            index: 42,
+            spot_processor: v1::OrderProcessor {
+                symbol_manager: v1::SymbolManager {
+                    symbols: HashMap::from([(
+                        "BTCUSDT".to_string(),
+                        v1::Symbol {
+                            name: "BTCUSDT".to_string(),
+                            base_currency: "BTC".to_string(),
+                            quote_currency: "USDT".to_string(),
+                            price_precision: 2,
+                            quantity_precision: 4,
+                            min_price: dec!(0.01),
+                            max_price: dec!(1000000),
+                            min_quantity: dec!(0.0001),
+                            max_quantity: dec!(1000),
+                            status: crate::engine::entry::SymbolStatus::Active,
+                            created_at: 1,
+                            updated_at: 1,
+                        },
+                    )]),
+                    matchers: HashMap::from([(
+                        "BTCUSDT".to_string(),
+                        v1::Matcher {
+                            orderbook: v1::OrderBook {
+                                symbol: "BTCUSDT".to_string(),
+                                bids: BTreeMap::from([(dec!(100), vec![resting.clone()])]),
+                                asks: BTreeMap::new(),
+                                orders_by_id: HashMap::from([("7".to_string(), resting)]),
+                            },
+                        },
+                    )]),
+                },
+            },
+        }
+    }
+
+    /// Path of a golden file, `v<N>.snapshot` holds a snapshot written in version N and
+    /// `v<N>.json` the engine state it restores to
+    fn golden_path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/snapshots")
+            .join(name)
+    }
+
+    fn read_golden(name: &str) -> Vec<u8> {
+        let path = golden_path(name);
+        fs::read(&path).unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e))
+    }
+
+    #[test]
+    fn restore_is_byte_for_byte() {
+        let data = encode(&populated_engine()).unwrap();
+        let mut restored = MatchEngine::new();
+        restored.on_snapshot(&data).unwrap();
+
+        let again = restored.snapshot().unwrap();
+        assert_eq!(again, data);
+        assert_eq!(checksum(&again), checksum(&data));
+    }
+
+    #[test]
+    fn encoding_does_not_depend_on_insertion_order() {
+        let create = |engine: &mut MatchEngine, index, name: &str| {
+            apply(
+                engine,
+                index,
+                MatchCmd {
+                    cmd: MatchCmdType::CreateSymbol,
+                    symbol: Some(symbol(name)),
+                    ..Default::default()
+                },
+            )
+        };
+        let names = ["BTCUSDT", "ETHUSDT", "SOLUSDT", "XRPUSDT"];
+        let mut forward = MatchEngine::new();
+        let mut backward = MatchEngine::new();
+        for (i, name) in names.iter().enumerate() {
+            create(&mut forward, 1, name);
+            create(&mut backward, 1, names[names.len() - 1 - i]);
+        }
+        assert_eq!(
+            checksum(&encode(&forward).unwrap()),
+            checksum(&encode(&backward).unwrap())
+        );
+    }
+
+    #[test]
+    fn current_version_matches_golden_file() {
+        let data = encode(&populated_engine()).unwrap();
+        let name = format!("v{}", SNAPSHOT_VERSION);
+        if std::env::var_os(UPDATE_GOLDEN).is_some() {
+            let state = serde_json::to_value(decode(&data).unwrap()).unwrap();
+            fs::write(golden_path(&format!("{}.snapshot", name)), &data).unwrap();
+            fs::write(
+                golden_path(&format!("{}.json", name)),
+                serde_json::to_string_pretty(&state).unwrap() + "\n",
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            checksum(&data),
+            checksum(&read_golden(&format!("{}.snapshot", name))),
+            "the version {} snapshot encoding changed: bump SNAPSHOT_VERSION and freeze the \
+             old golden file, or rerun with {}=1 if the change is compatible",
+            SNAPSHOT_VERSION,
+            UPDATE_GOLDEN
+        );
+    }
+
+    #[test]
+    fn golden_files_of_all_versions_restore() {
+        for version in 1..=SNAPSHOT_VERSION {
+            let data = read_golden(&format!("v{}.snapshot", version));
+            let expected: serde_json::Value =
+                serde_json::from_slice(&read_golden(&format!("v{}.json", version))).unwrap();
+            let restored = decode(&data)
+                .unwrap_or_else(|e| panic!("version {} golden snapshot: {}", version, e));
+            assert_eq!(
+                serde_json::to_value(&restored).unwrap(),
+                expected,
+                "version {} golden snapshot",
+                version
+            );
+
+            // Re-encoding the restored engine is stable
+            let mut reloaded = MatchEngine::new();
+            reloaded.on_snapshot(&restored.snapshot().unwrap()).unwrap();
+            assert_eq!(
+                checksum(&reloaded.snapshot().unwrap()),
+                checksum(&restored.snapshot().unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn v1_snapshot_migrates_to_default_tenant() {
+        let legacy = legacy_engine();
+        let data = bincode::serialize(&legacy).unwrap();
+
+        let restored = decode(&data).unwrap();
+        let state = serde_json::to_value(&restored).unwrap();
+        assert_eq!(state["index"], 42);
+        let tenant = &state["tenants"]["default"];
+        assert_eq!(tenant["id"], "default");
+        let symbols = &tenant["spot_processor"]["symbol_manager"]["symbols"];
+        assert_eq!(symbols["BTCUSDT"]["tenant"], "default");
+        let book = &tenant["spot_processor"]["symbol_manager"]["matchers"]["BTCUSDT"]["orderbook"];
+        assert_eq!(book["orders_by_id"]["7"]["filled_quantity"], "0.5");
+        assert_eq!(book["bids"]["100"][0]["id"], "7");
+
+        // a migrated snapshot is re-written in the current version
+        let data = encode(&restored).unwrap();
+        assert_eq!(data[4..8], SNAPSHOT_VERSION.to_le_bytes());
+        assert_eq!(state, serde_json::to_value(decode(&data).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        data.extend_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        data.extend_from_slice(b"{}");
+        assert!(decode(&data).is_err());
+
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(b"{}");
+        assert!(decode(&data).is_err());
+    }
+
+    #[test]
+    fn required_features_are_listed_and_restored() {
+        let mut engine = populated_engine();
+        apply(
+            &mut engine,
+            7,
+            MatchCmd {
+                cmd: MatchCmdType::CreateSymbol,
+                symbol: Some(Symbol {
+                    settle_balances: true,
+                    ..symbol("ETHUSDT")
+                }),
+                ..Default::default()
+            },
+        );
+        let data = encode(&engine).unwrap();
+        let restored = decode(&data).unwrap();
+        assert_eq!(restored.features(), &BTreeSet::from(["balances"]));
+        assert_eq!(encode(&restored).unwrap(), data);
+
+        // the feature list is not part of the checksum
+        let mut unlisted = restored.clone();
+        unlisted.features_mut().clear();
+        let bare = encode(&unlisted).unwrap();
+        assert_ne!(bare, data);
+        assert_eq!(checksum(&bare), checksum(&data));
+    }
+
+    #[test]
+    fn rejects_snapshots_with_unknown_features() {
+        let encoded = encode(&populated_engine()).unwrap();
+        assert_eq!(&encoded[HEADER_SIZE + FEATURES_LEN_SIZE..][..2], b"[]");
+        let features = br#"["balances","teleport"]"#;
+        let mut data = encoded[..HEADER_SIZE].to_vec();
+        data.extend_from_slice(&(features.len() as u32).to_le_bytes());
+        data.extend_from_slice(features);
+        data.extend_from_slice(&encoded[HEADER_SIZE + FEATURES_LEN_SIZE + 2..]);
+
+        let err = decode(&data).err().unwrap();
+        assert!(err.contains("teleport"), "{}", err);
+        assert!(!err.contains("balances"), "{}", err);
+        let mut engine = populated_engine();
+        assert!(engine.on_snapshot(&data).is_err());
+        assert_eq!(engine.snapshot().unwrap(), encoded);
+    }
+
+    #[test]
+    fn rejects_corrupt_data() {
+        assert!(decode(b"not a snapshot").is_err());
+        let mut data = encode(&populated_engine()).unwrap();
+        data.truncate(data.len() / 2);
+        assert!(decode(&data).is_err());
+    }
+}
@@ -3,11 +3,14 @@
 //! This module provides functionality for spot market trading:
 //! - `order_processor`: Processes orders and manages their lifecycle
 //! - `symbol_manager`: Manages trading symbols and their configurations
+//! - `trigger_manager`: Holds stop orders off the book until they are triggered
 //!
 //! Together these components handle all spot market operations.
 
 pub mod order_processor;
 pub mod symbol_manager;
+pub mod trigger_manager;
 
 pub use order_processor::OrderProcessor;
 pub use symbol_manager::SymbolManager;
+pub use trigger_manager::{StopOrder, TriggerManager};
@@ -3,9 +3,13 @@
 //! This module provides functionality for processing orders in the spot market.
 //! It handles order placement, cancellation, and symbol management through a unified interface.
 
-use crate::engine::entry::{Order, Symbol, SymbolStatus, Trade};
+use crate::engine::data::OrderBook;
+use crate::engine::entry::{Order, OrderType, Symbol, SymbolStatus, TimeInForce, Trade};
+use crate::engine::matchlogic::Matcher;
 use crate::engine::spot::SymbolManager;
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 
 /// Main processor for handling spot market orders
 /// Manages symbols and their associated order matching logic
@@ -50,7 +54,84 @@ impl OrderProcessor {
         if !symbol_info.validate_quantity(order.quantity) {
             return Err(format!("Invalid quantity for symbol {}", symbol_info.name));
         }
-        Ok(matcher.place_order(order.clone()))
+        // Only orders that may rest in the book can hide part of their quantity
+        if order.is_iceberg()
+            && (matches!(order.order_type, OrderType::Market | OrderType::Stop)
+                || !symbol_info.validate_quantity(order.display_quantity))
+        {
+            return Err(format!(
+                "Invalid display quantity for symbol {}",
+                symbol_info.name
+            ));
+        }
+        if order.is_stop() {
+            return Err(format!("Stop order {} is not triggered yet", order.id));
+        }
+        if order.order_type != OrderType::Market
+            && !symbol_info.price_in_band(order.price, matcher.reference_price())
+        {
+            return Err(format!(
+                "Price {} is outside the price band of symbol {}",
+                order.price, symbol_info.name
+            ));
+        }
+        // Market orders trade up to the edge of the band, the rest is dropped
+        let collar = match order.order_type {
+            OrderType::Market => symbol_info.band_edge(order.side, matcher.reference_price()),
+            _ => None,
+        };
+        if order.time_in_force == TimeInForce::Fok && !matcher.can_fill_within(order, collar) {
+            return Err(format!("Order {} cannot be filled completely", order.id));
+        }
+        if order.order_type == OrderType::LimitMaker && matcher.would_take(order) {
+            return Err(format!("Post-only order {} would take liquidity", order.id));
+        }
+        Ok(matcher.place_order_within(order.clone(), collar))
+    }
+
+    /// Checks a stop order before it is held for its trigger
+    ///
+    /// The order is checked like any order placed on the symbol, and its stop
+    /// price like a price.
+    ///
+    /// # Arguments
+    /// * `order` - The stop or stop-limit order
+    ///
+    /// # Returns
+    /// * `Ok(())` - If the order may wait for its trigger
+    /// * `Err(String)` - Error message if the order is rejected
+    pub fn check_stop_order(&self, order: &Order) -> Result<(), String> {
+        let symbol_info = self
+            .symbol_manager
+            .get_symbol(&order.symbol)
+            .ok_or_else(|| format!("Symbol with id {} does not exist", &order.symbol))?;
+
+        if symbol_info.status != SymbolStatus::Active {
+            return Err(format!("Symbol with id {} is not active", &order.symbol));
+        }
+        if !symbol_info.validate_price(order.price) {
+            return Err(format!("Invalid price for symbol {}", symbol_info.name));
+        }
+        if !symbol_info.validate_price(order.stop_price) {
+            return Err(format!(
+                "Invalid stop price for symbol {}",
+                symbol_info.name
+            ));
+        }
+        if !symbol_info.validate_quantity(order.quantity) {
+            return Err(format!("Invalid quantity for symbol {}", symbol_info.name));
+        }
+        // Only orders that may rest in the book can hide part of their quantity
+        if order.is_iceberg()
+            && (matches!(order.order_type, OrderType::Market | OrderType::Stop)
+                || !symbol_info.validate_quantity(order.display_quantity))
+        {
+            return Err(format!(
+                "Invalid display quantity for symbol {}",
+                symbol_info.name
+            ));
+        }
+        Ok(())
     }
 
     /// Cancels an existing order
@@ -74,13 +155,124 @@ impl OrderProcessor {
             .get_symbol_and_matcher(symbol_id)
             .ok_or_else(|| format!("Symbol with id {} does not exist", symbol_id))?;
 
-        if symbol_info.status != SymbolStatus::Active {
+        if !symbol_info.status.accepts_cancels() {
             return Err(format!("Symbol with id {} is not active", symbol_id));
         }
 
         Ok(matcher.cancel_order(order_id))
     }
 
+    /// Cancels every resting order of an account, see `Matcher::cancel_account_orders`
+    ///
+    /// Without a symbol the active and halted symbols are walked in name order; inactive
+    /// symbols keep their orders, like they refuse single cancels.
+    ///
+    /// # Arguments
+    /// * `account_id` - Account whose orders are canceled
+    /// * `symbol_id` - ID of the only symbol to cancel on, None for all of them
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Order>)` - The canceled orders, in the order they were canceled
+    /// * `Err(String)` - Error message if the symbol does not exist or is not active
+    pub fn cancel_all_orders(
+        &mut self,
+        account_id: u64,
+        symbol_id: Option<&str>,
+    ) -> Result<Vec<Order>, String> {
+        let mut names = match symbol_id {
+            Some(symbol_id) => {
+                let symbol_info = self
+                    .symbol_manager
+                    .get_symbol(symbol_id)
+                    .ok_or_else(|| format!("Symbol with id {} does not exist", symbol_id))?;
+                if !symbol_info.status.accepts_cancels() {
+                    return Err(format!("Symbol with id {} is not active", symbol_id));
+                }
+                vec![symbol_id.to_string()]
+            }
+            None => self
+                .symbol_manager
+                .list_symbols()
+                .into_iter()
+                .filter(|symbol| symbol.status.accepts_cancels())
+                .map(|symbol| symbol.name.clone())
+                .collect(),
+        };
+        names.sort();
+        let mut canceled = Vec::new();
+        for name in names {
+            if let Some(matcher) = self.symbol_manager.get_matcher(&name) {
+                canceled.extend(matcher.cancel_account_orders(account_id));
+            }
+        }
+        Ok(canceled)
+    }
+
+    /// Cancels every order resting in the book of a symbol, of every account, see
+    /// `Matcher::cancel_all_orders`
+    ///
+    /// # Arguments
+    /// * `symbol_id` - ID of the symbol
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Order>)` - The canceled orders, in the order they were canceled
+    /// * `Err(String)` - Error message if the symbol does not exist or takes no cancels
+    pub fn cancel_book(&mut self, symbol_id: &str) -> Result<Vec<Order>, String> {
+        let symbol_info = self
+            .symbol_manager
+            .get_symbol(symbol_id)
+            .ok_or_else(|| format!("Symbol with id {} does not exist", symbol_id))?;
+        if !symbol_info.status.accepts_cancels() {
+            return Err(format!("Symbol with id {} is not active", symbol_id));
+        }
+        Ok(self
+            .symbol_manager
+            .get_matcher(symbol_id)
+            .map(|matcher| matcher.cancel_all_orders())
+            .unwrap_or_default())
+    }
+
+    /// Changes the price and quantity of a resting order, see `Matcher::amend_order`
+    ///
+    /// # Arguments
+    /// * `symbol_id` - ID of the symbol the order belongs to
+    /// * `order_id` - ID of the order to amend
+    /// * `price` - The new price
+    /// * `quantity` - The new quantity, including what already filled
+    ///
+    /// # Returns
+    /// * `Ok(Order)` - The amended order
+    /// * `Err(String)` - Error message if the amendment is rejected
+    pub fn amend_order(
+        &mut self,
+        symbol_id: &str,
+        order_id: &str,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Result<Order, String> {
+        let (symbol_info, matcher) = self
+            .symbol_manager
+            .get_symbol_and_matcher(symbol_id)
+            .ok_or_else(|| format!("Symbol with id {} does not exist", symbol_id))?;
+
+        if symbol_info.status != SymbolStatus::Active {
+            return Err(format!("Symbol with id {} is not active", symbol_id));
+        }
+        if !symbol_info.validate_price(price) {
+            return Err(format!("Invalid price for symbol {}", symbol_info.name));
+        }
+        if !symbol_info.validate_quantity(quantity) {
+            return Err(format!("Invalid quantity for symbol {}", symbol_info.name));
+        }
+        if !symbol_info.price_in_band(price, matcher.reference_price()) {
+            return Err(format!(
+                "Price {} is outside the price band of symbol {}",
+                price, symbol_info.name
+            ));
+        }
+        matcher.amend_order(order_id, price, quantity)
+    }
+
     /// Adds a new trading symbol
     ///
     /// # Arguments
@@ -103,6 +295,30 @@ impl OrderProcessor {
         self.symbol_manager.update_symbol(symbol)
     }
 
+    /// Halts trading in an active symbol, see `SymbolManager::halt_symbol`
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol to halt
+    /// * `cancel_orders` - Whether the orders resting in its book are canceled
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Order>)` - The canceled orders
+    /// * `Err(String)` - Error message if the symbol does not exist or is not active
+    pub fn halt_symbol(&mut self, symbol: &str, cancel_orders: bool) -> Result<Vec<Order>, String> {
+        self.symbol_manager.halt_symbol(symbol, cancel_orders)
+    }
+
+    /// Resumes trading in a halted symbol
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol to resume
+    ///
+    /// # Returns
+    /// Whether the symbol was halted
+    pub fn resume_symbol(&mut self, symbol: &str) -> bool {
+        self.symbol_manager.resume_symbol(symbol)
+    }
+
     /// Delists (removes) a symbol from trading
     ///
     /// # Arguments
@@ -114,6 +330,57 @@ impl OrderProcessor {
         self.symbol_manager.delist_symbol(symbol)
     }
 
+    /// Retrieves a symbol's configuration
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol
+    ///
+    /// # Returns
+    /// Reference to the symbol if found, None otherwise
+    pub fn get_symbol(&self, symbol: &str) -> Option<&Symbol> {
+        self.symbol_manager.get_symbol(symbol)
+    }
+
+    /// Retrieves the order book of a symbol
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol
+    ///
+    /// # Returns
+    /// Reference to the order book if the symbol is listed, None otherwise
+    pub fn get_orderbook(&self, symbol: &str) -> Option<&OrderBook> {
+        self.symbol_manager.get_orderbook(symbol)
+    }
+
+    /// Retrieves the matcher of a symbol as shared copy-on-write
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol
+    ///
+    /// # Returns
+    /// The shared matcher if the symbol is listed, None otherwise
+    pub fn get_shared_matcher(&self, symbol: &str) -> Option<&Arc<Matcher>> {
+        self.symbol_manager.get_shared_matcher(symbol)
+    }
+
+    /// Moves a symbol and its order book out into a processor of their own
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol to move
+    ///
+    /// # Returns
+    /// Processor holding only the symbol if found, None otherwise
+    pub fn split_off(&mut self, symbol: &str) -> Option<Self> {
+        Some(Self {
+            symbol_manager: self.symbol_manager.split_off(symbol)?,
+        })
+    }
+
+    /// Moves the symbols of a processor split off before back into this one
+    pub fn merge(&mut self, other: Self) {
+        self.symbol_manager.merge(other.symbol_manager);
+    }
+
     /// Lists all available trading symbols
     ///
     /// # Returns
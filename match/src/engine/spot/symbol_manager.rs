@@ -2,11 +2,17 @@
 //!
 //! This module provides functionality for managing trading symbols and their associated matchers.
 //! It handles symbol lifecycle operations including creation, updates, deactivation, and delisting.
+//!
+//! Matchers are shared copy-on-write: cloning the manager, as the raft node does to
+//! serialize a snapshot in the background, only counts references, and the first
+//! write to a symbol afterwards copies that symbol's order book alone.
 
-use crate::engine::entry::{Symbol, SymbolStatus};
+use crate::engine::data::OrderBook;
+use crate::engine::entry::{Order, Symbol, SymbolStatus};
 use crate::engine::matchlogic::Matcher;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
 
 /// Manager for handling trading symbols and their associated order matchers
 /// Maintains the lifecycle and state of all trading symbols in the system
@@ -14,8 +20,8 @@ use std::collections::HashMap;
 pub struct SymbolManager {
     /// Map of symbol names to their configurations
     symbols: HashMap<String, Symbol>,
-    /// Map of symbol names to their order matchers
-    matchers: HashMap<String, Matcher>,
+    /// Map of symbol names to their order matchers, shared with clones until written
+    matchers: HashMap<String, Arc<Matcher>>,
 }
 
 #[allow(unused)]
@@ -42,8 +48,10 @@ impl SymbolManager {
         }
 
         self.symbols.insert(symbol.name.clone(), symbol.clone());
-        self.matchers
-            .insert(symbol.name.clone(), Matcher::new(symbol.name.clone()));
+        self.matchers.insert(
+            symbol.name.clone(),
+            Arc::new(Matcher::new(symbol.name.clone())),
+        );
         Ok(())
     }
 
@@ -83,7 +91,32 @@ impl SymbolManager {
     /// # Returns
     /// Mutable reference to the matcher if found, None otherwise
     pub fn get_matcher(&mut self, name: &str) -> Option<&mut Matcher> {
-        self.matchers.get_mut(name)
+        self.matchers.get_mut(name).map(Arc::make_mut)
+    }
+
+    /// Retrieves a symbol's order book
+    ///
+    /// # Arguments
+    /// * `name` - Name of the symbol to retrieve the order book for
+    ///
+    /// # Returns
+    /// Reference to the order book if found, None otherwise
+    pub fn get_orderbook(&self, name: &str) -> Option<&OrderBook> {
+        self.matchers.get(name).map(|matcher| matcher.orderbook())
+    }
+
+    /// Retrieves a symbol's matcher as shared with clones of the manager
+    ///
+    /// Holding on to the shared matcher freezes the order book as it is: the next
+    /// write to the symbol copies the book rather than changing it in place.
+    ///
+    /// # Arguments
+    /// * `name` - Name of the symbol
+    ///
+    /// # Returns
+    /// The shared matcher if found, None otherwise
+    pub fn get_shared_matcher(&self, name: &str) -> Option<&Arc<Matcher>> {
+        self.matchers.get(name)
     }
 
     /// Lists all available trading symbols
@@ -111,6 +144,47 @@ impl SymbolManager {
         }
     }
 
+    /// Halts trading in an active symbol, see `engine::circuit_breaker`
+    ///
+    /// # Arguments
+    /// * `name` - Name of the symbol to halt
+    /// * `cancel_orders` - Whether the orders resting in its book are canceled
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Order>)` - The canceled orders, see `Matcher::cancel_all_orders`
+    /// * `Err(String)` - If symbol does not exist or is not active
+    pub fn halt_symbol(&mut self, name: &str, cancel_orders: bool) -> Result<Vec<Order>, String> {
+        let symbol = self
+            .symbols
+            .get_mut(name)
+            .ok_or_else(|| format!("Symbol {} does not exist", name))?;
+        if symbol.status != SymbolStatus::Active {
+            return Err(format!("Symbol {} is not active", name));
+        }
+        symbol.status = SymbolStatus::Halted;
+        match self.get_matcher(name) {
+            Some(matcher) if cancel_orders => Ok(matcher.cancel_all_orders()),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Resumes trading in a halted symbol
+    ///
+    /// # Arguments
+    /// * `name` - Name of the symbol to resume
+    ///
+    /// # Returns
+    /// Whether the symbol was halted, symbols removed or deactivated since stay so
+    pub fn resume_symbol(&mut self, name: &str) -> bool {
+        match self.symbols.get_mut(name) {
+            Some(symbol) if symbol.status == SymbolStatus::Halted => {
+                symbol.status = SymbolStatus::Active;
+                true
+            }
+            _ => false,
+        }
+    }
+
     /// Delists a symbol, removing it from trading completely
     ///
     /// # Arguments
@@ -139,6 +213,75 @@ impl SymbolManager {
     pub fn get_symbol_and_matcher(&mut self, name: &str) -> Option<(&Symbol, &mut Matcher)> {
         let symbol = self.symbols.get(name)?;
         let matcher = self.matchers.get_mut(name)?;
-        Some((symbol, matcher))
+        Some((symbol, Arc::make_mut(matcher)))
+    }
+
+    /// Moves a symbol and its matcher out into a manager of their own
+    ///
+    /// # Arguments
+    /// * `name` - Name of the symbol to move
+    ///
+    /// # Returns
+    /// Manager holding only the symbol if found, None otherwise
+    pub fn split_off(&mut self, name: &str) -> Option<SymbolManager> {
+        let symbol = self.symbols.remove(name)?;
+        let matcher = self.matchers.remove(name)?;
+        Some(SymbolManager {
+            symbols: HashMap::from([(name.to_string(), symbol)]),
+            matchers: HashMap::from([(name.to_string(), matcher)]),
+        })
+    }
+
+    /// Moves the symbols of another manager back into this one
+    ///
+    /// # Arguments
+    /// * `other` - Manager split off before
+    pub fn merge(&mut self, other: SymbolManager) {
+        self.symbols.extend(other.symbols);
+        self.matchers.extend(other.matchers);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::entry::{Order, OrderSide, OrderType};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn clones_keep_their_books_when_the_original_is_written() {
+        let mut manager = SymbolManager::new();
+        for name in ["BTCUSDT", "ETHUSDT"] {
+            manager
+                .add_symbol(Symbol {
+                    name: name.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let frozen = manager.clone();
+
+        let order = Order {
+            id: "1".to_string(),
+            symbol: "BTCUSDT".to_string(),
+            order_type: OrderType::Limit,
+            side: OrderSide::Buy,
+            price: dec!(100),
+            quantity: dec!(1),
+            ..Default::default()
+        };
+        manager.get_matcher("BTCUSDT").unwrap().place_order(order);
+
+        assert_eq!(manager.get_orderbook("BTCUSDT").unwrap().order_count(), 1);
+        assert_eq!(frozen.get_orderbook("BTCUSDT").unwrap().order_count(), 0);
+        // Only the written symbol was copied
+        assert!(!Arc::ptr_eq(
+            &manager.matchers["BTCUSDT"],
+            &frozen.matchers["BTCUSDT"]
+        ));
+        assert!(Arc::ptr_eq(
+            &manager.matchers["ETHUSDT"],
+            &frozen.matchers["ETHUSDT"]
+        ));
     }
 }
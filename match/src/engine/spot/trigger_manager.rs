@@ -0,0 +1,293 @@
+//! Trigger Manager Module
+//!
+//! This module holds the stop and stop-limit orders of a tenant's spot symbols off
+//! the book until the last trade price of their symbol reaches their stop price: at
+//! or above it for buys, at or below it for sells. A triggered stop order is placed
+//! as a market order, a triggered stop-limit order as a limit order at its price.
+//!
+//! A trailing stop order moves its stop price along with the last trade price,
+//! keeping its trailing offset from the best last price seen while it waits: a sell
+//! stop follows rising prices from below, a buy stop falling prices from above, and
+//! neither moves back. Its limit price, if any, stays where it was placed. The stop
+//! prices are part of the waiting orders, so they are in every snapshot.
+//!
+//! Orders wait in arrival order per symbol and are triggered in that order, so every
+//! replica activates the same orders in the same sequence. The engine checks the
+//! triggers after each order applied on a symbol with waiting stops, see
+//! `MatchEngine::process`, and keeps checking while activated orders trade.
+
+use crate::engine::entry::{Order, OrderSide, OrderType};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Stop order waiting for its trigger
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StopOrder {
+    /// Account the order was placed for
+    pub account_id: u64,
+    /// The order as placed
+    pub order: Order,
+}
+
+impl StopOrder {
+    /// Checks whether a last trade price reaches the stop price of the order
+    ///
+    /// # Arguments
+    /// * `last_price` - Price of the last trade of the symbol
+    ///
+    /// # Returns
+    /// True if the order is triggered
+    pub fn is_triggered(&self, last_price: Decimal) -> bool {
+        match self.order.side {
+            OrderSide::Buy => last_price >= self.order.stop_price,
+            OrderSide::Sell => last_price <= self.order.stop_price,
+        }
+    }
+
+    /// Moves the stop price of a trailing order after a last trade price
+    ///
+    /// An order placed without a stop price takes its first one from the given
+    /// price.
+    ///
+    /// # Arguments
+    /// * `last_price` - Price of the last trade of the symbol
+    pub fn trail(&mut self, last_price: Decimal) {
+        let order = &mut self.order;
+        if !order.is_trailing() {
+            return;
+        }
+        let trailed = match order.side {
+            OrderSide::Buy => last_price + order.trailing_offset,
+            OrderSide::Sell => (last_price - order.trailing_offset).max(Decimal::ZERO),
+        };
+        let moves = order.stop_price.is_zero()
+            || match order.side {
+                OrderSide::Buy => trailed < order.stop_price,
+                OrderSide::Sell => trailed > order.stop_price,
+            };
+        if moves {
+            order.stop_price = trailed;
+        }
+    }
+
+    /// Returns the order to place once triggered
+    ///
+    /// # Returns
+    /// The order as a market order if it was a stop order, as a limit order if
+    /// it was a stop-limit order
+    pub fn activate(self) -> Order {
+        let mut order = self.order;
+        order.order_type = match order.order_type {
+            OrderType::StopLimit => OrderType::Limit,
+            _ => OrderType::Market,
+        };
+        order
+    }
+}
+
+/// Stop orders of a tenant's spot symbols waiting for their trigger
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TriggerManager {
+    /// Waiting orders by symbol, in arrival order
+    pending: BTreeMap<String, Vec<StopOrder>>,
+}
+
+impl TriggerManager {
+    /// Holds a stop order until it is triggered
+    ///
+    /// # Arguments
+    /// * `account_id` - Account the order is placed for
+    /// * `order` - The stop or stop-limit order
+    pub fn add(&mut self, account_id: u64, order: Order) {
+        self.pending
+            .entry(order.symbol.clone())
+            .or_default()
+            .push(StopOrder { account_id, order });
+    }
+
+    /// Looks up a waiting order
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol
+    /// * `order_id` - ID of the order
+    ///
+    /// # Returns
+    /// The order if it waits for its trigger, None otherwise
+    pub fn get(&self, symbol: &str, order_id: &str) -> Option<&StopOrder> {
+        self.pending
+            .get(symbol)?
+            .iter()
+            .find(|stop| stop.order.id == order_id)
+    }
+
+    /// Returns whether any order waits on a symbol
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol
+    pub fn has_pending(&self, symbol: &str) -> bool {
+        self.pending.contains_key(symbol)
+    }
+
+    /// Removes a waiting order, e.g. when it is canceled
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol
+    /// * `order_id` - ID of the order
+    ///
+    /// # Returns
+    /// The removed order if it was waiting, None otherwise
+    pub fn remove(&mut self, symbol: &str, order_id: &str) -> Option<StopOrder> {
+        let orders = self.pending.get_mut(symbol)?;
+        let position = orders.iter().position(|stop| stop.order.id == order_id)?;
+        let removed = orders.remove(position);
+        if orders.is_empty() {
+            self.pending.remove(symbol);
+        }
+        Some(removed)
+    }
+
+    /// Removes the waiting orders of an account, e.g. when they are all canceled
+    ///
+    /// # Arguments
+    /// * `account_id` - Account whose orders are removed
+    /// * `symbol` - ID of the only symbol to remove orders of, None for all of them
+    ///
+    /// # Returns
+    /// The removed orders by symbol name, in arrival order
+    pub fn remove_account(&mut self, account_id: u64, symbol: Option<&str>) -> Vec<StopOrder> {
+        let mut removed = Vec::new();
+        for (name, orders) in self.pending.iter_mut() {
+            if symbol.is_some_and(|symbol| symbol != name) {
+                continue;
+            }
+            let (own, others): (Vec<StopOrder>, Vec<StopOrder>) = std::mem::take(orders)
+                .into_iter()
+                .partition(|stop| stop.account_id == account_id);
+            *orders = others;
+            removed.extend(own);
+        }
+        self.pending.retain(|_, orders| !orders.is_empty());
+        removed
+    }
+
+    /// Removes the orders a last trade price triggers
+    ///
+    /// Trailing orders move their stop price with the price first, see
+    /// `StopOrder::trail`.
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol
+    /// * `last_price` - Price of the last trade of the symbol
+    ///
+    /// # Returns
+    /// The triggered orders in arrival order
+    pub fn take_triggered(&mut self, symbol: &str, last_price: Decimal) -> Vec<StopOrder> {
+        let Some(orders) = self.pending.get_mut(symbol) else {
+            return Vec::new();
+        };
+        for stop in orders.iter_mut() {
+            stop.trail(last_price);
+        }
+        let (triggered, waiting) = std::mem::take(orders)
+            .into_iter()
+            .partition(|stop| stop.is_triggered(last_price));
+        *orders = waiting;
+        if orders.is_empty() {
+            self.pending.remove(symbol);
+        }
+        triggered
+    }
+
+    /// Drops the orders waiting on a symbol, e.g. when it is delisted
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol
+    ///
+    /// # Returns
+    /// The dropped orders
+    pub fn remove_symbol(&mut self, symbol: &str) -> Vec<StopOrder> {
+        self.pending.remove(symbol).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn stop(id: &str, order_type: OrderType, side: OrderSide, stop_price: Decimal) -> Order {
+        Order {
+            id: id.to_string(),
+            symbol: "BTCUSDT".to_string(),
+            order_type,
+            side,
+            price: dec!(100),
+            quantity: dec!(1),
+            stop_price,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn orders_trigger_in_arrival_order_once_the_price_is_reached() {
+        let mut triggers = TriggerManager::default();
+        triggers.add(7, stop("1", OrderType::Stop, OrderSide::Buy, dec!(105)));
+        triggers.add(
+            7,
+            stop("2", OrderType::StopLimit, OrderSide::Sell, dec!(95)),
+        );
+        triggers.add(8, stop("3", OrderType::Stop, OrderSide::Buy, dec!(103)));
+        assert!(triggers.take_triggered("BTCUSDT", dec!(100)).is_empty());
+
+        let triggered = triggers.take_triggered("BTCUSDT", dec!(105));
+        let ids: Vec<&str> = triggered.iter().map(|s| s.order.id.as_str()).collect();
+        assert_eq!(ids, ["1", "3"]);
+        assert_eq!(triggered[1].account_id, 8);
+        assert!(triggers.get("BTCUSDT", "1").is_none());
+
+        let triggered = triggers.take_triggered("BTCUSDT", dec!(95));
+        let order = triggered.into_iter().next().unwrap().activate();
+        assert_eq!(order.order_type, OrderType::Limit);
+        assert_eq!(order.id, "2");
+        assert!(!triggers.has_pending("BTCUSDT"));
+    }
+
+    #[test]
+    fn canceled_orders_no_longer_wait() {
+        let mut triggers = TriggerManager::default();
+        triggers.add(7, stop("1", OrderType::Stop, OrderSide::Sell, dec!(95)));
+        assert!(triggers.remove("BTCUSDT", "2").is_none());
+        let removed = triggers.remove("BTCUSDT", "1").unwrap();
+        assert_eq!(removed.activate().order_type, OrderType::Market);
+        assert!(!triggers.has_pending("BTCUSDT"));
+    }
+
+    #[test]
+    fn trailing_stops_follow_the_price_and_never_move_back() {
+        let mut triggers = TriggerManager::default();
+        let mut order = stop("1", OrderType::Stop, OrderSide::Sell, Decimal::ZERO);
+        order.trailing_offset = dec!(5);
+        let mut trailing = StopOrder {
+            account_id: 7,
+            order,
+        };
+        trailing.trail(dec!(100));
+        assert_eq!(trailing.order.stop_price, dec!(95));
+        triggers.add(7, trailing.order);
+
+        // Rising prices raise the stop price, falling ones leave it
+        assert!(triggers.take_triggered("BTCUSDT", dec!(110)).is_empty());
+        assert!(triggers.take_triggered("BTCUSDT", dec!(106)).is_empty());
+        assert_eq!(
+            triggers.get("BTCUSDT", "1").unwrap().order.stop_price,
+            dec!(105)
+        );
+        let triggered = triggers.take_triggered("BTCUSDT", dec!(105));
+        assert_eq!(triggered[0].order.stop_price, dec!(105));
+        assert_eq!(
+            triggered[0].clone().activate().order_type,
+            OrderType::Market
+        );
+    }
+}
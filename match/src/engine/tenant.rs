@@ -0,0 +1,867 @@
+//! Tenant Module
+//!
+//! This module defines the tenant namespace used to run several isolated exchanges
+//! inside one cluster. Each tenant owns its own symbol set, order books and command
+//! sequence, so commands for one tenant can never observe or mutate another tenant's state.
+
+use crate::engine::circuit_breaker::{CircuitBreakers, Halt};
+use crate::engine::client_orders::ClientOrderIndex;
+use crate::engine::data::OrderBook;
+use crate::engine::dedupe::RequestDedupe;
+use crate::engine::entry::{Order, OrderSide, OrderType, Symbol, SymbolStatus, Trade};
+use crate::engine::funding::FundingLog;
+use crate::engine::ledger::{self, Hold, Ledger};
+use crate::engine::matchengine::MarketType;
+use crate::engine::matchlogic::Matcher;
+use crate::engine::perp::ContractProcessor;
+use crate::engine::position::Positions;
+use crate::engine::spot::{OrderProcessor, StopOrder, TriggerManager};
+use crate::engine::throttle::OrderThrottle;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Tenant used when no tenant is configured or supplied with a command
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Isolated exchange namespace inside the match engine
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Tenant {
+    /// Unique identifier of the tenant
+    pub id: String,
+    /// Sequence number of the last command applied for this tenant
+    pub sequence: u64,
+    /// Processor for handling this tenant's spot market orders
+    pub spot_processor: OrderProcessor,
+    /// Processor for handling this tenant's perpetual contract orders
+    #[serde(default)]
+    pub perp_processor: ContractProcessor,
+    /// Recently applied request IDs, used to drop retried commands
+    #[serde(default)]
+    pub dedupe: RequestDedupe,
+    /// Account balances and the funds held for open orders
+    #[serde(default)]
+    pub ledger: Ledger,
+    /// Funding commands applied to the ledger, keyed by idempotency key
+    #[serde(default)]
+    pub funding: FundingLog,
+    /// Net positions of the accounts per symbol, moved by settled trades
+    #[serde(default)]
+    pub positions: Positions,
+    /// Order rate limits of the accounts, advanced by engine time
+    #[serde(default)]
+    pub throttle: OrderThrottle,
+    /// Orders of the accounts by client order ID
+    #[serde(default)]
+    pub client_orders: ClientOrderIndex,
+    /// Stop orders on spot symbols waiting for their trigger
+    #[serde(default)]
+    pub stop_orders: TriggerManager,
+    /// Prices recently traded on the symbols with a circuit breaker
+    #[serde(default)]
+    pub circuit_breakers: CircuitBreakers,
+}
+
+impl Tenant {
+    /// Creates a new empty tenant
+    ///
+    /// # Arguments
+    /// * `id` - Unique identifier of the tenant
+    pub fn new(id: String) -> Self {
+        Self {
+            id,
+            sequence: 0,
+            spot_processor: OrderProcessor::new(),
+            perp_processor: ContractProcessor::new(),
+            dedupe: RequestDedupe::default(),
+            ledger: Ledger::default(),
+            funding: FundingLog::default(),
+            positions: Positions::default(),
+            throttle: OrderThrottle::default(),
+            client_orders: ClientOrderIndex::default(),
+            stop_orders: TriggerManager::default(),
+            circuit_breakers: CircuitBreakers::default(),
+        }
+    }
+
+    /// Advances the tenant's command sequence
+    ///
+    /// # Returns
+    /// The sequence number assigned to the command being applied
+    pub fn next_sequence(&mut self) -> u64 {
+        self.sequence += 1;
+        self.sequence
+    }
+
+    /// Lists a symbol on a market
+    ///
+    /// Spot symbols and contracts share one namespace, so a name can be listed on
+    /// only one market and orders, books and metrics stay unambiguous.
+    ///
+    /// # Arguments
+    /// * `market` - Market to list the symbol on
+    /// * `symbol` - The symbol to add
+    ///
+    /// # Returns
+    /// Result indicating success or failure
+    pub fn add_symbol(&mut self, market: MarketType, symbol: Symbol) -> Result<(), String> {
+        if self.get_symbol(market.other(), &symbol.name).is_some() {
+            return Err(format!(
+                "Symbol {} already exists on the {:?} market",
+                symbol.name,
+                market.other()
+            ));
+        }
+        if symbol.circuit_breaker < Decimal::ZERO
+            || (!symbol.circuit_breaker.is_zero()
+                && (symbol.circuit_window_ms == 0 || symbol.halt_ms == 0))
+        {
+            return Err(format!(
+                "Circuit breaker of symbol {} needs a positive move, window and halt time",
+                symbol.name
+            ));
+        }
+        match market {
+            MarketType::Spot => self.spot_processor.add_symbol(symbol),
+            MarketType::Perp => self.perp_processor.add_symbol(symbol),
+        }
+    }
+
+    /// Changes the settings of a listed symbol that may change while it trades,
+    /// currently its price band
+    ///
+    /// # Arguments
+    /// * `market` - Market the symbol is listed on
+    /// * `update` - The symbol with its new settings, and the time of the update
+    ///   unless it is zero
+    ///
+    /// # Returns
+    /// Result indicating success or failure
+    pub fn update_symbol(&mut self, market: MarketType, update: &Symbol) -> Result<(), String> {
+        let mut symbol = self
+            .get_symbol(market, &update.name)
+            .cloned()
+            .ok_or_else(|| format!("Symbol {} does not exist", update.name))?;
+        if update.price_band < Decimal::ZERO {
+            return Err(format!("Price band of symbol {} is negative", update.name));
+        }
+        symbol.price_band = update.price_band;
+        if update.updated_at > 0 {
+            symbol.updated_at = update.updated_at;
+        }
+        match market {
+            MarketType::Spot => self.spot_processor.update_symbol(symbol),
+            MarketType::Perp => self.perp_processor.update_symbol(symbol),
+        }
+    }
+
+    /// Retrieves a symbol's configuration
+    ///
+    /// # Arguments
+    /// * `market` - Market the symbol is listed on
+    /// * `symbol` - ID of the symbol
+    ///
+    /// # Returns
+    /// Reference to the symbol if found, None otherwise
+    pub fn get_symbol(&self, market: MarketType, symbol: &str) -> Option<&Symbol> {
+        match market {
+            MarketType::Spot => self.spot_processor.get_symbol(symbol),
+            MarketType::Perp => self.perp_processor.get_symbol(symbol),
+        }
+    }
+
+    /// Lists the symbols of both markets
+    ///
+    /// # Returns
+    /// Vector of references to all symbols and contracts
+    pub fn list_symbols(&self) -> Vec<&Symbol> {
+        let mut symbols = self.spot_processor.list_symbols();
+        symbols.extend(self.perp_processor.list_symbols());
+        symbols
+    }
+
+    /// Retrieves the order book of a symbol on either market
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol
+    ///
+    /// # Returns
+    /// Reference to the order book if the symbol is listed, None otherwise
+    pub fn get_orderbook(&self, symbol: &str) -> Option<&OrderBook> {
+        self.spot_processor
+            .get_orderbook(symbol)
+            .or_else(|| self.perp_processor.get_orderbook(symbol))
+    }
+
+    /// Retrieves the matcher of a symbol on either market as shared copy-on-write,
+    /// see `SymbolManager::get_shared_matcher`
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol
+    ///
+    /// # Returns
+    /// The shared matcher if the symbol is listed, None otherwise
+    pub fn get_shared_matcher(&self, symbol: &str) -> Option<&Arc<Matcher>> {
+        self.spot_processor
+            .get_shared_matcher(symbol)
+            .or_else(|| self.perp_processor.get_shared_matcher(symbol))
+    }
+
+    /// Returns whether an order rests on the book of its symbol on either market,
+    /// or waits for its trigger
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol
+    /// * `order_id` - ID of the order
+    pub fn is_open(&self, symbol: &str, order_id: &str) -> bool {
+        self.get_orderbook(symbol)
+            .is_some_and(|book| book.get_order(order_id).is_some())
+            || self.stop_orders.get(symbol, order_id).is_some()
+    }
+
+    /// Forgets the client order IDs of orders that are no longer open
+    pub fn prune_client_orders(&mut self) {
+        let Tenant {
+            client_orders,
+            spot_processor,
+            perp_processor,
+            stop_orders,
+            ..
+        } = self;
+        client_orders.prune(|symbol, order_id| {
+            spot_processor
+                .get_orderbook(symbol)
+                .or_else(|| perp_processor.get_orderbook(symbol))
+                .is_some_and(|book| book.get_order(order_id).is_some())
+                || stop_orders.get(symbol, order_id).is_some()
+        });
+    }
+
+    /// Places an order on a perpetual contract
+    ///
+    /// Orders of accounts with trading disabled are rejected like on spot symbols.
+    ///
+    /// # Arguments
+    /// * `account_id` - Account the order is placed for
+    /// * `order` - The order to place
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Trade>)` - List of trades generated from matching this order
+    /// * `Err(String)` - Error message if the order is rejected
+    pub fn place_contract_order(
+        &mut self,
+        account_id: u64,
+        order: &Order,
+    ) -> Result<Vec<Trade>, String> {
+        if self.ledger.risk_limits(account_id).trading_disabled {
+            return Err(format!("Trading is disabled for account {}", account_id));
+        }
+        self.perp_processor.place_order(order)
+    }
+
+    /// Places an order, holding and settling balances if its symbol settles them
+    ///
+    /// Stop orders are held off the book for their trigger, see
+    /// `engine::spot::trigger_manager`, and hold no funds until they are placed.
+    /// A trailing stop placed without a stop price takes its first one from the
+    /// last trade price and is rejected before the symbol traded.
+    /// Orders of accounts with trading disabled are rejected on every symbol. On
+    /// symbols that settle balances the hold is placed before matching and the
+    /// order is rejected if the account cannot fund it, counting its credit line.
+    /// Trades are settled out of the holds of both orders, see `Ledger::settle`,
+    /// and orders that leave the book release what they still hold.
+    ///
+    /// # Arguments
+    /// * `account_id` - Account the order is placed for
+    /// * `order` - The order to place
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Trade>)` - List of trades generated from matching this order
+    /// * `Err(String)` - Error message if the order is rejected
+    pub fn place_order(&mut self, account_id: u64, order: &Order) -> Result<Vec<Trade>, String> {
+        if self.ledger.risk_limits(account_id).trading_disabled {
+            return Err(format!("Trading is disabled for account {}", account_id));
+        }
+        if order.is_stop() {
+            let mut stop = StopOrder {
+                account_id,
+                order: order.clone(),
+            };
+            if order.is_trailing() && order.stop_price.is_zero() {
+                let last_price = self.last_price(&order.symbol).ok_or_else(|| {
+                    format!(
+                        "Trailing stop order {} needs a stop price before {} traded",
+                        order.id, order.symbol
+                    )
+                })?;
+                stop.trail(last_price);
+            }
+            self.spot_processor.check_stop_order(&stop.order)?;
+            if self.is_open(&order.symbol, &order.id) {
+                return Err(format!("Order {} already exists", order.id));
+            }
+            self.stop_orders.add(stop.account_id, stop.order);
+            return Ok(Vec::new());
+        }
+        let symbol = match self.spot_processor.get_symbol(&order.symbol) {
+            Some(symbol) if symbol.settle_balances => symbol.clone(),
+            _ => return self.spot_processor.place_order(order),
+        };
+        let amount = match (order.side, order.order_type) {
+            (OrderSide::Sell, _) => Some(order.quantity),
+            (OrderSide::Buy, OrderType::Limit | OrderType::LimitMaker | OrderType::StopLimit) => {
+                order.price.checked_mul(order.quantity)
+            }
+            (OrderSide::Buy, OrderType::Market | OrderType::Stop) => self
+                .spot_processor
+                .get_orderbook(&order.symbol)
+                .map_or(Some(Decimal::ZERO), |book| {
+                    ledger::market_buy_cost(book, order.quantity)
+                }),
+        }
+        .ok_or_else(|| format!("Amount of order {} overflows", order.id))?;
+        let hold = Hold {
+            account_id,
+            currency: match order.side {
+                OrderSide::Buy => symbol.quote_currency.clone(),
+                OrderSide::Sell => symbol.base_currency.clone(),
+            },
+            amount,
+            maker_fee: order.maker_fee,
+            fee_currency: symbol.fee_currency.clone(),
+        };
+        self.ledger.place_hold(&order.symbol, &order.id, hold)?;
+        let trades = match self.spot_processor.place_order(order) {
+            Ok(trades) => trades,
+            Err(e) => {
+                self.ledger.release(&order.symbol, &order.id);
+                return Err(e);
+            }
+        };
+        // The holds cover every fill the matcher can produce, so settlement only
+        // fails on a broken invariant
+        for trade in &trades {
+            if let Err(e) =
+                self.ledger
+                    .settle(trade, order, &symbol.base_currency, &symbol.quote_currency)
+            {
+                log::error!(
+                    "cannot settle trade of orders {} and {}: {}",
+                    trade.buyer_order_id,
+                    trade.seller_order_id,
+                    e
+                );
+            }
+        }
+
+        let filled: Decimal = trades.iter().map(|trade| trade.quantity).sum();
+        let book = self.spot_processor.get_orderbook(&order.symbol);
+        if book.and_then(|book| book.get_order(&order.id)).is_none() {
+            // Filled, or an immediate-or-cancel remainder that did not rest
+            self.ledger.release(&order.symbol, &order.id);
+        } else if order.side == OrderSide::Buy && order.order_type != OrderType::Market {
+            // A buy filled below its limit only keeps what its remainder may cost
+            let needed = order.price * (order.quantity - filled);
+            self.ledger.trim(&order.symbol, &order.id, needed);
+        }
+        for trade in &trades {
+            let maker = match order.side {
+                OrderSide::Buy => &trade.seller_order_id,
+                OrderSide::Sell => &trade.buyer_order_id,
+            };
+            if book.and_then(|book| book.get_order(maker)).is_none() {
+                self.ledger.release(&order.symbol, maker);
+            }
+        }
+        Ok(trades)
+    }
+
+    /// Changes the price and quantity of a resting spot order, see `Matcher::amend_order`
+    ///
+    /// On symbols that settle balances the order's hold is resized to what its
+    /// remainder may cost at the new price first, and the amendment is rejected
+    /// if the account cannot fund it. Stop orders waiting for their trigger are
+    /// not in the book and cannot be amended.
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol the order belongs to
+    /// * `order_id` - ID of the order to amend
+    /// * `price` - The new price, zero to keep it
+    /// * `quantity` - The new quantity including what already filled, zero to keep it
+    ///
+    /// # Returns
+    /// * `Ok(Order)` - The amended order
+    /// * `Err(String)` - Error message if the amendment is rejected
+    pub fn amend_order(
+        &mut self,
+        symbol: &str,
+        order_id: &str,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Result<Order, String> {
+        let resting = self
+            .spot_processor
+            .get_orderbook(symbol)
+            .and_then(|book| book.resting_order(order_id))
+            .ok_or_else(|| format!("Order {} is not resting in the book", order_id))?;
+        let price = if price.is_zero() {
+            resting.price
+        } else {
+            price
+        };
+        let quantity = if quantity.is_zero() {
+            resting.quantity
+        } else {
+            quantity
+        };
+        let remaining = (quantity - resting.filled_quantity).max(Decimal::ZERO);
+        let needed = match resting.side {
+            OrderSide::Buy => price
+                .checked_mul(remaining)
+                .ok_or_else(|| format!("Amount of order {} overflows", order_id))?,
+            OrderSide::Sell => remaining,
+        };
+        let held = self.ledger.hold(symbol, order_id).map(|hold| hold.amount);
+        if held.is_some() {
+            self.ledger.resize_hold(symbol, order_id, needed)?;
+        }
+        let amended = self
+            .spot_processor
+            .amend_order(symbol, order_id, price, quantity);
+        if let (Err(_), Some(held)) = (&amended, held) {
+            // Growing back into what was just released cannot fail
+            if let Err(e) = self.ledger.resize_hold(symbol, order_id, held) {
+                log::error!("cannot restore the hold of order {}: {}", order_id, e);
+            }
+        }
+        amended
+    }
+
+    /// Changes the price and quantity of a resting order on a perpetual contract
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the contract the order belongs to
+    /// * `order_id` - ID of the order to amend
+    /// * `price` - The new price, zero to keep it
+    /// * `quantity` - The new quantity including what already filled, zero to keep it
+    ///
+    /// # Returns
+    /// * `Ok(Order)` - The amended order
+    /// * `Err(String)` - Error message if the amendment is rejected
+    pub fn amend_contract_order(
+        &mut self,
+        symbol: &str,
+        order_id: &str,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Result<Order, String> {
+        let resting = self
+            .perp_processor
+            .get_orderbook(symbol)
+            .and_then(|book| book.resting_order(order_id))
+            .ok_or_else(|| format!("Order {} is not resting in the book", order_id))?;
+        let price = if price.is_zero() {
+            resting.price
+        } else {
+            price
+        };
+        let quantity = if quantity.is_zero() {
+            resting.quantity
+        } else {
+            quantity
+        };
+        self.perp_processor
+            .amend_order(symbol, order_id, price, quantity)
+    }
+
+    /// Cancels an order, releasing the funds it still holds
+    ///
+    /// A stop order waiting for its trigger is dropped, it holds no funds.
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol the order belongs to
+    /// * `order_id` - ID of the order to cancel
+    ///
+    /// # Returns
+    /// * `Ok(Some(Order))` - The canceled order if found
+    /// * `Ok(None)` - If order was not found
+    /// * `Err(String)` - Error message if cancellation fails
+    pub fn cancel_order(&mut self, symbol: &str, order_id: &str) -> Result<Option<Order>, String> {
+        if let Some(stop) = self.stop_orders.remove(symbol, order_id) {
+            return Ok(Some(stop.order));
+        }
+        let canceled = self.spot_processor.cancel_order(symbol, order_id)?;
+        if canceled.is_some() {
+            self.ledger.release(symbol, order_id);
+        }
+        Ok(canceled)
+    }
+
+    /// Cancels every open spot order of an account, releasing the funds they
+    /// hold, see `OrderProcessor::cancel_all_orders`
+    ///
+    /// Stop orders waiting for their trigger are dropped after the resting
+    /// orders, they hold no funds.
+    ///
+    /// # Arguments
+    /// * `account_id` - Account whose orders are canceled
+    /// * `symbol` - ID of the only symbol to cancel on, None for all of them
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Order>)` - The canceled orders, in the order they were canceled
+    /// * `Err(String)` - Error message if the symbol does not exist or is not active
+    pub fn cancel_all_orders(
+        &mut self,
+        account_id: u64,
+        symbol: Option<&str>,
+    ) -> Result<Vec<Order>, String> {
+        let mut canceled = self.spot_processor.cancel_all_orders(account_id, symbol)?;
+        for order in &canceled {
+            self.ledger.release(&order.symbol, &order.id);
+        }
+        let stops = self.stop_orders.remove_account(account_id, symbol);
+        canceled.extend(stops.into_iter().map(|stop| stop.order));
+        Ok(canceled)
+    }
+
+    /// Delists a symbol, releasing the funds held by its dropped orders and
+    /// dropping the stop orders waiting on it
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol to remove
+    ///
+    /// # Returns
+    /// Result indicating success or failure
+    pub fn remove_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        self.spot_processor.del_symbol(symbol)?;
+        self.ledger.release_symbol(symbol);
+        self.stop_orders.remove_symbol(symbol);
+        Ok(())
+    }
+
+    /// Removes the stop orders the last trade price of a spot symbol triggers
+    ///
+    /// # Arguments
+    /// * `symbol` - ID of the symbol
+    ///
+    /// # Returns
+    /// The triggered orders in arrival order, none before the symbol traded or
+    /// while it is halted
+    pub fn take_triggered_stops(&mut self, symbol: &str) -> Vec<StopOrder> {
+        let active = self
+            .spot_processor
+            .get_symbol(symbol)
+            .is_some_and(|symbol| symbol.status == SymbolStatus::Active);
+        match self.last_price(symbol) {
+            Some(last_price) if active => self.stop_orders.take_triggered(symbol, last_price),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Records the price an order last traded at and halts its symbol if the
+    /// price moved further than its circuit breaker allows, see `engine::circuit_breaker`
+    ///
+    /// A halt of a spot symbol that cancels its orders releases the funds they
+    /// hold and drops the stop orders waiting on it as well.
+    ///
+    /// # Arguments
+    /// * `market` - Market the symbol is listed on
+    /// * `symbol` - ID of the symbol
+    /// * `price` - Price of the last trade of the order
+    /// * `now_ms` - Engine time of the order in milliseconds
+    ///
+    /// # Returns
+    /// The halt, None if the symbol keeps trading
+    pub fn check_circuit_breaker(
+        &mut self,
+        market: MarketType,
+        symbol: &str,
+        price: Decimal,
+        now_ms: u64,
+    ) -> Option<Halt> {
+        let config = self
+            .get_symbol(market, symbol)
+            .filter(|config| config.status == SymbolStatus::Active)?
+            .clone();
+        let moved_from = self.circuit_breakers.observe(&config, price, now_ms)?;
+        let canceled = self.halt_symbol(market, symbol).ok()?;
+        Some(Halt {
+            until_ms: now_ms.saturating_add(config.halt_ms),
+            reason: format!(
+                "circuit breaker: price moved from {} to {} within {}ms",
+                moved_from, price, config.circuit_window_ms
+            ),
+            canceled,
+        })
+    }
+
+    /// Halts trading in an active symbol, canceling the orders resting in its book
+    /// if the symbol cancels on halt, see `Symbol::cancel_on_halt`
+    ///
+    /// Canceled spot orders release the funds they hold and the stop orders
+    /// waiting on the symbol are dropped with them. The circuit breaker of the
+    /// symbol starts over once it resumes.
+    ///
+    /// # Arguments
+    /// * `market` - Market the symbol is listed on
+    /// * `symbol` - ID of the symbol
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Order>)` - The canceled orders, in the order they were canceled
+    /// * `Err(String)` - Error message if the symbol does not exist or is not active
+    pub fn halt_symbol(&mut self, market: MarketType, symbol: &str) -> Result<Vec<Order>, String> {
+        let cancel_orders = self
+            .get_symbol(market, symbol)
+            .is_some_and(|config| config.cancel_on_halt);
+        let mut canceled = match market {
+            MarketType::Spot => self.spot_processor.halt_symbol(symbol, cancel_orders),
+            MarketType::Perp => self.perp_processor.halt_symbol(symbol, cancel_orders),
+        }?;
+        self.circuit_breakers.remove(symbol);
+        if market == MarketType::Spot && cancel_orders {
+            for order in &canceled {
+                self.ledger.release(symbol, &order.id);
+            }
+            let stops = self.stop_orders.remove_symbol(symbol);
+            canceled.extend(stops.into_iter().map(|stop| stop.order));
+        }
+        Ok(canceled)
+    }
+
+    /// Cancels every order on a symbol, of every account, see `OrderProcessor::cancel_book`
+    ///
+    /// Canceled spot orders release the funds they hold and the stop orders
+    /// waiting on the symbol are dropped after the resting orders.
+    ///
+    /// # Arguments
+    /// * `market` - Market the symbol is listed on
+    /// * `symbol` - ID of the symbol
+    ///
+    /// # Returns
+    /// * `Ok(Vec<Order>)` - The canceled orders, in the order they were canceled
+    /// * `Err(String)` - Error message if the symbol does not exist or takes no cancels
+    pub fn cancel_book(&mut self, market: MarketType, symbol: &str) -> Result<Vec<Order>, String> {
+        if market == MarketType::Perp {
+            return self.perp_processor.cancel_book(symbol);
+        }
+        let mut canceled = self.spot_processor.cancel_book(symbol)?;
+        for order in &canceled {
+            self.ledger.release(symbol, &order.id);
+        }
+        let stops = self.stop_orders.remove_symbol(symbol);
+        canceled.extend(stops.into_iter().map(|stop| stop.order));
+        Ok(canceled)
+    }
+
+    /// Resumes trading in a symbol halted by its circuit breaker
+    ///
+    /// # Arguments
+    /// * `market` - Market the symbol is listed on
+    /// * `symbol` - ID of the symbol
+    ///
+    /// # Returns
+    /// Whether the symbol was halted
+    pub fn resume_symbol(&mut self, market: MarketType, symbol: &str) -> bool {
+        match market {
+            MarketType::Spot => self.spot_processor.resume_symbol(symbol),
+            MarketType::Perp => self.perp_processor.resume_symbol(symbol),
+        }
+    }
+
+    /// Returns the price of the last trade of a spot symbol, None before it traded
+    fn last_price(&self, symbol: &str) -> Option<Decimal> {
+        self.spot_processor
+            .get_shared_matcher(symbol)
+            .and_then(|matcher| matcher.last_price())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::clock::Hlc;
+    use crate::engine::codec;
+    use crate::engine::entry::order::OrderStatus;
+    use crate::engine::history::OrderChange;
+    use crate::engine::matchengine::{CommandEnvelope, MatchCmd, MatchCmdType, MatchEngine};
+    use rust_decimal_macros::dec;
+
+    fn apply(engine: &mut MatchEngine, index: u64, cmd: MatchCmd) {
+        let envelope = CommandEnvelope {
+            account_id: index,
+            hlc: Hlc::from_millis(1_000).0,
+            cmd,
+            ..Default::default()
+        };
+        engine.on_message(index, &codec::encode(&envelope)).unwrap();
+    }
+
+    fn create(tenant: &str) -> MatchCmd {
+        MatchCmd {
+            cmd: MatchCmdType::CreateSymbol,
+            tenant: tenant.to_string(),
+            symbol: Some(Symbol {
+                name: "BTC".to_string(),
+                max_price: dec!(1000),
+                max_quantity: dec!(1000),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn place(tenant: &str, id: &str, side: OrderSide) -> MatchCmd {
+        MatchCmd {
+            tenant: tenant.to_string(),
+            order: Some(Order {
+                id: id.to_string(),
+                symbol: "BTC".to_string(),
+                order_type: OrderType::Limit,
+                side,
+                price: dec!(100),
+                quantity: dec!(1),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn status(change: &OrderChange) -> OrderStatus {
+        match change {
+            OrderChange::Placed { order, .. } => order.status,
+            change => panic!("unexpected {:?}", change),
+        }
+    }
+
+    #[test]
+    fn tenants_neither_see_nor_match_each_others_orders() {
+        let mut engine = MatchEngine::new();
+        apply(&mut engine, 1, create("a"));
+        apply(&mut engine, 2, place("a", "1", OrderSide::Sell));
+        // Tenant b has no BTC symbol of its own yet
+        apply(&mut engine, 3, place("b", "2", OrderSide::Buy));
+        apply(&mut engine, 4, create("b"));
+        apply(&mut engine, 5, place("b", "3", OrderSide::Buy));
+
+        let events = engine.take_order_events();
+        let placed: Vec<_> = events
+            .iter()
+            .filter(|event| matches!(event.change, OrderChange::Placed { .. }))
+            .map(|event| (event.tenant.as_str(), status(&event.change)))
+            .collect();
+        assert_eq!(
+            placed,
+            vec![
+                ("a", OrderStatus::New),
+                ("b", OrderStatus::Rejected),
+                ("b", OrderStatus::New),
+            ]
+        );
+
+        let a = engine.get_tenant("a").unwrap();
+        let b = engine.get_tenant("b").unwrap();
+        assert!(a.is_open("BTC", "1"));
+        assert!(!a.is_open("BTC", "3"));
+        assert!(b.is_open("BTC", "3"));
+        assert!(!b.is_open("BTC", "1"));
+        assert_eq!(a.sequence, 2);
+        assert_eq!(b.sequence, 3);
+        assert!(engine.get_tenant(DEFAULT_TENANT).is_none());
+    }
+
+    #[test]
+    fn batches_are_placed_whole_or_not_at_all() {
+        let mut engine = MatchEngine::new();
+        apply(&mut engine, 1, create("a"));
+        apply(&mut engine, 2, place("a", "1", OrderSide::Sell));
+        let order = |id, price| Order {
+            price,
+            ..place("a", id, OrderSide::Buy).order.unwrap()
+        };
+        let batch = |orders| MatchCmd {
+            cmd: MatchCmdType::Batch,
+            tenant: "a".to_string(),
+            orders,
+            ..Default::default()
+        };
+        // The second order is above the symbol's maximum price
+        apply(
+            &mut engine,
+            3,
+            batch(vec![order("2", dec!(100)), order("3", dec!(5000))]),
+        );
+        apply(
+            &mut engine,
+            4,
+            batch(vec![order("4", dec!(90)), order("5", dec!(95))]),
+        );
+
+        let events = engine.take_order_events();
+        let placed = |index| -> Vec<_> {
+            events
+                .iter()
+                .filter(|event| event.index == index)
+                .map(|event| match &event.change {
+                    OrderChange::Placed { order, trades } => {
+                        (order.id.as_str(), order.status, trades.len())
+                    }
+                    change => panic!("unexpected {:?}", change),
+                })
+                .collect()
+        };
+        assert_eq!(
+            placed(3),
+            vec![
+                ("2", OrderStatus::Rejected, 0),
+                ("3", OrderStatus::Rejected, 0)
+            ]
+        );
+        assert_eq!(
+            placed(4),
+            vec![("4", OrderStatus::New, 0), ("5", OrderStatus::New, 0)]
+        );
+        let a = engine.get_tenant("a").unwrap();
+        assert!(a.is_open("BTC", "1"));
+        assert!(!a.is_open("BTC", "2"));
+        assert!(a.is_open("BTC", "4") && a.is_open("BTC", "5"));
+        assert!(engine.take_settlements().is_empty());
+    }
+
+    #[test]
+    fn replacements_report_the_status_of_the_new_order() {
+        let mut engine = MatchEngine::new();
+        apply(&mut engine, 1, create("r"));
+        apply(&mut engine, 2, place("r", "1", OrderSide::Buy));
+        let replace = |index, request_id: &str, old, new, price| {
+            let envelope = CommandEnvelope {
+                request_id: request_id.to_string(),
+                hlc: Hlc::from_millis(1_000).0,
+                cmd: MatchCmd {
+                    cmd: MatchCmdType::ReplaceOrder,
+                    orders: vec![Order {
+                        price,
+                        ..place("r", new, OrderSide::Buy).order.unwrap()
+                    }],
+                    ..place("r", old, OrderSide::Buy)
+                },
+                ..Default::default()
+            };
+            engine.on_message(index, &codec::encode(&envelope)).unwrap();
+        };
+        replace(3, "placed", "1", "2", dec!(99));
+        // The new order is above the symbol's maximum price
+        replace(4, "rejected", "2", "3", dec!(5000));
+        engine.flush_read_view();
+
+        let placed = crate::read_view::cancellation("r", "placed").unwrap();
+        assert_eq!(placed.order_ids, vec!["1".to_string()]);
+        assert_eq!(placed.replacement, Some(OrderStatus::New));
+        assert!(placed.reject_reason.is_empty());
+        let rejected = crate::read_view::cancellation("r", "rejected").unwrap();
+        assert_eq!(rejected.order_ids, vec!["2".to_string()]);
+        assert_eq!(rejected.replacement, Some(OrderStatus::Rejected));
+        assert!(!rejected.reject_reason.is_empty());
+        let r = engine.get_tenant("r").unwrap();
+        assert!(!r.is_open("BTC", "2") && !r.is_open("BTC", "3"));
+    }
+}
@@ -0,0 +1,114 @@
+//! Order Throttle Module
+//!
+//! This module limits how many orders an account places per second, see
+//! `RiskLimits::orders_per_second`, with a token bucket per account. Buckets are
+//! part of the replicated tenant state and only advanced by the engine time of the
+//! commands, never by a local clock, so every replica admits and rejects the same
+//! orders and a new leader enforces the limit exactly where the old one left off.
+
+use crate::engine::ledger::RiskLimits;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Thousandths of a token an order costs, buckets are kept in thousandths so they
+/// refill by whole units every millisecond
+const ORDER_COST: u64 = 1000;
+
+/// Token bucket of one account
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Bucket {
+    /// Thousandths of a token left
+    tokens: u64,
+    /// Engine time in milliseconds the bucket was last refilled at
+    updated_ms: u64,
+}
+
+/// Order rate limits of the accounts of a tenant
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct OrderThrottle {
+    /// Buckets of the accounts with a limit that placed orders, keyed by account
+    buckets: BTreeMap<u64, Bucket>,
+}
+
+impl OrderThrottle {
+    /// Takes a token for an order of an account
+    ///
+    /// The bucket of an account holds up to `order_burst` tokens, or one
+    /// second's worth if unset, starts full and refills at `orders_per_second`.
+    ///
+    /// # Arguments
+    /// * `account_id` - Account placing the order
+    /// * `limits` - Risk limits of the account
+    /// * `now_ms` - Engine time of the order in milliseconds
+    ///
+    /// # Returns
+    /// * `Ok(())` - If the order may be placed
+    /// * `Err(String)` - If the account placed too many orders
+    pub fn admit(
+        &mut self,
+        account_id: u64,
+        limits: &RiskLimits,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        let rate = match limits.orders_per_second {
+            Some(rate) if rate > 0 => u64::from(rate),
+            _ => {
+                self.buckets.remove(&account_id);
+                return Ok(());
+            }
+        };
+        let burst = match limits.order_burst {
+            0 => rate,
+            burst => u64::from(burst),
+        };
+        let capacity = burst * ORDER_COST;
+        let bucket = self.buckets.entry(account_id).or_insert(Bucket {
+            tokens: capacity,
+            updated_ms: now_ms,
+        });
+        let elapsed = now_ms.saturating_sub(bucket.updated_ms);
+        bucket.tokens = bucket
+            .tokens
+            .saturating_add(elapsed.saturating_mul(rate))
+            .min(capacity);
+        bucket.updated_ms = bucket.updated_ms.max(now_ms);
+        if bucket.tokens < ORDER_COST {
+            return Err(format!(
+                "Account {} exceeds {} orders per second",
+                account_id, rate
+            ));
+        }
+        bucket.tokens -= ORDER_COST;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_above_the_rate_are_rejected_until_the_bucket_refills() {
+        let limits = RiskLimits {
+            orders_per_second: Some(2),
+            order_burst: 3,
+            ..Default::default()
+        };
+        let mut throttle = OrderThrottle::default();
+        for _ in 0..3 {
+            throttle.admit(1, &limits, 1_000).unwrap();
+        }
+        assert!(throttle.admit(1, &limits, 1_000).is_err());
+        // Other accounts and accounts without a limit have buckets of their own
+        throttle.admit(2, &limits, 1_000).unwrap();
+        throttle.admit(3, &RiskLimits::default(), 1_000).unwrap();
+
+        // Two orders per second refill one token every 500ms
+        assert!(throttle.admit(1, &limits, 1_499).is_err());
+        throttle.admit(1, &limits, 1_500).unwrap();
+        assert!(throttle.admit(1, &limits, 1_500).is_err());
+
+        // An earlier engine time never refills the bucket
+        assert!(throttle.admit(1, &limits, 900).is_err());
+    }
+}
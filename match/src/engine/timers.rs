@@ -0,0 +1,313 @@
+//! Replicated Timer Module
+//!
+//! This module keeps the timers engine features register to act at a later engine
+//! time, e.g. orders expiring at the end of their validity. Timers are part of the
+//! engine state and fire while commands are applied: before a command stamped with
+//! a hybrid logical clock timestamp is applied, every timer due at or before the
+//! new engine time fires, so all replicas fire the same timers at the same index.
+//!
+//! Without traffic engine time stands still, so the leader proposes a `Tick`
+//! command whenever a timer is due on its wall clock, see `crate::ticker`. A timer
+//! therefore fires at the first command at or after its time, never before it.
+
+use super::matchengine::MarketType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// What a timer does when it fires
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimerAction {
+    /// Cancel an order if it is still open
+    CancelOrder {
+        /// Market of the order
+        market: MarketType,
+        /// Symbol the order was placed on
+        symbol: String,
+        /// ID of the order
+        order_id: String,
+    },
+    /// Resume trading in a symbol halted by its circuit breaker, see
+    /// `engine::circuit_breaker`
+    ResumeSymbol {
+        /// Market of the symbol
+        market: MarketType,
+        /// ID of the symbol
+        symbol: String,
+    },
+}
+
+/// Timer registered by the engine
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timer {
+    /// ID of the timer, unique within the engine
+    pub id: u64,
+    /// Tenant the timer acts on
+    pub tenant: String,
+    /// Account the action is made for, 0 if not account scoped
+    pub account_id: u64,
+    /// What the timer does
+    pub action: TimerAction,
+}
+
+/// Timers of the engine by the engine time they are due at
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Timers {
+    /// Timers by due time in milliseconds, in the order they were registered
+    due: BTreeMap<u64, Vec<Timer>>,
+    /// ID of the next timer registered
+    next_id: u64,
+}
+
+impl Timers {
+    /// Registers a timer
+    ///
+    /// # Arguments
+    /// * `at_ms` - Engine time in milliseconds the timer is due at
+    /// * `tenant` - Tenant the timer acts on
+    /// * `account_id` - Account the action is made for
+    /// * `action` - What the timer does
+    ///
+    /// # Returns
+    /// The ID of the timer, see `cancel`
+    pub fn schedule(
+        &mut self,
+        at_ms: u64,
+        tenant: &str,
+        account_id: u64,
+        action: TimerAction,
+    ) -> u64 {
+        self.next_id += 1;
+        let timer = Timer {
+            id: self.next_id,
+            tenant: tenant.to_string(),
+            account_id,
+            action,
+        };
+        self.due.entry(at_ms).or_default().push(timer);
+        self.next_id
+    }
+
+    /// Unregisters a timer that has not fired
+    ///
+    /// # Arguments
+    /// * `at_ms` - Engine time the timer is due at
+    /// * `id` - ID returned by `schedule`
+    ///
+    /// # Returns
+    /// Whether the timer was registered
+    pub fn cancel(&mut self, at_ms: u64, id: u64) -> bool {
+        let Some(timers) = self.due.get_mut(&at_ms) else {
+            return false;
+        };
+        let before = timers.len();
+        timers.retain(|timer| timer.id != id);
+        let canceled = timers.len() < before;
+        if timers.is_empty() {
+            self.due.remove(&at_ms);
+        }
+        canceled
+    }
+
+    /// Unregisters the timers that have not fired and match a condition
+    ///
+    /// # Arguments
+    /// * `matches` - Whether a timer is unregistered
+    ///
+    /// # Returns
+    /// The number of timers unregistered
+    pub fn cancel_where(&mut self, mut matches: impl FnMut(&Timer) -> bool) -> usize {
+        let before = self.len();
+        for timers in self.due.values_mut() {
+            timers.retain(|timer| !matches(timer));
+        }
+        self.due.retain(|_, timers| !timers.is_empty());
+        before - self.len()
+    }
+
+    /// Returns the engine time in milliseconds the next timer is due at
+    pub fn next_due(&self) -> Option<u64> {
+        self.due.keys().next().copied()
+    }
+
+    /// Removes the timers due at or before an engine time
+    ///
+    /// # Arguments
+    /// * `now_ms` - Engine time in milliseconds
+    ///
+    /// # Returns
+    /// The due timers by due time, then in the order they were registered
+    pub fn take_due(&mut self, now_ms: u64) -> Vec<Timer> {
+        let later = self.due.split_off(&now_ms.saturating_add(1));
+        std::mem::replace(&mut self.due, later)
+            .into_values()
+            .flatten()
+            .collect()
+    }
+
+    /// Returns the number of timers registered
+    pub fn len(&self) -> usize {
+        self.due.values().map(Vec::len).sum()
+    }
+
+    /// Returns whether no timer is registered
+    pub fn is_empty(&self) -> bool {
+        self.due.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::clock::Hlc;
+    use crate::engine::codec;
+    use crate::engine::entry::order::OrderStatus;
+    use crate::engine::entry::{Order, OrderSide, OrderType, Symbol, TimeInForce};
+    use crate::engine::matchengine::{
+        CommandEnvelope, MatchCmd, MatchCmdType, MatchEngine, OrderChange, DEFAULT_TENANT,
+    };
+    use rust_decimal_macros::dec;
+
+    fn apply(engine: &mut MatchEngine, index: u64, at_ms: u64, cmd: MatchCmd) {
+        let envelope = CommandEnvelope {
+            account_id: 1,
+            hlc: Hlc::from_millis(at_ms).0,
+            cmd,
+            ..Default::default()
+        };
+        engine.on_message(index, &codec::encode(&envelope)).unwrap();
+    }
+
+    fn cancel(order_id: &str) -> TimerAction {
+        TimerAction::CancelOrder {
+            market: MarketType::Spot,
+            symbol: "BTC".to_string(),
+            order_id: order_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn due_timers_fire_in_time_then_registration_order() {
+        let mut timers = Timers::default();
+        let late = timers.schedule(2_000, "a", 1, cancel("1"));
+        timers.schedule(1_000, "a", 1, cancel("2"));
+        timers.schedule(1_000, "b", 2, cancel("3"));
+        let gone = timers.schedule(1_500, "a", 1, cancel("4"));
+        assert_eq!(timers.next_due(), Some(1_000));
+        assert!(timers.cancel(1_500, gone));
+        assert!(!timers.cancel(1_500, gone));
+        assert!(!timers.cancel(1_000, late));
+
+        assert!(timers.take_due(999).is_empty());
+        let fired: Vec<_> = timers.take_due(1_999).into_iter().map(|t| t.id).collect();
+        assert_eq!(fired, [2, 3]);
+        assert_eq!(timers.next_due(), Some(2_000));
+        let fired = timers.take_due(2_000);
+        assert_eq!(fired[0].action, cancel("1"));
+        assert!(timers.is_empty());
+    }
+
+    #[test]
+    fn timers_fire_once_engine_time_reaches_them() {
+        let mut engine = MatchEngine::new();
+        let symbol = Symbol {
+            name: "BTC".to_string(),
+            max_price: dec!(1000),
+            max_quantity: dec!(1000),
+            ..Default::default()
+        };
+        let create = MatchCmd {
+            cmd: MatchCmdType::CreateSymbol,
+            symbol: Some(symbol),
+            ..Default::default()
+        };
+        apply(&mut engine, 1, 1_000, create);
+        let order = Order {
+            id: "7".to_string(),
+            symbol: "BTC".to_string(),
+            order_type: OrderType::Limit,
+            side: OrderSide::Buy,
+            price: dec!(100),
+            quantity: dec!(1),
+            ..Default::default()
+        };
+        let place = MatchCmd {
+            order: Some(order),
+            ..Default::default()
+        };
+        apply(&mut engine, 2, 1_000, place);
+        engine
+            .timers_mut()
+            .schedule(2_000, DEFAULT_TENANT, 1, cancel("7"));
+        engine.take_order_events();
+
+        let tick = MatchCmd {
+            cmd: MatchCmdType::Tick,
+            ..Default::default()
+        };
+        apply(&mut engine, 3, 1_999, tick.clone());
+        let tenant = engine.get_tenant(DEFAULT_TENANT).unwrap();
+        assert!(tenant.is_open("BTC", "7"));
+        apply(&mut engine, 4, 2_000, tick);
+        let tenant = engine.get_tenant(DEFAULT_TENANT).unwrap();
+        assert!(!tenant.is_open("BTC", "7"));
+        assert!(engine.timers().is_empty());
+        let events = engine.take_order_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].index, events[0].account_id), (4, 1));
+        assert!(matches!(events[0].change, OrderChange::Canceled { .. }));
+    }
+
+    #[test]
+    fn good_till_date_orders_are_canceled_at_their_expiry() {
+        let mut engine = MatchEngine::new();
+        let symbol = Symbol {
+            name: "BTC".to_string(),
+            max_price: dec!(1000),
+            max_quantity: dec!(1000),
+            ..Default::default()
+        };
+        let create = MatchCmd {
+            cmd: MatchCmdType::CreateSymbol,
+            symbol: Some(symbol),
+            ..Default::default()
+        };
+        apply(&mut engine, 1, 1_000, create);
+        let place = |id: &str, expire_at| MatchCmd {
+            order: Some(Order {
+                id: id.to_string(),
+                symbol: "BTC".to_string(),
+                order_type: OrderType::Limit,
+                side: OrderSide::Buy,
+                price: dec!(100),
+                quantity: dec!(1),
+                time_in_force: TimeInForce::Gtd,
+                expire_at,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        apply(&mut engine, 2, 1_000, place("7", 2_000));
+        apply(&mut engine, 3, 1_500, place("8", 1_500));
+        assert_eq!(engine.timers().next_due(), Some(2_000));
+        // Order 8 expires as it arrives
+        let events = engine.take_order_events();
+        assert!(matches!(
+            &events[1].change,
+            OrderChange::Placed { order, .. } if order.status == OrderStatus::Rejected
+        ));
+
+        let tick = MatchCmd {
+            cmd: MatchCmdType::Tick,
+            ..Default::default()
+        };
+        apply(&mut engine, 4, 2_000, tick);
+        let tenant = engine.get_tenant(DEFAULT_TENANT).unwrap();
+        assert!(!tenant.is_open("BTC", "7"));
+        let events = engine.take_order_events();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            &events[0].change,
+            OrderChange::Canceled { order_id, .. } if order_id == "7"
+        ));
+    }
+}
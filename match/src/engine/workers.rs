@@ -0,0 +1,617 @@
+//! Per-Symbol Apply Workers
+//!
+//! Orders and cancels on symbols that do not settle balances read and write
+//! nothing but the order book of their symbol. With apply workers configured the
+//! engine queues such commands instead of applying them on the raft loop, and at
+//! the next barrier hands the queued commands of each symbol, together with the
+//! symbol's book, to one of a fixed set of worker threads picked by hashing tenant
+//! and symbol. Books of different symbols are matched in parallel, the commands of
+//! one symbol in log order.
+//!
+//! Every other command is a barrier and drains the queue before it is applied, as
+//! does reading the state for snapshots, checksums and the events published after
+//! an apply batch. Outcomes are merged back in log order, so events, metrics and
+//! snapshots are the same as if the commands had been applied one by one.
+
+use super::entry::{Order, Trade};
+use super::matchengine::MarketType;
+use super::perp::ContractProcessor;
+use super::spot::OrderProcessor;
+use super::tenant::Tenant;
+use crate::affinity;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::{self, Sender};
+
+/// Order book of a symbol, detached from its tenant while a job works on it
+#[derive(Debug)]
+pub enum Book {
+    /// Processor holding only the spot symbol
+    Spot(OrderProcessor),
+    /// Processor holding only the contract
+    Perp(ContractProcessor),
+}
+
+impl Book {
+    /// Detaches the book of a symbol from its tenant
+    ///
+    /// # Arguments
+    /// * `tenant` - Tenant the symbol is listed by
+    /// * `market` - Market the symbol is listed on
+    /// * `symbol` - ID of the symbol
+    ///
+    /// # Returns
+    /// The book if the symbol is listed, None otherwise
+    fn detach(tenant: &mut Tenant, market: MarketType, symbol: &str) -> Option<Book> {
+        match market {
+            MarketType::Spot => tenant.spot_processor.split_off(symbol).map(Book::Spot),
+            MarketType::Perp => tenant.perp_processor.split_off(symbol).map(Book::Perp),
+        }
+    }
+
+    /// Attaches a detached book to its tenant again
+    fn attach(self, tenant: &mut Tenant) {
+        match self {
+            Book::Spot(processor) => tenant.spot_processor.merge(processor),
+            Book::Perp(processor) => tenant.perp_processor.merge(processor),
+        }
+    }
+
+    /// Returns the market of the book
+    fn market(&self) -> MarketType {
+        match self {
+            Book::Spot(_) => MarketType::Spot,
+            Book::Perp(_) => MarketType::Perp,
+        }
+    }
+}
+
+/// Change a queued command asks for
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Place the order
+    Place(Order),
+    /// Cancel the order with the given ID
+    Cancel(String),
+}
+
+/// Command queued for the apply workers
+#[derive(Debug, Clone)]
+pub struct Queued {
+    /// Raft index of the command
+    pub index: u64,
+    /// Tenant the command is scoped to
+    pub tenant: String,
+    /// Market the symbol is listed on
+    pub market: MarketType,
+    /// Symbol whose book the command changes
+    pub symbol: String,
+    /// Account the command was made for
+    pub account_id: u64,
+    /// Engine time of the command in seconds
+    pub time: u64,
+    /// The change to apply
+    pub action: Action,
+}
+
+/// Result of applying a queued command
+#[derive(Debug)]
+pub enum Outcome {
+    /// The order was placed, or rejected by the book
+    Placed {
+        /// The order as it was placed
+        order: Order,
+        /// Trades of the order, or why it was rejected
+        result: Result<Vec<Trade>, String>,
+        /// Whether the rest of the order stayed on the book
+        resting: bool,
+    },
+    /// The order was canceled, or was not on the book
+    Canceled {
+        /// ID of the order
+        order_id: String,
+        /// Whether the order was on the book
+        canceled: bool,
+    },
+}
+
+/// Queued commands of one symbol and the book they are applied to
+#[derive(Debug)]
+pub struct Job {
+    /// Tenant the symbol is listed by
+    pub tenant: String,
+    /// The detached book
+    pub book: Book,
+    /// Commands in log order, paired with their outcome once applied
+    pub commands: Vec<(Queued, Option<Outcome>)>,
+}
+
+impl Job {
+    /// Detaches the book a command works on and starts a job for its symbol
+    ///
+    /// # Arguments
+    /// * `tenant` - Tenant the command is scoped to
+    /// * `queued` - First queued command of the symbol
+    ///
+    /// # Returns
+    /// The job, None if the symbol is not listed
+    pub fn start(tenant: &mut Tenant, queued: &Queued) -> Option<Job> {
+        Some(Job {
+            tenant: tenant.id.clone(),
+            book: Book::detach(tenant, queued.market, &queued.symbol)?,
+            commands: Vec::new(),
+        })
+    }
+
+    /// Attaches the book to its tenant again
+    ///
+    /// # Returns
+    /// The market of the book and the applied commands with their outcomes
+    pub fn finish(self, tenant: &mut Tenant) -> (MarketType, Vec<(Queued, Option<Outcome>)>) {
+        let market = self.book.market();
+        self.book.attach(tenant);
+        (market, self.commands)
+    }
+
+    /// Applies the commands in log order
+    fn run(&mut self) {
+        for (queued, outcome) in &mut self.commands {
+            *outcome = Some(match (&mut self.book, &queued.action) {
+                (Book::Spot(processor), Action::Place(order)) => {
+                    let result = processor.place_order(order);
+                    let resting = processor
+                        .get_orderbook(&order.symbol)
+                        .is_some_and(|book| book.get_order(&order.id).is_some());
+                    Outcome::Placed {
+                        order: order.clone(),
+                        result,
+                        resting,
+                    }
+                }
+                (Book::Perp(processor), Action::Place(order)) => {
+                    let result = processor.place_order(order);
+                    let resting = processor
+                        .get_orderbook(&order.symbol)
+                        .is_some_and(|book| book.get_order(&order.id).is_some());
+                    Outcome::Placed {
+                        order: order.clone(),
+                        result,
+                        resting,
+                    }
+                }
+                (Book::Spot(processor), Action::Cancel(order_id)) => Outcome::Canceled {
+                    order_id: order_id.clone(),
+                    canceled: matches!(
+                        processor.cancel_order(&queued.symbol, order_id),
+                        Ok(Some(_))
+                    ),
+                },
+                (Book::Perp(processor), Action::Cancel(order_id)) => Outcome::Canceled {
+                    order_id: order_id.clone(),
+                    canceled: matches!(
+                        processor.cancel_order(&queued.symbol, order_id),
+                        Ok(Some(_))
+                    ),
+                },
+            });
+        }
+    }
+
+    /// Picks the worker of the job's symbol
+    fn worker(&self, workers: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        self.tenant.hash(&mut hasher);
+        if let Some((queued, _)) = self.commands.first() {
+            queued.symbol.hash(&mut hasher);
+        }
+        (hasher.finish() % workers as u64) as usize
+    }
+}
+
+/// Job handed to a worker with the channel it is returned on
+type Task = (Job, Sender<Job>);
+
+/// Fixed set of worker threads jobs are run on
+#[derive(Debug)]
+pub struct Workers {
+    /// Queue of each worker
+    queues: Vec<Sender<Task>>,
+}
+
+impl Workers {
+    /// Starts the worker threads
+    ///
+    /// Workers stop once the last handle to the set is dropped.
+    ///
+    /// # Arguments
+    /// * `count` - Number of threads, at least one is started
+    /// * `cores` - Cores the threads are pinned to round-robin, empty leaves them
+    ///   unpinned
+    pub fn start(count: usize, cores: &[usize]) -> Workers {
+        let queues = (0..count.max(1))
+            .map(|i| {
+                let (queue, tasks) = mpsc::channel::<Task>();
+                let core = (!cores.is_empty()).then(|| cores[i % cores.len()]);
+                std::thread::Builder::new()
+                    .name(format!("apply-{}", i))
+                    .spawn(move || {
+                        if let Some(core) = core {
+                            affinity::pin_or_warn(&format!("apply-{}", i), &[core]);
+                        }
+                        for (mut job, done) in tasks {
+                            job.run();
+                            let _ = done.send(job);
+                        }
+                    })
+                    .expect("spawn apply worker");
+                queue
+            })
+            .collect();
+        Workers { queues }
+    }
+
+    /// Runs jobs, each on the worker of its symbol
+    ///
+    /// A single job is run on the calling thread, handing it over would only add
+    /// latency.
+    ///
+    /// # Arguments
+    /// * `jobs` - Jobs of distinct symbols
+    ///
+    /// # Returns
+    /// The jobs with their commands applied, in no particular order
+    pub fn run(&self, mut jobs: Vec<Job>) -> Vec<Job> {
+        if jobs.len() == 1 {
+            jobs[0].run();
+            return jobs;
+        }
+        let count = jobs.len();
+        let (done, finished) = mpsc::channel();
+        for job in jobs {
+            let worker = job.worker(self.queues.len());
+            self.queues[worker]
+                .send((job, done.clone()))
+                .expect("apply worker stopped");
+        }
+        drop(done);
+        let jobs: Vec<Job> = finished.iter().collect();
+        // A worker that panicked dropped its job and the book with it
+        assert_eq!(jobs.len(), count, "apply worker failed");
+        jobs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::codec;
+    use crate::engine::entry::{Order, OrderSide, OrderType, Symbol};
+    use crate::engine::ledger::Transfer;
+    use crate::engine::matchengine::{
+        CommandEnvelope, MarketType, MatchCmd, MatchCmdType, MatchEngine,
+    };
+    use rust_decimal_macros::dec;
+
+    fn apply(engine: &mut MatchEngine, index: u64, account_id: u64, cmd: MatchCmd) {
+        let envelope = CommandEnvelope {
+            account_id,
+            cmd,
+            proposed_at: index * 1000,
+            ..Default::default()
+        };
+        engine.on_message(index, &codec::encode(&envelope)).unwrap();
+    }
+
+    /// Lists three symbols trading without balances, one settling balances and a
+    /// contract, then trades on all of them with deposits in between
+    fn commands() -> Vec<(u64, MatchCmd)> {
+        let mut commands = Vec::new();
+        for (name, market, settle) in [
+            ("AAA", MarketType::Spot, false),
+            ("BBB", MarketType::Spot, false),
+            ("CCC", MarketType::Spot, false),
+            ("SET", MarketType::Spot, true),
+            ("PERP", MarketType::Perp, false),
+        ] {
+            let symbol = Symbol {
+                name: name.to_string(),
+                base_currency: "BTC".to_string(),
+                quote_currency: "USDT".to_string(),
+                max_price: dec!(1000000),
+                max_quantity: dec!(1000),
+                max_leverage: dec!(10),
+                maintenance_margin: dec!(0.05),
+                settle_balances: settle,
+                ..Default::default()
+            };
+            commands.push((
+                0,
+                MatchCmd {
+                    cmd: MatchCmdType::CreateSymbol,
+                    symbol: Some(symbol),
+                    market,
+                    ..Default::default()
+                },
+            ));
+        }
+        for account_id in [1, 2] {
+            for currency in ["BTC", "USDT"] {
+                commands.push((
+                    account_id,
+                    MatchCmd {
+                        cmd: MatchCmdType::Deposit,
+                        transfer: Some(Transfer {
+                            currency: currency.to_string(),
+                            amount: dec!(1000000),
+                            idempotency_key: format!("{}-{}", account_id, currency),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                ));
+            }
+        }
+        for round in 0..20u64 {
+            // Halfway through, a batch quotes the spot symbols at once
+            if round == 10 {
+                let orders = ["AAA", "BBB", "SET"]
+                    .into_iter()
+                    .map(|symbol| Order {
+                        id: format!("{}-batch", symbol),
+                        symbol: symbol.to_string(),
+                        order_type: OrderType::Limit,
+                        side: OrderSide::Sell,
+                        price: dec!(101),
+                        quantity: dec!(2),
+                        ..Default::default()
+                    })
+                    .collect();
+                commands.push((
+                    2,
+                    MatchCmd {
+                        cmd: MatchCmdType::Batch,
+                        orders,
+                        ..Default::default()
+                    },
+                ));
+            }
+            for (symbol, market) in [
+                ("AAA", MarketType::Spot),
+                ("BBB", MarketType::Spot),
+                ("CCC", MarketType::Spot),
+                ("SET", MarketType::Spot),
+                ("PERP", MarketType::Perp),
+            ] {
+                let side = if round % 2 == 0 {
+                    OrderSide::Buy
+                } else {
+                    OrderSide::Sell
+                };
+                let order = Order {
+                    id: format!("{}-{}", symbol, round),
+                    symbol: symbol.to_string(),
+                    order_type: OrderType::Limit,
+                    side,
+                    price: dec!(100) + rust_decimal::Decimal::from(round % 3),
+                    quantity: dec!(1) + rust_decimal::Decimal::from(round % 4),
+                    client_order_id: format!("{}-c{}", symbol, round % 6),
+                    ..Default::default()
+                };
+                let cmd = if round % 5 == 4 {
+                    MatchCmdType::CancelOrder
+                } else {
+                    MatchCmdType::PlaceOrder
+                };
+                // Every other cancel names the order by its client order ID
+                let order = match cmd {
+                    MatchCmdType::CancelOrder if round % 10 == 4 => Order {
+                        id: format!("{}-{}", symbol, round - 2),
+                        client_order_id: String::new(),
+                        ..order
+                    },
+                    MatchCmdType::CancelOrder => Order {
+                        id: String::new(),
+                        client_order_id: format!("{}-c{}", symbol, (round - 2) % 6),
+                        ..order
+                    },
+                    _ => order,
+                };
+                commands.push((
+                    1 + round % 2,
+                    MatchCmd {
+                        cmd,
+                        order: Some(order),
+                        market,
+                        ..Default::default()
+                    },
+                ));
+            }
+        }
+        commands
+    }
+
+    /// Takes the order events of an engine
+    fn order_events(engine: &mut MatchEngine) -> Vec<serde_json::Value> {
+        engine
+            .take_order_events()
+            .iter()
+            .map(|event| serde_json::to_value(event).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parallel_apply_matches_sequential_apply() {
+        let mut sequential = MatchEngine::new();
+        let mut parallel = MatchEngine::with_workers(3, &[]);
+        for (i, (account_id, cmd)) in commands().into_iter().enumerate() {
+            let index = i as u64 + 1;
+            apply(&mut sequential, index, account_id, cmd.clone());
+            apply(&mut parallel, index, account_id, cmd);
+        }
+        parallel.barrier();
+
+        assert_eq!(sequential.snapshot().unwrap(), parallel.snapshot().unwrap());
+        let events = order_events(&mut sequential);
+        assert!(events
+            .iter()
+            .any(|event| event.pointer("/change/Canceled").is_some()));
+        assert_eq!(events, order_events(&mut parallel));
+        assert_eq!(sequential.take_settlements(), parallel.take_settlements());
+    }
+
+    #[test]
+    fn client_order_ids_are_unique_among_open_orders() {
+        let mut engine = MatchEngine::with_workers(2, &[]);
+        let symbol = Symbol {
+            name: "AAA".to_string(),
+            max_price: dec!(1000000),
+            max_quantity: dec!(1000),
+            ..Default::default()
+        };
+        let create = MatchCmd {
+            cmd: MatchCmdType::CreateSymbol,
+            symbol: Some(symbol),
+            ..Default::default()
+        };
+        let order = |cmd, id: &str| MatchCmd {
+            cmd,
+            order: Some(Order {
+                id: id.to_string(),
+                symbol: "AAA".to_string(),
+                order_type: OrderType::Limit,
+                price: dec!(100),
+                quantity: dec!(1),
+                client_order_id: "x".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        apply(&mut engine, 1, 0, create);
+        apply(&mut engine, 2, 7, order(MatchCmdType::PlaceOrder, "1"));
+        apply(&mut engine, 3, 7, order(MatchCmdType::PlaceOrder, "2"));
+        apply(&mut engine, 4, 8, order(MatchCmdType::PlaceOrder, "3"));
+        apply(&mut engine, 5, 7, order(MatchCmdType::CancelOrder, ""));
+        apply(&mut engine, 6, 7, order(MatchCmdType::PlaceOrder, ""));
+        engine.barrier();
+
+        let events: Vec<_> = order_events(&mut engine)
+            .into_iter()
+            .skip(1)
+            .map(|event| {
+                let placed = event.pointer("/change/Placed/order");
+                let canceled = event.pointer("/change/Canceled/order_id");
+                match (placed, canceled) {
+                    (Some(order), _) => format!("{} {}", order["id"], order["status"]),
+                    (_, Some(order_id)) => format!("{} canceled", order_id),
+                    _ => String::new(),
+                }
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "\"1\" \"New\"",
+                "\"2\" \"Rejected\"",
+                "\"3\" \"New\"",
+                "\"1\" canceled",
+                "\"6\" \"New\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn stop_orders_are_placed_once_the_last_trade_price_reaches_them() {
+        let mut engine = MatchEngine::with_workers(2, &[]);
+        let symbol = Symbol {
+            name: "AAA".to_string(),
+            max_price: dec!(1000000),
+            max_quantity: dec!(1000),
+            ..Default::default()
+        };
+        let create = MatchCmd {
+            cmd: MatchCmdType::CreateSymbol,
+            symbol: Some(symbol),
+            ..Default::default()
+        };
+        let order = |id: &str, order_type, side, price, stop_price| Order {
+            id: id.to_string(),
+            symbol: "AAA".to_string(),
+            order_type,
+            side,
+            price,
+            quantity: dec!(1),
+            stop_price,
+            ..Default::default()
+        };
+        let place = |order| MatchCmd {
+            cmd: MatchCmdType::PlaceOrder,
+            order: Some(order),
+            ..Default::default()
+        };
+        let (buy, sell) = (OrderSide::Buy, OrderSide::Sell);
+        let zero = rust_decimal::Decimal::ZERO;
+        let commands = [
+            (0, create),
+            (
+                7,
+                place(order("1", OrderType::Limit, sell, dec!(100), zero)),
+            ),
+            (
+                7,
+                place(order("2", OrderType::Limit, sell, dec!(102), zero)),
+            ),
+            // Waits, the symbol has not traded yet
+            (8, place(order("3", OrderType::Stop, buy, zero, dec!(100)))),
+            // Trades at 100, which triggers the stop order, filled at 102
+            (9, place(order("4", OrderType::Limit, buy, dec!(100), zero))),
+            // Waits for a trade at 101 or below
+            (
+                8,
+                place(order("5", OrderType::StopLimit, sell, dec!(99), dec!(101))),
+            ),
+            (
+                8,
+                MatchCmd {
+                    cmd: MatchCmdType::CancelOrder,
+                    order: Some(order("5", OrderType::StopLimit, sell, zero, zero)),
+                    ..Default::default()
+                },
+            ),
+        ];
+        for (i, (account_id, cmd)) in commands.into_iter().enumerate() {
+            apply(&mut engine, i as u64 + 1, account_id, cmd);
+        }
+        engine.barrier();
+
+        let events: Vec<_> = order_events(&mut engine)
+            .into_iter()
+            .skip(1)
+            .map(|event| {
+                let placed = event.pointer("/change/Placed/order");
+                let canceled = event.pointer("/change/Canceled/order_id");
+                match (placed, canceled) {
+                    (Some(order), _) => format!(
+                        "{} {} {}",
+                        order["id"], order["order_type"], order["status"]
+                    ),
+                    (_, Some(order_id)) => format!("{} canceled", order_id),
+                    _ => String::new(),
+                }
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "\"1\" \"Limit\" \"New\"",
+                "\"2\" \"Limit\" \"New\"",
+                "\"3\" \"Stop\" \"New\"",
+                "\"4\" \"Limit\" \"Filled\"",
+                "\"3\" \"Market\" \"Filled\"",
+                "\"5\" \"StopLimit\" \"New\"",
+                "\"5\" canceled",
+            ]
+        );
+        let tenant = engine.get_tenant("default").unwrap();
+        assert_eq!(tenant.get_orderbook("AAA").unwrap().order_count(), 0);
+        assert!(!tenant.stop_orders.has_pending("AAA"));
+    }
+}
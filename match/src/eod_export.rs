@@ -0,0 +1,449 @@
+//! End-of-day export
+//!
+//! With an `[eod_export]` section, every node writes a daily cut of each listed
+//! symbol when the trading day ends at `at` (UTC): the full resting book as of a
+//! known applied index, the open interest and the statistics of the day. Each
+//! symbol is written as `<date>/<tenant>/<symbol>.json` to `dir` and uploaded with
+//! PUT below `upload_url`, where `<date>` is the day the trading day ends on, or
+//! the day before if it ends at midnight. `ExportEndOfDay` writes the same cut
+//! for the calling tenant on demand, with the statistics of the running day.
+//!
+//! Books are read through `read_view::book`, so the export never holds up
+//! matching. Statistics are tallied from the order events of each apply batch by
+//! engine time: open, high, low and close price, base and quote volume, trades,
+//! orders and cancels. They are kept in memory, so a node restarted during the
+//! day only counts what it applied since its last snapshot.
+
+use crate::config::{self, EodExportConfig};
+use crate::engine::entry::order::OrderStatus;
+use crate::engine::entry::Order;
+use crate::engine::history::{OrderChange, OrderEvent};
+use crate::read_view;
+use hyper::{Body, Client, Method, Request};
+use once_cell::sync::OnceCell;
+use rust_decimal::Decimal;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Seconds in a day
+const DAY: u64 = 24 * 60 * 60;
+
+/// Export settings and the end of the trading day in seconds after midnight,
+/// None if the export is disabled
+static EXPORT: OnceCell<Option<(EodExportConfig, u64)>> = OnceCell::new();
+
+/// Statistics by tenant, symbol and trading day
+static STATS: Mutex<BTreeMap<(String, String, u64), DailyStats>> = Mutex::new(BTreeMap::new());
+
+/// Trading statistics of a symbol over one trading day
+#[derive(Debug, Clone, Default, PartialEq)]
+struct DailyStats {
+    /// Price of the first trade, zero without trades
+    open: Decimal,
+    /// Highest trade price
+    high: Decimal,
+    /// Lowest trade price
+    low: Decimal,
+    /// Price of the last trade
+    close: Decimal,
+    /// Base quantity traded
+    volume: Decimal,
+    /// Quote amount traded
+    quote_volume: Decimal,
+    /// Number of trades
+    trades: u64,
+    /// Number of orders placed, rejected ones included
+    orders: u64,
+    /// Number of orders canceled
+    cancels: u64,
+}
+
+impl DailyStats {
+    /// Counts a trade
+    fn trade(&mut self, price: Decimal, quantity: Decimal) {
+        if self.trades == 0 {
+            self.open = price;
+            self.high = price;
+            self.low = price;
+        }
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume += quantity;
+        self.quote_volume += price * quantity;
+        self.trades += 1;
+    }
+
+    /// Converts the statistics to their JSON form
+    fn to_json(&self) -> Value {
+        json!({
+            "open": self.open.to_string(),
+            "high": self.high.to_string(),
+            "low": self.low.to_string(),
+            "close": self.close.to_string(),
+            "volume": self.volume.to_string(),
+            "quote_volume": self.quote_volume.to_string(),
+            "trades": self.trades,
+            "orders": self.orders,
+            "cancels": self.cancels,
+        })
+    }
+}
+
+/// Returns the trading day a time falls in
+///
+/// Trading day `n` ends at `n` days after the epoch plus `cut`.
+///
+/// # Arguments
+///
+/// * `time` - Seconds since the epoch
+/// * `cut` - End of the trading day in seconds after midnight
+fn trading_day(time: u64, cut: u64) -> u64 {
+    (time + DAY - cut) / DAY
+}
+
+/// Returns the calendar date a trading day is named after, `YYYY-MM-DD`
+///
+/// # Arguments
+///
+/// * `day` - Trading day, see `trading_day`
+/// * `cut` - End of the trading day in seconds after midnight
+fn date(day: u64, cut: u64) -> String {
+    // Civil date of the last second of the trading day
+    let days = (day * DAY + cut - 1) / DAY;
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + u64::from(m <= 2);
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+/// Parses the end of the trading day
+///
+/// # Arguments
+///
+/// * `at` - UTC time of day, `HH:MM`
+///
+/// # Returns
+///
+/// Seconds after midnight, or why the time is invalid
+fn parse_cut(at: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid end of day {:?}, expected HH:MM", at);
+    let (hours, minutes) = at.split_once(':').ok_or_else(invalid)?;
+    let hours: u64 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u64 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+    Ok(hours * 3600 + minutes * 60)
+}
+
+/// Returns the current wall clock in seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Tallies the order events of a batch into the statistics of their day
+///
+/// # Arguments
+///
+/// * `stats` - Statistics by tenant, symbol and trading day
+/// * `events` - Order events of the batch, in log order
+/// * `cut` - End of the trading day in seconds after midnight
+fn tally(stats: &mut BTreeMap<(String, String, u64), DailyStats>, events: &[OrderEvent], cut: u64) {
+    for event in events {
+        let day = trading_day(event.time, cut);
+        let symbol = match &event.change {
+            OrderChange::Placed { order, .. } => &order.symbol,
+            OrderChange::Canceled { symbol, .. } => symbol,
+            OrderChange::SymbolRemoved { .. }
+            | OrderChange::SymbolListed { .. }
+            | OrderChange::SymbolHalted { .. }
+            | OrderChange::SymbolResumed { .. } => continue,
+        };
+        let entry = stats
+            .entry((event.tenant.clone(), symbol.clone(), day))
+            .or_default();
+        if let OrderChange::Placed { trades, .. } = &event.change {
+            entry.orders += 1;
+            for trade in trades {
+                entry.trade(trade.price, trade.quantity);
+            }
+        } else {
+            entry.cancels += 1;
+        }
+    }
+    // Keep the day before for the export at its end
+    if let Some(latest) = stats.keys().map(|(_, _, day)| *day).max() {
+        stats.retain(|(_, _, day), _| day + 1 >= latest);
+    }
+}
+
+/// Validates the end-of-day export settings, if any
+///
+/// Must be called before the raft loop starts applying entries.
+///
+/// # Returns
+///
+/// Returns an error if the end of the trading day is invalid
+pub fn open() -> Result<(), String> {
+    let export = match config::instance().lock().unwrap().eod_export.clone() {
+        Some(export) => {
+            let cut = parse_cut(&export.at)?;
+            log::info!("exporting the end of day at {} UTC", export.at);
+            Some((export, cut))
+        }
+        None => None,
+    };
+    let _ = EXPORT.set(export);
+    Ok(())
+}
+
+/// Returns whether the end-of-day export is configured
+pub fn is_enabled() -> bool {
+    matches!(EXPORT.get(), Some(Some(_)))
+}
+
+/// Tallies the order events of an apply batch into the daily statistics
+///
+/// # Arguments
+///
+/// * `events` - Order events of the batch, in log order
+pub fn write(events: &[OrderEvent]) {
+    let cut = match EXPORT.get() {
+        Some(Some((_, cut))) if !events.is_empty() => *cut,
+        _ => return,
+    };
+    tally(&mut STATS.lock().unwrap(), events, cut);
+}
+
+/// Starts exporting every tenant at the end of each trading day
+pub fn start() {
+    let Some(Some((_, cut))) = EXPORT.get() else {
+        return;
+    };
+    let cut = *cut;
+    tokio::spawn(async move {
+        loop {
+            let now = now();
+            let end = trading_day(now, cut) * DAY + cut;
+            tokio::time::sleep(Duration::from_secs(end - now)).await;
+            let tenants: BTreeSet<String> = read_view::published_books()
+                .into_iter()
+                .map(|(tenant, _, _)| tenant)
+                .collect();
+            let day = trading_day(end - 1, cut);
+            for tenant in tenants {
+                match export_day(&tenant, day).await {
+                    Ok(files) => log::info!(
+                        "exported the end of day {} of tenant {}: {} symbols",
+                        date(day, cut),
+                        tenant,
+                        files.len()
+                    ),
+                    Err(e) => log::error!("end-of-day export of tenant {} failed: {}", tenant, e),
+                }
+            }
+        }
+    });
+}
+
+/// Exports the running trading day of a tenant now
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant whose symbols are exported
+///
+/// # Returns
+///
+/// Returns the names of the files written, or why the export failed
+pub async fn export_now(tenant: &str) -> Result<Vec<String>, String> {
+    let Some(Some((_, cut))) = EXPORT.get() else {
+        return Err("end-of-day exports need eod_export to be configured".to_string());
+    };
+    export_day(tenant, trading_day(now(), *cut)).await
+}
+
+/// Writes and uploads the cut of every symbol of a tenant for a trading day
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant whose symbols are exported
+/// * `day` - Trading day the statistics are taken from
+///
+/// # Returns
+///
+/// Returns the names of the files written, relative to the export directory
+async fn export_day(tenant: &str, day: u64) -> Result<Vec<String>, String> {
+    let Some(Some((export, cut))) = EXPORT.get() else {
+        return Ok(Vec::new());
+    };
+    let date = date(day, *cut);
+    let symbols: Vec<String> = read_view::published_books()
+        .into_iter()
+        .filter(|(owner, _, _)| owner == tenant)
+        .map(|(_, symbol, _)| symbol)
+        .collect();
+    let mut files = Vec::new();
+    for symbol in symbols {
+        let Some(view) = read_view::book(tenant, &symbol).await else {
+            continue;
+        };
+        let stats = STATS
+            .lock()
+            .unwrap()
+            .get(&(tenant.to_string(), symbol.clone(), day))
+            .cloned()
+            .unwrap_or_default();
+        let book = view.orderbook();
+        let cut = json!({
+            "tenant": tenant,
+            "symbol": symbol,
+            "trading_day": date,
+            "index": view.index,
+            "exported_at": now(),
+            "bids": book.bids.values().rev().flatten().map(resting).collect::<Vec<_>>(),
+            "asks": book.asks.values().flatten().map(resting).collect::<Vec<_>>(),
+            "open_interest": read_view::open_interest(tenant, &symbol).to_string(),
+            "stats": stats.to_json(),
+        });
+        drop(view);
+        let name = format!("{}/{}/{}.json", date, tenant, symbol);
+        let body = serde_json::to_vec_pretty(&cut).map_err(|e| e.to_string())?;
+        if let Some(dir) = &export.dir {
+            let path = Path::new(dir).join(&name);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| format!("cannot create {:?}: {}", parent, e))?;
+            }
+            std::fs::write(&path, &body).map_err(|e| format!("cannot write {:?}: {}", path, e))?;
+        }
+        if let Some(url) = &export.upload_url {
+            upload(&format!("{}/{}", url.trim_end_matches('/'), name), body).await?;
+        }
+        files.push(name);
+    }
+    Ok(files)
+}
+
+/// Describes a resting order of the book
+fn resting(order: &Order) -> Value {
+    json!({
+        "order_id": order.id,
+        "side": format!("{:?}", order.side),
+        "price": order.price.to_string(),
+        "quantity": order.quantity.to_string(),
+        "remaining": order.remaining_quantity().to_string(),
+        "partially_filled": order.status == OrderStatus::PartiallyFilled,
+        "created_at": order.created_at,
+    })
+}
+
+/// Uploads an export file with PUT and checks for a success status
+async fn upload(url: &str, body: Vec<u8>) -> Result<(), String> {
+    let request = Request::builder()
+        .method(Method::PUT)
+        .uri(url)
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .map_err(|e| e.to_string())?;
+    let response = Client::new()
+        .request(request)
+        .await
+        .map_err(|e| format!("cannot upload to {}: {}", url, e))?;
+    if !response.status().is_success() {
+        return Err(format!("{} responded {}", url, response.status()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::entry::{OrderSide, OrderType, Trade};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn trading_days_end_at_the_cut_and_are_named_by_their_last_second() {
+        let cut = parse_cut("22:00").unwrap();
+        // 2026-10-16 21:59:59 and 22:00:00 UTC
+        let before = 1_792_187_999;
+        assert_eq!(trading_day(before, cut) + 1, trading_day(before + 1, cut));
+        assert_eq!(date(trading_day(before, cut), cut), "2026-10-16");
+        assert_eq!(date(trading_day(before + 1, cut), cut), "2026-10-17");
+        let midnight = parse_cut("00:00").unwrap();
+        assert_eq!(date(trading_day(before, midnight), midnight), "2026-10-16");
+        assert!(parse_cut("24:00").is_err());
+    }
+
+    #[test]
+    fn trades_orders_and_cancels_are_tallied_per_day() {
+        let trade = |price, quantity| {
+            Trade::new(
+                "t".to_string(),
+                "BTCUSDT".to_string(),
+                price,
+                quantity,
+                "1".to_string(),
+                "2".to_string(),
+                0,
+            )
+        };
+        let event = |time, change| OrderEvent {
+            index: 1,
+            tenant: "a".to_string(),
+            account_id: 1,
+            time,
+            change,
+        };
+        let placed = |trades| OrderChange::Placed {
+            order: Order::new(
+                "1".to_string(),
+                "BTCUSDT".to_string(),
+                OrderType::Limit,
+                OrderSide::Buy,
+                "100".to_string(),
+                "1".to_string(),
+            ),
+            trades,
+        };
+        let events = [
+            event(DAY, placed(vec![trade(dec!(100), dec!(1))])),
+            event(
+                DAY + 1,
+                placed(vec![trade(dec!(102), dec!(1)), trade(dec!(99), dec!(2))]),
+            ),
+            event(
+                DAY + 2,
+                OrderChange::Canceled {
+                    symbol: "BTCUSDT".to_string(),
+                    order_id: "3".to_string(),
+                },
+            ),
+            event(2 * DAY, placed(vec![])),
+        ];
+        let mut stats = BTreeMap::new();
+        tally(&mut stats, &events, 0);
+        let day = &stats[&("a".to_string(), "BTCUSDT".to_string(), 2)];
+        assert_eq!(
+            (day.open, day.high, day.low, day.close),
+            (dec!(100), dec!(102), dec!(99), dec!(99))
+        );
+        assert_eq!((day.volume, day.quote_volume), (dec!(4), dec!(400)));
+        assert_eq!((day.trades, day.orders, day.cancels), (3, 2, 1));
+        assert_eq!(
+            stats[&("a".to_string(), "BTCUSDT".to_string(), 3)].orders,
+            1
+        );
+    }
+}
@@ -0,0 +1,356 @@
+//! Metrics exporters
+//!
+//! The `/metrics` endpoint is always served for Prometheus scraping. Deployments that
+//! don't scrape can additionally push the same registry to StatsD, a Prometheus
+//! Pushgateway or an OTLP collector, selected via the `metrics_exporters` config.
+
+use crate::config::{self, MetricsExporterConfig};
+use crate::metrics::REGISTRY_INSTANCE;
+use hyper::{Body, Client, Method, Request};
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use prometheus::{Encoder, TextEncoder};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+/// Largest StatsD datagram, chosen to fit a typical MTU
+const STATSD_MAX_DATAGRAM: usize = 1432;
+
+/// Destination metrics are pushed to
+#[tonic::async_trait]
+pub trait Exporter: Send + Sync {
+    /// Name of the exporter, used in logs
+    fn name(&self) -> &'static str;
+
+    /// Pushes the gathered metric families
+    ///
+    /// # Arguments
+    ///
+    /// * `families` - Current state of the metrics registry
+    ///
+    /// # Returns
+    ///
+    /// Returns an error message if the push failed
+    async fn export(&self, families: &[MetricFamily]) -> Result<(), String>;
+}
+
+/// Starts pushing metrics to the configured exporters
+///
+/// Does nothing if no exporter is configured.
+pub async fn start_exporters() {
+    let (configs, interval_ms, node_id) = {
+        let config = config::instance().lock().unwrap();
+        (
+            config.metrics_exporters.clone(),
+            config.metrics_push_interval_ms,
+            config.id,
+        )
+    };
+    let mut exporters: Vec<Box<dyn Exporter>> = Vec::new();
+    for config in configs {
+        match build_exporter(config, node_id).await {
+            Ok(exporter) => {
+                log::info!("metrics exporter {} enabled", exporter.name());
+                exporters.push(exporter);
+            }
+            Err(e) => log::error!("failed to create metrics exporter: {}", e),
+        }
+    }
+    if exporters.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(interval_ms.max(1)));
+        loop {
+            interval.tick().await;
+            let families = REGISTRY_INSTANCE.gather();
+            for exporter in &exporters {
+                if let Err(e) = exporter.export(&families).await {
+                    log::warn!("metrics exporter {} failed: {}", exporter.name(), e);
+                }
+            }
+        }
+    });
+}
+
+/// Creates the exporter described by a config entry
+///
+/// # Arguments
+///
+/// * `config` - Exporter configuration
+/// * `node_id` - ID of this node, attached to pushed metrics as instance
+async fn build_exporter(
+    config: MetricsExporterConfig,
+    node_id: u64,
+) -> Result<Box<dyn Exporter>, String> {
+    let exporter: Box<dyn Exporter> = match config {
+        MetricsExporterConfig::Statsd { addr, prefix } => {
+            let socket = UdpSocket::bind("0.0.0.0:0")
+                .await
+                .map_err(|e| format!("statsd bind failed: {}", e))?;
+            socket
+                .connect(&addr)
+                .await
+                .map_err(|e| format!("statsd connect to {} failed: {}", addr, e))?;
+            Box::new(StatsdExporter { socket, prefix })
+        }
+        MetricsExporterConfig::Pushgateway { url, job } => Box::new(PushgatewayExporter {
+            url: format!(
+                "{}/metrics/job/{}/instance/{}",
+                url.trim_end_matches('/'),
+                job,
+                node_id
+            ),
+        }),
+        MetricsExporterConfig::Otlp { endpoint } => Box::new(OtlpExporter {
+            url: format!("{}/v1/metrics", endpoint.trim_end_matches('/')),
+            node_id,
+        }),
+    };
+    Ok(exporter)
+}
+
+/// Sends an HTTP request and checks for a success status
+///
+/// # Arguments
+///
+/// * `method` - HTTP method
+/// * `url` - Target URL
+/// * `content_type` - Content type of the body
+/// * `body` - Request body
+async fn send(method: Method, url: &str, content_type: &str, body: Vec<u8>) -> Result<(), String> {
+    let request = Request::builder()
+        .method(method)
+        .uri(url)
+        .header("content-type", content_type)
+        .body(Body::from(body))
+        .map_err(|e| e.to_string())?;
+    let response = Client::new()
+        .request(request)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("{} responded {}", url, response.status()));
+    }
+    Ok(())
+}
+
+/// Pushes metrics as StatsD gauges over UDP
+///
+/// Labels are sent as DogStatsD style tags. Counters are sent as gauges of their
+/// cumulative value and histograms as `_count` and `_sum` gauges.
+struct StatsdExporter {
+    /// Socket connected to the StatsD daemon
+    socket: UdpSocket,
+    /// Prefix prepended to every metric name
+    prefix: String,
+}
+
+impl StatsdExporter {
+    /// Formats one StatsD gauge line
+    fn line(&self, name: &str, labels: &[LabelPair], value: f64) -> String {
+        let mut line = format!("{}{}:{}|g", self.prefix, name, value);
+        if !labels.is_empty() {
+            let tags: Vec<String> = labels
+                .iter()
+                .map(|l| format!("{}:{}", l.get_name(), l.get_value()))
+                .collect();
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+        line
+    }
+}
+
+#[tonic::async_trait]
+impl Exporter for StatsdExporter {
+    fn name(&self) -> &'static str {
+        "statsd"
+    }
+
+    async fn export(&self, families: &[MetricFamily]) -> Result<(), String> {
+        let mut lines = Vec::new();
+        for family in families {
+            let name = family.get_name();
+            for metric in family.get_metric() {
+                let labels = metric.get_label();
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        lines.push(self.line(name, labels, metric.get_counter().get_value()))
+                    }
+                    MetricType::GAUGE => {
+                        lines.push(self.line(name, labels, metric.get_gauge().get_value()))
+                    }
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        lines.push(self.line(
+                            &format!("{}_count", name),
+                            labels,
+                            histogram.get_sample_count() as f64,
+                        ));
+                        lines.push(self.line(
+                            &format!("{}_sum", name),
+                            labels,
+                            histogram.get_sample_sum(),
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut datagram = String::new();
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + line.len() + 1 > STATSD_MAX_DATAGRAM {
+                self.socket
+                    .send(datagram.as_bytes())
+                    .await
+                    .map_err(|e| e.to_string())?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            self.socket
+                .send(datagram.as_bytes())
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Pushes metrics to a Prometheus Pushgateway in the text exposition format
+struct PushgatewayExporter {
+    /// Grouping URL metrics are pushed to
+    url: String,
+}
+
+#[tonic::async_trait]
+impl Exporter for PushgatewayExporter {
+    fn name(&self) -> &'static str {
+        "pushgateway"
+    }
+
+    async fn export(&self, families: &[MetricFamily]) -> Result<(), String> {
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::new();
+        encoder
+            .encode(families, &mut buffer)
+            .map_err(|e| e.to_string())?;
+        send(Method::PUT, &self.url, encoder.format_type(), buffer).await
+    }
+}
+
+/// Pushes metrics to an OTLP collector over HTTP with JSON encoding
+///
+/// Counters become cumulative monotonic sums, gauges stay gauges and histograms
+/// are converted from cumulative Prometheus buckets to OTLP bucket counts.
+struct OtlpExporter {
+    /// Metrics endpoint of the collector
+    url: String,
+    /// ID of this node, reported as service instance
+    node_id: u64,
+}
+
+impl OtlpExporter {
+    /// Converts metric labels to OTLP attributes
+    fn attributes(labels: &[LabelPair]) -> Value {
+        labels
+            .iter()
+            .map(|l| json!({"key": l.get_name(), "value": {"stringValue": l.get_value()}}))
+            .collect()
+    }
+
+    /// Converts a metric family to an OTLP metric
+    fn metric(family: &MetricFamily, time: &str) -> Option<Value> {
+        let points = family.get_metric().iter();
+        let data = match family.get_field_type() {
+            MetricType::COUNTER => json!({"sum": {
+                "aggregationTemporality": 2,
+                "isMonotonic": true,
+                "dataPoints": points.map(|m| json!({
+                    "attributes": Self::attributes(m.get_label()),
+                    "timeUnixNano": time,
+                    "asDouble": m.get_counter().get_value(),
+                })).collect::<Vec<_>>(),
+            }}),
+            MetricType::GAUGE => json!({"gauge": {
+                "dataPoints": points.map(|m| json!({
+                    "attributes": Self::attributes(m.get_label()),
+                    "timeUnixNano": time,
+                    "asDouble": m.get_gauge().get_value(),
+                })).collect::<Vec<_>>(),
+            }}),
+            MetricType::HISTOGRAM => json!({"histogram": {
+                "aggregationTemporality": 2,
+                "dataPoints": points.map(|m| {
+                    let histogram = m.get_histogram();
+                    let mut previous = 0;
+                    let mut counts = Vec::new();
+                    let mut bounds = Vec::new();
+                    for bucket in histogram.get_bucket() {
+                        counts.push((bucket.get_cumulative_count() - previous).to_string());
+                        bounds.push(bucket.get_upper_bound());
+                        previous = bucket.get_cumulative_count();
+                    }
+                    counts.push((histogram.get_sample_count() - previous).to_string());
+                    json!({
+                        "attributes": Self::attributes(m.get_label()),
+                        "timeUnixNano": time,
+                        "count": histogram.get_sample_count().to_string(),
+                        "sum": histogram.get_sample_sum(),
+                        "bucketCounts": counts,
+                        "explicitBounds": bounds,
+                    })
+                }).collect::<Vec<_>>(),
+            }}),
+            _ => return None,
+        };
+        let mut metric = json!({
+            "name": family.get_name(),
+            "description": family.get_help(),
+        });
+        metric.as_object_mut()?.extend(data.as_object()?.clone());
+        Some(metric)
+    }
+}
+
+#[tonic::async_trait]
+impl Exporter for OtlpExporter {
+    fn name(&self) -> &'static str {
+        "otlp"
+    }
+
+    async fn export(&self, families: &[MetricFamily]) -> Result<(), String> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+            .to_string();
+        let metrics: Vec<Value> = families
+            .iter()
+            .filter_map(|family| Self::metric(family, &time))
+            .collect();
+        let body = json!({"resourceMetrics": [{
+            "resource": {"attributes": [
+                {"key": "service.name", "value": {"stringValue": "raft-match"}},
+                {"key": "service.instance.id", "value": {"stringValue": self.node_id.to_string()}},
+            ]},
+            "scopeMetrics": [{
+                "scope": {"name": "raft-match"},
+                "metrics": metrics,
+            }],
+        }]});
+        send(
+            Method::POST,
+            &self.url,
+            "application/json",
+            body.to_string().into_bytes(),
+        )
+        .await
+    }
+}
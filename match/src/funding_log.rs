@@ -0,0 +1,150 @@
+//! Funding audit journal
+//!
+//! With `funding_audit_path` set, every deposit, withdrawal and adjustment applied
+//! by this node is appended to that file as a length-delimited `FundingEvent`,
+//! including rejected ones and resubmissions of a used idempotency key. Like the
+//! settlement journal, events are written and flushed on the raft loop after each
+//! apply batch and never dropped; events written again after a restart replays
+//! the log carry the same raft index.
+
+use crate::config;
+use crate::engine::funding::{FundingKind, FundingOutcome};
+use crate::engine::matchengine::FundingRecord;
+use crate::match_service::pb;
+use once_cell::sync::OnceCell;
+use prost::Message;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+
+/// Journal file, None if the journal is disabled
+static JOURNAL: OnceCell<Option<Mutex<BufWriter<File>>>> = OnceCell::new();
+
+/// Opens the funding audit journal if `funding_audit_path` is configured
+///
+/// Must be called before the raft loop starts applying entries.
+///
+/// # Returns
+///
+/// Returns an error if the journal file cannot be opened
+pub fn open() -> Result<(), String> {
+    let path = config::instance()
+        .lock()
+        .unwrap()
+        .funding_audit_path
+        .clone();
+    let journal = match path {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| format!("cannot open funding audit journal {}: {}", path, e))?;
+            log::info!("writing funding audit events to {}", path);
+            Some(Mutex::new(BufWriter::new(file)))
+        }
+        None => None,
+    };
+    let _ = JOURNAL.set(journal);
+    Ok(())
+}
+
+/// Appends funding events to the journal and flushes it
+///
+/// # Arguments
+///
+/// * `records` - Funding commands of an apply batch, in apply order
+pub fn write(records: &[FundingRecord]) {
+    let journal = match JOURNAL.get() {
+        Some(Some(journal)) if !records.is_empty() => journal,
+        _ => return,
+    };
+    let mut file = journal.lock().unwrap();
+    if let Err(e) = write_events(&mut *file, records) {
+        log::error!(
+            "cannot write {} funding events to the journal: {}",
+            records.len(),
+            e
+        );
+    }
+}
+
+/// Encodes funding records as length-delimited events and flushes the writer
+fn write_events<W: Write>(file: &mut W, records: &[FundingRecord]) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    for record in records {
+        buf.clear();
+        event(record)
+            .encode_length_delimited(&mut buf)
+            .expect("encode funding event");
+        file.write_all(&buf)?;
+    }
+    file.flush()
+}
+
+/// Converts a funding record to its journal event
+pub(crate) fn event(record: &FundingRecord) -> pb::FundingEvent {
+    let (outcome, error, duplicate_of) = match &record.outcome {
+        FundingOutcome::Applied => (pb::FundingOutcome::Applied, String::new(), 0),
+        FundingOutcome::Rejected(e) => (pb::FundingOutcome::Rejected, e.clone(), 0),
+        FundingOutcome::Duplicate(index) => (pb::FundingOutcome::Duplicate, String::new(), *index),
+    };
+    pb::FundingEvent {
+        index: record.index,
+        tenant: record.tenant.clone(),
+        kind: match record.kind {
+            FundingKind::Deposit => pb::FundingKind::Deposit,
+            FundingKind::Withdrawal => pb::FundingKind::Withdrawal,
+            FundingKind::Adjustment => pb::FundingKind::Adjustment,
+        } as i32,
+        account_id: record.account_id,
+        currency: record.currency.clone(),
+        amount: record.amount.to_string(),
+        idempotency_key: record.idempotency_key.clone(),
+        reason: record.reason.clone(),
+        request_id: record.request_id.clone(),
+        client_id: record.client_id.clone(),
+        proposed_at: record.proposed_at,
+        outcome: outcome as i32,
+        error,
+        duplicate_of,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn duplicates_name_the_index_that_applied_the_key() {
+        let records = [
+            FundingRecord {
+                index: 4,
+                kind: FundingKind::Adjustment,
+                amount: dec!(-12.5),
+                idempotency_key: "adj-7".to_string(),
+                outcome: FundingOutcome::Rejected("Insufficient USDT".to_string()),
+                ..Default::default()
+            },
+            FundingRecord {
+                index: 9,
+                idempotency_key: "adj-7".to_string(),
+                outcome: FundingOutcome::Duplicate(4),
+                ..Default::default()
+            },
+        ];
+        let mut file = Vec::new();
+        write_events(&mut file, &records).unwrap();
+
+        let mut buf = file.as_slice();
+        let rejected = pb::FundingEvent::decode_length_delimited(&mut buf).unwrap();
+        assert_eq!(rejected.kind(), pb::FundingKind::Adjustment);
+        assert_eq!(rejected.amount, "-12.5");
+        assert_eq!(rejected.error, "Insufficient USDT");
+        let duplicate = pb::FundingEvent::decode_length_delimited(&mut buf).unwrap();
+        assert_eq!(duplicate.outcome(), pb::FundingOutcome::Duplicate);
+        assert_eq!(duplicate.duplicate_of, 4);
+        assert!(buf.is_empty());
+    }
+}
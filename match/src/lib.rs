@@ -0,0 +1,51 @@
+//! Raft match service library
+//!
+//! Matching engine, raft replication and the gRPC and metrics servers of the match
+//! service. The `match` binary only loads the configuration and runs the server;
+//! the library is also linked by the fuzz targets in `fuzz/`.
+
+pub mod admin_service;
+pub mod affinity;
+pub mod allocator;
+pub mod audit;
+pub mod audit_trail;
+pub mod binary_gateway;
+pub mod change_feed;
+pub mod cluster_events;
+pub mod cluster_status;
+pub mod config;
+pub mod dead_letter;
+pub mod degraded;
+pub mod divergence;
+pub mod drop_copy;
+pub mod engine;
+pub mod eod_export;
+pub mod exporter;
+pub mod funding_log;
+pub mod market_data;
+pub mod match_service;
+pub mod memory;
+pub mod metrics;
+pub mod metrics_endpoint;
+pub mod multicast;
+pub mod order_session;
+pub mod process_metrics;
+pub mod projection;
+pub mod query_store;
+pub mod raft;
+pub mod raft_client;
+pub mod raft_service;
+pub mod read_view;
+pub mod readiness;
+pub mod recorder;
+pub mod server;
+pub mod settlement_log;
+pub mod slow_log;
+pub mod snapshot_progress;
+pub mod staleness;
+pub mod state_failure;
+pub mod state_match;
+pub mod symbol_stats;
+pub mod ticker;
+pub mod version;
+pub mod webhook;
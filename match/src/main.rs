@@ -1,20 +1,16 @@
 //! Main entry point for the Raft match service
 //!
 //! This module initializes the service, handles configuration, and manages the server lifecycle.
-
-mod config;
-mod engine;
-mod match_service;
-mod metrics;
-mod raft;
-mod raft_client;
-mod raft_service;
-mod server;
-mod state_match;
+//! The service itself lives in the `raft_match` library.
 
 use clap::Parser;
+use raft_match::{affinity, allocator, config, server};
 use tokio::signal;
 
+/// Global allocator keeping the allocation counters exported as metrics
+#[global_allocator]
+static GLOBAL: allocator::CountingAllocator = allocator::CountingAllocator;
+
 /// Handles graceful shutdown signals
 ///
 /// This function listens for Ctrl+C and SIGTERM signals on Unix systems,
@@ -53,6 +49,9 @@ struct Args {
     /// Whether to run in staging mode
     #[arg(short = 's', long = "stage", default_value_t = false)]
     stage: bool,
+    /// Runs a one-node cluster on a temporary directory, the config file is optional
+    #[arg(long = "single", default_value_t = false)]
+    single: bool,
 }
 
 /// Main entry point of the application
@@ -60,15 +59,46 @@ struct Args {
 /// This function:
 /// 1. Initializes logging
 /// 2. Parses command line arguments
-/// 3. Loads configuration
-/// 4. Starts the server
-/// 5. Waits for shutdown signal
-/// 6. Stops the server gracefully
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// 3. Loads configuration, turned into a one-node cluster with `--single`
+/// 4. Builds the gRPC runtime as laid out by `runtime_layout`
+/// 5. Runs the server on it until a shutdown signal
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::try_init().unwrap_or_default();
     let args = Args::parse();
-    config::RuntimeConfig::from_toml(&args.config).expect("Config is missing");
+    if !args.single || std::path::Path::new(&args.config).exists() {
+        config::RuntimeConfig::from_toml(&args.config).expect("Config is missing");
+    }
+    // Removed again on shutdown, a single node starts from scratch every run
+    let single_dir = if args.single {
+        let dir = tempfile::Builder::new()
+            .prefix("raft-match-single-")
+            .tempdir()?;
+        config::instance()
+            .lock()
+            .unwrap()
+            .make_single(&dir.path().to_string_lossy());
+        log::info!("running a single node on {}", dir.path().display());
+        Some(dir)
+    } else {
+        None
+    };
+    let layout = config::instance().lock().unwrap().runtime_layout.clone();
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = layout.grpc_threads {
+        runtime.worker_threads(threads);
+    }
+    if !layout.grpc_cores.is_empty() {
+        let cores = layout.grpc_cores.clone();
+        runtime.on_thread_start(move || affinity::pin_or_warn("grpc", &cores));
+    }
+    runtime.build()?.block_on(run());
+    drop(single_dir);
+    Ok(())
+}
+
+/// Starts the server, waits for a shutdown signal and stops the server gracefully
+async fn run() {
     {
         server::instance().lock().await.start().await;
     }
@@ -76,5 +106,4 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     {
         server::instance().lock().await.stop();
     }
-    Ok(())
 }
@@ -0,0 +1,442 @@
+//! Depth queries and streams
+//!
+//! `GetDepth` answers with the best price levels of a book and `SubscribeDepth`
+//! streams them whenever an apply batch changed one of the subscribed books, each
+//! message the full depth as of a raft index. Both come in two modes: L2
+//! aggregates each level into its visible quantity and order count, which is
+//! all most clients need; L3 also lists every resting order of the level in time
+//! priority with its queue position, so market makers can estimate where their
+//! own orders stand.
+//!
+//! Order IDs are anonymized in L3: each is replaced with the first 8 bytes, in
+//! hex, of an HMAC-SHA256 of tenant, symbol and order ID keyed with
+//! `depth_order_id_key`. The ID is stable while the order rests and the same on
+//! every node sharing the key, but cannot be traced back to the order without
+//! the key, so it should be set to a secret.
+//!
+//! Mode and number of levels are chosen per subscription. A subscription starts
+//! with the depth of each of its symbols; a removed symbol is sent with no levels
+//! and index 0. Depth is built on a task of its own from the published book views
+//! (see `read_view`), once per book, mode and number of levels per batch.
+//! Subscribers that fall `channels.depth` updates behind are disconnected rather
+//! than missing updates, and resubscribe to get the current depth again.
+
+use crate::engine::data::OrderBook;
+use crate::engine::entry::Order;
+use crate::engine::history::{OrderChange, OrderEvent};
+use crate::match_service::pb::{Depth, DepthLevel, DepthMode, DepthOrder};
+use crate::{config, metrics, read_view};
+use hmac::{Hmac, Mac};
+use once_cell::sync::OnceCell;
+use rust_decimal::Decimal;
+use sha2::Sha256;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender, UnboundedSender};
+
+/// Price levels per side when a request asks for none
+pub const DEFAULT_LEVELS: usize = 20;
+
+/// Most price levels per side a request may ask for
+pub const MAX_LEVELS: usize = 1000;
+
+/// Queue of the publisher task, unset until started
+static FEED: OnceCell<UnboundedSender<Update>> = OnceCell::new();
+
+/// Number of connected subscribers, batches are not fed while there are none
+static SUBSCRIBERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Stream of depth updates sent to one subscriber
+pub type DepthStream = Receiver<Result<Depth, tonic::Status>>;
+
+/// Client subscribed to the depth of some books
+#[derive(Debug)]
+struct Subscriber {
+    /// Tenant the client is scoped to
+    tenant: String,
+    /// Symbols the client gets the depth of
+    symbols: BTreeSet<String>,
+    /// Price levels per side
+    levels: usize,
+    /// L2 or L3
+    mode: DepthMode,
+    /// Queue of the updates not yet streamed to the client
+    sender: Sender<Result<Depth, tonic::Status>>,
+}
+
+/// Work of the publisher task, in apply order
+#[derive(Debug)]
+enum Update {
+    /// Tenant and symbol of the books changed by an apply batch
+    Books(BTreeSet<(String, String)>),
+    /// A new subscriber, sent the depth of its symbols first
+    Subscribe(Subscriber),
+}
+
+/// Anonymizes the ID of a resting order
+///
+/// # Arguments
+///
+/// * `key` - Key of the HMAC, `depth_order_id_key`
+/// * `tenant` - Tenant the symbol belongs to
+/// * `symbol` - Symbol the order rests on
+/// * `order_id` - ID of the order
+///
+/// # Returns
+///
+/// The first 8 bytes of the HMAC in hex
+fn anonymize(key: &[u8], tenant: &str, symbol: &str, order_id: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    for part in [tenant, symbol, order_id] {
+        mac.update(part.as_bytes());
+        mac.update(&[0]);
+    }
+    hex::encode(&mac.finalize().into_bytes()[..8])
+}
+
+/// Builds the depth of a book
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the symbol belongs to
+/// * `symbol` - ID of the symbol
+/// * `book` - Raft index and order book, None if the symbol is not listed
+/// * `levels` - Price levels per side
+/// * `mode` - L2 or L3
+/// * `key` - Key order IDs are anonymized with in L3
+fn build(
+    tenant: &str,
+    symbol: &str,
+    book: Option<(u64, &OrderBook)>,
+    levels: usize,
+    mode: DepthMode,
+    key: &[u8],
+) -> Depth {
+    let mut depth = Depth {
+        symbol: symbol.to_string(),
+        mode: mode as i32,
+        ..Default::default()
+    };
+    let Some((index, book)) = book else {
+        return depth;
+    };
+    let level = |(price, orders): (Decimal, &[Order])| {
+        let quantity: Decimal = orders.iter().map(Order::visible_quantity).sum();
+        let order_count = orders.len() as u32;
+        let orders = match mode {
+            DepthMode::L2 => Vec::new(),
+            DepthMode::L3 => orders
+                .iter()
+                .enumerate()
+                .map(|(position, order)| DepthOrder {
+                    order_id: anonymize(key, tenant, symbol, &order.id),
+                    quantity: order.visible_quantity().to_string(),
+                    queue_position: position as u32 + 1,
+                })
+                .collect(),
+        };
+        DepthLevel {
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+            order_count,
+            orders,
+        }
+    };
+    let (bids, asks) = book.level_orders(levels);
+    depth.index = index;
+    depth.bids = bids.into_iter().map(level).collect();
+    depth.asks = asks.into_iter().map(level).collect();
+    depth
+}
+
+/// Returns the number of levels per side to serve for a request
+///
+/// # Arguments
+///
+/// * `requested` - Levels asked for, 0 for the default
+///
+/// # Returns
+///
+/// Returns the levels, or why the request asks for too many
+pub fn levels(requested: u32) -> Result<usize, String> {
+    match requested as usize {
+        0 => Ok(DEFAULT_LEVELS),
+        levels if levels <= MAX_LEVELS => Ok(levels),
+        levels => Err(format!(
+            "{} levels asked for, at most {} are served",
+            levels, MAX_LEVELS
+        )),
+    }
+}
+
+/// Returns the depth of a book
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the symbol belongs to
+/// * `symbol` - ID of the symbol
+/// * `levels` - Price levels per side
+/// * `mode` - L2 or L3
+///
+/// # Returns
+///
+/// The depth as of the index the book was last published at, None if the
+/// symbol is not listed
+pub async fn get(tenant: &str, symbol: &str, levels: usize, mode: DepthMode) -> Option<Depth> {
+    let view = read_view::book(tenant, symbol).await?;
+    let key = config::instance()
+        .lock()
+        .unwrap()
+        .depth_order_id_key
+        .clone();
+    let book = Some((view.index, view.orderbook()));
+    Some(build(tenant, symbol, book, levels, mode, key.as_bytes()))
+}
+
+/// Subscribes a client to the depth of some books
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the client is scoped to
+/// * `symbols` - Symbols to get the depth of
+/// * `levels` - Price levels per side
+/// * `mode` - L2 or L3
+///
+/// # Returns
+///
+/// Returns the updates, starting with the current depth of each symbol, or why
+/// the subscription is refused
+pub fn subscribe(
+    tenant: &str,
+    symbols: &[String],
+    levels: usize,
+    mode: DepthMode,
+) -> Result<DepthStream, String> {
+    if symbols.is_empty() {
+        return Err("no symbol to subscribe to".to_string());
+    }
+    let Some(feed) = FEED.get() else {
+        return Err("depth feed is not running".to_string());
+    };
+    let capacity = config::instance().lock().unwrap().channels.depth;
+    let (sender, receiver) = mpsc::channel(capacity.max(symbols.len()));
+    let subscriber = Subscriber {
+        tenant: tenant.to_string(),
+        symbols: symbols.iter().cloned().collect(),
+        levels,
+        mode,
+        sender,
+    };
+    SUBSCRIBERS.fetch_add(1, Ordering::AcqRel);
+    feed.send(Update::Subscribe(subscriber))
+        .map_err(|_| "depth feed is not running".to_string())?;
+    Ok(receiver)
+}
+
+/// Starts the task sending depth updates to the subscribers
+///
+/// Must be called on the runtime before the raft loop starts applying entries.
+pub fn start() {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    if FEED.set(sender).is_err() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut subscribers = Vec::new();
+        while let Some(update) = receiver.recv().await {
+            publish(&mut subscribers, update).await;
+            SUBSCRIBERS.store(subscribers.len(), Ordering::Release);
+            metrics::DEPTH_SUBSCRIBERS_GAUGE.set(subscribers.len() as i64);
+        }
+    });
+}
+
+/// Feeds the books changed by an apply batch to the subscribers
+///
+/// # Arguments
+///
+/// * `events` - Order events of the batch, in log order
+pub fn write(events: &[OrderEvent]) {
+    let Some(feed) = FEED.get() else {
+        return;
+    };
+    if events.is_empty() || SUBSCRIBERS.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    let books = events
+        .iter()
+        .map(|event| {
+            let symbol = match &event.change {
+                OrderChange::Placed { order, .. } => &order.symbol,
+                OrderChange::Canceled { symbol, .. }
+                | OrderChange::SymbolRemoved { symbol }
+                | OrderChange::SymbolListed { symbol }
+                | OrderChange::SymbolHalted { symbol, .. }
+                | OrderChange::SymbolResumed { symbol } => symbol,
+            };
+            (event.tenant.clone(), symbol.clone())
+        })
+        .collect();
+    let _ = feed.send(Update::Books(books));
+}
+
+/// Sends the depth an update calls for
+///
+/// # Arguments
+///
+/// * `subscribers` - Connected subscribers, those gone or too far behind are removed
+/// * `update` - Changed books or a new subscriber
+async fn publish(subscribers: &mut Vec<Subscriber>, update: Update) {
+    let key = config::instance()
+        .lock()
+        .unwrap()
+        .depth_order_id_key
+        .clone();
+    match update {
+        Update::Subscribe(subscriber) => {
+            let mut depths = Vec::new();
+            for symbol in &subscriber.symbols {
+                let view = read_view::book(&subscriber.tenant, symbol).await;
+                let book = view.as_ref().map(|view| (view.index, view.orderbook()));
+                let (levels, mode) = (subscriber.levels, subscriber.mode);
+                depths.push(build(
+                    &subscriber.tenant,
+                    symbol,
+                    book,
+                    levels,
+                    mode,
+                    key.as_bytes(),
+                ));
+            }
+            if send(&subscriber, depths) {
+                subscribers.push(subscriber);
+            }
+        }
+        Update::Books(books) => {
+            let mut outbox: Vec<Vec<Depth>> = subscribers.iter().map(|_| Vec::new()).collect();
+            for (tenant, symbol) in &books {
+                let watching = |subscriber: &Subscriber| {
+                    subscriber.tenant == *tenant && subscriber.symbols.contains(symbol)
+                };
+                if !subscribers.iter().any(&watching) {
+                    continue;
+                }
+                let view = read_view::book(tenant, symbol).await;
+                let book = view.as_ref().map(|view| (view.index, view.orderbook()));
+                let mut built: BTreeMap<(i32, usize), Depth> = BTreeMap::new();
+                for (subscriber, queued) in subscribers.iter().zip(outbox.iter_mut()) {
+                    if !watching(subscriber) {
+                        continue;
+                    }
+                    let (levels, mode) = (subscriber.levels, subscriber.mode);
+                    let depth = built.entry((mode as i32, levels)).or_insert_with(|| {
+                        build(tenant, symbol, book, levels, mode, key.as_bytes())
+                    });
+                    queued.push(depth.clone());
+                }
+            }
+            let mut outbox = outbox.into_iter();
+            subscribers.retain(|subscriber| {
+                let depths = outbox.next().unwrap_or_default();
+                send(subscriber, depths)
+            });
+        }
+    }
+}
+
+/// Queues depth updates for a subscriber
+///
+/// # Arguments
+///
+/// * `subscriber` - Client to send the updates to
+/// * `depths` - Updates to send
+///
+/// # Returns
+///
+/// Whether the subscriber is still connected and keeping up
+fn send(subscriber: &Subscriber, depths: Vec<Depth>) -> bool {
+    for depth in depths {
+        match subscriber.sender.try_send(Ok(depth)) {
+            Ok(()) => {}
+            Err(TrySendError::Closed(_)) => return false,
+            Err(TrySendError::Full(_)) => {
+                log::warn!(
+                    "disconnect depth subscriber of tenant {} falling behind",
+                    subscriber.tenant
+                );
+                metrics::CHANNEL_DROPPED_COUNTER_VEC
+                    .with_label_values(&["depth"])
+                    .inc();
+                return false;
+            }
+        }
+    }
+    !subscriber.sender.is_closed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::entry::{OrderSide, OrderType};
+    use rust_decimal_macros::dec;
+
+    fn order(id: &str, side: OrderSide, price: Decimal, quantity: Decimal) -> Order {
+        Order {
+            id: id.to_string(),
+            symbol: "BTCUSDT".to_string(),
+            order_type: OrderType::Limit,
+            side,
+            price,
+            quantity,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn l3_lists_anonymized_orders_in_queue_order_under_the_l2_levels() {
+        let mut book = OrderBook::new("BTCUSDT".to_string());
+        book.add_order(order("1", OrderSide::Buy, dec!(99), dec!(1)));
+        book.add_order(order("2", OrderSide::Buy, dec!(100), dec!(2)));
+        book.add_order(order("3", OrderSide::Buy, dec!(100), dec!(3)));
+        book.add_order(order("4", OrderSide::Sell, dec!(101), dec!(4)));
+
+        let l2 = build("t", "BTCUSDT", Some((7, &book)), 1, DepthMode::L2, b"k");
+        assert_eq!(l2.index, 7);
+        assert_eq!(l2.bids.len(), 1);
+        assert_eq!(l2.bids[0].price, "100");
+        assert_eq!(l2.bids[0].quantity, "5");
+        assert_eq!(l2.bids[0].order_count, 2);
+        assert!(l2.bids[0].orders.is_empty());
+        assert_eq!(l2.asks[0].quantity, "4");
+
+        let l3 = build("t", "BTCUSDT", Some((7, &book)), 1, DepthMode::L3, b"k");
+        let queue: Vec<_> = l3.bids[0]
+            .orders
+            .iter()
+            .map(|order| {
+                (
+                    order.order_id.clone(),
+                    order.quantity.as_str(),
+                    order.queue_position,
+                )
+            })
+            .collect();
+        let id = |order_id| anonymize(b"k", "t", "BTCUSDT", order_id);
+        assert_eq!(queue, [(id("2"), "2", 1), (id("3"), "3", 2)]);
+        assert_eq!(queue[0].0.len(), 16);
+        assert_ne!(queue[0].0, "2");
+        // The same order on another key or tenant cannot be matched up
+        assert_ne!(anonymize(b"other", "t", "BTCUSDT", "2"), id("2"));
+        assert_ne!(anonymize(b"k", "u", "BTCUSDT", "2"), id("2"));
+
+        let removed = build("t", "BTCUSDT", None, 1, DepthMode::L3, b"k");
+        assert_eq!((removed.index, removed.bids.len()), (0, 0));
+    }
+
+    #[test]
+    fn levels_default_and_are_bounded() {
+        assert_eq!(levels(0), Ok(DEFAULT_LEVELS));
+        assert_eq!(levels(5), Ok(5));
+        assert!(levels(MAX_LEVELS as u32 + 1).is_err());
+    }
+}
@@ -2,21 +2,50 @@
 //!
 //! This module implements the gRPC service for order matching operations.
 
+use std::collections::{BTreeMap, HashSet};
 use std::str::FromStr;
+use std::time::Duration;
 
+use once_cell::sync::OnceCell;
 use pb::match_service_server::MatchService;
+use pb::recorded_request::Request as Recorded;
 use pb::{
-    CancelOrderRequest, CancelOrderResponse, CreateSymbolRequest, CreateSymbolResponse,
-    PlaceOrderRequest, PlaceOrderResponse, QueryOrderRequest, QueryOrderResponse,
-    RemoveSymbolRequest, RemoveSymbolResponse,
+    AdjustBalanceRequest, AdjustBalanceResponse, AmendOrderRequest, AmendOrderResponse,
+    CancelAllOrdersRequest, CancelAllOrdersResponse, CancelOrderRequest, CancelOrderResponse,
+    CreateSymbolRequest, CreateSymbolResponse, DepositRequest, DepositResponse,
+    ExportAuditTrailRequest, ExportEndOfDayRequest, ExportEndOfDayResponse, GetBalancesRequest,
+    GetBalancesResponse, GetDepthRequest, GetDepthResponse, GetOrderHistoryRequest,
+    GetOrderHistoryResponse, GetPositionsRequest, GetPositionsResponse, GetReadinessRequest,
+    GetReadinessResponse, GetSymbolStatsRequest, GetSymbolStatsResponse, GetTradesRequest,
+    GetTradesResponse, LiquidateRequest, LiquidateResponse, ListDeadLettersRequest,
+    ListDeadLettersResponse, OrderSessionRequest, PlaceOrderRequest, PlaceOrderResponse,
+    PlaceOrdersRequest, PlaceOrdersResponse, QueryOrderRequest, QueryOrderResponse,
+    ReadConsistency, RemoveSymbolRequest, RemoveSymbolResponse, ReplaceOrderRequest,
+    ReplaceOrderResponse, ReplayDeadLettersRequest, ReplayDeadLettersResponse, SetMarkPriceRequest,
+    SetMarkPriceResponse, SetRiskLimitsRequest, SetRiskLimitsResponse, SubscribeChangesRequest,
+    SubscribeClusterEventsRequest, SubscribeDepthRequest, SubscribeDropCopyRequest,
+    UpdateSymbolRequest, UpdateSymbolResponse, WithdrawRequest, WithdrawResponse,
 };
 use rust_decimal::Decimal;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
 
-use crate::engine::entry::Order;
+use crate::engine::entry::order::OrderStatus;
 use crate::engine::entry::Symbol;
-use crate::engine::matchengine::MatchCmd;
+use crate::engine::entry::{Order, OrderSide, OrderType, TimeInForce};
+use crate::engine::matchengine::{
+    CommandEnvelope, MarkPrice, MarketType, MatchCmd, MatchCmdType, RiskLimits, Transfer,
+};
+use crate::engine::{clock, codec};
+use crate::query_store::{self, OrderRecord, QueryStore, TradeRecord};
 use crate::raft::proposal::Proposal;
-use crate::server;
+use crate::slow_log::RequestTrace;
+use crate::{
+    audit_trail, change_feed, cluster_events, config, dead_letter, degraded, divergence, drop_copy,
+    eod_export, market_data, memory, metrics, order_session, read_view, readiness, recorder,
+    server, staleness, state_failure, symbol_stats, version,
+};
 
 /// Protocol buffer definitions for match service
 #[allow(clippy::module_inception)]
@@ -24,35 +53,922 @@ pub mod pb {
     tonic::include_proto!("r#match");
 }
 
+/// Metadata key carrying the tenant API key
+const API_KEY_HEADER: &str = "x-api-key";
+/// Metadata key carrying the key authorizing funding RPCs
+const ADMIN_KEY_HEADER: &str = "x-admin-key";
+/// Metadata key carrying the client supplied request ID, reused on retries
+const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Metadata key carrying the client ID
+const CLIENT_ID_HEADER: &str = "x-client-id";
+/// Metadata key carrying the ID of the leader on writes refused by a follower
+const LEADER_ID_HEADER: &str = "x-leader-id";
+/// Metadata key carrying the address of the leader on writes refused by a follower
+const LEADER_ADDR_HEADER: &str = "x-leader-addr";
+/// Metadata key carrying the gRPC deadline of the call
+const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+/// Number of orders or trades returned by history queries without a limit
+const DEFAULT_QUERY_LIMIT: usize = 100;
+/// Largest number of orders or trades a history query returns
+const MAX_QUERY_LIMIT: usize = 1000;
+/// Longest client order ID accepted, in bytes
+const MAX_CLIENT_ORDER_ID_LEN: usize = 64;
+/// Largest number of orders a `PlaceOrders` batch may carry
+const MAX_BATCH_ORDERS: usize = 100;
+/// Head start taken on the client deadline, so the handler answers with
+/// DEADLINE_EXCEEDED before the transport cancels the call on its own
+const DEADLINE_MARGIN: Duration = Duration::from_millis(5);
+
+/// Permits bounding the number of proposals waiting for commit
+static INFLIGHT_PROPOSALS: OnceCell<Option<Semaphore>> = OnceCell::new();
+
+/// Returns the in-flight proposal limiter, None if in-flight proposals are unlimited
+fn inflight_proposals() -> Option<&'static Semaphore> {
+    INFLIGHT_PROPOSALS
+        .get_or_init(|| {
+            config::instance()
+                .lock()
+                .unwrap()
+                .max_inflight_proposals
+                .map(Semaphore::new)
+        })
+        .as_ref()
+}
+
 /// Match service implementation
 #[derive(Debug, Default)]
 pub struct MatchServiceSVC {}
 
+/// Resolves the tenant a request is scoped to
+///
+/// The tenant is looked up from the API key in the request metadata and the
+/// per-tenant request counter is updated.
+///
+/// # Arguments
+///
+/// * `request` - Incoming request
+/// * `method` - Name of the RPC method, used as metric label
+///
+/// # Returns
+///
+/// Returns the tenant ID or an unauthenticated status if the API key is unknown
+fn resolve_tenant<T>(
+    request: &tonic::Request<T>,
+    method: &'static str,
+) -> Result<String, tonic::Status> {
+    let api_key = request
+        .metadata()
+        .get(API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok());
+    let tenant = config::instance()
+        .lock()
+        .unwrap()
+        .tenant_for_api_key(api_key)
+        .ok_or_else(|| tonic::Status::unauthenticated("invalid api key"))?;
+    metrics::TENANT_REQ_COUNTER_VEC
+        .with_label_values(&[&tenant, method])
+        .inc();
+    Ok(tenant)
+}
+
+/// Authorizes a deposit, withdrawal, adjustment or risk limit change, or a call
+/// of the admin service, see `admin_service`
+///
+/// With `admin_keys` configured the request must carry one of them as
+/// `x-admin-key` on top of the tenant API key.
+///
+/// # Arguments
+///
+/// * `request` - Incoming request
+///
+/// # Returns
+///
+/// Returns Ok if the request may move funds, or a permission denied status
+pub(crate) fn check_admin<T>(request: &tonic::Request<T>) -> Result<(), tonic::Status> {
+    let config = config::instance().lock().unwrap();
+    if config.admin_keys.is_empty() {
+        return Ok(());
+    }
+    let admin_key = request
+        .metadata()
+        .get(ADMIN_KEY_HEADER)
+        .and_then(|v| v.to_str().ok());
+    match admin_key {
+        Some(key) if config.admin_keys.iter().any(|k| k == key) => Ok(()),
+        _ => Err(tonic::Status::permission_denied("invalid admin key")),
+    }
+}
+
+/// Wraps a command into an envelope carrying the request metadata
+///
+/// Requests without an `x-request-id` get a fresh ID, so they are traceable but
+/// not protected against duplicate submission on retry.
+///
+/// # Arguments
+///
+/// * `request` - Incoming request
+/// * `account_id` - Account the command is made for, 0 if not account scoped
+/// * `cmd` - The command to wrap
+pub(crate) fn envelope<T>(
+    request: &tonic::Request<T>,
+    account_id: u64,
+    cmd: MatchCmd,
+) -> CommandEnvelope {
+    let metadata = request.metadata();
+    let request_id = metadata
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let client_id = metadata
+        .get(CLIENT_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    log::debug!("request {} from client {:?}", request_id, client_id);
+    stamped_envelope(request_id, client_id, account_id, cmd)
+}
+
+/// Wraps a command into an envelope stamped with the hybrid logical clock once
+/// the cluster supports it
+///
+/// # Arguments
+///
+/// * `request_id` - Unique ID of the request, reused on retries
+/// * `client_id` - ID of the client that sent the request
+/// * `account_id` - Account the command is made for, 0 if not account scoped
+/// * `cmd` - The command to wrap
+pub(crate) fn stamped_envelope(
+    request_id: String,
+    client_id: String,
+    account_id: u64,
+    cmd: MatchCmd,
+) -> CommandEnvelope {
+    let mut envelope = CommandEnvelope::new(request_id, client_id, account_id, cmd);
+    if version::cluster_supports(codec::FEATURE_HLC) {
+        envelope.hlc = clock::now().0;
+    }
+    envelope
+}
+
+/// Parses the fee rate of an order, an empty rate charges no fee
+///
+/// # Arguments
+///
+/// * `field` - Name of the field, used in the error message
+/// * `value` - Rate as a decimal string, e.g. `0.001` for 0.1%
+/// * `rebate` - Whether the rate may be negative, paying a rebate
+///
+/// # Returns
+///
+/// Returns the rate or an invalid argument status if it is not within [0, 1),
+/// or (-1, 1) for rebates
+fn parse_fee_rate(field: &str, value: &str, rebate: bool) -> Result<Decimal, tonic::Status> {
+    if value.is_empty() {
+        return Ok(Decimal::ZERO);
+    }
+    let min = if rebate { -Decimal::ONE } else { Decimal::ZERO };
+    match Decimal::from_str(value) {
+        Ok(rate) if rate.abs() < Decimal::ONE && rate >= min => Ok(rate),
+        _ => Err(tonic::Status::invalid_argument(format!(
+            "invalid {} {:?}",
+            field, value
+        ))),
+    }
+}
+
+/// Maps the market of a request to the market commands address
+pub(crate) fn market_type(market: pb::MarketType) -> MarketType {
+    match market {
+        pb::MarketType::Spot => MarketType::Spot,
+        pb::MarketType::Perp => MarketType::Perp,
+    }
+}
+
+/// Returns the query store orders and trades are read from
+///
+/// # Returns
+///
+/// Returns the store or a failed precondition status if it is disabled
+fn query_store() -> Result<&'static QueryStore, tonic::Status> {
+    query_store::instance().ok_or_else(|| {
+        tonic::Status::failed_precondition("order queries need query_store_path to be configured")
+    })
+}
+
+/// Clamps the limit of a history query, 0 asks for the default
+fn query_limit(limit: u32) -> usize {
+    match limit {
+        0 => DEFAULT_QUERY_LIMIT,
+        limit => (limit as usize).min(MAX_QUERY_LIMIT),
+    }
+}
+
+/// Converts an order status to the wire format
+fn order_status(status: OrderStatus) -> pb::OrderStatus {
+    match status {
+        OrderStatus::New => pb::OrderStatus::New,
+        OrderStatus::PartiallyFilled => pb::OrderStatus::PartiallyFilled,
+        OrderStatus::Filled => pb::OrderStatus::Filled,
+        OrderStatus::Canceled => pb::OrderStatus::Canceled,
+        OrderStatus::Rejected => pb::OrderStatus::Rejected,
+    }
+}
+
+/// Converts an order of the query store to the wire format
+pub(crate) fn order_state(record: OrderRecord) -> pb::OrderState {
+    let order = record.order;
+    let status = order_status(order.status);
+    pb::OrderState {
+        order: Some(pb::Order {
+            order_id: order.id.parse().unwrap_or_default(),
+            account_id: record.account_id,
+            order_side: match order.side {
+                OrderSide::Buy => pb::OrderSide::Buy,
+                OrderSide::Sell => pb::OrderSide::Sell,
+            } as i32,
+            order_type: match order.order_type {
+                OrderType::Limit => pb::OrderType::Limit,
+                OrderType::Market => pb::OrderType::Market,
+                OrderType::LimitMaker => pb::OrderType::LimitMaker,
+                OrderType::Stop => pb::OrderType::Stop,
+                OrderType::StopLimit => pb::OrderType::StopLimit,
+            } as i32,
+            symbol: order.symbol,
+            quantity: order.quantity.to_string(),
+            price: order.price.to_string(),
+            taker_fee: order.taker_fee.to_string(),
+            maker_fee: order.maker_fee.to_string(),
+            leverage: order.leverage.to_string(),
+            client_order_id: order.client_order_id,
+            time_in_force: match order.time_in_force {
+                TimeInForce::Gtc => pb::TimeInForce::Gtc,
+                TimeInForce::Ioc => pb::TimeInForce::Ioc,
+                TimeInForce::Fok => pb::TimeInForce::Fok,
+                TimeInForce::Gtd => pb::TimeInForce::Gtd,
+            } as i32,
+            stop_price: order.stop_price.to_string(),
+            display_quantity: order.display_quantity.to_string(),
+            trailing_offset: order.trailing_offset.to_string(),
+            expire_at: order.expire_at,
+            ..Default::default()
+        }),
+        filled_quantity: order.filled_quantity.to_string(),
+        status: status as i32,
+        created_at: order.created_at,
+        updated_at: order.updated_at,
+    }
+}
+
+/// Converts a trade of the query store to the wire format
+pub(crate) fn trade_record(record: TradeRecord) -> pb::TradeRecord {
+    pb::TradeRecord {
+        index: record.index,
+        trade_seq: record.trade_seq,
+        symbol: record.symbol,
+        taker_side: match record.taker_side {
+            OrderSide::Buy => pb::OrderSide::Buy,
+            OrderSide::Sell => pb::OrderSide::Sell,
+        } as i32,
+        price: record.price.to_string(),
+        quantity: record.quantity.to_string(),
+        buyer_order_id: record.buyer_order_id,
+        buyer_account_id: record.buyer_account_id,
+        seller_order_id: record.seller_order_id,
+        seller_account_id: record.seller_account_id,
+        match_time: record.time,
+    }
+}
+
+/// Parses an optional positive decimal of a request, empty values parse to zero
+///
+/// # Arguments
+///
+/// * `field` - Name of the field, used in the error message
+/// * `value` - Value as a decimal string
+///
+/// # Returns
+///
+/// Returns the value or an invalid argument status if it is not positive
+fn parse_positive(field: &str, value: &str) -> Result<Decimal, tonic::Status> {
+    if value.is_empty() {
+        return Ok(Decimal::ZERO);
+    }
+    match Decimal::from_str(value) {
+        Ok(value) if value > Decimal::ZERO => Ok(value),
+        _ => Err(tonic::Status::invalid_argument(format!(
+            "invalid {} {:?}",
+            field, value
+        ))),
+    }
+}
+
+/// Converts the order of a request to a match engine order
+///
+/// # Arguments
+///
+/// * `order` - Order of a place order or liquidate request
+///
+/// # Returns
+///
+/// Returns the order or an invalid argument status if a field is invalid
+pub(crate) fn match_order(order: &pb::Order) -> Result<Order, tonic::Status> {
+    let order_side = match order.order_side() {
+        crate::match_service::pb::OrderSide::Buy => crate::engine::entry::OrderSide::Buy,
+        crate::match_service::pb::OrderSide::Sell => crate::engine::entry::OrderSide::Sell,
+    };
+    let order_type = match order.order_type() {
+        crate::match_service::pb::OrderType::Limit => crate::engine::entry::OrderType::Limit,
+        crate::match_service::pb::OrderType::LimitMaker => {
+            crate::engine::entry::OrderType::LimitMaker
+        }
+        crate::match_service::pb::OrderType::Market => crate::engine::entry::OrderType::Market,
+        crate::match_service::pb::OrderType::Stop => crate::engine::entry::OrderType::Stop,
+        crate::match_service::pb::OrderType::StopLimit => {
+            crate::engine::entry::OrderType::StopLimit
+        }
+    };
+    let time_in_force = match order.time_in_force() {
+        pb::TimeInForce::Gtc => TimeInForce::Gtc,
+        pb::TimeInForce::Ioc => TimeInForce::Ioc,
+        pb::TimeInForce::Fok => TimeInForce::Fok,
+        pb::TimeInForce::Gtd => TimeInForce::Gtd,
+    };
+    let mut match_order = Order::new(
+        order.order_id.to_string(),
+        order.symbol.clone(),
+        order_type,
+        order_side,
+        order.price.clone(),
+        order.quantity.clone(),
+    );
+    match_order.taker_fee = parse_fee_rate("taker fee", &order.taker_fee, false)?;
+    match_order.maker_fee = parse_fee_rate("maker fee", &order.maker_fee, true)?;
+    match_order.leverage = parse_positive("leverage", &order.leverage)?;
+    match_order.time_in_force = time_in_force;
+    if (time_in_force == TimeInForce::Gtd) != (order.expire_at > 0) {
+        return Err(tonic::Status::invalid_argument(
+            "an expiry time needs the GTD time in force and GTD orders need one",
+        ));
+    }
+    match_order.expire_at = order.expire_at;
+    match_order.stop_price = parse_positive("stop price", &order.stop_price)?;
+    match_order.trailing_offset = parse_positive("trailing offset", &order.trailing_offset)?;
+    if !match_order.trailing_offset.is_zero() && !match_order.is_stop() {
+        return Err(tonic::Status::invalid_argument(
+            "only stop orders can trail",
+        ));
+    }
+    if match_order.is_stop() && !match_order.is_trailing() && match_order.stop_price.is_zero() {
+        return Err(tonic::Status::invalid_argument(
+            "stop orders need a stop price",
+        ));
+    }
+    match_order.display_quantity = parse_positive("display quantity", &order.display_quantity)?;
+    if order.client_order_id.len() > MAX_CLIENT_ORDER_ID_LEN {
+        return Err(tonic::Status::invalid_argument(format!(
+            "client order ID longer than {} bytes",
+            MAX_CLIENT_ORDER_ID_LEN
+        )));
+    }
+    match_order.client_order_id = order.client_order_id.clone();
+    if order.order_id == 0 && !order.client_order_id.is_empty() {
+        // The engine numbers the order with its raft index
+        match_order.id = String::new();
+    }
+    Ok(match_order)
+}
+
+/// Refuses a post-only order that would trade against the book as last applied
+///
+/// The engine checks again when the order is applied and rejects it if the book
+/// moved in between; this check answers the client at once in the common case.
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the order is placed for
+/// * `order` - The order about to be proposed
+///
+/// # Returns
+///
+/// Returns a failed precondition status if the order would take liquidity
+pub(crate) async fn check_post_only(tenant: &str, order: &Order) -> Result<(), tonic::Status> {
+    if order.order_type != OrderType::LimitMaker {
+        return Ok(());
+    }
+    match read_view::book(tenant, &order.symbol).await {
+        Some(book) if book.would_take(order) => Err(tonic::Status::failed_precondition(
+            "post-only order would take liquidity",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Builds a balance command from the fields of a funding request
+///
+/// # Arguments
+///
+/// * `cmd` - Deposit, withdrawal or adjustment
+/// * `tenant` - Tenant the command is scoped to
+/// * `currency` - Currency to move
+/// * `amount` - Amount to move as a decimal string, signed for adjustments
+/// * `idempotency_key` - Key of the movement, empty to use the request ID
+/// * `reason` - Reason kept in the audit trail, required for adjustments
+///
+/// # Returns
+///
+/// Returns the command or an invalid argument status if a field is invalid
+fn transfer_cmd(
+    cmd: MatchCmdType,
+    tenant: String,
+    currency: &str,
+    amount: &str,
+    idempotency_key: &str,
+    reason: &str,
+) -> Result<MatchCmd, tonic::Status> {
+    let amount =
+        Decimal::from_str(amount).map_err(|_| tonic::Status::invalid_argument("invalid amount"))?;
+    if currency.is_empty() {
+        return Err(tonic::Status::invalid_argument("currency must be set"));
+    }
+    if matches!(cmd, MatchCmdType::Adjust) {
+        if amount.is_zero() || reason.trim().is_empty() {
+            return Err(tonic::Status::invalid_argument(
+                "adjustments need a nonzero amount and a reason",
+            ));
+        }
+    } else if amount <= Decimal::ZERO {
+        return Err(tonic::Status::invalid_argument("amount must be positive"));
+    }
+    Ok(MatchCmd {
+        cmd,
+        tenant,
+        order: None,
+        symbol: None,
+        transfer: Some(Transfer {
+            currency: currency.to_string(),
+            amount,
+            idempotency_key: idempotency_key.to_string(),
+            reason: reason.to_string(),
+        }),
+        risk: None,
+        market: MarketType::Spot,
+        mark_price: None,
+        orders: Vec::new(),
+    })
+}
+
+/// Wraps a funding command into an envelope, keying it by request ID if the
+/// request named no idempotency key
+///
+/// # Arguments
+///
+/// * `request` - Incoming request
+/// * `account_id` - Account credited or debited
+/// * `cmd` - Funding command, see `transfer_cmd`
+fn funding_envelope<T>(
+    request: &tonic::Request<T>,
+    account_id: u64,
+    cmd: MatchCmd,
+) -> CommandEnvelope {
+    let mut envelope = envelope(request, account_id, cmd);
+    if let Some(transfer) = envelope.cmd.transfer.as_mut() {
+        if transfer.idempotency_key.is_empty() {
+            transfer.idempotency_key = envelope.request_id.clone();
+        }
+    }
+    envelope
+}
+
+/// Parses a `grpc-timeout` value as defined by the gRPC over HTTP2 spec
+///
+/// # Arguments
+///
+/// * `value` - Header value, at most 8 digits followed by a unit
+///
+/// # Returns
+///
+/// Returns the timeout or None if the value is malformed
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 || !value.is_ascii() {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 60 * 60)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+/// Computes the deadline of a request from its `grpc-timeout` metadata
+///
+/// # Arguments
+///
+/// * `request` - Incoming request
+///
+/// # Returns
+///
+/// Returns the deadline or None if the client did not set one
+pub(crate) fn request_deadline<T>(request: &tonic::Request<T>) -> Option<Instant> {
+    let timeout = request
+        .metadata()
+        .get(GRPC_TIMEOUT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_grpc_timeout)?;
+    Some(Instant::now() + timeout.saturating_sub(DEADLINE_MARGIN))
+}
+
+/// Refuses writes on a node that is not the leader
+///
+/// The raft loop drops proposals made on a follower, so they are refused
+/// upfront with UNAVAILABLE. If a leader is known, its ID and address are
+/// attached as `x-leader-id` and `x-leader-addr` so clients can redirect. A
+/// leader whose state machine failed refuses writes too until it has stepped
+/// down, see `state_failure`.
+///
+/// # Returns
+///
+/// Returns Ok on the leader, or the status to answer with
+pub(crate) fn check_leader() -> Result<(), tonic::Status> {
+    let leader_id = metrics::RAFT_LEADER_ID_GAUGE.get() as u64;
+    let config = config::instance().lock().unwrap();
+    if leader_id == config.id {
+        return match state_failure::failed_at() {
+            Some(index) => Err(state_failed(index)),
+            None => Ok(()),
+        };
+    }
+    if leader_id == 0 {
+        return Err(tonic::Status::unavailable("no leader"));
+    }
+    let mut status = tonic::Status::unavailable(format!("not leader, leader is {}", leader_id));
+    let metadata = status.metadata_mut();
+    metadata.insert(LEADER_ID_HEADER, leader_id.into());
+    if let Some(addr) = config
+        .node_list
+        .iter()
+        .find(|node| node.id == leader_id)
+        .and_then(|node| node.addr.parse().ok())
+    {
+        metadata.insert(LEADER_ADDR_HEADER, addr);
+    }
+    Err(status)
+}
+
+/// Refuses orders while the leader is degraded, see `degraded`
+///
+/// A follower leaves refusing writes to `check_leader`, its backlog does not
+/// delay commands.
+///
+/// # Returns
+///
+/// Returns Ok if orders are accepted, or UNAVAILABLE so clients retry later
+pub(crate) async fn check_degraded() -> Result<(), tonic::Status> {
+    if check_leader().is_err() {
+        return Ok(());
+    }
+    let sender = server::instance().lock().await.proposal_sender(false);
+    let queued = sender.max_capacity() - sender.capacity();
+    degraded::check_order(queued).map_err(tonic::Status::unavailable)
+}
+
+/// Status refusing calls on a node whose state machine failed
+///
+/// # Arguments
+///
+/// * `index` - Index of the entry applying panicked at
+fn state_failed(index: u64) -> tonic::Status {
+    tonic::Status::unavailable(format!("state machine failed at entry {}", index))
+}
+
+/// Checks whether this node may answer a read, waiting for it if needed
+///
+/// A node whose state diverged from the leader or whose state machine failed
+/// never answers. Local reads are
+/// answered by any other node and may lag behind the latest writes, by no more
+/// than `max_staleness_ms` if set, see `staleness`. Leader and linearizable reads
+/// are redirected like writes. A leader read is served on the leader's lease: it
+/// sees every acknowledged write unless the node lost its quorum less than an
+/// election timeout ago and has not noticed yet. A linearizable read first has a
+/// quorum confirm the leader's commit index (read index) and waits until it is
+/// applied, at the cost of a round trip to the followers.
+///
+/// # Arguments
+///
+/// * `consistency` - Consistency asked for by the client
+/// * `max_staleness_ms` - Largest staleness of a local read, 0 for any
+/// * `deadline` - Client deadline, None to wait without bound
+///
+/// # Returns
+///
+/// Returns Ok if the read may be answered here, or the status to answer with
+async fn check_read(
+    consistency: ReadConsistency,
+    max_staleness_ms: u64,
+    deadline: Option<Instant>,
+) -> Result<(), tonic::Status> {
+    if let Some(index) = state_failure::failed_at() {
+        return Err(state_failed(index));
+    }
+    if divergence::is_fenced() {
+        return Err(tonic::Status::unavailable(
+            "replica state diverged from the leader",
+        ));
+    }
+    match consistency {
+        ReadConsistency::Local => check_staleness(max_staleness_ms),
+        ReadConsistency::Leader => check_leader(),
+        ReadConsistency::Linearizable => {
+            check_leader()?;
+            read_index(deadline).await
+        }
+    }
+}
+
+/// Refuses a local read if this node lags further behind the leader than asked
+///
+/// The status names the leader like `check_leader` does, so clients can read
+/// from it instead.
+///
+/// # Arguments
+///
+/// * `max_staleness_ms` - Largest staleness accepted, 0 for any
+///
+/// # Returns
+///
+/// Returns Ok if the state is fresh enough, or UNAVAILABLE
+fn check_staleness(max_staleness_ms: u64) -> Result<(), tonic::Status> {
+    if max_staleness_ms == 0 {
+        return Ok(());
+    }
+    let message = match staleness::staleness() {
+        Some(staleness) if staleness <= Duration::from_millis(max_staleness_ms) => return Ok(()),
+        Some(staleness) => format!(
+            "replica is {} ms stale, more than {} ms",
+            staleness.as_millis(),
+            max_staleness_ms
+        ),
+        None => "replica never caught up with a leader".to_string(),
+    };
+    match check_leader() {
+        Ok(()) => Ok(()),
+        Err(status) => Err(tonic::Status::with_metadata(
+            tonic::Code::Unavailable,
+            message,
+            status.metadata().clone(),
+        )),
+    }
+}
+
+/// Waits until the leader may serve a linearizable read
+///
+/// # Arguments
+///
+/// * `deadline` - Client deadline, None to wait without bound
+///
+/// # Returns
+///
+/// Returns Ok once the commit index confirmed by a quorum is applied, or an
+/// error status if the deadline passed or the node stepped down first
+async fn read_index(deadline: Option<Instant>) -> Result<(), tonic::Status> {
+    let (proposal, rx) = Proposal::read_index();
+    let proposal = proposal.with_deadline(deadline);
+    let sender = server::instance().lock().await.proposal_sender(true);
+    let _ = sender.send(proposal).await;
+    let result = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, rx)
+            .await
+            .map_err(|_| tonic::Status::deadline_exceeded("deadline exceeded"))?,
+        None => rx.await,
+    };
+    match result {
+        Ok(true) => Ok(()),
+        _ if deadline.is_some_and(|d| d <= Instant::now()) => {
+            Err(tonic::Status::deadline_exceeded("deadline exceeded"))
+        }
+        _ => Err(tonic::Status::unavailable(
+            "leadership changed before the read was confirmed",
+        )),
+    }
+}
+
+/// Proposes a command through Raft and waits until it is applied
+///
+/// The wait is bounded by the client deadline. Once it expires the proposal is
+/// abandoned, wherever it is in the pipeline, and DEADLINE_EXCEEDED is returned.
+/// If the configured number of in-flight proposals is reached the command is
+/// rejected with RESOURCE_EXHAUSTED without being proposed, on a follower it is
+/// rejected with UNAVAILABLE, see `check_leader`.
+///
+/// # Arguments
+///
+/// * `envelope` - Command to propose
+/// * `deadline` - Client deadline, None to wait without bound
+/// * `priority` - Whether the command is committed ahead of regular proposals
+/// * `trace` - Latency trace of the request, see `slow_log`
+///
+/// # Returns
+///
+/// Returns Ok once the proposal is applied, or an error status; UNAVAILABLE if
+/// the entry was replaced by a new leader or never applied, the command may be
+/// retried with the same request ID
+pub(crate) async fn propose(
+    envelope: CommandEnvelope,
+    deadline: Option<Instant>,
+    priority: bool,
+    trace: &mut RequestTrace,
+) -> Result<(), tonic::Status> {
+    let symbol = match (&envelope.cmd.order, &envelope.cmd.symbol) {
+        (Some(order), _) => order.symbol.as_str(),
+        (None, Some(symbol)) => symbol.name.as_str(),
+        (None, None) => "",
+    };
+    trace.describe(&envelope.request_id, symbol, envelope.account_id);
+    if let Err(status) = check_leader() {
+        trace.fail(&status);
+        return Err(status);
+    }
+    if let Err(e) = version::check_proposable(&envelope) {
+        let status = tonic::Status::failed_precondition(e);
+        trace.fail(&status);
+        return Err(status);
+    }
+    let data = codec::encode(&envelope);
+    trace.mark("prepare");
+    let result = propose_data(data, deadline, priority, trace).await;
+    if let Err(status) = &result {
+        trace.fail(status);
+    }
+    result
+}
+
+/// Proposes encoded command data and waits until it is applied, see `propose`
+async fn propose_data(
+    data: Vec<u8>,
+    deadline: Option<Instant>,
+    priority: bool,
+    trace: &mut RequestTrace,
+) -> Result<(), tonic::Status> {
+    let _permit = match inflight_proposals() {
+        Some(limit) => Some(
+            limit
+                .try_acquire()
+                .map_err(|_| tonic::Status::resource_exhausted("too many in-flight proposals"))?,
+        ),
+        None => None,
+    };
+    let (proposal, rx) = Proposal::normal(data);
+    let proposal = proposal.with_deadline(deadline);
+    let sender = server::instance().lock().await.proposal_sender(priority);
+    let result = async {
+        let _ = sender.send(proposal).await;
+        trace.mark("enqueue");
+        rx.await
+    };
+    let result = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, result)
+            .await
+            .map_err(|_| tonic::Status::deadline_exceeded("deadline exceeded"))?,
+        None => result.await,
+    };
+    trace.mark("commit");
+    match result.map_err(|_| tonic::Status::internal("raft error"))? {
+        true => Ok(()),
+        false if deadline.is_some_and(|d| d <= Instant::now()) => {
+            Err(tonic::Status::deadline_exceeded("deadline exceeded"))
+        }
+        false => Err(tonic::Status::unavailable(
+            "proposal was dropped or replaced before it applied",
+        )),
+    }
+}
+
 #[tonic::async_trait]
 impl MatchService for MatchServiceSVC {
     /// Queries an order's status
     ///
+    /// Orders are read from the query store, see `check_read` for the
+    /// consistency options.
+    ///
     /// # Arguments
     ///
-    /// * `_request` - Query order request
+    /// * `request` - Query order request
     ///
     /// # Returns
     ///
-    /// Returns the order status or an error
+    /// Returns the order status, no order if it is unknown
     async fn query_order(
         &self,
-        _request: tonic::Request<QueryOrderRequest>,
+        request: tonic::Request<QueryOrderRequest>,
     ) -> Result<tonic::Response<QueryOrderResponse>, tonic::Status> {
-        todo!()
+        let tenant = resolve_tenant(&request, "query_order")?;
+        let query = request.get_ref();
+        check_read(
+            query.consistency(),
+            query.max_staleness_ms,
+            request_deadline(&request),
+        )
+        .await?;
+        let store = query_store()?;
+        let order = if query.client_order_id.is_empty() {
+            store.order(&tenant, &query.symbol, &query.order_id.to_string())
+        } else {
+            store.client_order(&tenant, query.account_id, &query.client_order_id)
+        }
+        .map_err(tonic::Status::internal)?;
+        Ok(tonic::Response::new(QueryOrderResponse {
+            ret: 0,
+            message: "ok".to_string(),
+            order: order.map(order_state),
+        }))
+    }
+
+    /// Returns the latest orders of an account, newest first
+    ///
+    /// Orders are read like in `query_order`.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Get order history request
+    ///
+    /// # Returns
+    ///
+    /// Returns the orders, empty if the account never placed one
+    async fn get_order_history(
+        &self,
+        request: tonic::Request<GetOrderHistoryRequest>,
+    ) -> Result<tonic::Response<GetOrderHistoryResponse>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "get_order_history")?;
+        let query = request.get_ref();
+        check_read(
+            query.consistency(),
+            query.max_staleness_ms,
+            request_deadline(&request),
+        )
+        .await?;
+        let orders = query_store()?
+            .account_orders(&tenant, query.account_id, query_limit(query.limit))
+            .map_err(tonic::Status::internal)?;
+        Ok(tonic::Response::new(GetOrderHistoryResponse {
+            ret: 0,
+            message: "ok".to_string(),
+            orders: orders.into_iter().map(order_state).collect(),
+        }))
+    }
+
+    /// Returns the latest trades of an account or a symbol, newest first
+    ///
+    /// Trades are read like orders in `query_order`.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Get trades request
+    ///
+    /// # Returns
+    ///
+    /// Returns the trades, empty if none were matched
+    async fn get_trades(
+        &self,
+        request: tonic::Request<GetTradesRequest>,
+    ) -> Result<tonic::Response<GetTradesResponse>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "get_trades")?;
+        let query = request.get_ref();
+        check_read(
+            query.consistency(),
+            query.max_staleness_ms,
+            request_deadline(&request),
+        )
+        .await?;
+        let store = query_store()?;
+        let limit = query_limit(query.limit);
+        let trades = if query.account_id != 0 {
+            store.account_trades(&tenant, query.account_id, limit)
+        } else {
+            store.symbol_trades(&tenant, &query.symbol, limit)
+        }
+        .map_err(tonic::Status::internal)?;
+        Ok(tonic::Response::new(GetTradesResponse {
+            ret: 0,
+            message: "ok".to_string(),
+            trades: trades.into_iter().map(trade_record).collect(),
+        }))
     }
 
     /// Places a new order
     ///
     /// This method:
     /// 1. Converts the request to a match engine order
-    /// 2. Creates a match command
-    /// 3. Proposes the command through Raft
-    /// 4. Waits for consensus
+    /// 2. Refuses it if a memory limit is reached, see `memory`, or while the
+    ///    leader is degraded, see `degraded`
+    /// 3. Creates a match command
+    /// 4. Proposes the command through Raft
+    /// 5. Waits for consensus
     ///
     /// # Arguments
     ///
@@ -66,45 +982,33 @@ impl MatchService for MatchServiceSVC {
         request: tonic::Request<PlaceOrderRequest>,
     ) -> Result<tonic::Response<PlaceOrderResponse>, tonic::Status> {
         log::info!("place order {:?}", request.get_ref());
+        let mut trace = RequestTrace::new("place_order");
+        let tenant = resolve_tenant(&request, "place_order")?;
+        recorder::record(&tenant, || Recorded::PlaceOrder(request.get_ref().clone()));
         if let Some(order) = &request.get_ref().order {
-            let order_side = match order.order_side() {
-                crate::match_service::pb::OrderSide::Buy => crate::engine::entry::OrderSide::Buy,
-                crate::match_service::pb::OrderSide::Sell => crate::engine::entry::OrderSide::Sell,
-            };
-            let order_type = match order.order_type() {
-                crate::match_service::pb::OrderType::Limit => {
-                    crate::engine::entry::OrderType::Limit
-                }
-                crate::match_service::pb::OrderType::LimitMaker => {
-                    crate::engine::entry::OrderType::Limit
-                }
-                crate::match_service::pb::OrderType::Market => {
-                    crate::engine::entry::OrderType::Market
-                }
-            };
-            let match_order = Order::new(
-                order.order_id.to_string(),
-                order.symbol.clone(),
-                order_type,
-                order_side,
-                order.price.clone(),
-                order.quantity.clone(),
-            );
+            let match_order = match_order(order)?;
+            memory::check_order(&tenant, &match_order.symbol)
+                .map_err(tonic::Status::resource_exhausted)?;
+            check_post_only(&tenant, &match_order).await?;
+            check_degraded().await?;
             let cmd = MatchCmd {
                 cmd: crate::engine::matchengine::MatchCmdType::PlaceOrder,
+                tenant,
                 order: Some(match_order),
                 symbol: None,
+                transfer: None,
+                risk: None,
+                market: market_type(order.market()),
+                mark_price: None,
+                orders: Vec::new(),
             };
-            let data =
-                bincode::serialize(&cmd).map_err(|_| tonic::Status::internal("serialize error"))?;
-            let (proposal, rx) = Proposal::normal(data.clone());
-            {
-                let mut server = server::instance().lock().await;
-                server.add_proposal(proposal).await;
-            }
-            let _ = rx
-                .await
-                .map_err(|_| tonic::Status::internal("raft error"))?;
+            propose(
+                envelope(&request, order.account_id, cmd),
+                request_deadline(&request),
+                false,
+                &mut trace,
+            )
+            .await?;
         };
 
         Ok(tonic::Response::new(PlaceOrderResponse {
@@ -113,6 +1017,84 @@ impl MatchService for MatchServiceSVC {
         }))
     }
 
+    /// Places a batch of orders with a single raft proposal
+    ///
+    /// This method:
+    /// 1. Validates every order and checks that they share one account and market
+    /// 2. Creates one batch command holding all of them
+    /// 3. Proposes the command through Raft
+    /// 4. Waits for consensus
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Place orders request
+    ///
+    /// # Returns
+    ///
+    /// Returns a response indicating success or failure
+    async fn place_orders(
+        &self,
+        request: tonic::Request<PlaceOrdersRequest>,
+    ) -> Result<tonic::Response<PlaceOrdersResponse>, tonic::Status> {
+        log::info!("place orders {:?}", request.get_ref());
+        let mut trace = RequestTrace::new("place_orders");
+        let tenant = resolve_tenant(&request, "place_orders")?;
+        recorder::record(&tenant, || Recorded::PlaceOrders(request.get_ref().clone()));
+        let orders = &request.get_ref().orders;
+        if orders.is_empty() || orders.len() > MAX_BATCH_ORDERS {
+            return Err(tonic::Status::invalid_argument(format!(
+                "a batch holds 1 to {} orders",
+                MAX_BATCH_ORDERS
+            )));
+        }
+        let (account_id, market) = (orders[0].account_id, orders[0].market());
+        let mut order_ids = HashSet::new();
+        let mut match_orders = Vec::with_capacity(orders.len());
+        for order in orders {
+            if order.account_id != account_id || order.market() != market {
+                return Err(tonic::Status::invalid_argument(
+                    "the orders of a batch must share one account and market",
+                ));
+            }
+            // The engine numbers an order without ID with the raft index, which
+            // the orders of a batch share
+            if order.order_id == 0 || !order_ids.insert(order.order_id) {
+                return Err(tonic::Status::invalid_argument(
+                    "the orders of a batch need distinct order IDs",
+                ));
+            }
+            let match_order = match_order(order)?;
+            memory::check_order(&tenant, &match_order.symbol)
+                .map_err(tonic::Status::resource_exhausted)?;
+            check_post_only(&tenant, &match_order).await?;
+            match_orders.push(match_order);
+        }
+        check_degraded().await?;
+        let cmd = MatchCmd {
+            cmd: crate::engine::matchengine::MatchCmdType::Batch,
+            tenant,
+            order: None,
+            symbol: None,
+            transfer: None,
+            risk: None,
+            market: market_type(market),
+            mark_price: None,
+            orders: match_orders,
+        };
+        propose(
+            envelope(&request, account_id, cmd),
+            request_deadline(&request),
+            false,
+            &mut trace,
+        )
+        .await?;
+
+        Ok(tonic::Response::new(PlaceOrdersResponse {
+            ret: 0,
+            message: "ok".to_string(),
+        }))
+    }
+
     /// Cancels an existing order
     ///
     /// This method:
@@ -132,30 +1114,295 @@ impl MatchService for MatchServiceSVC {
         request: tonic::Request<CancelOrderRequest>,
     ) -> Result<tonic::Response<CancelOrderResponse>, tonic::Status> {
         log::info!("cancel order {:?}", request.get_ref());
+        let mut trace = RequestTrace::new("cancel_order");
+        let tenant = resolve_tenant(&request, "cancel_order")?;
+        recorder::record(&tenant, || Recorded::CancelOrder(request.get_ref().clone()));
         let order_id = request.get_ref().order_id;
+        let client_order_id = &request.get_ref().client_order_id;
 
-        let mut match_order = Order::default();
-        match_order.id = order_id.to_string();
-        match_order.symbol = request.get_ref().symbol.clone();
+        // An order named only by its client order ID is resolved by the engine
+        let match_order = if order_id == 0 && !client_order_id.is_empty() {
+            Order {
+                id: String::new(),
+                symbol: request.get_ref().symbol.clone(),
+                client_order_id: client_order_id.clone(),
+                ..Default::default()
+            }
+        } else {
+            Order {
+                id: order_id.to_string(),
+                symbol: request.get_ref().symbol.clone(),
+                ..Default::default()
+            }
+        };
 
         let cmd = MatchCmd {
             cmd: crate::engine::matchengine::MatchCmdType::CancelOrder,
+            tenant,
             order: Some(match_order),
             symbol: None,
+            transfer: None,
+            risk: None,
+            market: market_type(request.get_ref().market()),
+            mark_price: None,
+            orders: Vec::new(),
         };
 
-        let data =
-            bincode::serialize(&cmd).map_err(|_| tonic::Status::internal("serialize error"))?;
-        let (proposal, rx) = Proposal::normal(data);
-        server::instance().lock().await.add_proposal(proposal).await;
-        rx.await
-            .map_err(|_| tonic::Status::internal("raft error"))?;
+        propose(
+            envelope(&request, request.get_ref().account_id, cmd),
+            request_deadline(&request),
+            true,
+            &mut trace,
+        )
+        .await?;
         Ok(tonic::Response::new(CancelOrderResponse {
             ret: 0,
             message: "ok".to_string(),
         }))
     }
 
+    /// Changes the price or quantity of a resting order
+    ///
+    /// This method:
+    /// 1. Validates the new price and quantity
+    /// 2. Creates an amend order command
+    /// 3. Proposes the command through Raft
+    /// 4. Waits for consensus
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Amend order request
+    ///
+    /// # Returns
+    ///
+    /// Returns a response indicating success or failure
+    async fn amend_order(
+        &self,
+        request: tonic::Request<AmendOrderRequest>,
+    ) -> Result<tonic::Response<AmendOrderResponse>, tonic::Status> {
+        log::info!("amend order {:?}", request.get_ref());
+        let mut trace = RequestTrace::new("amend_order");
+        let tenant = resolve_tenant(&request, "amend_order")?;
+        recorder::record(&tenant, || Recorded::AmendOrder(request.get_ref().clone()));
+        let price = parse_positive("price", &request.get_ref().price)?;
+        let quantity = parse_positive("quantity", &request.get_ref().quantity)?;
+        if price.is_zero() && quantity.is_zero() {
+            return Err(tonic::Status::invalid_argument(
+                "an amendment needs a new price or quantity",
+            ));
+        }
+        let order_id = request.get_ref().order_id;
+        let client_order_id = &request.get_ref().client_order_id;
+
+        // An order named only by its client order ID is resolved by the engine
+        let (id, client_order_id) = if order_id == 0 && !client_order_id.is_empty() {
+            (String::new(), client_order_id.clone())
+        } else {
+            (order_id.to_string(), String::new())
+        };
+        let match_order = Order {
+            id,
+            symbol: request.get_ref().symbol.clone(),
+            client_order_id,
+            price,
+            quantity,
+            ..Default::default()
+        };
+
+        let cmd = MatchCmd {
+            cmd: crate::engine::matchengine::MatchCmdType::AmendOrder,
+            tenant,
+            order: Some(match_order),
+            symbol: None,
+            transfer: None,
+            risk: None,
+            market: market_type(request.get_ref().market()),
+            mark_price: None,
+            orders: Vec::new(),
+        };
+
+        propose(
+            envelope(&request, request.get_ref().account_id, cmd),
+            request_deadline(&request),
+            true,
+            &mut trace,
+        )
+        .await?;
+        Ok(tonic::Response::new(AmendOrderResponse {
+            ret: 0,
+            message: "ok".to_string(),
+        }))
+    }
+
+    /// Cancels every open order of an account
+    ///
+    /// This method:
+    /// 1. Creates a cancel-all command, scoped to the symbol if one is given
+    /// 2. Proposes the command through Raft
+    /// 3. Waits for consensus
+    /// 4. Reads the orders the command canceled from the read view
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Cancel all orders request
+    ///
+    /// # Returns
+    ///
+    /// Returns the number and IDs of the canceled orders
+    async fn cancel_all_orders(
+        &self,
+        request: tonic::Request<CancelAllOrdersRequest>,
+    ) -> Result<tonic::Response<CancelAllOrdersResponse>, tonic::Status> {
+        log::info!("cancel all orders {:?}", request.get_ref());
+        let mut trace = RequestTrace::new("cancel_all_orders");
+        let tenant = resolve_tenant(&request, "cancel_all_orders")?;
+        recorder::record(&tenant, || {
+            Recorded::CancelAllOrders(request.get_ref().clone())
+        });
+        let symbol = &request.get_ref().symbol;
+
+        // Without a symbol the command covers every symbol of the market
+        let match_order = (!symbol.is_empty()).then(|| Order {
+            symbol: symbol.clone(),
+            ..Default::default()
+        });
+
+        let cmd = MatchCmd {
+            cmd: crate::engine::matchengine::MatchCmdType::CancelAllOrders,
+            tenant: tenant.clone(),
+            order: match_order,
+            symbol: None,
+            transfer: None,
+            risk: None,
+            market: market_type(request.get_ref().market()),
+            mark_price: None,
+            orders: Vec::new(),
+        };
+
+        let envelope = envelope(&request, request.get_ref().account_id, cmd);
+        let request_id = envelope.request_id.clone();
+        propose(envelope, request_deadline(&request), true, &mut trace).await?;
+
+        // Published before the proposal was acknowledged; a retry of an applied
+        // request reads the outcome of its first apply
+        let order_ids = read_view::cancellation(&tenant, &request_id)
+            .map(|cancellation| cancellation.order_ids)
+            .unwrap_or_default();
+        Ok(tonic::Response::new(CancelAllOrdersResponse {
+            ret: 0,
+            message: "ok".to_string(),
+            count: order_ids.len() as u32,
+            order_ids: order_ids.iter().filter_map(|id| id.parse().ok()).collect(),
+        }))
+    }
+
+    /// Cancels an order and places its replacement with a single raft proposal
+    ///
+    /// This method:
+    /// 1. Validates the replacement and checks that it shares the account and
+    ///    market of the replaced order
+    /// 2. Creates a replace command holding both
+    /// 3. Proposes the command through Raft
+    /// 4. Waits for consensus
+    /// 5. Reads whether the replaced order was canceled and the status of the
+    ///    new order from the read view
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Replace order request
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the new order was placed, its status or why it was
+    /// rejected, and the ID of the canceled order
+    async fn replace_order(
+        &self,
+        request: tonic::Request<ReplaceOrderRequest>,
+    ) -> Result<tonic::Response<ReplaceOrderResponse>, tonic::Status> {
+        log::info!("replace order {:?}", request.get_ref());
+        let mut trace = RequestTrace::new("replace_order");
+        let tenant = resolve_tenant(&request, "replace_order")?;
+        recorder::record(&tenant, || {
+            Recorded::ReplaceOrder(request.get_ref().clone())
+        });
+        let Some(order) = &request.get_ref().order else {
+            return Err(tonic::Status::invalid_argument(
+                "a replace needs a new order",
+            ));
+        };
+        let account_id = request.get_ref().account_id;
+        if order.account_id != account_id || order.market() != request.get_ref().market() {
+            return Err(tonic::Status::invalid_argument(
+                "the new order must share the account and market of the replaced one",
+            ));
+        }
+        let replacement = match_order(order)?;
+        memory::check_order(&tenant, &replacement.symbol)
+            .map_err(tonic::Status::resource_exhausted)?;
+        check_post_only(&tenant, &replacement).await?;
+        check_degraded().await?;
+        let order_id = request.get_ref().order_id;
+        let client_order_id = &request.get_ref().client_order_id;
+
+        // An order named only by its client order ID is resolved by the engine
+        let replaced = if order_id == 0 && !client_order_id.is_empty() {
+            Order {
+                id: String::new(),
+                symbol: request.get_ref().symbol.clone(),
+                client_order_id: client_order_id.clone(),
+                ..Default::default()
+            }
+        } else {
+            Order {
+                id: order_id.to_string(),
+                symbol: request.get_ref().symbol.clone(),
+                ..Default::default()
+            }
+        };
+
+        let cmd = MatchCmd {
+            cmd: crate::engine::matchengine::MatchCmdType::ReplaceOrder,
+            tenant: tenant.clone(),
+            order: Some(replaced),
+            symbol: None,
+            transfer: None,
+            risk: None,
+            market: market_type(order.market()),
+            mark_price: None,
+            orders: vec![replacement],
+        };
+
+        let envelope = envelope(&request, account_id, cmd);
+        let request_id = envelope.request_id.clone();
+        propose(envelope, request_deadline(&request), false, &mut trace).await?;
+
+        // Published before the proposal was acknowledged; a retry of an applied
+        // request reads the outcome of its first apply
+        let cancellation = read_view::cancellation(&tenant, &request_id).unwrap_or_default();
+        let canceled_order_id = cancellation
+            .order_ids
+            .first()
+            .and_then(|id| id.parse().ok())
+            .unwrap_or_default();
+        let status = cancellation.replacement.unwrap_or(OrderStatus::Rejected);
+        let (ret, message) = if status == OrderStatus::Rejected {
+            let reason = match cancellation.reject_reason {
+                reason if reason.is_empty() => "the new order was rejected".to_string(),
+                reason => reason,
+            };
+            (pb::ResultCode::Fail as i32, reason)
+        } else {
+            (0, "ok".to_string())
+        };
+        Ok(tonic::Response::new(ReplaceOrderResponse {
+            ret,
+            message,
+            replaced: status != OrderStatus::Rejected,
+            canceled_order_id,
+            status: order_status(status) as i32,
+        }))
+    }
+
     /// Creates a new trading symbol
     ///
     /// This method:
@@ -175,6 +1422,11 @@ impl MatchService for MatchServiceSVC {
         &self,
         request: tonic::Request<CreateSymbolRequest>,
     ) -> Result<tonic::Response<CreateSymbolResponse>, tonic::Status> {
+        let mut trace = RequestTrace::new("create_symbol");
+        let tenant = resolve_tenant(&request, "create_symbol")?;
+        recorder::record(&tenant, || {
+            Recorded::CreateSymbol(request.get_ref().clone())
+        });
         let symbol = request.get_ref().symbol.as_ref().unwrap();
         let min_quantity = Decimal::from_str(&symbol.min_quantity)
             .map_err(|_| tonic::Status::invalid_argument("invalid min quantity"))?;
@@ -184,7 +1436,7 @@ impl MatchService for MatchServiceSVC {
             .map_err(|_| tonic::Status::invalid_argument("invalid min amount"))?;
         let max_amount = Decimal::from_str(&symbol.max_amount)
             .map_err(|_| tonic::Status::invalid_argument("invalid max amount"))?;
-        let match_symbol = Symbol::new(
+        let mut match_symbol = Symbol::new(
             symbol.symbol.clone(),
             symbol.base.clone(),
             symbol.quote.clone(),
@@ -195,60 +1447,911 @@ impl MatchService for MatchServiceSVC {
             min_amount,
             max_amount,
         );
+        match_symbol.settle_balances = symbol.settle_balances;
+        match_symbol.max_leverage = parse_positive("max leverage", &symbol.max_leverage)?;
+        match_symbol.maintenance_margin =
+            parse_positive("maintenance margin", &symbol.maintenance_margin)?;
+        if !symbol.fee_currency.is_empty() {
+            if !symbol.settle_balances {
+                return Err(tonic::Status::invalid_argument(
+                    "a fee currency needs settle_balances",
+                ));
+            }
+            if symbol.fee_currency != symbol.base && symbol.fee_currency != symbol.quote {
+                return Err(tonic::Status::invalid_argument(format!(
+                    "fee currency {} is neither {} nor {}",
+                    symbol.fee_currency, symbol.base, symbol.quote
+                )));
+            }
+            match_symbol.fee_currency = symbol.fee_currency.clone();
+        }
+        match_symbol.price_band = parse_positive("price band", &symbol.price_band)?;
+        match_symbol.circuit_breaker = parse_positive("circuit breaker", &symbol.circuit_breaker)?;
+        if !match_symbol.circuit_breaker.is_zero()
+            && (symbol.circuit_window_ms == 0 || symbol.halt_ms == 0)
+        {
+            return Err(tonic::Status::invalid_argument(
+                "a circuit breaker needs circuit_window_ms and halt_ms",
+            ));
+        }
+        match_symbol.circuit_window_ms = symbol.circuit_window_ms;
+        match_symbol.halt_ms = symbol.halt_ms;
+        match_symbol.cancel_on_halt = symbol.cancel_on_halt;
         let cmd = MatchCmd {
             cmd: crate::engine::matchengine::MatchCmdType::CreateSymbol,
+            tenant,
             order: None,
             symbol: Some(match_symbol),
+            transfer: None,
+            risk: None,
+            market: market_type(symbol.market()),
+            mark_price: None,
+            orders: Vec::new(),
         };
-        let data =
-            bincode::serialize(&cmd).map_err(|_| tonic::Status::internal("serialize error"))?;
-        let (proposal, rx) = Proposal::normal(data);
-        server::instance().lock().await.add_proposal(proposal).await;
-        rx.await
-            .map_err(|e| tonic::Status::internal(format!("raft error: {:?}", e)))?;
+        propose(
+            envelope(&request, 0, cmd),
+            request_deadline(&request),
+            false,
+            &mut trace,
+        )
+        .await?;
         Ok(tonic::Response::new(CreateSymbolResponse {
             ret: 0,
             message: "ok".to_string(),
         }))
     }
 
-    /// Removes a trading symbol
+    /// Changes the settings of a listed symbol that may change while it trades
     ///
     /// This method:
-    /// 1. Validates symbol parameters
-    /// 2. Creates a remove symbol command
-    /// 3. Proposes the removal through Raft
+    /// 1. Validates the new price band
+    /// 2. Creates an update symbol command
+    /// 3. Proposes the update through Raft
     /// 4. Waits for consensus
     ///
     /// # Arguments
     ///
-    /// * `request` - Remove symbol request
+    /// * `request` - Update symbol request
     ///
     /// # Returns
     ///
     /// Returns a response indicating success or failure
-    async fn remove_symbol(
+    async fn update_symbol(
         &self,
-        request: tonic::Request<RemoveSymbolRequest>,
-    ) -> Result<tonic::Response<RemoveSymbolResponse>, tonic::Status> {
+        request: tonic::Request<UpdateSymbolRequest>,
+    ) -> Result<tonic::Response<UpdateSymbolResponse>, tonic::Status> {
+        let mut trace = RequestTrace::new("update_symbol");
+        let tenant = resolve_tenant(&request, "update_symbol")?;
+        recorder::record(&tenant, || {
+            Recorded::UpdateSymbol(request.get_ref().clone())
+        });
+        let match_symbol = Symbol {
+            name: request.get_ref().symbol.clone(),
+            price_band: parse_positive("price band", &request.get_ref().price_band)?,
+            ..Default::default()
+        };
+        let cmd = MatchCmd {
+            cmd: crate::engine::matchengine::MatchCmdType::UpdateSymbol,
+            tenant,
+            order: None,
+            symbol: Some(match_symbol),
+            transfer: None,
+            risk: None,
+            market: market_type(request.get_ref().market()),
+            mark_price: None,
+            orders: Vec::new(),
+        };
+        propose(
+            envelope(&request, 0, cmd),
+            request_deadline(&request),
+            false,
+            &mut trace,
+        )
+        .await?;
+        Ok(tonic::Response::new(UpdateSymbolResponse {
+            ret: 0,
+            message: "ok".to_string(),
+        }))
+    }
+
+    /// Removes a trading symbol
+    ///
+    /// This method:
+    /// 1. Validates symbol parameters
+    /// 2. Creates a remove symbol command
+    /// 3. Proposes the removal through Raft
+    /// 4. Waits for consensus
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Remove symbol request
+    ///
+    /// # Returns
+    ///
+    /// Returns a response indicating success or failure
+    async fn remove_symbol(
+        &self,
+        request: tonic::Request<RemoveSymbolRequest>,
+    ) -> Result<tonic::Response<RemoveSymbolResponse>, tonic::Status> {
+        let mut trace = RequestTrace::new("remove_symbol");
+        let tenant = resolve_tenant(&request, "remove_symbol")?;
+        recorder::record(&tenant, || {
+            Recorded::RemoveSymbol(request.get_ref().clone())
+        });
         let match_symbol = Symbol {
             name: request.get_ref().symbol.clone(),
             ..Default::default()
         };
         let cmd = MatchCmd {
             cmd: crate::engine::matchengine::MatchCmdType::RemoveSymbol,
+            tenant,
             order: None,
             symbol: Some(match_symbol),
+            transfer: None,
+            risk: None,
+            market: market_type(request.get_ref().market()),
+            mark_price: None,
+            orders: Vec::new(),
         };
-        let data =
-            bincode::serialize(&cmd).map_err(|_| tonic::Status::internal("serialize error"))?;
-        let (proposal, rx) = Proposal::normal(data);
-        server::instance().lock().await.add_proposal(proposal).await;
-        rx.await
-            .map_err(|_| tonic::Status::internal("raft error"))?;
+        propose(
+            envelope(&request, 0, cmd),
+            request_deadline(&request),
+            false,
+            &mut trace,
+        )
+        .await?;
         Ok(tonic::Response::new(RemoveSymbolResponse {
             ret: 0,
             message: "ok".to_string(),
         }))
     }
+
+    /// Credits funds to an account
+    ///
+    /// The funds become available once the deposit is committed, orders on
+    /// symbols that settle balances hold them. A deposit whose idempotency key
+    /// was applied before is ignored; the outcome is recorded in the funding
+    /// audit journal, see `engine::funding`.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Deposit request
+    ///
+    /// # Returns
+    ///
+    /// Returns a response indicating success or failure
+    async fn deposit(
+        &self,
+        request: tonic::Request<DepositRequest>,
+    ) -> Result<tonic::Response<DepositResponse>, tonic::Status> {
+        log::info!("deposit {:?}", request.get_ref());
+        let mut trace = RequestTrace::new("deposit");
+        let tenant = resolve_tenant(&request, "deposit")?;
+        check_admin(&request)?;
+        recorder::record(&tenant, || Recorded::Deposit(request.get_ref().clone()));
+        let deposit = request.get_ref();
+        let cmd = transfer_cmd(
+            MatchCmdType::Deposit,
+            tenant,
+            &deposit.currency,
+            &deposit.amount,
+            &deposit.idempotency_key,
+            &deposit.reason,
+        )?;
+        propose(
+            funding_envelope(&request, deposit.account_id, cmd),
+            request_deadline(&request),
+            false,
+            &mut trace,
+        )
+        .await?;
+        Ok(tonic::Response::new(DepositResponse {
+            ret: 0,
+            message: "ok".to_string(),
+        }))
+    }
+
+    /// Debits available funds from an account
+    ///
+    /// Funds held for open orders cannot be withdrawn, a withdrawal exceeding
+    /// the available funds is rejected when it is applied. Idempotency keys work
+    /// as for deposits.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Withdraw request
+    ///
+    /// # Returns
+    ///
+    /// Returns a response indicating success or failure
+    async fn withdraw(
+        &self,
+        request: tonic::Request<WithdrawRequest>,
+    ) -> Result<tonic::Response<WithdrawResponse>, tonic::Status> {
+        log::info!("withdraw {:?}", request.get_ref());
+        let mut trace = RequestTrace::new("withdraw");
+        let tenant = resolve_tenant(&request, "withdraw")?;
+        check_admin(&request)?;
+        recorder::record(&tenant, || Recorded::Withdraw(request.get_ref().clone()));
+        let withdrawal = request.get_ref();
+        let cmd = transfer_cmd(
+            MatchCmdType::Withdraw,
+            tenant,
+            &withdrawal.currency,
+            &withdrawal.amount,
+            &withdrawal.idempotency_key,
+            &withdrawal.reason,
+        )?;
+        propose(
+            funding_envelope(&request, withdrawal.account_id, cmd),
+            request_deadline(&request),
+            false,
+            &mut trace,
+        )
+        .await?;
+        Ok(tonic::Response::new(WithdrawResponse {
+            ret: 0,
+            message: "ok".to_string(),
+        }))
+    }
+
+    /// Corrects the available funds of an account by a signed amount
+    ///
+    /// For manual corrections outside regular deposits and withdrawals; the
+    /// reason is kept in the audit trail. A debit exceeding the available funds
+    /// is rejected when it is applied.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Adjust balance request
+    ///
+    /// # Returns
+    ///
+    /// Returns a response indicating success or failure
+    async fn adjust_balance(
+        &self,
+        request: tonic::Request<AdjustBalanceRequest>,
+    ) -> Result<tonic::Response<AdjustBalanceResponse>, tonic::Status> {
+        log::info!("adjust balance {:?}", request.get_ref());
+        let mut trace = RequestTrace::new("adjust_balance");
+        let tenant = resolve_tenant(&request, "adjust_balance")?;
+        check_admin(&request)?;
+        recorder::record(&tenant, || {
+            Recorded::AdjustBalance(request.get_ref().clone())
+        });
+        let adjustment = request.get_ref();
+        let cmd = transfer_cmd(
+            MatchCmdType::Adjust,
+            tenant,
+            &adjustment.currency,
+            &adjustment.amount,
+            &adjustment.idempotency_key,
+            &adjustment.reason,
+        )?;
+        propose(
+            funding_envelope(&request, adjustment.account_id, cmd),
+            request_deadline(&request),
+            false,
+            &mut trace,
+        )
+        .await?;
+        Ok(tonic::Response::new(AdjustBalanceResponse {
+            ret: 0,
+            message: "ok".to_string(),
+        }))
+    }
+
+    /// Replaces the pre-trade risk limits of an account
+    ///
+    /// Credit lines let the account hold more than its available funds for
+    /// orders on symbols that settle balances; disabling trading rejects all its
+    /// new orders. Authorized like funding requests.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Set risk limits request
+    ///
+    /// # Returns
+    ///
+    /// Returns a response indicating success or failure
+    async fn set_risk_limits(
+        &self,
+        request: tonic::Request<SetRiskLimitsRequest>,
+    ) -> Result<tonic::Response<SetRiskLimitsResponse>, tonic::Status> {
+        log::info!("set risk limits {:?}", request.get_ref());
+        let mut trace = RequestTrace::new("set_risk_limits");
+        let tenant = resolve_tenant(&request, "set_risk_limits")?;
+        check_admin(&request)?;
+        recorder::record(&tenant, || {
+            Recorded::SetRiskLimits(request.get_ref().clone())
+        });
+        let limits = request.get_ref();
+        let mut credit = BTreeMap::new();
+        for line in &limits.credit {
+            match Decimal::from_str(&line.amount) {
+                Ok(amount) if amount >= Decimal::ZERO && !line.currency.is_empty() => {
+                    credit.insert(line.currency.clone(), amount);
+                }
+                _ => {
+                    return Err(tonic::Status::invalid_argument(format!(
+                        "invalid credit line {:?}",
+                        line
+                    )))
+                }
+            }
+        }
+        let cmd = MatchCmd {
+            cmd: MatchCmdType::SetRiskLimits,
+            tenant,
+            order: None,
+            symbol: None,
+            transfer: None,
+            risk: Some(RiskLimits {
+                credit,
+                trading_disabled: limits.trading_disabled,
+                orders_per_second: (limits.orders_per_second > 0)
+                    .then_some(limits.orders_per_second),
+                order_burst: limits.order_burst,
+            }),
+            market: MarketType::Spot,
+            mark_price: None,
+            orders: Vec::new(),
+        };
+        propose(
+            envelope(&request, limits.account_id, cmd),
+            request_deadline(&request),
+            false,
+            &mut trace,
+        )
+        .await?;
+        Ok(tonic::Response::new(SetRiskLimitsResponse {
+            ret: 0,
+            message: "ok".to_string(),
+        }))
+    }
+
+    /// Records the mark price of a perpetual contract
+    ///
+    /// Published by the price feed, authorized like funding requests. Prices of
+    /// contracts that are not listed are rejected when they are applied.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Set mark price request
+    ///
+    /// # Returns
+    ///
+    /// Returns a response indicating success or failure
+    async fn set_mark_price(
+        &self,
+        request: tonic::Request<SetMarkPriceRequest>,
+    ) -> Result<tonic::Response<SetMarkPriceResponse>, tonic::Status> {
+        let mut trace = RequestTrace::new("set_mark_price");
+        let tenant = resolve_tenant(&request, "set_mark_price")?;
+        check_admin(&request)?;
+        recorder::record(&tenant, || {
+            Recorded::SetMarkPrice(request.get_ref().clone())
+        });
+        let mark = request.get_ref();
+        let price = parse_positive("mark price", &mark.price)?;
+        if price.is_zero() {
+            return Err(tonic::Status::invalid_argument("mark price must be set"));
+        }
+        let cmd = MatchCmd {
+            cmd: MatchCmdType::SetMarkPrice,
+            tenant,
+            mark_price: Some(MarkPrice {
+                symbol: mark.symbol.clone(),
+                price,
+            }),
+            market: MarketType::Perp,
+            ..Default::default()
+        };
+        propose(
+            envelope(&request, 0, cmd),
+            request_deadline(&request),
+            false,
+            &mut trace,
+        )
+        .await?;
+        Ok(tonic::Response::new(SetMarkPriceResponse {
+            ret: 0,
+            message: "ok".to_string(),
+        }))
+    }
+
+    /// Injects a liquidation order into a perpetual contract
+    ///
+    /// Sent by the risk engine, authorized like funding requests. The order is
+    /// matched at market regardless of the contract limits and never rests, see
+    /// `ContractProcessor::liquidate`.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Liquidate request
+    ///
+    /// # Returns
+    ///
+    /// Returns a response indicating success or failure
+    async fn liquidate(
+        &self,
+        request: tonic::Request<LiquidateRequest>,
+    ) -> Result<tonic::Response<LiquidateResponse>, tonic::Status> {
+        log::warn!("liquidate {:?}", request.get_ref());
+        let mut trace = RequestTrace::new("liquidate");
+        let tenant = resolve_tenant(&request, "liquidate")?;
+        check_admin(&request)?;
+        recorder::record(&tenant, || Recorded::Liquidate(request.get_ref().clone()));
+        let order = request
+            .get_ref()
+            .order
+            .as_ref()
+            .ok_or_else(|| tonic::Status::invalid_argument("order must be set"))?;
+        // Liquidations match at market, the price may be left empty
+        let liquidation = pb::Order {
+            price: "0".to_string(),
+            ..order.clone()
+        };
+        let cmd = MatchCmd {
+            cmd: MatchCmdType::Liquidate,
+            tenant,
+            order: Some(match_order(&liquidation)?),
+            market: MarketType::Perp,
+            ..Default::default()
+        };
+        propose(
+            envelope(&request, order.account_id, cmd),
+            request_deadline(&request),
+            false,
+            &mut trace,
+        )
+        .await?;
+        Ok(tonic::Response::new(LiquidateResponse {
+            ret: 0,
+            message: "ok".to_string(),
+        }))
+    }
+
+    /// Returns the available and held funds of an account per currency
+    ///
+    /// Balances are read from the ledger replicated to this node, see
+    /// `check_read` for the consistency options. Reads are not recorded.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Get balances request
+    ///
+    /// # Returns
+    ///
+    /// Returns the balances, empty if the account never held funds
+    async fn get_balances(
+        &self,
+        request: tonic::Request<GetBalancesRequest>,
+    ) -> Result<tonic::Response<GetBalancesResponse>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "get_balances")?;
+        let query = request.get_ref();
+        check_read(
+            query.consistency(),
+            query.max_staleness_ms,
+            request_deadline(&request),
+        )
+        .await?;
+        let balances = read_view::balances(&tenant, query.account_id)
+            .into_iter()
+            .map(|(currency, balance)| pb::Balance {
+                currency,
+                available: balance.available.to_string(),
+                held: balance.held.to_string(),
+            })
+            .collect();
+        Ok(tonic::Response::new(GetBalancesResponse {
+            ret: 0,
+            message: "ok".to_string(),
+            balances,
+        }))
+    }
+
+    /// Returns the positions of an account per symbol
+    ///
+    /// Positions are read like balances, see `get_balances`.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Get positions request
+    ///
+    /// # Returns
+    ///
+    /// Returns the positions, empty if the account never traded a symbol that
+    /// settles balances
+    async fn get_positions(
+        &self,
+        request: tonic::Request<GetPositionsRequest>,
+    ) -> Result<tonic::Response<GetPositionsResponse>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "get_positions")?;
+        let query = request.get_ref();
+        check_read(
+            query.consistency(),
+            query.max_staleness_ms,
+            request_deadline(&request),
+        )
+        .await?;
+        let positions = read_view::positions(&tenant, query.account_id)
+            .into_iter()
+            .map(|(symbol, position)| pb::Position {
+                symbol,
+                quantity: position.quantity.to_string(),
+                entry_price: position.entry_price.to_string(),
+                realized_pnl: position.realized_pnl.to_string(),
+            })
+            .collect();
+        Ok(tonic::Response::new(GetPositionsResponse {
+            ret: 0,
+            message: "ok".to_string(),
+            positions,
+        }))
+    }
+
+    /// Returns the best price levels of a book
+    ///
+    /// Aggregated by price (L2) or per order with anonymized IDs and queue
+    /// positions (L3), see `market_data`. The book is read like balances, see
+    /// `get_balances`.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Get depth request
+    ///
+    /// # Returns
+    ///
+    /// Returns the depth, not found if the symbol is not listed or invalid
+    /// argument if too many levels are asked for
+    async fn get_depth(
+        &self,
+        request: tonic::Request<GetDepthRequest>,
+    ) -> Result<tonic::Response<GetDepthResponse>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "get_depth")?;
+        let query = request.get_ref();
+        let levels = market_data::levels(query.levels).map_err(tonic::Status::invalid_argument)?;
+        check_read(
+            query.consistency(),
+            query.max_staleness_ms,
+            request_deadline(&request),
+        )
+        .await?;
+        let depth = market_data::get(&tenant, &query.symbol, levels, query.mode())
+            .await
+            .ok_or_else(|| {
+                tonic::Status::not_found(format!("symbol {} is not listed", query.symbol))
+            })?;
+        Ok(tonic::Response::new(GetDepthResponse {
+            ret: 0,
+            message: "ok".to_string(),
+            depth: Some(depth),
+        }))
+    }
+
+    /// Returns the matching statistics of a symbol per interval
+    ///
+    /// Fill ratio, time to fill, time-weighted spread and trades as tallied by
+    /// this node since it started, see `symbol_stats`.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Get symbol stats request
+    ///
+    /// # Returns
+    ///
+    /// Returns the intervals kept, latest first, none if the node has not seen
+    /// the symbol
+    async fn get_symbol_stats(
+        &self,
+        request: tonic::Request<GetSymbolStatsRequest>,
+    ) -> Result<tonic::Response<GetSymbolStatsResponse>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "get_symbol_stats")?;
+        let query = request.get_ref();
+        let intervals = symbol_stats::get(&tenant, &query.symbol, query.intervals as usize);
+        Ok(tonic::Response::new(GetSymbolStatsResponse {
+            ret: 0,
+            message: "ok".to_string(),
+            intervals,
+        }))
+    }
+
+    type SubscribeClusterEventsStream = ReceiverStream<Result<pb::ClusterEvent, tonic::Status>>;
+
+    /// Streams changes of the cluster seen by this node
+    ///
+    /// Any node can be subscribed to, see `cluster_events`. The stream ends when
+    /// the client falls behind and has to subscribe again.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Subscribe cluster events request
+    ///
+    /// # Returns
+    ///
+    /// Returns the events, starting with the leader currently known
+    async fn subscribe_cluster_events(
+        &self,
+        request: tonic::Request<SubscribeClusterEventsRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeClusterEventsStream>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "subscribe_cluster_events")?;
+        Ok(tonic::Response::new(ReceiverStream::new(
+            cluster_events::subscribe(&tenant),
+        )))
+    }
+
+    type SubscribeDropCopyStream = ReceiverStream<Result<pb::ExecutionReport, tonic::Status>>;
+
+    /// Streams the execution reports of the tenant's drop copy accounts
+    ///
+    /// Any node can be subscribed to, see `drop_copy`. Needs an admin key when
+    /// admin keys are configured. The stream ends when the consumer falls behind
+    /// and has to subscribe again.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Subscribe drop copy request
+    ///
+    /// # Returns
+    ///
+    /// Returns the reports, or invalid argument if an account is not listed
+    async fn subscribe_drop_copy(
+        &self,
+        request: tonic::Request<SubscribeDropCopyRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeDropCopyStream>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "subscribe_drop_copy")?;
+        check_admin(&request)?;
+        let reports = drop_copy::subscribe(&tenant, &request.get_ref().account_ids)
+            .map_err(tonic::Status::invalid_argument)?;
+        Ok(tonic::Response::new(ReceiverStream::new(reports)))
+    }
+
+    type SubscribeDepthStream = ReceiverStream<Result<pb::Depth, tonic::Status>>;
+
+    /// Streams the depth of books whenever they change
+    ///
+    /// Any node can be subscribed to, each subscription in L2 or L3 with its own
+    /// number of levels, see `market_data`. The stream ends when the client falls
+    /// behind and has to subscribe again.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Subscribe depth request
+    ///
+    /// # Returns
+    ///
+    /// Returns the depth of each symbol, then its updates, or invalid argument if
+    /// no symbol or too many levels are asked for
+    async fn subscribe_depth(
+        &self,
+        request: tonic::Request<SubscribeDepthRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeDepthStream>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "subscribe_depth")?;
+        let query = request.get_ref();
+        let levels = market_data::levels(query.levels).map_err(tonic::Status::invalid_argument)?;
+        let depths = market_data::subscribe(&tenant, &query.symbols, levels, query.mode())
+            .map_err(tonic::Status::invalid_argument)?;
+        Ok(tonic::Response::new(ReceiverStream::new(depths)))
+    }
+
+    type OrderSessionStream = ReceiverStream<Result<pb::OrderSessionResponse, tonic::Status>>;
+
+    /// Opens an order session placing and canceling the orders sent on it
+    ///
+    /// Acks and the execution reports of the accounts orders are placed for
+    /// come back on the same stream, see `order_session`. Orders of the session
+    /// still open when the stream ends are canceled.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Stream of order session requests
+    ///
+    /// # Returns
+    ///
+    /// Returns the acks and reports
+    async fn order_session(
+        &self,
+        request: tonic::Request<tonic::Streaming<OrderSessionRequest>>,
+    ) -> Result<tonic::Response<Self::OrderSessionStream>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "order_session")?;
+        let client_id = request
+            .metadata()
+            .get(CLIENT_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let (session, reports) = drop_copy::subscribe_session(&tenant);
+        let capacity = config::instance().lock().unwrap().channels.drop_copy;
+        let (sender, receiver) = tokio::sync::mpsc::channel(capacity.max(1));
+        let requests = request.into_inner();
+        tokio::spawn(order_session::run(
+            tenant, client_id, session, requests, reports, sender,
+        ));
+        Ok(tonic::Response::new(ReceiverStream::new(receiver)))
+    }
+
+    type ExportAuditTrailStream = ReceiverStream<Result<pb::AuditRecord, tonic::Status>>;
+
+    /// Streams the audit trail records of the tenant's commands within a range
+    ///
+    /// Read from the trail of this node, see `audit_trail`, so any node with
+    /// the trail enabled can be asked. Needs an admin key when admin keys are
+    /// configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Export audit trail request
+    ///
+    /// # Returns
+    ///
+    /// Returns the records in log order, or failed precondition if the trail
+    /// is disabled
+    async fn export_audit_trail(
+        &self,
+        request: tonic::Request<ExportAuditTrailRequest>,
+    ) -> Result<tonic::Response<Self::ExportAuditTrailStream>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "export_audit_trail")?;
+        check_admin(&request)?;
+        let query = request.get_ref();
+        let range = audit_trail::Range {
+            tenant,
+            from_index: query.from_index,
+            to_index: (query.to_index > 0).then_some(query.to_index),
+            from_time: query.from_time,
+            to_time: (query.to_time > 0).then_some(query.to_time),
+        };
+        let records = audit_trail::export(range).map_err(tonic::Status::failed_precondition)?;
+        Ok(tonic::Response::new(ReceiverStream::new(records)))
+    }
+
+    type SubscribeChangesStream = ReceiverStream<Result<pb::ChangeEvent, tonic::Status>>;
+
+    /// Streams the commands of every tenant applied from an index on
+    ///
+    /// Served from the change feed of this node, see `change_feed`, starting
+    /// with a snapshot of the engine if the node no longer keeps the index.
+    /// Needs an admin key when admin keys are configured. The stream ends when
+    /// the consumer falls behind and has to subscribe again.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Subscribe changes request
+    ///
+    /// # Returns
+    ///
+    /// Returns the changes in log order, or failed precondition if the feed
+    /// is disabled
+    async fn subscribe_changes(
+        &self,
+        request: tonic::Request<SubscribeChangesRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeChangesStream>, tonic::Status> {
+        check_admin(&request)?;
+        let changes = change_feed::subscribe(request.get_ref().from_index)
+            .map_err(tonic::Status::failed_precondition)?;
+        Ok(tonic::Response::new(ReceiverStream::new(changes)))
+    }
+
+    /// Exports the tenant's books, open interest and running daily statistics
+    ///
+    /// Written from the read view of this node, see `eod_export`. Needs an
+    /// admin key when admin keys are configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Export end of day request
+    ///
+    /// # Returns
+    ///
+    /// Returns the files written, or failed precondition if the export is not
+    /// configured
+    async fn export_end_of_day(
+        &self,
+        request: tonic::Request<ExportEndOfDayRequest>,
+    ) -> Result<tonic::Response<ExportEndOfDayResponse>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "export_end_of_day")?;
+        check_admin(&request)?;
+        if !eod_export::is_enabled() {
+            return Err(tonic::Status::failed_precondition(
+                "end-of-day export is not configured",
+            ));
+        }
+        let files = eod_export::export_now(&tenant)
+            .await
+            .map_err(tonic::Status::internal)?;
+        Ok(tonic::Response::new(ExportEndOfDayResponse {
+            ret: 0,
+            message: "ok".to_string(),
+            files,
+        }))
+    }
+
+    /// Lists the tenant's dead letters kept by this node
+    ///
+    /// See `dead_letter`. Needs an admin key when admin keys are configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - List dead letters request
+    ///
+    /// # Returns
+    ///
+    /// Returns the letters by seq, or failed precondition if the journal is disabled
+    async fn list_dead_letters(
+        &self,
+        request: tonic::Request<ListDeadLettersRequest>,
+    ) -> Result<tonic::Response<ListDeadLettersResponse>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "list_dead_letters")?;
+        check_admin(&request)?;
+        let query = request.get_ref();
+        let sink = (!query.sink.is_empty()).then_some(query.sink.as_str());
+        let letters = dead_letter::list(&tenant, sink, query.from_seq, query.limit as usize)
+            .map_err(tonic::Status::failed_precondition)?;
+        Ok(tonic::Response::new(ListDeadLettersResponse {
+            ret: 0,
+            message: "ok".to_string(),
+            letters,
+        }))
+    }
+
+    /// Hands the tenant's dead letters kept by this node back to their publishers
+    ///
+    /// See `dead_letter`. Needs an admin key when admin keys are configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Replay dead letters request
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of letters handed back, failed precondition if the
+    /// journal is disabled or unavailable if a publisher did not take a letter
+    async fn replay_dead_letters(
+        &self,
+        request: tonic::Request<ReplayDeadLettersRequest>,
+    ) -> Result<tonic::Response<ReplayDeadLettersResponse>, tonic::Status> {
+        let tenant = resolve_tenant(&request, "replay_dead_letters")?;
+        check_admin(&request)?;
+        if !dead_letter::is_enabled() {
+            return Err(tonic::Status::failed_precondition(
+                "dead-letter journal is disabled",
+            ));
+        }
+        let query = request.get_ref();
+        let sink = (!query.sink.is_empty()).then_some(query.sink.as_str());
+        let replayed =
+            dead_letter::replay(&tenant, &query.seqs, sink).map_err(tonic::Status::unavailable)?;
+        Ok(tonic::Response::new(ReplayDeadLettersResponse {
+            ret: 0,
+            message: "ok".to_string(),
+            replayed,
+        }))
+    }
+
+    /// Reports whether this node is ready to take traffic
+    ///
+    /// Meant for orchestrator probes, so no API key is required, see `readiness`.
+    ///
+    /// # Arguments
+    ///
+    /// * `_request` - Get readiness request
+    ///
+    /// # Returns
+    ///
+    /// Returns the readiness conditions and whether they all hold
+    async fn get_readiness(
+        &self,
+        _request: tonic::Request<GetReadinessRequest>,
+    ) -> Result<tonic::Response<GetReadinessResponse>, tonic::Status> {
+        let report = readiness::report();
+        Ok(tonic::Response::new(GetReadinessResponse {
+            ready: report.ready(),
+            restored: report.restored,
+            replayed: report.replayed,
+            peers_connected: report.peers_connected,
+            caught_up: report.caught_up,
+            leader_id: report.leader_id,
+            lag: report.lag,
+        }))
+    }
 }
@@ -0,0 +1,230 @@
+//! Memory accounting of the engine
+//!
+//! After each apply batch the engine records here what its order books and the
+//! request and funding histories of its tenants are estimated to hold. Estimates
+//! count the data structures, not allocator overhead, see
+//! `OrderBook::estimated_memory`; the allocator's own view is in `allocator`.
+//!
+//! `symbol_memory_limit` and `engine_memory_limit` cap the estimates. They are
+//! enforced when an order is proposed, never when it is applied: estimates depend
+//! on the allocation history of a node and the configuration may differ between
+//! nodes, while applying a command must give the same result on every replica. An
+//! order on a symbol whose book is at its limit, or on any symbol while the engine
+//! is at its limit, is refused with `RESOURCE_EXHAUSTED`; cancels, liquidations
+//! and every other command are still accepted, so memory can always be freed.
+
+use crate::{config, metrics};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Component holding the order books of a tenant
+const BOOKS: &str = "books";
+
+/// Current estimates, recorded by the raft loop
+static USAGE: Mutex<Usage> = Mutex::new(Usage::new());
+
+/// Estimated memory of the engine
+#[derive(Debug)]
+struct Usage {
+    /// Bytes by tenant and symbol
+    books: BTreeMap<(String, String), usize>,
+    /// Bytes by tenant and component, the books summed up
+    components: BTreeMap<(String, &'static str), usize>,
+    /// Bytes of the whole engine
+    total: usize,
+}
+
+impl Usage {
+    /// Creates empty usage
+    const fn new() -> Self {
+        Usage {
+            books: BTreeMap::new(),
+            components: BTreeMap::new(),
+            total: 0,
+        }
+    }
+
+    /// Replaces the estimate of a tenant's component
+    fn set_component(&mut self, tenant: &str, component: &'static str, bytes: usize) {
+        let previous = self
+            .components
+            .insert((tenant.to_string(), component), bytes)
+            .unwrap_or(0);
+        self.total = self.total + bytes - previous;
+        metrics::ENGINE_MEMORY_GAUGE_VEC
+            .with_label_values(&[tenant, component])
+            .set(bytes as i64);
+        metrics::ENGINE_MEMORY_TOTAL_GAUGE.set(self.total as i64);
+    }
+
+    /// Replaces the estimate of a book, None drops it
+    fn set_book(&mut self, tenant: &str, symbol: &str, bytes: Option<usize>) {
+        let key = (tenant.to_string(), symbol.to_string());
+        let previous = match bytes {
+            Some(bytes) => self.books.insert(key, bytes),
+            None => self.books.remove(&key),
+        }
+        .unwrap_or(0);
+        let books = self
+            .components
+            .get(&(tenant.to_string(), BOOKS))
+            .copied()
+            .unwrap_or(0);
+        self.set_component(tenant, BOOKS, books + bytes.unwrap_or(0) - previous);
+    }
+
+    /// Checks whether an order may be proposed
+    ///
+    /// # Arguments
+    /// * `tenant` - Tenant the order is for
+    /// * `symbol` - Symbol the order is placed on
+    /// * `symbol_limit` - Cap of a single book in bytes
+    /// * `engine_limit` - Cap of the whole engine in bytes
+    ///
+    /// # Returns
+    /// The limit that was reached and why, if one was
+    fn check(
+        &self,
+        tenant: &str,
+        symbol: &str,
+        symbol_limit: Option<u64>,
+        engine_limit: Option<u64>,
+    ) -> Result<(), (&'static str, String)> {
+        if let Some(limit) = engine_limit {
+            if self.total as u64 >= limit {
+                return Err((
+                    "engine",
+                    format!(
+                        "engine holds {} bytes, at its limit of {}",
+                        self.total, limit
+                    ),
+                ));
+            }
+        }
+        if let Some(limit) = symbol_limit {
+            let book = self
+                .books
+                .get(&(tenant.to_string(), symbol.to_string()))
+                .copied()
+                .unwrap_or(0);
+            if book as u64 >= limit {
+                return Err((
+                    "symbol",
+                    format!(
+                        "order book of {} holds {} bytes, at its limit of {}",
+                        symbol, book, limit
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Records the estimated memory of an order book
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the symbol belongs to
+/// * `symbol` - Symbol of the order book
+/// * `bytes` - Estimate of the book, None if the symbol is no longer traded
+pub fn record_book(tenant: &str, symbol: &str, bytes: Option<usize>) {
+    USAGE.lock().unwrap().set_book(tenant, symbol, bytes);
+    match bytes {
+        Some(bytes) => metrics::SYMBOL_BOOK_MEMORY_GAUGE_VEC
+            .with_label_values(&[tenant, symbol])
+            .set(bytes as f64),
+        None => {
+            let _ = metrics::SYMBOL_BOOK_MEMORY_GAUGE_VEC.remove_label_values(&[tenant, symbol]);
+        }
+    }
+}
+
+/// Records the estimated memory of a history kept by a tenant
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant keeping the history
+/// * `component` - Name of the history, e.g. `dedupe` or `funding`
+/// * `bytes` - Estimate of the history
+pub fn record_history(tenant: &str, component: &'static str, bytes: usize) {
+    USAGE
+        .lock()
+        .unwrap()
+        .set_component(tenant, component, bytes);
+}
+
+/// Forgets all estimates, before the engine is restored from a snapshot
+pub fn clear() {
+    let mut usage = USAGE.lock().unwrap();
+    for ((tenant, component), _) in std::mem::take(&mut usage.components) {
+        let _ = metrics::ENGINE_MEMORY_GAUGE_VEC.remove_label_values(&[&tenant, component]);
+    }
+    for ((tenant, symbol), _) in std::mem::take(&mut usage.books) {
+        let _ = metrics::SYMBOL_BOOK_MEMORY_GAUGE_VEC.remove_label_values(&[&tenant, &symbol]);
+    }
+    usage.total = 0;
+    metrics::ENGINE_MEMORY_TOTAL_GAUGE.set(0);
+}
+
+/// Checks the memory limits before an order is proposed
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the order is for
+/// * `symbol` - Symbol the order is placed on
+///
+/// # Returns
+///
+/// Returns why the order is refused if a limit is reached
+pub fn check_order(tenant: &str, symbol: &str) -> Result<(), String> {
+    let (symbol_limit, engine_limit) = {
+        let config = config::instance().lock().unwrap();
+        (config.symbol_memory_limit, config.engine_memory_limit)
+    };
+    if symbol_limit.is_none() && engine_limit.is_none() {
+        return Ok(());
+    }
+    USAGE
+        .lock()
+        .unwrap()
+        .check(tenant, symbol, symbol_limit, engine_limit)
+        .map_err(|(limit, reason)| {
+            metrics::MEMORY_REJECTED_COUNTER_VEC
+                .with_label_values(&[tenant, limit])
+                .inc();
+            reason
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_refuse_orders_until_memory_is_freed() {
+        let mut usage = Usage::new();
+        usage.set_book("t", "BTCUSDT", Some(600));
+        usage.set_book("t", "ETHUSDT", Some(300));
+        usage.set_component("t", "dedupe", 100);
+        assert_eq!(usage.total, 1000);
+        assert_eq!(usage.components[&("t".to_string(), BOOKS)], 900);
+
+        assert!(usage.check("t", "BTCUSDT", Some(1000), None).is_ok());
+        assert_eq!(
+            usage.check("t", "BTCUSDT", Some(600), None).unwrap_err().0,
+            "symbol"
+        );
+        // Other books stay open below their own limit
+        assert!(usage.check("t", "ETHUSDT", Some(600), None).is_ok());
+        assert_eq!(
+            usage.check("t", "ETHUSDT", None, Some(1000)).unwrap_err().0,
+            "engine"
+        );
+
+        // Delisting frees the book
+        usage.set_book("t", "BTCUSDT", None);
+        assert_eq!(usage.total, 400);
+        assert!(usage.check("t", "ETHUSDT", Some(600), Some(1000)).is_ok());
+    }
+}
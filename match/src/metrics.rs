@@ -3,9 +3,37 @@
 //! This module provides functionality for collecting and exposing service metrics
 //! using Prometheus.
 
+use crate::config;
+use crate::engine::data::OrderBook;
+use crate::engine::entry::{Symbol, Trade};
 use lazy_static::lazy_static;
-use prometheus::{CounterVec, HistogramOpts, HistogramVec, Opts, Registry};
+use prometheus::{
+    exponential_buckets, CounterVec, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntGauge, IntGaugeVec, Opts, Registry,
+};
+use rust_decimal::prelude::ToPrimitive;
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::time::Instant;
+use tokio::sync::mpsc::Sender;
+
+/// Interval at which watched channels are sampled
+pub const CHANNEL_SAMPLE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+/// Fill ratio above which a channel counts as saturated
+const CHANNEL_HIGH_WATER_RATIO: f64 = 0.8;
+
+/// Probe reporting the depth and capacity of a channel, None once the channel is closed
+type ChannelProbe = Box<dyn Fn() -> Option<(usize, usize)> + Send>;
+
+/// Channel watched by the saturation sampler
+struct WatchedChannel {
+    /// Name of the channel, used as metric label
+    name: String,
+    /// Probe reading the channel state
+    probe: ChannelProbe,
+    /// Whether the channel was above the high-water mark at the last sample
+    saturated: bool,
+}
 
 lazy_static! {
     /// Global Prometheus registry instance
@@ -15,20 +43,735 @@ lazy_static! {
     pub static ref REQ_COUNTER_VEC: CounterVec =
         CounterVec::new(Opts::new("request_counter", "request counter"), &["method"]).unwrap();
 
+    /// Counter for tracking request counts by tenant and method
+    pub static ref TENANT_REQ_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("tenant_request_counter", "request counter by tenant"),
+        &["tenant", "method"]
+    )
+    .unwrap();
+
+    /// Counter for tracking applied orders by tenant, symbol and result (accepted/rejected)
+    pub static ref SYMBOL_ORDER_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("symbol_order_counter", "orders by symbol and result"),
+        &["tenant", "symbol", "result"]
+    )
+    .unwrap();
+
+    /// Counter for tracking canceled orders by tenant and symbol
+    pub static ref SYMBOL_CANCEL_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("symbol_cancel_counter", "canceled orders by symbol"),
+        &["tenant", "symbol"]
+    )
+    .unwrap();
+
+    /// Counter for tracking trades by tenant and symbol
+    pub static ref SYMBOL_TRADE_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("symbol_trade_counter", "trades by symbol"),
+        &["tenant", "symbol"]
+    )
+    .unwrap();
+
+    /// Counter for tracking traded base currency volume by tenant and symbol
+    pub static ref SYMBOL_BASE_VOLUME_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("symbol_base_volume", "traded base volume by symbol"),
+        &["tenant", "symbol"]
+    )
+    .unwrap();
+
+    /// Counter for tracking traded notional (quote currency volume) by tenant and symbol
+    pub static ref SYMBOL_QUOTE_VOLUME_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("symbol_quote_volume", "traded notional in quote currency by symbol"),
+        &["tenant", "symbol"]
+    )
+    .unwrap();
+
+    /// Counter for tracking traded notional by tenant and quote currency, across symbols
+    pub static ref NOTIONAL_VOLUME_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("notional_volume", "traded notional by quote currency"),
+        &["tenant", "quote"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the number of resting orders by tenant and symbol
+    pub static ref SYMBOL_RESTING_ORDERS_GAUGE_VEC: GaugeVec = GaugeVec::new(
+        Opts::new("symbol_resting_orders", "resting orders by symbol"),
+        &["tenant", "symbol"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the number of price levels by tenant and symbol
+    pub static ref SYMBOL_PRICE_LEVELS_GAUGE_VEC: GaugeVec = GaugeVec::new(
+        Opts::new("symbol_price_levels", "price levels by symbol"),
+        &["tenant", "symbol"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the estimated order book memory by tenant and symbol
+    pub static ref SYMBOL_BOOK_MEMORY_GAUGE_VEC: GaugeVec = GaugeVec::new(
+        Opts::new("symbol_book_memory_bytes", "estimated order book memory by symbol"),
+        &["tenant", "symbol"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the estimated engine memory by tenant and component
+    pub static ref ENGINE_MEMORY_GAUGE_VEC: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("engine_memory_bytes", "estimated engine memory by tenant and component"),
+        &["tenant", "component"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the estimated memory of the whole engine
+    pub static ref ENGINE_MEMORY_TOTAL_GAUGE: IntGauge =
+        IntGauge::new("engine_memory_total_bytes", "estimated memory of the whole engine").unwrap();
+
+    /// Counter for tracking orders refused by a memory limit by tenant and limit
+    pub static ref MEMORY_REJECTED_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("memory_rejected_orders", "orders refused by a memory limit"),
+        &["tenant", "limit"]
+    )
+    .unwrap();
+
+    /// Gauge set while the leader refuses new orders, see `degraded`
+    pub static ref DEGRADED_GAUGE: IntGauge =
+        IntGauge::new("degraded", "1 while new orders are refused to let the backlog drain").unwrap();
+
+    /// Gauge for whether the node is ready to take traffic, see `readiness`
+    pub static ref READY_GAUGE: IntGauge =
+        IntGauge::new("ready", "1 while the node is restored, connected and caught up").unwrap();
+
+    /// Counter for tracking orders refused in degraded mode by exceeded threshold
+    pub static ref DEGRADED_REJECTED_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("degraded_rejected_orders", "orders refused in degraded mode"),
+        &["threshold"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the best bid price by tenant and symbol
+    pub static ref SYMBOL_BEST_BID_GAUGE_VEC: GaugeVec = GaugeVec::new(
+        Opts::new("symbol_best_bid", "best bid price by symbol"),
+        &["tenant", "symbol"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the best ask price by tenant and symbol
+    pub static ref SYMBOL_BEST_ASK_GAUGE_VEC: GaugeVec = GaugeVec::new(
+        Opts::new("symbol_best_ask", "best ask price by symbol"),
+        &["tenant", "symbol"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the bid-ask spread by tenant and symbol
+    pub static ref SYMBOL_SPREAD_GAUGE_VEC: GaugeVec = GaugeVec::new(
+        Opts::new("symbol_spread", "bid-ask spread by symbol"),
+        &["tenant", "symbol"]
+    )
+    .unwrap();
+
+    /// Histogram for tracking the time the state machine takes to apply a batch of entries
+    pub static ref RAFT_APPLY_HISTOGRAM: Histogram = Histogram::with_opts(
+        HistogramOpts::new("raft_apply_seconds", "state machine apply latency per batch")
+            .buckets(buckets("raft_apply_seconds"))
+    )
+    .unwrap();
+
+    /// Histogram for tracking the number of entries the state machine applies as one batch
+    pub static ref RAFT_APPLY_BATCH_SIZE_HISTOGRAM: Histogram = Histogram::with_opts(
+        HistogramOpts::new("raft_apply_batch_size", "entries applied per batch")
+            .buckets(exponential_buckets(1.0, 2.0, 14).unwrap())
+    )
+    .unwrap();
+
+    /// Counter for tracking malformed committed entries the state machine skipped
+    pub static ref RAFT_APPLY_REJECTED_COUNTER: IntCounter =
+        IntCounter::new("raft_apply_rejected", "malformed committed entries skipped")
+            .unwrap();
+
+    /// Histogram for tracking the delay between an entry being committed and applied
+    pub static ref RAFT_COMMIT_TO_APPLY_HISTOGRAM: Histogram = Histogram::with_opts(
+        HistogramOpts::new("raft_commit_to_apply_seconds", "entry commit to apply delay")
+            .buckets(buckets("raft_commit_to_apply_seconds"))
+    )
+    .unwrap();
+
+    /// Histogram for tracking the time taken to write new entries to the raft log
+    pub static ref RAFT_LOG_APPEND_HISTOGRAM: Histogram = Histogram::with_opts(
+        HistogramOpts::new("raft_log_append_seconds", "raft log append latency")
+            .buckets(buckets("raft_log_append_seconds"))
+    )
+    .unwrap();
+
+    /// Gauge for tracking the raft commit index
+    pub static ref RAFT_COMMIT_INDEX_GAUGE: IntGauge =
+        IntGauge::new("raft_commit_index", "raft commit index").unwrap();
+
+    /// Gauge for tracking the raft applied index
+    pub static ref RAFT_APPLIED_INDEX_GAUGE: IntGauge =
+        IntGauge::new("raft_applied_index", "raft applied index").unwrap();
+
+    /// Gauge for tracking the raft leader known to this node, 0 if there is none
+    pub static ref RAFT_LEADER_ID_GAUGE: IntGauge =
+        IntGauge::new("raft_leader_id", "raft leader known to this node").unwrap();
+
+    /// Gauge for tracking the learners in the raft configuration known to this node
+    pub static ref RAFT_LEARNERS_GAUGE: IntGauge =
+        IntGauge::new("raft_learners", "learners replicating without a vote").unwrap();
+
+    /// Counter for tracking the learners the leader proposed to promote to voter
+    pub static ref RAFT_LEARNER_PROMOTIONS_COUNTER: IntCounter =
+        IntCounter::new("raft_learner_promotions", "learner promotions proposed by the leader")
+            .unwrap();
+
+    /// Gauge for tracking the entries kept before the last snapshot for lagging followers
+    pub static ref RAFT_RETAINED_ENTRIES_GAUGE: IntGauge = IntGauge::new(
+        "raft_retained_entries",
+        "entries kept before the last snapshot for lagging followers"
+    )
+    .unwrap();
+
+    /// Gauge for tracking committed entries not yet applied (commit index - applied index)
+    pub static ref RAFT_APPLY_LAG_GAUGE: IntGauge =
+        IntGauge::new("raft_apply_lag", "committed entries not yet applied").unwrap();
+
+    /// Gauge for tracking the applied index of the last state checksum
+    pub static ref STATE_CHECKSUM_INDEX_GAUGE: IntGauge =
+        IntGauge::new("state_checksum_index", "applied index of the last state checksum").unwrap();
+
+    /// Counter for tracking state checksums that differ from the leader's, by follower
+    pub static ref STATE_DIVERGENCE_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("state_divergence_counter", "state checksum mismatches by follower"),
+        &["node"]
+    )
+    .unwrap();
+
+    /// Counter for tracking order book invariant violations found by the audit
+    pub static ref BOOK_AUDIT_VIOLATION_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("book_audit_violations", "order book invariant violations found by the audit"),
+        &["tenant", "symbol", "invariant"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking order books whose last audit found violations
+    pub static ref BOOK_AUDIT_FAILING_GAUGE_VEC: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("book_audit_failing", "1 if the last audit of an order book found violations"),
+        &["tenant", "symbol"]
+    )
+    .unwrap();
+
+    /// Gauge set on the leader while the last checksum of a follower mismatched
+    pub static ref STATE_DIVERGENT_GAUGE_VEC: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("state_divergent", "1 if the last state checksum of a follower mismatched"),
+        &["node"]
+    )
+    .unwrap();
+
+    /// Gauge set while this node refuses reads after a checksum mismatch
+    pub static ref STATE_FENCED_GAUGE: IntGauge =
+        IntGauge::new("state_fenced", "1 while reads are fenced after a state checksum mismatch").unwrap();
+
+    /// Gauge set to the index of the entry the state machine failed at, see `state_failure`
+    pub static ref STATE_MACHINE_FAILED_GAUGE: IntGauge =
+        IntGauge::new("state_machine_failed", "index of the entry applying panicked at, 0 if none").unwrap();
+
+    /// Gauge for tracking the clients subscribed to cluster events
+    pub static ref CLUSTER_EVENT_SUBSCRIBERS_GAUGE: IntGauge =
+        IntGauge::new("cluster_event_subscribers", "clients subscribed to cluster events").unwrap();
+
+    /// Gauge for tracking the clients subscribed to depth updates
+    pub static ref DEPTH_SUBSCRIBERS_GAUGE: IntGauge =
+        IntGauge::new("depth_subscribers", "clients subscribed to depth updates").unwrap();
+
+    /// Gauge for tracking the consumers subscribed to the change feed
+    pub static ref CHANGE_FEED_SUBSCRIBERS_GAUGE: IntGauge =
+        IntGauge::new("change_feed_subscribers", "consumers subscribed to the change feed").unwrap();
+
+    /// Gauge for tracking the consumers subscribed to the drop copy feed
+    pub static ref DROP_COPY_SUBSCRIBERS_GAUGE: IntGauge =
+        IntGauge::new("drop_copy_subscribers", "consumers subscribed to the drop copy feed").unwrap();
+
+    /// Gauge for tracking the last raft index written by each projection
+    pub static ref PROJECTION_CURSOR_GAUGE_VEC: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("projection_cursor", "last raft index written by projection"),
+        &["projection"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the raft indexes applied but not yet written by each projection
+    pub static ref PROJECTION_LAG_GAUGE_VEC: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("projection_lag", "raft indexes applied but not written by projection"),
+        &["projection"]
+    )
+    .unwrap();
+
+    /// Counter for tracking webhook notifications delivered and given up
+    pub static ref WEBHOOK_DELIVERY_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("webhook_delivery_counter", "webhook notifications by outcome"),
+        &["webhook", "outcome"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking snapshot operations in progress, by kind and peer
+    pub static ref SNAPSHOT_IN_PROGRESS_GAUGE_VEC: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("snapshot_in_progress", "1 while a snapshot save, send or install runs"),
+        &["kind", "peer"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the size of the snapshot of an operation, by kind and peer
+    pub static ref SNAPSHOT_BYTES_GAUGE_VEC: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("snapshot_bytes", "size of the snapshot saved, sent or installed"),
+        &["kind", "peer"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the bytes of a snapshot written or sent so far, by kind and peer
+    pub static ref SNAPSHOT_DONE_BYTES_GAUGE_VEC: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("snapshot_done_bytes", "bytes of the snapshot written or sent in the current step"),
+        &["kind", "peer"]
+    )
+    .unwrap();
+
+    /// Counter for tracking failed snapshot operations, by kind
+    pub static ref SNAPSHOT_FAILURES_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("snapshot_failures", "snapshot saves, sends and installs that failed"),
+        &["kind"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the duration of the last successful snapshot operation, by kind
+    pub static ref SNAPSHOT_LAST_DURATION_GAUGE_VEC: GaugeVec = GaugeVec::new(
+        Opts::new("snapshot_last_duration_seconds", "duration of the last successful snapshot operation"),
+        &["kind"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the number of dead letters not replayed yet
+    pub static ref DEAD_LETTERS_GAUGE: IntGauge =
+        IntGauge::new("dead_letters", "events given up by publishers and not replayed").unwrap();
+
+    /// Gauge for tracking the sequence of the last message of the multicast feed
+    pub static ref MULTICAST_SEQUENCE_GAUGE: IntGauge =
+        IntGauge::new("multicast_sequence", "last message sent on the multicast feed").unwrap();
+
+    /// Gauge for tracking the number of queued items by channel
+    pub static ref CHANNEL_DEPTH_GAUGE_VEC: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("channel_depth", "queued items by channel"),
+        &["channel"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the capacity by channel
+    pub static ref CHANNEL_CAPACITY_GAUGE_VEC: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("channel_capacity", "capacity by channel"),
+        &["channel"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the largest depth seen by channel
+    pub static ref CHANNEL_MAX_DEPTH_GAUGE_VEC: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("channel_max_depth", "largest depth seen by channel"),
+        &["channel"]
+    )
+    .unwrap();
+
+    /// Counter for tracking how often a channel crossed its high-water mark
+    pub static ref CHANNEL_HIGH_WATER_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("channel_high_water_counter", "high-water mark crossings by channel"),
+        &["channel"]
+    )
+    .unwrap();
+
+    /// Counter for tracking items dropped because a channel was full
+    pub static ref CHANNEL_DROPPED_COUNTER_VEC: CounterVec = CounterVec::new(
+        Opts::new("channel_dropped_counter", "items dropped on full channel"),
+        &["channel"]
+    )
+    .unwrap();
+
+    /// Channels sampled by `sample_channels`
+    static ref WATCHED_CHANNELS: Mutex<Vec<WatchedChannel>> = Mutex::new(Vec::new());
+
     /// Histogram for tracking method execution times
     pub static ref METHOD_HISTOGRAM_VEC: HistogramVec = HistogramVec::new(
-        HistogramOpts::new("method_cost", "method cost"),
+        HistogramOpts::new("method_cost", "method cost").buckets(buckets("method_cost")),
         &["method"]
     )
     .unwrap();
 }
 
+/// Returns the bucket boundaries of a latency histogram
+///
+/// Boundaries configured under `histogram_buckets` for the metric name take
+/// precedence; otherwise, or if they are invalid, exponential buckets from 10µs
+/// to about 5s are used, matching the microsecond scale of matching and apply
+/// latencies.
+///
+/// # Arguments
+///
+/// * `name` - Name of the histogram
+pub fn buckets(name: &str) -> Vec<f64> {
+    config::instance()
+        .lock()
+        .unwrap()
+        .histogram_buckets
+        .get(name)
+        .filter(|buckets| valid_buckets(buckets))
+        .cloned()
+        .unwrap_or_else(|| exponential_buckets(0.00001, 2.0, 20).unwrap())
+}
+
+/// Checks that histogram boundaries are non-empty, finite and strictly increasing
+fn valid_buckets(buckets: &[f64]) -> bool {
+    !buckets.is_empty()
+        && buckets.iter().all(|b| b.is_finite())
+        && buckets.windows(2).all(|w| w[0] < w[1])
+}
+
+/// Histograms whose buckets can be configured under `histogram_buckets`
+const CONFIGURABLE_HISTOGRAMS: [&str; 4] = [
+    "method_cost",
+    "raft_apply_seconds",
+    "raft_commit_to_apply_seconds",
+    "raft_log_append_seconds",
+];
+
 /// Initializes the metrics registry
 ///
 /// Registers all metric collectors with the global registry
 pub fn init_registry() {
+    let configured = config::instance().lock().unwrap().histogram_buckets.clone();
+    for (name, boundaries) in configured {
+        if !CONFIGURABLE_HISTOGRAMS.contains(&name.as_str()) {
+            log::warn!(
+                "histogram_buckets configured for unknown histogram {}",
+                name
+            );
+        } else if buckets(&name) != boundaries {
+            log::warn!(
+                "ignoring buckets {:?} of histogram {}, boundaries must be finite and increasing",
+                boundaries,
+                name
+            );
+        }
+    }
     let _ = REGISTRY_INSTANCE.register(Box::new(REQ_COUNTER_VEC.clone()));
     let _ = REGISTRY_INSTANCE.register(Box::new(METHOD_HISTOGRAM_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(TENANT_REQ_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SYMBOL_ORDER_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SYMBOL_CANCEL_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SYMBOL_TRADE_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SYMBOL_BASE_VOLUME_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SYMBOL_QUOTE_VOLUME_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(NOTIONAL_VOLUME_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SYMBOL_RESTING_ORDERS_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SYMBOL_PRICE_LEVELS_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SYMBOL_BOOK_MEMORY_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(ENGINE_MEMORY_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(ENGINE_MEMORY_TOTAL_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(DEGRADED_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(DEGRADED_REJECTED_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(READY_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(MEMORY_REJECTED_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SYMBOL_BEST_BID_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SYMBOL_BEST_ASK_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SYMBOL_SPREAD_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(RAFT_APPLY_HISTOGRAM.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(RAFT_APPLY_BATCH_SIZE_HISTOGRAM.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(RAFT_APPLY_REJECTED_COUNTER.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(RAFT_COMMIT_TO_APPLY_HISTOGRAM.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(RAFT_LOG_APPEND_HISTOGRAM.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(RAFT_COMMIT_INDEX_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(RAFT_APPLIED_INDEX_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(RAFT_APPLY_LAG_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(RAFT_LEADER_ID_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(RAFT_LEARNERS_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(RAFT_LEARNER_PROMOTIONS_COUNTER.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(RAFT_RETAINED_ENTRIES_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(STATE_CHECKSUM_INDEX_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(STATE_DIVERGENCE_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(STATE_DIVERGENT_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(BOOK_AUDIT_VIOLATION_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(BOOK_AUDIT_FAILING_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(STATE_FENCED_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(STATE_MACHINE_FAILED_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(CLUSTER_EVENT_SUBSCRIBERS_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(DROP_COPY_SUBSCRIBERS_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(DEPTH_SUBSCRIBERS_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(CHANGE_FEED_SUBSCRIBERS_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(PROJECTION_CURSOR_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(PROJECTION_LAG_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(WEBHOOK_DELIVERY_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SNAPSHOT_IN_PROGRESS_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SNAPSHOT_BYTES_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SNAPSHOT_DONE_BYTES_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SNAPSHOT_FAILURES_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(SNAPSHOT_LAST_DURATION_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(DEAD_LETTERS_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(MULTICAST_SEQUENCE_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(CHANNEL_DEPTH_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(CHANNEL_CAPACITY_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(CHANNEL_MAX_DEPTH_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(CHANNEL_HIGH_WATER_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(CHANNEL_DROPPED_COUNTER_VEC.clone()));
+}
+
+/// Registers a channel with the saturation sampler
+///
+/// Only a weak handle is kept, so watching never keeps a channel open. A channel
+/// registered under an existing name replaces the previous one.
+///
+/// # Arguments
+///
+/// * `name` - Name of the channel, used as metric label
+/// * `sender` - Sending half of the channel
+pub fn watch_channel<T: Send + 'static>(name: &str, sender: &Sender<T>) {
+    let weak = sender.downgrade();
+    let probe: ChannelProbe = Box::new(move || {
+        let sender = weak.upgrade()?;
+        Some((
+            sender.max_capacity() - sender.capacity(),
+            sender.max_capacity(),
+        ))
+    });
+    let mut channels = WATCHED_CHANNELS.lock().unwrap();
+    channels.retain(|c| c.name != name);
+    channels.push(WatchedChannel {
+        name: name.to_string(),
+        probe,
+        saturated: false,
+    });
+}
+
+/// Samples the depth of all watched channels
+///
+/// Closed channels are unregistered and their gauges removed.
+pub fn sample_channels() {
+    let mut channels = WATCHED_CHANNELS.lock().unwrap();
+    channels.retain_mut(|channel| {
+        let labels = [channel.name.as_str()];
+        let (depth, capacity) = match (channel.probe)() {
+            Some(state) => state,
+            None => {
+                let _ = CHANNEL_DEPTH_GAUGE_VEC.remove_label_values(&labels);
+                let _ = CHANNEL_CAPACITY_GAUGE_VEC.remove_label_values(&labels);
+                return false;
+            }
+        };
+        CHANNEL_DEPTH_GAUGE_VEC
+            .with_label_values(&labels)
+            .set(depth as i64);
+        CHANNEL_CAPACITY_GAUGE_VEC
+            .with_label_values(&labels)
+            .set(capacity as i64);
+        let max_depth = CHANNEL_MAX_DEPTH_GAUGE_VEC.with_label_values(&labels);
+        if depth as i64 > max_depth.get() {
+            max_depth.set(depth as i64);
+        }
+        let saturated = depth as f64 >= capacity as f64 * CHANNEL_HIGH_WATER_RATIO;
+        if saturated && !channel.saturated {
+            CHANNEL_HIGH_WATER_COUNTER_VEC
+                .with_label_values(&labels)
+                .inc();
+        }
+        channel.saturated = saturated;
+        true
+    });
+}
+
+/// Updates the raft progress gauges
+///
+/// # Arguments
+///
+/// * `committed` - Current commit index
+/// * `applied` - Current applied index
+/// * `leader_id` - Leader known to this node, 0 if there is none
+pub fn record_raft_progress(committed: u64, applied: u64, leader_id: u64) {
+    RAFT_LEADER_ID_GAUGE.set(leader_id as i64);
+    RAFT_COMMIT_INDEX_GAUGE.set(committed as i64);
+    RAFT_APPLIED_INDEX_GAUGE.set(applied as i64);
+    RAFT_APPLY_LAG_GAUGE.set(committed.saturating_sub(applied) as i64);
+}
+
+/// Orders, cancels and trades of one symbol counted by an `OrderTally`
+#[derive(Debug, Default, Clone)]
+struct SymbolTally {
+    /// Quote currency of the symbol, empty until an order is counted
+    quote: String,
+    /// Orders accepted by the book
+    accepted: u64,
+    /// Orders rejected
+    rejected: u64,
+    /// Orders canceled
+    canceled: u64,
+    /// Trades generated
+    trades: u64,
+    /// Traded base volume
+    base_volume: f64,
+    /// Traded notional in quote currency
+    quote_volume: f64,
+}
+
+/// Order, cancel and trade counts of an apply batch
+///
+/// Counted in plain maps while the batch is applied and added to the labelled
+/// counters by `flush`, which looks up each counter once per batch rather than
+/// once per command.
+#[derive(Debug, Default, Clone)]
+pub struct OrderTally {
+    /// Counts by tenant and symbol
+    tenants: HashMap<String, HashMap<String, SymbolTally>>,
+}
+
+impl OrderTally {
+    /// Returns the counts of a symbol, allocating only for the first command of
+    /// the symbol in a batch
+    fn symbol(&mut self, tenant: &str, symbol: &str) -> &mut SymbolTally {
+        if !self.tenants.contains_key(tenant) {
+            self.tenants.insert(tenant.to_string(), HashMap::new());
+        }
+        let symbols = self.tenants.get_mut(tenant).unwrap();
+        if !symbols.contains_key(symbol) {
+            symbols.insert(symbol.to_string(), SymbolTally::default());
+        }
+        symbols.get_mut(symbol).unwrap()
+    }
+
+    /// Counts the outcome of an applied order
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - Tenant the order belongs to
+    /// * `symbol` - Symbol the order was placed on, None if it is not listed
+    /// * `result` - Trades generated by the order, or the rejection reason
+    pub fn order(
+        &mut self,
+        tenant: &str,
+        symbol: Option<&Symbol>,
+        result: &Result<Vec<Trade>, String>,
+    ) {
+        // Keep client supplied names of unlisted symbols out of the label set
+        let (symbol, quote) = match symbol {
+            Some(symbol) => (symbol.name.as_str(), symbol.quote_currency.as_str()),
+            None => ("unknown", "unknown"),
+        };
+        let tally = self.symbol(tenant, symbol);
+        if tally.quote.is_empty() {
+            tally.quote = quote.to_string();
+        }
+        let trades = match result {
+            Ok(trades) => trades,
+            Err(_) => {
+                tally.rejected += 1;
+                return;
+            }
+        };
+        tally.accepted += 1;
+        tally.trades += trades.len() as u64;
+        tally.base_volume += trades
+            .iter()
+            .filter_map(|t| t.quantity.to_f64())
+            .sum::<f64>();
+        tally.quote_volume += trades
+            .iter()
+            .filter_map(|t| t.total_amount()?.to_f64())
+            .sum::<f64>();
+    }
+
+    /// Counts a canceled order
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - Tenant the order belongs to
+    /// * `symbol` - Symbol the order was resting on
+    pub fn cancel(&mut self, tenant: &str, symbol: &str) {
+        self.symbol(tenant, symbol).canceled += 1;
+    }
+
+    /// Adds the counts to the order, cancel, trade and volume counters and starts
+    /// counting anew
+    pub fn flush(&mut self) {
+        let mut notional: HashMap<(String, String), f64> = HashMap::new();
+        let counts = self
+            .tenants
+            .drain()
+            .flat_map(|(tenant, symbols)| symbols.into_iter().map(move |s| (tenant.clone(), s)));
+        for (tenant, (symbol, tally)) in counts {
+            let labels = [tenant.as_str(), symbol.as_str()];
+            if tally.accepted > 0 {
+                SYMBOL_ORDER_COUNTER_VEC
+                    .with_label_values(&[&tenant, &symbol, "accepted"])
+                    .inc_by(tally.accepted as f64);
+            }
+            if tally.rejected > 0 {
+                SYMBOL_ORDER_COUNTER_VEC
+                    .with_label_values(&[&tenant, &symbol, "rejected"])
+                    .inc_by(tally.rejected as f64);
+            }
+            if tally.canceled > 0 {
+                SYMBOL_CANCEL_COUNTER_VEC
+                    .with_label_values(&labels)
+                    .inc_by(tally.canceled as f64);
+            }
+            if tally.trades == 0 {
+                continue;
+            }
+            SYMBOL_TRADE_COUNTER_VEC
+                .with_label_values(&labels)
+                .inc_by(tally.trades as f64);
+            SYMBOL_BASE_VOLUME_COUNTER_VEC
+                .with_label_values(&labels)
+                .inc_by(tally.base_volume);
+            SYMBOL_QUOTE_VOLUME_COUNTER_VEC
+                .with_label_values(&labels)
+                .inc_by(tally.quote_volume);
+            *notional.entry((tenant, tally.quote)).or_default() += tally.quote_volume;
+        }
+        for ((tenant, quote), volume) in notional {
+            NOTIONAL_VOLUME_COUNTER_VEC
+                .with_label_values(&[&tenant, &quote])
+                .inc_by(volume);
+        }
+    }
+}
+
+/// Updates the order book gauges of a symbol
+///
+/// Gauges of sides without orders are removed rather than reported as zero, and
+/// passing no order book drops all gauges of a symbol, e.g. after delisting. The
+/// memory gauge is kept by `memory`.
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the symbol belongs to
+/// * `symbol` - Symbol of the order book
+/// * `orderbook` - Current order book, None if the symbol is no longer traded
+pub fn record_orderbook(tenant: &str, symbol: &str, orderbook: Option<&OrderBook>) {
+    let labels = [tenant, symbol];
+    let set_or_remove = |gauge: &GaugeVec, value: Option<f64>| match value {
+        Some(value) => gauge.with_label_values(&labels).set(value),
+        None => {
+            let _ = gauge.remove_label_values(&labels);
+        }
+    };
+    set_or_remove(
+        &SYMBOL_RESTING_ORDERS_GAUGE_VEC,
+        orderbook.map(|book| book.order_count() as f64),
+    );
+    set_or_remove(
+        &SYMBOL_PRICE_LEVELS_GAUGE_VEC,
+        orderbook.map(|book| book.level_count() as f64),
+    );
+    set_or_remove(
+        &SYMBOL_BEST_BID_GAUGE_VEC,
+        orderbook.and_then(|book| book.get_best_bid()?.to_f64()),
+    );
+    set_or_remove(
+        &SYMBOL_BEST_ASK_GAUGE_VEC,
+        orderbook.and_then(|book| book.get_best_ask()?.to_f64()),
+    );
+    set_or_remove(
+        &SYMBOL_SPREAD_GAUGE_VEC,
+        orderbook.and_then(|book| book.get_spread()?.to_f64()),
+    );
 }
 
 /// Records metrics for an async operation
@@ -67,3 +810,71 @@ where
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn tally_adds_a_batch_to_the_counters() {
+        let tenant = "tally-test";
+        let symbol = Symbol {
+            name: "BTCUSDT".to_string(),
+            quote_currency: "USDT".to_string(),
+            ..Default::default()
+        };
+        let trade = |quantity| {
+            Trade::new(
+                String::new(),
+                symbol.name.clone(),
+                dec!(100),
+                quantity,
+                String::new(),
+                String::new(),
+                0,
+            )
+        };
+        let mut tally = OrderTally::default();
+        // A cancel counted first must not lose the quote of the symbol
+        tally.cancel(tenant, "BTCUSDT");
+        tally.order(
+            tenant,
+            Some(&symbol),
+            &Ok(vec![trade(dec!(1)), trade(dec!(2))]),
+        );
+        tally.order(tenant, Some(&symbol), &Ok(Vec::new()));
+        tally.order(tenant, None, &Err("unknown symbol".to_string()));
+        tally.flush();
+        tally.flush();
+
+        let orders = |symbol, result| {
+            SYMBOL_ORDER_COUNTER_VEC
+                .with_label_values(&[tenant, symbol, result])
+                .get()
+        };
+        assert_eq!(orders("BTCUSDT", "accepted"), 2.0);
+        assert_eq!(orders("unknown", "rejected"), 1.0);
+        let labels = [tenant, "BTCUSDT"];
+        assert_eq!(
+            SYMBOL_CANCEL_COUNTER_VEC.with_label_values(&labels).get(),
+            1.0
+        );
+        assert_eq!(
+            SYMBOL_TRADE_COUNTER_VEC.with_label_values(&labels).get(),
+            2.0
+        );
+        assert_eq!(
+            SYMBOL_BASE_VOLUME_COUNTER_VEC
+                .with_label_values(&labels)
+                .get(),
+            3.0
+        );
+        assert_eq!(
+            NOTIONAL_VOLUME_COUNTER_VEC
+                .with_label_values(&[tenant, "USDT"])
+                .get(),
+            300.0
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! HTTP endpoint for metrics scraping and health checks
+//!
+//! Serves `/metrics` in the Prometheus text format, `/healthz` with a JSON
+//! summary of the node, `/readyz` with its readiness (see `readiness`) and
+//! `/debug/heap` with the allocator statistics, plus
+//! `/debug/heap/profile` in builds with heap profiling (see `allocator`). All
+//! paths require the configured basic auth or bearer token, if any; every other
+//! path answers 404.
+
+use crate::config::{self, MetricsAuthConfig};
+use crate::{allocator, divergence, metrics, readiness, snapshot_progress, state_failure, version};
+use base64::Engine;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use prometheus::{Encoder, TextEncoder};
+use serde_json::json;
+use std::ffi::CString;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Settings shared by all requests to the endpoint
+struct EndpointConfig {
+    /// ID of this node
+    node_id: u64,
+    /// Directory the raft log and snapshots are stored in
+    base_path: String,
+    /// Required authentication, None if the endpoint is open
+    auth: Option<MetricsAuthConfig>,
+}
+
+/// Starts serving the metrics endpoint on the configured address
+pub fn start() {
+    let (addr, endpoint): (SocketAddr, _) = {
+        let config = config::instance().lock().unwrap();
+        (
+            config.metrics_addr.as_str().parse().unwrap(),
+            Arc::new(EndpointConfig {
+                node_id: config.id,
+                base_path: config.base_path.clone(),
+                auth: config.metrics_auth.clone(),
+            }),
+        )
+    };
+    let make_svc = make_service_fn(move |_| {
+        let endpoint = endpoint.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |request: Request<Body>| {
+                let endpoint = endpoint.clone();
+                async move { Ok::<_, hyper::Error>(handle(&endpoint, request)) }
+            }))
+        }
+    });
+    let server = hyper::Server::bind(&addr).serve(make_svc);
+    tokio::spawn(async move {
+        tokio::pin!(server);
+        server.await.unwrap()
+    });
+    log::info!("metrics server started on {}", addr);
+}
+
+/// Routes a request to its handler after checking authentication
+fn handle(endpoint: &EndpointConfig, request: Request<Body>) -> Response<Body> {
+    if !authorized(endpoint.auth.as_ref(), &request) {
+        let challenge = match endpoint.auth {
+            Some(MetricsAuthConfig::Basic { .. }) => "Basic realm=\"metrics\"",
+            _ => "Bearer",
+        };
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(WWW_AUTHENTICATE, challenge)
+            .body(Body::empty())
+            .unwrap();
+    }
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => metrics_response(),
+        (&Method::GET, "/healthz") => health_response(endpoint),
+        (&Method::GET, "/readyz") => ready_response(),
+        (&Method::GET, "/debug/heap") => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(allocator::heap_stats().to_string()))
+            .unwrap(),
+        #[cfg(feature = "heap-profiling")]
+        (&Method::GET, "/debug/heap/profile") => heap_profile_response(),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    }
+}
+
+/// Checks the `Authorization` header against the configured credentials
+///
+/// # Arguments
+///
+/// * `auth` - Required authentication, None accepts every request
+/// * `request` - Incoming request
+fn authorized(auth: Option<&MetricsAuthConfig>, request: &Request<Body>) -> bool {
+    let auth = match auth {
+        Some(auth) => auth,
+        None => return true,
+    };
+    let header = match request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(header) => header,
+        None => return false,
+    };
+    match auth {
+        MetricsAuthConfig::Basic { username, password } => header
+            .strip_prefix("Basic ")
+            .and_then(|encoded| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(encoded.trim())
+                    .ok()
+            })
+            .map(|decoded| {
+                let expected = format!("{}:{}", username, password);
+                constant_time_eq(&decoded, expected.as_bytes())
+            })
+            .unwrap_or(false),
+        MetricsAuthConfig::Bearer { token } => header
+            .strip_prefix("Bearer ")
+            .map(|presented| constant_time_eq(presented.trim().as_bytes(), token.as_bytes()))
+            .unwrap_or(false),
+    }
+}
+
+/// Compares two byte strings without exiting early on the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Encodes the metrics registry in the Prometheus text format
+fn metrics_response() -> Response<Body> {
+    let encoder = TextEncoder::new();
+    let metric_families = metrics::REGISTRY_INSTANCE.gather();
+    let mut buffer = Vec::new();
+    encoder.encode(&metric_families, &mut buffer).unwrap();
+    Response::builder()
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap()
+}
+
+/// Dumps a heap profile, 503 if profiling was not switched on at startup
+#[cfg(feature = "heap-profiling")]
+fn heap_profile_response() -> Response<Body> {
+    match allocator::heap_profile() {
+        Ok(profile) => Response::builder()
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(Body::from(profile))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::from(e))
+            .unwrap(),
+    }
+}
+
+/// Summarizes the health of the node
+///
+/// Answers 503 while the node knows of no leader, its reads are fenced after
+/// a state divergence or its state machine failed, so the endpoint can be used directly as a load balancer
+/// health check.
+fn health_response(endpoint: &EndpointConfig) -> Response<Body> {
+    let leader_id = metrics::RAFT_LEADER_ID_GAUGE.get() as u64;
+    let body = json!({
+        "node_id": endpoint.node_id,
+        "version": version::VERSION,
+        "allocator": allocator::NAME,
+        "leader_id": leader_id,
+        "is_leader": leader_id != 0 && leader_id == endpoint.node_id,
+        "commit_index": metrics::RAFT_COMMIT_INDEX_GAUGE.get(),
+        "applied_index": metrics::RAFT_APPLIED_INDEX_GAUGE.get(),
+        "apply_lag": metrics::RAFT_APPLY_LAG_GAUGE.get(),
+        "disk_free_bytes": disk_free(&endpoint.base_path),
+        "state_checksum_index": metrics::STATE_CHECKSUM_INDEX_GAUGE.get(),
+        "divergent": divergence::is_divergent(),
+        "state_machine_failed_at": state_failure::failed_at(),
+        "ready": readiness::report().ready(),
+        "snapshots": snapshot_progress::status(),
+    });
+    let status =
+        if leader_id == 0 || divergence::is_fenced() || state_failure::failed_at().is_some() {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::OK
+        };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Reports whether the node is ready to take traffic
+///
+/// Answers 503 until the node is restored, connected and caught up, so the
+/// endpoint can be used directly as an orchestrator readiness probe.
+fn ready_response() -> Response<Body> {
+    let report = readiness::report();
+    let body = json!({
+        "ready": report.ready(),
+        "restored": report.restored,
+        "replayed": report.replayed,
+        "peers_connected": report.peers_connected,
+        "caught_up": report.caught_up,
+        "leader_id": report.leader_id,
+        "lag": report.lag,
+    });
+    let status = if report.ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Queries the space available to the process on the filesystem of a path
+///
+/// # Returns
+///
+/// Returns the free space in bytes, None if the filesystem cannot be queried
+fn disk_free(path: &str) -> Option<u64> {
+    let path = CString::new(path).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: path is NUL terminated and statvfs only writes into the provided struct
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
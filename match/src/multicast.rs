@@ -0,0 +1,430 @@
+//! Multicast market data feed
+//!
+//! With a `[multicast]` section the node sends trades and the depth of changed
+//! books as UDP datagrams to a multicast group, so subscribers on the same
+//! network get market data without a gRPC stream each. Every datagram carries
+//! one message after a fixed header, all integers little-endian:
+//!
+//! | Field    | Size | Content                                               |
+//! |----------|------|-------------------------------------------------------|
+//! | session  | 8    | Start of the feed in ms since the epoch               |
+//! | sequence | 8    | Number of the message, from 1, per session            |
+//! | kind     | 1    | 0 heartbeat, 1 trade, 2 depth                         |
+//! | length   | 2    | Length of the payload                                 |
+//!
+//! Strings are a u16 length and UTF-8 bytes, decimals the 16 bytes of
+//! `rust_decimal::Decimal::serialize`. A trade is the raft index, the engine time
+//! in seconds, tenant, symbol, price, quantity, the taker side (0 buy, 1 sell) and
+//! the buyer and seller order IDs. A depth message is the raft index of the book,
+//! tenant, symbol, the number of bid and of ask levels (u8 each), then price and
+//! quantity of each level, bids highest first, asks lowest first; a removed
+//! symbol is sent with no levels.
+//!
+//! Heartbeats are sent after a second without messages and carry the sequence
+//! of the next message without using it up, so a subscriber notices lost
+//! messages at the end of a burst too. Lost messages are recovered over TCP from
+//! `retransmit_addr`, which keeps the last `retransmit_buffer` messages: a request
+//! is the first sequence (u64) and a count (u32), answered with each message
+//! still kept as a u16 length and the datagram, then the connection is closed. A
+//! new session, seen by its number, restarts the sequence; subscribers then
+//! take the depth of each book afresh.
+//!
+//! Each node publishes its own feed of what it applies, the feed is fed after
+//! every apply batch through an unbounded queue and sent from its own task.
+
+use crate::config::{self, MulticastConfig};
+use crate::engine::entry::{OrderSide, Trade};
+use crate::engine::history::{OrderChange, OrderEvent};
+use crate::{metrics, read_view};
+use once_cell::sync::OnceCell;
+use rust_decimal::Decimal;
+use std::collections::{BTreeSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// Time without messages after which a heartbeat is sent
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most price levels per side in a depth message, keeps it within one datagram
+const MAX_DEPTH: usize = 20;
+
+/// Most messages sent for one retransmission request
+const MAX_RETRANSMIT: u32 = 10_000;
+
+/// Size of the header of every message
+const HEADER_LEN: usize = 19;
+
+/// Queue of the publisher task, None if the feed is disabled
+static FEED: OnceCell<Option<UnboundedSender<Update>>> = OnceCell::new();
+
+/// Kind of a feed message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageKind {
+    /// No payload, the sequence is that of the next message
+    Heartbeat = 0,
+    /// A trade
+    Trade = 1,
+    /// Best levels of a book
+    Depth = 2,
+}
+
+/// Trades and changed books of an apply batch
+#[derive(Debug, Default)]
+struct Update {
+    /// Raft index, engine time, tenant, taker side and trade, in log order
+    trades: Vec<(u64, u64, String, OrderSide, Trade)>,
+    /// Tenant and symbol of the books changed by the batch
+    books: BTreeSet<(String, String)>,
+}
+
+/// Numbers the messages of a session and keeps the last ones for retransmission
+#[derive(Debug)]
+struct Sequencer {
+    /// Start of the session in ms since the epoch
+    session: u64,
+    /// Sequence of the next message
+    next: u64,
+    /// Messages kept for retransmission, the last one has sequence `next - 1`
+    kept: VecDeque<Arc<Vec<u8>>>,
+    /// Number of messages kept
+    capacity: usize,
+}
+
+impl Sequencer {
+    /// Starts a session
+    fn new(session: u64, capacity: usize) -> Self {
+        Sequencer {
+            session,
+            next: 1,
+            kept: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Builds a datagram
+    fn datagram(&self, sequence: u64, kind: MessageKind, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(HEADER_LEN + payload.len());
+        datagram.extend_from_slice(&self.session.to_le_bytes());
+        datagram.extend_from_slice(&sequence.to_le_bytes());
+        datagram.push(kind as u8);
+        datagram.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    /// Numbers a message and keeps it for retransmission
+    fn message(&mut self, kind: MessageKind, payload: &[u8]) -> Arc<Vec<u8>> {
+        let datagram = Arc::new(self.datagram(self.next, kind, payload));
+        self.next += 1;
+        self.kept.push_back(datagram.clone());
+        if self.kept.len() > self.capacity {
+            self.kept.pop_front();
+        }
+        datagram
+    }
+
+    /// Builds a heartbeat, which uses up no sequence
+    fn heartbeat(&self) -> Vec<u8> {
+        self.datagram(self.next, MessageKind::Heartbeat, &[])
+    }
+
+    /// Returns the kept messages from a sequence on
+    fn replay(&self, from: u64, count: u32) -> Vec<Arc<Vec<u8>>> {
+        let first = self.next - self.kept.len() as u64;
+        let skip = from.saturating_sub(first) as usize;
+        self.kept
+            .iter()
+            .skip(skip)
+            .take(count.min(MAX_RETRANSMIT) as usize)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Appends a string to a payload
+fn put_str(payload: &mut Vec<u8>, value: &str) {
+    payload.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    payload.extend_from_slice(value.as_bytes());
+}
+
+/// Encodes a trade message
+fn trade_payload(
+    index: u64,
+    time: u64,
+    tenant: &str,
+    taker_side: OrderSide,
+    trade: &Trade,
+) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&index.to_le_bytes());
+    payload.extend_from_slice(&time.to_le_bytes());
+    put_str(&mut payload, tenant);
+    put_str(&mut payload, &trade.symbol);
+    payload.extend_from_slice(&trade.price.serialize());
+    payload.extend_from_slice(&trade.quantity.serialize());
+    payload.push(match taker_side {
+        OrderSide::Buy => 0,
+        OrderSide::Sell => 1,
+    });
+    put_str(&mut payload, &trade.buyer_order_id);
+    put_str(&mut payload, &trade.seller_order_id);
+    payload
+}
+
+/// Encodes a depth message
+fn depth_payload(
+    index: u64,
+    tenant: &str,
+    symbol: &str,
+    bids: &[(Decimal, Decimal)],
+    asks: &[(Decimal, Decimal)],
+) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&index.to_le_bytes());
+    put_str(&mut payload, tenant);
+    put_str(&mut payload, symbol);
+    payload.push(bids.len() as u8);
+    payload.push(asks.len() as u8);
+    for (price, quantity) in bids.iter().chain(asks) {
+        payload.extend_from_slice(&price.serialize());
+        payload.extend_from_slice(&quantity.serialize());
+    }
+    payload
+}
+
+/// Collects the trades and changed books of a batch
+fn update(events: &[OrderEvent]) -> Update {
+    let mut update = Update::default();
+    for event in events {
+        let symbol = match &event.change {
+            OrderChange::Placed { order, trades } => {
+                update.trades.extend(trades.iter().map(|trade| {
+                    let tenant = event.tenant.clone();
+                    (event.index, event.time, tenant, order.side, trade.clone())
+                }));
+                &order.symbol
+            }
+            OrderChange::Canceled { symbol, .. }
+            | OrderChange::SymbolRemoved { symbol }
+            | OrderChange::SymbolListed { symbol }
+            | OrderChange::SymbolHalted { symbol, .. }
+            | OrderChange::SymbolResumed { symbol } => symbol,
+        };
+        update.books.insert((event.tenant.clone(), symbol.clone()));
+    }
+    update
+}
+
+/// Starts the feed and its retransmission service if configured
+///
+/// Must be called on the runtime before the raft loop starts applying entries.
+///
+/// # Returns
+///
+/// Returns an error if the feed is misconfigured or its sockets cannot be bound
+pub fn start() -> Result<(), String> {
+    let Some(config) = config::instance().lock().unwrap().multicast.clone() else {
+        let _ = FEED.set(None);
+        return Ok(());
+    };
+    if config.depth == 0 || config.depth > MAX_DEPTH {
+        return Err(format!("multicast depth must be 1 to {}", MAX_DEPTH));
+    }
+    let group: SocketAddr = config
+        .group
+        .parse()
+        .map_err(|e| format!("invalid multicast group {:?}: {}", config.group, e))?;
+    let socket = std::net::UdpSocket::bind(&config.interface)
+        .and_then(|socket| {
+            socket.set_multicast_ttl_v4(config.ttl)?;
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket)
+        })
+        .map_err(|e| {
+            format!(
+                "cannot bind multicast socket to {}: {}",
+                config.interface, e
+            )
+        })?;
+    let session = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    let sequencer = Arc::new(Mutex::new(Sequencer::new(
+        session,
+        config.retransmit_buffer,
+    )));
+    if let Some(addr) = &config.retransmit_addr {
+        let listener = std::net::TcpListener::bind(addr)
+            .and_then(|listener| {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            })
+            .map_err(|e| format!("cannot bind multicast retransmission to {}: {}", addr, e))?;
+        tokio::spawn(serve_retransmissions(listener, sequencer.clone()));
+    }
+    let (sender, receiver) = mpsc::unbounded_channel();
+    log::info!("multicast market data to {} in session {}", group, session);
+    tokio::spawn(publish(group, config.depth, socket, sequencer, receiver));
+    let _ = FEED.set(Some(sender));
+    Ok(())
+}
+
+/// Feeds the trades and changed books of an apply batch to the feed
+///
+/// # Arguments
+///
+/// * `events` - Order events of the batch, in log order
+pub fn write(events: &[OrderEvent]) {
+    let Some(Some(feed)) = FEED.get() else {
+        return;
+    };
+    if !events.is_empty() {
+        let _ = feed.send(update(events));
+    }
+}
+
+/// Sends the messages of the batches fed until the node stops
+///
+/// # Arguments
+///
+/// * `group` - Multicast group the datagrams are sent to
+/// * `depth` - Price levels per side in depth messages
+/// * `socket` - Socket the datagrams are sent from
+/// * `sequencer` - Numbers the messages
+/// * `receiver` - Updates in apply order
+async fn publish(
+    group: SocketAddr,
+    depth: usize,
+    socket: UdpSocket,
+    sequencer: Arc<Mutex<Sequencer>>,
+    mut receiver: UnboundedReceiver<Update>,
+) {
+    loop {
+        let update = match tokio::time::timeout(HEARTBEAT_INTERVAL, receiver.recv()).await {
+            Ok(Some(update)) => update,
+            Ok(None) => return,
+            Err(_) => Update::default(),
+        };
+        let mut messages = Vec::new();
+        for (index, time, tenant, taker_side, trade) in &update.trades {
+            let payload = trade_payload(*index, *time, tenant, *taker_side, trade);
+            messages.push((MessageKind::Trade, payload));
+        }
+        for (tenant, symbol) in &update.books {
+            let payload = match read_view::book(tenant, symbol).await {
+                Some(view) => {
+                    let (bids, asks) = view.orderbook().depth(depth);
+                    depth_payload(view.index, tenant, symbol, &bids, &asks)
+                }
+                None => depth_payload(0, tenant, symbol, &[], &[]),
+            };
+            messages.push((MessageKind::Depth, payload));
+        }
+        let datagrams: Vec<_> = {
+            let mut sequencer = sequencer.lock().unwrap();
+            if messages.is_empty() {
+                vec![Arc::new(sequencer.heartbeat())]
+            } else {
+                let datagrams = messages
+                    .iter()
+                    .map(|(kind, payload)| sequencer.message(*kind, payload))
+                    .collect();
+                metrics::MULTICAST_SEQUENCE_GAUGE.set(sequencer.next as i64 - 1);
+                datagrams
+            }
+        };
+        for datagram in datagrams {
+            // A datagram lost here is recovered like one lost on the network
+            if let Err(e) = socket.send_to(&datagram, group).await {
+                log::warn!("cannot send multicast datagram: {}", e);
+            }
+        }
+    }
+}
+
+/// Answers retransmission requests until the node stops
+async fn serve_retransmissions(listener: TcpListener, sequencer: Arc<Mutex<Sequencer>>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log::warn!("cannot accept multicast retransmission request: {}", e);
+                continue;
+            }
+        };
+        let sequencer = sequencer.clone();
+        tokio::spawn(async move {
+            if let Err(e) = retransmit(stream, sequencer).await {
+                log::debug!("multicast retransmission failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Answers one retransmission request
+async fn retransmit(
+    mut stream: TcpStream,
+    sequencer: Arc<Mutex<Sequencer>>,
+) -> std::io::Result<()> {
+    let from = stream.read_u64_le().await?;
+    let count = stream.read_u32_le().await?;
+    let messages = sequencer.lock().unwrap().replay(from, count);
+    for datagram in messages {
+        stream.write_u16_le(datagram.len() as u16).await?;
+        stream.write_all(&datagram).await?;
+    }
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn messages_are_numbered_and_kept_for_retransmission() {
+        let mut sequencer = Sequencer::new(7, 2);
+        let heartbeat = sequencer.heartbeat();
+        assert_eq!(heartbeat.len(), HEADER_LEN);
+        assert_eq!(heartbeat[..8], 7u64.to_le_bytes());
+        assert_eq!(heartbeat[8..16], 1u64.to_le_bytes());
+
+        let payload = depth_payload(3, "a", "BTCUSDT", &[(dec!(99), dec!(1))], &[]);
+        for _ in 0..3 {
+            sequencer.message(MessageKind::Depth, &payload);
+        }
+        // A heartbeat does not use up the sequence of the next message
+        assert_eq!(sequencer.heartbeat()[8..16], 4u64.to_le_bytes());
+
+        // Message 1 is no longer kept
+        let replayed = sequencer.replay(1, 10);
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(replayed[0][8..16], 2u64.to_le_bytes());
+        assert_eq!(replayed[0][16], MessageKind::Depth as u8);
+        assert_eq!(replayed[0][HEADER_LEN..], payload[..]);
+        assert_eq!(sequencer.replay(3, 10).len(), 1);
+        assert!(sequencer.replay(4, 10).is_empty());
+    }
+
+    #[test]
+    fn depth_messages_carry_both_sides() {
+        let payload = depth_payload(
+            3,
+            "a",
+            "BTC",
+            &[(dec!(99), dec!(1))],
+            &[(dec!(101), dec!(2)), (dec!(102), dec!(1))],
+        );
+        // Index, tenant, symbol and the level counts
+        let levels = 8 + 2 + 1 + 2 + 3 + 2;
+        assert_eq!(payload[levels - 2..levels], [1, 2]);
+        assert_eq!(payload.len(), levels + 3 * 32);
+        let price: [u8; 16] = payload[levels + 32..levels + 48].try_into().unwrap();
+        assert_eq!(Decimal::deserialize(price), dec!(101));
+    }
+}
@@ -0,0 +1,317 @@
+//! Order sessions
+//!
+//! `OrderSession` is a bidirectional stream for clients placing and canceling
+//! many orders: each message on the stream is a place or cancel, answered on
+//! the same stream by an ack once applied or refused, with the status code the
+//! matching unary RPC would return. Messages are proposed as they arrive
+//! without waiting for earlier ones, so acks may come out of order.
+//!
+//! Between the acks the session streams the execution reports of every account
+//! it placed an order for, derived like the drop copy feed, see `drop_copy`, so
+//! fills of resting orders arrive without a separate subscription. The session
+//! keeps track of which of its orders are still open from those reports; when
+//! the stream ends, because the client closed it, the connection dropped or the
+//! session fell behind on reports, those orders are canceled. An order whose
+//! report had not arrived yet when the stream ended is left alone.
+
+use crate::drop_copy::{self, ReportStream};
+use crate::engine::entry::Order;
+use crate::engine::matchengine::{MarketType, MatchCmd, MatchCmdType};
+use crate::match_service::pb::{self, order_session_request, order_session_response};
+use crate::match_service::{
+    check_degraded, check_post_only, market_type, match_order, propose, stamped_envelope,
+};
+use crate::memory;
+use crate::slow_log::RequestTrace;
+use std::collections::{BTreeMap, BTreeSet};
+use tokio::sync::mpsc::Sender;
+use tonic::Streaming;
+
+/// Sender of the responses of a session
+type ResponseSender = Sender<Result<pb::OrderSessionResponse, tonic::Status>>;
+
+/// Orders placed on a session
+#[derive(Debug, Default)]
+struct Orders {
+    /// Market of each order placed, by symbol and order ID
+    placed: BTreeMap<(String, u64), MarketType>,
+    /// Orders placed that are resting or partially filled
+    open: BTreeSet<(String, u64)>,
+}
+
+impl Orders {
+    /// Updates the open orders from an execution report
+    ///
+    /// # Arguments
+    ///
+    /// * `report` - Report of an order of a watched account
+    fn update(&mut self, report: &pb::ExecutionReport) {
+        let Some(state) = &report.order else {
+            return;
+        };
+        let Some(order) = &state.order else {
+            return;
+        };
+        let key = (order.symbol.clone(), order.order_id);
+        if !self.placed.contains_key(&key) {
+            return;
+        }
+        match state.status() {
+            pb::OrderStatus::Filled | pb::OrderStatus::Canceled | pb::OrderStatus::Rejected => {
+                self.open.remove(&key);
+            }
+            pb::OrderStatus::New | pb::OrderStatus::PartiallyFilled => {
+                self.open.insert(key);
+            }
+        }
+    }
+
+    /// Returns the open orders with their markets
+    fn open(&self) -> Vec<(String, u64, MarketType)> {
+        self.open
+            .iter()
+            .filter_map(|key| {
+                let market = self.placed.get(key)?;
+                Some((key.0.clone(), key.1, *market))
+            })
+            .collect()
+    }
+}
+
+/// Serves an order session until its stream ends
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the session is scoped to
+/// * `client_id` - ID of the client, stamped on the commands
+/// * `session` - ID of the session, see `drop_copy::subscribe_session`
+/// * `requests` - Messages from the client
+/// * `reports` - Reports of the accounts the session watches
+/// * `responses` - Acks and reports sent to the client
+pub async fn run(
+    tenant: String,
+    client_id: String,
+    session: u64,
+    mut requests: Streaming<pb::OrderSessionRequest>,
+    mut reports: ReportStream,
+    responses: ResponseSender,
+) {
+    let mut orders = Orders::default();
+    loop {
+        tokio::select! {
+            request = requests.message() => {
+                let request = match request {
+                    Ok(Some(request)) => request,
+                    Ok(None) => break,
+                    Err(e) => {
+                        log::warn!("order session {} failed: {}", session, e);
+                        break;
+                    }
+                };
+                let request_id = if request.request_id.is_empty() {
+                    uuid::Uuid::new_v4().to_string()
+                } else {
+                    request.request_id
+                };
+                match request.message {
+                    Some(order_session_request::Message::Place(place)) => {
+                        if let Some(order) = &place.order {
+                            drop_copy::watch(session, order.account_id);
+                            orders.placed.insert(
+                                (order.symbol.clone(), order.order_id),
+                                market_type(order.market()),
+                            );
+                        }
+                        let (tenant, client_id) = (tenant.clone(), client_id.clone());
+                        let responses = responses.clone();
+                        tokio::spawn(async move {
+                            let result = place_order(&tenant, &client_id, &request_id, place).await;
+                            let _ = responses.send(Ok(ack(request_id, result))).await;
+                        });
+                    }
+                    Some(order_session_request::Message::Cancel(cancel)) => {
+                        let (tenant, client_id) = (tenant.clone(), client_id.clone());
+                        let responses = responses.clone();
+                        tokio::spawn(async move {
+                            let market = market_type(cancel.market());
+                            let cancel = (cancel.symbol, cancel.order_id, market);
+                            let result = cancel_order(&tenant, &client_id, &request_id, cancel).await;
+                            let _ = responses.send(Ok(ack(request_id, result))).await;
+                        });
+                    }
+                    None => {
+                        let status = tonic::Status::invalid_argument("empty message");
+                        let _ = responses.send(Ok(ack(request_id, Err(status)))).await;
+                    }
+                }
+            }
+            report = reports.recv() => {
+                let Some(report) = report else {
+                    break;
+                };
+                let report = match report {
+                    Ok(report) => report,
+                    Err(status) => {
+                        let _ = responses.send(Err(status)).await;
+                        break;
+                    }
+                };
+                orders.update(&report);
+                let response = pb::OrderSessionResponse {
+                    message: Some(order_session_response::Message::Report(report)),
+                };
+                if responses.send(Ok(response)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    drop(reports);
+    let open = orders.open();
+    if !open.is_empty() {
+        log::info!(
+            "order session {} ended, canceling {} orders",
+            session,
+            open.len()
+        );
+    }
+    for (symbol, order_id, market) in open {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        if let Err(e) =
+            cancel_order(&tenant, &client_id, &request_id, (symbol, order_id, market)).await
+        {
+            log::warn!(
+                "order session {} failed to cancel order {}: {}",
+                session,
+                order_id,
+                e
+            );
+        }
+    }
+}
+
+/// Places an order sent on a session, the way `PlaceOrder` does
+async fn place_order(
+    tenant: &str,
+    client_id: &str,
+    request_id: &str,
+    place: pb::PlaceOrderRequest,
+) -> Result<(), tonic::Status> {
+    let mut trace = RequestTrace::new("session_place_order");
+    let Some(order) = &place.order else {
+        return Ok(());
+    };
+    let match_order = match_order(order)?;
+    memory::check_order(tenant, &match_order.symbol).map_err(tonic::Status::resource_exhausted)?;
+    check_post_only(tenant, &match_order).await?;
+    check_degraded().await?;
+    let cmd = MatchCmd {
+        cmd: MatchCmdType::PlaceOrder,
+        tenant: tenant.to_string(),
+        order: Some(match_order),
+        symbol: None,
+        transfer: None,
+        risk: None,
+        market: market_type(order.market()),
+        mark_price: None,
+        orders: Vec::new(),
+    };
+    let envelope = stamped_envelope(
+        request_id.to_string(),
+        client_id.to_string(),
+        order.account_id,
+        cmd,
+    );
+    propose(envelope, None, false, &mut trace).await
+}
+
+/// Cancels an order, the way `CancelOrder` does
+///
+/// # Arguments
+///
+/// * `cancel` - Symbol, ID and market of the order
+async fn cancel_order(
+    tenant: &str,
+    client_id: &str,
+    request_id: &str,
+    cancel: (String, u64, MarketType),
+) -> Result<(), tonic::Status> {
+    let mut trace = RequestTrace::new("session_cancel_order");
+    let (symbol, order_id, market) = cancel;
+    let cmd = MatchCmd {
+        cmd: MatchCmdType::CancelOrder,
+        tenant: tenant.to_string(),
+        order: Some(Order {
+            id: order_id.to_string(),
+            symbol,
+            ..Default::default()
+        }),
+        symbol: None,
+        transfer: None,
+        risk: None,
+        market,
+        mark_price: None,
+        orders: Vec::new(),
+    };
+    let envelope = stamped_envelope(request_id.to_string(), client_id.to_string(), 0, cmd);
+    propose(envelope, None, true, &mut trace).await
+}
+
+/// Builds the ack of a request from its outcome
+fn ack(request_id: String, result: Result<(), tonic::Status>) -> pb::OrderSessionResponse {
+    let ack = match result {
+        Ok(()) => pb::OrderSessionAck {
+            request_id,
+            code: 0,
+            message: "ok".to_string(),
+        },
+        Err(status) => pb::OrderSessionAck {
+            request_id,
+            code: status.code() as i32,
+            message: status.message().to_string(),
+        },
+    };
+    pb::OrderSessionResponse {
+        message: Some(order_session_response::Message::Ack(ack)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(order_id: u64, status: pb::OrderStatus) -> pb::ExecutionReport {
+        pb::ExecutionReport {
+            order: Some(drop_copy::order_ref(
+                1,
+                "BTC",
+                &order_id.to_string(),
+                status,
+            )),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_open_orders() {
+        let mut orders = Orders::default();
+        orders
+            .placed
+            .insert(("BTC".to_string(), 1), MarketType::Spot);
+        orders
+            .placed
+            .insert(("BTC".to_string(), 2), MarketType::Spot);
+        orders.update(&report(1, pb::OrderStatus::New));
+        orders.update(&report(2, pb::OrderStatus::PartiallyFilled));
+        orders.update(&report(3, pb::OrderStatus::New));
+        assert_eq!(orders.open().len(), 2);
+        orders.update(&report(2, pb::OrderStatus::Filled));
+        orders.update(&report(1, pb::OrderStatus::PartiallyFilled));
+        assert_eq!(
+            orders.open(),
+            vec![("BTC".to_string(), 1, MarketType::Spot)]
+        );
+        orders.update(&report(1, pb::OrderStatus::Canceled));
+        assert!(orders.open().is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+//! Process and runtime metrics
+//!
+//! This module samples process level resources (resident memory, open file
+//! descriptors, CPU time), tokio runtime activity and allocator counters and
+//! publishes them through the global metrics registry.
+
+use crate::allocator;
+use crate::metrics::REGISTRY_INSTANCE;
+use lazy_static::lazy_static;
+use prometheus::{Gauge, GaugeVec, IntGauge, Opts};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+
+/// Interval at which process and runtime metrics are sampled
+pub const PROCESS_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// Gauge for tracking the resident set size of the process
+    pub static ref PROCESS_RESIDENT_MEMORY_GAUGE: IntGauge =
+        IntGauge::new("process_resident_memory_bytes", "resident memory size").unwrap();
+
+    /// Gauge for tracking the number of open file descriptors
+    pub static ref PROCESS_OPEN_FDS_GAUGE: IntGauge =
+        IntGauge::new("process_open_fds", "open file descriptors").unwrap();
+
+    /// Gauge for tracking the user and system CPU time consumed by the process
+    pub static ref PROCESS_CPU_SECONDS_GAUGE: Gauge =
+        Gauge::new("process_cpu_seconds", "user and system CPU time").unwrap();
+
+    /// Gauge for tracking the number of tokio worker threads
+    pub static ref TOKIO_WORKERS_GAUGE: IntGauge =
+        IntGauge::new("tokio_workers", "tokio worker threads").unwrap();
+
+    /// Gauge for tracking the number of alive tokio tasks
+    pub static ref TOKIO_ALIVE_TASKS_GAUGE: IntGauge =
+        IntGauge::new("tokio_alive_tasks", "alive tokio tasks").unwrap();
+
+    /// Gauge for tracking the depth of the tokio global task queue
+    pub static ref TOKIO_GLOBAL_QUEUE_DEPTH_GAUGE: IntGauge =
+        IntGauge::new("tokio_global_queue_depth", "tasks in the tokio global queue").unwrap();
+
+    /// Gauge for tracking the busy ratio of each tokio worker over the last sample interval
+    pub static ref TOKIO_WORKER_BUSY_RATIO_GAUGE_VEC: GaugeVec = GaugeVec::new(
+        Opts::new("tokio_worker_busy_ratio", "tokio worker utilization"),
+        &["worker"]
+    )
+    .unwrap();
+
+    /// Gauge for tracking the bytes currently allocated
+    pub static ref ALLOC_ALLOCATED_BYTES_GAUGE: IntGauge =
+        IntGauge::new("alloc_allocated_bytes", "bytes currently allocated").unwrap();
+
+    /// Gauge for tracking the number of allocations since start
+    pub static ref ALLOC_ALLOCATIONS_GAUGE: IntGauge =
+        IntGauge::new("alloc_allocations", "allocations since start").unwrap();
+
+    /// Gauge for tracking the number of deallocations since start
+    pub static ref ALLOC_DEALLOCATIONS_GAUGE: IntGauge =
+        IntGauge::new("alloc_deallocations", "deallocations since start").unwrap();
+}
+
+/// Registers the process and runtime metrics with the global registry
+pub fn init_registry() {
+    let _ = REGISTRY_INSTANCE.register(Box::new(PROCESS_RESIDENT_MEMORY_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(PROCESS_OPEN_FDS_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(PROCESS_CPU_SECONDS_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(TOKIO_WORKERS_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(TOKIO_ALIVE_TASKS_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(TOKIO_GLOBAL_QUEUE_DEPTH_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(TOKIO_WORKER_BUSY_RATIO_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(ALLOC_ALLOCATED_BYTES_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(ALLOC_ALLOCATIONS_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(ALLOC_DEALLOCATIONS_GAUGE.clone()));
+}
+
+/// Starts the sampler task on the current tokio runtime
+pub fn start_sampler() {
+    let handle = Handle::current();
+    tokio::spawn(async move {
+        let mut sampler = RuntimeSampler::new(handle);
+        let mut interval = tokio::time::interval(PROCESS_SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            sample_process();
+            sample_allocator();
+            sampler.sample();
+        }
+    });
+}
+
+/// Samples resident memory, open file descriptors and CPU time of the process
+fn sample_process() {
+    if let Some(rss) = resident_memory() {
+        PROCESS_RESIDENT_MEMORY_GAUGE.set(rss as i64);
+    }
+    if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+        PROCESS_OPEN_FDS_GAUGE.set(fds.count() as i64);
+    }
+    if let Some(cpu) = cpu_time() {
+        PROCESS_CPU_SECONDS_GAUGE.set(cpu.as_secs_f64());
+    }
+}
+
+/// Samples the allocator counters
+fn sample_allocator() {
+    let stats = allocator::stats();
+    ALLOC_ALLOCATED_BYTES_GAUGE.set(stats.allocated as i64);
+    ALLOC_ALLOCATIONS_GAUGE.set(stats.allocations as i64);
+    ALLOC_DEALLOCATIONS_GAUGE.set(stats.deallocations as i64);
+}
+
+/// Reads the resident set size from `/proc/self/status`
+///
+/// # Returns
+///
+/// Returns the resident memory in bytes, None where procfs is unavailable
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Reads the user and system CPU time consumed by the process
+///
+/// # Returns
+///
+/// Returns the CPU time, None if it cannot be queried
+fn cpu_time() -> Option<Duration> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage only writes into the provided rusage struct
+    let usage = unsafe {
+        if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) != 0 {
+            return None;
+        }
+        usage.assume_init()
+    };
+    let to_duration = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    Some(to_duration(usage.ru_utime) + to_duration(usage.ru_stime))
+}
+
+/// Samples tokio runtime metrics
+///
+/// Worker utilization is derived from the busy time accumulated between two samples.
+struct RuntimeSampler {
+    /// Runtime being sampled
+    handle: Handle,
+    /// Time of the previous sample
+    last_sample: Instant,
+    /// Total busy time of each worker at the previous sample
+    last_busy: Vec<Duration>,
+}
+
+impl RuntimeSampler {
+    /// Creates a new sampler for the given runtime
+    fn new(handle: Handle) -> Self {
+        let metrics = handle.metrics();
+        let last_busy = (0..metrics.num_workers())
+            .map(|worker| metrics.worker_total_busy_duration(worker))
+            .collect();
+        Self {
+            handle,
+            last_sample: Instant::now(),
+            last_busy,
+        }
+    }
+
+    /// Publishes the runtime metrics accumulated since the previous sample
+    fn sample(&mut self) {
+        let metrics = self.handle.metrics();
+        TOKIO_WORKERS_GAUGE.set(metrics.num_workers() as i64);
+        TOKIO_ALIVE_TASKS_GAUGE.set(metrics.num_alive_tasks() as i64);
+        TOKIO_GLOBAL_QUEUE_DEPTH_GAUGE.set(metrics.global_queue_depth() as i64);
+
+        let elapsed = self.last_sample.elapsed();
+        self.last_sample = Instant::now();
+        for (worker, last_busy) in self.last_busy.iter_mut().enumerate() {
+            let busy = metrics.worker_total_busy_duration(worker);
+            let ratio = busy.saturating_sub(*last_busy).as_secs_f64() / elapsed.as_secs_f64();
+            TOKIO_WORKER_BUSY_RATIO_GAUGE_VEC
+                .with_label_values(&[&worker.to_string()])
+                .set(ratio.min(1.0));
+            *last_busy = busy;
+        }
+    }
+}
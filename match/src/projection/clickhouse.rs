@@ -0,0 +1,193 @@
+//! ClickHouse projection
+//!
+//! Rows go to `match_order_events` and `match_trades` over the HTTP interface in
+//! `JSONEachRow` format, the cursor of each projection to
+//! `match_projection_cursors`. ClickHouse has no transactions, so the cursor is
+//! written after the rows; the row tables are `ReplacingMergeTree`s keyed by raft
+//! position, which collapses rows written again after a failure between the two
+//! writes. Queries that must not see such duplicates before merges read with
+//! `FINAL`.
+
+use super::{Projection, ProjectionBatch};
+use hyper::{Body, Client, Method, Request};
+use serde::Serialize;
+
+/// Projection into a ClickHouse database
+pub(super) struct ClickhouseProjection {
+    /// Name of the projection
+    name: String,
+    /// Base URL of the HTTP interface
+    url: String,
+    /// Database the tables are in
+    database: String,
+    /// User to authenticate as
+    user: Option<String>,
+    /// Password of the user
+    password: Option<String>,
+}
+
+impl ClickhouseProjection {
+    /// Creates the projection
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the projection
+    /// * `url` - Base URL of the HTTP interface
+    /// * `database` - Database the tables are created in
+    /// * `user` - User to authenticate as
+    /// * `password` - Password of the user
+    pub(super) fn new(
+        name: String,
+        url: String,
+        database: String,
+        user: Option<String>,
+        password: Option<String>,
+    ) -> Self {
+        Self {
+            name,
+            url,
+            database,
+            user,
+            password,
+        }
+    }
+
+    /// Runs a query, the query and its data are sent as request body
+    ///
+    /// # Returns
+    ///
+    /// Returns the response body
+    async fn query(&self, body: String) -> Result<String, String> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}/", self.url.trim_end_matches('/')));
+        if let Some(user) = &self.user {
+            request = request.header("X-ClickHouse-User", user);
+        }
+        if let Some(password) = &self.password {
+            request = request.header("X-ClickHouse-Key", password);
+        }
+        let request = request.body(Body::from(body)).map_err(|e| e.to_string())?;
+        let response = Client::new()
+            .request(request)
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| e.to_string())?;
+        let body = String::from_utf8_lossy(&body).into_owned();
+        if !status.is_success() {
+            return Err(format!(
+                "{} responded {}: {}",
+                self.url,
+                status,
+                body.trim()
+            ));
+        }
+        Ok(body)
+    }
+
+    /// Inserts rows into a table
+    async fn insert<T: Serialize>(&self, table: &str, rows: &[T]) -> Result<(), String> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let mut body = format!(
+            "INSERT INTO {}.{} FORMAT JSONEachRow\n",
+            self.database, table
+        );
+        for row in rows {
+            body.push_str(&serde_json::to_string(row).map_err(|e| e.to_string())?);
+            body.push('\n');
+        }
+        self.query(body).await.map(|_| ())
+    }
+
+    /// Reads the stored cursor, 0 if the projection never wrote anything
+    async fn cursor(&self) -> Result<u64, String> {
+        let body = self
+            .query(format!(
+                "SELECT max(raft_index) FROM {}.match_projection_cursors WHERE name = '{}' \
+                 FORMAT TabSeparated",
+                self.database, self.name
+            ))
+            .await?;
+        body.trim()
+            .parse()
+            .map_err(|e| format!("unexpected cursor {:?}: {}", body.trim(), e))
+    }
+}
+
+#[tonic::async_trait]
+impl Projection for ClickhouseProjection {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn prepare(&self) -> Result<u64, String> {
+        let database = &self.database;
+        self.query(format!(
+            "CREATE TABLE IF NOT EXISTS {}.match_projection_cursors (
+                name String,
+                raft_index UInt64
+            ) ENGINE = ReplacingMergeTree(raft_index) ORDER BY name",
+            database
+        ))
+        .await?;
+        self.query(format!(
+            "CREATE TABLE IF NOT EXISTS {}.match_order_events (
+                raft_index UInt64,
+                seq UInt32,
+                tenant String,
+                account_id UInt64,
+                time UInt64,
+                kind LowCardinality(String),
+                symbol String,
+                order_id String,
+                side LowCardinality(String),
+                order_type LowCardinality(String),
+                price Decimal(38, 18),
+                quantity Decimal(38, 18),
+                filled_quantity Decimal(38, 18),
+                status LowCardinality(String)
+            ) ENGINE = ReplacingMergeTree ORDER BY (raft_index, seq)",
+            database
+        ))
+        .await?;
+        self.query(format!(
+            "CREATE TABLE IF NOT EXISTS {}.match_trades (
+                raft_index UInt64,
+                trade_seq UInt32,
+                tenant String,
+                symbol String,
+                taker_side LowCardinality(String),
+                taker_account_id UInt64,
+                price Decimal(38, 18),
+                quantity Decimal(38, 18),
+                buyer_order_id String,
+                seller_order_id String,
+                time UInt64
+            ) ENGINE = ReplacingMergeTree ORDER BY (raft_index, trade_seq)",
+            database
+        ))
+        .await?;
+        self.cursor().await
+    }
+
+    async fn apply(&self, batch: &ProjectionBatch) -> Result<(), String> {
+        let (orders, trades) = batch.rows(self.cursor().await?);
+        if orders.is_empty() {
+            return Ok(());
+        }
+        self.insert("match_order_events", &orders).await?;
+        self.insert("match_trades", &trades).await?;
+        self.query(format!(
+            "INSERT INTO {}.match_projection_cursors FORMAT JSONEachRow\n{}\n",
+            self.database,
+            serde_json::json!({"name": self.name, "raft_index": batch.last_index()})
+        ))
+        .await
+        .map(|_| ())
+    }
+}
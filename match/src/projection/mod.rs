@@ -0,0 +1,504 @@
+//! Read model projections
+//!
+//! Projections feed external stores selected via the `projections` config with
+//! the orders and trades this node applies, so reporting systems can read them
+//! from their own database instead of polling the API. Every projection writes
+//! an append-only table of order events and one of trades, keyed by the raft
+//! index that produced them.
+//!
+//! Each projection runs on its own task, fed after each apply batch through an
+//! unbounded channel, so a slow or unreachable store never stalls the raft loop;
+//! failed writes are retried until they succeed. A projection keeps a cursor, the
+//! last raft index it wrote, in the store itself and skips events at or below it,
+//! which makes the writes exactly-once across retries and restarts: the log is
+//! replayed from the last snapshot on restart and the events the store already
+//! holds are dropped. A snapshot lets the log it covers be compacted, so it waits
+//! up to `projection_catch_up_ms` for every projection to write the events the
+//! snapshot covers; a projection still behind by then misses them.
+
+mod clickhouse;
+mod postgres;
+
+use crate::config::{self, ProjectionConfig};
+use crate::engine::entry::{OrderSide, Trade};
+use crate::engine::history::{OrderChange, OrderEvent};
+use crate::metrics;
+use once_cell::sync::OnceCell;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+/// Time waited before a failed write is retried
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Feeds of the running projections, empty if none is configured
+static FEEDS: OnceCell<Vec<Feed>> = OnceCell::new();
+
+/// Store a read model is projected to
+#[tonic::async_trait]
+pub trait Projection: Send + Sync {
+    /// Name of the projection, used in logs, metrics and as key of its cursor
+    fn name(&self) -> &str;
+
+    /// Creates the tables of the projection if they don't exist
+    ///
+    /// # Returns
+    ///
+    /// Returns the cursor of the projection, 0 if it never wrote anything
+    async fn prepare(&self) -> Result<u64, String>;
+
+    /// Writes the rows of a batch and advances the cursor to its last index
+    ///
+    /// Rows of events at or below the stored cursor must be skipped, a batch may be
+    /// written again after a failure or a restart.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - Order events of an apply batch
+    ///
+    /// # Returns
+    ///
+    /// Returns an error message if the write failed, the batch is retried
+    async fn apply(&self, batch: &ProjectionBatch) -> Result<(), String>;
+}
+
+/// Order events of an apply batch, in raft log order
+#[derive(Debug, Clone)]
+pub struct ProjectionBatch {
+    /// Events of the batch
+    pub events: Vec<OrderEvent>,
+}
+
+/// Order event as written to external stores
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OrderEventRow {
+    /// Raft index of the command
+    pub raft_index: u64,
+    /// Position of the event among the events of its command
+    pub seq: u32,
+    /// Tenant the order belongs to
+    pub tenant: String,
+    /// Account the command was made for, 0 if not account scoped
+    pub account_id: u64,
+    /// Engine time of the command in seconds
+    pub time: u64,
+    /// `placed`, `canceled`, `symbol_removed`, `symbol_listed`, `symbol_halted`
+    /// or `symbol_resumed`
+    pub kind: &'static str,
+    /// Symbol of the order
+    pub symbol: String,
+    /// ID of the order, empty for removed symbols
+    pub order_id: String,
+    /// Side of a placed order
+    pub side: String,
+    /// Type of a placed order
+    pub order_type: String,
+    /// Limit price of a placed order
+    pub price: Decimal,
+    /// Quantity of a placed order
+    pub quantity: Decimal,
+    /// Quantity of a placed order filled on placement
+    pub filled_quantity: Decimal,
+    /// Status of a placed order after matching
+    pub status: String,
+}
+
+/// Trade as written to external stores
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TradeRow {
+    /// Raft index of the command that matched the trade
+    pub raft_index: u64,
+    /// Position of the trade among the trades of its command
+    pub trade_seq: u32,
+    /// Tenant the symbol belongs to
+    pub tenant: String,
+    /// Symbol traded
+    pub symbol: String,
+    /// Side of the order that took liquidity
+    pub taker_side: String,
+    /// Account of the order that took liquidity
+    pub taker_account_id: u64,
+    /// Price of the trade
+    pub price: Decimal,
+    /// Base quantity of the trade
+    pub quantity: Decimal,
+    /// Order ID of the buyer
+    pub buyer_order_id: String,
+    /// Order ID of the seller
+    pub seller_order_id: String,
+    /// Engine time of the trade in seconds
+    pub time: u64,
+}
+
+impl ProjectionBatch {
+    /// Returns the raft index of the last event, 0 if the batch is empty
+    pub fn last_index(&self) -> u64 {
+        self.events.last().map_or(0, |event| event.index)
+    }
+
+    /// Flattens the events after a cursor into rows
+    ///
+    /// # Arguments
+    ///
+    /// * `cursor` - Last raft index already written, older events are skipped
+    ///
+    /// # Returns
+    ///
+    /// Returns the order event rows and the trade rows
+    pub fn rows(&self, cursor: u64) -> (Vec<OrderEventRow>, Vec<TradeRow>) {
+        let mut orders = Vec::new();
+        let mut trades = Vec::new();
+        let mut previous = 0;
+        let mut seq = 0;
+        for event in self.events.iter().filter(|event| event.index > cursor) {
+            seq = if event.index == previous { seq + 1 } else { 0 };
+            previous = event.index;
+            let mut row = OrderEventRow {
+                raft_index: event.index,
+                seq,
+                tenant: event.tenant.clone(),
+                account_id: event.account_id,
+                time: event.time,
+                kind: "",
+                symbol: String::new(),
+                order_id: String::new(),
+                side: String::new(),
+                order_type: String::new(),
+                price: Decimal::ZERO,
+                quantity: Decimal::ZERO,
+                filled_quantity: Decimal::ZERO,
+                status: String::new(),
+            };
+            match &event.change {
+                OrderChange::Placed {
+                    order,
+                    trades: fills,
+                } => {
+                    row.kind = "placed";
+                    row.symbol = order.symbol.clone();
+                    row.order_id = order.id.clone();
+                    row.side = format!("{:?}", order.side);
+                    row.order_type = format!("{:?}", order.order_type);
+                    row.price = order.price;
+                    row.quantity = order.quantity;
+                    row.filled_quantity = order.filled_quantity;
+                    row.status = format!("{:?}", order.status);
+                    trades.extend(
+                        fills.iter().enumerate().map(|(trade_seq, trade)| {
+                            trade_row(event, &order.id, trade_seq, trade)
+                        }),
+                    );
+                }
+                OrderChange::Canceled { symbol, order_id } => {
+                    row.kind = "canceled";
+                    row.symbol = symbol.clone();
+                    row.order_id = order_id.clone();
+                }
+                OrderChange::SymbolRemoved { symbol } => {
+                    row.kind = "symbol_removed";
+                    row.symbol = symbol.clone();
+                }
+                OrderChange::SymbolListed { symbol } => {
+                    row.kind = "symbol_listed";
+                    row.symbol = symbol.clone();
+                }
+                OrderChange::SymbolHalted { symbol, .. } => {
+                    row.kind = "symbol_halted";
+                    row.symbol = symbol.clone();
+                }
+                OrderChange::SymbolResumed { symbol } => {
+                    row.kind = "symbol_resumed";
+                    row.symbol = symbol.clone();
+                }
+            }
+            orders.push(row);
+        }
+        (orders, trades)
+    }
+}
+
+/// Describes a trade of a placed order
+fn trade_row(event: &OrderEvent, taker_id: &str, trade_seq: usize, trade: &Trade) -> TradeRow {
+    let taker_side = if trade.buyer_order_id == taker_id {
+        OrderSide::Buy
+    } else {
+        OrderSide::Sell
+    };
+    TradeRow {
+        raft_index: event.index,
+        trade_seq: trade_seq as u32,
+        tenant: event.tenant.clone(),
+        symbol: trade.symbol.clone(),
+        taker_side: format!("{:?}", taker_side),
+        taker_account_id: event.account_id,
+        price: trade.price,
+        quantity: trade.quantity,
+        buyer_order_id: trade.buyer_order_id.clone(),
+        seller_order_id: trade.seller_order_id.clone(),
+        time: event.time,
+    }
+}
+
+/// Progress of a running projection as seen by the raft loop
+struct Feed {
+    /// Name of the projection
+    name: String,
+    /// Sends batches to the projection task
+    sender: UnboundedSender<Arc<ProjectionBatch>>,
+    /// Last raft index sent to the projection
+    sent: Arc<AtomicU64>,
+    /// Last raft index the projection wrote
+    written: Arc<AtomicU64>,
+}
+
+/// Creates the projection described by a config entry
+///
+/// # Arguments
+///
+/// * `config` - Projection configuration
+fn build_projection(config: ProjectionConfig) -> Result<Box<dyn Projection>, String> {
+    let projection: Box<dyn Projection> = match config {
+        ProjectionConfig::Postgres { name, url } => {
+            Box::new(postgres::PostgresProjection::new(check_name(name)?, &url)?)
+        }
+        ProjectionConfig::Clickhouse {
+            name,
+            url,
+            database,
+            user,
+            password,
+        } => Box::new(clickhouse::ClickhouseProjection::new(
+            check_name(name)?,
+            url,
+            check_name(database)?,
+            user,
+            password,
+        )),
+    };
+    Ok(projection)
+}
+
+/// Checks that a name can be used in SQL without quoting
+fn check_name(name: String) -> Result<String, String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!(
+            "invalid projection name {:?}, use letters, digits and _ only",
+            name
+        ));
+    }
+    Ok(name)
+}
+
+/// Starts the configured projections
+///
+/// Must be called on the runtime before the raft loop starts applying entries.
+/// Does nothing if no projection is configured.
+///
+/// # Returns
+///
+/// Returns an error if a projection is misconfigured
+pub fn start() -> Result<(), String> {
+    let configs = config::instance().lock().unwrap().projections.clone();
+    let mut feeds = Vec::new();
+    for config in configs {
+        let projection = build_projection(config)?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let feed = Feed {
+            name: projection.name().to_string(),
+            sender,
+            sent: Arc::new(AtomicU64::new(0)),
+            written: Arc::new(AtomicU64::new(0)),
+        };
+        log::info!("projection {} enabled", feed.name);
+        tokio::spawn(run(projection, receiver, feed.written.clone()));
+        feeds.push(feed);
+    }
+    let _ = FEEDS.set(feeds);
+    Ok(())
+}
+
+/// Writes the batches sent to a projection until the node stops
+///
+/// # Arguments
+///
+/// * `projection` - The projection to write to
+/// * `receiver` - Batches in apply order
+/// * `written` - Updated with the last raft index written
+async fn run(
+    projection: Box<dyn Projection>,
+    mut receiver: mpsc::UnboundedReceiver<Arc<ProjectionBatch>>,
+    written: Arc<AtomicU64>,
+) {
+    let name = projection.name().to_string();
+    let cursor = loop {
+        match projection.prepare().await {
+            Ok(cursor) => break cursor,
+            Err(e) => {
+                log::warn!("projection {} cannot be prepared: {}", name, e);
+                tokio::time::sleep(RETRY_INTERVAL).await;
+            }
+        }
+    };
+    log::info!("projection {} resumes after index {}", name, cursor);
+    written.store(cursor, Ordering::Release);
+    metrics::PROJECTION_CURSOR_GAUGE_VEC
+        .with_label_values(&[&name])
+        .set(cursor as i64);
+    while let Some(batch) = receiver.recv().await {
+        let last_index = batch.last_index();
+        if last_index > written.load(Ordering::Acquire) {
+            while let Err(e) = projection.apply(&batch).await {
+                log::warn!(
+                    "projection {} cannot write up to index {}: {}",
+                    name,
+                    last_index,
+                    e
+                );
+                tokio::time::sleep(RETRY_INTERVAL).await;
+            }
+            written.store(last_index, Ordering::Release);
+        }
+        metrics::PROJECTION_CURSOR_GAUGE_VEC
+            .with_label_values(&[&name])
+            .set(written.load(Ordering::Acquire) as i64);
+    }
+}
+
+/// Sends the order events of an apply batch to the projections
+///
+/// # Arguments
+///
+/// * `events` - Order events of an apply batch, in apply order
+pub fn write(events: &[OrderEvent]) {
+    let feeds = match FEEDS.get() {
+        Some(feeds) if !feeds.is_empty() && !events.is_empty() => feeds,
+        _ => return,
+    };
+    let batch = Arc::new(ProjectionBatch {
+        events: events.to_vec(),
+    });
+    let last_index = batch.last_index();
+    for feed in feeds {
+        if feed.sender.send(batch.clone()).is_ok() {
+            feed.sent.store(last_index, Ordering::Release);
+        }
+        let lag = last_index.saturating_sub(feed.written.load(Ordering::Acquire));
+        metrics::PROJECTION_LAG_GAUGE_VEC
+            .with_label_values(&[&feed.name])
+            .set(lag as i64);
+    }
+}
+
+/// Returns the last raft index every projection wrote, None if none is configured
+pub fn exported_index() -> Option<u64> {
+    FEEDS
+        .get()?
+        .iter()
+        .map(|feed| feed.written.load(Ordering::Acquire))
+        .min()
+}
+
+/// Waits for the projections to write every batch sent to them, called before a
+/// snapshot lets the raft log be compacted
+///
+/// Gives up after `projection_catch_up_ms`, logging the projections still behind.
+pub fn catch_up() {
+    let feeds = match FEEDS.get() {
+        Some(feeds) if !feeds.is_empty() => feeds,
+        _ => return,
+    };
+    let timeout = Duration::from_millis(config::instance().lock().unwrap().projection_catch_up_ms);
+    let deadline = Instant::now() + timeout;
+    let behind =
+        |feed: &&Feed| feed.written.load(Ordering::Acquire) < feed.sent.load(Ordering::Acquire);
+    while feeds.iter().any(|feed| behind(&feed)) {
+        if Instant::now() >= deadline {
+            for feed in feeds.iter().filter(behind) {
+                log::error!(
+                    "projection {} is behind at index {} of {}, the snapshot lets it miss the rest",
+                    feed.name,
+                    feed.written.load(Ordering::Acquire),
+                    feed.sent.load(Ordering::Acquire)
+                );
+            }
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::entry::Order;
+    use rust_decimal_macros::dec;
+
+    fn event(index: u64, change: OrderChange) -> OrderEvent {
+        OrderEvent {
+            index,
+            tenant: "t".to_string(),
+            account_id: 7,
+            time: 100,
+            change,
+        }
+    }
+
+    #[test]
+    fn rows_skip_events_at_or_below_the_cursor() {
+        let order = Order {
+            id: "2".to_string(),
+            symbol: "BTCUSDT".to_string(),
+            side: OrderSide::Sell,
+            quantity: dec!(1),
+            filled_quantity: dec!(1),
+            ..Default::default()
+        };
+        let trade = Trade::new(
+            "x".to_string(),
+            "BTCUSDT".to_string(),
+            dec!(100),
+            dec!(1),
+            "1".to_string(),
+            "2".to_string(),
+            0,
+        );
+        let batch = ProjectionBatch {
+            events: vec![
+                event(
+                    4,
+                    OrderChange::Canceled {
+                        symbol: "BTCUSDT".to_string(),
+                        order_id: "0".to_string(),
+                    },
+                ),
+                event(
+                    5,
+                    OrderChange::Placed {
+                        order,
+                        trades: vec![trade],
+                    },
+                ),
+                event(
+                    5,
+                    OrderChange::SymbolRemoved {
+                        symbol: "BTCUSDT".to_string(),
+                    },
+                ),
+            ],
+        };
+        assert_eq!(batch.last_index(), 5);
+
+        let (orders, trades) = batch.rows(4);
+        let kinds: Vec<_> = orders.iter().map(|row| (row.kind, row.seq)).collect();
+        assert_eq!(kinds, vec![("placed", 0), ("symbol_removed", 1)]);
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].taker_side, "Sell");
+        assert_eq!(trades[0].taker_account_id, 7);
+
+        assert_eq!(batch.rows(3).0.len(), 3);
+        assert!(batch.rows(5).0.is_empty());
+    }
+}
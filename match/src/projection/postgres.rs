@@ -0,0 +1,172 @@
+//! PostgreSQL projection
+//!
+//! Rows go to `match_order_events` and `match_trades`, the cursor of each
+//! projection to `match_projection_cursors`. A batch is written in one
+//! transaction that locks the cursor row first, so rows and cursor are committed
+//! together and nodes projecting into the same database under the same name
+//! never write an event twice.
+
+use super::{Projection, ProjectionBatch};
+use sqlx::postgres::{PgPool, PgPoolOptions};
+
+/// Statements creating the tables, run before the first write
+const SCHEMA: [&str; 3] = [
+    "CREATE TABLE IF NOT EXISTS match_projection_cursors (
+        name TEXT PRIMARY KEY,
+        raft_index BIGINT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS match_order_events (
+        raft_index BIGINT NOT NULL,
+        seq INTEGER NOT NULL,
+        tenant TEXT NOT NULL,
+        account_id BIGINT NOT NULL,
+        time BIGINT NOT NULL,
+        kind TEXT NOT NULL,
+        symbol TEXT NOT NULL,
+        order_id TEXT NOT NULL,
+        side TEXT NOT NULL,
+        order_type TEXT NOT NULL,
+        price NUMERIC NOT NULL,
+        quantity NUMERIC NOT NULL,
+        filled_quantity NUMERIC NOT NULL,
+        status TEXT NOT NULL,
+        PRIMARY KEY (raft_index, seq)
+    )",
+    "CREATE TABLE IF NOT EXISTS match_trades (
+        raft_index BIGINT NOT NULL,
+        trade_seq INTEGER NOT NULL,
+        tenant TEXT NOT NULL,
+        symbol TEXT NOT NULL,
+        taker_side TEXT NOT NULL,
+        taker_account_id BIGINT NOT NULL,
+        price NUMERIC NOT NULL,
+        quantity NUMERIC NOT NULL,
+        buyer_order_id TEXT NOT NULL,
+        seller_order_id TEXT NOT NULL,
+        time BIGINT NOT NULL,
+        PRIMARY KEY (raft_index, trade_seq)
+    )",
+];
+
+/// Projection into a PostgreSQL database
+pub(super) struct PostgresProjection {
+    /// Name of the projection
+    name: String,
+    /// Connections, opened on first use
+    pool: PgPool,
+}
+
+impl PostgresProjection {
+    /// Creates the projection without connecting yet
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the projection
+    /// * `url` - Connection URL of the database
+    pub(super) fn new(name: String, url: &str) -> Result<Self, String> {
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect_lazy(url)
+            .map_err(|e| format!("invalid postgres url for projection {}: {}", name, e))?;
+        Ok(Self { name, pool })
+    }
+}
+
+#[tonic::async_trait]
+impl Projection for PostgresProjection {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn prepare(&self) -> Result<u64, String> {
+        for statement in SCHEMA {
+            sqlx::query(statement)
+                .execute(&self.pool)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        sqlx::query(
+            "INSERT INTO match_projection_cursors (name, raft_index) VALUES ($1, 0)
+             ON CONFLICT (name) DO NOTHING",
+        )
+        .bind(&self.name)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        let cursor: i64 =
+            sqlx::query_scalar("SELECT raft_index FROM match_projection_cursors WHERE name = $1")
+                .bind(&self.name)
+                .fetch_one(&self.pool)
+                .await
+                .map_err(|e| e.to_string())?;
+        Ok(cursor as u64)
+    }
+
+    async fn apply(&self, batch: &ProjectionBatch) -> Result<(), String> {
+        let mut tx = self.pool.begin().await.map_err(|e| e.to_string())?;
+        let cursor: i64 = sqlx::query_scalar(
+            "SELECT raft_index FROM match_projection_cursors WHERE name = $1 FOR UPDATE",
+        )
+        .bind(&self.name)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        let (orders, trades) = batch.rows(cursor as u64);
+        if orders.is_empty() {
+            return Ok(());
+        }
+        for row in orders {
+            sqlx::query(
+                "INSERT INTO match_order_events (raft_index, seq, tenant, account_id, time, kind,
+                 symbol, order_id, side, order_type, price, quantity, filled_quantity, status)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10,
+                 $11::numeric, $12::numeric, $13::numeric, $14)",
+            )
+            .bind(row.raft_index as i64)
+            .bind(row.seq as i32)
+            .bind(row.tenant)
+            .bind(row.account_id as i64)
+            .bind(row.time as i64)
+            .bind(row.kind)
+            .bind(row.symbol)
+            .bind(row.order_id)
+            .bind(row.side)
+            .bind(row.order_type)
+            .bind(row.price.to_string())
+            .bind(row.quantity.to_string())
+            .bind(row.filled_quantity.to_string())
+            .bind(row.status)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        }
+        for row in trades {
+            sqlx::query(
+                "INSERT INTO match_trades (raft_index, trade_seq, tenant, symbol, taker_side,
+                 taker_account_id, price, quantity, buyer_order_id, seller_order_id, time)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7::numeric, $8::numeric, $9, $10, $11)",
+            )
+            .bind(row.raft_index as i64)
+            .bind(row.trade_seq as i32)
+            .bind(row.tenant)
+            .bind(row.symbol)
+            .bind(row.taker_side)
+            .bind(row.taker_account_id as i64)
+            .bind(row.price.to_string())
+            .bind(row.quantity.to_string())
+            .bind(row.buyer_order_id)
+            .bind(row.seller_order_id)
+            .bind(row.time as i64)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        }
+        sqlx::query("UPDATE match_projection_cursors SET raft_index = $2 WHERE name = $1")
+            .bind(&self.name)
+            .bind(batch.last_index() as i64)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        tx.commit().await.map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,802 @@
+//! Query store
+//!
+//! With `query_store_path` set, every node keeps the orders and trades it applies
+//! in an embedded sled database, keyed for the reads the service answers: orders
+//! by symbol and ID, by account and by client order ID, trades by symbol and by
+//! account in raft log order. Reads are served from it instead of the engine, which the raft loop
+//! owns, and history survives restarts without replaying the log from the start.
+//!
+//! The store is written on the raft loop after each apply batch, like the read
+//! view, so reads from the leader see every acknowledged write. It remembers the
+//! last raft index it applied and skips older events when the log is replayed
+//! after a restart; events of a batch interrupted by a crash are applied again,
+//! which leaves the same result. The store holds what this node applied: a node
+//! that catches up through a snapshot sent by the leader misses the history the
+//! snapshot covers.
+//!
+//! With `order_retention_secs` or `trade_retention_secs` set, a background thread
+//! periodically prunes closed orders and trades older than that. Rows the
+//! projections (see `projection`) have not exported yet are kept until they have.
+//!
+//! Records are JSON, so fields added to orders and trades with `#[serde(default)]`
+//! stay readable both ways.
+
+use crate::config;
+use crate::engine::entry::order::OrderStatus;
+use crate::engine::entry::{Order, OrderSide, Trade};
+use crate::engine::history::{OrderChange, OrderEvent};
+use crate::projection;
+use once_cell::sync::OnceCell;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Key of the last applied raft index in the meta tree
+const APPLIED_INDEX_KEY: &[u8] = b"applied_index";
+/// Interval between two runs of the retention policy
+const RETENTION_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Store of this node, None if the store is disabled
+static STORE: OnceCell<Option<QueryStore>> = OnceCell::new();
+
+/// Order as kept by the query store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderRecord {
+    /// Raft index of the command that placed the order
+    pub index: u64,
+    /// Account the order was placed for
+    pub account_id: u64,
+    /// The order with its fills and status
+    pub order: Order,
+    /// Raft index and trade sequence of the last change, trades up to it are
+    /// counted into the fills
+    pub last_change: (u64, u32),
+}
+
+/// Trade as kept by the query store
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TradeRecord {
+    /// Raft index of the command that matched the trade
+    pub index: u64,
+    /// Position of the trade among the trades of its command
+    pub trade_seq: u32,
+    /// Symbol traded
+    pub symbol: String,
+    /// Side of the order that took liquidity
+    pub taker_side: OrderSide,
+    /// Price of the trade
+    pub price: Decimal,
+    /// Base quantity of the trade
+    pub quantity: Decimal,
+    /// Order ID of the buyer
+    pub buyer_order_id: String,
+    /// Account of the buyer, 0 if its order is unknown to the store
+    pub buyer_account_id: u64,
+    /// Order ID of the seller
+    pub seller_order_id: String,
+    /// Account of the seller, 0 if its order is unknown to the store
+    pub seller_account_id: u64,
+    /// Engine time of the trade in seconds
+    pub time: u64,
+}
+
+/// Orders and trades applied by this node
+pub struct QueryStore {
+    /// Database, kept to flush it
+    db: sled::Db,
+    /// Order records keyed by tenant, symbol and order ID
+    orders: sled::Tree,
+    /// Keys of `orders` keyed by tenant, account and raft index
+    account_orders: sled::Tree,
+    /// Keys of `orders` keyed by tenant, client order ID and account, the
+    /// latest order given the ID
+    client_orders: sled::Tree,
+    /// Trade records keyed by tenant, symbol, raft index and trade sequence
+    trades: sled::Tree,
+    /// Keys of `trades` keyed by tenant, account, raft index and trade sequence
+    account_trades: sled::Tree,
+    /// Last applied raft index
+    meta: sled::Tree,
+}
+
+/// Joins the parts of a key, strings are terminated by a zero byte
+fn key(strings: &[&str], numbers: &[u8]) -> Vec<u8> {
+    let mut key = Vec::new();
+    for part in strings {
+        key.extend_from_slice(part.as_bytes());
+        key.push(0);
+    }
+    key.extend_from_slice(numbers);
+    key
+}
+
+/// Encodes a raft index and trade sequence so keys sort in log order
+fn position(index: u64, trade_seq: u32) -> Vec<u8> {
+    let mut bytes = index.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&trade_seq.to_be_bytes());
+    bytes
+}
+
+/// Returns the tenant a key of the order or trade tree starts with
+fn tenant_of(key: &[u8]) -> &str {
+    let end = key.iter().position(|byte| *byte == 0).unwrap_or(key.len());
+    std::str::from_utf8(&key[..end]).unwrap_or_default()
+}
+
+/// Encodes an order or trade record
+fn encode<T: Serialize>(record: &T) -> Result<Vec<u8>, String> {
+    serde_json::to_vec(record).map_err(|e| e.to_string())
+}
+
+/// Decodes an order record
+fn decode_order(value: &[u8]) -> Result<OrderRecord, String> {
+    serde_json::from_slice(value).map_err(|e| format!("corrupt order record: {}", e))
+}
+
+/// Decodes a trade record
+fn decode_trade(value: &[u8]) -> Result<TradeRecord, String> {
+    serde_json::from_slice(value).map_err(|e| format!("corrupt trade record: {}", e))
+}
+
+/// Maps database errors to the engine's error type
+fn db_error(e: sled::Error) -> String {
+    format!("query store: {}", e)
+}
+
+impl QueryStore {
+    /// Opens or creates the store
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Directory of the database
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the database cannot be opened
+    pub fn open(path: &str) -> Result<Self, String> {
+        let db = sled::open(path).map_err(db_error)?;
+        Ok(Self {
+            orders: db.open_tree("orders").map_err(db_error)?,
+            account_orders: db.open_tree("account_orders").map_err(db_error)?,
+            client_orders: db.open_tree("client_orders").map_err(db_error)?,
+            trades: db.open_tree("trades").map_err(db_error)?,
+            account_trades: db.open_tree("account_trades").map_err(db_error)?,
+            meta: db.open_tree("meta").map_err(db_error)?,
+            db,
+        })
+    }
+
+    /// Returns the last raft index applied to the store, 0 if it is empty
+    pub fn applied_index(&self) -> Result<u64, String> {
+        let value = self.meta.get(APPLIED_INDEX_KEY).map_err(db_error)?;
+        Ok(value.map_or(0, |value| {
+            u64::from_be_bytes(value.as_ref().try_into().unwrap_or_default())
+        }))
+    }
+
+    /// Applies the order events of an apply batch
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - Order events in apply order
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the database cannot be written
+    pub fn apply(&self, events: &[OrderEvent]) -> Result<(), String> {
+        let applied = self.applied_index()?;
+        for event in events.iter().filter(|event| event.index > applied) {
+            match &event.change {
+                OrderChange::Placed { order, trades } => self.place(event, order, trades)?,
+                OrderChange::Canceled { symbol, order_id } => {
+                    self.close(&key(&[&event.tenant, symbol, order_id], &[]), event)?
+                }
+                OrderChange::SymbolRemoved { symbol } => {
+                    let prefix = key(&[&event.tenant, symbol], &[]);
+                    for entry in self.orders.scan_prefix(prefix) {
+                        let (order_key, _) = entry.map_err(db_error)?;
+                        self.close(&order_key, event)?;
+                    }
+                }
+                OrderChange::SymbolListed { .. }
+                | OrderChange::SymbolHalted { .. }
+                | OrderChange::SymbolResumed { .. } => {}
+            }
+        }
+        if let Some(last) = events.last().filter(|last| last.index > applied) {
+            self.meta
+                .insert(APPLIED_INDEX_KEY, &last.index.to_be_bytes())
+                .map_err(db_error)?;
+        }
+        Ok(())
+    }
+
+    /// Records a placed order and its trades, adding the fills to the makers
+    fn place(&self, event: &OrderEvent, order: &Order, trades: &[Trade]) -> Result<(), String> {
+        let order_key = key(&[&event.tenant, &order.symbol, &order.id], &[]);
+        let record = OrderRecord {
+            index: event.index,
+            account_id: event.account_id,
+            order: order.clone(),
+            last_change: (event.index, trades.len() as u32),
+        };
+        self.put_order(&order_key, &record)?;
+        let account_key = key(
+            &[&event.tenant],
+            &[
+                event.account_id.to_be_bytes().as_slice(),
+                &event.index.to_be_bytes(),
+            ]
+            .concat(),
+        );
+        self.account_orders
+            .insert(account_key, order_key.as_slice())
+            .map_err(db_error)?;
+        if !order.client_order_id.is_empty() {
+            self.put_client_order(event, order, &order_key)?;
+        }
+
+        for (seq, trade) in trades.iter().enumerate() {
+            let trade_seq = seq as u32;
+            let taker_side = if trade.buyer_order_id == order.id {
+                OrderSide::Buy
+            } else {
+                OrderSide::Sell
+            };
+            let maker_id = match taker_side {
+                OrderSide::Buy => &trade.seller_order_id,
+                OrderSide::Sell => &trade.buyer_order_id,
+            };
+            let maker_key = key(&[&event.tenant, &order.symbol, maker_id], &[]);
+            let maker_account = match self.get_order(&maker_key)? {
+                Some(mut maker) => {
+                    if maker.last_change < (event.index, trade_seq) {
+                        maker.order.filled_quantity += trade.quantity;
+                        maker.order.update_status(event.time);
+                        maker.last_change = (event.index, trade_seq);
+                        self.put_order(&maker_key, &maker)?;
+                    }
+                    maker.account_id
+                }
+                None => 0,
+            };
+            let (buyer_account_id, seller_account_id) = match taker_side {
+                OrderSide::Buy => (event.account_id, maker_account),
+                OrderSide::Sell => (maker_account, event.account_id),
+            };
+            let record = TradeRecord {
+                index: event.index,
+                trade_seq,
+                symbol: order.symbol.clone(),
+                taker_side,
+                price: trade.price,
+                quantity: trade.quantity,
+                buyer_order_id: trade.buyer_order_id.clone(),
+                buyer_account_id,
+                seller_order_id: trade.seller_order_id.clone(),
+                seller_account_id,
+                time: event.time,
+            };
+            let trade_key = key(
+                &[&event.tenant, &order.symbol],
+                &position(event.index, trade_seq),
+            );
+            self.trades
+                .insert(trade_key.as_slice(), encode(&record)?)
+                .map_err(db_error)?;
+            for account_id in [buyer_account_id, seller_account_id] {
+                let account_key = key(
+                    &[&event.tenant],
+                    &[
+                        account_id.to_be_bytes().as_slice(),
+                        &position(event.index, trade_seq),
+                    ]
+                    .concat(),
+                );
+                self.account_trades
+                    .insert(account_key, trade_key.as_slice())
+                    .map_err(db_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Points the client order ID of a placed order at it
+    ///
+    /// An order rejected because its ID is in use by an open order of the
+    /// account leaves the ID with the open order.
+    fn put_client_order(
+        &self,
+        event: &OrderEvent,
+        order: &Order,
+        order_key: &[u8],
+    ) -> Result<(), String> {
+        let client_key = key(
+            &[&event.tenant, &order.client_order_id],
+            &event.account_id.to_be_bytes(),
+        );
+        if order.status == OrderStatus::Rejected {
+            if let Some(previous) = self.client_orders.get(&client_key).map_err(db_error)? {
+                let open = self
+                    .get_order(&previous)?
+                    .is_some_and(|record| record.order.is_cancelable());
+                if open && previous.as_ref() != order_key {
+                    return Ok(());
+                }
+            }
+        }
+        self.client_orders
+            .insert(client_key, order_key)
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Marks an order that is still open as canceled
+    fn close(&self, order_key: &[u8], event: &OrderEvent) -> Result<(), String> {
+        if let Some(mut record) = self.get_order(order_key)? {
+            if record.order.is_cancelable() {
+                record.order.status = OrderStatus::Canceled;
+                record.order.updated_at = event.time;
+                record.last_change = (event.index, 0);
+                self.put_order(order_key, &record)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads an order record
+    fn get_order(&self, order_key: &[u8]) -> Result<Option<OrderRecord>, String> {
+        match self.orders.get(order_key).map_err(db_error)? {
+            Some(value) => decode_order(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes an order record
+    fn put_order(&self, order_key: &[u8], record: &OrderRecord) -> Result<(), String> {
+        self.orders
+            .insert(order_key, encode(record)?)
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Returns an order
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - Tenant the order belongs to
+    /// * `symbol` - Symbol the order was placed on
+    /// * `order_id` - ID of the order
+    pub fn order(
+        &self,
+        tenant: &str,
+        symbol: &str,
+        order_id: &str,
+    ) -> Result<Option<OrderRecord>, String> {
+        self.get_order(&key(&[tenant, symbol, order_id], &[]))
+    }
+
+    /// Returns the latest order an account gave a client order ID
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - Tenant the account belongs to
+    /// * `account_id` - ID of the account
+    /// * `client_order_id` - ID the client gave the order
+    pub fn client_order(
+        &self,
+        tenant: &str,
+        account_id: u64,
+        client_order_id: &str,
+    ) -> Result<Option<OrderRecord>, String> {
+        let client_key = key(&[tenant, client_order_id], &account_id.to_be_bytes());
+        match self.client_orders.get(client_key).map_err(db_error)? {
+            Some(order_key) => self.get_order(&order_key),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the latest orders of an account, newest first
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - Tenant the account belongs to
+    /// * `account_id` - ID of the account
+    /// * `limit` - Maximum number of orders returned
+    pub fn account_orders(
+        &self,
+        tenant: &str,
+        account_id: u64,
+        limit: usize,
+    ) -> Result<Vec<OrderRecord>, String> {
+        let prefix = key(&[tenant], &account_id.to_be_bytes());
+        let mut records = Vec::new();
+        for entry in self.account_orders.scan_prefix(prefix).rev().take(limit) {
+            let (_, order_key) = entry.map_err(db_error)?;
+            records.extend(self.get_order(&order_key)?);
+        }
+        Ok(records)
+    }
+
+    /// Returns the latest trades of a symbol, newest first
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - Tenant the symbol belongs to
+    /// * `symbol` - Symbol traded
+    /// * `limit` - Maximum number of trades returned
+    pub fn symbol_trades(
+        &self,
+        tenant: &str,
+        symbol: &str,
+        limit: usize,
+    ) -> Result<Vec<TradeRecord>, String> {
+        let prefix = key(&[tenant, symbol], &[]);
+        self.trades
+            .scan_prefix(prefix)
+            .rev()
+            .take(limit)
+            .map(|entry| {
+                let (_, value) = entry.map_err(db_error)?;
+                decode_trade(&value)
+            })
+            .collect()
+    }
+
+    /// Returns the latest trades of an account on either side, newest first
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - Tenant the account belongs to
+    /// * `account_id` - ID of the account
+    /// * `limit` - Maximum number of trades returned
+    pub fn account_trades(
+        &self,
+        tenant: &str,
+        account_id: u64,
+        limit: usize,
+    ) -> Result<Vec<TradeRecord>, String> {
+        let prefix = key(&[tenant], &account_id.to_be_bytes());
+        let mut records = Vec::new();
+        for entry in self.account_trades.scan_prefix(prefix).rev().take(limit) {
+            let (_, trade_key) = entry.map_err(db_error)?;
+            if let Some(value) = self.trades.get(trade_key).map_err(db_error)? {
+                records.push(decode_trade(&value)?);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Removes closed orders and trades older than the given times
+    ///
+    /// Open orders are always kept. Pruned orders disappear from the account history
+    /// and pruned trades from both the symbol and the account history.
+    ///
+    /// # Arguments
+    ///
+    /// * `orders_before` - Engine time closed orders last updated before are pruned, None keeps them
+    /// * `trades_before` - Engine time trades made before are pruned, None keeps them
+    /// * `up_to_index` - Last raft index that may be pruned, later rows are kept
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of orders and trades pruned
+    pub fn prune(
+        &self,
+        orders_before: Option<u64>,
+        trades_before: Option<u64>,
+        up_to_index: u64,
+    ) -> Result<(usize, usize), String> {
+        let mut orders = 0;
+        if let Some(before) = orders_before {
+            for entry in self.orders.iter() {
+                let (order_key, value) = entry.map_err(db_error)?;
+                let record = decode_order(&value)?;
+                if record.order.is_cancelable()
+                    || record.order.updated_at >= before
+                    || record.last_change.0 > up_to_index
+                {
+                    continue;
+                }
+                let account_key = key(
+                    &[tenant_of(&order_key)],
+                    &[record.account_id.to_be_bytes(), record.index.to_be_bytes()].concat(),
+                );
+                self.account_orders.remove(account_key).map_err(db_error)?;
+                if !record.order.client_order_id.is_empty() {
+                    let client_key = key(
+                        &[tenant_of(&order_key), &record.order.client_order_id],
+                        &record.account_id.to_be_bytes(),
+                    );
+                    let latest = self.client_orders.get(&client_key).map_err(db_error)?;
+                    if latest.is_some_and(|latest| latest == order_key) {
+                        self.client_orders.remove(client_key).map_err(db_error)?;
+                    }
+                }
+                self.orders.remove(order_key).map_err(db_error)?;
+                orders += 1;
+            }
+        }
+        let mut trades = 0;
+        if let Some(before) = trades_before {
+            for entry in self.trades.iter() {
+                let (trade_key, value) = entry.map_err(db_error)?;
+                let record = decode_trade(&value)?;
+                if record.time >= before || record.index > up_to_index {
+                    continue;
+                }
+                for account_id in [record.buyer_account_id, record.seller_account_id] {
+                    let account_key = key(
+                        &[tenant_of(&trade_key)],
+                        &[
+                            account_id.to_be_bytes().as_slice(),
+                            &position(record.index, record.trade_seq),
+                        ]
+                        .concat(),
+                    );
+                    self.account_trades.remove(account_key).map_err(db_error)?;
+                }
+                self.trades.remove(trade_key).map_err(db_error)?;
+                trades += 1;
+            }
+        }
+        Ok((orders, trades))
+    }
+
+    /// Writes everything applied so far to disk
+    pub fn flush(&self) -> Result<(), String> {
+        self.db.flush().map(|_| ()).map_err(db_error)
+    }
+}
+
+/// Opens the query store if `query_store_path` is configured
+///
+/// Must be called before the raft loop starts applying entries.
+///
+/// # Returns
+///
+/// Returns an error if the database cannot be opened
+pub fn open() -> Result<(), String> {
+    let (path, order_retention, trade_retention) = {
+        let config = config::instance().lock().unwrap();
+        (
+            config.query_store_path.clone(),
+            config.order_retention_secs,
+            config.trade_retention_secs,
+        )
+    };
+    let store = match path {
+        Some(path) => {
+            let store = QueryStore::open(&path)?;
+            log::info!(
+                "serving queries from {} applied up to index {}",
+                path,
+                store.applied_index()?
+            );
+            Some(store)
+        }
+        None => None,
+    };
+    let _ = STORE.set(store);
+    if instance().is_some() && (order_retention.is_some() || trade_retention.is_some()) {
+        std::thread::Builder::new()
+            .name("retention".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(RETENTION_INTERVAL);
+                retain(order_retention, trade_retention);
+            })
+            .map_err(|e| format!("cannot start the retention thread: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Prunes the store by the configured retention, keeping what is not exported yet
+///
+/// # Arguments
+///
+/// * `order_retention` - Age in seconds closed orders are kept for
+/// * `trade_retention` - Age in seconds trades are kept for
+fn retain(order_retention: Option<u64>, trade_retention: Option<u64>) {
+    let Some(store) = instance() else {
+        return;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let up_to_index = projection::exported_index().unwrap_or(u64::MAX);
+    let result = store.prune(
+        order_retention.map(|age| now.saturating_sub(age)),
+        trade_retention.map(|age| now.saturating_sub(age)),
+        up_to_index,
+    );
+    match result {
+        Ok((orders, trades)) => {
+            log::info!("retention pruned {} orders and {} trades", orders, trades)
+        }
+        Err(e) => log::error!("cannot prune the query store: {}", e),
+    }
+}
+
+/// Returns the query store, None if it is disabled
+pub fn instance() -> Option<&'static QueryStore> {
+    STORE.get().and_then(Option::as_ref)
+}
+
+/// Applies the order events of an apply batch to the store
+///
+/// # Arguments
+///
+/// * `events` - Order events of an apply batch, in apply order
+pub fn write(events: &[OrderEvent]) {
+    let store = match instance() {
+        Some(store) if !events.is_empty() => store,
+        _ => return,
+    };
+    if let Err(e) = store.apply(events) {
+        log::error!("cannot apply {} order events: {}", events.len(), e);
+    }
+}
+
+/// Flushes the store, called before a snapshot lets the raft log be compacted
+pub fn flush() {
+    if let Some(store) = instance() {
+        if let Err(e) = store.flush() {
+            log::error!("cannot flush the query store: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn order(id: &str, side: OrderSide, quantity: Decimal, status: OrderStatus) -> Order {
+        Order {
+            id: id.to_string(),
+            symbol: "BTCUSDT".to_string(),
+            side,
+            price: dec!(100),
+            quantity,
+            status,
+            ..Default::default()
+        }
+    }
+
+    fn placed(index: u64, account_id: u64, order: Order, trades: Vec<Trade>) -> OrderEvent {
+        OrderEvent {
+            index,
+            tenant: "default".to_string(),
+            account_id,
+            time: index,
+            change: OrderChange::Placed { order, trades },
+        }
+    }
+
+    #[test]
+    fn makers_are_filled_and_replays_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = QueryStore::open(dir.path().to_str().unwrap()).unwrap();
+        let ask = order("1", OrderSide::Sell, dec!(2), OrderStatus::New);
+        let mut bid = order("2", OrderSide::Buy, dec!(1), OrderStatus::Filled);
+        bid.filled_quantity = dec!(1);
+        let trade = Trade::new(
+            "t".to_string(),
+            "BTCUSDT".to_string(),
+            dec!(100),
+            dec!(1),
+            "2".to_string(),
+            "1".to_string(),
+            0,
+        );
+        let events = [
+            placed(3, 7, ask, Vec::new()),
+            placed(4, 8, bid, vec![trade]),
+        ];
+        store.apply(&events).unwrap();
+        // Replaying the log after a restart must not fill the maker twice
+        store.apply(&events).unwrap();
+        assert_eq!(store.applied_index().unwrap(), 4);
+
+        let maker = store.order("default", "BTCUSDT", "1").unwrap().unwrap();
+        assert_eq!(maker.order.filled_quantity, dec!(1));
+        assert_eq!(maker.order.status, OrderStatus::PartiallyFilled);
+        let trades = store.account_trades("default", 7, 10).unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].taker_side, OrderSide::Buy);
+        assert_eq!(
+            (trades[0].buyer_account_id, trades[0].seller_account_id),
+            (8, 7)
+        );
+        assert_eq!(
+            store.symbol_trades("default", "BTCUSDT", 10).unwrap(),
+            trades
+        );
+
+        store
+            .apply(&[OrderEvent {
+                index: 5,
+                tenant: "default".to_string(),
+                account_id: 0,
+                time: 5,
+                change: OrderChange::SymbolRemoved {
+                    symbol: "BTCUSDT".to_string(),
+                },
+            }])
+            .unwrap();
+        let orders = store.account_orders("default", 7, 10).unwrap();
+        assert_eq!(orders[0].order.status, OrderStatus::Canceled);
+        let taker = store.order("default", "BTCUSDT", "2").unwrap().unwrap();
+        assert_eq!(taker.order.status, OrderStatus::Filled);
+    }
+
+    #[test]
+    fn retention_prunes_closed_orders_and_old_trades_up_to_the_exported_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = QueryStore::open(dir.path().to_str().unwrap()).unwrap();
+        let mut ask = order("1", OrderSide::Sell, dec!(1), OrderStatus::New);
+        ask.updated_at = 3;
+        let mut bid = order("2", OrderSide::Buy, dec!(1), OrderStatus::Filled);
+        bid.filled_quantity = dec!(1);
+        bid.updated_at = 4;
+        let trade = Trade::new(
+            "t".to_string(),
+            "BTCUSDT".to_string(),
+            dec!(100),
+            dec!(1),
+            "2".to_string(),
+            "1".to_string(),
+            0,
+        );
+        let mut rest = order("3", OrderSide::Buy, dec!(1), OrderStatus::New);
+        rest.updated_at = 6;
+        store
+            .apply(&[
+                placed(3, 7, ask, Vec::new()),
+                placed(4, 8, bid, vec![trade]),
+                placed(6, 8, rest, Vec::new()),
+            ])
+            .unwrap();
+
+        // Nothing changed past the exported index goes, the maker was filled at 4
+        assert_eq!(store.prune(Some(10), Some(10), 3).unwrap(), (0, 0));
+        assert_eq!(store.prune(Some(10), Some(10), 6).unwrap(), (2, 1));
+        assert!(store.order("default", "BTCUSDT", "1").unwrap().is_none());
+        assert!(store.order("default", "BTCUSDT", "2").unwrap().is_none());
+        let orders = store.account_orders("default", 8, 10).unwrap();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].order.id, "3");
+        assert!(store
+            .symbol_trades("default", "BTCUSDT", 10)
+            .unwrap()
+            .is_empty());
+        assert!(store.account_trades("default", 7, 10).unwrap().is_empty());
+        assert_eq!(store.account_trades.len(), 0);
+    }
+
+    #[test]
+    fn client_order_ids_find_the_latest_order_not_a_rejected_duplicate() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = QueryStore::open(dir.path().to_str().unwrap()).unwrap();
+        let with_client_id = |id: &str, status| Order {
+            client_order_id: "abc".to_string(),
+            ..order(id, OrderSide::Buy, dec!(1), status)
+        };
+        let events = [
+            placed(1, 7, with_client_id("1", OrderStatus::Canceled), Vec::new()),
+            placed(2, 7, with_client_id("2", OrderStatus::New), Vec::new()),
+            placed(3, 7, with_client_id("3", OrderStatus::Rejected), Vec::new()),
+            placed(4, 8, with_client_id("4", OrderStatus::Rejected), Vec::new()),
+        ];
+        store.apply(&events).unwrap();
+        let found = |account_id| {
+            store
+                .client_order("default", account_id, "abc")
+                .unwrap()
+                .map(|record| record.order.id)
+        };
+        assert_eq!(found(7).as_deref(), Some("2"));
+        assert_eq!(found(8).as_deref(), Some("4"));
+        assert_eq!(found(9), None);
+    }
+}
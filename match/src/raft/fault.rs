@@ -0,0 +1,174 @@
+//! Fault injection for the raft transport and storage
+//!
+//! Decides for every outgoing raft message whether it is dropped, delayed or
+//! delivered twice, and cuts node sets off from the rest of the cluster during
+//! scheduled partition windows. Delays are drawn per message, so delayed
+//! messages also overtake each other. The process can also be made to abort at
+//! crash points of the raft loop, to test recovery from a crash at exactly that
+//! point. Compiled for tests and with the `fault-injection` feature only, where
+//! it is configured with the `fault_injection` section of the runtime config.
+
+use once_cell::sync::OnceCell;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Crash probabilities of this process and the source of the crash decisions
+static CRASHES: OnceCell<Mutex<(HashMap<CrashPoint, f64>, StdRng)>> = OnceCell::new();
+
+/// Faults injected into the raft transport
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct FaultConfig {
+    /// Seed of the fault decisions, random if unset
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Fraction of messages dropped
+    #[serde(default)]
+    pub drop_ratio: f64,
+    /// Fraction of messages delivered twice
+    #[serde(default)]
+    pub duplicate_ratio: f64,
+    /// Fraction of messages delayed
+    #[serde(default)]
+    pub delay_ratio: f64,
+    /// Longest delay in milliseconds, delays are uniformly distributed up to it
+    #[serde(default)]
+    pub max_delay_ms: u64,
+    /// Scheduled network partitions
+    #[serde(default)]
+    pub partitions: Vec<PartitionConfig>,
+    /// Probability of aborting the process each time a crash point is passed
+    #[serde(default)]
+    pub crash_ratio: HashMap<CrashPoint, f64>,
+}
+
+/// Point of the raft loop at which the process can be made to crash
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum CrashPoint {
+    /// Log entries are written, the hard state is not saved and no message is sent yet
+    Append,
+    /// A new snapshot is written and the previous one removed, but not yet renamed into place
+    Snapshot,
+    /// A batch of entries is applied to the state machine, the applied index has not advanced yet
+    Apply,
+}
+
+/// A node set cut off from the rest of the cluster for a time window
+#[derive(Debug, Deserialize, Clone)]
+pub struct PartitionConfig {
+    /// Start of the partition in milliseconds since the transport started
+    pub start_ms: u64,
+    /// End of the partition in milliseconds since the transport started
+    pub end_ms: u64,
+    /// Nodes on the minority side of the partition
+    pub nodes: Vec<u64>,
+}
+
+impl PartitionConfig {
+    /// Checks whether the partition separates two nodes at the given time
+    fn separates(&self, from: u64, to: u64, now: Duration) -> bool {
+        let now = now.as_millis() as u64;
+        now >= self.start_ms
+            && now < self.end_ms
+            && self.nodes.contains(&from) != self.nodes.contains(&to)
+    }
+}
+
+/// Applies a fault configuration to individual messages
+pub struct FaultInjector {
+    /// Faults to inject
+    config: FaultConfig,
+    /// Source of the fault decisions
+    rng: StdRng,
+}
+
+impl FaultInjector {
+    /// Creates an injector for the given faults
+    pub fn new(config: FaultConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self { config, rng }
+    }
+
+    /// Decides how a message is delivered
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - Sending node
+    /// * `to` - Receiving node
+    /// * `now` - Time since the transport started
+    ///
+    /// # Returns
+    ///
+    /// Returns the delay of each copy of the message to deliver, empty if the message is dropped
+    pub fn deliveries(&mut self, from: u64, to: u64, now: Duration) -> Vec<Duration> {
+        if self
+            .config
+            .partitions
+            .iter()
+            .any(|partition| partition.separates(from, to, now))
+        {
+            return Vec::new();
+        }
+        if self.rng.gen_bool(self.config.drop_ratio) {
+            return Vec::new();
+        }
+        let copies = if self.rng.gen_bool(self.config.duplicate_ratio) {
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                if self.config.max_delay_ms > 0 && self.rng.gen_bool(self.config.delay_ratio) {
+                    Duration::from_millis(self.rng.gen_range(1..=self.config.max_delay_ms))
+                } else {
+                    Duration::ZERO
+                }
+            })
+            .collect()
+    }
+}
+
+/// Enables the crash points of this process
+///
+/// Only the first call takes effect.
+///
+/// # Arguments
+///
+/// * `config` - Faults to inject, its seed also seeds the crash decisions
+pub fn install_crash_points(config: &FaultConfig) {
+    let rng = match config.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let _ = CRASHES.set(Mutex::new((config.crash_ratio.clone(), rng)));
+}
+
+/// Aborts the process with the probability configured for a crash point
+///
+/// Aborting skips destructors and buffered writes just like `kill -9`. Does
+/// nothing unless crash points were installed.
+///
+/// # Arguments
+///
+/// * `point` - Crash point being passed
+pub fn crash_point(point: CrashPoint) {
+    let crashes = match CRASHES.get() {
+        Some(crashes) => crashes,
+        None => return,
+    };
+    let mut crashes = crashes.lock().unwrap();
+    let (ratios, rng) = &mut *crashes;
+    let ratio = ratios.get(&point).copied().unwrap_or(0.0).clamp(0.0, 1.0);
+    if ratio > 0.0 && rng.gen_bool(ratio) {
+        log::error!("injected crash at {:?}", point);
+        std::process::abort();
+    }
+}
@@ -1,20 +1,246 @@
 //! Raft implementation
 //! This module provides a Raft consensus implementation with file-based storage.
 
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod fault; // Transport fault injection
 pub mod node; // Raft node implementation
 pub mod proposal; // Proposal handling
-mod segment; // File segment implementation
+pub mod segment; // File segment implementation
+#[cfg(test)]
+mod sim; // Deterministic cluster simulation
 mod storage; // Storage implementation
 
+use raft::eraftpb::{ConfState, Entry, HardState, Snapshot};
+use raft::Storage;
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Serialization of a frozen state machine state, run off the raft loop
+pub type FrozenSnapshot = Box<dyn FnOnce() -> Result<Vec<u8>, String> + Send>;
+
+/// Why a committed entry was not applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApplyError {
+    /// The entry is malformed for every build, e.g. it cannot be decoded in
+    /// any format version, and left the state unchanged. A refusal that
+    /// depends on the build is `Unsupported`, see `StateMachine::apply`
+    Malformed(String),
+    /// The entry needs a command format or feature this build does not
+    /// support; newer replicas apply it, so the state can no longer be trusted
+    Unsupported(String),
+    /// Applying the entry panicked with the given message, the state may be
+    /// half-applied and can no longer be trusted
+    Panicked(String),
+}
+
+impl fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApplyError::Malformed(reason) => write!(f, "malformed: {}", reason),
+            ApplyError::Unsupported(reason) => write!(f, "unsupported: {}", reason),
+            ApplyError::Panicked(message) => write!(f, "panicked: {}", message),
+        }
+    }
+}
+
+/// Returns the message a panic was raised with
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Applies an entry, turning a panic into an error
+///
+/// # Arguments
+///
+/// * `apply` - Applies the entry, returns why it was not applied
+///
+/// # Returns
+///
+/// Returns why the entry was not applied or that applying it panicked
+pub fn catch_apply(apply: impl FnOnce() -> Result<(), ApplyError>) -> Result<(), ApplyError> {
+    match panic::catch_unwind(AssertUnwindSafe(apply)) {
+        Ok(result) => result,
+        Err(payload) => Err(ApplyError::Panicked(panic_message(payload.as_ref()))),
+    }
+}
+
 /// Trait for implementing a state machine that can be managed by Raft
 /// The state machine is responsible for applying committed entries and handling snapshots
+///
+/// The node owns the state machine and never clones it. Errors are handled by
+/// the node: a malformed entry is logged, counted in `raft_apply_rejected` and
+/// skipped, a failed snapshot is retried at the next snapshot interval, and a
+/// failed restore stops the node, which could not apply anything after it. An
+/// unsupported entry or a panic while applying fences the node, see `state_failure`.
 pub trait StateMachine {
     /// Apply a committed entry to the state machine
-    fn apply(&mut self, index: u64, data: &[u8]);
+    ///
+    /// # Returns
+    ///
+    /// Returns why the entry was not applied. A malformed entry must leave the
+    /// state unchanged and be refused alike on every replica of every build,
+    /// e.g. because no format version decodes it; the node skips it and goes on
+    /// with the next entry. Any other refusal, e.g. of an entry only a newer
+    /// build can apply, must be unsupported so the node never skips it.
+    fn apply(&mut self, index: u64, data: &[u8]) -> Result<(), ApplyError>;
+
+    /// Apply a batch of committed entries in log order
+    ///
+    /// Each entry comes with its index. The node passes every run of normal
+    /// entries between configuration changes and checksums as one batch; the
+    /// default applies them one by one, state machines override it to share
+    /// decoding, locking and bookkeeping across the batch. Overrides should
+    /// apply each entry with `catch_apply`, so a panic names its entry.
+    ///
+    /// # Returns
+    ///
+    /// Returns the index of each malformed entry and why, see `apply`; the other
+    /// entries of the batch are applied regardless. Applying stops at the
+    /// first entry that is unsupported or panicked, which is the last one returned.
+    fn apply_batch(&mut self, entries: &[(u64, &[u8])]) -> Vec<(u64, ApplyError)> {
+        let mut errors = Vec::new();
+        for (index, data) in entries {
+            if let Err(e) = catch_apply(|| self.apply(*index, data)) {
+                let failed = !matches!(e, ApplyError::Malformed(_));
+                errors.push((*index, e));
+                if failed {
+                    break;
+                }
+            }
+        }
+        errors
+    }
+
+    /// Called after a batch of committed entries has been applied
+    fn on_apply_batch(&mut self) {}
+
+    /// Finish applying the entries passed to `apply`
+    ///
+    /// State machines may apply entries in the background; the node calls this
+    /// before it reads the state, i.e. before checksums and snapshots.
+    fn barrier(&mut self) {}
 
     /// Create a snapshot of the current state machine state
-    fn snapshot(&self) -> Vec<u8>;
+    fn snapshot(&self) -> Result<Vec<u8>, String>;
+
+    /// Freeze the current state for a snapshot serialized on a background thread
+    ///
+    /// Called after `barrier`, entries are applied meanwhile. The default
+    /// serializes right away on the raft loop; state machines that can share
+    /// their state cheaply override it to serialize a frozen copy instead.
+    fn freeze(&self) -> Result<FrozenSnapshot, String> {
+        let data = self.snapshot()?;
+        Ok(Box::new(move || Ok(data)))
+    }
 
     /// Restore the state machine from a snapshot
-    fn on_snapshot(&mut self, last_index: u64, last_term: u64, data: &[u8]);
+    ///
+    /// # Returns
+    ///
+    /// Returns why the snapshot could not be restored
+    fn on_snapshot(&mut self, last_index: u64, last_term: u64, data: &[u8]) -> Result<(), String>;
+}
+
+/// Trait for the storage a raft node persists its log, hard state and snapshots to
+pub trait LogStorage: Storage {
+    /// Append entries to storage
+    fn append_entries(&mut self, entries: &[Entry]) -> raft::Result<()>;
+
+    /// Set the configuration state
+    fn set_conf_state(&mut self, conf_state: ConfState);
+
+    /// Set the hard state
+    fn set_hardstate(&mut self, hs: HardState);
+
+    /// Set the commit index
+    fn set_commit(&mut self, commit: u64);
+
+    /// Apply a snapshot received from the leader
+    fn apply_snapshot(&mut self, snapshot: &Snapshot) -> raft::Result<()>;
+
+    /// Save a snapshot of the state machine at the applied index and compact the log,
+    /// keeping the entries from `compact_to` on, at most the applied index
+    fn save_snapshot(
+        &mut self,
+        biz_data: Vec<u8>,
+        applied: u64,
+        compact_to: u64,
+    ) -> raft::Result<()>;
+
+    /// Get the current commit index
+    fn commit(&self) -> u64;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sums the entries, empty ones are malformed; longer ones are of a newer build
+    #[derive(Default)]
+    struct Sum(u64);
+
+    impl StateMachine for Sum {
+        fn apply(&mut self, _index: u64, data: &[u8]) -> Result<(), ApplyError> {
+            let byte = data
+                .first()
+                .ok_or_else(|| ApplyError::Malformed("empty entry".to_string()))?;
+            if data.len() > 1 {
+                return Err(ApplyError::Unsupported("newer entry".to_string()));
+            }
+            assert!(self.0 < 256, "sum overflows a byte");
+            self.0 += u64::from(*byte);
+            Ok(())
+        }
+
+        fn snapshot(&self) -> Result<Vec<u8>, String> {
+            Ok(self.0.to_le_bytes().to_vec())
+        }
+
+        fn on_snapshot(&mut self, _: u64, _: u64, data: &[u8]) -> Result<(), String> {
+            let bytes = data.try_into().map_err(|_| "not a sum".to_string())?;
+            self.0 = u64::from_le_bytes(bytes);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn malformed_entries_do_not_stop_the_batch_but_failures_do() {
+        let mut sum = Sum::default();
+        let rejected = sum.apply_batch(&[(1, &[2]), (2, &[]), (3, &[5])]);
+        assert_eq!(
+            rejected,
+            vec![(2, ApplyError::Malformed("empty entry".to_string()))]
+        );
+        assert_eq!(sum.0, 7);
+
+        let frozen = sum.freeze().unwrap();
+        sum.apply(4, &[1]).unwrap();
+        let mut restored = Sum::default();
+        restored.on_snapshot(4, 1, &frozen().unwrap()).unwrap();
+        assert_eq!(restored.0, 7);
+        assert!(restored.on_snapshot(4, 1, &[1]).is_err());
+
+        // A panic stops the batch at its entry
+        let failed = restored.apply_batch(&[(5, &[255]), (6, &[1]), (7, &[1])]);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, 6);
+        assert!(matches!(failed[0].1, ApplyError::Panicked(_)));
+        assert_eq!(restored.0, 262);
+
+        // So does an entry of a newer build, unlike a malformed one
+        let mut sum = Sum::default();
+        let failed = sum.apply_batch(&[(1, &[2]), (2, &[1, 1]), (3, &[5])]);
+        assert_eq!(
+            failed,
+            vec![(2, ApplyError::Unsupported("newer entry".to_string()))]
+        );
+        assert_eq!(sum.0, 2);
+    }
 }
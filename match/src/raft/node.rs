@@ -5,16 +5,27 @@
 #![allow(clippy::field_reassign_with_default)]
 
 use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
 
 use slog::Drain;
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot::{self, error::TryRecvError};
 use tokio::time::{self, Duration, Instant};
 
 use protobuf::Message as PbMessage;
-use raft::{prelude::*, StateRole};
+use raft::{prelude::*, ProgressState, ReadState, StateRole};
 
-use crate::raft::proposal::Proposal;
-use crate::raft::StateMachine;
+use crate::affinity;
+use crate::cluster_events;
+use crate::cluster_status::{self, PeerStatus, RaftStatus};
+use crate::divergence::StateCheck;
+use crate::metrics;
+use crate::raft::proposal::{Proposal, ProposalReceivers, SnapshotRequest};
+use crate::raft::{panic_message, ApplyError, LogStorage, StateMachine};
+use crate::readiness;
+use crate::snapshot_progress::{self, Kind};
+use crate::staleness;
+use crate::state_failure;
 use slog::o;
 
 use super::storage::FileStorage;
@@ -28,8 +39,9 @@ const SAVE_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60); // Interval fo
 fn default_config(id: u64, applied: u64) -> Config {
     Config {
         id,
-        election_tick: 10, // Number of ticks before starting election
-        heartbeat_tick: 3, // Number of ticks between heartbeats
+        election_tick: 10,  // Number of ticks before starting election
+        heartbeat_tick: 3,  // Number of ticks between heartbeats
+        check_quorum: true, // Leader steps down without a quorum, which lease reads rely on
         applied,
         ..Default::default()
     }
@@ -45,17 +57,27 @@ fn is_initial_msg(msg: &Message) -> bool {
 }
 
 /// Add all followers to the cluster
-/// This function adds multiple followers to the Raft cluster through configuration changes
-pub async fn add_all_followers(ids: Vec<u64>, proposals: &Sender<Proposal>) {
-    for id in ids {
+/// This function adds multiple followers to the Raft cluster through configuration changes,
+/// each as a voter or, if flagged, as a learner
+pub async fn add_all_followers(ids: Vec<(u64, bool)>, proposals: &Sender<Proposal>) {
+    for (id, learner) in ids {
         let mut conf_change = ConfChange::default();
         conf_change.node_id = id;
-        conf_change.set_change_type(ConfChangeType::AddNode);
+        conf_change.set_change_type(if learner {
+            ConfChangeType::AddLearnerNode
+        } else {
+            ConfChangeType::AddNode
+        });
         let (proposal, rx) = Proposal::conf_change(&conf_change);
         let _ = proposals.send(proposal).await;
         match rx.await {
             Ok(ret) => {
-                log::info!("Add follower {}, result: {}", id, ret);
+                log::info!(
+                    "Add follower {} (learner: {}), result: {}",
+                    id,
+                    learner,
+                    ret
+                );
             }
             Err(e) => {
                 log::error!("Failed to add follower: {:?}", e);
@@ -64,42 +86,79 @@ pub async fn add_all_followers(ids: Vec<u64>, proposals: &Sender<Proposal>) {
     }
 }
 
+/// Index the log is compacted to after saving a snapshot
+/// Keeps the entries the slowest follower still needs, at most `max_retained` of
+/// them before the snapshot, so a briefly lagging follower gets entries instead
+/// of the whole snapshot
+///
+/// # Arguments
+///
+/// * `applied` - Applied index the snapshot covers
+/// * `matched` - Match indexes of the live followers
+/// * `max_retained` - Entries before the snapshot that may be kept, 0 compacts to it
+fn compaction_index(
+    applied: u64,
+    matched: impl IntoIterator<Item = u64>,
+    max_retained: u64,
+) -> u64 {
+    let needed = matched
+        .into_iter()
+        .map(|matched| matched + 1)
+        .min()
+        .unwrap_or(applied)
+        .min(applied);
+    needed.max(applied.saturating_sub(max_retained))
+}
+
+/// Snapshot being serialized in the background
+struct Saving {
+    /// Applied index the snapshot covers
+    applied: u64,
+    /// Entries before the snapshot that may be kept, see `compaction_index`
+    max_retained: u64,
+    /// Receives the serialized snapshot
+    rx: oneshot::Receiver<Result<Vec<u8>, String>>,
+}
+
 /// Raft node implementation
 /// This struct represents a Raft node with its associated state and components
-pub struct Node<S: StateMachine> {
-    raft_group: RawNode<FileStorage>, // The core Raft node implementation
-    out_mailbox: Sender<Message>,     // Channel for sending messages to other nodes
-    my_mailbox: Receiver<Message>,    // Channel for receiving messages from other nodes
-    state_machine: S,                 // The state machine that applies committed entries
-    proposals: Receiver<Proposal>,    // Channel for receiving proposals
-    proposed: VecDeque<Proposal>,     // Queue of pending proposals
+pub struct Node<S: StateMachine, L: LogStorage = FileStorage> {
+    raft_group: RawNode<L>,            // The core Raft node implementation
+    out_mailbox: Sender<Message>,      // Channel for sending messages to other nodes
+    my_mailbox: Receiver<Message>,     // Channel for receiving messages from other nodes
+    state_machine: S,                  // The state machine that applies committed entries
+    proposals: ProposalReceivers,      // Channels for receiving proposals
+    proposed: VecDeque<Proposal>,      // Queue of pending proposals
+    commits: VecDeque<(u64, Instant)>, // Commit indexes not yet applied and when they were seen
+    state_check: Option<StateCheck>,   // Schedule of state checksums, None if disabled
+    snapshotting: Option<Saving>,      // Snapshot being serialized in the background
+    learner_promotion: Option<u64>, // Lag under which learners are promoted, None if promoted by hand
+    max_retained_entries: u64,      // Entries kept before a snapshot for lagging followers
 }
 
-impl<S: StateMachine + Send + Clone + 'static> Node<S> {
+impl<S: StateMachine + Send + 'static> Node<S, FileStorage> {
     /// Create a new raft leader node
     /// Initializes a new Raft node with leader configuration
     fn create_raft_leader(
         id: u64,
         out_mailbox: Sender<Message>,
         my_mailbox: Receiver<Message>,
-        proposals: Receiver<Proposal>,
+        proposals: ProposalReceivers,
         logger: &slog::Logger,
         state_machine: S,
         base_path: &str,
     ) -> Self {
-        let logger = logger.new(o!("tag" => format!("peer_{}", id)));
         let storage = FileStorage::new(base_path, true).unwrap();
         let cfg = default_config(id, storage.commit());
-        let raft_group = RawNode::new(&cfg, storage, &logger).unwrap();
-
-        Node {
-            raft_group,
+        Node::new(
+            &cfg,
+            storage,
             out_mailbox,
             my_mailbox,
             proposals,
+            logger,
             state_machine,
-            proposed: VecDeque::new(),
-        }
+        )
     }
 
     /// Create a new raft follower node
@@ -108,15 +167,110 @@ impl<S: StateMachine + Send + Clone + 'static> Node<S> {
         id: u64,
         out_mailbox: Sender<Message>,
         my_mailbox: Receiver<Message>,
-        proposals: Receiver<Proposal>,
+        proposals: ProposalReceivers,
         logger: &slog::Logger,
         state_machine: S,
         base_path: &str,
     ) -> Self {
-        let logger = logger.new(o!("tag" => format!("peer_{}", id)));
         let storage = FileStorage::new(base_path, false).unwrap();
         let cfg = default_config(id, storage.commit());
-        let raft_group = RawNode::new(&cfg, storage, &logger).unwrap();
+        Node::new(
+            &cfg,
+            storage,
+            out_mailbox,
+            my_mailbox,
+            proposals,
+            logger,
+            state_machine,
+        )
+    }
+
+    /// Start a new raft node
+    /// Initializes and starts a new Raft node with the specified configuration.
+    /// Outgoing messages are queued up to `outbound_capacity` in the returned mailbox.
+    /// With `dedicated_cores` set the raft loop gets a thread of its own running a
+    /// current-thread runtime, pinned to the given cores unless they are empty;
+    /// otherwise it is spawned on the calling runtime.
+    /// While leader, learners within `learner_promotion` entries of the log are promoted
+    /// to voters, never if it is None. Compacting after a snapshot keeps up to
+    /// `max_retained_entries` entries the slowest live follower still needs
+    #[allow(clippy::too_many_arguments)]
+    pub fn start_raft(
+        with_leader: bool,
+        id: u64,
+        rx: Receiver<Message>,
+        rx_proposals: ProposalReceivers,
+        state_machine: S,
+        base_path: &str,
+        state_check: Option<StateCheck>,
+        learner_promotion: Option<u64>,
+        max_retained_entries: u64,
+        outbound_capacity: usize,
+        dedicated_cores: Option<Vec<usize>>,
+    ) -> Receiver<Message> {
+        // Setup logger
+        let decorator = slog_term::TermDecorator::new().build();
+        let drain = slog_term::FullFormat::new(decorator).build().fuse();
+        let drain = slog_async::Async::new(drain)
+            .chan_size(LOGGER_CHANNEL_SIZE)
+            .overflow_strategy(slog_async::OverflowStrategy::Block)
+            .build()
+            .fuse();
+        let logger = slog::Logger::root(drain, o!());
+
+        let (sx, out_mailbox) = mpsc::channel(outbound_capacity);
+        metrics::watch_channel("raft_outbound", &sx);
+
+        // Create and start node
+        let mut node = if with_leader {
+            Node::create_raft_leader(id, sx, rx, rx_proposals, &logger, state_machine, base_path)
+        } else {
+            Node::create_raft_follower(id, sx, rx, rx_proposals, &logger, state_machine, base_path)
+        };
+        node.state_check = state_check;
+        node.learner_promotion = learner_promotion;
+        node.max_retained_entries = max_retained_entries;
+        readiness::restored(node.raft_group.raft.raft_log.committed);
+
+        match dedicated_cores {
+            None => {
+                tokio::spawn(async move {
+                    node.run_background_tasks().await;
+                });
+            }
+            Some(cores) => {
+                std::thread::Builder::new()
+                    .name("raft".to_string())
+                    .spawn(move || {
+                        affinity::pin_or_warn("raft", &cores);
+                        let runtime = tokio::runtime::Builder::new_current_thread()
+                            .enable_all()
+                            .build()
+                            .expect("build raft runtime");
+                        runtime.block_on(node.run_background_tasks());
+                    })
+                    .expect("spawn raft thread");
+            }
+        }
+
+        out_mailbox
+    }
+}
+
+impl<S: StateMachine + Send + 'static, L: LogStorage + Send + 'static> Node<S, L> {
+    /// Create a raft node on top of the given storage
+    /// Entries up to `cfg.applied` must already be reflected in the state machine
+    pub(super) fn new(
+        cfg: &Config,
+        storage: L,
+        out_mailbox: Sender<Message>,
+        my_mailbox: Receiver<Message>,
+        proposals: ProposalReceivers,
+        logger: &slog::Logger,
+        state_machine: S,
+    ) -> Self {
+        let logger = logger.new(o!("tag" => format!("peer_{}", cfg.id)));
+        let raft_group = RawNode::new(cfg, storage, &logger).unwrap();
 
         Node {
             raft_group,
@@ -125,37 +279,118 @@ impl<S: StateMachine + Send + Clone + 'static> Node<S> {
             proposals,
             state_machine,
             proposed: VecDeque::new(),
+            commits: VecDeque::new(),
+            state_check: None,
+            snapshotting: None,
+            learner_promotion: None,
+            max_retained_entries: 0,
         }
     }
 
     /// Process committed entries
-    /// Applies committed entries to the state machine and handles configuration changes
+    /// Applies committed entries to the state machine, handles configuration changes
+    /// and takes the state checksums that are due
     fn handle_committed_entries(
-        raft_group: &mut RawNode<FileStorage>,
+        raft_group: &mut RawNode<L>,
         entries: Vec<Entry>,
         state_machine: &mut S,
+        commits: &mut VecDeque<(u64, Instant)>,
+        state_check: Option<&StateCheck>,
     ) -> u64 {
         let mut last_index = 0u64;
-        for entry in entries {
-            if entry.data.is_empty() {
-                continue;
+        let mut batch = Vec::with_capacity(entries.len());
+        for entry in &entries {
+            Self::observe_apply(entry.index, commits);
+            if !entry.data.is_empty() {
+                match entry.get_entry_type() {
+                    EntryType::EntryConfChange => {
+                        Self::apply_batch(state_machine, &mut batch);
+                        let mut cc = ConfChange::default();
+                        cc.merge_from_bytes(&entry.data).unwrap();
+                        let cs = raft_group.apply_conf_change(&cc).unwrap();
+                        raft_group.raft.raft_log.store.set_conf_state(cs);
+                    }
+                    _ => batch.push((entry.index, entry.data.as_ref())),
+                }
+                last_index = entry.index;
             }
 
-            match entry.get_entry_type() {
-                EntryType::EntryConfChange => {
-                    let mut cc = ConfChange::default();
-                    cc.merge_from_bytes(&entry.data).unwrap();
-                    let cs = raft_group.apply_conf_change(&cc).unwrap();
-                    raft_group.raft.raft_log.store.set_conf_state(cs);
+            if let Some(state_check) = state_check {
+                if state_check.is_due(entry.index) && state_failure::failed_at().is_none() {
+                    Self::apply_batch(state_machine, &mut batch);
+                    state_check.observe(entry.index, raft_group.raft.leader_id, state_machine);
+                }
+            }
+        }
+        Self::apply_batch(state_machine, &mut batch);
+        last_index
+    }
+
+    /// Apply a run of normal entries as one batch
+    /// Clears the batch, does nothing if it is empty or the state machine failed.
+    /// An unsupported entry or a panic fails the state machine at its entry, a
+    /// panic the state machine did not attribute to an entry at the first entry
+    /// of the batch; only malformed entries are skipped.
+    fn apply_batch(state_machine: &mut S, batch: &mut Vec<(u64, &[u8])>) {
+        if batch.is_empty() || state_failure::failed_at().is_some() {
+            batch.clear();
+            return;
+        }
+        let start = Instant::now();
+        let errors = panic::catch_unwind(AssertUnwindSafe(|| state_machine.apply_batch(batch)))
+            .unwrap_or_else(|payload| {
+                vec![(
+                    batch[0].0,
+                    ApplyError::Panicked(panic_message(payload.as_ref())),
+                )]
+            });
+        for (index, e) in errors {
+            match e {
+                ApplyError::Malformed(e) => {
+                    log::error!("Skipped malformed entry {}: {}", index, e);
+                    metrics::RAFT_APPLY_REJECTED_COUNTER.inc();
                 }
-                _ => {
-                    state_machine.apply(entry.index, entry.data.as_ref());
+                ApplyError::Unsupported(message) | ApplyError::Panicked(message) => {
+                    let data = batch
+                        .iter()
+                        .find(|(i, _)| *i == index)
+                        .map(|(_, data)| *data);
+                    state_failure::record(index, &message, data);
                 }
             }
+        }
+        metrics::RAFT_APPLY_HISTOGRAM.observe(start.elapsed().as_secs_f64());
+        metrics::RAFT_APPLY_BATCH_SIZE_HISTOGRAM.observe(batch.len() as f64);
+        #[cfg(feature = "fault-injection")]
+        super::fault::crash_point(super::fault::CrashPoint::Apply);
+        batch.clear();
+    }
 
-            last_index = entry.index;
+    /// Remember when the commit index advanced
+    /// Used to measure how long committed entries wait before being applied
+    fn observe_commit(raft_group: &RawNode<L>, commits: &mut VecDeque<(u64, Instant)>) {
+        let committed = raft_group.raft.raft_log.committed;
+        let last = commits
+            .back()
+            .map(|(index, _)| *index)
+            .unwrap_or_else(|| raft_group.raft.raft_log.applied());
+        if committed > last {
+            commits.push_back((committed, Instant::now()));
+        }
+    }
+
+    /// Record the commit to apply delay of an entry
+    /// Drops commit observations that are fully applied
+    fn observe_apply(index: u64, commits: &mut VecDeque<(u64, Instant)>) {
+        if let Some((_, seen)) = commits.iter().find(|(committed, _)| *committed >= index) {
+            metrics::RAFT_COMMIT_TO_APPLY_HISTOGRAM.observe(seen.elapsed().as_secs_f64());
+        }
+        while let Some((committed, _)) = commits.front() {
+            if *committed > index {
+                break;
+            }
+            commits.pop_front();
         }
-        last_index
     }
 
     /// Process raft ready state
@@ -169,15 +404,22 @@ impl<S: StateMachine + Send + Clone + 'static> Node<S> {
         }
 
         let mut ready = raft_group.ready();
+        Self::observe_commit(raft_group, &mut self.commits);
 
-        // Step 1: Handle messages
+        // Step 1: Handle messages and confirmed reads
         if !ready.messages().is_empty() {
             Self::handle_out_messages(&self.out_mailbox, &ready.take_messages());
         }
+        Self::confirm_reads(ready.read_states(), &mut self.proposed);
 
-        // Step 2: Handle snapshot if any
+        // Step 2: Handle snapshot if any, a snapshot still being saved is older
         if *ready.snapshot() != Snapshot::default() {
             Self::handle_snapshot(raft_group, &ready, &mut self.state_machine);
+            if let Some(saving) = self.snapshotting.take() {
+                let superseded = "superseded by a snapshot from the leader".to_string();
+                snapshot_progress::finish(Kind::Save, 0, Err(superseded));
+                Self::finish_snapshot_requests(&mut self.proposed, saving.applied, false);
+            }
         }
 
         // Step 3: Handle committed entries
@@ -185,6 +427,8 @@ impl<S: StateMachine + Send + Clone + 'static> Node<S> {
             raft_group,
             ready.take_committed_entries(),
             &mut self.state_machine,
+            &mut self.commits,
+            self.state_check.as_ref(),
         );
 
         // Step 4: Persist raft state
@@ -198,87 +442,403 @@ impl<S: StateMachine + Send + Clone + 'static> Node<S> {
         if let Some(commit) = light_rd.commit_index() {
             Self::update_commit(raft_group, commit);
         }
+        Self::observe_commit(raft_group, &mut self.commits);
         Self::handle_out_messages(&self.out_mailbox, light_rd.messages());
         let index2 = Self::handle_committed_entries(
             raft_group,
             light_rd.take_committed_entries(),
             &mut self.state_machine,
+            &mut self.commits,
+            self.state_check.as_ref(),
         );
 
-        Self::notice_proposed(index1.max(index2), &mut self.proposed);
+        let last_index = index1.max(index2);
+        if last_index > 0 && state_failure::failed_at().is_none() {
+            let state_machine = &mut self.state_machine;
+            if let Err(payload) =
+                panic::catch_unwind(AssertUnwindSafe(|| state_machine.on_apply_batch()))
+            {
+                state_failure::record(last_index, &panic_message(payload.as_ref()), None);
+            }
+        }
+        Self::notice_proposed(raft_group, last_index, &mut self.proposed);
         raft_group.advance_apply();
     }
 
+    /// Record the read index a quorum confirmed for each read request
+    /// The request waits in the proposals until that index is applied
+    fn confirm_reads(read_states: &[ReadState], proposed: &mut VecDeque<Proposal>) {
+        for state in read_states {
+            let Ok(id) = <[u8; 8]>::try_from(state.request_ctx.as_slice()) else {
+                continue;
+            };
+            let id = u64::from_be_bytes(id);
+            if let Some(proposal) = proposed.iter_mut().find(|p| p.read_index == Some(id)) {
+                proposal.proposed = state.index;
+            }
+        }
+    }
+
     /// Notify proposals about their status
-    /// Updates the status of pending proposals based on the last applied index
-    fn notice_proposed(last_index: u64, proposed: &mut VecDeque<Proposal>) {
-        let mut i = 0;
-        while i < proposed.len() {
-            if proposed[i].proposed <= last_index {
-                let _ = proposed[i].propose_success.take().unwrap().send(true);
-                proposed.remove(i);
+    /// Updates the status of pending proposals based on the last applied index.
+    /// A proposal only succeeded if the entry applied at its index is from the term
+    /// it was proposed in, a later leader may have replaced it with its own entry.
+    /// Proposals from the entry the state machine failed at on fail, they are never applied.
+    /// Read requests succeed once their confirmed index is applied and fail if the
+    /// node stepped down before, the new leader has to be asked again. Leader
+    /// transfers succeed once the transferee leads and fail once the transfer was
+    /// aborted or another node leads. Snapshot requests are left to the snapshot saves.
+    fn notice_proposed(
+        raft_group: &RawNode<L>,
+        last_index: u64,
+        proposed: &mut VecDeque<Proposal>,
+    ) {
+        let failed_at = state_failure::failed_at();
+        let applied = last_index.max(raft_group.raft.raft_log.applied());
+        proposed.retain_mut(|proposal| {
+            if failed_at.is_some_and(|index| proposal.proposed >= index) {
+                proposal.fail();
+                return false;
+            }
+            if proposal.snapshot.is_some() {
+                return true;
+            }
+            if let Some(transferee) = proposal.transfer_leader {
+                let raft = &raft_group.raft;
+                if raft.leader_id == transferee {
+                    let _ = proposal.propose_success.take().unwrap().send(true);
+                    return false;
+                }
+                // No leader while the transferee campaigns, the old one while the
+                // transfer is pending
+                if raft.leader_id != 0
+                    && (raft.term != proposal.term || raft.lead_transferee.is_none())
+                {
+                    proposal.fail();
+                    return false;
+                }
+                return true;
+            }
+            if proposal.read_index.is_some() {
+                if proposal.term != raft_group.raft.term
+                    || raft_group.raft.state != StateRole::Leader
+                {
+                    proposal.fail();
+                    return false;
+                }
+                if proposal.proposed > applied {
+                    return true;
+                }
+                let _ = proposal.propose_success.take().unwrap().send(true);
+                return false;
+            }
+            if proposal.proposed > last_index {
+                return true;
+            }
+            let term = raft_group.raft.raft_log.term(proposal.proposed).ok();
+            let _ = proposal
+                .propose_success
+                .take()
+                .unwrap()
+                .send(term == Some(proposal.term));
+            false
+        });
+    }
+
+    /// Abandon pending proposals whose proposer deadline has passed
+    /// The entries may still commit, but the proposers are released immediately
+    fn expire_proposed(proposed: &mut VecDeque<Proposal>) {
+        proposed.retain_mut(|proposal| {
+            if proposal.is_expired() {
+                proposal.fail();
+                false
             } else {
-                i += 1;
+                true
             }
-        }
+        });
     }
 
     /// Handle raft messages
-    /// Sends messages to other nodes in the cluster
+    /// Sends messages to other nodes in the cluster, snapshots sent are tracked in
+    /// `snapshot_progress` until the follower acknowledges them
     fn handle_out_messages(sender: &Sender<Message>, messages: &[Message]) {
         if !messages.is_empty() {
             for msg in messages {
+                let snapshot = msg.get_msg_type() == MessageType::MsgSnapshot;
+                if snapshot {
+                    let index = msg.get_snapshot().get_metadata().index;
+                    let bytes = msg.compute_size() as u64;
+                    snapshot_progress::begin(Kind::Send, msg.to, index, "queued", bytes);
+                }
                 if let Err(e) = sender.try_send(msg.clone()) {
                     log::error!("Failed to send raft message {:?}, Raft will retry", e);
+                    if snapshot {
+                        let dropped = "outbound queue full, snapshot dropped".to_string();
+                        snapshot_progress::finish(Kind::Send, msg.to, Err(dropped));
+                    }
                 }
             }
         }
     }
 
+    /// Finish tracking the snapshots sent to followers that left the snapshot state
+    /// A follower that acknowledged the snapshot matches its index, otherwise it
+    /// rejected the snapshot or this node stepped down before it was acknowledged
+    fn observe_snapshot_sends(raft_group: &RawNode<L>) {
+        let leader = raft_group.raft.state == StateRole::Leader;
+        for (peer, index) in snapshot_progress::sending() {
+            let progress = raft_group.raft.prs().get(peer);
+            if leader && progress.is_some_and(|pr| pr.state == ProgressState::Snapshot) {
+                continue;
+            }
+            let result = match progress {
+                _ if !leader => Err("stepped down before the snapshot was acknowledged"),
+                Some(pr) if pr.matched >= index => Ok(()),
+                Some(_) => Err("follower rejected the snapshot"),
+                None => Err("follower was removed"),
+            };
+            snapshot_progress::finish(Kind::Send, peer, result.map_err(str::to_string));
+        }
+    }
+
+    /// Promote a learner that caught up with the leader to voter
+    /// Proposes one promotion at a time, once no other conf change is pending, for the
+    /// first learner whose match index is within `max_lag` entries of the last index
+    fn promote_learners(raft_group: &mut RawNode<L>, max_lag: Option<u64>) {
+        let learners = raft_group.raft.prs().conf().learners();
+        metrics::RAFT_LEARNERS_GAUGE.set(learners.len() as i64);
+        let Some(max_lag) = max_lag else {
+            return;
+        };
+        if raft_group.raft.state != StateRole::Leader || raft_group.raft.has_pending_conf() {
+            return;
+        }
+        let last_index = raft_group.raft.raft_log.last_index();
+        let caught_up = learners
+            .iter()
+            .filter_map(|id| Some((*id, raft_group.raft.prs().get(*id)?.matched)))
+            .filter(|(_, matched)| last_index.saturating_sub(*matched) <= max_lag)
+            .min();
+        let Some((id, matched)) = caught_up else {
+            return;
+        };
+        let mut conf_change = ConfChange::default();
+        conf_change.node_id = id;
+        conf_change.set_change_type(ConfChangeType::AddNode);
+        match raft_group.propose_conf_change(vec![], conf_change) {
+            Ok(()) => {
+                log::info!(
+                    "Promote learner {} at match index {} of {}",
+                    id,
+                    matched,
+                    last_index
+                );
+                metrics::RAFT_LEARNER_PROMOTIONS_COUNTER.inc();
+            }
+            Err(e) => log::warn!("Failed to promote learner {}: {:?}", id, e),
+        }
+    }
+
     /// Handle snapshot
     /// Applies a snapshot to the state machine and updates the storage
-    fn handle_snapshot(
-        raft_group: &mut RawNode<FileStorage>,
-        ready: &Ready,
-        state_machine: &mut S,
-    ) {
+    /// Panics if the state machine cannot restore it, no later entry could be applied
+    fn handle_snapshot(raft_group: &mut RawNode<L>, ready: &Ready, state_machine: &mut S) {
         let snapshot = ready.snapshot().clone();
         let metadata = snapshot.get_metadata().clone();
+        let leader = raft_group.raft.leader_id;
+        snapshot_progress::begin(Kind::Install, leader, metadata.index, "received", 0);
 
         {
             let store = &mut raft_group.raft.raft_log.store;
             if let Err(e) = store.apply_snapshot(&snapshot) {
                 log::error!("Failed to apply snapshot: {:?}, need to retry or panic", e);
+                snapshot_progress::finish(Kind::Install, leader, Err(format!("{:?}", e)));
                 return;
             }
         }
 
-        state_machine.on_snapshot(metadata.index, metadata.term, ready.snapshot().get_data());
+        snapshot_progress::enter(Kind::Install, leader, "restoring", 0);
+        if let Err(e) =
+            state_machine.on_snapshot(metadata.index, metadata.term, ready.snapshot().get_data())
+        {
+            snapshot_progress::finish(Kind::Install, leader, Err(e.clone()));
+            panic!(
+                "Failed to restore snapshot at index {}: {}",
+                metadata.index, e
+            );
+        }
+        snapshot_progress::finish(Kind::Install, leader, Ok(()));
     }
 
     /// Handle save snapshot
-    /// Creates and saves a snapshot of the current state
-    fn handle_save_snapshot(raft_group: &mut RawNode<FileStorage>, state_machine: &mut S) {
-        let biz_data = state_machine.snapshot();
-        let applied = raft_group.raft.raft_log.applied();
+    /// Saves a snapshot of the state at the given applied index and compacts the log.
+    /// The leader keeps the entries its live followers still need, up to `max_retained`;
+    /// followers in the snapshot state or not heard from lately get a snapshot anyway.
+    /// Returns whether the snapshot was saved
+    fn handle_save_snapshot(
+        raft_group: &mut RawNode<L>,
+        biz_data: Vec<u8>,
+        applied: u64,
+        max_retained: u64,
+    ) -> bool {
+        let compact_to = if raft_group.raft.state == StateRole::Leader {
+            let id = raft_group.raft.id;
+            let matched = raft_group
+                .raft
+                .prs()
+                .iter()
+                .filter(|(peer, pr)| {
+                    **peer != id && pr.recent_active && pr.state != ProgressState::Snapshot
+                })
+                .map(|(_, pr)| pr.matched);
+            compaction_index(applied, matched, max_retained)
+        } else {
+            applied
+        };
+        metrics::RAFT_RETAINED_ENTRIES_GAUGE.set((applied - compact_to) as i64);
         let store = &mut raft_group.raft.raft_log.store;
-        store.save_snapshot(biz_data, applied).unwrap();
-        log::info!("Save snapshot at index: {}", applied);
+        if let Err(e) = store.save_snapshot(biz_data, applied, compact_to) {
+            log::error!("Failed to save snapshot at index {}: {:?}", applied, e);
+            snapshot_progress::finish(Kind::Save, 0, Err(format!("{:?}", e)));
+            return false;
+        }
+        snapshot_progress::finish(Kind::Save, 0, Ok(()));
+        log::info!(
+            "Save snapshot at index: {}, log compacted to {}",
+            applied,
+            compact_to
+        );
+        true
+    }
+
+    /// Start saving a snapshot
+    /// Freezes the state machine and serializes it on a background thread, so
+    /// applying entries goes on meanwhile; does nothing while a snapshot is being saved.
+    /// The snapshot covers the snapshot requests waiting for one, with `compact` it
+    /// compacts the log up to the snapshot
+    fn start_save_snapshot(&mut self, compact: bool) {
+        if self.snapshotting.is_some() || state_failure::failed_at().is_some() {
+            return;
+        }
+        self.state_machine.barrier();
+        let applied = self.raft_group.raft.raft_log.applied();
+        snapshot_progress::begin(Kind::Save, 0, applied, "serializing", 0);
+        let frozen = match self.state_machine.freeze() {
+            Ok(frozen) => frozen,
+            Err(e) => {
+                log::error!("Failed to freeze the state machine for a snapshot: {}", e);
+                snapshot_progress::finish(Kind::Save, 0, Err(e));
+                Self::finish_snapshot_requests(&mut self.proposed, u64::MAX, false);
+                return;
+            }
+        };
+        let (tx, rx) = oneshot::channel();
+        let spawned = std::thread::Builder::new()
+            .name("snapshot".to_string())
+            .spawn(move || {
+                let _ = tx.send(frozen());
+            });
+        match spawned {
+            Ok(_) => {
+                let max_retained = if compact {
+                    0
+                } else {
+                    self.max_retained_entries
+                };
+                self.snapshotting = Some(Saving {
+                    applied,
+                    max_retained,
+                    rx,
+                });
+                for proposal in self.proposed.iter_mut() {
+                    if proposal.snapshot.is_some() && proposal.proposed == u64::MAX {
+                        proposal.proposed = applied;
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to start snapshot thread: {}", e);
+                snapshot_progress::finish(Kind::Save, 0, Err(e.to_string()));
+                Self::finish_snapshot_requests(&mut self.proposed, u64::MAX, false);
+            }
+        }
+    }
+
+    /// Finish saving a snapshot
+    /// Writes the snapshot serialized in the background once it is ready and compacts the log
+    fn finish_save_snapshot(&mut self) {
+        let Some(saving) = self.snapshotting.as_mut() else {
+            return;
+        };
+        let biz_data = match saving.rx.try_recv() {
+            Ok(biz_data) => biz_data,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Closed) => {
+                Err("snapshot thread stopped without a snapshot".to_string())
+            }
+        };
+        let Saving {
+            applied,
+            max_retained,
+            ..
+        } = self.snapshotting.take().unwrap();
+        let saved = match biz_data {
+            Ok(biz_data) => {
+                Self::handle_save_snapshot(&mut self.raft_group, biz_data, applied, max_retained)
+            }
+            Err(e) => {
+                log::error!("Failed to serialize snapshot at index {}: {}", applied, e);
+                snapshot_progress::finish(Kind::Save, 0, Err(e));
+                false
+            }
+        };
+        Self::finish_snapshot_requests(&mut self.proposed, applied, saved);
+    }
+
+    /// The snapshot the waiting snapshot requests ask for, Compact if any of them does
+    fn requested_snapshot(proposed: &VecDeque<Proposal>) -> Option<SnapshotRequest> {
+        proposed
+            .iter()
+            .filter(|proposal| proposal.proposed == u64::MAX)
+            .filter_map(|proposal| proposal.snapshot)
+            .max_by_key(|request| *request == SnapshotRequest::Compact)
+    }
+
+    /// Notify the snapshot requests a save covers whether it succeeded
+    /// The save at `applied` covers the requests taken up when it started, u64::MAX
+    /// also covers the requests still waiting for a save
+    fn finish_snapshot_requests(proposed: &mut VecDeque<Proposal>, applied: u64, saved: bool) {
+        proposed.retain_mut(|proposal| {
+            if proposal.snapshot.is_none() || proposal.proposed > applied {
+                return true;
+            }
+            let _ = proposal.propose_success.take().unwrap().send(saved);
+            false
+        });
     }
 
     /// Persist raft state to storage
     /// Saves the current Raft state to persistent storage
-    fn persist_raft_state(raft_group: &mut RawNode<FileStorage>, ready: &Ready) {
+    fn persist_raft_state(raft_group: &mut RawNode<L>, ready: &Ready) {
         let store = &mut raft_group.raft.raft_log.store;
 
         // Persist entries
-        if let Err(e) = store.append_entries(ready.entries()) {
+        let start = Instant::now();
+        let result = store.append_entries(ready.entries());
+        if !ready.entries().is_empty() {
+            metrics::RAFT_LOG_APPEND_HISTOGRAM.observe(start.elapsed().as_secs_f64());
+        }
+        if let Err(e) = result {
             log::error!(
                 "Failed to persist raft log: {:?}, need to retry or panic",
                 e
             );
             return;
         }
+        #[cfg(feature = "fault-injection")]
+        if !ready.entries().is_empty() {
+            super::fault::crash_point(super::fault::CrashPoint::Append);
+        }
 
         // Persist hard state
         if let Some(hs) = ready.hs() {
@@ -288,7 +848,7 @@ impl<S: StateMachine + Send + Clone + 'static> Node<S> {
 
     /// Update commit
     /// Updates the commit index in the storage
-    fn update_commit(raft_group: &mut RawNode<FileStorage>, commit: u64) {
+    fn update_commit(raft_group: &mut RawNode<L>, commit: u64) {
         let store = &mut raft_group.raft.raft_log.store;
         store.set_commit(commit);
     }
@@ -303,17 +863,25 @@ impl<S: StateMachine + Send + Clone + 'static> Node<S> {
         loop {
             let raft_group = &mut self.raft_group;
             tokio::select! {
+                biased;
                 Some(outmsg) = self.my_mailbox.recv() => {
                     // Process incoming messages
-                    let _ = raft_group.step(outmsg);
+                    Self::step_message(raft_group, outmsg);
                     while let Ok(msg) = self.my_mailbox.try_recv() {
-                        let _ = raft_group.step(msg);
+                        Self::step_message(raft_group, msg);
                     }
                 }
-                Some(proposal) = self.proposals.recv() => {
-                    // Propose entries if leader
+                Some(proposal) = self.proposals.priority.recv() => {
+                    // Propose priority entries if leader
+                    Self::propose(raft_group, proposal, &mut self.proposed);
+                    Self::propose_priority(raft_group, &mut self.proposals.priority, &mut self.proposed);
+                }
+                Some(proposal) = self.proposals.normal.recv() => {
+                    // Propose entries if leader, priority entries of the same window go first
+                    Self::propose_priority(raft_group, &mut self.proposals.priority, &mut self.proposed);
                     Self::propose(raft_group, proposal, &mut self.proposed);
-                    while let Ok(proposal) = self.proposals.try_recv() {
+                    while let Ok(proposal) = self.proposals.normal.try_recv() {
+                        Self::propose_priority(raft_group, &mut self.proposals.priority, &mut self.proposed);
                         Self::propose(raft_group, proposal, &mut self.proposed);
                     }
                 }
@@ -324,67 +892,197 @@ impl<S: StateMachine + Send + Clone + 'static> Node<S> {
             // Tick raft
             if last_tick.elapsed() >= TICK_INTERVAL {
                 raft_group.tick();
+                Self::observe_snapshot_sends(raft_group);
+                Self::promote_learners(raft_group, self.learner_promotion);
+                Self::publish_status(raft_group);
                 last_tick = Instant::now();
             }
 
-            // Save snapshot
-            if last_save_snapshot.elapsed() >= SAVE_SNAPSHOT_INTERVAL
-                && last_index_snapshot < raft_group.raft.raft_log.applied()
-            {
-                Self::handle_save_snapshot(raft_group, &mut self.state_machine);
+            // Save snapshot, periodically or once requested and no save is running
+            let applied = raft_group.raft.raft_log.applied();
+            let requested = Self::requested_snapshot(&self.proposed);
+            let due = last_save_snapshot.elapsed() >= SAVE_SNAPSHOT_INTERVAL
+                && last_index_snapshot < applied;
+            if due || (requested.is_some() && self.snapshotting.is_none()) {
+                self.start_save_snapshot(requested == Some(SnapshotRequest::Compact));
                 last_save_snapshot = Instant::now();
-                last_index_snapshot = raft_group.raft.raft_log.applied();
+                last_index_snapshot = applied;
             }
+            self.finish_save_snapshot();
+
+            // Release proposers that are no longer waiting
+            Self::expire_proposed(&mut self.proposed);
 
             // Process ready state
-            self.on_ready();
+            self.process_ready();
         }
     }
 
-    /// Start a new raft node
-    /// Initializes and starts a new Raft node with the specified configuration
-    pub fn start_raft(
-        with_leader: bool,
-        id: u64,
-        rx: Receiver<Message>,
-        rx_proposals: Receiver<Proposal>,
-        state_machine: S,
-        base_path: &str,
-    ) -> Receiver<Message> {
-        // Setup logger
-        let decorator = slog_term::TermDecorator::new().build();
-        let drain = slog_term::FullFormat::new(decorator).build().fuse();
-        let drain = slog_async::Async::new(drain)
-            .chan_size(LOGGER_CHANNEL_SIZE)
-            .overflow_strategy(slog_async::OverflowStrategy::Block)
-            .build()
-            .fuse();
-        let logger = slog::Logger::root(drain, o!());
-
-        let (sx, out_mailbox) = mpsc::channel(1000);
+    /// Process the ready state and publish the raft progress and the known leader
+    fn process_ready(&mut self) {
+        Self::observe_commit(&self.raft_group, &mut self.commits);
+        self.on_ready();
+        metrics::record_raft_progress(
+            self.raft_group.raft.raft_log.committed,
+            self.raft_group.raft.raft_log.applied(),
+            self.raft_group.raft.leader_id,
+        );
+        cluster_events::observe_leader(self.raft_group.raft.leader_id, self.raft_group.raft.term);
+        readiness::observe_progress(
+            self.raft_group.raft.raft_log.applied(),
+            self.raft_group.raft.raft_log.committed,
+            self.raft_group.raft.leader_id,
+        );
+        staleness::observe_applied(
+            self.raft_group.raft.raft_log.applied(),
+            self.raft_group.raft.state == StateRole::Leader,
+        );
+        if state_failure::failed_at().is_some() {
+            Self::hand_over_leadership(&mut self.raft_group);
+        }
+    }
 
-        // Create and start node
-        let mut node = if with_leader {
-            Node::create_raft_leader(id, sx, rx, rx_proposals, &logger, state_machine, base_path)
-        } else {
-            Node::create_raft_follower(id, sx, rx, rx_proposals, &logger, state_machine, base_path)
-        };
+    /// Transfer leadership to the most up to date follower
+    /// Called once the state machine failed, so the cluster keeps a leader that can apply
+    fn hand_over_leadership(raft_group: &mut RawNode<L>) {
+        if raft_group.raft.state != StateRole::Leader || raft_group.raft.lead_transferee.is_some() {
+            return;
+        }
+        let id = raft_group.raft.id;
+        let transferee = raft_group
+            .raft
+            .prs()
+            .iter()
+            .filter(|(peer, _)| **peer != id)
+            .max_by_key(|(_, progress)| progress.matched)
+            .map(|(peer, _)| *peer);
+        if let Some(transferee) = transferee {
+            log::warn!(
+                "state machine failed, transferring leadership to {}",
+                transferee
+            );
+            raft_group.transfer_leader(transferee);
+        }
+    }
 
-        tokio::spawn(async move {
-            node.run_background_tasks().await;
+    /// Publish the role, log indexes and configuration of this node, and the
+    /// replication progress of its peers while leader, see `cluster_status`
+    fn publish_status(raft_group: &RawNode<L>) {
+        let raft = &raft_group.raft;
+        let leader = raft.state == StateRole::Leader;
+        let mut peers: Vec<PeerStatus> = raft
+            .prs()
+            .iter()
+            .map(|(id, progress)| PeerStatus {
+                id: *id,
+                voter: raft.prs().conf().voters().contains(*id),
+                matched: if leader { progress.matched } else { 0 },
+                state: match progress.state {
+                    _ if !leader => "",
+                    ProgressState::Probe => "probe",
+                    ProgressState::Replicate => "replicate",
+                    ProgressState::Snapshot => "snapshot",
+                },
+                recent_active: leader && progress.recent_active,
+            })
+            .collect();
+        peers.sort_by_key(|peer| peer.id);
+        cluster_status::publish(RaftStatus {
+            id: raft.id,
+            term: raft.term,
+            leader_id: raft.leader_id,
+            role: match raft.state {
+                StateRole::Leader => "leader",
+                StateRole::Follower => "follower",
+                StateRole::Candidate => "candidate",
+                StateRole::PreCandidate => "precandidate",
+            },
+            commit_index: raft.raft_log.committed,
+            applied_index: raft.raft_log.applied(),
+            first_index: raft.raft_log.first_index(),
+            last_index: raft.raft_log.last_index(),
+            lead_transferee: raft.lead_transferee.unwrap_or(0),
+            peers,
         });
+    }
 
-        out_mailbox
+    /// Step a message received from a peer
+    /// Appends carry the commit index of their leader, which readiness is measured against,
+    /// appends and heartbeats the commit index staleness is measured against
+    fn step_message(raft_group: &mut RawNode<L>, msg: Message) {
+        if msg.get_msg_type() == MessageType::MsgAppend {
+            readiness::observe_leader_commit(msg.commit);
+        }
+        if matches!(
+            msg.get_msg_type(),
+            MessageType::MsgAppend | MessageType::MsgHeartbeat
+        ) {
+            staleness::observe_leader_message(msg.commit);
+        }
+        let _ = raft_group.step(msg);
+    }
+
+    /// Propose all queued priority entries to the raft group
+    /// Called before regular proposals so cancels are never stuck behind new orders
+    fn propose_priority(
+        raft_group: &mut RawNode<L>,
+        priority_proposals: &mut Receiver<Proposal>,
+        proposed: &mut VecDeque<Proposal>,
+    ) {
+        while let Ok(proposal) = priority_proposals.try_recv() {
+            Self::propose(raft_group, proposal, proposed);
+        }
     }
 
     /// Propose a new entry to the raft group
-    /// Submits a new proposal to the Raft group if this node is the leader
+    /// Submits a new proposal to the Raft group if this node is the leader, snapshot
+    /// requests are served by any node
     fn propose(
-        raft_group: &mut RawNode<FileStorage>,
+        raft_group: &mut RawNode<L>,
         mut proposal: Proposal,
         proposed: &mut VecDeque<Proposal>,
     ) {
-        if raft_group.raft.state != StateRole::Leader {
+        if raft_group.raft.state != StateRole::Leader && proposal.snapshot.is_none() {
+            return;
+        }
+
+        // The proposer already gave up or the entry would never be applied, don't append it
+        if proposal.is_expired() || state_failure::failed_at().is_some() {
+            proposal.fail();
+            return;
+        }
+
+        // Snapshot requests wait for the next save to start, see `start_save_snapshot`
+        if proposal.snapshot.is_some() {
+            proposal.proposed = u64::MAX;
+            proposed.push_back(proposal);
+            return;
+        }
+
+        // Transfers append nothing, they wait for the transferee to win an election;
+        // one that is not up to date is caught up first, within an election timeout
+        if let Some(transferee) = proposal.transfer_leader {
+            if transferee == raft_group.raft.id {
+                let _ = proposal.propose_success.take().unwrap().send(true);
+                return;
+            }
+            if !raft_group.raft.prs().conf().voters().contains(transferee) {
+                proposal.fail();
+                return;
+            }
+            raft_group.transfer_leader(transferee);
+            proposal.proposed = u64::MAX;
+            proposal.term = raft_group.raft.term;
+            proposed.push_back(proposal);
+            return;
+        }
+
+        // Reads append nothing, they wait for a quorum to confirm the commit index
+        if let Some(id) = proposal.read_index {
+            raft_group.read_index(id.to_be_bytes().to_vec());
+            proposal.proposed = u64::MAX;
+            proposal.term = raft_group.raft.term;
+            proposed.push_back(proposal);
             return;
         }
 
@@ -393,20 +1091,105 @@ impl<S: StateMachine + Send + Clone + 'static> Node<S> {
         if let Some(ref data) = proposal.normal {
             let _ = raft_group.propose(vec![], data.clone());
         } else if let Some(ref cc) = proposal.conf_change {
+            // A restarted first leader adds its learners again, never demote a promoted
+            // one; raft turns a change made while another is pending into an empty entry
+            let demotes = cc.get_change_type() == ConfChangeType::AddLearnerNode
+                && raft_group.raft.prs().conf().voters().contains(cc.node_id);
+            if demotes || raft_group.raft.has_pending_conf() {
+                proposal.fail();
+                return;
+            }
             let _ = raft_group.propose_conf_change(vec![], cc.clone());
-        } else if let Some(_transferee) = proposal.transfer_leader {
-            // TODO: implement transfer leader.
-            unimplemented!();
         }
 
         let new_last_index = raft_group.raft.raft_log.last_index() + 1;
         if new_last_index == last_index {
-            if let Some(sender) = proposal.propose_success.take() {
-                let _ = sender.send(false);
-            }
+            proposal.fail();
         } else {
             proposal.proposed = last_index;
+            proposal.term = raft_group.raft.term;
             proposed.push_back(proposal);
         }
     }
 }
+
+/// Hooks for driving a node step by step in the deterministic simulation
+#[cfg(test)]
+impl<S: StateMachine + Send + 'static, L: LogStorage + Send + 'static> Node<S, L> {
+    /// Advance the logical clock of the raft group by one tick
+    pub(super) fn tick(&mut self) {
+        self.raft_group.tick();
+        Self::promote_learners(&mut self.raft_group, self.learner_promotion);
+    }
+
+    /// Promote learners within `max_lag` entries of the leader's last index
+    pub(super) fn promote_within(&mut self, max_lag: u64) {
+        self.learner_promotion = Some(max_lag);
+    }
+
+    /// Whether a node votes in the configuration known to this node
+    pub(super) fn is_voter(&self, id: u64) -> bool {
+        self.raft_group.raft.prs().conf().voters().contains(id)
+    }
+
+    /// Step a message received from a peer
+    pub(super) fn step(&mut self, msg: Message) {
+        Self::step_message(&mut self.raft_group, msg);
+    }
+
+    /// Propose an entry, dropped unless this node is the leader
+    pub(super) fn submit(&mut self, proposal: Proposal) {
+        Self::propose(&mut self.raft_group, proposal, &mut self.proposed);
+    }
+
+    /// Handle the ready state, sending messages and applying committed entries
+    pub(super) fn process(&mut self) {
+        self.process_ready();
+    }
+
+    /// Save a snapshot of the state machine and compact the log
+    /// Serializes on the calling thread, so the simulation snapshots at deterministic points
+    #[cfg(test)]
+    pub(super) fn save_snapshot(&mut self) {
+        self.state_machine.barrier();
+        let biz_data = self.state_machine.snapshot().unwrap();
+        let applied = self.raft_group.raft.raft_log.applied();
+        Self::handle_save_snapshot(
+            &mut self.raft_group,
+            biz_data,
+            applied,
+            self.max_retained_entries,
+        );
+    }
+
+    /// The state machine the node applies committed entries to
+    pub(super) fn state_machine(&self) -> &S {
+        &self.state_machine
+    }
+
+    /// Current term and whether this node is its leader
+    pub(super) fn role(&self) -> (u64, bool) {
+        (
+            self.raft_group.raft.term,
+            self.raft_group.raft.state == StateRole::Leader,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compaction_keeps_what_the_slowest_follower_needs_up_to_the_bound() {
+        // No follower to keep entries for
+        assert_eq!(compaction_index(100, [], 50), 100);
+        // Followers caught up, the snapshot entry stays like before
+        assert_eq!(compaction_index(100, [120, 100], 50), 100);
+        // The slowest follower still needs entry 81
+        assert_eq!(compaction_index(100, [80, 95], 50), 81);
+        // Too far behind, it gets the snapshot
+        assert_eq!(compaction_index(100, [10], 50), 50);
+        assert_eq!(compaction_index(100, [80], 0), 100);
+    }
+}
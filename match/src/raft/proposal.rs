@@ -4,14 +4,31 @@
 
 #![allow(clippy::field_reassign_with_default)]
 
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use tokio::sync::oneshot::Receiver;
 use tokio::sync::oneshot::Sender;
+use tokio::time::Instant;
 
 use raft::prelude::*;
 
+/// Sequence of the read index requests of this process, matched by their confirmation
+static READ_IDS: AtomicU64 = AtomicU64::new(1);
+
+/// Snapshot an operator asked a node to save, see `Proposal::snapshot`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotRequest {
+    /// Save a snapshot and compact the log like the periodic save does
+    Save,
+    /// Save a snapshot and compact the log up to it, dropping the entries kept
+    /// for lagging followers
+    Compact,
+}
+
 /// Represents a proposal that can be submitted to the Raft cluster
-/// A proposal can be one of three types: normal entry, configuration change, or leader transfer
+/// A proposal can be one of five types: normal entry, configuration change, leader transfer,
+/// read index request or snapshot request
 pub struct Proposal {
     /// Normal proposal data (key-value pair where key is u16 and value is string)
     pub normal: Option<Vec<u8>>,
@@ -19,10 +36,20 @@ pub struct Proposal {
     pub conf_change: Option<ConfChange>,
     /// Leader transfer proposal
     pub transfer_leader: Option<u64>,
+    /// ID of a read index request, which appends nothing and succeeds once the
+    /// commit index confirmed by a quorum when it was made is applied
+    pub read_index: Option<u64>,
+    /// Snapshot request, which appends nothing and succeeds once a snapshot of the
+    /// state applied when the node took it up is saved
+    pub snapshot: Option<SnapshotRequest>,
     /// The index at which this proposal was proposed (0 if not yet proposed)
     pub proposed: u64,
+    /// The leader term in which this proposal was proposed
+    pub term: u64,
     /// Channel for notifying the proposer about the success/failure of the proposal
     pub propose_success: Option<Sender<bool>>,
+    /// Point in time after which the proposer no longer waits for the result
+    pub deadline: Option<Instant>,
 }
 
 impl Proposal {
@@ -34,8 +61,12 @@ impl Proposal {
             normal: None,
             conf_change: Some(cc.clone()),
             transfer_leader: None,
+            read_index: None,
+            snapshot: None,
             proposed: 0,
+            term: 0,
             propose_success: Some(tx),
+            deadline: None,
         };
         (proposal, rx)
     }
@@ -48,9 +79,96 @@ impl Proposal {
             normal: Some(data),
             conf_change: None,
             transfer_leader: None,
+            read_index: None,
+            snapshot: None,
+            proposed: 0,
+            term: 0,
+            propose_success: Some(tx),
+            deadline: None,
+        };
+        (proposal, rx)
+    }
+
+    /// Create a new read index request
+    /// Returns the request and a receiver notified once the read may be served
+    pub fn read_index() -> (Self, Receiver<bool>) {
+        let (tx, rx) = oneshot::channel();
+        let proposal = Proposal {
+            normal: None,
+            conf_change: None,
+            transfer_leader: None,
+            read_index: Some(READ_IDS.fetch_add(1, Ordering::Relaxed)),
+            snapshot: None,
             proposed: 0,
+            term: 0,
             propose_success: Some(tx),
+            deadline: None,
         };
         (proposal, rx)
     }
+
+    /// Create a new leader transfer request
+    /// Returns the request and a receiver notified once the transferee leads
+    pub fn transfer_leader(transferee: u64) -> (Self, Receiver<bool>) {
+        let (tx, rx) = oneshot::channel();
+        let proposal = Proposal {
+            normal: None,
+            conf_change: None,
+            transfer_leader: Some(transferee),
+            read_index: None,
+            snapshot: None,
+            proposed: 0,
+            term: 0,
+            propose_success: Some(tx),
+            deadline: None,
+        };
+        (proposal, rx)
+    }
+
+    /// Create a new snapshot request, served by any node
+    /// Returns the request and a receiver notified once the snapshot is saved
+    pub fn snapshot(request: SnapshotRequest) -> (Self, Receiver<bool>) {
+        let (tx, rx) = oneshot::channel();
+        let proposal = Proposal {
+            normal: None,
+            conf_change: None,
+            transfer_leader: None,
+            read_index: None,
+            snapshot: Some(request),
+            proposed: 0,
+            term: 0,
+            propose_success: Some(tx),
+            deadline: None,
+        };
+        (proposal, rx)
+    }
+
+    /// Sets the deadline of the proposal
+    /// Expired proposals are abandoned instead of being proposed or waited on
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Check whether the proposer's deadline has passed
+    pub fn is_expired(&self) -> bool {
+        self.deadline
+            .map(|deadline| deadline <= Instant::now())
+            .unwrap_or(false)
+    }
+
+    /// Notify the proposer that the proposal failed or was abandoned
+    pub fn fail(&mut self) {
+        if let Some(sender) = self.propose_success.take() {
+            let _ = sender.send(false);
+        }
+    }
+}
+
+/// Receiving ends of the proposal lanes of a raft node
+pub struct ProposalReceivers {
+    /// Regular proposals, committed in arrival order
+    pub normal: mpsc::Receiver<Proposal>,
+    /// Proposals committed ahead of queued regular ones, e.g. cancels
+    pub priority: mpsc::Receiver<Proposal>,
 }
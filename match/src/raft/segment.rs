@@ -52,8 +52,10 @@ impl Segment {
         if segment.file.metadata()?.len() == 0 {
             segment.write_header()?;
         } else {
-            segment.read_header()?;
-            segment.rebuild_entry_positions()?;
+            let index = scan(&mut segment.file)?;
+            segment.start_index = index.start_index;
+            segment.end_index = index.end_index;
+            segment.entry_positions = index.entry_positions;
         }
 
         Ok(segment)
@@ -73,20 +75,6 @@ impl Segment {
         Ok(())
     }
 
-    /// Read the segment header from the file
-    fn read_header(&mut self) -> io::Result<()> {
-        self.file.seek(SeekFrom::Start(0))?;
-        let mut header_bytes = vec![0u8; HEADER_SIZE as usize];
-        self.file.read_exact(&mut header_bytes)?;
-
-        let header: SegmentHeader =
-            bincode::deserialize(&header_bytes).map_err(io::Error::other)?;
-
-        self.start_index = header.start_index;
-        self.end_index = header.end_index;
-        Ok(())
-    }
-
     /// Write an entry header containing its size
     fn write_entry_header(&mut self, size: u64) -> io::Result<()> {
         let size_bytes = size.to_le_bytes();
@@ -94,29 +82,6 @@ impl Segment {
         Ok(())
     }
 
-    /// Read an entry header to get its size
-    fn read_entry_header(&mut self) -> io::Result<u64> {
-        let mut size_bytes = [0u8; 8];
-        self.file.read_exact(&mut size_bytes)?;
-        Ok(u64::from_le_bytes(size_bytes))
-    }
-
-    /// Rebuild the entry position index by scanning the file
-    fn rebuild_entry_positions(&mut self) -> io::Result<()> {
-        self.entry_positions.clear();
-        let mut pos = HEADER_SIZE;
-
-        while pos < self.file.metadata()?.len() {
-            self.file.seek(SeekFrom::Start(pos))?;
-            let entry_size = self.read_entry_header()?;
-            let entry_index = self.start_index + (self.entry_positions.len() as u64);
-            self.entry_positions.insert(entry_index, pos);
-            pos += ENTRY_HEADER_SIZE + entry_size;
-        }
-
-        Ok(())
-    }
-
     /// Append new entries to the segment
     pub fn append(&mut self, entries: &Vec<Vec<u8>>) -> io::Result<()> {
         self.file.seek(SeekFrom::End(0))?;
@@ -151,12 +116,7 @@ impl Segment {
             io::Error::new(io::ErrorKind::InvalidInput, "Entry position not found")
         })?;
 
-        self.file.seek(SeekFrom::Start(*pos))?;
-        let entry_size = self.read_entry_header()?;
-
-        let mut entry = vec![0u8; entry_size as usize];
-        self.file.read_exact(&mut entry)?;
-        Ok(entry)
+        read_entry_at(&mut self.file, *pos)
     }
 
     /// Clear the segment by removing its file
@@ -182,3 +142,80 @@ impl Segment {
         self.end_index <= self.start_index
     }
 }
+
+/// Parse the start index from a segment file name, `segment_<start_index>.log`
+/// Returns None for files that are not segments
+pub fn file_start_index(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("segment_")?
+        .strip_suffix(".log")?
+        .parse()
+        .ok()
+}
+
+/// Location of the entries in a segment file
+#[derive(Debug, Default)]
+pub struct SegmentIndex {
+    /// The index of the first entry in the segment
+    pub start_index: u64,
+    /// The index of the last entry in the segment
+    pub end_index: u64,
+    /// Maps entry index to its position in the file
+    pub entry_positions: BTreeMap<u64, u64>,
+}
+
+/// Scan a segment file and locate its entries
+/// Fails with `InvalidData` instead of panicking if the header is corrupt or an entry runs past the end of the file
+pub fn scan<R: Read + Seek>(reader: &mut R) -> io::Result<SegmentIndex> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut header_bytes = vec![0u8; HEADER_SIZE as usize];
+    reader.read_exact(&mut header_bytes)?;
+    let header: SegmentHeader = bincode::deserialize(&header_bytes).map_err(io::Error::other)?;
+
+    let mut index = SegmentIndex {
+        start_index: header.start_index,
+        end_index: header.end_index,
+        entry_positions: BTreeMap::new(),
+    };
+    let mut pos = HEADER_SIZE;
+    while pos < len {
+        let entry_size = read_entry_header(reader, pos, len)?;
+        let entry_index = index
+            .start_index
+            .checked_add(index.entry_positions.len() as u64)
+            .ok_or_else(|| invalid_data("entry index overflows"))?;
+        index.entry_positions.insert(entry_index, pos);
+        pos += ENTRY_HEADER_SIZE + entry_size;
+    }
+    Ok(index)
+}
+
+/// Read the entry stored at a position located by `scan`
+pub fn read_entry_at<R: Read + Seek>(reader: &mut R, pos: u64) -> io::Result<Vec<u8>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let entry_size = read_entry_header(reader, pos, len)?;
+    let mut entry = vec![0u8; entry_size as usize];
+    reader.read_exact(&mut entry)?;
+    Ok(entry)
+}
+
+/// Read the size of the entry at a position, checking that the entry fits into the file
+fn read_entry_header<R: Read + Seek>(reader: &mut R, pos: u64, len: u64) -> io::Result<u64> {
+    if len.saturating_sub(pos) < ENTRY_HEADER_SIZE {
+        return Err(invalid_data("truncated entry header"));
+    }
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut size_bytes = [0u8; 8];
+    reader.read_exact(&mut size_bytes)?;
+    let size = u64::from_le_bytes(size_bytes);
+    if size > len - pos - ENTRY_HEADER_SIZE {
+        return Err(invalid_data("entry runs past the end of the segment"));
+    }
+    Ok(size)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
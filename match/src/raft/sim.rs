@@ -0,0 +1,687 @@
+//! Deterministic simulation of a raft cluster
+//!
+//! Runs several `Node`s in a single thread over an in-memory transport and
+//! in-memory storage. Time only advances when the simulation ticks the nodes and
+//! every random decision, including the election timeouts, comes from one seeded
+//! generator, so a failing seed replays exactly. Set `RAFT_SIM_SEED` to run a
+//! single seed.
+//!
+//! The proposals are match engine commands. While nodes crash, restart and get
+//! partitioned, and optionally while the transport drops, delays, duplicates and
+//! reorders messages (see `raft::fault`), every step checks that no two nodes
+//! apply different entries at the same index and that there is at most one
+//! leader per term. Once the faults stop, every acknowledged proposal must be
+//! applied on every node and all engines must hold the same state.
+
+use super::fault::{FaultConfig, FaultInjector, PartitionConfig};
+use super::node::Node;
+use super::proposal::{Proposal, ProposalReceivers};
+use super::{ApplyError, LogStorage, StateMachine};
+use crate::engine::codec;
+use crate::engine::entry::{Order, OrderSide, OrderType, Symbol};
+use crate::engine::matchengine::{CommandEnvelope, MatchCmd, MatchCmdType, MatchEngine};
+use crate::state_match;
+use prost::bytes::Bytes;
+use raft::eraftpb::{ConfState, Entry, HardState, Message, Snapshot};
+use raft::storage::MemStorage;
+use raft::{Config, GetEntriesContext, RaftState, Storage, StorageError};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rust_decimal_macros::dec;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::sync::oneshot;
+
+/// Capacity of a node's outgoing message channel
+const OUTBOX_SIZE: usize = 10000;
+/// Message delivery rounds per step before the network is considered settled
+const MAX_ROUNDS: usize = 100;
+/// Steps without faults that let the cluster converge at the end of a run
+const SETTLE_STEPS: usize = 200;
+/// Virtual time of a step, the tick interval of a real node
+const TICK: Duration = Duration::from_millis(100);
+/// Symbol the proposed orders are placed on
+const SYMBOL: &str = "BTCUSDT";
+
+/// In-memory storage that survives a simulated crash of its node
+#[derive(Clone)]
+struct MemLog {
+    /// Log entries, hard state and configuration
+    store: MemStorage,
+    /// Latest snapshot including the state machine data
+    snapshot: Arc<Mutex<Snapshot>>,
+}
+
+impl MemLog {
+    /// Creates the storage of a node of a fresh cluster with the given voters and learners
+    fn new(voters: Vec<u64>, learners: Vec<u64>) -> Self {
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 1;
+        snapshot.mut_metadata().term = 1;
+        snapshot.mut_metadata().mut_conf_state().voters = voters;
+        snapshot.mut_metadata().mut_conf_state().learners = learners;
+        let store = MemStorage::new();
+        store.wl().apply_snapshot(snapshot.clone()).unwrap();
+        Self {
+            store,
+            snapshot: Arc::new(Mutex::new(snapshot)),
+        }
+    }
+}
+
+impl LogStorage for MemLog {
+    fn append_entries(&mut self, entries: &[Entry]) -> raft::Result<()> {
+        self.store.wl().append(entries)
+    }
+
+    fn set_conf_state(&mut self, conf_state: ConfState) {
+        self.store.wl().set_conf_state(conf_state)
+    }
+
+    fn set_hardstate(&mut self, hs: HardState) {
+        self.store.wl().set_hardstate(hs)
+    }
+
+    fn set_commit(&mut self, commit: u64) {
+        self.store.wl().mut_hard_state().set_commit(commit)
+    }
+
+    fn apply_snapshot(&mut self, snapshot: &Snapshot) -> raft::Result<()> {
+        self.store.wl().apply_snapshot(snapshot.clone())?;
+        *self.snapshot.lock().unwrap() = snapshot.clone();
+        Ok(())
+    }
+
+    fn save_snapshot(
+        &mut self,
+        biz_data: Vec<u8>,
+        applied: u64,
+        compact_to: u64,
+    ) -> raft::Result<()> {
+        let mut snapshot = Snapshot::default();
+        snapshot.set_data(Bytes::from(biz_data));
+        snapshot.mut_metadata().index = applied;
+        snapshot.mut_metadata().term = self.store.term(applied)?;
+        snapshot
+            .mut_metadata()
+            .set_conf_state(self.store.initial_state()?.conf_state);
+        self.store.wl().compact(compact_to.min(applied))?;
+        *self.snapshot.lock().unwrap() = snapshot;
+        Ok(())
+    }
+
+    fn commit(&self) -> u64 {
+        self.store.rl().hard_state().commit
+    }
+}
+
+impl Storage for MemLog {
+    fn initial_state(&self) -> raft::Result<RaftState> {
+        self.store.initial_state()
+    }
+
+    fn entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+        context: GetEntriesContext,
+    ) -> raft::Result<Vec<Entry>> {
+        self.store.entries(low, high, max_size, context)
+    }
+
+    fn term(&self, idx: u64) -> raft::Result<u64> {
+        self.store.term(idx)
+    }
+
+    fn first_index(&self) -> raft::Result<u64> {
+        self.store.first_index()
+    }
+
+    fn last_index(&self) -> raft::Result<u64> {
+        self.store.last_index()
+    }
+
+    /// Returns the saved snapshot, which covers every compacted entry
+    fn snapshot(&self, request_index: u64, _to: u64) -> raft::Result<Snapshot> {
+        let snapshot = self.snapshot.lock().unwrap().clone();
+        if snapshot.get_metadata().index < request_index {
+            return Err(raft::Error::Store(
+                StorageError::SnapshotTemporarilyUnavailable,
+            ));
+        }
+        Ok(snapshot)
+    }
+}
+
+/// State machine that records every applied entry and feeds it to a match engine
+#[derive(Default)]
+struct Recorder {
+    /// Applied entries as (index, data), in apply order
+    applied: Vec<(u64, Vec<u8>)>,
+    /// Index of the latest applied entry or snapshot
+    last_index: u64,
+    /// Engine the entries are applied to
+    engine: MatchEngine,
+}
+
+impl StateMachine for Recorder {
+    fn apply(&mut self, index: u64, data: &[u8]) -> Result<(), ApplyError> {
+        assert!(
+            index > self.last_index,
+            "entry {} applied after entry {}",
+            index,
+            self.last_index
+        );
+        self.applied.push((index, data.to_vec()));
+        self.last_index = index;
+        self.engine
+            .on_message(index, data)
+            .map_err(|e| state_match::apply_error(data, e))
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>, String> {
+        bincode::serialize(&(&self.applied, self.engine.snapshot()?)).map_err(|e| e.to_string())
+    }
+
+    fn on_snapshot(&mut self, last_index: u64, _last_term: u64, data: &[u8]) -> Result<(), String> {
+        self.last_index = last_index;
+        if data.is_empty() {
+            return Ok(());
+        }
+        let (applied, engine): (Vec<(u64, Vec<u8>)>, Vec<u8>) =
+            bincode::deserialize(data).map_err(|e| e.to_string())?;
+        self.applied = applied;
+        self.engine = MatchEngine::new();
+        self.engine.on_snapshot(&engine)
+    }
+}
+
+/// A running node with the receiving end of its outgoing messages
+struct SimNode {
+    /// The raft node
+    node: Node<Recorder, MemLog>,
+    /// Messages the node sends to its peers
+    outbox: Receiver<Message>,
+    /// Highest index of the node's applied entries already checked
+    checked: u64,
+}
+
+/// Simulated cluster driven by a seeded random generator
+struct Cluster {
+    /// Seed of the run, reported on failures
+    seed: u64,
+    /// Source of every random decision
+    rng: StdRng,
+    /// Storage of each node, by node ID - 1
+    stores: Vec<MemLog>,
+    /// Running nodes, None while crashed
+    nodes: Vec<Option<SimNode>>,
+    /// Side of the network partition each node is on
+    sides: Vec<bool>,
+    /// Faults injected into the transport, if any
+    faults: Option<FaultInjector>,
+    /// Virtual time since the start of the run
+    now: Duration,
+    /// Delayed messages as (delivery time, message)
+    delayed: Vec<(Duration, Message)>,
+    /// Data applied at each index by any node
+    applied: BTreeMap<u64, Vec<u8>>,
+    /// Leader of each term
+    leaders: BTreeMap<u64, u64>,
+    /// Proposals waiting for their result
+    pending: Vec<(Vec<u8>, oneshot::Receiver<bool>)>,
+    /// Proposals reported as committed
+    acked: Vec<Vec<u8>>,
+    /// Sequence number of the next proposal
+    next_value: u64,
+    /// Lag under which the leader promotes learners, None if it never does
+    learner_promotion: Option<u64>,
+}
+
+impl Cluster {
+    /// Starts a cluster of `size` voters
+    fn new(size: u64, seed: u64) -> Self {
+        Self::with_learners(size, 0, None, seed)
+    }
+
+    /// Starts a cluster of `size` voters followed by `learners` learners, which the
+    /// leader promotes once within `learner_promotion` entries of its log
+    fn with_learners(size: u64, learners: u64, learner_promotion: Option<u64>, seed: u64) -> Self {
+        let voters: Vec<u64> = (1..=size).collect();
+        let learners: Vec<u64> = (size + 1..=size + learners).collect();
+        let ids: Vec<u64> = voters.iter().chain(&learners).copied().collect();
+        let mut cluster = Cluster {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            stores: ids
+                .iter()
+                .map(|_| MemLog::new(voters.clone(), learners.clone()))
+                .collect(),
+            nodes: ids.iter().map(|_| None).collect(),
+            sides: vec![false; ids.len()],
+            faults: None,
+            now: Duration::ZERO,
+            delayed: Vec::new(),
+            applied: BTreeMap::new(),
+            leaders: BTreeMap::new(),
+            pending: Vec::new(),
+            acked: Vec::new(),
+            next_value: 0,
+            learner_promotion,
+        };
+        for id in ids {
+            cluster.start(id);
+        }
+        cluster
+    }
+
+    /// Passes all messages through a transport fault injector until the cluster is healed
+    fn with_faults(mut self, config: FaultConfig) -> Self {
+        self.faults = Some(FaultInjector::new(config));
+        self
+    }
+
+    /// Starts a node from its storage, restoring the state machine from the snapshot
+    fn start(&mut self, id: u64) {
+        let store = self.stores[id as usize - 1].clone();
+        let snapshot = store.snapshot.lock().unwrap().clone();
+        let metadata = snapshot.get_metadata();
+        let mut recorder = Recorder::default();
+        recorder
+            .on_snapshot(metadata.index, metadata.term, snapshot.get_data())
+            .unwrap();
+
+        // Election timeouts are drawn here instead of by raft so runs are reproducible
+        let election_tick = self.rng.gen_range(10..20);
+        let cfg = Config {
+            id,
+            election_tick: 10,
+            heartbeat_tick: 3,
+            min_election_tick: election_tick,
+            max_election_tick: election_tick + 1,
+            applied: metadata.index,
+            ..Default::default()
+        };
+        let (out_mailbox, outbox) = mpsc::channel(OUTBOX_SIZE);
+        let (_, my_mailbox) = mpsc::channel(1);
+        let (_, normal) = mpsc::channel(1);
+        let (_, priority) = mpsc::channel(1);
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let mut node = Node::new(
+            &cfg,
+            store,
+            out_mailbox,
+            my_mailbox,
+            ProposalReceivers { normal, priority },
+            &logger,
+            recorder,
+        );
+        if let Some(max_lag) = self.learner_promotion {
+            node.promote_within(max_lag);
+        }
+        self.nodes[id as usize - 1] = Some(SimNode {
+            node,
+            outbox,
+            checked: 0,
+        });
+    }
+
+    /// Whether messages from one node reach another
+    fn connected(&self, from: u64, to: u64) -> bool {
+        self.sides[from as usize - 1] == self.sides[to as usize - 1]
+    }
+
+    /// Runs `steps` steps with random faults, then heals the cluster and checks convergence
+    fn run(&mut self, steps: usize) {
+        for _ in 0..steps {
+            self.inject_fault();
+            self.step(true);
+        }
+        for id in 1..=self.nodes.len() as u64 {
+            if self.nodes[id as usize - 1].is_none() {
+                self.start(id);
+            }
+        }
+        self.sides.iter_mut().for_each(|side| *side = false);
+        self.faults = None;
+        for _ in 0..SETTLE_STEPS {
+            self.step(false);
+        }
+        self.check_converged();
+    }
+
+    /// Crashes, restarts, partitions or heals nodes at random
+    fn inject_fault(&mut self) {
+        let id = self.rng.gen_range(1..=self.nodes.len() as u64);
+        match self.rng.gen_range(0..1000) {
+            0..=9 => self.nodes[id as usize - 1] = None,
+            10..=59 if self.nodes[id as usize - 1].is_none() => self.start(id),
+            60..=69 => {
+                for side in self.sides.iter_mut() {
+                    *side = self.rng.gen_bool(0.5);
+                }
+            }
+            70..=99 => self.sides.iter_mut().for_each(|side| *side = false),
+            100..=119 => {
+                if let Some(sim) = self.nodes[id as usize - 1].as_mut() {
+                    sim.node.save_snapshot();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Advances time by one tick, optionally proposes, and delivers messages until settled
+    fn step(&mut self, propose: bool) {
+        self.now += TICK;
+        if propose && self.rng.gen_bool(0.5) {
+            self.propose();
+        }
+        for sim in self.nodes.iter_mut().flatten() {
+            sim.node.tick();
+        }
+        for _ in 0..MAX_ROUNDS {
+            let now = self.now;
+            let (mut messages, delayed): (Vec<_>, Vec<_>) = std::mem::take(&mut self.delayed)
+                .into_iter()
+                .partition(|(due, _)| *due <= now);
+            self.delayed = delayed;
+            let mut sent = Vec::new();
+            for sim in self.nodes.iter_mut().flatten() {
+                sim.node.process();
+                while let Ok(msg) = sim.outbox.try_recv() {
+                    sent.push(msg);
+                }
+            }
+            for msg in sent {
+                let delays = match self.faults.as_mut() {
+                    Some(faults) => faults.deliveries(msg.from, msg.to, now),
+                    None => vec![Duration::ZERO],
+                };
+                for delay in delays {
+                    messages.push((now + delay, msg.clone()));
+                }
+            }
+            let (messages, delayed): (Vec<_>, Vec<_>) =
+                messages.into_iter().partition(|(due, _)| *due <= now);
+            self.delayed.extend(delayed);
+            if messages.is_empty() {
+                break;
+            }
+            for (_, msg) in messages {
+                if !self.connected(msg.from, msg.to) {
+                    continue;
+                }
+                if let Some(sim) = self.nodes[msg.to as usize - 1].as_mut() {
+                    sim.node.step(msg);
+                }
+            }
+        }
+        self.collect_results();
+        self.check_safety();
+    }
+
+    /// Encodes the next command, (re)creating the symbol every 100 commands and placing orders otherwise
+    fn next_command(&mut self) -> Vec<u8> {
+        let n = self.next_value;
+        self.next_value += 1;
+        let cmd = if n.is_multiple_of(100) {
+            MatchCmd {
+                cmd: MatchCmdType::CreateSymbol,
+                symbol: Some(Symbol::new(
+                    SYMBOL.to_string(),
+                    "BTC".to_string(),
+                    "USDT".to_string(),
+                    2,
+                    4,
+                    dec!(0.01),
+                    dec!(1000000),
+                    dec!(0.0001),
+                    dec!(1000),
+                )),
+                ..Default::default()
+            }
+        } else {
+            let side = if self.rng.gen_bool(0.5) {
+                OrderSide::Buy
+            } else {
+                OrderSide::Sell
+            };
+            MatchCmd {
+                cmd: MatchCmdType::PlaceOrder,
+                order: Some(Order::new(
+                    n.to_string(),
+                    SYMBOL.to_string(),
+                    OrderType::Limit,
+                    side,
+                    self.rng.gen_range(95..=105).to_string(),
+                    self.rng.gen_range(1..=3).to_string(),
+                )),
+                ..Default::default()
+            }
+        };
+        codec::encode(&CommandEnvelope {
+            request_id: format!("{}:{}", self.seed, n),
+            proposed_at: n + 1,
+            cmd,
+            ..Default::default()
+        })
+    }
+
+    /// Proposes the next command on a node that believes it is the leader
+    fn propose(&mut self) {
+        let value = self.next_command();
+        let leader = self
+            .nodes
+            .iter_mut()
+            .flatten()
+            .find(|sim| sim.node.role().1);
+        if let Some(sim) = leader {
+            let (proposal, rx) = Proposal::normal(value.clone());
+            sim.node.submit(proposal);
+            self.pending.push((value, rx));
+        }
+    }
+
+    /// Moves proposals with a result out of the pending list
+    fn collect_results(&mut self) {
+        let acked = &mut self.acked;
+        self.pending.retain_mut(|(value, rx)| match rx.try_recv() {
+            Ok(true) => {
+                acked.push(value.clone());
+                false
+            }
+            Err(oneshot::error::TryRecvError::Empty) => true,
+            _ => false,
+        });
+    }
+
+    /// Checks that applied entries agree across nodes and that every term has one leader
+    fn check_safety(&mut self) {
+        for (i, sim) in self.nodes.iter_mut().enumerate() {
+            let sim = match sim {
+                Some(sim) => sim,
+                None => continue,
+            };
+            let id = i as u64 + 1;
+            let (term, is_leader) = sim.node.role();
+            if is_leader {
+                let leader = *self.leaders.entry(term).or_insert(id);
+                assert_eq!(
+                    leader, id,
+                    "seed {}: nodes {} and {} both lead term {}",
+                    self.seed, leader, id, term
+                );
+            }
+            let recorder = sim.node.state_machine();
+            let start = recorder
+                .applied
+                .partition_point(|(index, _)| *index <= sim.checked);
+            for (index, data) in &recorder.applied[start..] {
+                let expected = self.applied.entry(*index).or_insert_with(|| data.clone());
+                assert_eq!(
+                    expected, data,
+                    "seed {}: node {} applied a divergent entry at index {}",
+                    self.seed, id, index
+                );
+            }
+            sim.checked = sim.checked.max(recorder.last_index);
+        }
+    }
+
+    /// Checks that all nodes applied the same entries, including every acknowledged
+    /// one, and that their engines hold the same state
+    fn check_converged(&self) {
+        let mut replicas = self
+            .nodes
+            .iter()
+            .flatten()
+            .map(|sim| sim.node.state_machine());
+        let first = replicas.next().unwrap();
+        let engine = serde_json::to_value(&first.engine).unwrap();
+        for replica in replicas {
+            assert_eq!(
+                first.applied, replica.applied,
+                "seed {}: nodes did not converge",
+                self.seed
+            );
+            assert_eq!(
+                engine,
+                serde_json::to_value(&replica.engine).unwrap(),
+                "seed {}: engine states differ",
+                self.seed
+            );
+        }
+        for value in &self.acked {
+            assert!(
+                first.applied.iter().any(|(_, data)| data == value),
+                "seed {}: acknowledged proposal {} was lost",
+                self.seed,
+                codec::decode(value).unwrap().request_id
+            );
+        }
+    }
+}
+
+/// Seeds to run, or only the one given in `RAFT_SIM_SEED`
+fn seeds(count: u64) -> Vec<u64> {
+    match std::env::var("RAFT_SIM_SEED") {
+        Ok(seed) => vec![seed.parse().expect("RAFT_SIM_SEED must be a number")],
+        Err(_) => (0..count).collect(),
+    }
+}
+
+#[test]
+fn three_nodes_stay_consistent_under_faults() {
+    for seed in seeds(20) {
+        Cluster::new(3, seed).run(2000);
+    }
+}
+
+#[test]
+fn five_nodes_stay_consistent_under_faults() {
+    for seed in seeds(10) {
+        Cluster::new(5, seed).run(2000);
+    }
+}
+
+#[test]
+fn acknowledged_proposals_survive_leader_crash() {
+    for seed in seeds(20) {
+        let mut cluster = Cluster::new(3, seed);
+        cluster.run(100);
+        let leader = cluster
+            .nodes
+            .iter()
+            .position(|sim| sim.as_ref().is_some_and(|sim| sim.node.role().1));
+        if let Some(leader) = leader {
+            cluster.nodes[leader] = None;
+        }
+        cluster.run(300);
+        assert!(
+            !cluster.acked.is_empty(),
+            "seed {}: nothing committed",
+            seed
+        );
+    }
+}
+
+#[test]
+fn caught_up_learners_are_promoted_to_voters() {
+    for seed in seeds(10) {
+        let mut cluster = Cluster::with_learners(3, 2, Some(10), seed);
+        cluster.run(300);
+        for sim in cluster.nodes.iter().flatten() {
+            assert!(
+                (1..=5).all(|id| sim.node.is_voter(id)),
+                "seed {}: learners were not promoted",
+                seed
+            );
+        }
+    }
+}
+
+#[test]
+fn leadership_moves_to_the_transferee() {
+    for seed in seeds(10) {
+        let mut cluster = Cluster::new(3, seed);
+        cluster.run(100);
+        let leader = cluster
+            .nodes
+            .iter()
+            .position(|sim| sim.as_ref().is_some_and(|sim| sim.node.role().1))
+            .unwrap_or_else(|| panic!("seed {}: no leader", seed));
+        let transferee = (leader + 1) % 3;
+        let (proposal, mut rx) = Proposal::transfer_leader(transferee as u64 + 1);
+        cluster.nodes[leader]
+            .as_mut()
+            .unwrap()
+            .node
+            .submit(proposal);
+        for _ in 0..SETTLE_STEPS {
+            cluster.step(false);
+        }
+        assert_eq!(rx.try_recv(), Ok(true), "seed {}: transfer failed", seed);
+        assert!(
+            cluster.nodes[transferee].as_ref().unwrap().node.role().1,
+            "seed {}: transferee does not lead",
+            seed
+        );
+    }
+}
+
+#[test]
+fn engines_converge_after_transport_faults() {
+    for seed in seeds(10) {
+        let faults = FaultConfig {
+            seed: Some(seed),
+            drop_ratio: 0.2,
+            duplicate_ratio: 0.1,
+            delay_ratio: 0.3,
+            max_delay_ms: 500,
+            partitions: vec![
+                PartitionConfig {
+                    start_ms: 20_000,
+                    end_ms: 40_000,
+                    nodes: vec![1],
+                },
+                PartitionConfig {
+                    start_ms: 60_000,
+                    end_ms: 80_000,
+                    nodes: vec![2, 3],
+                },
+            ],
+            ..Default::default()
+        };
+        let mut cluster = Cluster::new(3, seed).with_faults(faults);
+        cluster.run(1000);
+        assert!(
+            !cluster.acked.is_empty(),
+            "seed {}: nothing committed",
+            seed
+        );
+    }
+}
@@ -2,7 +2,9 @@
 //! This module provides a file-based storage implementation for Raft, supporting
 //! persistent storage of Raft entries, snapshots, and state.
 
-use crate::raft::segment::Segment;
+use crate::raft::segment::{self, Segment};
+use crate::raft::LogStorage;
+use crate::snapshot_progress::{self, Kind};
 use prost::bytes::Bytes;
 use protobuf::Message;
 use raft::eraftpb::Entry;
@@ -12,8 +14,31 @@ use raft::{storage::MemStorage, GetEntriesContext, RaftState, Result, Storage};
 use raft_proto::eraftpb::ConfState;
 use std::collections::BTreeMap;
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
+/// Bytes of a snapshot written between two progress reports
+const SNAPSHOT_WRITE_CHUNK: usize = 1 << 20;
+
+/// Writes a snapshot file in chunks, reporting the bytes written to `snapshot_progress`
+///
+/// # Arguments
+///
+/// * `path` - File written
+/// * `data` - Encoded snapshot
+/// * `kind` - Operation the write belongs to, a save or an install
+fn write_snapshot_file(path: &Path, data: &[u8], kind: Kind) -> std::io::Result<()> {
+    snapshot_progress::enter(kind, 0, "writing", data.len() as u64);
+    let mut file = fs::File::create(path)?;
+    let mut written = 0;
+    for chunk in data.chunks(SNAPSHOT_WRITE_CHUNK) {
+        file.write_all(chunk)?;
+        written += chunk.len() as u64;
+        snapshot_progress::advance(kind, 0, written);
+    }
+    Ok(())
+}
+
 /// File-based storage implementation for Raft
 /// Combines in-memory storage with persistent file storage
 pub struct FileStorage {
@@ -58,53 +83,23 @@ impl FileStorage {
         let mut segments = BTreeMap::new();
         let mut entries = Vec::new();
 
-        // Find all segment files
+        // Find all segment files, sorted by start index
         let mut segment_files: Vec<_> = fs::read_dir(&base_path)?
             .filter_map(|entry| {
-                let entry = entry.ok()?;
-                let path = entry.path();
-                if path.is_file() && path.to_string_lossy().contains("segment_") {
-                    Some(path)
-                } else {
-                    None
+                let path = entry.ok()?.path();
+                if !path.is_file() {
+                    return None;
                 }
+                let start_index = segment::file_start_index(&path)?;
+                Some((start_index, path))
             })
             .collect();
-
-        // Sort segments by start index
-        segment_files.sort_by(|a, b| {
-            let a_idx = a
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .trim_start_matches("segment_")
-                .trim_end_matches(".log")
-                .parse::<u64>()
-                .unwrap();
-            let b_idx = b
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .trim_start_matches("segment_")
-                .trim_end_matches(".log")
-                .parse::<u64>()
-                .unwrap();
-            a_idx.cmp(&b_idx)
-        });
+        segment_files.sort();
 
         let last_index = mem_storage.last_index().unwrap();
 
         // Load each segment
-        for segment_path in segment_files {
-            let start_index = segment_path
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .trim_start_matches("segment_")
-                .trim_end_matches(".log")
-                .parse::<u64>()
-                .unwrap();
-
+        for (start_index, segment_path) in segment_files {
             let mut segment = Segment::new(&segment_path, start_index)
                 .map_err(|e| raft::Error::Store(raft::StorageError::Other(Box::new(e))))?;
 
@@ -151,10 +146,12 @@ impl FileStorage {
         }
         Ok(self.segments.get_mut(&start_index).unwrap())
     }
+}
 
+impl LogStorage for FileStorage {
     /// Append entries to storage
     /// Writes entries to both memory and persistent storage
-    pub fn append_entries(&mut self, entries: &[Entry]) -> Result<()> {
+    fn append_entries(&mut self, entries: &[Entry]) -> Result<()> {
         // First append to mem_storage
         self.mem_storage.wl().append(entries)?;
 
@@ -181,23 +178,23 @@ impl FileStorage {
     }
 
     /// Set the configuration state
-    pub fn set_conf_state(&mut self, conf_state: ConfState) {
+    fn set_conf_state(&mut self, conf_state: ConfState) {
         self.mem_storage.wl().set_conf_state(conf_state)
     }
 
     /// Set the hard state
-    pub fn set_hardstate(&mut self, hs: HardState) {
+    fn set_hardstate(&mut self, hs: HardState) {
         self.mem_storage.wl().set_hardstate(hs);
     }
 
     /// Set the commit index
-    pub fn set_commit(&mut self, commit: u64) {
+    fn set_commit(&mut self, commit: u64) {
         self.mem_storage.wl().mut_hard_state().set_commit(commit);
     }
 
     /// Apply a snapshot to storage
     /// Writes the snapshot to disk and updates memory state
-    pub fn apply_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+    fn apply_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
         let snapshot_path = self
             .base_path
             .join(format!("snapshot_{}", snapshot.get_metadata().index));
@@ -205,16 +202,20 @@ impl FileStorage {
             .write_to_bytes()
             .map_err(|e| raft::Error::Store(raft::StorageError::Other(Box::new(e))))?;
 
-        fs::write(&snapshot_path, &snapshot_data)
+        write_snapshot_file(&snapshot_path, &snapshot_data, Kind::Install)
             .map_err(|e| raft::Error::Store(raft::StorageError::Other(Box::new(e))))?;
         self.mem_storage.wl().apply_snapshot(snapshot.clone())?;
         Ok(())
     }
 
     /// Save a snapshot of the current state
-    /// Creates a new snapshot with the given business data and applied index
-    pub fn save_snapshot(&mut self, biz_data: Vec<u8>, applied: u64) -> Result<()> {
+    /// Creates a new snapshot with the given business data and applied index, then drops
+    /// the entries and segments before `compact_to`
+    fn save_snapshot(&mut self, biz_data: Vec<u8>, applied: u64, compact_to: u64) -> Result<()> {
         let mut snapshot = self.mem_storage.snapshot(applied, 0)?;
+        // The data may have been taken a while ago, the snapshot covers what it holds
+        snapshot.mut_metadata().index = applied;
+        snapshot.mut_metadata().term = self.mem_storage.term(applied)?;
         snapshot.set_data(Bytes::from(biz_data));
         let snapshot_path = self.base_path.join("snapshot");
         let temp_path = self.base_path.join("snapshot.tmp");
@@ -224,7 +225,7 @@ impl FileStorage {
             .write_to_bytes()
             .map_err(|e| raft::Error::Store(raft::StorageError::Other(Box::new(e))))?;
 
-        fs::write(&temp_path, &snapshot_data)
+        write_snapshot_file(&temp_path, &snapshot_data, Kind::Save)
             .map_err(|e| raft::Error::Store(raft::StorageError::Other(Box::new(e))))?;
 
         // Remove old snapshot if exists
@@ -233,17 +234,18 @@ impl FileStorage {
                 .map_err(|e| raft::Error::Store(raft::StorageError::Other(Box::new(e))))?;
         }
 
+        #[cfg(feature = "fault-injection")]
+        crate::raft::fault::crash_point(crate::raft::fault::CrashPoint::Snapshot);
+
         // Rename temp file to actual snapshot file
         fs::rename(&temp_path, &snapshot_path)
             .map_err(|e| raft::Error::Store(raft::StorageError::Other(Box::new(e))))?;
 
-        self.mem_storage
-            .wl()
-            .compact(snapshot.get_metadata().index)
-            .unwrap();
+        let compact_to = compact_to.min(applied);
+        self.mem_storage.wl().compact(compact_to).unwrap();
         let mut to_remove = Vec::new();
         for (start_index, segment) in self.segments.iter_mut() {
-            if segment.get_end_index() <= snapshot.get_metadata().index {
+            if segment.get_end_index() <= compact_to {
                 segment.clear()?;
                 to_remove.push(*start_index);
             }
@@ -255,7 +257,7 @@ impl FileStorage {
     }
 
     /// Get the current commit index
-    pub fn commit(&self) -> u64 {
+    fn commit(&self) -> u64 {
         self.mem_storage.rl().hard_state().commit
     }
 }
@@ -3,15 +3,18 @@
 //! This module provides functionality for sending Raft messages to other nodes
 //! in the cluster.
 
-use crate::config;
+use crate::snapshot_progress::{self, Kind};
+use crate::{config, metrics, readiness};
 use pb::raft_service_client::RaftServiceClient;
 use pb::PostDataRequest;
 use protobuf::Message;
-use raft::prelude::Message as RaftMessage;
+use raft::prelude::{Message as RaftMessage, MessageType};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
 
 /// Protocol buffer definitions for Raft service
 #[allow(clippy::module_inception)]
@@ -19,10 +22,13 @@ pub mod pb {
     tonic::include_proto!("raft");
 }
 
+/// Request queued for a peer and whether it carries a snapshot
+type Outbound = (PostDataRequest, bool);
+
 /// Client for a single peer node
 struct PeerClient {
     /// Channel sender for sending messages to the peer
-    sender: Sender<PostDataRequest>,
+    sender: Sender<Outbound>,
     /// Flag indicating if the client is invalid/needs reconnection
     invalid: Arc<AtomicBool>,
 }
@@ -37,23 +43,28 @@ impl PeerClient {
     ///
     /// # Arguments
     ///
+    /// * `id` - ID of the peer node
     /// * `addr` - Address of the peer node
     ///
     /// # Returns
     ///
     /// Returns a new PeerClient instance or an error if connection fails
-    async fn new(addr: String) -> Result<Self, tonic::transport::Error> {
+    async fn new(id: u64, addr: String) -> Result<Self, tonic::transport::Error> {
         let client = RaftServiceClient::connect(addr).await?;
-        let (sender, receiver) = mpsc::channel(1000);
+        let capacity = config::instance().lock().unwrap().channels.peer_outbound;
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        metrics::watch_channel(&format!("peer_{}", id), &sender);
 
         // Start background streaming task
         let mut client_clone = client.clone();
         let invalid = Arc::new(AtomicBool::new(false));
         let invalid_clone = invalid.clone();
         tokio::spawn(async move {
-            if let Err(e) = Self::stream_messages(&mut client_clone, receiver).await {
+            if let Err(e) = Self::stream_messages(&mut client_clone, id, receiver).await {
                 log::error!("Streaming messages failed: {}", e);
                 invalid_clone.store(true, Ordering::SeqCst);
+                let error = format!("stream to peer failed: {}", e.message());
+                snapshot_progress::finish(Kind::Send, id, Err(error));
             }
         });
 
@@ -62,9 +73,12 @@ impl PeerClient {
 
     /// Streams messages to the peer node
     ///
+    /// A snapshot counts as sent in `snapshot_progress` once the transport takes it.
+    ///
     /// # Arguments
     ///
     /// * `client` - Raft service client
+    /// * `id` - ID of the peer node
     /// * `receiver` - Channel receiver for incoming messages
     ///
     /// # Returns
@@ -72,9 +86,17 @@ impl PeerClient {
     /// Returns Ok(()) if successful, or an error if streaming fails
     async fn stream_messages(
         client: &mut RaftServiceClient<tonic::transport::Channel>,
-        receiver: Receiver<PostDataRequest>,
+        id: u64,
+        receiver: Receiver<Outbound>,
     ) -> Result<(), tonic::Status> {
-        let stream = tokio_stream::wrappers::ReceiverStream::new(receiver);
+        let stream = tokio_stream::wrappers::ReceiverStream::new(receiver).map(
+            move |(request, snapshot)| {
+                if snapshot {
+                    snapshot_progress::advance(Kind::Send, id, request.data.len() as u64);
+                }
+                request
+            },
+        );
         let _ = client.post_data(stream).await?;
         Ok(())
     }
@@ -105,6 +127,7 @@ impl RaftClient {
     ///
     /// * `data` - The Raft message to send
     pub async fn post_data(&self, data: RaftMessage) {
+        let snapshot = data.get_msg_type() == MessageType::MsgSnapshot;
         let peers = self.peers.clone();
         let mut peers = peers.lock().await;
 
@@ -115,20 +138,30 @@ impl RaftClient {
             let addr = config::instance().lock().unwrap().node_list[data.to as usize - 1]
                 .addr
                 .clone();
-            match PeerClient::new(addr).await {
+            match PeerClient::new(data.to, addr).await {
                 Ok(client) => {
+                    readiness::peer_connected(data.to);
                     peers.insert(data.to, client);
                     peers.get_mut(&data.to).unwrap()
                 }
                 Err(e) => {
                     log::error!("Failed to create peer client: {}", e);
+                    if snapshot {
+                        let error = format!("cannot connect to peer: {}", e);
+                        snapshot_progress::finish(Kind::Send, data.to, Err(error));
+                    }
                     return;
                 }
             }
         };
 
         if peer_client.invalid.load(Ordering::SeqCst) {
+            readiness::peer_disconnected(data.to);
             peers.remove(&data.to);
+            if snapshot {
+                let error = "connection to peer lost, snapshot dropped".to_string();
+                snapshot_progress::finish(Kind::Send, data.to, Err(error));
+            }
             return;
         }
 
@@ -136,8 +169,17 @@ impl RaftClient {
         let request = PostDataRequest {
             data: data.write_to_bytes().unwrap(),
         };
-        if let Err(_e) = peer_client.sender.try_send(request) {
-            // log::error!("Failed to send message to peer: {}", e);
+        if snapshot {
+            snapshot_progress::enter(Kind::Send, data.to, "sending", 0);
+        }
+        if let Err(TrySendError::Full(_)) = peer_client.sender.try_send((request, snapshot)) {
+            metrics::CHANNEL_DROPPED_COUNTER_VEC
+                .with_label_values(&[&format!("peer_{}", data.to)])
+                .inc();
+            if snapshot {
+                let error = "peer queue full, snapshot dropped".to_string();
+                snapshot_progress::finish(Kind::Send, data.to, Err(error));
+            }
         }
     }
 }
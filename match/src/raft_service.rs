@@ -2,9 +2,13 @@
 //!
 //! This module implements the gRPC service for Raft communication between nodes.
 
-use crate::server;
+use crate::engine::codec;
+use crate::{divergence, server, version};
 use pb::raft_service_server::RaftService;
-use pb::{PostDataRequest, PostDataResponse};
+use pb::{
+    GetFeaturesRequest, GetFeaturesResponse, PostDataRequest, PostDataResponse,
+    ReportChecksumRequest, ReportChecksumResponse,
+};
 use protobuf::Message;
 use raft::prelude::Message as RaftMessage;
 use tonic::Streaming;
@@ -64,4 +68,46 @@ impl RaftService for RaftServiceSVC {
         }
         Ok(tonic::Response::new(PostDataResponse::default()))
     }
+
+    /// Compares a follower's state checksum with the checksum of this node
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Checksum the follower took at an applied index
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the checksums match, or unknown if this node has no
+    /// checksum of the index
+    async fn report_checksum(
+        &self,
+        request: tonic::Request<ReportChecksumRequest>,
+    ) -> Result<tonic::Response<ReportChecksumResponse>, tonic::Status> {
+        let result = divergence::compare(request.get_ref());
+        Ok(tonic::Response::new(ReportChecksumResponse {
+            result: result as i32,
+        }))
+    }
+
+    /// Reports the version and command features of this node
+    ///
+    /// # Arguments
+    ///
+    /// * `_request` - Empty request
+    ///
+    /// # Returns
+    ///
+    /// Returns the version of the build and the command features it can apply
+    async fn get_features(
+        &self,
+        _request: tonic::Request<GetFeaturesRequest>,
+    ) -> Result<tonic::Response<GetFeaturesResponse>, tonic::Status> {
+        Ok(tonic::Response::new(GetFeaturesResponse {
+            version: version::VERSION.to_string(),
+            features: codec::SUPPORTED_FEATURES
+                .iter()
+                .map(|feature| feature.to_string())
+                .collect(),
+        }))
+    }
 }
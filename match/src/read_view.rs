@@ -0,0 +1,321 @@
+//! Read view of the replicated state
+//!
+//! The engine is owned by the raft loop, so the gRPC service cannot read it
+//! directly. After each apply batch the engine publishes the parts of its state
+//! that reads need here, before the proposals of the batch are acknowledged, so a
+//! client always reads its own acknowledged writes from the node that took them.
+//!
+//! Order books are too large to copy after every batch. For each book changed in
+//! a batch the engine publishes a weak reference to its matcher, which it shares
+//! copy-on-write, with the applied index. A reader upgrading the reference holds
+//! the book as of that index: matching goes on, and the next write to the symbol
+//! copies the book instead of changing it under the reader. A write made before
+//! the reader got there drops the reference, and the reader waits for the end of
+//! the batch, which publishes the book again.
+//!
+//! The orders canceled by a cancel-all or replace command are published the same
+//! way, by tenant and request ID, so the request that proposed it can report
+//! them. Only the latest cancellations are kept.
+
+use crate::engine::data::OrderBook;
+use crate::engine::entry::Order;
+use crate::engine::history::Cancellation;
+use crate::engine::ledger::Balance;
+use crate::engine::matchlogic::Matcher;
+use crate::engine::position::Position;
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, RwLock, Weak};
+use tokio::sync::Notify;
+
+/// Balances by tenant and account, then by currency
+static BALANCES: RwLock<BTreeMap<(String, u64), BTreeMap<String, Balance>>> =
+    RwLock::new(BTreeMap::new());
+/// Positions by tenant and account, then by symbol
+static POSITIONS: RwLock<BTreeMap<(String, u64), BTreeMap<String, Position>>> =
+    RwLock::new(BTreeMap::new());
+
+/// Number of cancellations kept for the requests that proposed them
+const CANCELLATIONS_KEPT: usize = 1024;
+/// Orders canceled by the latest cancel-all and replace commands, oldest first
+static CANCELLATIONS: RwLock<VecDeque<Cancellation>> = RwLock::new(VecDeque::new());
+
+/// Matcher of an order book and the index it was published at
+type PublishedBook = (u64, Weak<Matcher>);
+
+/// Order books by tenant and symbol
+static BOOKS: RwLock<BTreeMap<(String, String), PublishedBook>> = RwLock::new(BTreeMap::new());
+/// Wakes readers waiting for books to be published again
+static BOOKS_PUBLISHED: Notify = Notify::const_new();
+
+/// Order book of a symbol as of a known applied index
+///
+/// The view stays consistent however long it is held, at the cost of one copy
+/// of the book if the symbol is written meanwhile, so hold it only for a read.
+#[derive(Debug, Clone)]
+pub struct BookView {
+    /// Raft index of the last command applied before the view was published
+    pub index: u64,
+    /// The matcher as of that index
+    matcher: Arc<Matcher>,
+}
+
+impl BookView {
+    /// Returns the order book
+    pub fn orderbook(&self) -> &OrderBook {
+        self.matcher.orderbook()
+    }
+
+    /// Returns whether an order would trade against the book, see `Matcher::would_take`
+    pub fn would_take(&self, order: &Order) -> bool {
+        self.matcher.would_take(order)
+    }
+}
+
+/// Publishes the current balances of an account
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the account belongs to
+/// * `account_id` - ID of the account
+/// * `balances` - All balances of the account keyed by currency
+pub fn publish_balances(tenant: &str, account_id: u64, balances: BTreeMap<String, Balance>) {
+    BALANCES
+        .write()
+        .unwrap()
+        .insert((tenant.to_string(), account_id), balances);
+}
+
+/// Publishes the current positions of an account
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the account belongs to
+/// * `account_id` - ID of the account
+/// * `positions` - All positions of the account keyed by symbol
+pub fn publish_positions(tenant: &str, account_id: u64, positions: BTreeMap<String, Position>) {
+    POSITIONS
+        .write()
+        .unwrap()
+        .insert((tenant.to_string(), account_id), positions);
+}
+
+/// Publishes the current order book of a symbol
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the symbol belongs to
+/// * `symbol` - ID of the symbol
+/// * `index` - Raft index of the last command applied
+/// * `matcher` - Shared matcher of the symbol, None if it is no longer listed
+pub fn publish_book(tenant: &str, symbol: &str, index: u64, matcher: Option<&Arc<Matcher>>) {
+    let key = (tenant.to_string(), symbol.to_string());
+    let mut books = BOOKS.write().unwrap();
+    match matcher {
+        Some(matcher) => books.insert(key, (index, Arc::downgrade(matcher))),
+        None => books.remove(&key),
+    };
+}
+
+/// Wakes the readers waiting for books published in the batch, called once all
+/// books changed by the batch are published
+pub fn books_published() {
+    BOOKS_PUBLISHED.notify_waiters();
+}
+
+/// Publishes the orders canceled by an applied cancel-all or replace command
+///
+/// # Arguments
+///
+/// * `cancellation` - Orders the command canceled, with the request that proposed it
+pub fn publish_cancellation(cancellation: Cancellation) {
+    let mut cancellations = CANCELLATIONS.write().unwrap();
+    if cancellations.len() == CANCELLATIONS_KEPT {
+        cancellations.pop_front();
+    }
+    cancellations.push_back(cancellation);
+}
+
+/// Drops everything published, used before the state is replaced by a snapshot
+pub fn clear() {
+    BALANCES.write().unwrap().clear();
+    POSITIONS.write().unwrap().clear();
+    BOOKS.write().unwrap().clear();
+}
+
+/// Returns the published balances of an account
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the account belongs to
+/// * `account_id` - ID of the account
+///
+/// # Returns
+///
+/// Balances keyed by currency, empty if the account never held funds
+pub fn balances(tenant: &str, account_id: u64) -> BTreeMap<String, Balance> {
+    BALANCES
+        .read()
+        .unwrap()
+        .get(&(tenant.to_string(), account_id))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Returns the published positions of an account
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the account belongs to
+/// * `account_id` - ID of the account
+///
+/// # Returns
+///
+/// Positions keyed by symbol, empty if the account never traded a settled symbol
+pub fn positions(tenant: &str, account_id: u64) -> BTreeMap<String, Position> {
+    POSITIONS
+        .read()
+        .unwrap()
+        .get(&(tenant.to_string(), account_id))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Returns the open interest of a symbol
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the symbol belongs to
+/// * `symbol` - ID of the symbol
+///
+/// # Returns
+///
+/// Sum of the long positions of the tenant's accounts in the symbol, which
+/// equals the sum of the short ones
+pub fn open_interest(tenant: &str, symbol: &str) -> Decimal {
+    POSITIONS
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|((owner, _), _)| owner == tenant)
+        .filter_map(|(_, positions)| positions.get(symbol))
+        .map(|position| position.quantity.max(Decimal::ZERO))
+        .sum()
+}
+
+/// Returns the orders canceled by a cancel-all or replace command
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the command is scoped to
+/// * `request_id` - Unique ID of the request that proposed the command
+///
+/// # Returns
+///
+/// The canceled orders and the outcome of a replacement, None if the command
+/// was not applied on this node or its cancellation is no longer kept
+pub fn cancellation(tenant: &str, request_id: &str) -> Option<Cancellation> {
+    CANCELLATIONS
+        .read()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|cancellation| cancellation.tenant == tenant && cancellation.request_id == request_id)
+        .cloned()
+}
+
+/// Lists the published order books
+///
+/// # Returns
+///
+/// Tenant, symbol and index of every book as last published
+pub fn published_books() -> Vec<(String, String, u64)> {
+    BOOKS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|((tenant, symbol), (index, _))| (tenant.clone(), symbol.clone(), *index))
+        .collect()
+}
+
+/// Returns a view of the order book of a symbol
+///
+/// Returns at once unless the book was written since it was last published, in
+/// which case it waits for the end of the apply batch.
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the symbol belongs to
+/// * `symbol` - ID of the symbol
+///
+/// # Returns
+///
+/// The book as of the index it was last published at, None if the symbol is not listed
+pub async fn book(tenant: &str, symbol: &str) -> Option<BookView> {
+    let key = (tenant.to_string(), symbol.to_string());
+    loop {
+        // Registered before looking, so a publish in between is not missed
+        let published = BOOKS_PUBLISHED.notified();
+        tokio::pin!(published);
+        published.as_mut().enable();
+        let (index, matcher) = BOOKS.read().unwrap().get(&key)?.clone();
+        if let Some(matcher) = matcher.upgrade() {
+            return Some(BookView { index, matcher });
+        }
+        published.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::entry::{Order, OrderSide, OrderType};
+    use rust_decimal_macros::dec;
+
+    fn order(id: &str) -> Order {
+        Order {
+            id: id.to_string(),
+            symbol: "BTCUSDT".to_string(),
+            order_type: OrderType::Limit,
+            side: OrderSide::Buy,
+            price: dec!(100),
+            quantity: dec!(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn book_views_stay_at_their_index_while_matching_goes_on() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut matcher = Arc::new(Matcher::new("BTCUSDT".to_string()));
+        Arc::make_mut(&mut matcher).place_order(order("a"));
+        publish_book("view-test", "BTCUSDT", 1, Some(&matcher));
+        books_published();
+
+        let view = runtime.block_on(book("view-test", "BTCUSDT")).unwrap();
+        assert_eq!(view.index, 1);
+        // The write copies the book held by the view
+        Arc::make_mut(&mut matcher).place_order(order("b"));
+        assert_eq!(view.orderbook().order_count(), 1);
+        assert_eq!(matcher.orderbook().order_count(), 2);
+        drop(view);
+
+        // Written since published, the reader waits for the next publish
+        publish_book("view-test", "BTCUSDT", 2, Some(&matcher));
+        Arc::make_mut(&mut matcher).place_order(order("c"));
+        let view = runtime.block_on(async {
+            let reader = tokio::spawn(async { book("view-test", "BTCUSDT").await });
+            tokio::task::yield_now().await;
+            publish_book("view-test", "BTCUSDT", 3, Some(&matcher));
+            books_published();
+            reader.await.unwrap().unwrap()
+        });
+        assert_eq!(view.index, 3);
+        assert_eq!(view.orderbook().order_count(), 3);
+
+        publish_book("view-test", "BTCUSDT", 4, None);
+        assert!(runtime.block_on(book("view-test", "BTCUSDT")).is_none());
+    }
+}
@@ -0,0 +1,260 @@
+//! Readiness of the node to take traffic
+//!
+//! A restarted node answers requests as soon as its gRPC server is up, long
+//! before its state is current. The node only reports ready once it has
+//! loaded its snapshot and log, applied again the entries committed before
+//! the restart, connected to the leader (or, as leader, to a quorum) and
+//! applied to within `readiness_max_lag` entries of the leader's commit index.
+//!
+//! Readiness is served on `/readyz`, by the `GetReadiness` RPC and, the first
+//! time the node becomes ready, sent as `READY=1` to the service manager when
+//! started with `NOTIFY_SOCKET` set, e.g. by systemd with `Type=notify`. The
+//! connection and lag conditions are checked live, so a node that loses its
+//! leader or falls behind reports not ready again.
+
+use crate::{config, metrics};
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Progress of the node towards being ready
+static STATE: Mutex<State> = Mutex::new(State::new());
+
+/// Highest commit index a leader sent, see `observe_leader_commit`
+static LEADER_COMMIT: AtomicU64 = AtomicU64::new(0);
+
+/// Progress of the node as last seen by the raft loop
+#[derive(Debug)]
+struct State {
+    /// Commit index restored from disk, None until the node is restored
+    replay_target: Option<u64>,
+    /// Last applied index
+    applied: u64,
+    /// Leader known to the node, 0 if there is none
+    leader_id: u64,
+    /// Peers an outbound connection is open to
+    peers: BTreeSet<u64>,
+    /// Whether the service manager was told the node is ready
+    notified: bool,
+}
+
+impl State {
+    /// Creates the state of a node that has not loaded anything yet
+    const fn new() -> Self {
+        State {
+            replay_target: None,
+            applied: 0,
+            leader_id: 0,
+            peers: BTreeSet::new(),
+            notified: false,
+        }
+    }
+}
+
+/// Conditions the node must meet to take traffic
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The snapshot and log were loaded from disk
+    pub restored: bool,
+    /// Entries committed before the restart were applied again
+    pub replayed: bool,
+    /// Connected to the leader, or as leader to a quorum
+    pub peers_connected: bool,
+    /// Applied to within `readiness_max_lag` entries of the leader's commit index
+    pub caught_up: bool,
+    /// Leader known to the node, 0 if there is none
+    pub leader_id: u64,
+    /// Entries the leader committed that the node has not applied
+    pub lag: u64,
+}
+
+impl Report {
+    /// Returns whether every condition holds
+    pub fn ready(&self) -> bool {
+        self.restored && self.replayed && self.peers_connected && self.caught_up
+    }
+}
+
+/// Evaluates the conditions on the progress of the node
+///
+/// # Arguments
+///
+/// * `state` - Progress of the node
+/// * `node_id` - ID of the node
+/// * `nodes` - Number of nodes in the cluster
+/// * `leader_commit` - Highest commit index known of the leader
+/// * `max_lag` - Largest lag the node is ready at
+fn evaluate(state: &State, node_id: u64, nodes: usize, leader_commit: u64, max_lag: u64) -> Report {
+    let peers_connected = if nodes <= 1 {
+        true
+    } else if state.leader_id == 0 {
+        false
+    } else if state.leader_id == node_id {
+        (state.peers.len() + 1) * 2 > nodes
+    } else {
+        state.peers.contains(&state.leader_id)
+    };
+    let lag = leader_commit.saturating_sub(state.applied);
+    Report {
+        restored: state.replay_target.is_some(),
+        replayed: state
+            .replay_target
+            .is_some_and(|target| state.applied >= target),
+        peers_connected,
+        caught_up: state.leader_id != 0 && lag <= max_lag,
+        leader_id: state.leader_id,
+        lag,
+    }
+}
+
+/// Evaluates the readiness of the node
+pub fn report() -> Report {
+    let (node_id, nodes, max_lag) = {
+        let config = config::instance().lock().unwrap();
+        (config.id, config.node_list.len(), config.readiness_max_lag)
+    };
+    let state = STATE.lock().unwrap();
+    evaluate(
+        &state,
+        node_id,
+        nodes,
+        LEADER_COMMIT.load(Ordering::Relaxed),
+        max_lag,
+    )
+}
+
+/// Records that the node loaded its snapshot and log
+///
+/// # Arguments
+///
+/// * `committed` - Commit index restored from disk, applied again before the node is ready
+pub fn restored(committed: u64) {
+    STATE.lock().unwrap().replay_target = Some(committed);
+    log::info!("restored, replaying up to index {}", committed);
+}
+
+/// Records the commit index of an append sent by a leader
+///
+/// # Arguments
+///
+/// * `commit` - Commit index of the leader
+pub fn observe_leader_commit(commit: u64) {
+    LEADER_COMMIT.fetch_max(commit, Ordering::Relaxed);
+}
+
+/// Records that an outbound connection to a peer was opened
+pub fn peer_connected(id: u64) {
+    STATE.lock().unwrap().peers.insert(id);
+}
+
+/// Records that the outbound connection to a peer was lost
+pub fn peer_disconnected(id: u64) {
+    STATE.lock().unwrap().peers.remove(&id);
+}
+
+/// Records the progress of the raft loop, notifying the service manager the
+/// first time the node is ready
+///
+/// # Arguments
+///
+/// * `applied` - Last applied index
+/// * `committed` - Commit index of the node
+/// * `leader_id` - Leader known to the node, 0 if there is none
+pub fn observe_progress(applied: u64, committed: u64, leader_id: u64) {
+    let (node_id, nodes, max_lag) = {
+        let config = config::instance().lock().unwrap();
+        (config.id, config.node_list.len(), config.readiness_max_lag)
+    };
+    if leader_id == node_id {
+        LEADER_COMMIT.fetch_max(committed, Ordering::Relaxed);
+    }
+    let mut state = STATE.lock().unwrap();
+    state.applied = applied;
+    state.leader_id = leader_id;
+    let ready = evaluate(
+        &state,
+        node_id,
+        nodes,
+        LEADER_COMMIT.load(Ordering::Relaxed),
+        max_lag,
+    )
+    .ready();
+    metrics::READY_GAUGE.set(ready as i64);
+    if ready && !state.notified {
+        state.notified = true;
+        log::info!("ready at applied index {}", applied);
+        if let Err(e) = notify_service_manager("READY=1") {
+            log::warn!("failed to notify the service manager: {}", e);
+        }
+    }
+}
+
+/// Sends a state change to the service manager, see `sd_notify(3)`
+///
+/// Does nothing unless the process was started with `NOTIFY_SOCKET` set.
+///
+/// # Arguments
+///
+/// * `message` - Newline separated assignments, e.g. `READY=1`
+///
+/// # Returns
+///
+/// Returns why the message could not be sent
+#[cfg(unix)]
+fn notify_service_manager(message: &str) -> Result<(), String> {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound().map_err(|e| e.to_string())?;
+    let path = path.to_string_lossy();
+    let result = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())
+                .map_err(|e| e.to_string())?;
+            socket.send_to_addr(message.as_bytes(), &addr)
+        }
+        _ => socket.send_to(message.as_bytes(), path.as_ref()),
+    };
+    result.map(|_| ()).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Sends a state change to the service manager, unsupported on this platform
+#[cfg(not(unix))]
+fn notify_service_manager(_message: &str) -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_once_restored_connected_and_caught_up() {
+        let mut state = State::new();
+        assert!(!evaluate(&state, 2, 3, 0, 10).ready());
+
+        state.replay_target = Some(100);
+        state.applied = 50;
+        state.leader_id = 1;
+        let report = evaluate(&state, 2, 3, 120, 10);
+        assert!(report.restored && !report.replayed);
+        assert!(!report.peers_connected && !report.caught_up);
+        assert_eq!(report.lag, 70);
+
+        state.applied = 115;
+        state.peers.insert(1);
+        assert!(evaluate(&state, 2, 3, 120, 10).ready());
+
+        // As leader a quorum of connections is needed
+        state.leader_id = 2;
+        state.peers.clear();
+        assert!(!evaluate(&state, 2, 3, 120, 10).peers_connected);
+        state.peers.insert(3);
+        assert!(evaluate(&state, 2, 3, 120, 10).ready());
+    }
+}
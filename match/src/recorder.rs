@@ -0,0 +1,137 @@
+//! Client request recording
+//!
+//! With `record_path` set, every write request accepted by the match service is
+//! appended to that file as a length-delimited `RecordedRequest`, together with
+//! the time it was received. The benchmark replays such recordings against a
+//! test cluster (`benchmark replay`). Writing happens on a background task; if
+//! it falls behind, requests are dropped from the recording rather than slowing
+//! down the service, and counted in `channel_dropped_counter{channel="recorder"}`.
+
+use crate::match_service::pb::recorded_request::Request;
+use crate::match_service::pb::RecordedRequest;
+use crate::{config, metrics};
+use once_cell::sync::OnceCell;
+use prost::Message;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Queue of requests to record, None if recording is disabled
+static RECORDER: OnceCell<Option<Sender<RecordedRequest>>> = OnceCell::new();
+
+/// Starts recording requests if `record_path` is configured
+///
+/// # Returns
+///
+/// Returns an error if the recording file cannot be opened
+pub fn start() -> Result<(), String> {
+    let (path, capacity) = {
+        let config = config::instance().lock().unwrap();
+        (config.record_path.clone(), config.channels.recorder)
+    };
+    let sender = match path {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| format!("cannot open recording file {}: {}", path, e))?;
+            let (sender, receiver) = mpsc::channel(capacity.max(1));
+            metrics::watch_channel("recorder", &sender);
+            std::thread::spawn(move || write_records(BufWriter::new(file), receiver));
+            log::info!("recording client requests to {}", path);
+            Some(sender)
+        }
+        None => None,
+    };
+    let _ = RECORDER.set(sender);
+    Ok(())
+}
+
+/// Appends queued requests to the recording until the service shuts down
+///
+/// The file is flushed whenever the queue runs empty, so a recording is
+/// complete up to the last quiet moment if the process is killed.
+fn write_records<W: Write>(mut file: W, mut receiver: Receiver<RecordedRequest>) {
+    let mut buf = Vec::new();
+    while let Some(record) = receiver.blocking_recv() {
+        buf.clear();
+        record
+            .encode_length_delimited(&mut buf)
+            .expect("encode recorded request");
+        if let Err(e) = file.write_all(&buf) {
+            log::error!("stopped recording client requests: {}", e);
+            return;
+        }
+        if receiver.is_empty() {
+            if let Err(e) = file.flush() {
+                log::error!("stopped recording client requests: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Records a request received now
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant the request was made for
+/// * `request` - Builds the recorded request, only called if recording is enabled
+pub fn record<F: FnOnce() -> Request>(tenant: &str, request: F) {
+    let sender = match RECORDER.get() {
+        Some(Some(sender)) => sender,
+        _ => return,
+    };
+    let record = RecordedRequest {
+        received_at_us: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64,
+        tenant: tenant.to_string(),
+        request: Some(request()),
+    };
+    if sender.try_send(record).is_err() {
+        metrics::CHANNEL_DROPPED_COUNTER_VEC
+            .with_label_values(&["recorder"])
+            .inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::match_service::pb::CancelOrderRequest;
+
+    #[test]
+    fn records_are_length_delimited_in_arrival_order() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let (sender, receiver) = mpsc::channel(16);
+        for order_id in 1..=3 {
+            let request = Request::CancelOrder(CancelOrderRequest {
+                symbol: "BTCUSDT".to_string(),
+                order_id,
+                ..Default::default()
+            });
+            sender
+                .try_send(RecordedRequest {
+                    received_at_us: order_id,
+                    tenant: "default".to_string(),
+                    request: Some(request),
+                })
+                .unwrap();
+        }
+        drop(sender);
+        write_records(file.reopen().unwrap(), receiver);
+
+        let data = std::fs::read(file.path()).unwrap();
+        let mut buf = data.as_slice();
+        let mut received = Vec::new();
+        while !buf.is_empty() {
+            let record = RecordedRequest::decode_length_delimited(&mut buf).unwrap();
+            received.push(record.received_at_us);
+        }
+        assert_eq!(received, [1, 2, 3]);
+    }
+}
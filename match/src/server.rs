@@ -3,20 +3,25 @@
 //! This module implements the main server that coordinates Raft consensus,
 //! gRPC services, and metrics collection.
 
+use crate::admin_service::pb::admin_service_server::AdminServiceServer;
+use crate::admin_service::AdminServiceSVC;
 use crate::match_service::pb::match_service_server::MatchServiceServer;
 use crate::match_service::MatchServiceSVC;
-use crate::metrics;
 use crate::raft_service::pb::raft_service_server::RaftServiceServer;
 use crate::raft_service::RaftServiceSVC;
-use crate::{config, state_match};
+use crate::state_match;
+use crate::{audit, audit_trail, change_feed, config, divergence, funding_log, projection};
+use crate::{binary_gateway, exporter, metrics, metrics_endpoint, process_metrics, version};
+use crate::{
+    dead_letter, eod_export, market_data, multicast, query_store, recorder, settlement_log, ticker,
+    webhook,
+};
 
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response};
-use prometheus::{Encoder, TextEncoder};
 use raft::eraftpb::Message;
 use std::sync::Arc;
+use tower::limit::GlobalConcurrencyLimitLayer;
 
-use crate::raft::proposal::Proposal;
+use crate::raft::proposal::{Proposal, ProposalReceivers};
 use crate::raft_client;
 use once_cell::sync::OnceCell;
 use tokio::sync::mpsc;
@@ -37,6 +42,8 @@ pub struct Server {
     pub(crate) in_mailbox: Sender<Message>,
     /// Channel for receiving proposals from clients
     pub(crate) tx_proposals: Sender<Proposal>,
+    /// Channel for proposals that are committed ahead of regular ones, e.g. cancels
+    pub(crate) tx_priority_proposals: Sender<Proposal>,
 }
 
 impl Server {
@@ -44,27 +51,73 @@ impl Server {
     ///
     /// This method:
     /// 1. Sets up channels for message passing
-    /// 2. Initializes the Raft node
-    /// 3. Starts the outbound message handler
+    /// 2. Opens the settlement, funding audit and dead-letter journals, the audit
+    ///    trail and the query store, starts the projections, webhooks, multicast
+    ///    and depth feeds and checks the end-of-day export if configured
+    /// 3. Initializes the Raft node
+    /// 4. Starts the outbound message handler
     fn builder() -> Self {
-        let (tx_proposals, rx_proposals) = mpsc::channel(1000);
-        let state_match = state_match::StateMatch::new();
+        let channels = config::instance().lock().unwrap().channels.clone();
+        let (tx_proposals, rx_proposals) = mpsc::channel(channels.proposals.max(1));
+        let (tx_priority_proposals, rx_priority_proposals) =
+            mpsc::channel(channels.priority_proposals.max(1));
+        settlement_log::open().expect("open settlement journal");
+        funding_log::open().expect("open funding audit journal");
+        audit_trail::open().expect("open audit trail");
+        change_feed::start();
+        query_store::open().expect("open query store");
+        eod_export::open().expect("open end-of-day export");
+        projection::start().expect("start projections");
+        dead_letter::open().expect("open dead-letter journal");
+        webhook::start().expect("start webhooks");
+        multicast::start().expect("start multicast feed");
+        market_data::start();
+        let apply_workers = config::instance().lock().unwrap().apply_workers;
+        let layout = config::instance().lock().unwrap().runtime_layout.clone();
+        let state_match = state_match::StateMatch::new(apply_workers, &layout.apply_cores);
         let id = config::instance().lock().unwrap().id;
         let start_with_leader = config::instance().lock().unwrap().start_with_leader;
         let base_path = config::instance().lock().unwrap().base_path.clone();
-        let (in_mailbox, rx) = mpsc::channel(10000);
+        let state_check_interval = config::instance().lock().unwrap().state_check_interval;
+        let state_check = state_check_interval.map(|interval| {
+            let (state_check, checkpoints) = divergence::StateCheck::new(interval);
+            divergence::start(checkpoints);
+            state_check
+        });
+        let learner_promotion = {
+            let config = config::instance().lock().unwrap();
+            (!config.manual_learner_promotion).then_some(config.learner_promotion_max_lag)
+        };
+        let max_retained_entries = config::instance().lock().unwrap().max_retained_entries;
+        #[cfg(feature = "fault-injection")]
+        if let Some(faults) = &config::instance().lock().unwrap().fault_injection {
+            crate::raft::fault::install_crash_points(faults);
+        }
+        let (in_mailbox, rx) = mpsc::channel(channels.raft_inbound.max(1));
+        metrics::watch_channel("proposals", &tx_proposals);
+        metrics::watch_channel("priority_proposals", &tx_priority_proposals);
+        metrics::watch_channel("raft_inbound", &in_mailbox);
         let out_mailbox = crate::raft::node::Node::start_raft(
             start_with_leader,
             id,
             rx,
-            rx_proposals,
+            ProposalReceivers {
+                normal: rx_proposals,
+                priority: rx_priority_proposals,
+            },
             state_match,
             &base_path,
+            state_check,
+            learner_promotion,
+            max_retained_entries,
+            channels.raft_outbound.max(1),
+            layout.raft_thread.then_some(layout.raft_cores),
         );
         Self::start_run_out_message(out_mailbox);
         Server {
             in_mailbox,
             tx_proposals,
+            tx_priority_proposals,
         }
     }
 
@@ -75,13 +128,23 @@ impl Server {
     ///
     /// This method:
     /// 1. Initializes the logger
-    /// 2. Starts the gRPC server
-    /// 3. Starts the metrics server
-    /// 4. Initializes follower nodes
+    /// 2. Starts recording client requests if configured
+    /// 3. Starts the gRPC server and the binary order entry listener if configured
+    /// 4. Starts the metrics server
+    /// 5. Starts asking the other members for their command features
+    /// 6. Starts auditing the order books if configured
+    /// 7. Starts the end-of-day export if configured
+    /// 8. Initializes follower nodes
     pub async fn start(&mut self) {
         self.init_logger().await;
+        recorder::start().expect("start request recorder");
         self.start_grpc_server().await;
+        binary_gateway::start().expect("start binary order entry");
         self.start_metrics_server().await;
+        version::start();
+        audit::start();
+        ticker::start();
+        eod_export::start();
         self.init_followers().await;
     }
 
@@ -90,13 +153,21 @@ impl Server {
         log::info!("server stop");
     }
 
-    /// Adds a new proposal to the server
+    /// Returns the channel proposals are submitted to
+    ///
+    /// The sender is cloned so callers can wait for queue capacity without
+    /// holding the server lock, which would otherwise stall the priority lane
+    /// behind a full regular queue.
     ///
     /// # Arguments
     ///
-    /// * `proposal` - The proposal to add
-    pub async fn add_proposal(&mut self, proposal: Proposal) {
-        let _ = self.tx_proposals.send(proposal).await;
+    /// * `priority` - Whether to use the priority lane
+    pub fn proposal_sender(&self, priority: bool) -> Sender<Proposal> {
+        if priority {
+            self.tx_priority_proposals.clone()
+        } else {
+            self.tx_proposals.clone()
+        }
     }
 
     /// Starts the gRPC server
@@ -113,12 +184,25 @@ impl Server {
             .as_str()
             .parse()
             .unwrap();
-        let mut server = tonic::transport::Server::builder();
+        let (max_concurrent_rpcs, max_streams_per_connection) = {
+            let config = config::instance().lock().unwrap();
+            (
+                config.max_concurrent_rpcs,
+                config.max_streams_per_connection,
+            )
+        };
+        let mut server = tonic::transport::Server::builder()
+            .max_concurrent_streams(max_streams_per_connection)
+            .layer(tower::util::option_layer(
+                max_concurrent_rpcs.map(GlobalConcurrencyLimitLayer::new),
+            ));
         let raft_service = RaftServiceSVC::default();
         let match_service = MatchServiceSVC::default();
+        let admin_service = AdminServiceSVC::default();
         let grpc_server = server
             .add_service(RaftServiceServer::new(raft_service))
             .add_service(MatchServiceServer::new(match_service))
+            .add_service(AdminServiceServer::new(admin_service))
             .serve(addr);
         tokio::spawn(async move {
             tokio::pin!(grpc_server);
@@ -130,39 +214,22 @@ impl Server {
     /// Starts the metrics server
     ///
     /// This method:
-    /// 1. Binds to the configured metrics address
-    /// 2. Sets up the metrics endpoint
-    /// 3. Starts serving metrics requests
+    /// 1. Registers the metrics and starts the samplers
+    /// 2. Serves `/metrics` and `/healthz` on the configured metrics address
+    /// 3. Starts the configured push exporters
     async fn start_metrics_server(&mut self) {
-        let addr = config::instance()
-            .lock()
-            .unwrap()
-            .metrics_addr
-            .as_str()
-            .parse()
-            .unwrap();
-        let make_svc = make_service_fn(move |_| {
-            let registry = metrics::REGISTRY_INSTANCE.clone();
-            async move {
-                Ok::<_, hyper::Error>(service_fn(move |_: Request<Body>| {
-                    let registry = registry.clone();
-                    async move {
-                        let encoder = TextEncoder::new();
-                        let metric_families = registry.gather();
-                        let mut buffer = Vec::new();
-                        encoder.encode(&metric_families, &mut buffer).unwrap();
-                        Ok::<_, hyper::Error>(Response::new(Body::from(buffer)))
-                    }
-                }))
-            }
-        });
         metrics::init_registry();
-        let server = hyper::Server::bind(&addr).serve(make_svc);
-        tokio::spawn(async move {
-            tokio::pin!(server);
-            server.await.unwrap()
+        process_metrics::init_registry();
+        process_metrics::start_sampler();
+        tokio::spawn(async {
+            let mut interval = tokio::time::interval(metrics::CHANNEL_SAMPLE_INTERVAL);
+            loop {
+                interval.tick().await;
+                metrics::sample_channels();
+            }
         });
-        log::info!("metrics server started on {}", addr);
+        metrics_endpoint::start();
+        exporter::start_exporters().await;
     }
 
     /// Starts the outbound message handler
@@ -170,17 +237,39 @@ impl Server {
     /// This method:
     /// 1. Creates a new runtime
     /// 2. Initializes the Raft client
-    /// 3. Processes outbound messages
+    /// 3. Processes outbound messages, passing them through the configured
+    ///    faults when built with the `fault-injection` feature
     ///
     /// # Arguments
     ///
     /// * `out_mailbox` - Channel for receiving outbound messages
     fn start_run_out_message(mut out_mailbox: Receiver<Message>) {
+        #[cfg(feature = "fault-injection")]
+        let mut faults = config::instance()
+            .lock()
+            .unwrap()
+            .fault_injection
+            .clone()
+            .map(crate::raft::fault::FaultInjector::new);
         std::thread::spawn(move || {
             let rt = tokio::runtime::Runtime::new().unwrap();
             rt.block_on(async {
                 let client = Arc::new(Mutex::new(raft_client::RaftClient::builder()));
+                #[cfg(feature = "fault-injection")]
+                let start = tokio::time::Instant::now();
                 while let Some(msg) = out_mailbox.recv().await {
+                    #[cfg(feature = "fault-injection")]
+                    if let Some(faults) = faults.as_mut() {
+                        for delay in faults.deliveries(msg.from, msg.to, start.elapsed()) {
+                            let client = client.clone();
+                            let msg = msg.clone();
+                            tokio::spawn(async move {
+                                tokio::time::sleep(delay).await;
+                                client.lock().await.post_data(msg).await;
+                            });
+                        }
+                        continue;
+                    }
                     let raft_client = client.lock().await;
                     raft_client.post_data(msg).await;
                 }
@@ -193,7 +282,7 @@ impl Server {
     /// This method:
     /// 1. Checks if the current node is a leader
     /// 2. Gets the list of follower IDs
-    /// 3. Sends add follower proposals
+    /// 3. Sends add follower proposals, as learners for nodes flagged so
     async fn init_followers(&self) {
         let is_leader = config::instance().lock().unwrap().start_with_leader;
         if !is_leader {
@@ -201,14 +290,14 @@ impl Server {
         }
 
         let self_id = config::instance().lock().unwrap().id;
-        let ids: Vec<u64> = config::instance()
+        let ids: Vec<(u64, bool)> = config::instance()
             .lock()
             .unwrap()
             .node_list
             .iter()
-            .map(|n| n.id)
+            .map(|n| (n.id, n.learner))
             .collect();
-        let ids = ids.iter().filter(|i| **i != self_id).cloned().collect();
+        let ids = ids.into_iter().filter(|(i, _)| *i != self_id).collect();
 
         let proposals = self.tx_proposals.clone();
         tokio::spawn(async move {
@@ -0,0 +1,145 @@
+//! Settlement journal
+//!
+//! With `settlement_path` set, the settlement of every trade applied by this node
+//! is appended to that file as a length-delimited `SettlementEvent`, for
+//! downstream accounting. Unlike the request recorder, the journal never drops
+//! events: they are written and flushed on the raft loop after each apply batch,
+//! so a slow disk slows down applying instead. Events replayed from the log after
+//! a restart are written again; consumers drop them by raft index and trade
+//! sequence.
+
+use crate::config;
+use crate::engine::entry::OrderSide;
+use crate::engine::matchengine::Settlement;
+use crate::match_service::pb;
+use once_cell::sync::OnceCell;
+use prost::Message;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+
+/// Journal file, None if the journal is disabled
+static JOURNAL: OnceCell<Option<Mutex<BufWriter<File>>>> = OnceCell::new();
+
+/// Opens the settlement journal if `settlement_path` is configured
+///
+/// Must be called before the raft loop starts applying entries.
+///
+/// # Returns
+///
+/// Returns an error if the journal file cannot be opened
+pub fn open() -> Result<(), String> {
+    let path = config::instance().lock().unwrap().settlement_path.clone();
+    let journal = match path {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| format!("cannot open settlement journal {}: {}", path, e))?;
+            log::info!("writing settlements to {}", path);
+            Some(Mutex::new(BufWriter::new(file)))
+        }
+        None => None,
+    };
+    let _ = JOURNAL.set(journal);
+    Ok(())
+}
+
+/// Appends settlements to the journal and flushes it
+///
+/// # Arguments
+///
+/// * `settlements` - Settlements of an apply batch, in apply order
+pub fn write(settlements: &[Settlement]) {
+    let journal = match JOURNAL.get() {
+        Some(Some(journal)) if !settlements.is_empty() => journal,
+        _ => return,
+    };
+    let mut file = journal.lock().unwrap();
+    if let Err(e) = write_events(&mut *file, settlements) {
+        log::error!(
+            "cannot write {} settlements to the journal: {}",
+            settlements.len(),
+            e
+        );
+    }
+}
+
+/// Encodes settlements as length-delimited events and flushes the writer
+fn write_events<W: Write>(file: &mut W, settlements: &[Settlement]) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    for settlement in settlements {
+        buf.clear();
+        event(settlement)
+            .encode_length_delimited(&mut buf)
+            .expect("encode settlement event");
+        file.write_all(&buf)?;
+    }
+    file.flush()
+}
+
+/// Converts a settlement to its journal event
+pub(crate) fn event(settlement: &Settlement) -> pb::SettlementEvent {
+    pb::SettlementEvent {
+        index: settlement.index,
+        trade_seq: settlement.trade_seq,
+        tenant: settlement.tenant.clone(),
+        symbol: settlement.symbol.clone(),
+        buyer_order_id: settlement.buyer_order_id.clone(),
+        buyer_account_id: settlement.buyer_account_id,
+        seller_order_id: settlement.seller_order_id.clone(),
+        seller_account_id: settlement.seller_account_id,
+        taker_side: match settlement.taker_side {
+            OrderSide::Buy => pb::OrderSide::Buy,
+            OrderSide::Sell => pb::OrderSide::Sell,
+        } as i32,
+        price: settlement.price.to_string(),
+        quantity: settlement.quantity.to_string(),
+        amount: settlement.amount.to_string(),
+        base: settlement.base.clone(),
+        quote: settlement.quote.clone(),
+        buyer_fee: settlement.buyer_fee.to_string(),
+        seller_fee: settlement.seller_fee.to_string(),
+        buyer_fee_currency: settlement.buyer_fee_currency.clone(),
+        seller_fee_currency: settlement.seller_fee_currency.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn events_are_length_delimited_in_apply_order() {
+        let settlements: Vec<Settlement> = (0..3)
+            .map(|seq| Settlement {
+                index: 7,
+                trade_seq: seq,
+                symbol: "BTCUSDT".to_string(),
+                taker_side: OrderSide::Sell,
+                price: dec!(100.5),
+                quantity: dec!(2),
+                amount: dec!(201.0),
+                seller_fee: dec!(0.201),
+                ..Default::default()
+            })
+            .collect();
+        let mut file = Vec::new();
+        write_events(&mut file, &settlements).unwrap();
+
+        let mut buf = file.as_slice();
+        let mut events = Vec::new();
+        while !buf.is_empty() {
+            events.push(pb::SettlementEvent::decode_length_delimited(&mut buf).unwrap());
+        }
+        assert_eq!(
+            events.iter().map(|e| e.trade_seq).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert_eq!(events[0].taker_side(), pb::OrderSide::Sell);
+        assert_eq!(events[0].amount, "201.0");
+        assert_eq!(events[0].seller_fee, "0.201");
+    }
+}
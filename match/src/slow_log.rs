@@ -0,0 +1,162 @@
+//! Slow request logging
+//!
+//! This module traces the phases of a client request and logs the full request
+//! details when its latency exceeds the configured threshold, so latency outliers
+//! can be diagnosed after the fact. Logged requests are sampled and capped per
+//! second to protect the log volume.
+
+use crate::config;
+use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Slow request logging settings, read once from the runtime config
+struct SlowLogConfig {
+    /// Latency above which a request is logged, None disables logging
+    threshold: Option<Duration>,
+    /// Fraction of slow requests that are logged
+    sample_rate: f64,
+    /// Maximum number of slow requests logged per second
+    max_per_second: u64,
+}
+
+/// Settings shared by all requests
+static CONFIG: OnceCell<SlowLogConfig> = OnceCell::new();
+/// Second the current log budget belongs to
+static WINDOW: AtomicU64 = AtomicU64::new(0);
+/// Slow requests logged within the current second
+static LOGGED: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the slow request logging settings
+fn settings() -> &'static SlowLogConfig {
+    CONFIG.get_or_init(|| {
+        let config = config::instance().lock().unwrap();
+        SlowLogConfig {
+            threshold: config.slow_request_threshold_ms.map(Duration::from_millis),
+            sample_rate: config.slow_request_sample_rate.clamp(0.0, 1.0),
+            max_per_second: config.slow_request_max_per_second,
+        }
+    })
+}
+
+/// Decides whether a slow request is logged, applying sampling and the per second cap
+fn sampled(settings: &SlowLogConfig) -> bool {
+    if settings.sample_rate < 1.0 && rand::random::<f64>() >= settings.sample_rate {
+        return false;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    if WINDOW.swap(now, Ordering::Relaxed) != now {
+        LOGGED.store(0, Ordering::Relaxed);
+    }
+    LOGGED.fetch_add(1, Ordering::Relaxed) < settings.max_per_second
+}
+
+/// Latency trace of a single request
+///
+/// Phases are recorded with `mark`; the trace is checked against the threshold
+/// when it is dropped, so every exit path of a handler is covered.
+pub struct RequestTrace {
+    /// Name of the RPC method
+    method: &'static str,
+    /// Request ID of the command
+    request_id: String,
+    /// Symbol the request is for
+    symbol: String,
+    /// Account the request is made for
+    account_id: u64,
+    /// Final status of the request
+    status: tonic::Code,
+    /// Start of the request
+    start: Instant,
+    /// End of the previous phase
+    last: Instant,
+    /// Completed phases and their durations
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl RequestTrace {
+    /// Starts tracing a request
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - Name of the RPC method
+    pub fn new(method: &'static str) -> Self {
+        let now = Instant::now();
+        Self {
+            method,
+            request_id: String::new(),
+            symbol: String::new(),
+            account_id: 0,
+            status: tonic::Code::Ok,
+            start: now,
+            last: now,
+            phases: Vec::new(),
+        }
+    }
+
+    /// Sets the request details included in the log
+    ///
+    /// # Arguments
+    ///
+    /// * `request_id` - Request ID of the command
+    /// * `symbol` - Symbol the request is for
+    /// * `account_id` - Account the request is made for
+    pub fn describe(&mut self, request_id: &str, symbol: &str, account_id: u64) {
+        self.request_id = request_id.to_string();
+        self.symbol = symbol.to_string();
+        self.account_id = account_id;
+    }
+
+    /// Ends the current phase
+    ///
+    /// # Arguments
+    ///
+    /// * `phase` - Name of the phase that just completed
+    pub fn mark(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.phases.push((phase, now - self.last));
+        self.last = now;
+    }
+
+    /// Records the status the request failed with
+    ///
+    /// # Arguments
+    ///
+    /// * `status` - Error returned to the client
+    pub fn fail(&mut self, status: &tonic::Status) {
+        self.status = status.code();
+    }
+}
+
+impl Drop for RequestTrace {
+    fn drop(&mut self) {
+        let settings = settings();
+        let total = self.start.elapsed();
+        match settings.threshold {
+            Some(threshold) if total >= threshold => {}
+            _ => return,
+        }
+        if !sampled(settings) {
+            return;
+        }
+        let mut breakdown: Vec<String> = self
+            .phases
+            .iter()
+            .map(|(phase, elapsed)| format!("{}={:?}", phase, elapsed))
+            .collect();
+        breakdown.push(format!("rest={:?}", self.last.elapsed()));
+        log::warn!(
+            "slow request: method={} request_id={} symbol={} account={} status={:?} total={:?} phases=[{}]",
+            self.method,
+            self.request_id,
+            self.symbol,
+            self.account_id,
+            self.status,
+            total,
+            breakdown.join(" ")
+        );
+    }
+}
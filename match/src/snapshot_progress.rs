@@ -0,0 +1,360 @@
+//! Snapshot progress
+//!
+//! Tracks the snapshots a node is working on, so a snapshot that stalls shows up
+//! before a follower is noticed never catching up. Three kinds are tracked:
+//! saves of the node's own state (serialized in the background, then written to
+//! disk), sends of the leader's snapshot to a follower that fell behind the
+//! compacted log, and installs of a snapshot received from the leader (written to
+//! disk, then restored into the state machine).
+//!
+//! Each operation reports its phase, size and bytes written or handed to the
+//! transport as it goes. A send is a single raft message: it is done once raft
+//! sees the follower acknowledge the snapshot, and failed if the message was
+//! dropped or the follower rejected it. The ETA extrapolates the rate of the
+//! operation, or of the last one of its kind before any bytes moved. The last
+//! error of every kind is kept until the next one succeeds.
+//!
+//! Operations are shown in the `snapshots` field of `/healthz` and by
+//! `raftctl snapshots`, and published as `snapshot_in_progress{kind,peer}`,
+//! `snapshot_bytes{kind,peer}`, `snapshot_done_bytes{kind,peer}`,
+//! `snapshot_failures{kind}` and `snapshot_last_duration_seconds{kind}`.
+
+use crate::metrics;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Operations and history of this node
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker::new());
+
+/// Kind of snapshot operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    /// Save of the node's own state
+    Save,
+    /// Send of the leader's snapshot to a follower
+    Send,
+    /// Install of a snapshot received from the leader
+    Install,
+}
+
+impl Kind {
+    /// Returns the name of the kind used in metrics and the status
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Save => "save",
+            Kind::Send => "send",
+            Kind::Install => "install",
+        }
+    }
+}
+
+/// Snapshot operation in progress
+#[derive(Debug, Clone)]
+struct Operation {
+    /// Applied index the snapshot covers
+    index: u64,
+    /// Follower sent to or leader installed from, 0 for saves
+    peer: u64,
+    /// Current step, e.g. `serializing` or `writing`
+    phase: &'static str,
+    /// Size of the snapshot in bytes, 0 until known
+    bytes: u64,
+    /// Bytes written or handed to the transport
+    done_bytes: u64,
+    /// When the operation started
+    started: Instant,
+    /// When the current step started
+    phase_started: Instant,
+}
+
+/// Last error of a kind
+#[derive(Debug, Clone)]
+struct Failure {
+    /// Applied index of the failed snapshot
+    index: u64,
+    /// Peer of the failed operation, 0 for saves
+    peer: u64,
+    /// What went wrong
+    error: String,
+}
+
+/// Operations in progress and the outcome of finished ones
+#[derive(Debug)]
+struct Tracker {
+    /// Operations by kind and peer, one save and install at a time with peer 0
+    active: BTreeMap<(Kind, u64), Operation>,
+    /// Duration and size of the last successful operation of each kind
+    last: BTreeMap<Kind, (Duration, u64)>,
+    /// Last error of each kind, cleared when an operation of the kind succeeds
+    failures: BTreeMap<Kind, Failure>,
+}
+
+impl Tracker {
+    /// Creates a tracker without operations
+    const fn new() -> Self {
+        Tracker {
+            active: BTreeMap::new(),
+            last: BTreeMap::new(),
+            failures: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the estimated time left of an operation
+    fn eta(&self, kind: Kind, operation: &Operation, now: Instant) -> Option<Duration> {
+        if operation.bytes > 0 && operation.done_bytes > 0 {
+            let left = operation.bytes.saturating_sub(operation.done_bytes);
+            let elapsed = now.saturating_duration_since(operation.phase_started);
+            return Some(elapsed.mul_f64(left as f64 / operation.done_bytes as f64));
+        }
+        let elapsed = now.saturating_duration_since(operation.started);
+        let (duration, bytes) = self.last.get(&kind)?;
+        let expected = match (operation.bytes, *bytes) {
+            (0, _) | (_, 0) => *duration,
+            (size, last) => duration.mul_f64(size as f64 / last as f64),
+        };
+        Some(expected.saturating_sub(elapsed))
+    }
+
+    /// Returns the status of the operations and the last errors
+    fn status(&self, now: Instant) -> Value {
+        let active: Vec<Value> = self
+            .active
+            .iter()
+            .map(|((kind, _), operation)| {
+                json!({
+                    "kind": kind.as_str(),
+                    "index": operation.index,
+                    "peer": operation.peer,
+                    "phase": operation.phase,
+                    "bytes": operation.bytes,
+                    "done_bytes": operation.done_bytes,
+                    "elapsed_ms": now.saturating_duration_since(operation.started).as_millis() as u64,
+                    "eta_ms": self.eta(*kind, operation, now).map(|eta| eta.as_millis() as u64),
+                })
+            })
+            .collect();
+        let errors: Vec<Value> = self
+            .failures
+            .iter()
+            .map(|(kind, failure)| {
+                json!({
+                    "kind": kind.as_str(),
+                    "index": failure.index,
+                    "peer": failure.peer,
+                    "error": failure.error,
+                })
+            })
+            .collect();
+        json!({ "active": active, "last_errors": errors })
+    }
+}
+
+/// Key of an operation, only sends run for several peers at once
+fn key(kind: Kind, peer: u64) -> (Kind, u64) {
+    match kind {
+        Kind::Send => (kind, peer),
+        Kind::Save | Kind::Install => (kind, 0),
+    }
+}
+
+/// Starts tracking an operation, replacing one of the same kind and peer
+///
+/// # Arguments
+///
+/// * `kind` - Kind of operation
+/// * `peer` - Follower sent to or leader installed from, 0 for saves
+/// * `index` - Applied index the snapshot covers
+/// * `phase` - First step of the operation
+/// * `bytes` - Size of the snapshot, 0 if not known yet
+pub fn begin(kind: Kind, peer: u64, index: u64, phase: &'static str, bytes: u64) {
+    let operation = Operation {
+        index,
+        peer,
+        phase,
+        bytes,
+        done_bytes: 0,
+        started: Instant::now(),
+        phase_started: Instant::now(),
+    };
+    TRACKER
+        .lock()
+        .unwrap()
+        .active
+        .insert(key(kind, peer), operation);
+    let labels = [kind.as_str(), &peer.to_string()];
+    metrics::SNAPSHOT_IN_PROGRESS_GAUGE_VEC
+        .with_label_values(&labels)
+        .set(1);
+    metrics::SNAPSHOT_BYTES_GAUGE_VEC
+        .with_label_values(&labels)
+        .set(bytes as i64);
+    metrics::SNAPSHOT_DONE_BYTES_GAUGE_VEC
+        .with_label_values(&labels)
+        .set(0);
+}
+
+/// Moves an operation to its next step
+///
+/// # Arguments
+///
+/// * `kind` - Kind of operation
+/// * `peer` - Peer the operation was started with
+/// * `phase` - The new step
+/// * `bytes` - Size of the snapshot once known, 0 keeps the size
+pub fn enter(kind: Kind, peer: u64, phase: &'static str, bytes: u64) {
+    let mut tracker = TRACKER.lock().unwrap();
+    let Some(operation) = tracker.active.get_mut(&key(kind, peer)) else {
+        return;
+    };
+    operation.phase = phase;
+    operation.phase_started = Instant::now();
+    operation.done_bytes = 0;
+    if bytes > 0 {
+        operation.bytes = bytes;
+    }
+    let labels = [kind.as_str(), &operation.peer.to_string()];
+    metrics::SNAPSHOT_BYTES_GAUGE_VEC
+        .with_label_values(&labels)
+        .set(operation.bytes as i64);
+    metrics::SNAPSHOT_DONE_BYTES_GAUGE_VEC
+        .with_label_values(&labels)
+        .set(0);
+}
+
+/// Records the bytes an operation has written or handed to the transport
+///
+/// # Arguments
+///
+/// * `kind` - Kind of operation
+/// * `peer` - Peer the operation was started with
+/// * `done_bytes` - Bytes done in the current step so far
+pub fn advance(kind: Kind, peer: u64, done_bytes: u64) {
+    let mut tracker = TRACKER.lock().unwrap();
+    let Some(operation) = tracker.active.get_mut(&key(kind, peer)) else {
+        return;
+    };
+    operation.done_bytes = done_bytes;
+    metrics::SNAPSHOT_DONE_BYTES_GAUGE_VEC
+        .with_label_values(&[kind.as_str(), &operation.peer.to_string()])
+        .set(done_bytes as i64);
+}
+
+/// Stops tracking an operation
+///
+/// # Arguments
+///
+/// * `kind` - Kind of operation
+/// * `peer` - Peer the operation was started with
+/// * `result` - Outcome, the error is kept as the last error of the kind
+pub fn finish(kind: Kind, peer: u64, result: Result<(), String>) {
+    let mut tracker = TRACKER.lock().unwrap();
+    let Some(operation) = tracker.active.remove(&key(kind, peer)) else {
+        return;
+    };
+    metrics::SNAPSHOT_IN_PROGRESS_GAUGE_VEC
+        .with_label_values(&[kind.as_str(), &operation.peer.to_string()])
+        .set(0);
+    match result {
+        Ok(()) => {
+            let elapsed = operation.started.elapsed();
+            metrics::SNAPSHOT_LAST_DURATION_GAUGE_VEC
+                .with_label_values(&[kind.as_str()])
+                .set(elapsed.as_secs_f64());
+            tracker.last.insert(kind, (elapsed, operation.bytes));
+            tracker.failures.remove(&kind);
+        }
+        Err(error) => {
+            log::warn!(
+                "snapshot {} at index {} with peer {} failed in {}: {}",
+                kind.as_str(),
+                operation.index,
+                operation.peer,
+                operation.phase,
+                error
+            );
+            metrics::SNAPSHOT_FAILURES_COUNTER_VEC
+                .with_label_values(&[kind.as_str()])
+                .inc();
+            let failure = Failure {
+                index: operation.index,
+                peer: operation.peer,
+                error,
+            };
+            tracker.failures.insert(kind, failure);
+        }
+    }
+}
+
+/// Returns the followers a snapshot is being sent to, with its index
+pub fn sending() -> Vec<(u64, u64)> {
+    TRACKER
+        .lock()
+        .unwrap()
+        .active
+        .iter()
+        .filter(|((kind, _), _)| *kind == Kind::Send)
+        .map(|((_, peer), operation)| (*peer, operation.index))
+        .collect()
+}
+
+/// Returns the operations in progress and the last errors, as shown on `/healthz`
+pub fn status() -> Value {
+    TRACKER.lock().unwrap().status(Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operation(bytes: u64, done_bytes: u64, started: Instant) -> Operation {
+        Operation {
+            index: 10,
+            peer: 2,
+            phase: "writing",
+            bytes,
+            done_bytes,
+            started,
+            phase_started: started,
+        }
+    }
+
+    #[test]
+    fn eta_extrapolates_the_current_or_last_rate() {
+        let start = Instant::now();
+        let now = start + Duration::from_secs(2);
+        let mut tracker = Tracker::new();
+        // Nothing to go by before the first operation of a kind finished
+        assert_eq!(
+            tracker.eta(Kind::Send, &operation(100, 0, start), now),
+            None
+        );
+        // A quarter done in 2s leaves 6s
+        assert_eq!(
+            tracker.eta(Kind::Send, &operation(100, 25, start), now),
+            Some(Duration::from_secs(6))
+        );
+        // Twice the size of the last send, which took 3s
+        tracker
+            .last
+            .insert(Kind::Send, (Duration::from_secs(3), 50));
+        assert_eq!(
+            tracker.eta(Kind::Send, &operation(100, 0, start), now),
+            Some(Duration::from_secs(4))
+        );
+        // Size not known yet, as long as the last one
+        assert_eq!(
+            tracker.eta(Kind::Send, &operation(0, 0, start), now),
+            Some(Duration::from_secs(1))
+        );
+
+        tracker
+            .active
+            .insert(key(Kind::Send, 2), operation(100, 25, start));
+        let status = tracker.status(now);
+        assert_eq!(status["active"][0]["kind"], "send");
+        assert_eq!(status["active"][0]["eta_ms"], 6000);
+        assert_eq!(status["last_errors"], json!([]));
+    }
+}
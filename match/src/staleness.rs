@@ -0,0 +1,142 @@
+//! Staleness of the state a node reads from
+//!
+//! A follower answering reads with `READ_CONSISTENCY_LOCAL` may lag behind the
+//! leader. Clients bound that lag in time with `max_staleness_ms`: every append
+//! and heartbeat from the leader reports its commit index, and once the node has
+//! applied that index its state holds every write the leader had committed when
+//! it sent the message. The staleness of the node is the time since the latest
+//! message it has caught up with that way. The leader's own state is never
+//! stale. A heartbeat reports no more than the follower has replicated, so a
+//! follower the leader is still sending entries to may understate its
+//! staleness by up to a heartbeat interval.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Freshness of the state of this node
+static FRESHNESS: Mutex<Freshness> = Mutex::new(Freshness::new());
+
+/// Commit indexes reported by the leader and how far the node caught up
+#[derive(Debug)]
+struct Freshness {
+    /// Last applied index
+    applied: u64,
+    /// Reported commit indexes not applied yet with when they arrived, both ascending
+    reported: VecDeque<(u64, Instant)>,
+    /// Arrival of the latest report the node caught up with, None if none yet
+    fresh_at: Option<Instant>,
+}
+
+impl Freshness {
+    /// Creates the freshness of a node that has not heard from a leader
+    const fn new() -> Self {
+        Freshness {
+            applied: 0,
+            reported: VecDeque::new(),
+            fresh_at: None,
+        }
+    }
+
+    /// Records a commit index reported by the leader
+    fn report(&mut self, commit: u64, now: Instant) {
+        if commit <= self.applied {
+            self.fresh_at = Some(now);
+            return;
+        }
+        // A lower index arriving later is caught up with sooner and counts for more
+        while self
+            .reported
+            .back()
+            .is_some_and(|(index, _)| *index >= commit)
+        {
+            self.reported.pop_back();
+        }
+        self.reported.push_back((commit, now));
+    }
+
+    /// Records the last applied index
+    fn apply(&mut self, applied: u64) {
+        self.applied = applied;
+        while let Some((index, at)) = self.reported.front() {
+            if *index > applied {
+                break;
+            }
+            self.fresh_at = Some(*at);
+            self.reported.pop_front();
+        }
+    }
+
+    /// Returns how old the state is, None if the node never caught up
+    fn staleness(&self, now: Instant) -> Option<Duration> {
+        self.fresh_at.map(|at| now.saturating_duration_since(at))
+    }
+}
+
+/// Records a commit index reported by an append or heartbeat of the leader
+///
+/// # Arguments
+///
+/// * `commit` - Commit index carried by the message
+pub fn observe_leader_message(commit: u64) {
+    FRESHNESS.lock().unwrap().report(commit, Instant::now());
+}
+
+/// Records the progress of the raft loop
+///
+/// # Arguments
+///
+/// * `applied` - Last applied index
+/// * `is_leader` - Whether this node is the leader, whose state is current
+pub fn observe_applied(applied: u64, is_leader: bool) {
+    let mut freshness = FRESHNESS.lock().unwrap();
+    freshness.apply(applied);
+    if is_leader {
+        freshness.reported.clear();
+        freshness.fresh_at = Some(Instant::now());
+    }
+}
+
+/// Returns how far the state of this node may lag behind the leader
+///
+/// # Returns
+///
+/// Returns the staleness, or None if the node never caught up with a leader
+pub fn staleness() -> Option<Duration> {
+    FRESHNESS.lock().unwrap().staleness(Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fresh_as_of_the_latest_report_applied() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut freshness = Freshness::new();
+        assert_eq!(freshness.staleness(at(0)), None);
+
+        freshness.report(10, at(0));
+        freshness.report(12, at(100));
+        freshness.apply(10);
+        assert_eq!(
+            freshness.staleness(at(150)),
+            Some(Duration::from_millis(150))
+        );
+
+        // A heartbeat reporting less replaces the later index it arrived behind
+        freshness.report(11, at(200));
+        assert_eq!(freshness.reported.len(), 1);
+        freshness.apply(11);
+        assert_eq!(
+            freshness.staleness(at(250)),
+            Some(Duration::from_millis(50))
+        );
+
+        // Reports of an index already applied are fresh right away
+        freshness.report(11, at(300));
+        assert_eq!(freshness.staleness(at(300)), Some(Duration::ZERO));
+        assert!(freshness.reported.is_empty());
+    }
+}
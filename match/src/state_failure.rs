@@ -0,0 +1,107 @@
+//! Failure of the state machine
+//!
+//! A panic while applying a committed entry, e.g. a malformed command reaching
+//! an `unwrap`, may leave the state half-applied. The node catches it, see
+//! `raft::catch_apply`, instead of letting it take down the raft loop, and fails
+//! the state machine. So does an entry only a newer build can apply, e.g. a
+//! command requiring a feature this build lacks, which skipping would leave this
+//! node diverged from the replicas that applied it. Once failed, no further
+//! entries are applied, reads and writes are refused with `UNAVAILABLE`,
+//! `/health` answers 503, a leader hands leadership to a follower,
+//! `state_machine_failed` is set to the index of the poison entry for alerting
+//! and cluster event subscribers are notified.
+//!
+//! The poison entry is kept under `base_path` for offline analysis, its data in
+//! `poison-<index>.bin` and the panic message or refusal in `poison-<index>.txt`.
+//! The node keeps replicating the log and must be restarted, after a fix or an
+//! upgrade, to apply entries again.
+
+use crate::{cluster_events, config, metrics};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Index of the entry the state machine failed at, 0 while it has not failed
+static FAILED_AT: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the index of the entry the state machine failed at, None if it has not failed
+pub fn failed_at() -> Option<u64> {
+    match FAILED_AT.load(Ordering::Relaxed) {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+/// Fails the state machine at a poison entry
+///
+/// Only the first failure is recorded, the state machine applies nothing after it.
+///
+/// # Arguments
+///
+/// * `index` - Index of the entry applying failed at
+/// * `message` - Message of the panic, or why the entry is unsupported
+/// * `data` - Data of the entry, None if the panic was not raised by an entry
+pub fn record(index: u64, message: &str, data: Option<&[u8]>) {
+    if FAILED_AT
+        .compare_exchange(0, index, Ordering::Relaxed, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+    log::error!(
+        "state machine failed at entry {}, no further entries are applied: {}",
+        index,
+        message
+    );
+    metrics::STATE_MACHINE_FAILED_GAUGE.set(index as i64);
+    let base_path = config::instance().lock().unwrap().base_path.clone();
+    if let Err(e) = keep(Path::new(&base_path), index, message, data) {
+        log::error!("failed to keep poison entry {}: {}", index, e);
+    }
+    cluster_events::observe_failed(index);
+}
+
+/// Writes a poison entry and why applying it failed to a directory
+///
+/// # Arguments
+///
+/// * `dir` - Directory to write to
+/// * `index` - Index of the entry
+/// * `message` - Message of the panic, or why the entry is unsupported
+/// * `data` - Data of the entry, None writes only the message
+///
+/// # Returns
+///
+/// Returns why the files could not be written
+fn keep(dir: &Path, index: u64, message: &str, data: Option<&[u8]>) -> Result<(), String> {
+    if let Some(data) = data {
+        let path = dir.join(format!("poison-{}.bin", index));
+        std::fs::write(&path, data).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    let path = dir.join(format!("poison-{}.txt", index));
+    std::fs::write(&path, format!("{}\n", message))
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poison_entries_are_kept_for_analysis() {
+        let dir = tempfile::tempdir().unwrap();
+        keep(
+            dir.path(),
+            42,
+            "called `Option::unwrap()` on a `None` value",
+            Some(b"cmd"),
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("poison-42.bin")).unwrap(),
+            b"cmd"
+        );
+        assert!(std::fs::read_to_string(dir.path().join("poison-42.txt"))
+            .unwrap()
+            .contains("unwrap"));
+    }
+}
@@ -3,7 +3,11 @@
 //! This module implements the Raft state machine interface for the match engine.
 
 use crate::engine::matchengine::MatchEngine;
-use crate::raft::StateMachine;
+use crate::engine::{clock, codec};
+use crate::raft::{catch_apply, ApplyError, FrozenSnapshot, StateMachine};
+use crate::settlement_log;
+use crate::{audit_trail, change_feed, cluster_events, drop_copy, eod_export, funding_log};
+use crate::{market_data, multicast, projection, query_store, symbol_stats, ticker, webhook};
 
 /// State machine that wraps the match engine
 ///
@@ -17,10 +21,35 @@ pub struct StateMatch {
 
 impl StateMatch {
     /// Creates a new StateMatch instance
-    pub fn new() -> StateMatch {
-        StateMatch {
-            match_engine: MatchEngine::new(),
-        }
+    ///
+    /// # Arguments
+    ///
+    /// * `apply_workers` - Number of threads orders and cancels are matched on in
+    ///   parallel per symbol, 0 applies every entry on the raft loop
+    /// * `apply_cores` - Cores the apply workers are pinned to, empty leaves them unpinned
+    ///
+    /// Applied commands are recorded if the audit trail is open or the change
+    /// feed is enabled.
+    pub fn new(apply_workers: usize, apply_cores: &[usize]) -> StateMatch {
+        let mut match_engine = MatchEngine::with_workers(apply_workers, apply_cores);
+        match_engine.record_commands(audit_trail::is_open() || change_feed::is_enabled());
+        StateMatch { match_engine }
+    }
+}
+
+/// Tells a command every build refuses from one only a newer build can apply
+///
+/// Only the former is malformed, the engine's other refusals depend on the build.
+///
+/// # Arguments
+///
+/// * `data` - The command the engine refused
+/// * `reason` - Why the engine refused it
+pub(crate) fn apply_error(data: &[u8], reason: String) -> ApplyError {
+    if codec::needs_upgrade(data) {
+        ApplyError::Unsupported(reason)
+    } else {
+        ApplyError::Malformed(reason)
     }
 }
 
@@ -31,8 +60,99 @@ impl StateMachine for StateMatch {
     ///
     /// * `index` - The log index of the entry
     /// * `data` - The data to apply
-    fn apply(&mut self, index: u64, data: &[u8]) {
-        self.match_engine.on_message(index, data);
+    ///
+    /// # Returns
+    ///
+    /// Returns that the entry is malformed if it is no command, or that it needs
+    /// a newer build
+    fn apply(&mut self, index: u64, data: &[u8]) -> Result<(), ApplyError> {
+        self.match_engine
+            .on_message(index, data)
+            .map_err(|e| apply_error(data, e))
+    }
+
+    /// Applies a batch of log entries to the state machine
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - Index and data of each entry, in log order
+    ///
+    /// # Returns
+    ///
+    /// Returns the index of each malformed entry and why, and of the
+    /// entry that needs a newer build or applying panicked at, after which
+    /// nothing is applied
+    fn apply_batch(&mut self, entries: &[(u64, &[u8])]) -> Vec<(u64, ApplyError)> {
+        if let (Some((first, _)), Some((last, _))) = (entries.first(), entries.last()) {
+            log::debug!(
+                "apply_batch: {} commands, index {}..={}",
+                entries.len(),
+                first,
+                last
+            );
+        }
+        let mut errors = Vec::new();
+        for (index, data) in entries {
+            let applied = catch_apply(|| {
+                self.match_engine
+                    .process(*index, data)
+                    .map_err(|e| apply_error(data, e))
+            });
+            if let Err(e) = applied {
+                let failed = !matches!(e, ApplyError::Malformed(_));
+                errors.push((*index, e));
+                if failed {
+                    break;
+                }
+            }
+        }
+        errors
+    }
+
+    /// Publishes the order book metrics, memory estimates, read view, settlements, funding events and
+    /// order events of the applied batch, feeds the order events to the projections,
+    /// notifies cluster event subscribers of halted symbols and drop copy subscribers
+    /// of executions, tallies the daily statistics of the end-of-day export, queues
+    /// webhook notifications, multicast market data and depth updates, tallies the
+    /// matching statistics of symbols, and appends the applied commands to the audit trail
+    /// and the change feed;
+    /// commands this node proposes next are stamped after the applied engine time,
+    /// and the ticker learns when the next timer is due
+    fn on_apply_batch(&mut self) {
+        self.match_engine.barrier();
+        clock::observe(self.match_engine.clock());
+        ticker::observe_next_due(self.match_engine.timers().next_due());
+        self.match_engine.flush_book_metrics();
+        self.match_engine.flush_memory();
+        self.match_engine.flush_read_view();
+        let settlements = self.match_engine.take_settlements();
+        settlement_log::write(&settlements);
+        let funding_events = self.match_engine.take_funding_events();
+        funding_log::write(&funding_events);
+        let order_events = self.match_engine.take_order_events();
+        query_store::write(&order_events);
+        projection::write(&order_events);
+        cluster_events::write(&order_events);
+        drop_copy::write(&order_events);
+        eod_export::write(&order_events);
+        webhook::write(&order_events);
+        multicast::write(&order_events);
+        market_data::write(&order_events);
+        symbol_stats::write(&order_events);
+        let commands = self.match_engine.take_commands();
+        audit_trail::write(&commands, &order_events, &settlements, &funding_events);
+        let engine = &self.match_engine;
+        change_feed::write(
+            engine.index(),
+            &commands,
+            &order_events,
+            &settlements,
+            &funding_events,
+            || -> FrozenSnapshot {
+                let frozen = engine.clone();
+                Box::new(move || frozen.snapshot())
+            },
+        );
     }
 
     /// Creates a snapshot of the current state
@@ -40,20 +160,48 @@ impl StateMachine for StateMatch {
     /// # Returns
     ///
     /// Returns a byte vector containing the serialized state
-    fn snapshot(&self) -> Vec<u8> {
+    fn snapshot(&self) -> Result<Vec<u8>, String> {
+        // The log up to the snapshot may be compacted, the store and the
+        // projections must not need it
+        query_store::flush();
+        projection::catch_up();
         self.match_engine.snapshot()
     }
 
+    /// Freezes a clone of the state for a snapshot, the order books are shared
+    /// with it copy-on-write
+    ///
+    /// # Returns
+    ///
+    /// Returns the serialization of the clone
+    fn freeze(&self) -> Result<FrozenSnapshot, String> {
+        let frozen = self.clone();
+        Ok(Box::new(move || frozen.snapshot()))
+    }
+
+    /// Applies the entries queued for the apply workers
+    fn barrier(&mut self) {
+        self.match_engine.barrier();
+    }
+
     /// Restores state from a snapshot
     ///
     /// # Arguments
     ///
-    /// * `_last_index` - The last applied log index
+    /// * `last_index` - The last applied log index
     /// * `_last_term` - The last applied log term
     /// * `data` - The snapshot data to restore from
-    fn on_snapshot(&mut self, _last_index: u64, _last_term: u64, data: &[u8]) {
-        if !data.is_empty() {
-            self.match_engine.on_snapshot(data);
+    ///
+    /// # Returns
+    ///
+    /// Returns why the snapshot could not be decoded
+    fn on_snapshot(&mut self, last_index: u64, _last_term: u64, data: &[u8]) -> Result<(), String> {
+        if data.is_empty() {
+            return Ok(());
         }
+        self.match_engine.on_snapshot(data)?;
+        change_feed::restored(last_index);
+        ticker::observe_next_due(self.match_engine.timers().next_due());
+        Ok(())
     }
 }
@@ -0,0 +1,508 @@
+//! Matching statistics of symbols
+//!
+//! Tallies how well each book serves the orders sent to it, for surveillance and
+//! market-quality monitoring: the fill ratio and time to fill of the orders
+//! placed, the spread weighted by how long it was quoted, and the trades executed.
+//! Statistics are kept per tenant and symbol in intervals of
+//! `symbol_stats_interval_secs` of engine time, the last `symbol_stats_intervals`
+//! of each symbol, and returned by `GetSymbolStats`.
+//!
+//! Fills are credited to the interval the order was placed in, so the fill ratio
+//! of an interval keeps growing while its orders rest. Time to fill runs from
+//! placement to the fill that completes the order, 0 for orders filled on
+//! arrival. The spread is sampled at the end of every apply batch that changed the
+//! book and holds until the next; time the book is empty or one-sided is not
+//! quoted time.
+//!
+//! Each node tallies the order events it applies and keeps the statistics in
+//! memory only: they start over on restart, and orders resting in the snapshot a
+//! node starts from are not credited with their fills.
+
+use crate::config;
+use crate::engine::entry::{Order, OrderStatus, OrderType, Trade};
+use crate::engine::history::{OrderChange, OrderEvent};
+use crate::match_service::pb::SymbolStatsInterval;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Statistics of this node, created from the configuration on first use
+static STATS: Mutex<Option<Stats>> = Mutex::new(None);
+
+/// Tallies of a symbol over one interval
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Interval {
+    /// Orders placed, rejected ones excluded
+    orders: u64,
+    /// Quantity of the orders placed
+    ordered_quantity: Decimal,
+    /// Quantity filled so far of the orders placed
+    filled_quantity: Decimal,
+    /// Orders placed that were filled completely
+    filled_orders: u64,
+    /// Sum of the times to fill of the filled orders in seconds
+    time_to_fill_secs: u64,
+    /// Sum of the spreads quoted times the seconds they were quoted for
+    spread_secs: Decimal,
+    /// Seconds the book was two-sided
+    quoted_secs: u64,
+    /// Trades executed
+    trades: u64,
+    /// Quantity traded
+    volume: Decimal,
+}
+
+/// Statistics of a symbol
+#[derive(Debug, Clone, Default)]
+struct Book {
+    /// Intervals kept by the engine time they start at in seconds
+    intervals: BTreeMap<u64, Interval>,
+    /// Spread quoted since `since`, None while the book is not two-sided
+    spread: Option<Decimal>,
+    /// Engine time in seconds the spread was last accrued up to
+    since: u64,
+}
+
+impl Book {
+    /// Returns the interval starting at a time, dropping the oldest beyond `kept`
+    fn interval(&mut self, start: u64, kept: usize) -> &mut Interval {
+        if !self.intervals.contains_key(&start) && self.intervals.len() >= kept {
+            self.intervals.pop_first();
+        }
+        self.intervals.entry(start).or_default()
+    }
+
+    /// Credits the spread quoted since the last accrual to the intervals up to a time
+    ///
+    /// # Arguments
+    ///
+    /// * `to` - Engine time in seconds
+    /// * `interval_secs` - Length of the intervals
+    /// * `kept` - Number of intervals kept
+    fn accrue(&mut self, to: u64, interval_secs: u64, kept: usize) {
+        let Some(spread) = self.spread else {
+            self.since = self.since.max(to);
+            return;
+        };
+        let retained = interval_secs.saturating_mul(kept as u64);
+        let mut from = self.since.max(to.saturating_sub(retained));
+        while from < to {
+            let start = from - from % interval_secs;
+            let end = (start + interval_secs).min(to);
+            let interval = self.interval(start, kept);
+            interval.quoted_secs += end - from;
+            interval.spread_secs += spread * Decimal::from(end - from);
+            from = end;
+        }
+        self.since = self.since.max(to);
+    }
+}
+
+/// Order resting on a book, until filled or canceled
+#[derive(Debug, Clone)]
+struct Resting {
+    /// Engine time the order was placed at in seconds
+    placed: u64,
+    /// Start of the interval the order was placed in
+    interval: u64,
+    /// Quantity left to fill
+    remaining: Decimal,
+}
+
+/// Statistics of every symbol seen by this node
+#[derive(Debug)]
+struct Stats {
+    /// Length of the intervals in seconds
+    interval_secs: u64,
+    /// Number of intervals kept per symbol
+    kept: usize,
+    /// Latest engine time seen in seconds
+    now: u64,
+    /// Statistics by tenant and symbol
+    books: BTreeMap<(String, String), Book>,
+    /// Resting orders by tenant, symbol and order ID
+    resting: HashMap<(String, String, String), Resting>,
+    /// Spreads of the books changed by the batch being applied, None if not two-sided
+    quotes: BTreeMap<(String, String), Option<Decimal>>,
+}
+
+impl Stats {
+    /// Creates statistics without symbols
+    ///
+    /// # Arguments
+    ///
+    /// * `interval_secs` - Length of the intervals, at least a second
+    /// * `kept` - Number of intervals kept per symbol, at least one
+    fn new(interval_secs: u64, kept: usize) -> Self {
+        Stats {
+            interval_secs: interval_secs.max(1),
+            kept: kept.max(1),
+            now: 0,
+            books: BTreeMap::new(),
+            resting: HashMap::new(),
+            quotes: BTreeMap::new(),
+        }
+    }
+
+    /// Records the spread of a book changed by the batch being applied
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - Tenant of the book
+    /// * `symbol` - Symbol of the book
+    /// * `spread` - Best ask minus best bid, None if the book is not two-sided
+    fn quote(&mut self, tenant: &str, symbol: &str, spread: Option<Decimal>) {
+        self.quotes
+            .insert((tenant.to_string(), symbol.to_string()), spread);
+    }
+
+    /// Tallies the order events of a batch, then accrues the spreads it quoted
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - Order events of the batch, in log order
+    fn tally(&mut self, events: &[OrderEvent]) {
+        for event in events {
+            self.now = self.now.max(event.time);
+            let tenant = &event.tenant;
+            match &event.change {
+                OrderChange::Placed { order, trades } => {
+                    self.placed(tenant, event.time, order, trades)
+                }
+                OrderChange::Canceled { symbol, order_id } => {
+                    self.resting
+                        .remove(&(tenant.clone(), symbol.clone(), order_id.clone()));
+                }
+                OrderChange::SymbolRemoved { symbol } => {
+                    self.resting
+                        .retain(|(t, s, _), _| !(t == tenant && s == symbol));
+                }
+                OrderChange::SymbolListed { symbol } => {
+                    self.books
+                        .entry((tenant.clone(), symbol.clone()))
+                        .or_default();
+                }
+                OrderChange::SymbolHalted { .. } | OrderChange::SymbolResumed { .. } => {}
+            }
+        }
+        for (key, spread) in std::mem::take(&mut self.quotes) {
+            let book = self.books.entry(key).or_default();
+            book.accrue(self.now, self.interval_secs, self.kept);
+            book.spread = spread;
+        }
+    }
+
+    /// Tallies a placed order and the trades it took part in as taker
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - Tenant of the order
+    /// * `time` - Engine time of the placement in seconds
+    /// * `order` - The order after matching
+    /// * `trades` - Trades of the order
+    fn placed(&mut self, tenant: &str, time: u64, order: &Order, trades: &[Trade]) {
+        let start = time - time % self.interval_secs;
+        let book = self
+            .books
+            .entry((tenant.to_string(), order.symbol.clone()))
+            .or_default();
+        for trade in trades {
+            let maker_id = if trade.buyer_order_id == order.id {
+                &trade.seller_order_id
+            } else {
+                &trade.buyer_order_id
+            };
+            let key = (tenant.to_string(), order.symbol.clone(), maker_id.clone());
+            if let Some(maker) = self.resting.get_mut(&key) {
+                maker.remaining -= trade.quantity;
+                let filled = maker.remaining <= Decimal::ZERO;
+                if let Some(interval) = book.intervals.get_mut(&maker.interval) {
+                    interval.filled_quantity += trade.quantity;
+                    if filled {
+                        interval.filled_orders += 1;
+                        interval.time_to_fill_secs += time.saturating_sub(maker.placed);
+                    }
+                }
+                if filled {
+                    self.resting.remove(&key);
+                }
+            }
+            let interval = book.interval(start, self.kept);
+            interval.trades += 1;
+            interval.volume += trade.quantity;
+        }
+        if order.status == OrderStatus::Rejected {
+            return;
+        }
+        let interval = book.interval(start, self.kept);
+        interval.orders += 1;
+        interval.ordered_quantity += order.quantity;
+        interval.filled_quantity += order.filled_quantity;
+        let remaining = order.quantity - order.filled_quantity;
+        if remaining <= Decimal::ZERO {
+            interval.filled_orders += 1;
+        } else if order.order_type != OrderType::Market
+            && matches!(
+                order.status,
+                OrderStatus::New | OrderStatus::PartiallyFilled
+            )
+        {
+            let resting = Resting {
+                placed: time,
+                interval: start,
+                remaining,
+            };
+            let key = (tenant.to_string(), order.symbol.clone(), order.id.clone());
+            self.resting.insert(key, resting);
+        }
+    }
+
+    /// Returns the latest intervals of a symbol, the open quote accrued up to now
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - Tenant of the symbol
+    /// * `symbol` - Name of the symbol
+    /// * `count` - Number of intervals, 0 for all kept
+    fn get(&self, tenant: &str, symbol: &str, count: usize) -> Vec<SymbolStatsInterval> {
+        let Some(book) = self.books.get(&(tenant.to_string(), symbol.to_string())) else {
+            return Vec::new();
+        };
+        let mut book = book.clone();
+        book.accrue(self.now, self.interval_secs, self.kept);
+        let count = if count == 0 { self.kept } else { count };
+        book.intervals
+            .iter()
+            .rev()
+            .take(count)
+            .map(|(start, interval)| to_pb(*start, interval))
+            .collect()
+    }
+}
+
+/// Converts an interval to its message
+fn to_pb(start: u64, interval: &Interval) -> SymbolStatsInterval {
+    let fill_ratio = if interval.ordered_quantity.is_zero() {
+        0.0
+    } else {
+        (interval.filled_quantity / interval.ordered_quantity)
+            .to_f64()
+            .unwrap_or_default()
+    };
+    let avg_time_to_fill_secs = if interval.filled_orders == 0 {
+        0.0
+    } else {
+        interval.time_to_fill_secs as f64 / interval.filled_orders as f64
+    };
+    let time_weighted_spread = if interval.quoted_secs == 0 {
+        String::new()
+    } else {
+        (interval.spread_secs / Decimal::from(interval.quoted_secs))
+            .normalize()
+            .to_string()
+    };
+    SymbolStatsInterval {
+        start,
+        orders: interval.orders,
+        ordered_quantity: interval.ordered_quantity.to_string(),
+        filled_quantity: interval.filled_quantity.to_string(),
+        fill_ratio,
+        filled_orders: interval.filled_orders,
+        avg_time_to_fill_secs,
+        time_weighted_spread,
+        quoted_secs: interval.quoted_secs,
+        trades: interval.trades,
+        volume: interval.volume.to_string(),
+    }
+}
+
+/// Runs a function on the statistics of this node, creating them on first use
+fn with_stats<R>(f: impl FnOnce(&mut Stats) -> R) -> R {
+    let mut stats = STATS.lock().unwrap();
+    let stats = stats.get_or_insert_with(|| {
+        let config = config::instance().lock().unwrap();
+        Stats::new(
+            config.symbol_stats_interval_secs,
+            config.symbol_stats_intervals,
+        )
+    });
+    f(stats)
+}
+
+/// Records the spread of a book changed by the batch being applied
+///
+/// Called by the engine for every changed book before the batch's order events
+/// are written, see `write`.
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant of the book
+/// * `symbol` - Symbol of the book
+/// * `spread` - Best ask minus best bid, None if the book is not two-sided
+pub fn quote(tenant: &str, symbol: &str, spread: Option<Decimal>) {
+    with_stats(|stats| stats.quote(tenant, symbol, spread));
+}
+
+/// Tallies the order events of an applied batch
+///
+/// # Arguments
+///
+/// * `events` - Order events of the batch, in log order
+pub fn write(events: &[OrderEvent]) {
+    with_stats(|stats| stats.tally(events));
+}
+
+/// Returns the latest intervals of a symbol, empty if the node has none
+///
+/// # Arguments
+///
+/// * `tenant` - Tenant of the symbol
+/// * `symbol` - Name of the symbol
+/// * `count` - Number of intervals, 0 for all kept
+///
+/// # Returns
+///
+/// The intervals, latest first
+pub fn get(tenant: &str, symbol: &str, count: usize) -> Vec<SymbolStatsInterval> {
+    with_stats(|stats| stats.get(tenant, symbol, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::entry::OrderSide;
+    use rust_decimal_macros::dec;
+
+    fn event(time: u64, change: OrderChange) -> OrderEvent {
+        OrderEvent {
+            index: time,
+            tenant: "t".to_string(),
+            account_id: 1,
+            time,
+            change,
+        }
+    }
+
+    fn placed(id: &str, side: OrderSide, quantity: Decimal, filled: Decimal) -> Order {
+        let status = if filled == quantity {
+            OrderStatus::Filled
+        } else if filled.is_zero() {
+            OrderStatus::New
+        } else {
+            OrderStatus::PartiallyFilled
+        };
+        Order {
+            id: id.to_string(),
+            symbol: "BTC".to_string(),
+            order_type: OrderType::Limit,
+            side,
+            price: dec!(100),
+            quantity,
+            filled_quantity: filled,
+            status,
+            ..Default::default()
+        }
+    }
+
+    fn trade(buyer: &str, seller: &str, quantity: Decimal) -> Trade {
+        Trade {
+            id: format!("{buyer}-{seller}"),
+            symbol: "BTC".to_string(),
+            price: dec!(100),
+            quantity,
+            buyer_order_id: buyer.to_string(),
+            seller_order_id: seller.to_string(),
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn fills_are_credited_to_the_interval_the_order_was_placed_in() {
+        let mut stats = Stats::new(60, 3);
+        let maker = placed("1", OrderSide::Buy, dec!(2), dec!(0));
+        stats.quote("t", "BTC", Some(dec!(2)));
+        stats.tally(&[event(
+            10,
+            OrderChange::Placed {
+                order: maker,
+                trades: vec![],
+            },
+        )]);
+        // Half the maker fills a minute later, the rest at 130s
+        let taker = placed("2", OrderSide::Sell, dec!(1), dec!(1));
+        stats.quote("t", "BTC", Some(dec!(4)));
+        stats.tally(&[event(
+            70,
+            OrderChange::Placed {
+                order: taker,
+                trades: vec![trade("1", "2", dec!(1))],
+            },
+        )]);
+        let taker = placed("3", OrderSide::Sell, dec!(3), dec!(1));
+        stats.quote("t", "BTC", None);
+        stats.tally(&[event(
+            130,
+            OrderChange::Placed {
+                order: taker,
+                trades: vec![trade("1", "3", dec!(1))],
+            },
+        )]);
+
+        let intervals = stats.get("t", "BTC", 0);
+        let starts: Vec<_> = intervals.iter().map(|i| i.start).collect();
+        assert_eq!(starts, [120, 60, 0]);
+        let first = &intervals[2];
+        assert_eq!((first.orders, first.filled_quantity.as_str()), (1, "2"));
+        assert_eq!(first.fill_ratio, 1.0);
+        assert_eq!(first.avg_time_to_fill_secs, 120.0);
+        assert_eq!(first.trades, 0);
+        // Spread 2 from 10s to 60s
+        assert_eq!(
+            (first.quoted_secs, first.time_weighted_spread.as_str()),
+            (50, "2")
+        );
+        // 2 from 60s to 70s, then 4 to 120s
+        let second = &intervals[1];
+        assert_eq!(
+            (second.filled_orders, second.avg_time_to_fill_secs),
+            (1, 0.0)
+        );
+        assert_eq!((second.trades, second.volume.as_str()), (1, "1"));
+        assert_eq!(
+            second.time_weighted_spread,
+            (dec!(220) / dec!(60)).normalize().to_string()
+        );
+        // The remaining taker rests, one-sided book from 130s on
+        let third = &intervals[0];
+        assert!((third.fill_ratio - 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!(third.filled_orders, 0);
+        assert_eq!(
+            (third.quoted_secs, third.time_weighted_spread.as_str()),
+            (10, "4")
+        );
+
+        // The oldest interval is dropped once more than 3 are kept
+        stats.tally(&[
+            event(
+                190,
+                OrderChange::Placed {
+                    order: placed("4", OrderSide::Buy, dec!(1), dec!(0)),
+                    trades: vec![],
+                },
+            ),
+            event(
+                200,
+                OrderChange::Canceled {
+                    symbol: "BTC".to_string(),
+                    order_id: "3".to_string(),
+                },
+            ),
+        ]);
+        let starts: Vec<_> = stats.get("t", "BTC", 0).iter().map(|i| i.start).collect();
+        assert_eq!(starts, [180, 120, 60]);
+        assert_eq!(stats.get("t", "BTC", 1).len(), 1);
+        let resting: Vec<_> = stats.resting.keys().map(|(_, _, id)| id.as_str()).collect();
+        assert_eq!(resting, ["4"]);
+    }
+}
@@ -0,0 +1,86 @@
+//! Timer ticks
+//!
+//! Replicated timers (see `engine::timers`) fire when a command moves engine time
+//! past them, which never happens while no commands arrive. Every node learns when
+//! the next timer is due from the batches it applies; every
+//! `timer_tick_interval_ms` the leader checks that time against its wall clock and,
+//! once it has passed, proposes a `Tick` command stamped with its hybrid logical
+//! clock, so the timer fires at the same index on every replica. Ticks are only
+//! proposed while a timer is due, an idle cluster with no timers writes none.
+//!
+//! Ticks need the `timers` and `hlc` command features; while some member lacks
+//! them timers fire with the next client command instead.
+
+use crate::engine::codec;
+use crate::engine::matchengine::{MatchCmd, MatchCmdType};
+use crate::match_service::{propose, stamped_envelope};
+use crate::slow_log::RequestTrace;
+use crate::{config, metrics, version};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+/// Engine time in milliseconds the next timer is due at, `u64::MAX` if none
+static NEXT_DUE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Records when the next timer is due after an apply batch or a snapshot
+///
+/// # Arguments
+///
+/// * `next_due` - Engine time in milliseconds of the next timer, None if none
+pub fn observe_next_due(next_due: Option<u64>) {
+    NEXT_DUE.store(next_due.unwrap_or(u64::MAX), Ordering::Release);
+}
+
+/// Starts the tick task unless ticks are disabled
+pub fn start() {
+    let (node_id, interval_ms) = {
+        let config = config::instance().lock().unwrap();
+        (config.id, config.timer_tick_interval_ms)
+    };
+    if interval_ms == 0 {
+        return;
+    }
+    let period = Duration::from_millis(interval_ms);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if metrics::RAFT_LEADER_ID_GAUGE.get() as u64 != node_id || !due() {
+                continue;
+            }
+            if !version::cluster_supports(codec::FEATURE_TIMERS)
+                || !version::cluster_supports(codec::FEATURE_HLC)
+            {
+                continue;
+            }
+            if let Err(status) = tick(Instant::now() + period).await {
+                log::warn!("failed to propose timer tick: {}", status.message());
+            }
+        }
+    });
+}
+
+/// Returns whether the next timer is due on the wall clock of this node
+fn due() -> bool {
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    NEXT_DUE.load(Ordering::Acquire) <= now_ms
+}
+
+/// Proposes a tick and waits until it is applied
+///
+/// # Arguments
+///
+/// * `deadline` - Time after which the tick is abandoned
+async fn tick(deadline: Instant) -> Result<(), tonic::Status> {
+    let mut trace = RequestTrace::new("timer_tick");
+    let cmd = MatchCmd {
+        cmd: MatchCmdType::Tick,
+        ..Default::default()
+    };
+    let envelope = stamped_envelope(String::new(), "ticker".to_string(), 0, cmd);
+    propose(envelope, Some(deadline), true, &mut trace).await
+}
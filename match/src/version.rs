@@ -0,0 +1,219 @@
+//! Rolling upgrade support
+//!
+//! Nodes running different builds can share a cluster while it is upgraded one
+//! node at a time. Every build applies a set of command features (see
+//! `codec::SUPPORTED_FEATURES`); each node asks the other members for theirs
+//! over the raft service and refuses to propose a command that relies on a
+//! feature some member does not support yet. Members that cannot be reached, or
+//! run a build that predates the negotiation, count as supporting no features.
+
+use crate::config;
+use crate::engine::codec;
+use crate::engine::matchengine::CommandEnvelope;
+use crate::raft_service::pb::raft_service_client::RaftServiceClient;
+use crate::raft_service::pb::GetFeaturesRequest;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Version of this build
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Interval between refreshes of the member features
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Timeout of a feature request to a member
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Features of the other members by node ID, as last reported
+static MEMBER_FEATURES: Mutex<BTreeMap<u64, Vec<String>>> = Mutex::new(BTreeMap::new());
+
+/// Asks a member for the command features it supports
+///
+/// # Arguments
+///
+/// * `addr` - Raft address of the member
+///
+/// # Returns
+///
+/// Returns the features, empty if the member predates the negotiation, or an
+/// error if the member cannot be reached
+async fn fetch_features(addr: String) -> Result<Vec<String>, String> {
+    let request = async {
+        let mut client = RaftServiceClient::connect(addr)
+            .await
+            .map_err(|e| e.to_string())?;
+        match client.get_features(GetFeaturesRequest {}).await {
+            Ok(response) => Ok(response.into_inner().features),
+            Err(status) if status.code() == tonic::Code::Unimplemented => Ok(Vec::new()),
+            Err(status) => Err(status.to_string()),
+        }
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, request)
+        .await
+        .map_err(|_| "timed out".to_string())?
+}
+
+/// Records the features a member reported, logging changes
+///
+/// # Arguments
+///
+/// * `id` - ID of the member
+/// * `features` - Supported features, None if the member could not be asked
+fn update_member(id: u64, features: Option<Vec<String>>) {
+    let mut members = MEMBER_FEATURES.lock().unwrap();
+    let previous = match features {
+        Some(features) => members
+            .insert(id, features.clone())
+            .filter(|f| *f != features),
+        None => members.remove(&id),
+    };
+    if let Some(previous) = previous {
+        log::info!(
+            "features of node {} changed from {:?} to {:?}",
+            id,
+            previous,
+            members.get(&id)
+        );
+    }
+}
+
+/// Starts the task that keeps the features of the other members up to date
+pub fn start() {
+    let (self_id, members) = {
+        let config = config::instance().lock().unwrap();
+        let members: Vec<(u64, String)> = config
+            .node_list
+            .iter()
+            .filter(|node| node.id != config.id)
+            .map(|node| (node.id, node.addr.clone()))
+            .collect();
+        (config.id, members)
+    };
+    log::info!(
+        "node {} runs version {} with features {:?}",
+        self_id,
+        VERSION,
+        codec::SUPPORTED_FEATURES
+    );
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            for (id, addr) in &members {
+                match fetch_features(addr.clone()).await {
+                    Ok(features) => update_member(*id, Some(features)),
+                    Err(e) => {
+                        log::debug!("cannot ask node {} for its features: {}", id, e);
+                        update_member(*id, None);
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Returns the features every configured member supports
+///
+/// # Arguments
+///
+/// * `members` - IDs of the other members
+pub fn cluster_features(members: &[u64]) -> Vec<&'static str> {
+    common_features(
+        codec::SUPPORTED_FEATURES,
+        members,
+        &MEMBER_FEATURES.lock().unwrap(),
+    )
+}
+
+/// Intersects the own features with the features reported by the members
+///
+/// # Arguments
+///
+/// * `own` - Features of this node
+/// * `members` - IDs of the other members, members without a report support nothing
+/// * `reported` - Features reported by the members
+fn common_features(
+    own: &[&'static str],
+    members: &[u64],
+    reported: &BTreeMap<u64, Vec<String>>,
+) -> Vec<&'static str> {
+    own.iter()
+        .copied()
+        .filter(|feature| {
+            members.iter().all(|id| {
+                reported
+                    .get(id)
+                    .is_some_and(|features| features.iter().any(|f| f == feature))
+            })
+        })
+        .collect()
+}
+
+/// Returns the IDs of the configured members other than this node
+fn other_members() -> Vec<u64> {
+    let config = config::instance().lock().unwrap();
+    config
+        .node_list
+        .iter()
+        .map(|node| node.id)
+        .filter(|id| *id != config.id)
+        .collect()
+}
+
+/// Returns whether every member supports a feature, to fall back to the base
+/// format of a command while some member does not
+///
+/// # Arguments
+///
+/// * `feature` - Name of the feature
+pub fn cluster_supports(feature: &str) -> bool {
+    cluster_features(&other_members()).contains(&feature)
+}
+
+/// Checks that every member can apply a command before it is proposed
+///
+/// # Arguments
+///
+/// * `envelope` - Command about to be proposed
+///
+/// # Returns
+///
+/// Returns an error naming the features some member does not support yet
+pub fn check_proposable(envelope: &CommandEnvelope) -> Result<(), String> {
+    let required = codec::required_features(envelope);
+    if required.is_empty() {
+        return Ok(());
+    }
+    let supported = cluster_features(&other_members());
+    let missing: Vec<&str> = required
+        .into_iter()
+        .filter(|feature| !supported.contains(feature))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "command requires features {:?} not supported by every cluster member yet",
+            missing
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_features_of_every_member_are_common() {
+        let own = ["stop_orders", "iceberg"];
+        let mut reported = BTreeMap::new();
+        reported.insert(2, vec!["stop_orders".to_string(), "iceberg".to_string()]);
+        reported.insert(3, vec!["stop_orders".to_string()]);
+
+        assert_eq!(common_features(&own, &[2], &reported), own);
+        assert_eq!(common_features(&own, &[2, 3], &reported), ["stop_orders"]);
+        // Node 4 never answered, e.g. an old build or down during the upgrade
+        assert!(common_features(&own, &[2, 3, 4], &reported).is_empty());
+    }
+}
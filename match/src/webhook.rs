@@ -0,0 +1,494 @@
+//! Webhook notifications
+//!
+//! Integrators that cannot keep a stream open list endpoints under
+//! `[[webhooks]]` and get a JSON POST for each trade, rejected order and symbol
+//! listed, removed, halted or resumed, built from the order events of each apply
+//! batch. Each endpoint can be limited to a tenant and to some notification types
+//! (`trade`, `order_rejected`, `symbol_listed`, `symbol_removed`, `symbol_halted`,
+//! `symbol_resumed`).
+//!
+//! Only the node that is leader when a batch is applied sends its notifications,
+//! so each is sent once per endpoint; notifications queued on a leader that
+//! stops or loses leadership before delivering them are not sent by its
+//! successor. Every endpoint is fed through its own queue of `channels.webhooks`
+//! notifications and delivered in log order on its own task, so a slow endpoint
+//! never stalls the raft loop or the other endpoints. A failed delivery, a
+//! transport error or a non-2xx status, is retried with exponential backoff up
+//! to `max_attempts` times, so an endpoint may see a notification more than once
+//! and should drop repeated `id`s. Notifications given up after the last attempt
+//! or dropped because the queue is full go to the dead-letter journal, see
+//! `dead_letter`, from which they can be replayed on the node that gave them up.
+//!
+//! With a `secret`, requests carry `x-webhook-timestamp` and
+//! `x-webhook-signature: sha256=<hex>`, the HMAC-SHA256 of the timestamp, a `.`
+//! and the body, so the endpoint can check where a notification comes from and
+//! refuse replays of old ones.
+
+use crate::config::{self, WebhookConfig};
+use crate::dead_letter;
+use crate::engine::entry::order::OrderStatus;
+use crate::engine::history::{OrderChange, OrderEvent};
+use crate::match_service::pb::DeadLetter;
+use crate::metrics;
+use hmac::{Hmac, Mac};
+use hyper::{Body, Client, Method, Request};
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+/// Time waited before the first retry of a failed delivery, doubled on each retry
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Longest time waited between two deliveries of a notification
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Types of notifications
+const KINDS: [&str; 6] = [
+    "trade",
+    "order_rejected",
+    "symbol_listed",
+    "symbol_removed",
+    "symbol_halted",
+    "symbol_resumed",
+];
+
+/// Queues of the configured endpoints, empty if none is configured
+static HOOKS: OnceCell<Vec<Hook>> = OnceCell::new();
+
+/// Notification of an applied command
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    /// Unique ID, the raft index of the command and the position in it
+    pub id: String,
+    /// Type of the notification, one of `KINDS`
+    pub kind: &'static str,
+    /// Tenant of the command
+    pub tenant: String,
+    /// JSON body posted to the endpoint
+    pub body: Value,
+}
+
+/// Configured endpoint and the queue of its task
+struct Hook {
+    /// Endpoint settings
+    config: WebhookConfig,
+    /// Queue of the notifications not yet delivered
+    sender: Sender<Arc<Notification>>,
+}
+
+impl Hook {
+    /// Whether the endpoint wants a notification
+    fn wants(&self, notification: &Notification) -> bool {
+        self.config
+            .tenant
+            .as_ref()
+            .is_none_or(|tenant| *tenant == notification.tenant)
+            && (self.config.events.is_empty()
+                || self
+                    .config
+                    .events
+                    .iter()
+                    .any(|kind| kind == notification.kind))
+    }
+}
+
+/// Describes the notifications of a batch of order events
+///
+/// # Arguments
+///
+/// * `events` - Order events of an apply batch, in log order
+///
+/// # Returns
+///
+/// Returns the notifications in log order
+pub fn notifications(events: &[OrderEvent]) -> Vec<Notification> {
+    let mut notifications = Vec::new();
+    let mut seq = 0;
+    let mut last_index = 0;
+    let mut push = |event: &OrderEvent, kind: &'static str, details: Value| {
+        if event.index != last_index {
+            last_index = event.index;
+            seq = 0;
+        }
+        let id = format!("{}-{}", event.index, seq);
+        seq += 1;
+        let mut body = json!({
+            "id": id,
+            "type": kind,
+            "tenant": event.tenant,
+            "index": event.index,
+            "time": event.time,
+        });
+        if let (Value::Object(body), Value::Object(details)) = (&mut body, details) {
+            body.extend(details);
+        }
+        notifications.push(Notification {
+            id,
+            kind,
+            tenant: event.tenant.clone(),
+            body,
+        });
+    };
+    for event in events {
+        match &event.change {
+            OrderChange::Placed { order, .. } if order.status == OrderStatus::Rejected => {
+                let details = json!({
+                    "account_id": event.account_id,
+                    "symbol": order.symbol,
+                    "order_id": order.id,
+                    "side": format!("{:?}", order.side),
+                    "price": order.price.to_string(),
+                    "quantity": order.quantity.to_string(),
+                });
+                push(event, "order_rejected", details);
+            }
+            OrderChange::Placed { order, trades } => {
+                for trade in trades {
+                    let details = json!({
+                        "symbol": trade.symbol,
+                        "price": trade.price.to_string(),
+                        "quantity": trade.quantity.to_string(),
+                        "buyer_order_id": trade.buyer_order_id,
+                        "seller_order_id": trade.seller_order_id,
+                        "taker_order_id": order.id,
+                        "taker_side": format!("{:?}", order.side),
+                        "taker_account_id": event.account_id,
+                    });
+                    push(event, "trade", details);
+                }
+            }
+            OrderChange::SymbolListed { symbol } => {
+                push(event, "symbol_listed", json!({ "symbol": symbol }));
+            }
+            OrderChange::SymbolRemoved { symbol } => {
+                push(event, "symbol_removed", json!({ "symbol": symbol }));
+            }
+            OrderChange::SymbolHalted { symbol, reason } => {
+                let details = json!({ "symbol": symbol, "reason": reason });
+                push(event, "symbol_halted", details);
+            }
+            OrderChange::SymbolResumed { symbol } => {
+                push(event, "symbol_resumed", json!({ "symbol": symbol }));
+            }
+            OrderChange::Canceled { .. } => {}
+        }
+    }
+    notifications
+}
+
+/// Signs a notification body
+///
+/// # Arguments
+///
+/// * `secret` - Key of the endpoint
+/// * `timestamp` - Seconds since the epoch sent as `x-webhook-timestamp`
+/// * `body` - Body of the request
+///
+/// # Returns
+///
+/// Returns the hex encoded HMAC-SHA256 of `<timestamp>.<body>`
+fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Starts delivering notifications to the configured endpoints
+///
+/// Must be called on the runtime before the raft loop starts applying entries.
+/// Does nothing if no webhook is configured.
+///
+/// # Returns
+///
+/// Returns an error if a webhook is misconfigured
+pub fn start() -> Result<(), String> {
+    let (configs, capacity) = {
+        let config = config::instance().lock().unwrap();
+        (config.webhooks.clone(), config.channels.webhooks.max(1))
+    };
+    let mut hooks = Vec::new();
+    for (n, config) in configs.into_iter().enumerate() {
+        if !config.url.starts_with("http://") {
+            return Err(format!(
+                "webhook url {:?} must start with http://",
+                config.url
+            ));
+        }
+        let name = format!("webhook_{}", n);
+        let (sender, receiver) = mpsc::channel(capacity);
+        metrics::watch_channel(&name, &sender);
+        log::info!("webhook {} posts to {}", name, config.url);
+        tokio::spawn(run(name, config.clone(), receiver));
+        hooks.push(Hook { config, sender });
+    }
+    let _ = HOOKS.set(hooks);
+    Ok(())
+}
+
+/// Queues the notifications of an apply batch if this node is the leader
+///
+/// # Arguments
+///
+/// * `events` - Order events of the batch, in log order
+pub fn write(events: &[OrderEvent]) {
+    let hooks = match HOOKS.get() {
+        Some(hooks) if !hooks.is_empty() && !events.is_empty() => hooks,
+        _ => return,
+    };
+    if metrics::RAFT_LEADER_ID_GAUGE.get() as u64 != config::instance().lock().unwrap().id {
+        return;
+    }
+    let mut dropped = Vec::new();
+    for notification in notifications(events).into_iter().map(Arc::new) {
+        for (n, hook) in hooks.iter().enumerate() {
+            if !hook.wants(&notification) {
+                continue;
+            }
+            if let Err(TrySendError::Full(notification)) =
+                hook.sender.try_send(notification.clone())
+            {
+                let name = format!("webhook_{}", n);
+                log::warn!("{} is behind, drop notification {}", name, notification.id);
+                metrics::CHANNEL_DROPPED_COUNTER_VEC
+                    .with_label_values(&[&name])
+                    .inc();
+                dropped.push(notification.dead_letter(&name, "queue full".to_string()));
+            }
+        }
+    }
+    dead_letter::spool(dropped);
+}
+
+/// Queues a dead letter of an endpoint to be delivered again
+///
+/// Unlike `write` this works on any node, the one that gave the letter up.
+///
+/// # Arguments
+///
+/// * `letter` - Letter of a webhook, its sink names the endpoint
+///
+/// # Returns
+///
+/// Returns an error if the endpoint is no longer configured or its queue is full
+pub fn replay(letter: &DeadLetter) -> Result<(), String> {
+    let hook = letter
+        .sink
+        .strip_prefix("webhook_")
+        .and_then(|n| n.parse::<usize>().ok())
+        .and_then(|n| HOOKS.get()?.get(n))
+        .ok_or_else(|| format!("{} is not configured", letter.sink))?;
+    let body = serde_json::from_str(&letter.body)
+        .map_err(|e| format!("bad body of dead letter {}: {}", letter.seq, e))?;
+    let notification = Notification {
+        id: letter.id.clone(),
+        kind: KINDS
+            .into_iter()
+            .find(|kind| *kind == letter.kind)
+            .unwrap_or_default(),
+        tenant: letter.tenant.clone(),
+        body,
+    };
+    hook.sender
+        .try_send(Arc::new(notification))
+        .map_err(|_| format!("{} is behind", letter.sink))
+}
+
+impl Notification {
+    /// Describes the notification as a dead letter of an endpoint
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the endpoint
+    /// * `error` - Why the notification was given up
+    fn dead_letter(&self, name: &str, error: String) -> DeadLetter {
+        DeadLetter {
+            sink: name.to_string(),
+            id: self.id.clone(),
+            kind: self.kind.to_string(),
+            tenant: self.tenant.clone(),
+            body: self.body.to_string(),
+            error,
+            ..Default::default()
+        }
+    }
+}
+
+/// Delivers the notifications queued for an endpoint until the node stops
+///
+/// # Arguments
+///
+/// * `name` - Name of the endpoint in logs and metrics
+/// * `config` - Endpoint settings
+/// * `receiver` - Notifications in log order
+async fn run(name: String, config: WebhookConfig, mut receiver: Receiver<Arc<Notification>>) {
+    let client = Client::new();
+    while let Some(notification) = receiver.recv().await {
+        let body = notification.body.to_string();
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 1;
+        let outcome = loop {
+            match post(&client, &config, &notification.id, &body).await {
+                Ok(()) => break "delivered",
+                Err(e) if attempt >= config.max_attempts => {
+                    log::error!(
+                        "{} gave up notification {} after {} attempts: {}",
+                        name,
+                        notification.id,
+                        attempt,
+                        e
+                    );
+                    let letter = notification.dead_letter(&name, e);
+                    tokio::task::spawn_blocking(move || dead_letter::spool(vec![letter]));
+                    break "failed";
+                }
+                Err(e) => {
+                    log::warn!("{} cannot deliver {}: {}", name, notification.id, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    attempt += 1;
+                }
+            }
+        };
+        metrics::WEBHOOK_DELIVERY_COUNTER_VEC
+            .with_label_values(&[&name, outcome])
+            .inc();
+    }
+}
+
+/// Posts a notification once
+///
+/// # Arguments
+///
+/// * `client` - HTTP client of the endpoint
+/// * `config` - Endpoint settings
+/// * `id` - ID of the notification, sent as `x-webhook-id`
+/// * `body` - JSON body
+///
+/// # Returns
+///
+/// Returns an error if the request failed or was answered with a non-2xx status
+async fn post(
+    client: &Client<hyper::client::HttpConnector>,
+    config: &WebhookConfig,
+    id: &str,
+    body: &str,
+) -> Result<(), String> {
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(&config.url)
+        .header("content-type", "application/json")
+        .header("x-webhook-id", id);
+    if let Some(secret) = &config.secret {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let signature = sign(secret, timestamp, body.as_bytes());
+        request = request
+            .header("x-webhook-timestamp", timestamp)
+            .header("x-webhook-signature", format!("sha256={}", signature));
+    }
+    let request = request
+        .body(Body::from(body.to_string()))
+        .map_err(|e| e.to_string())?;
+    let response = client.request(request).await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("responded {}", response.status()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::entry::{Order, Trade};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn signatures_are_hmac_sha256_of_timestamp_and_body() {
+        assert_eq!(
+            sign("secret", 1_700_000_000, br#"{"id":"1-0"}"#),
+            "f85a699342d0bbf92c961985959d434395359980d523cfac74add0cc79ca069e"
+        );
+        assert_ne!(
+            sign("secret", 1_700_000_001, br#"{"id":"1-0"}"#),
+            sign("secret", 1_700_000_000, br#"{"id":"1-0"}"#)
+        );
+    }
+
+    #[test]
+    fn trades_rejections_and_listings_are_notified() {
+        let event = |index, change| OrderEvent {
+            index,
+            tenant: "a".to_string(),
+            account_id: 7,
+            time: 100,
+            change,
+        };
+        let order = |status| Order {
+            id: "1".to_string(),
+            symbol: "BTCUSDT".to_string(),
+            status,
+            ..Default::default()
+        };
+        let trade = Trade::new(
+            "t".to_string(),
+            "BTCUSDT".to_string(),
+            dec!(100),
+            dec!(1),
+            "1".to_string(),
+            "2".to_string(),
+            0,
+        );
+        let events = [
+            event(
+                1,
+                OrderChange::SymbolListed {
+                    symbol: "BTCUSDT".to_string(),
+                },
+            ),
+            event(
+                2,
+                OrderChange::Placed {
+                    order: order(OrderStatus::Filled),
+                    trades: vec![trade.clone(), trade],
+                },
+            ),
+            event(
+                3,
+                OrderChange::Placed {
+                    order: order(OrderStatus::Rejected),
+                    trades: Vec::new(),
+                },
+            ),
+            event(
+                4,
+                OrderChange::Canceled {
+                    symbol: "BTCUSDT".to_string(),
+                    order_id: "1".to_string(),
+                },
+            ),
+        ];
+        let notified: Vec<_> = notifications(&events)
+            .into_iter()
+            .map(|notification| (notification.id, notification.kind))
+            .collect();
+        assert_eq!(
+            notified,
+            [
+                ("1-0".to_string(), "symbol_listed"),
+                ("2-0".to_string(), "trade"),
+                ("2-1".to_string(), "trade"),
+                ("3-0".to_string(), "order_rejected"),
+            ]
+        );
+        let trade = &notifications(&events)[1].body;
+        assert_eq!(trade["taker_account_id"], 7);
+        assert_eq!(trade["price"], "100");
+    }
+}
@@ -0,0 +1,244 @@
+//! End-to-end scenarios on multi-node and single-node clusters
+//!
+//! Every test starts real `match` processes, so they are slow and ignored by
+//! default: run them with `cargo test -p match --test cluster -- --ignored`.
+
+mod common;
+
+use common::pb::{
+    CreateSymbolRequest, Order, OrderSide, OrderType, PlaceOrderRequest, Symbol, SymbolStatus,
+};
+use common::TestCluster;
+use std::time::Duration;
+use tonic::transport::Channel;
+
+type Client = common::pb::match_service_client::MatchServiceClient<Channel>;
+
+/// Longest time a cluster may take to elect a leader or catch up
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interval between saved snapshots of a node, see `SAVE_SNAPSHOT_INTERVAL`
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+const SYMBOL: &str = "BTCUSDT";
+
+async fn create_symbol(client: &mut Client) {
+    client
+        .create_symbol(CreateSymbolRequest {
+            symbol: Some(Symbol {
+                symbol: SYMBOL.to_string(),
+                base: "BTC".to_string(),
+                quote: "USDT".to_string(),
+                min_quantity: "0.0001".to_string(),
+                max_quantity: "1000".to_string(),
+                min_amount: "0.01".to_string(),
+                max_amount: "1000000".to_string(),
+                price_precision: 2,
+                quantity_precision: 4,
+                status: SymbolStatus::Alive as i32,
+                settle_balances: false,
+                ..Default::default()
+            }),
+        })
+        .await
+        .expect("create symbol");
+}
+
+/// Places a resting buy order far below any sell
+async fn place_bid(client: &mut Client, order_id: u64) -> Result<(), tonic::Status> {
+    client
+        .place_order(PlaceOrderRequest {
+            order: Some(Order {
+                order_id,
+                account_id: 1,
+                order_side: OrderSide::Buy as i32,
+                order_type: OrderType::Limit as i32,
+                symbol: SYMBOL.to_string(),
+                quantity: "1".to_string(),
+                price: format!("{}", 100 + order_id % 10),
+                ..Default::default()
+            }),
+        })
+        .await
+        .map(|_| ())
+}
+
+/// Resting orders of the test symbol as exported by a node
+async fn resting_orders(cluster: &TestCluster, id: u64) -> Option<f64> {
+    cluster
+        .metric(
+            id,
+            &format!(
+                "symbol_resting_orders{{symbol=\"{}\",tenant=\"default\"}}",
+                SYMBOL
+            ),
+        )
+        .await
+}
+
+/// Asserts that every running node holds the given number of resting orders
+async fn assert_resting_orders(cluster: &TestCluster, expected: u64) {
+    cluster.wait_for_catch_up(TIMEOUT).await;
+    for id in cluster.ids() {
+        if cluster.is_running(id) {
+            assert_eq!(
+                resting_orders(cluster, id).await,
+                Some(expected as f64),
+                "resting orders on node {}",
+                id
+            );
+        }
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn cluster_elects_a_single_leader() {
+    let cluster = TestCluster::start(3).await;
+    let leader = cluster.wait_for_leader(TIMEOUT).await;
+    for id in cluster.ids() {
+        let health = cluster.health(id).await.expect("node health");
+        assert_eq!(health["leader_id"], leader);
+        assert_eq!(health["is_leader"], id == leader);
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn single_node_serves_orders_without_config() {
+    let cluster = TestCluster::start_single().await;
+    let (_, mut client) = cluster.leader_client(TIMEOUT).await;
+    create_symbol(&mut client).await;
+    for order_id in 1..=10 {
+        place_bid(&mut client, order_id).await.expect("place order");
+    }
+    assert_resting_orders(&cluster, 10).await;
+}
+
+#[tokio::test]
+#[ignore]
+async fn acknowledged_orders_survive_leader_failover() {
+    let mut cluster = TestCluster::start(3).await;
+    let (leader, mut client) = cluster.leader_client(TIMEOUT).await;
+    create_symbol(&mut client).await;
+
+    let mut acknowledged = 0;
+    for order_id in 1..=50 {
+        place_bid(&mut client, order_id).await.expect("place order");
+        acknowledged += 1;
+    }
+
+    // Keep the order flow going across the failover, retrying on the new leader
+    cluster.kill(leader);
+    let mut order_id = 51;
+    while order_id <= 100 {
+        let (_, mut client) = cluster.leader_client(TIMEOUT).await;
+        while order_id <= 100 && place_bid(&mut client, order_id).await.is_ok() {
+            acknowledged += 1;
+            order_id += 1;
+        }
+    }
+    assert_ne!(cluster.wait_for_leader(TIMEOUT).await, leader);
+    assert_resting_orders(&cluster, acknowledged).await;
+
+    // The old leader rejoins as a follower and catches up
+    cluster.restart(leader);
+    cluster.wait_for_catch_up(TIMEOUT).await;
+    assert_resting_orders(&cluster, acknowledged).await;
+}
+
+#[tokio::test]
+#[ignore]
+async fn restarted_follower_restores_from_snapshot() {
+    let mut cluster = TestCluster::start(3).await;
+    let (leader, mut client) = cluster.leader_client(TIMEOUT).await;
+    create_symbol(&mut client).await;
+    for order_id in 1..=20 {
+        place_bid(&mut client, order_id).await.expect("place order");
+    }
+
+    // Let every node save a snapshot, then write past it
+    tokio::time::sleep(SNAPSHOT_INTERVAL + Duration::from_secs(5)).await;
+    for order_id in 21..=30 {
+        place_bid(&mut client, order_id).await.expect("place order");
+    }
+    let follower = cluster
+        .ids()
+        .into_iter()
+        .find(|id| *id != leader)
+        .expect("a follower");
+    cluster.kill(follower);
+    for order_id in 31..=40 {
+        place_bid(&mut client, order_id).await.expect("place order");
+    }
+
+    cluster.restart(follower);
+    assert_resting_orders(&cluster, 40).await;
+    assert!(
+        cluster.log(follower).contains("Save snapshot at index"),
+        "follower never saved a snapshot"
+    );
+}
+
+#[tokio::test]
+#[ignore]
+async fn client_sdk_follows_the_leader() {
+    use raft_match_client::{Client, Decimal, NewOrder, Side, SymbolSpec, SymbolStatus};
+
+    let mut cluster = TestCluster::start(3).await;
+    let leader = cluster.wait_for_leader(TIMEOUT).await;
+    // Start on a follower, the client is redirected to the leader
+    let mut ids = cluster.ids();
+    ids.sort_by_key(|id| *id == leader);
+    let client = Client::builder()
+        .endpoints(ids.iter().map(|id| cluster.grpc_addr(*id)))
+        .max_attempts(100)
+        .build()
+        .expect("build client");
+    client
+        .create_symbol(&SymbolSpec {
+            name: SYMBOL.to_string(),
+            base: "BTC".to_string(),
+            quote: "USDT".to_string(),
+            price_precision: 2,
+            quantity_precision: 4,
+            min_quantity: Decimal::new(1, 4),
+            max_quantity: Decimal::new(1000, 0),
+            min_amount: Decimal::new(1, 2),
+            max_amount: Decimal::new(1_000_000, 0),
+            status: SymbolStatus::Alive,
+            settle_balances: false,
+            fee_currency: String::new(),
+            price_band: Decimal::ZERO,
+            circuit_breaker: None,
+        })
+        .await
+        .expect("create symbol");
+    let bid = |order_id: u64| {
+        NewOrder::limit(
+            order_id,
+            1,
+            SYMBOL,
+            Side::Buy,
+            Decimal::from(100 + order_id % 10),
+            Decimal::ONE,
+        )
+    };
+    for order_id in 1..=20 {
+        client
+            .place_order(bid(order_id))
+            .await
+            .expect("place order");
+    }
+    assert_resting_orders(&cluster, 20).await;
+
+    // The client finds the new leader on its own
+    cluster.kill(leader);
+    for order_id in 21..=40 {
+        client
+            .place_order(bid(order_id))
+            .await
+            .expect("place order");
+    }
+    assert_resting_orders(&cluster, 40).await;
+}
@@ -0,0 +1,581 @@
+//! Multi-node cluster harness for end-to-end tests
+//!
+//! Spins up full `match` nodes with their storage in a temporary directory and
+//! gRPC and metrics endpoints on ephemeral ports, and offers helpers to kill
+//! and restart nodes, wait for a leader and talk to the nodes over gRPC. The
+//! server keeps its configuration and raft node in process wide singletons, so
+//! every node runs as a separate process of the `match` binary.
+
+#![allow(dead_code)]
+
+use pb::match_service_client::MatchServiceClient;
+use std::fs::{self, OpenOptions};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::time::Instant;
+use tonic::transport::Channel;
+
+pub mod pb {
+    tonic::include_proto!("r#match");
+}
+
+/// Interval between polls while waiting for a cluster condition
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A node of the test cluster
+struct TestNode {
+    /// Raft node ID
+    id: u64,
+    /// Port of the gRPC server
+    grpc_port: u16,
+    /// Port of the metrics and health endpoint
+    metrics_port: u16,
+    /// Path of the node's config file
+    config_path: PathBuf,
+    /// Path the node's output is appended to
+    log_path: PathBuf,
+    /// `match` binary the node runs
+    binary: PathBuf,
+    /// Whether the node runs with `--single` as a one-node cluster
+    single: bool,
+    /// Running process of the node, None while killed
+    process: Option<Child>,
+}
+
+/// A cluster of `match` processes sharing a temporary directory
+pub struct TestCluster {
+    /// Temporary directory removed with the cluster, None once kept for inspection
+    tempdir: Option<TempDir>,
+    /// Directory holding the configs, logs and data of all nodes
+    dir: PathBuf,
+    /// Nodes by position, node `i` has ID `i + 1`
+    nodes: Vec<TestNode>,
+    /// `node_list` section shared by all node configs
+    node_list: String,
+}
+
+impl TestCluster {
+    /// Starts a cluster and waits until every node follows the same leader
+    ///
+    /// Node 1 bootstraps the cluster and adds the other nodes as followers.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - Number of nodes
+    ///
+    /// # Returns
+    ///
+    /// Returns the running cluster
+    pub async fn start(size: u64) -> Self {
+        Self::start_with_config(size, "").await
+    }
+
+    /// Starts a cluster with extra settings in every node config, see `start`
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - Number of nodes
+    /// * `extra` - TOML added to every node config, top-level keys must come before any table
+    ///
+    /// # Returns
+    ///
+    /// Returns the running cluster
+    pub async fn start_with_config(size: u64, extra: &str) -> Self {
+        Self::launch(size, extra, Path::new(env!("CARGO_BIN_EXE_match"))).await
+    }
+
+    /// Starts a cluster running another build of `match`, see `start`
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - Number of nodes
+    /// * `binary` - Path of the `match` binary every node runs
+    ///
+    /// # Returns
+    ///
+    /// Returns the running cluster
+    pub async fn start_with_binary(size: u64, binary: &Path) -> Self {
+        Self::launch(size, "", binary).await
+    }
+
+    /// Starts a single `match --single` node and waits until it leads itself
+    ///
+    /// The node keeps its data in a temporary directory of its own, only the
+    /// ports come from the config file.
+    ///
+    /// # Returns
+    ///
+    /// Returns the running one-node cluster
+    pub async fn start_single() -> Self {
+        let tempdir = tempfile::Builder::new()
+            .prefix("raft-match-single-")
+            .tempdir()
+            .expect("create cluster directory");
+        let dir = tempdir.path().to_path_buf();
+        let mut node = TestNode {
+            id: 1,
+            grpc_port: free_port(),
+            metrics_port: free_port(),
+            config_path: dir.join("node1.toml"),
+            log_path: dir.join("node1.log"),
+            binary: PathBuf::from(env!("CARGO_BIN_EXE_match")),
+            single: true,
+            process: None,
+        };
+        let mut cluster = Self {
+            tempdir: Some(tempdir),
+            dir,
+            nodes: Vec::new(),
+            node_list: String::new(),
+        };
+        cluster.write_config(&node, "");
+        node.spawn();
+        cluster.nodes.push(node);
+        cluster.wait_for_leader(Duration::from_secs(30)).await;
+        cluster
+    }
+
+    /// Writes the node configs, spawns the nodes and waits until they joined
+    async fn launch(size: u64, extra: &str, binary: &Path) -> Self {
+        let tempdir = tempfile::Builder::new()
+            .prefix("raft-match-cluster-")
+            .tempdir()
+            .expect("create cluster directory");
+        let dir = tempdir.path().to_path_buf();
+        let mut nodes: Vec<TestNode> = (1..=size)
+            .map(|id| TestNode {
+                id,
+                grpc_port: free_port(),
+                metrics_port: free_port(),
+                config_path: dir.join(format!("node{}.toml", id)),
+                log_path: dir.join(format!("node{}.log", id)),
+                binary: binary.to_path_buf(),
+                single: false,
+                process: None,
+            })
+            .collect();
+
+        let node_list: String = nodes
+            .iter()
+            .map(|node| {
+                format!(
+                    "[[node_list]]\nid = {}\naddr = \"grpc://127.0.0.1:{}\"\n\n",
+                    node.id, node.grpc_port
+                )
+            })
+            .collect();
+        let mut cluster = Self {
+            tempdir: Some(tempdir),
+            dir,
+            nodes: Vec::new(),
+            node_list,
+        };
+        for node in &nodes {
+            cluster.write_config(node, extra);
+        }
+        for node in &mut nodes {
+            node.spawn();
+        }
+        cluster.nodes = nodes;
+        cluster
+            .wait_for_members(size, Duration::from_secs(30))
+            .await;
+        cluster
+    }
+
+    /// Replaces the extra settings of every node config, see `start_with_config`
+    ///
+    /// Running nodes pick the new settings up when they are restarted.
+    ///
+    /// # Arguments
+    ///
+    /// * `extra` - TOML added to every node config
+    pub fn set_config(&self, extra: &str) {
+        for node in &self.nodes {
+            self.write_config(node, extra);
+        }
+    }
+
+    /// Directory holding the configs, logs and data of all nodes
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Reads the output a node logged so far, across restarts
+    pub fn log(&self, id: u64) -> String {
+        fs::read_to_string(&self.node(id).log_path).unwrap_or_default()
+    }
+
+    /// IDs of all nodes, running or not
+    pub fn ids(&self) -> Vec<u64> {
+        self.nodes.iter().map(|node| node.id).collect()
+    }
+
+    /// Checks whether a node is running
+    pub fn is_running(&self, id: u64) -> bool {
+        self.node(id).process.is_some()
+    }
+
+    /// Kills a node without giving it a chance to shut down cleanly
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - ID of the node
+    pub fn kill(&mut self, id: u64) {
+        self.node_mut(id).kill();
+    }
+
+    /// Marks nodes whose process exited on its own as down
+    ///
+    /// # Returns
+    ///
+    /// Returns the IDs of the nodes that exited since the last call
+    pub fn reap(&mut self) -> Vec<u64> {
+        let mut exited = Vec::new();
+        for node in &mut self.nodes {
+            let done = match node.process.as_mut() {
+                Some(process) => !matches!(process.try_wait(), Ok(None)),
+                None => false,
+            };
+            if done {
+                node.kill();
+                exited.push(node.id);
+            }
+        }
+        exited
+    }
+
+    /// Restarts a killed node on its previous storage and ports
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - ID of the node
+    pub fn restart(&mut self, id: u64) {
+        let node = self.node_mut(id);
+        assert!(node.process.is_none(), "node {} is still running", id);
+        node.spawn();
+    }
+
+    /// Restarts a node on another `match` binary, keeping its storage and ports
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - ID of the node
+    /// * `binary` - Path of the binary the node runs from now on
+    pub fn upgrade(&mut self, id: u64, binary: &Path) {
+        let node = self.node_mut(id);
+        node.kill();
+        node.binary = binary.to_path_buf();
+        node.spawn();
+    }
+
+    /// Reads the health summary of a node
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - ID of the node
+    ///
+    /// # Returns
+    ///
+    /// Returns the `/healthz` JSON, None if the node is down or not serving yet
+    pub async fn health(&self, id: u64) -> Option<serde_json::Value> {
+        let body = self.http_get(id, "/healthz").await?;
+        serde_json::from_slice(&body).ok()
+    }
+
+    /// Reads the Prometheus metrics of a node
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - ID of the node
+    ///
+    /// # Returns
+    ///
+    /// Returns the `/metrics` text, None if the node is down or not serving yet
+    pub async fn metrics(&self, id: u64) -> Option<String> {
+        let body = self.http_get(id, "/metrics").await?;
+        String::from_utf8(body).ok()
+    }
+
+    /// Reads a single sample from the metrics of a node
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - ID of the node
+    /// * `series` - Metric name with its labels as exported, e.g. `symbol_resting_orders{symbol="BTCUSDT",tenant="default"}`
+    ///
+    /// # Returns
+    ///
+    /// Returns the sample value, None if the node is down or does not export the series
+    pub async fn metric(&self, id: u64, series: &str) -> Option<f64> {
+        let metrics = self.metrics(id).await?;
+        metrics.lines().find_map(|line| {
+            let (name, value) = line.rsplit_once(' ')?;
+            if name == series {
+                value.parse().ok()
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Waits until a running node leads and a majority of the running nodes follows it
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Longest time to wait
+    ///
+    /// # Returns
+    ///
+    /// Returns the ID of the leader
+    pub async fn wait_for_leader(&self, timeout: Duration) -> u64 {
+        self.wait_for("a leader", timeout, || self.leader()).await
+    }
+
+    /// Looks up a running node that leads and is followed by a majority of the running nodes
+    ///
+    /// # Returns
+    ///
+    /// Returns the ID of the leader, None if there is none right now
+    pub async fn leader(&self) -> Option<u64> {
+        let running = self.running();
+        let mut leaders = Vec::new();
+        for id in &running {
+            if let Some(health) = self.health(*id).await {
+                leaders.push((*id, health));
+            }
+        }
+        let leader = leaders
+            .iter()
+            .find(|(_, health)| health["is_leader"] == true)?
+            .0;
+        let followers = leaders
+            .iter()
+            .filter(|(_, health)| health["leader_id"] == leader)
+            .count();
+        (followers > running.len() / 2).then_some(leader)
+    }
+
+    /// Waits until every running node has applied all entries the leader committed
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Longest time to wait
+    pub async fn wait_for_catch_up(&self, timeout: Duration) {
+        let leader = self.wait_for_leader(timeout).await;
+        let commit = self
+            .health(leader)
+            .await
+            .and_then(|health| health["commit_index"].as_i64())
+            .expect("leader health");
+        self.wait_for("followers to catch up", timeout, || async move {
+            for id in self.running() {
+                let applied = self.health(id).await?["applied_index"].as_i64()?;
+                if applied < commit {
+                    return None;
+                }
+            }
+            Some(())
+        })
+        .await
+    }
+
+    /// Address of a node's gRPC server
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - ID of the node
+    pub fn grpc_addr(&self, id: u64) -> String {
+        format!("http://127.0.0.1:{}", self.node(id).grpc_port)
+    }
+
+    /// Connects a gRPC client to a node
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - ID of the node
+    ///
+    /// # Returns
+    ///
+    /// Returns the client, or the connection error
+    pub async fn client(
+        &self,
+        id: u64,
+    ) -> Result<MatchServiceClient<Channel>, tonic::transport::Error> {
+        MatchServiceClient::connect(self.grpc_addr(id)).await
+    }
+
+    /// Connects a gRPC client to the current leader, waiting for one if needed
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Longest time to wait for a leader
+    ///
+    /// # Returns
+    ///
+    /// Returns the ID of the leader and the client
+    pub async fn leader_client(&self, timeout: Duration) -> (u64, MatchServiceClient<Channel>) {
+        let leader = self.wait_for_leader(timeout).await;
+        let client = self.client(leader).await.expect("connect to leader");
+        (leader, client)
+    }
+
+    /// Waits until every node reports the same leader, right after startup
+    async fn wait_for_members(&self, size: u64, timeout: Duration) {
+        let leader = self.wait_for_leader(timeout).await;
+        self.wait_for("all nodes to join", timeout, || async move {
+            for id in 1..=size {
+                if self.health(id).await?["leader_id"] != leader {
+                    return None;
+                }
+            }
+            Some(())
+        })
+        .await
+    }
+
+    /// Polls a condition until it yields a value, panics once the timeout passed
+    async fn wait_for<T, F, Fut>(&self, what: &str, timeout: Duration, mut poll: F) -> T
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Option<T>>,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(value) = poll().await {
+                return value;
+            }
+            if Instant::now() >= deadline {
+                panic!(
+                    "timed out after {:?} waiting for {}, node logs in {}",
+                    timeout,
+                    what,
+                    self.dir.display()
+                );
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Issues a GET request to the metrics endpoint of a running node
+    async fn http_get(&self, id: u64, path: &str) -> Option<Vec<u8>> {
+        let node = self.node(id);
+        node.process.as_ref()?;
+        let uri = format!("http://127.0.0.1:{}{}", node.metrics_port, path)
+            .parse()
+            .ok()?;
+        let response = hyper::Client::new().get(uri).await.ok()?;
+        let body = hyper::body::to_bytes(response.into_body()).await.ok()?;
+        Some(body.to_vec())
+    }
+
+    /// Writes the config of a node
+    fn write_config(&self, node: &TestNode, extra: &str) {
+        if node.single {
+            let config = format!(
+                "addr = \"127.0.0.1:{}\"\nmetrics_addr = \"127.0.0.1:{}\"\n{}\n",
+                node.grpc_port, node.metrics_port, extra
+            );
+            fs::write(&node.config_path, config).expect("write node config");
+            return;
+        }
+        let config = format!(
+            "id = {}\nstart_with_leader = {}\naddr = \"127.0.0.1:{}\"\nmetrics_addr = \"127.0.0.1:{}\"\nbase_path = \"{}\"\n{}\n\n{}",
+            node.id,
+            node.id == 1,
+            node.grpc_port,
+            node.metrics_port,
+            self.dir.join(format!("data{}", node.id)).display(),
+            extra,
+            self.node_list
+        );
+        fs::write(&node.config_path, config).expect("write node config");
+    }
+
+    /// IDs of the running nodes
+    fn running(&self) -> Vec<u64> {
+        self.nodes
+            .iter()
+            .filter(|node| node.process.is_some())
+            .map(|node| node.id)
+            .collect()
+    }
+
+    fn node(&self, id: u64) -> &TestNode {
+        self.nodes
+            .iter()
+            .find(|node| node.id == id)
+            .unwrap_or_else(|| panic!("no node {}", id))
+    }
+
+    fn node_mut(&mut self, id: u64) -> &mut TestNode {
+        self.nodes
+            .iter_mut()
+            .find(|node| node.id == id)
+            .unwrap_or_else(|| panic!("no node {}", id))
+    }
+}
+
+impl Drop for TestCluster {
+    /// Kills all nodes and removes their data, unless the test failed
+    fn drop(&mut self) {
+        for node in &mut self.nodes {
+            node.kill();
+        }
+        if std::thread::panicking() {
+            std::mem::forget(self.tempdir.take());
+            eprintln!("node logs and data kept in {}", self.dir.display());
+        }
+    }
+}
+
+impl TestNode {
+    /// Starts the node's process, appending its output to the node's log
+    fn spawn(&mut self) {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log_path)
+            .expect("open node log");
+        let stderr = log.try_clone().expect("clone node log");
+        let mut command = Command::new(&self.binary);
+        if self.single {
+            command.arg("--single");
+        }
+        let process = command
+            .arg("--config")
+            .arg(&self.config_path)
+            .env(
+                "RUST_LOG",
+                std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string()),
+            )
+            .stdin(Stdio::null())
+            .stdout(Stdio::from(log))
+            .stderr(Stdio::from(stderr))
+            .spawn()
+            .expect("spawn match node");
+        self.process = Some(process);
+    }
+
+    /// Kills the node's process if it is running
+    fn kill(&mut self) {
+        if let Some(mut process) = self.process.take() {
+            let _ = process.kill();
+            let _ = process.wait();
+        }
+    }
+}
+
+/// Picks a port that is free right now
+///
+/// The port is released again before the node binds it, another process may
+/// grab it in between; collisions show up as a node failing to start.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("bind ephemeral port")
+        .port()
+}
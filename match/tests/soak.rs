@@ -0,0 +1,276 @@
+//! Crash-recovery soak test
+//!
+//! Runs an order flow against a cluster while nodes are killed with SIGKILL at
+//! random times and abort themselves at the injected crash points of the raft
+//! loop (mid-append, mid-snapshot, mid-apply). Every order is retried with the
+//! same request ID until it is acknowledged, so once the crashes stop and the
+//! cluster settled, every node must hold exactly the placed orders and no state
+//! checksum may have diverged from the leader's.
+//!
+//! Needs the `fault-injection` feature and is ignored by default:
+//! `cargo test -p match --features fault-injection --test soak -- --ignored --nocapture`.
+//! `SOAK_SECS` sets the duration of the crash phase (default 300) and
+//! `SOAK_SEED` the seed of the kill schedule.
+
+#![cfg(feature = "fault-injection")]
+
+mod common;
+
+use common::pb::{
+    CreateSymbolRequest, Order, OrderSide, OrderType, PlaceOrderRequest, Symbol, SymbolStatus,
+};
+use common::TestCluster;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Longest time the cluster may take to settle once the crashes stopped
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Timeout of a single client request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Node config during the crash phase: frequent state checksums and crash points
+const CRASH_CONFIG: &str = "state_check_interval = 100
+
+[fault_injection]
+crash_ratio = { append = 0.0005, apply = 0.0002, snapshot = 0.2 }
+";
+
+/// Node config once the crashes stopped, keeps the state checksums
+const CALM_CONFIG: &str = "state_check_interval = 100";
+
+const SYMBOL: &str = "BTCUSDT";
+
+/// Kills and restarts nodes on a seeded schedule and restarts crashed nodes
+struct Chaos {
+    /// Source of the kill schedule
+    rng: StdRng,
+    /// When the next node is killed
+    next_kill: Instant,
+    /// Nodes that are down and when they are restarted
+    down: Vec<(u64, Instant)>,
+    /// Nodes killed by the schedule
+    kills: u64,
+    /// Nodes that aborted at a crash point
+    crashes: u64,
+}
+
+impl Chaos {
+    fn new(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let next_kill = Instant::now() + Duration::from_millis(rng.gen_range(2_000..8_000));
+        Self {
+            rng,
+            next_kill,
+            down: Vec::new(),
+            kills: 0,
+            crashes: 0,
+        }
+    }
+
+    /// Time a node stays down before it is restarted
+    fn downtime(&mut self) -> Duration {
+        Duration::from_millis(self.rng.gen_range(500..3_000))
+    }
+
+    /// Restarts nodes that are due and kills one if the schedule says so
+    ///
+    /// At most one node is killed by the schedule at a time, crashes may take
+    /// down more.
+    fn step(&mut self, cluster: &mut TestCluster) {
+        for id in cluster.reap() {
+            self.crashes += 1;
+            let downtime = self.downtime();
+            self.down.push((id, Instant::now() + downtime));
+        }
+        let now = Instant::now();
+        self.down.retain(|(id, restart_at)| {
+            if *restart_at > now {
+                return true;
+            }
+            cluster.restart(*id);
+            false
+        });
+        if now >= self.next_kill && self.down.is_empty() {
+            let ids = cluster.ids();
+            let id = ids[self.rng.gen_range(0..ids.len())];
+            cluster.kill(id);
+            self.kills += 1;
+            let downtime = self.downtime();
+            self.down.push((id, now + downtime));
+            self.next_kill = now + Duration::from_millis(self.rng.gen_range(2_000..8_000));
+        }
+    }
+
+    /// Restarts every node that is still down
+    fn heal(&mut self, cluster: &mut TestCluster) {
+        let crashed = cluster.reap();
+        self.crashes += crashed.len() as u64;
+        let down = self.down.drain(..).map(|(id, _)| id);
+        for id in crashed.into_iter().chain(down) {
+            cluster.restart(id);
+        }
+    }
+}
+
+/// Places a resting buy order on the current leader
+///
+/// # Returns
+///
+/// Returns whether the order was acknowledged
+async fn place_bid(cluster: &TestCluster, order_id: u64) -> bool {
+    let leader = match cluster.leader().await {
+        Some(leader) => leader,
+        None => return false,
+    };
+    let mut client = match cluster.client(leader).await {
+        Ok(client) => client,
+        Err(_) => return false,
+    };
+    let mut request = tonic::Request::new(PlaceOrderRequest {
+        order: Some(Order {
+            order_id,
+            account_id: 1,
+            order_side: OrderSide::Buy as i32,
+            order_type: OrderType::Limit as i32,
+            symbol: SYMBOL.to_string(),
+            quantity: "1".to_string(),
+            price: format!("{}", 100 + order_id % 10),
+            ..Default::default()
+        }),
+    });
+    request.metadata_mut().insert(
+        "x-request-id",
+        format!("soak-{}", order_id).parse().unwrap(),
+    );
+    request.set_timeout(REQUEST_TIMEOUT);
+    tokio::time::timeout(REQUEST_TIMEOUT, client.place_order(request))
+        .await
+        .map(|response| response.is_ok())
+        .unwrap_or(false)
+}
+
+#[tokio::test]
+#[ignore]
+async fn acknowledged_orders_survive_crashes() {
+    let secs = std::env::var("SOAK_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(300);
+    let seed = std::env::var("SOAK_SEED")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(rand::random);
+    println!("soak seed {}", seed);
+
+    let mut cluster = TestCluster::start_with_config(3, CRASH_CONFIG).await;
+    let (_, mut client) = cluster.leader_client(TIMEOUT).await;
+    client
+        .create_symbol(CreateSymbolRequest {
+            symbol: Some(Symbol {
+                symbol: SYMBOL.to_string(),
+                base: "BTC".to_string(),
+                quote: "USDT".to_string(),
+                min_quantity: "0.0001".to_string(),
+                max_quantity: "1000".to_string(),
+                min_amount: "0.01".to_string(),
+                max_amount: "1000000".to_string(),
+                price_precision: 2,
+                quantity_precision: 4,
+                status: SymbolStatus::Alive as i32,
+                settle_balances: false,
+                ..Default::default()
+            }),
+        })
+        .await
+        .expect("create symbol");
+
+    // Crash phase: every order is retried until acknowledged, across crashes
+    let mut chaos = Chaos::new(seed);
+    let deadline = Instant::now() + Duration::from_secs(secs);
+    let mut orders = 0;
+    while Instant::now() < deadline {
+        let order_id = orders + 1;
+        let stalled_since = Instant::now();
+        while !place_bid(&cluster, order_id).await {
+            assert!(
+                stalled_since.elapsed() < TIMEOUT,
+                "order {} not acknowledged within {:?}, node logs in {}",
+                order_id,
+                TIMEOUT,
+                cluster.dir().display()
+            );
+            chaos.step(&mut cluster);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        orders = order_id;
+        chaos.step(&mut cluster);
+    }
+
+    // Stop the crashes: restart every node with the calm config, one at a time
+    chaos.heal(&mut cluster);
+    cluster.set_config(CALM_CONFIG);
+    for id in cluster.ids() {
+        chaos.heal(&mut cluster);
+        cluster.wait_for_leader(TIMEOUT).await;
+        cluster.kill(id);
+        cluster.restart(id);
+    }
+    cluster.wait_for_catch_up(TIMEOUT).await;
+
+    let crash_points: usize = cluster
+        .ids()
+        .iter()
+        .map(|id| cluster.log(*id).matches("injected crash at").count())
+        .sum();
+    println!(
+        "{} orders, {} kills, {} crashes ({} at crash points)",
+        orders, chaos.kills, chaos.crashes, crash_points
+    );
+
+    let series = format!(
+        "symbol_resting_orders{{symbol=\"{}\",tenant=\"default\"}}",
+        SYMBOL
+    );
+    for id in cluster.ids() {
+        assert_eq!(
+            cluster.metric(id, &series).await,
+            Some(orders as f64),
+            "resting orders on node {}, node logs in {}",
+            id,
+            cluster.dir().display()
+        );
+    }
+
+    // Give the checksums a round on the settled cluster, then look for
+    // mismatches logged by any node across all of its restarts
+    let (_, mut client) = cluster.leader_client(TIMEOUT).await;
+    for order_id in orders + 1..=orders + 100 {
+        let mut request = tonic::Request::new(PlaceOrderRequest {
+            order: Some(Order {
+                order_id,
+                account_id: 1,
+                order_side: OrderSide::Buy as i32,
+                order_type: OrderType::Limit as i32,
+                symbol: SYMBOL.to_string(),
+                quantity: "1".to_string(),
+                price: "100".to_string(),
+                ..Default::default()
+            }),
+        });
+        request.set_timeout(REQUEST_TIMEOUT);
+        client.place_order(request).await.expect("place order");
+    }
+    cluster.wait_for_catch_up(TIMEOUT).await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    for id in cluster.ids() {
+        assert!(
+            !cluster.log(id).contains("diverged"),
+            "node {} logged a state divergence, node logs in {}",
+            id,
+            cluster.dir().display()
+        );
+    }
+}
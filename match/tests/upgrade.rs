@@ -0,0 +1,174 @@
+//! Rolling upgrade test
+//!
+//! Starts a cluster on a previous build of `match`, keeps an order flow going
+//! and replaces the nodes one at a time with the current build, followers first
+//! and the leader last. Orders must keep being acknowledged throughout, apart
+//! from the election after the leader is replaced, and every node must end up
+//! with all of them.
+//!
+//! Ignored by default:
+//! `MATCH_PREVIOUS_BIN=/path/to/old/match cargo test -p match --test upgrade -- --ignored`.
+//! Without `MATCH_PREVIOUS_BIN` the nodes are restarted on the same build, which
+//! still checks that a rolling restart causes no downtime.
+
+mod common;
+
+use common::pb::{
+    CreateSymbolRequest, Order, OrderSide, OrderType, PlaceOrderRequest, Symbol, SymbolStatus,
+};
+use common::TestCluster;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Longest time the cluster may take to elect a leader or catch up
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest time no order may be acknowledged during the upgrade
+const MAX_UNAVAILABLE: Duration = Duration::from_secs(10);
+
+/// Timeout of a single client request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Orders placed before and after each node is upgraded
+const ORDERS_PER_STEP: u64 = 50;
+
+const SYMBOL: &str = "BTCUSDT";
+
+/// Order flow that tracks the longest time without an acknowledgement
+struct Load {
+    /// Orders acknowledged so far, order IDs are 1 up to this
+    orders: u64,
+    /// When the last order was acknowledged
+    last_ack: Instant,
+    /// Longest time between two acknowledgements
+    longest_gap: Duration,
+}
+
+impl Load {
+    /// Places orders, retrying each with the same request ID until it is acknowledged
+    async fn place(&mut self, cluster: &TestCluster, count: u64) {
+        for _ in 0..count {
+            let order_id = self.orders + 1;
+            while !place_bid(cluster, order_id).await {
+                assert!(
+                    self.last_ack.elapsed() < TIMEOUT,
+                    "order {} not acknowledged within {:?}, node logs in {}",
+                    order_id,
+                    TIMEOUT,
+                    cluster.dir().display()
+                );
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            self.longest_gap = self.longest_gap.max(self.last_ack.elapsed());
+            self.last_ack = Instant::now();
+            self.orders = order_id;
+        }
+    }
+}
+
+/// Places a resting buy order on the current leader
+///
+/// # Returns
+///
+/// Returns whether the order was acknowledged
+async fn place_bid(cluster: &TestCluster, order_id: u64) -> bool {
+    let leader = match cluster.leader().await {
+        Some(leader) => leader,
+        None => return false,
+    };
+    let mut client = match cluster.client(leader).await {
+        Ok(client) => client,
+        Err(_) => return false,
+    };
+    let mut request = tonic::Request::new(PlaceOrderRequest {
+        order: Some(Order {
+            order_id,
+            account_id: 1,
+            order_side: OrderSide::Buy as i32,
+            order_type: OrderType::Limit as i32,
+            symbol: SYMBOL.to_string(),
+            quantity: "1".to_string(),
+            price: format!("{}", 100 + order_id % 10),
+            ..Default::default()
+        }),
+    });
+    request.metadata_mut().insert(
+        "x-request-id",
+        format!("upgrade-{}", order_id).parse().unwrap(),
+    );
+    request.set_timeout(REQUEST_TIMEOUT);
+    tokio::time::timeout(REQUEST_TIMEOUT, client.place_order(request))
+        .await
+        .map(|response| response.is_ok())
+        .unwrap_or(false)
+}
+
+#[tokio::test]
+#[ignore]
+async fn rolling_upgrade_keeps_serving_orders() {
+    let current = PathBuf::from(env!("CARGO_BIN_EXE_match"));
+    let previous = std::env::var_os("MATCH_PREVIOUS_BIN")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| current.clone());
+
+    let mut cluster = TestCluster::start_with_binary(3, &previous).await;
+    let (leader, mut client) = cluster.leader_client(TIMEOUT).await;
+    client
+        .create_symbol(CreateSymbolRequest {
+            symbol: Some(Symbol {
+                symbol: SYMBOL.to_string(),
+                base: "BTC".to_string(),
+                quote: "USDT".to_string(),
+                min_quantity: "0.0001".to_string(),
+                max_quantity: "1000".to_string(),
+                min_amount: "0.01".to_string(),
+                max_amount: "1000000".to_string(),
+                price_precision: 2,
+                quantity_precision: 4,
+                status: SymbolStatus::Alive as i32,
+                settle_balances: false,
+                ..Default::default()
+            }),
+        })
+        .await
+        .expect("create symbol");
+
+    let mut load = Load {
+        orders: 0,
+        last_ack: Instant::now(),
+        longest_gap: Duration::ZERO,
+    };
+    let mut order: Vec<u64> = cluster
+        .ids()
+        .into_iter()
+        .filter(|id| *id != leader)
+        .collect();
+    order.push(leader);
+    for id in order {
+        load.place(&cluster, ORDERS_PER_STEP).await;
+        cluster.upgrade(id, &current);
+        load.place(&cluster, ORDERS_PER_STEP).await;
+        cluster.wait_for_catch_up(TIMEOUT).await;
+    }
+
+    assert!(
+        load.longest_gap < MAX_UNAVAILABLE,
+        "no order acknowledged for {:?}",
+        load.longest_gap
+    );
+    let series = format!(
+        "symbol_resting_orders{{symbol=\"{}\",tenant=\"default\"}}",
+        SYMBOL
+    );
+    for id in cluster.ids() {
+        let health = cluster.health(id).await.expect("node health");
+        assert_eq!(health["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            cluster.metric(id, &series).await,
+            Some(load.orders as f64),
+            "resting orders on node {}",
+            id
+        );
+    }
+}
@@ -0,0 +1,483 @@
+//! Operator CLI for a raft match cluster
+//!
+//! Reads the state of every node, and the snapshots it is saving, sending or
+//! installing, from its `/healthz` endpoint. Membership changes, leader
+//! transfer, snapshots, log compaction and symbol administration go through the
+//! admin gRPC service of a node, see `proto/admin.proto`; calls a follower
+//! refuses are sent once more to the leader it names.
+
+use base64::Engine;
+use clap::Parser;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Client, Request};
+use pb::admin_service_client::AdminServiceClient;
+use serde_json::Value;
+use std::time::Duration;
+use tonic::transport::Channel;
+
+/// Protocol buffer definitions of the admin service
+mod pb {
+    tonic::include_proto!("admin");
+}
+
+/// Metadata key carrying the admin key
+const ADMIN_KEY_HEADER: &str = "x-admin-key";
+/// Metadata key carrying the leader address on calls refused by a follower
+const LEADER_ADDR_HEADER: &str = "x-leader-addr";
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Metrics endpoints (`metrics_addr`) of the cluster nodes
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        default_value = "127.0.0.1:4011,127.0.0.1:4012,127.0.0.1:4013"
+    )]
+    nodes: Vec<String>,
+
+    /// Bearer token of the metrics endpoints
+    #[arg(long, conflicts_with = "basic_auth")]
+    token: Option<String>,
+
+    /// Basic auth credentials of the metrics endpoints, as `user:password`
+    #[arg(long)]
+    basic_auth: Option<String>,
+
+    /// Timeout of each request in milliseconds
+    #[arg(long, default_value = "2000")]
+    timeout_ms: u64,
+
+    /// gRPC endpoint (`addr`) of the node admin calls are sent to
+    #[arg(long, default_value = "127.0.0.1:4001")]
+    grpc: String,
+
+    /// Admin key of the gRPC endpoint, required if `admin_keys` are set
+    #[arg(long)]
+    admin_key: Option<String>,
+
+    /// Time in milliseconds an admin call waits for the cluster, e.g. for a
+    /// transferee to catch up or a snapshot to be written
+    #[arg(long, default_value = "30000")]
+    wait_ms: u64,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Show leader, commit and apply progress of every node, exits with an error
+    /// if no leader is known to a majority of the nodes
+    Status,
+    /// Show the snapshots every node is saving, sending or installing, with the
+    /// last error of each kind
+    Snapshots,
+    /// Show the raft state of the `--grpc` node and, if it leads, the
+    /// replication progress of every member
+    Cluster,
+    /// Add a node listed in `node_list` to the cluster
+    AddNode {
+        /// ID of the node
+        node_id: u64,
+        /// Add the node as a learner, which replicates but does not vote
+        #[arg(long)]
+        learner: bool,
+    },
+    /// Remove a node from the cluster, other than the leader
+    RemoveNode {
+        /// ID of the node
+        node_id: u64,
+    },
+    /// Hand leadership to a voter
+    TransferLeader {
+        /// ID of the node
+        node_id: u64,
+    },
+    /// Save a snapshot on the `--grpc` node now
+    Snapshot,
+    /// Save a snapshot on the `--grpc` node and compact its log up to it
+    Compact,
+    /// Halt trading in a symbol until it is resumed
+    Halt(SymbolArgs),
+    /// Resume trading in a halted symbol
+    Resume(SymbolArgs),
+    /// Cancel every order on a symbol, of every account
+    MassCancel(SymbolArgs),
+}
+
+/// Symbol an admin command acts on
+#[derive(clap::Args, Debug)]
+struct SymbolArgs {
+    /// ID of the symbol
+    symbol: String,
+    /// Tenant of the symbol, the default tenant if empty
+    #[arg(long, default_value = "")]
+    tenant: String,
+    /// Market the symbol is listed on
+    #[arg(long, value_enum, default_value = "spot")]
+    market: Market,
+}
+
+/// Market a symbol is listed on
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Market {
+    Spot,
+    Perp,
+}
+
+impl From<Market> for pb::MarketType {
+    fn from(market: Market) -> Self {
+        match market {
+            Market::Spot => pb::MarketType::Spot,
+            Market::Perp => pb::MarketType::Perp,
+        }
+    }
+}
+
+/// Health summary of a node as read from its metrics endpoint
+struct NodeStatus {
+    /// Metrics endpoint of the node
+    addr: String,
+    /// `/healthz` response of the node
+    health: Result<Value, String>,
+}
+
+/// Fetches the `/healthz` summary of a node
+///
+/// # Arguments
+///
+/// * `args` - Command line arguments with the credentials and timeout
+/// * `addr` - Metrics endpoint of the node
+///
+/// # Returns
+///
+/// Returns the health JSON, or a description of why it could not be read
+async fn fetch_health(args: &Args, addr: &str) -> Result<Value, String> {
+    let mut request = Request::get(format!("http://{}/healthz", addr));
+    if let Some(token) = &args.token {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+    } else if let Some(credentials) = &args.basic_auth {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+        request = request.header(AUTHORIZATION, format!("Basic {}", encoded));
+    }
+    let request = request.body(Body::empty()).map_err(|e| e.to_string())?;
+    let response = tokio::time::timeout(
+        Duration::from_millis(args.timeout_ms),
+        Client::new().request(request),
+    )
+    .await
+    .map_err(|_| "timed out".to_string())?
+    .map_err(|e| e.to_string())?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| e.to_string())?;
+    // 503 still carries the summary, it only means the node knows no leader
+    serde_json::from_slice(&body).map_err(|_| format!("HTTP {}", status))
+}
+
+/// Prints the status of every node
+///
+/// # Returns
+///
+/// Returns an error if no leader is known to a majority of the nodes
+async fn status(args: &Args) -> Result<(), String> {
+    let mut nodes = Vec::new();
+    for addr in &args.nodes {
+        nodes.push(NodeStatus {
+            addr: addr.clone(),
+            health: fetch_health(args, addr).await,
+        });
+    }
+
+    println!(
+        "{:<24} {:>5} {:>9} {:>7} {:>8} {:>12} {:>12} {:>9} {:>14}",
+        "NODE", "ID", "VERSION", "LEADER", "ROLE", "COMMIT", "APPLIED", "LAG", "DISK FREE"
+    );
+    for node in &nodes {
+        match &node.health {
+            Ok(health) => {
+                let role = if health["is_leader"] == true {
+                    "leader"
+                } else {
+                    "follower"
+                };
+                let field = |name: &str| health[name].to_string();
+                // Builds before rolling upgrade support report no version
+                let version = health["version"].as_str().unwrap_or("-");
+                println!(
+                    "{:<24} {:>5} {:>9} {:>7} {:>8} {:>12} {:>12} {:>9} {:>14}",
+                    node.addr,
+                    field("node_id"),
+                    version,
+                    field("leader_id"),
+                    role,
+                    field("commit_index"),
+                    field("applied_index"),
+                    field("apply_lag"),
+                    field("disk_free_bytes")
+                );
+            }
+            Err(e) => println!("{:<24} down: {}", node.addr, e),
+        }
+    }
+
+    let leaders: Vec<u64> = nodes
+        .iter()
+        .filter_map(|node| node.health.as_ref().ok()?["leader_id"].as_u64())
+        .filter(|leader| *leader != 0)
+        .collect();
+    let agreed = leaders
+        .iter()
+        .find(|leader| leaders.iter().filter(|l| l == leader).count() > nodes.len() / 2);
+    match agreed {
+        Some(leader) => {
+            println!("leader: node {}", leader);
+            Ok(())
+        }
+        None => Err("no leader known to a majority of the nodes".to_string()),
+    }
+}
+
+/// Prints the snapshot operations in progress and the last errors of every node
+///
+/// Builds that predate snapshot progress report none.
+async fn snapshots(args: &Args) {
+    println!(
+        "{:<24} {:>8} {:>12} {:>5} {:>12} {:>14} {:>14} {:>10}",
+        "NODE", "KIND", "INDEX", "PEER", "PHASE", "BYTES", "DONE", "ETA MS"
+    );
+    let mut errors = Vec::new();
+    for addr in &args.nodes {
+        let health = match fetch_health(args, addr).await {
+            Ok(health) => health,
+            Err(e) => {
+                println!("{:<24} down: {}", addr, e);
+                continue;
+            }
+        };
+        let snapshots = &health["snapshots"];
+        for operation in snapshots["active"].as_array().into_iter().flatten() {
+            let field = |name: &str| operation[name].to_string();
+            println!(
+                "{:<24} {:>8} {:>12} {:>5} {:>12} {:>14} {:>14} {:>10}",
+                addr,
+                operation["kind"].as_str().unwrap_or("-"),
+                field("index"),
+                field("peer"),
+                operation["phase"].as_str().unwrap_or("-"),
+                field("bytes"),
+                field("done_bytes"),
+                operation["eta_ms"]
+                    .as_u64()
+                    .map_or("-".to_string(), |eta| eta.to_string())
+            );
+        }
+        for failure in snapshots["last_errors"].as_array().into_iter().flatten() {
+            errors.push(format!(
+                "{} {} at index {} with peer {}: {}",
+                addr,
+                failure["kind"].as_str().unwrap_or("-"),
+                failure["index"],
+                failure["peer"],
+                failure["error"].as_str().unwrap_or("-")
+            ));
+        }
+    }
+    for error in errors {
+        println!("last error: {}", error);
+    }
+}
+
+/// Connects to the admin service of a node
+///
+/// # Arguments
+///
+/// * `args` - Command line arguments with the timeout
+/// * `addr` - gRPC endpoint of the node, with or without a scheme
+async fn connect(args: &Args, addr: &str) -> Result<AdminServiceClient<Channel>, String> {
+    let uri = match addr.split_once("://") {
+        Some((_, rest)) => format!("http://{}", rest),
+        None => format!("http://{}", addr),
+    };
+    let endpoint = Channel::from_shared(uri)
+        .map_err(|e| e.to_string())?
+        .connect_timeout(Duration::from_millis(args.timeout_ms));
+    let channel = endpoint.connect().await.map_err(|e| e.to_string())?;
+    Ok(AdminServiceClient::new(channel))
+}
+
+/// Wraps a message into a request carrying the admin key and the wait deadline
+fn request<T>(args: &Args, message: T) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    request.set_timeout(Duration::from_millis(args.wait_ms));
+    if let Some(key) = args.admin_key.as_ref().and_then(|key| key.parse().ok()) {
+        request.metadata_mut().insert(ADMIN_KEY_HEADER, key);
+    }
+    request
+}
+
+/// Prints the raft state of a node
+fn print_cluster(status: pb::GetClusterStatusResponse) {
+    let leader = match status.leader_id {
+        0 => "unknown".to_string(),
+        id => id.to_string(),
+    };
+    println!(
+        "node {} ({}), term {}, leader {}",
+        status.id, status.role, status.term, leader
+    );
+    println!(
+        "log {}..={}, committed {}, applied {}",
+        status.first_index, status.last_index, status.commit_index, status.applied_index
+    );
+    if status.lead_transferee != 0 {
+        println!("transferring leadership to {}", status.lead_transferee);
+    }
+    println!(
+        "{:>5} {:>8} {:>12} {:>10} {:>7}",
+        "ID", "MEMBER", "MATCHED", "STATE", "ACTIVE"
+    );
+    for peer in status.peers {
+        let member = if peer.voter { "voter" } else { "learner" };
+        let state = if peer.state.is_empty() {
+            "-"
+        } else {
+            &peer.state
+        };
+        println!(
+            "{:>5} {:>8} {:>12} {:>10} {:>7}",
+            peer.id, member, peer.matched, state, peer.recent_active
+        );
+    }
+}
+
+/// Makes an admin call on a node
+///
+/// # Arguments
+///
+/// * `args` - Command line arguments
+/// * `client` - Admin service client of the node
+///
+/// # Returns
+///
+/// Returns the status the node answered with if the call failed
+async fn admin_call(
+    args: &Args,
+    client: &mut AdminServiceClient<Channel>,
+) -> Result<(), tonic::Status> {
+    let symbol = |target: &SymbolArgs| {
+        (
+            target.tenant.clone(),
+            pb::MarketType::from(target.market) as i32,
+            target.symbol.clone(),
+        )
+    };
+    match &args.command {
+        Command::Status | Command::Snapshots => unreachable!("not an admin call"),
+        Command::Cluster => {
+            let status = client
+                .get_cluster_status(request(args, pb::GetClusterStatusRequest {}))
+                .await?;
+            print_cluster(status.into_inner());
+        }
+        Command::AddNode { node_id, learner } => {
+            let message = pb::AddNodeRequest {
+                node_id: *node_id,
+                learner: *learner,
+            };
+            client.add_node(request(args, message)).await?;
+            println!("node {} added", node_id);
+        }
+        Command::RemoveNode { node_id } => {
+            let message = pb::RemoveNodeRequest { node_id: *node_id };
+            client.remove_node(request(args, message)).await?;
+            println!("node {} removed", node_id);
+        }
+        Command::TransferLeader { node_id } => {
+            let message = pb::TransferLeaderRequest { node_id: *node_id };
+            client.transfer_leader(request(args, message)).await?;
+            println!("node {} leads", node_id);
+        }
+        Command::Snapshot => {
+            let message = pb::TriggerSnapshotRequest {};
+            client.trigger_snapshot(request(args, message)).await?;
+            println!("snapshot saved");
+        }
+        Command::Compact => {
+            let message = pb::CompactLogRequest {};
+            client.compact_log(request(args, message)).await?;
+            println!("snapshot saved, log compacted");
+        }
+        Command::Halt(target) => {
+            let (tenant, market, symbol) = symbol(target);
+            let message = pb::HaltSymbolRequest {
+                tenant,
+                market,
+                symbol,
+            };
+            client.halt_symbol(request(args, message)).await?;
+            println!("{} halted", target.symbol);
+        }
+        Command::Resume(target) => {
+            let (tenant, market, symbol) = symbol(target);
+            let message = pb::ResumeSymbolRequest {
+                tenant,
+                market,
+                symbol,
+            };
+            client.resume_symbol(request(args, message)).await?;
+            println!("{} resumed", target.symbol);
+        }
+        Command::MassCancel(target) => {
+            let (tenant, market, symbol) = symbol(target);
+            let message = pb::MassCancelRequest {
+                tenant,
+                market,
+                symbol,
+            };
+            let response = client.mass_cancel(request(args, message)).await?;
+            let order_ids = response.into_inner().order_ids;
+            println!("{} orders canceled", order_ids.len());
+            for order_id in order_ids {
+                println!("{}", order_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Makes an admin call on the `--grpc` node, or on the leader if the node
+/// refused it as a follower
+async fn admin(args: &Args) -> Result<(), String> {
+    let mut client = connect(args, &args.grpc).await?;
+    let status = match admin_call(args, &mut client).await {
+        Ok(()) => return Ok(()),
+        Err(status) => status,
+    };
+    let leader = status
+        .metadata()
+        .get(LEADER_ADDR_HEADER)
+        .and_then(|addr| addr.to_str().ok());
+    let Some(leader) = leader else {
+        return Err(format!("{:?}: {}", status.code(), status.message()));
+    };
+    eprintln!("{}, sending to {}", status.message(), leader);
+    let mut client = connect(args, leader).await?;
+    admin_call(args, &mut client)
+        .await
+        .map_err(|status| format!("{:?}: {}", status.code(), status.message()))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    match args.command {
+        Command::Status => status(&args).await?,
+        Command::Snapshots => snapshots(&args).await,
+        _ => admin(&args).await?,
+    }
+    Ok(())
+}
@@ -23,7 +23,7 @@ sleep 3
 
 # Run the benchmark
 echo "Starting benchmark..."
-../target/release/benchmark --concurrency 100 --interval 1 --duration 30 --server "grpc://127.0.0.1:4001"
+../target/release/benchmark --concurrency 100 --rate 30000 --duration 30 --server "grpc://127.0.0.1:4001"
 
 # Cleanup: kill the node processes
 echo "Cleaning up..."
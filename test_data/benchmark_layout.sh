@@ -0,0 +1,64 @@
+#!/bin/bash
+# Compares the latency of the default runtime layout with a pinned one. Runs the
+# three-node cluster once per layout under the same workload, then prints the
+# change of every percentile. The pinned layout gives each node 4 cores: one for
+# the raft thread, one for its apply worker and two for the gRPC runtime, so the
+# machine needs 12 cores besides those left to the benchmark client.
+
+# Change to the directory where the script is located
+cd "$(dirname "$0")"
+
+# Build the release version
+cd ..
+echo "Building release version..."
+cargo build --release
+
+cd test_data
+
+# Writes the config of each node for a layout, both layouts use one apply worker
+write_configs() {
+    for i in 1 2 3; do
+        {
+            echo "apply_workers = 1"
+            cat config$i.toml
+            if [ "$1" = "pinned" ]; then
+                base=$(( (i - 1) * 4 ))
+                echo
+                echo "[runtime_layout]"
+                echo "raft_thread = true"
+                echo "raft_cores = [$base]"
+                echo "apply_cores = [$(( base + 1 ))]"
+                echo "grpc_threads = 2"
+                echo "grpc_cores = [$(( base + 2 )), $(( base + 3 ))]"
+            fi
+        } > layout$i.toml
+    done
+}
+
+# Runs the benchmark against a cluster started with the given layout
+run_layout() {
+    write_configs "$1"
+    echo "Starting nodes with the $1 layout..."
+    ../target/release/match --config layout1.toml > match1.log 2>&1 &
+    NODE1_PID=$!
+    ../target/release/match --config layout2.toml > match2.log 2>&1 &
+    NODE2_PID=$!
+    ../target/release/match --config layout3.toml > match3.log 2>&1 &
+    NODE3_PID=$!
+    sleep 3
+
+    echo "Starting benchmark..."
+    ../target/release/benchmark --concurrency 100 --rate 20000 --warmup 5 --duration 30 \
+        --server "grpc://127.0.0.1:4001" --output "layout-$1.json"
+
+    echo "Cleaning up..."
+    kill $NODE1_PID $NODE2_PID $NODE3_PID
+    wait $NODE1_PID $NODE2_PID $NODE3_PID 2>/dev/null
+    rm -r data1 data2 data3
+    rm match1.log match2.log match3.log layout1.toml layout2.toml layout3.toml
+}
+
+run_layout default
+run_layout pinned
+
+../target/release/benchmark compare layout-default.json layout-pinned.json